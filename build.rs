@@ -107,12 +107,27 @@ fn main() {
     
     // Create a resource locator module to help find resources at runtime
     generate_resource_locator(&manifest_dir, &profile_dir, is_in_workspace);
+
+    // The server side of the gRPC auth service, included by src/grpc.rs
+    compile_protos(&manifest_dir);
     
     // Tell Cargo to rerun if any of these directories change
     println!("cargo:rerun-if-changed=templates");
     println!("cargo:rerun-if-changed=programfiles");
 }
 
+/// Generate the messages and service trait of `proto/auth.proto`, with the
+/// vendored protoc so no protobuf install is needed
+fn compile_protos(manifest_dir: &Path) {
+    let protoc = protoc_bin_vendored::protoc_bin_path().expect("No vendored protoc for this host");
+    let mut config = prost_build::Config::new();
+    config.protoc_executable(protoc);
+    tonic_prost_build::configure()
+        .build_client(false)
+        .compile_with_config(config, &[manifest_dir.join("proto/auth.proto")], &[manifest_dir.join("proto")])
+        .expect("Failed to compile proto/auth.proto");
+}
+
 /// Recursively copies a directory
 fn copy_dir_all(src: &Path, dst: &Path) -> std::io::Result<()> {
     if !dst.exists() {
@@ -1,7 +1,7 @@
-use sfx::prelude::*;
-use {{crate_name}}::APP;
-
-#[tokio::main]
-async fn main() {
-    APP.clone().run().await;
-}
+use sfx::prelude::*;
+use {{crate_name}}::APP;
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    sfx::serve(APP.clone()).await
+}
@@ -0,0 +1,14 @@
+-- The accounts of local_auth, one row per entry of programfiles/local_auth/users
+-- up
+CREATE TABLE sfx_users (
+    uid INTEGER PRIMARY KEY,
+    username TEXT NOT NULL UNIQUE,
+    email TEXT NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL,
+    password_salt TEXT NOT NULL,
+    profile TEXT NOT NULL DEFAULT '{}',
+    is_active BOOLEAN NOT NULL DEFAULT TRUE
+);
+
+-- down
+DROP TABLE sfx_users;
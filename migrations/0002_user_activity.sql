@@ -0,0 +1,10 @@
+-- Login history of each account, as kept in the activity fields of the users file
+-- up
+ALTER TABLE sfx_users ADD COLUMN last_login BIGINT;
+ALTER TABLE sfx_users ADD COLUMN failed_logins INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE sfx_users ADD COLUMN last_failed_login BIGINT;
+
+-- down
+ALTER TABLE sfx_users DROP COLUMN last_failed_login;
+ALTER TABLE sfx_users DROP COLUMN failed_logins;
+ALTER TABLE sfx_users DROP COLUMN last_login;
@@ -0,0 +1,8 @@
+-- Second factors of each account and the hashes of its unused backup codes, as JSON lists
+-- up
+ALTER TABLE sfx_users ADD COLUMN two_factor TEXT NOT NULL DEFAULT '[]';
+ALTER TABLE sfx_users ADD COLUMN backup_codes TEXT NOT NULL DEFAULT '[]';
+
+-- down
+ALTER TABLE sfx_users DROP COLUMN backup_codes;
+ALTER TABLE sfx_users DROP COLUMN two_factor;
@@ -0,0 +1,23 @@
+-- Display name, former usernames, addresses, phone and login details of each account,
+-- JSON values stored as text the way the users file keeps them
+-- up
+ALTER TABLE sfx_users ADD COLUMN display_name TEXT NOT NULL DEFAULT '';
+ALTER TABLE sfx_users ADD COLUMN former_usernames TEXT NOT NULL DEFAULT '[]';
+ALTER TABLE sfx_users ADD COLUMN pending_email TEXT;
+ALTER TABLE sfx_users ADD COLUMN emails TEXT NOT NULL DEFAULT '[]';
+ALTER TABLE sfx_users ADD COLUMN phone TEXT;
+ALTER TABLE sfx_users ADD COLUMN last_login_ip TEXT;
+ALTER TABLE sfx_users ADD COLUMN last_login_location TEXT;
+ALTER TABLE sfx_users ADD COLUMN login_countries TEXT NOT NULL DEFAULT '[]';
+ALTER TABLE sfx_users ADD COLUMN recent_logins TEXT NOT NULL DEFAULT '[]';
+
+-- down
+ALTER TABLE sfx_users DROP COLUMN recent_logins;
+ALTER TABLE sfx_users DROP COLUMN login_countries;
+ALTER TABLE sfx_users DROP COLUMN last_login_location;
+ALTER TABLE sfx_users DROP COLUMN last_login_ip;
+ALTER TABLE sfx_users DROP COLUMN phone;
+ALTER TABLE sfx_users DROP COLUMN emails;
+ALTER TABLE sfx_users DROP COLUMN pending_email;
+ALTER TABLE sfx_users DROP COLUMN former_usernames;
+ALTER TABLE sfx_users DROP COLUMN display_name;
@@ -0,0 +1,276 @@
+//! access.rs
+//!
+//! Who may reach a path, decided in one place by [`AccessGuard`] rather
+//! than by each endpoint. Every path is public unless a rule says
+//! otherwise; the rule with the longest prefix wins. sfx ships:
+//!
+//! - `/admin` for admins (a session of an admin, or the bearer token of an
+//!   admin's local account, see `crate::admin::check_is_admin`)
+//! - `/user/home` for signed-in users
+//!
+//! Applications declare the access of their routes next to them:
+//!
+//! ```rust,ignore
+//! use sfx::access::{Access, AccessRegistry};
+//!
+//! APP.access("/shop/orders", Access::Auth);
+//! ```
+//!
+//! and sites change either in `programfiles/op/access.json`, which wins
+//! over both for the same prefix:
+//!
+//! ```json
+//! { "rules": { "/shop/orders": "admin", "/shop/orders/receipts": "public" } }
+//! ```
+//!
+//! The guard comes on top of the checks the endpoints of sfx still make
+//! themselves (`check_is_admin`, the signed-in user of `/user/home`), so a
+//! rule may ask more of their paths but not less; moving those checks onto
+//! the rules is left for later.
+//!
+//! A browser opening a page it may not see is sent to the login page (with
+//! `?next=` back to it) when signed out, and gets the forbidden page when
+//! signed in; other requests (API calls, `fetch` from scripts, anything but
+//! a `GET` asking for HTML) get `401` or `403` with
+//! `{"success": false, "message": ...}`.
+
+use hotaru::http::*;
+use hotaru::prelude::*;
+use std::sync::RwLock;
+
+use crate::ctx::SfxCtx;
+use crate::op;
+
+static POLICY: Lazy<AccessPolicy> = Lazy::new(|| {
+    let path = op::programfiles().join("op/access.json");
+    AccessPolicy::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None)).unwrap_or_else(|err| {
+        tracing::error!(%err, "access.json: rules ignored");
+        AccessPolicy::default()
+    })
+});
+
+/// The rules declared by the application with [`require`]
+static DECLARED: Lazy<RwLock<Vec<Rule>>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+/// The rules of sfx itself
+const BUILT_IN: &[(&str, Access)] = &[("/admin", Access::Admin), ("/user/home", Access::Auth)];
+
+/// Who may reach a path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Anyone
+    Public,
+    /// Signed-in users
+    Auth,
+    /// Admins
+    Admin,
+}
+
+impl Access {
+    /// `public`, `auth` or `admin`
+    pub fn parse(level: &str) -> Option<Self> {
+        match level {
+            "public" => Some(Self::Public),
+            "auth" => Some(Self::Auth),
+            "admin" => Some(Self::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Public => "public",
+            Self::Auth => "auth",
+            Self::Admin => "admin",
+        }
+    }
+}
+
+/// The access of the paths under `prefix`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub prefix: String,
+    pub access: Access,
+}
+
+impl Rule {
+    pub fn new(prefix: impl Into<String>, access: Access) -> Self {
+        Self { prefix: prefix.into().trim_end_matches('/').to_string(), access }
+    }
+
+    /// Whether `path` is the prefix or under it
+    pub fn matches(&self, path: &str) -> bool {
+        self.prefix.is_empty() || path == self.prefix || path.strip_prefix(self.prefix.as_str()).is_some_and(|rest| rest.starts_with('/'))
+    }
+}
+
+/// The rules of `access.json`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccessPolicy {
+    pub rules: Vec<Rule>,
+}
+
+impl AccessPolicy {
+    pub fn from_value(value: &Value) -> Result<Self, String> {
+        let rules = match value.get("rules") {
+            Value::Dict(rules) => rules,
+            Value::None => return Ok(Self::default()),
+            _ => return Err("`rules` must be an object of path prefixes and `public`, `auth` or `admin`".to_string()),
+        };
+        let mut parsed = Vec::new();
+        for (prefix, level) in rules {
+            if !prefix.starts_with('/') {
+                return Err(format!("'{}' must be a path starting with /", prefix));
+            }
+            let Some(access) = Access::parse(&level.string()) else {
+                return Err(format!("'{}': '{}' must be `public`, `auth` or `admin`", prefix, level.string()));
+            };
+            parsed.push(Rule::new(prefix.as_str(), access));
+        }
+        Ok(Self { rules: parsed })
+    }
+}
+
+/// Declare the access of the paths under `prefix`; `access.json` may
+/// change it
+pub fn require(prefix: &str, access: Access) {
+    DECLARED.write().unwrap().push(Rule::new(prefix, access));
+}
+
+/// The access of `path` among the built-in, declared and configured rules,
+/// in that order of precedence for the same prefix
+pub fn resolve(path: &str, declared: &[Rule], configured: &[Rule]) -> Access {
+    let built_in: Vec<Rule> = BUILT_IN.iter().map(|(prefix, access)| Rule::new(*prefix, *access)).collect();
+    let mut found: Option<&Rule> = None;
+    for rule in built_in.iter().chain(declared).chain(configured) {
+        if rule.matches(path) && found.is_none_or(|found| rule.prefix.len() >= found.prefix.len()) {
+            found = Some(rule);
+        }
+    }
+    found.map_or(Access::Public, |rule| rule.access)
+}
+
+/// The access of `path` on this server
+pub fn required(path: &str) -> Access {
+    resolve(path, &DECLARED.read().unwrap(), &POLICY.rules)
+}
+
+/// Registration of access rules on the server itself:
+/// `APP.access("/shop/orders", Access::Auth)`
+pub trait AccessRegistry {
+    fn access(&self, prefix: &str, access: Access) -> &Self;
+}
+
+impl AccessRegistry for Server<TcpTransport, TokioRuntime> {
+    fn access(&self, prefix: &str, access: Access) -> &Self {
+        require(prefix, access);
+        self
+    }
+}
+
+/// Why a request was turned away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denied {
+    /// Nobody is signed in
+    SignIn,
+    /// The user is signed in without the rights
+    Forbidden,
+}
+
+/// Whether the request is signed in, by its session or a bearer token of
+/// a local account
+async fn signed_in(req: &mut HttpReqCtx) -> bool {
+    if req.signed_in().is_some() {
+        return true;
+    }
+    match crate::local_auth::analyze::get_auth_token(req) {
+        Some(token) => crate::admin::local_token_admin(&token).await.is_some(),
+        None => false,
+    }
+}
+
+/// Whether the request may reach a path of `access`
+pub async fn check(req: &mut HttpReqCtx, access: Access) -> Result<(), Denied> {
+    match access {
+        Access::Public => Ok(()),
+        Access::Auth if signed_in(req).await => Ok(()),
+        Access::Admin if crate::admin::check_is_admin(req).await => Ok(()),
+        Access::Admin if signed_in(req).await => Err(Denied::Forbidden),
+        _ => Err(Denied::SignIn),
+    }
+}
+
+/// Whether the request is a browser opening a page, answered with HTML
+fn wants_page(req: &mut HttpReqCtx) -> bool {
+    matches!(req.method(), HttpMethod::GET | HttpMethod::HEAD) && req.header_str("accept").is_some_and(|accept| accept.contains("text/html"))
+}
+
+/// The answer to a request turned away for `denied`
+pub fn denied_response(req: &mut HttpReqCtx, denied: Denied) -> HttpResponse {
+    match (denied, wants_page(req)) {
+        (Denied::SignIn, true) => {
+            let next = hotaru_lib::url_encoding::encode_url_owned(&req.request.meta.url());
+            redirect_response(&format!("/user/login?next={}", next))
+        }
+        (Denied::Forbidden, true) => op::forbidden_response(req, None),
+        (Denied::SignIn, false) => json_response(object!({ success: false, message: "Unauthorized" })).status(StatusCode::UNAUTHORIZED),
+        (Denied::Forbidden, false) => json_response(object!({ success: false, message: "Forbidden" })).status(StatusCode::FORBIDDEN),
+    }
+}
+
+middleware! {
+    /// Middleware turning away the requests the access rules do not allow.
+    /// **MUST ADD AFTER UserFetch MIDDLEWARE**
+    pub AccessGuard <HTTP> {
+        // CORS preflights carry no credentials
+        if req.method() == HttpMethod::OPTIONS {
+            return next(req).await;
+        }
+        let access = required(&req.path());
+        if let Err(denied) = check(&mut req, access).await {
+            tracing::debug!(path = %req.path(), access = access.as_str(), ?denied, "Request turned away");
+            req.response = denied_response(&mut req, denied);
+            return Ok(req)
+        }
+        next(req).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestRequest};
+
+    #[test]
+    fn the_longest_prefix_decides() {
+        let declared = vec![Rule::new("/shop/orders", Access::Auth), Rule::new("/admin/status", Access::Public)];
+        let rules = |json: &str| AccessPolicy::from_value(&Value::from_json(json).unwrap());
+        let configured = rules(r#"{ "rules": { "/shop/orders": "admin", "/shop/orders/public/": "public" } }"#).unwrap().rules;
+        assert_eq!(resolve("/admin/settings", &declared, &configured), Access::Admin);
+        assert_eq!(resolve("/admin", &declared, &configured), Access::Admin);
+        assert_eq!(resolve("/administrator", &declared, &configured), Access::Public);
+        assert_eq!(resolve("/admin/status", &declared, &configured), Access::Public);
+        assert_eq!(resolve("/user/home/security", &declared, &configured), Access::Auth);
+        assert_eq!(resolve("/user/login", &declared, &configured), Access::Public);
+        assert_eq!(resolve("/shop/orders/7", &declared, &configured), Access::Admin);
+        assert_eq!(resolve("/shop/orders/public/7", &declared, &configured), Access::Public);
+        assert_eq!(resolve("/shop/orders/7", &declared, &[]), Access::Auth);
+        assert!(rules(r#"{ "rules": { "/x": "staff" } }"#).is_err());
+        assert!(rules(r#"{ "rules": { "x": "auth" } }"#).is_err());
+        assert_eq!(AccessPolicy::from_value(&Value::None), Ok(AccessPolicy::default()));
+    }
+
+    #[tokio::test]
+    async fn pages_redirect_and_calls_get_status_codes() {
+        let mut req = TestRequest::get("/user/home").header("Accept", "text/html,application/xhtml+xml").build();
+        let res = denied_response(&mut req, Denied::SignIn);
+        assert_eq!(testing::location(&res).as_deref(), Some("/user/login?next=%2Fuser%2Fhome"));
+
+        let mut req = TestRequest::post("/admin/settings/shop/set").header("Accept", "text/html").build();
+        assert_eq!(testing::status(&denied_response(&mut req, Denied::SignIn)), 401);
+        let mut req = TestRequest::get("/admin/settings/json").header("Accept", "application/json").build();
+        let res = denied_response(&mut req, Denied::Forbidden);
+        assert_eq!(testing::status(&res), 403);
+        assert!(!testing::json(&res).get("success").boolean());
+    }
+}
@@ -0,0 +1,371 @@
+//! access_log.rs
+//!
+//! One line per request: method, path, status, latency, client address,
+//! user id and request id, from `programfiles/op/access_log.json`:
+//!
+//! ```json
+//! {
+//!     "enabled": true,
+//!     "format": "json",
+//!     "file": "",
+//!     "headers": false,
+//!     "bodies": false,
+//!     "max_body": 2048,
+//!     "redact": ["otp"],
+//!     "skip": ["/static/"]
+//! }
+//! ```
+//!
+//! `format` is `text` (the default) or `json`, one object per line. Lines
+//! go to stdout, or are appended to `file` when it is set. `headers` and
+//! `bodies` add the request headers and the form or JSON body; values under
+//! a name containing `password`, `token`, `secret`, `authorization`,
+//! `cookie` or one of `redact` are replaced by `[REDACTED]`, so credentials
+//! never reach the log. Paths starting with one of `skip` are not logged.
+//!
+//! Every request gets an id, the `X-Request-Id` sent by a trusted proxy or
+//! a new one, which is sent back in `X-Request-Id` and kept in the params as
+//! [`RequestId`].
+
+use hotaru::http::*;
+use hotaru::prelude::*;
+use hotaru_lib::random::random_alphanumeric_string;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::ctx::SfxCtx;
+
+static ACCESS_LOG: Lazy<AccessLogSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/access_log.json");
+    AccessLogSettings::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None))
+});
+
+/// Serializes appends to the log file
+static FILE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Names whose values are always redacted
+pub const REDACTED_NAMES: &[&str] = &["password", "token", "secret", "authorization", "cookie"];
+
+/// What replaces a redacted value
+pub const REDACTED: &str = "[REDACTED]";
+
+/// The header carrying the request id
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// The id of a request, also sent back as `X-Request-Id`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+/// How lines are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// The parsed content of `access_log.json`
+#[derive(Debug, Clone)]
+pub struct AccessLogSettings {
+    pub enabled: bool,
+    pub format: LogFormat,
+    /// Append to this file instead of stdout when not empty
+    pub file: String,
+    pub headers: bool,
+    pub bodies: bool,
+    /// Bodies are cut to this many bytes
+    pub max_body: usize,
+    /// Names redacted on top of [`REDACTED_NAMES`], lowercase
+    pub redact: Vec<String>,
+    /// Path prefixes not logged
+    pub skip: Vec<String>,
+}
+
+impl Default for AccessLogSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            format: LogFormat::Text,
+            file: String::new(),
+            headers: false,
+            bodies: false,
+            max_body: 2048,
+            redact: Vec::new(),
+            skip: Vec::new(),
+        }
+    }
+}
+
+impl AccessLogSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let default = Self::default();
+        let strings = |key: &str| match value.get(key) {
+            Value::List(list) => list.iter().map(|item| item.string()).filter(|item| !item.is_empty()).collect(),
+            _ => Vec::new(),
+        };
+        Self {
+            enabled: match value.get("enabled") {
+                Value::Boolean(enabled) => *enabled,
+                _ => default.enabled,
+            },
+            format: match value.get("format").string().to_ascii_lowercase().as_str() {
+                "json" => LogFormat::Json,
+                _ => LogFormat::Text,
+            },
+            file: value.get("file").string(),
+            headers: value.get("headers").boolean(),
+            bodies: value.get("bodies").boolean(),
+            max_body: match value.get("max_body") {
+                Value::Numerical(max) if *max > 0.0 => *max as usize,
+                _ => default.max_body,
+            },
+            redact: strings("redact").into_iter().map(|name: String| name.to_ascii_lowercase()).collect(),
+            skip: strings("skip"),
+        }
+    }
+
+    /// Whether the value of `name`, a header, form field or JSON key, is
+    /// kept out of the log
+    pub fn is_secret(&self, name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+        REDACTED_NAMES.iter().any(|secret| name.contains(secret))
+            || self.redact.iter().any(|secret| name.contains(secret.as_str()))
+    }
+
+    /// `value` with the values under secret names redacted, at any depth
+    pub fn redact(&self, value: &Value) -> Value {
+        match value {
+            Value::Dict(map) => Value::Dict(
+                map.iter()
+                    .map(|(key, value)| {
+                        let value = if self.is_secret(key) { Value::from(REDACTED) } else { self.redact(value) };
+                        (key.clone(), value)
+                    })
+                    .collect(),
+            ),
+            Value::List(list) => Value::List(list.iter().map(|item| self.redact(item)).collect()),
+            other => other.clone(),
+        }
+    }
+
+    fn skips(&self, path: &str) -> bool {
+        self.skip.iter().any(|prefix| path.starts_with(prefix.as_str()))
+    }
+}
+
+/// The loaded access log settings
+pub fn settings() -> &'static AccessLogSettings {
+    &ACCESS_LOG
+}
+
+/// The id of `req`, empty outside `AccessLog`
+pub fn request_id(req: &HttpReqCtx) -> String {
+    req.params.get::<RequestId>().map(|id| id.0.clone()).unwrap_or_default()
+}
+
+/// An id sent by a client or proxy, if it is short and plain enough to log
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 64 && id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// A logged request
+#[derive(Debug, Clone, Default)]
+pub struct Entry {
+    pub time: u64,
+    pub request_id: String,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub latency: Duration,
+    pub client: String,
+    /// `uid@host`, empty for guests
+    pub user: String,
+    /// The headers, redacted, when `headers` is on
+    pub headers: Option<Value>,
+    /// The body, redacted, when `bodies` is on
+    pub body: Option<Value>,
+}
+
+impl Entry {
+    pub fn to_json(&self) -> Value {
+        let mut entry = object!({
+            time: self.time,
+            request_id: &self.request_id,
+            method: &self.method,
+            path: &self.path,
+            status: self.status,
+            latency_ms: self.latency.as_secs_f64() * 1000.0,
+            client: &self.client,
+            user: &self.user,
+        });
+        if let Some(headers) = &self.headers {
+            entry.set("headers", headers.clone());
+        }
+        if let Some(body) = &self.body {
+            entry.set("body", body.clone());
+        }
+        entry
+    }
+
+    /// The line written for this entry in `format`
+    pub fn line(&self, format: LogFormat) -> String {
+        match format {
+            LogFormat::Json => self.to_json().into_json(),
+            LogFormat::Text => {
+                let mut line = format!(
+                    "{} {} {} {} {:.1}ms user={} rid={}",
+                    if self.client.is_empty() { "-" } else { &self.client },
+                    self.method,
+                    self.path,
+                    self.status,
+                    self.latency.as_secs_f64() * 1000.0,
+                    if self.user.is_empty() { "-" } else { &self.user },
+                    self.request_id,
+                );
+                if let Some(headers) = &self.headers {
+                    line.push_str(&format!(" headers={}", headers.into_json()));
+                }
+                if let Some(body) = &self.body {
+                    line.push_str(&format!(" body={}", body.into_json()));
+                }
+                line
+            }
+        }
+    }
+}
+
+/// The headers of `request`, redacted
+pub fn logged_headers(settings: &AccessLogSettings, request: &HttpRequest) -> Value {
+    let headers = request
+        .meta
+        .get_header_hashmap()
+        .keys()
+        .map(|name| (name.to_ascii_lowercase(), Value::from(request.meta.get_header(name.as_str()).unwrap_or_default())))
+        .collect();
+    settings.redact(&Value::Dict(headers))
+}
+
+/// The form or JSON body of `request` as parsed by the handler, redacted
+/// and cut to `max_body`; `None` for bodies the handler did not read
+pub fn logged_body(settings: &AccessLogSettings, request: &HttpRequest) -> Option<Value> {
+    let body = match &request.body {
+        HttpBody::Form(form) => Value::Dict(form.data.iter().map(|(key, value)| (key.clone(), Value::from(value.as_str()))).collect()),
+        HttpBody::Json(json) => json.clone(),
+        HttpBody::Files(_) => return Some(Value::from("<multipart>")),
+        _ => return None,
+    };
+    let body = settings.redact(&body);
+    let text = body.into_json();
+    if text.len() > settings.max_body {
+        let mut end = settings.max_body;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        return Some(Value::from(format!("{}…", &text[..end])));
+    }
+    Some(body)
+}
+
+fn write_line(settings: &AccessLogSettings, line: &str) {
+    if settings.file.is_empty() {
+        println!("{}", line);
+        return;
+    }
+    let _guard = FILE_LOCK.lock().unwrap();
+    let written = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&settings.file)
+        .and_then(|mut file| writeln!(file, "{}", line));
+    if let Err(err) = written {
+        tracing::warn!(file = %settings.file, %err, "Failed to write the access log");
+    }
+}
+
+middleware! {
+    /// Logs every request once answered, see the module documentation.
+    /// Add it first, so that it times and logs the whole chain, rejected
+    /// and failed requests included.
+    pub AccessLog <HTTP> {
+        let settings = settings();
+        let id = req
+            .header_str(REQUEST_ID_HEADER)
+            .filter(|id| valid_request_id(id) && crate::proxy::forwarded_info(&req).via_proxy)
+            .map(str::to_string)
+            .unwrap_or_else(|| random_alphanumeric_string(16));
+        req.params.set(RequestId(id.clone()));
+        req.request.meta.set_attribute(REQUEST_ID_HEADER, id.clone());
+        let method = req.method().to_string();
+        let path = req.path();
+        let start = Instant::now();
+
+        let mut req = next(req).await?;
+        req.response.meta.set_attribute(REQUEST_ID_HEADER, id.clone());
+        crate::latency::record_route(&method, &path, start.elapsed());
+        if !settings.enabled || settings.skips(&path) {
+            return Ok(req);
+        }
+        let entry = Entry {
+            time: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            request_id: id,
+            method,
+            path,
+            status: req.response.meta.start_line.status_code().as_u16(),
+            latency: start.elapsed(),
+            client: crate::proxy::client_ip(&req).map(|ip| ip.to_string()).unwrap_or_default(),
+            user: req.signed_in().map(|user| user.get_user_id().to_string()).unwrap_or_default(),
+            headers: settings.headers.then(|| logged_headers(settings, &req.request)),
+            body: if settings.bodies { logged_body(settings, &req.request) } else { None },
+        };
+        write_line(settings, &entry.line(settings.format));
+        Ok(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestRequest;
+
+    #[test]
+    fn secrets_are_redacted() {
+        let settings = AccessLogSettings::from_value(&Value::from_json(r#"{ "bodies": true, "redact": ["OTP"], "max_body": 64 }"#).unwrap());
+        let body = Value::from_json(r#"{ "user": "alice", "new_password": "x", "auth": { "refresh_token": "y", "otp_code": "1" } }"#).unwrap();
+        let redacted = settings.redact(&body);
+        assert_eq!(redacted.get("user").string(), "alice");
+        assert_eq!(redacted.get("new_password").string(), REDACTED);
+        assert_eq!(redacted.get("auth").get("refresh_token").string(), REDACTED);
+        assert_eq!(redacted.get("auth").get("otp_code").string(), REDACTED);
+
+        let req = TestRequest::post("/user/login")
+            .bearer("abc")
+            .header("Accept", "text/html")
+            .form(&[("username", "alice"), ("password", "Aa333333")])
+            .build();
+        let headers = logged_headers(&settings, &req.request);
+        assert_eq!(headers.get("authorization").string(), REDACTED);
+        assert_eq!(headers.get("accept").string(), "text/html");
+        let body = logged_body(&settings, &req.request).unwrap();
+        assert_eq!(body.get("password").string(), REDACTED);
+        assert!(!body.into_json().contains("Aa333333"));
+    }
+
+    #[test]
+    fn lines_in_both_formats() {
+        let entry = Entry {
+            request_id: "r1".to_string(),
+            method: "GET".to_string(),
+            path: "/blog".to_string(),
+            status: 200,
+            latency: Duration::from_millis(12),
+            user: "3@local".to_string(),
+            ..Entry::default()
+        };
+        assert_eq!(entry.line(LogFormat::Text), "- GET /blog 200 12.0ms user=3@local rid=r1");
+        let json = Value::from_json(&entry.line(LogFormat::Json)).unwrap();
+        assert_eq!(json.get("status").integer(), 200);
+        assert_eq!(json.get("request_id").string(), "r1");
+        assert!(valid_request_id("a-b_c.1") && !valid_request_id("a b") && !valid_request_id(""));
+    }
+}
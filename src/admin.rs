@@ -2,15 +2,45 @@ use hotaru::prelude::*;
 use hotaru::http::*; 
 
 use crate::user::{UserID, fetch::get_user_id}; 
+use crate::local_auth::{LOCAL_AUTH, analyze::get_auth_token, scope};
+use crate::modules;
 use crate::op; 
 use crate::APP; 
 
+pub mod analytics;
 pub mod api; 
 pub mod admins; 
+pub mod backups; 
+pub mod bans;
+pub mod blog;
+pub mod comments;
+pub mod forms;
+pub mod internal;
+pub mod jsonapi;
+pub mod l10n;
+pub mod links;
+pub mod media;
+pub mod moderation;
 pub mod panel; 
+pub mod read_only;
+pub mod remote;
+pub mod security;
+pub mod settings;
+pub mod sudo;
 pub mod user; 
 
+/// Whether the request comes from an admin. A request with a bearer token is
+/// judged by the local account the token was issued to, which is how other
+/// sfx frontends manage this server's users; a signed internal call by the
+/// admin it names (see [`internal`]); otherwise by the user signed in to the
+/// session.
 pub async fn check_is_admin(req: &mut HttpReqCtx) -> bool { 
+    if let Some(internal::SignedAdmin(admin)) = req.params.get::<internal::SignedAdmin>().cloned() {
+        return check_is_admin_id(admin);
+    }
+    if let Some(token) = get_auth_token(req) {
+        return matches!(local_token_admin(&token).await, Some((_, true)));
+    }
     let user = object!(get_user_id(req).await.to_string());
     println!("check_is_admin: user: {}, admins: {}, is_admin: {}", user, op::get_admin(), op::get_admin().contains(&user)); 
     op::get_admin().contains(&user) 
@@ -29,6 +59,19 @@ middleware! {
     }
 }
 
+/// The uid of the local account holding `token`, and whether `<uid>@local`
+/// is an admin the token may act for, i.e. one carrying the `users:admin`
+/// scope. `None` when the token is unknown or the local account store is
+/// switched off.
+pub async fn local_token_admin(token: &str) -> Option<(u32, bool)> {
+    if !modules::enabled(modules::LOCAL_AUTH) {
+        return None;
+    }
+    let uid = LOCAL_AUTH.uid_of_token(token).await?;
+    let scoped = LOCAL_AUTH.token_scopes(token).await.is_some_and(|(_, scopes)| scopes.allows(scope::USERS_ADMIN));
+    Some((uid, scoped && op::get_admin().contains(&object!(format!("{}@local", uid)))))
+}
+
 pub fn check_is_admin_id(id: UserID) -> bool {
     println!("check_is_admin_id: user: {}, admins: {}, is_admin: {}", id, op::get_admin(), op::get_admin().contains(&object!(id.to_string())));
     op::get_admin().contains(&object!(id.to_string()))
@@ -45,6 +88,8 @@ endpoint! {
             "admin/index.html", 
             pageprop = op::pageprop(req, "Admin", "Admin Dashboard"), 
             path = op::into_path_l(req, vec!["home", "admin"]), 
+            read_only = read_only::enabled(),
+            mail_templates = Value::List(crate::mail::templates::TEMPLATES.iter().map(|name| Value::from(*name)).collect()),
         ) 
     }
 }
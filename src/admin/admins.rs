@@ -3,8 +3,10 @@ use hotaru::prelude::*;
 
 use crate::APP;
 use crate::admin::check_is_admin;
+use crate::admin::sudo;
 use crate::local_auth::LOCAL_AUTH;
 use crate::op;
+use crate::methods;
 
 fn normalize_admin_entry(raw: &str) -> Option<String> {
     let trimmed = raw.trim();
@@ -66,10 +68,7 @@ endpoint! {
             return json_response(object!({ success: false, message: "Unauthorized" }))
                 .status(StatusCode::UNAUTHORIZED);
         }
-        if req.method() != POST {
-            return json_response(object!({ success: false, message: "Method not allowed" }))
-                .status(StatusCode::METHOD_NOT_ALLOWED);
-        }
+        methods!(req, POST);
 
         let form = req.form_or_default().await.clone();
         let raw = form.get_or_default("uid");
@@ -86,8 +85,16 @@ endpoint! {
                 .status(StatusCode::BAD_REQUEST);
         }
 
+        let sudo = match sudo::require_sudo(req).await {
+            Ok(sudo) => sudo,
+            Err(response) => return response,
+        };
+
         match op::add_admin_entry(&entry) {
-            Ok(()) => json_response(object!({ success: true, entry: entry })).status(StatusCode::OK),
+            Ok(()) => {
+                sudo::audit("grant", "admin", &entry, &sudo);
+                json_response(object!({ success: true, entry: entry })).status(StatusCode::OK)
+            }
             Err(err) => json_response(object!({ success: false, message: err.to_string() }))
                 .status(StatusCode::INTERNAL_SERVER_ERROR),
         }
@@ -102,10 +109,7 @@ endpoint! {
             return json_response(object!({ success: false, message: "Unauthorized" }))
                 .status(StatusCode::UNAUTHORIZED);
         }
-        if req.method() != POST {
-            return json_response(object!({ success: false, message: "Method not allowed" }))
-                .status(StatusCode::METHOD_NOT_ALLOWED);
-        }
+        methods!(req, POST);
 
         let entry = req
             .param("entry")
@@ -120,8 +124,16 @@ endpoint! {
             }
         };
 
+        let sudo = match sudo::require_sudo(req).await {
+            Ok(sudo) => sudo,
+            Err(response) => return response,
+        };
+
         match op::remove_admin_entry(&entry) {
-            Ok(()) => json_response(object!({ success: true })).status(StatusCode::OK),
+            Ok(()) => {
+                sudo::audit("revoke", "admin", &entry, &sudo);
+                json_response(object!({ success: true })).status(StatusCode::OK)
+            }
             Err(err) => json_response(object!({ success: false, message: err.to_string() }))
                 .status(StatusCode::INTERNAL_SERVER_ERROR),
         }
@@ -0,0 +1,170 @@
+use hotaru::http::*;
+use hotaru::prelude::*;
+use std::collections::BTreeMap;
+
+use crate::APP;
+use crate::admin::check_is_admin;
+use crate::analytics::{self, Day};
+use crate::op::{into_path_l, pageprop};
+
+/// Days shown when the request does not say
+const DEFAULT_DAYS: u64 = 7;
+
+/// Most days one report covers
+const MAX_DAYS: u64 = 90;
+
+/// Entries of each top list
+const TOP: usize = 20;
+
+/// The `days` query parameter, within `1..=MAX_DAYS`
+fn days(req: &mut HttpReqCtx) -> u64 {
+    req.query("days")
+        .and_then(|days| days.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_DAYS)
+        .clamp(1, MAX_DAYS)
+}
+
+/// The `TOP` largest counts of `map`, as `[{"key": ..., "count": ...}]`
+fn top(map: &BTreeMap<String, u64>) -> Value {
+    let mut entries: Vec<_> = map.iter().collect();
+    entries.sort_by_key(|(key, count)| (std::cmp::Reverse(**count), (*key).clone()));
+    Value::List(
+        entries
+            .into_iter()
+            .take(TOP)
+            .map(|(key, count)| object!({ key: key, count: *count }))
+            .collect(),
+    )
+}
+
+/// Exposures and conversions per variant, from the `experiment:variant:kind`
+/// keys of `Day::experiments`
+fn experiments(counts: &BTreeMap<String, u64>) -> Value {
+    let mut variants: BTreeMap<(&str, &str), (u64, u64)> = BTreeMap::new();
+    for (key, count) in counts {
+        let mut parts = key.rsplitn(3, ':');
+        let (Some(kind), Some(variant), Some(experiment)) = (parts.next(), parts.next(), parts.next()) else {
+            continue;
+        };
+        let entry = variants.entry((experiment, variant)).or_default();
+        match kind {
+            "exposure" => entry.0 += count,
+            "conversion" => entry.1 += count,
+            _ => {}
+        }
+    }
+    Value::List(
+        variants
+            .into_iter()
+            .map(|((experiment, variant), (exposures, conversions))| {
+                object!({
+                    experiment: experiment,
+                    variant: variant,
+                    exposures: exposures,
+                    conversions: conversions,
+                    // Per mille, templates have no number formatting
+                    rate: (conversions * 1000).checked_div(exposures).unwrap_or_default(),
+                })
+            })
+            .collect(),
+    )
+}
+
+/// The counts of the last `days` days
+///
+/// # Returns
+/// ```json
+/// {
+///     "days": [{ "date": "2026-10-16", "pageviews": 120, "visitors": 31 }],
+///     "totals": { "pageviews": 120, "visitors": 31 },
+///     "paths": [{ "key": "/docs", "count": 64 }],
+///     "referrers": [{ "key": "search.example", "count": 12 }],
+///     "locales": [{ "key": "en", "count": 100 }],
+///     "events": [{ "key": "signup", "count": 3 }],
+///     "experiments": [{ "experiment": "signup_button", "variant": "green", "exposures": 40, "conversions": 5, "rate": 125 }]
+/// }
+/// ```
+/// `days` is oldest first; the top lists cover the whole range.
+/// `visitors` of the total is the sum of the days. `rate` is conversions
+/// per thousand exposures.
+pub fn report(days: u64) -> Value {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+    let days = analytics::report(days, now);
+    let mut total = Day::default();
+    for day in &days {
+        total.merge(day, usize::MAX);
+    }
+    object!({
+        days: Value::List(
+            days.iter()
+                .map(|day| object!({ date: &day.date, pageviews: day.pageviews, visitors: day.visitors }))
+                .collect()
+        ),
+        totals: object!({ pageviews: total.pageviews, visitors: total.visitors }),
+        paths: top(&total.paths),
+        referrers: top(&total.referrers),
+        locales: top(&total.locales),
+        events: top(&total.events),
+        experiments: experiments(&total.experiments),
+    })
+}
+
+endpoint! {
+    APP.url("/admin/analytics"),
+
+    pub analytics_dashboard <HTTP> {
+        if !check_is_admin(req).await {
+            return redirect_response("/user/unauthorized");
+        }
+        let days = days(req);
+        let report = report(days);
+        let busiest = report
+            .get("days")
+            .list()
+            .iter()
+            .map(|day| day.get("pageviews").integer())
+            .max()
+            .unwrap_or_default()
+            .max(1);
+        let per_day: Vec<Value> = report
+            .get("days")
+            .list()
+            .into_iter()
+            .map(|mut day| {
+                let height = day.get("pageviews").integer() * 100 / busiest;
+                day.set("height", height);
+                day
+            })
+            .collect();
+        akari_render!(
+            "admin/analytics.html",
+            pageprop = pageprop(req, "Analytics", "Page views, referrers and languages"),
+            path = into_path_l(req, vec!["home", "admin"]),
+            enabled = analytics::settings().enabled,
+            range = days,
+            days = Value::List(per_day),
+            totals = report.get("totals").clone(),
+            paths = report.get("paths").clone(),
+            referrers = report.get("referrers").clone(),
+            locales = report.get("locales").clone(),
+            events = report.get("events").clone(),
+            experiments = report.get("experiments").clone()
+        )
+    }
+}
+
+endpoint! {
+    APP.url("/admin/analytics/json"),
+
+    /// GET /admin/analytics/json?days=7 - The counts of the last days, see `report`
+    /// Response: {"success": true, "days": [...], "totals": {...}, "paths": [...], ...}
+    pub analytics_json <HTTP> {
+        if !check_is_admin(req).await {
+            return json_response(object!({ success: false, message: "Unauthorized" }))
+                .status(StatusCode::UNAUTHORIZED);
+        }
+        let mut report = report(days(req));
+        report.set("success", true);
+        json_response(report)
+    }
+}
@@ -3,27 +3,224 @@ use hotaru::prelude::*;
 use tracing::{error, info, instrument};
 
 use crate::admin::check_is_admin;
+use crate::admin::jsonapi::{self, Format, Resource};
+use crate::admin::sudo;
+use crate::extract::{Extract, FromQuery, Page, Query, Rejection, parse_arg};
 use crate::local_auth::fop::UserStorage;
+use crate::user::client::UserEdit;
+use crate::user::logout;
 use crate::op;
 use crate::{
     APP,
-    local_auth::{LOCAL_AUTH, fop::FopError},
+    local_auth::{LOCAL_AUTH, age, fop::FopError},
 };
+use crate::methods;
 
-fn admin_user_json(uid: u32, user: &UserStorage) -> Value {
+fn admin_user_json(uid: u32, user: &UserStorage, sessions: usize) -> Value {
     let admin_entry = object!(format!("{}@local", uid));
-    object!({
+    let mut value = object!({
         uid: uid,
         username: &user.username,
+        display_name: user.display_name(),
         email: &user.email,
         is_active: user.is_active,
         is_admin: op::get_admin().contains(&admin_entry),
-    })
+        former_usernames: Value::new(user.former_usernames.iter().map(|former| former.into_json()).collect::<Vec<Value>>()),
+        pending_email: user.pending_email.as_ref().map(|pending| pending.public_json()).unwrap_or(Value::None),
+        emails: Value::new(user.emails.iter().map(|secondary| secondary.public_json()).collect::<Vec<Value>>()),
+        phone: user.phone.as_ref().map(|phone| phone.public_json()).unwrap_or(Value::None),
+        two_factor: crate::local_auth::second_factor::list_into_json(&user.two_factor),
+    });
+    add_activity(&mut value, user, sessions);
+    value
+}
+
+/// A local account as a JSON:API `users` resource, its sessions linked and,
+/// when `sessions` are given, identified
+fn user_resource(uid: u32, user: &UserStorage, count: usize, sessions: Option<&[Resource]>) -> Resource {
+    let data = sessions.map(|sessions| Value::List(sessions.iter().map(Resource::identifier).collect()));
+    Resource::new("users", uid, admin_user_json(uid, user, count))
+        .link(format!("/admin/users/{}", uid))
+        .relationship("sessions", format!("/admin/users/{}/sessions", uid), data, Some(object!({ count: count })))
+}
+
+/// The sessions of `uid` as JSON:API `sessions` resources
+async fn session_resources(uid: u32) -> Vec<Resource> {
+    LOCAL_AUTH
+        .admin_list_sessions(uid)
+        .await
+        .into_iter()
+        .map(|session| {
+            let mut attributes = object!({ created: session.started, expires: session.expires });
+            session.client.add_to(&mut attributes);
+            Resource::new("sessions", session.token, attributes).relationship(
+                "user",
+                format!("/admin/users/{}", uid),
+                Some(jsonapi::identifier("users", &uid.to_string())),
+                None,
+            )
+        })
+        .collect()
+}
+
+/// `users` of a page as JSON:API resources, and their sessions when the
+/// request includes them
+async fn user_resources(users: &[(u32, UserStorage)], include_sessions: bool) -> (Vec<Resource>, Vec<Resource>) {
+    let mut resources = Vec::with_capacity(users.len());
+    let mut included = Vec::new();
+    for (uid, user) in users {
+        if include_sessions {
+            let sessions = session_resources(*uid).await;
+            resources.push(user_resource(*uid, user, sessions.len(), Some(&sessions)));
+            included.extend(sessions);
+        } else {
+            resources.push(user_resource(*uid, user, LOCAL_AUTH.admin_session_count(*uid).await, None));
+        }
+    }
+    (resources, included)
+}
+
+/// Accounts without a login for this long are flagged as dormant
+pub const DORMANT_AFTER: u64 = 90 * 86_400;
+/// Accounts with this many wrong passwords since their last login are
+/// flagged as under attack
+pub const ATTACKED_AFTER: u32 = 5;
+
+/// Add the login activity of `user` to its admin JSON: `last_login` and
+/// `last_failed_login` (unix time or null), `last_login_ago`,
+/// `last_login_ip` and `last_login_location` (empty when unknown),
+/// `failed_logins`, `sessions`, and the `dormant` and `attacked` flags
+pub(crate) fn add_activity(value: &mut Value, user: &UserStorage, sessions: usize) {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+    let activity = &user.activity;
+    let time = |time: Option<u64>| time.map(Value::from).unwrap_or(Value::None);
+    value.set("last_login", time(activity.last_login));
+    value.set("last_login_ago", ago(activity.last_login, now));
+    value.set("last_login_ip", activity.last_login_ip.clone().unwrap_or_default());
+    value.set(
+        "last_login_location",
+        activity.last_login_location.as_ref().map(|location| location.to_string()).unwrap_or_default(),
+    );
+    value.set("failed_logins", activity.failed_logins);
+    value.set("last_failed_login", time(activity.last_failed_login));
+    value.set("sessions", sessions);
+    value.set("dormant", activity.last_login.is_none_or(|time| now.saturating_sub(time) > DORMANT_AFTER));
+    value.set("attacked", activity.failed_logins >= ATTACKED_AFTER);
+}
+
+/// How long before `now` the unix time `time` was, roughly
+fn ago(time: Option<u64>, now: u64) -> String {
+    let Some(time) = time else { return "never".to_string() };
+    match now.saturating_sub(time) {
+        secs if secs < 60 => "just now".to_string(),
+        secs if secs < 3_600 => format!("{} min ago", secs / 60),
+        secs if secs < 86_400 => format!("{} h ago", secs / 3_600),
+        secs => format!("{} d ago", secs / 86_400),
+    }
+}
+
+/// Columns the user list can be sorted by
+pub const SORT_KEYS: &[&str] = &["uid", "username", "email", "is_active", "last_login", "failed_logins"];
+/// Page sizes offered by the panel
+pub const PER_PAGE_CHOICES: &[usize] = &[10, 25, 50, 100];
+
+/// Search, sort and page parameters of `GET /admin/users`:
+/// `?q=&sort=uid|username|email|is_active|last_login|failed_logins&order=asc|desc&page=&per_page=`
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserQuery {
+    /// Case-insensitive part of the username or email, or an exact uid
+    pub search: String,
+    pub sort: String,
+    pub descending: bool,
+    /// 1-based
+    pub page: usize,
+    pub per_page: usize,
+}
+
+impl UserQuery {
+    pub fn from_request(req: &mut HttpReqCtx) -> Self {
+        Self::parse(|key| req.query(key))
+    }
+
+    /// Like [`FromQuery`], falling back to the first page for a page or
+    /// page size that does not parse
+    fn parse(mut get: impl FnMut(&str) -> Option<String>) -> Self {
+        let page = Page::from_query(&mut Query::new(&mut get)).unwrap_or_default();
+        Self::with_page(&mut Query::new(&mut get), page)
+    }
+
+    fn with_page(query: &mut Query, page: Page) -> Self {
+        let sort = query.text("sort").filter(|sort| SORT_KEYS.contains(&sort.as_str()));
+        Self {
+            search: query.text("q").map(|q| q.trim().to_string()).unwrap_or_default(),
+            sort: sort.unwrap_or_else(|| SORT_KEYS[0].to_string()),
+            descending: query.text("order").as_deref() == Some("desc"),
+            page: page.page,
+            per_page: page.per_page,
+        }
+    }
+
+    /// The parameters as a query string, without the leading `?`
+    pub fn to_query_string(&self) -> String {
+        let mut query = format!(
+            "page={}&per_page={}&sort={}&order={}",
+            self.page,
+            self.per_page,
+            self.sort,
+            if self.descending { "desc" } else { "asc" }
+        );
+        if !self.search.is_empty() {
+            query.push_str("&q=");
+            query.push_str(&hotaru_lib::url_encoding::encode_url_owned(&self.search));
+        }
+        query
+    }
+
+    /// Number of pages for `total` matches, at least 1
+    pub fn pages(&self, total: usize) -> usize {
+        total.div_ceil(self.per_page).max(1)
+    }
+
+    /// Filter, sort and slice `users`. Returns the page and the number of
+    /// matching users.
+    pub fn apply(&self, users: Vec<(u32, UserStorage)>) -> (Vec<(u32, UserStorage)>, usize) {
+        let needle = self.search.to_lowercase();
+        let mut users: Vec<(u32, UserStorage)> = users
+            .into_iter()
+            .filter(|(uid, user)| {
+                needle.is_empty()
+                    || uid.to_string() == needle
+                    || user.username.to_lowercase().contains(&needle)
+                    || user.email.to_lowercase().contains(&needle)
+            })
+            .collect();
+        match self.sort.as_str() {
+            "username" => users.sort_by_key(|(_, user)| user.username.to_lowercase()),
+            "email" => users.sort_by_key(|(_, user)| user.email.to_lowercase()),
+            "is_active" => users.sort_by_key(|(_, user)| user.is_active),
+            "last_login" => users.sort_by_key(|(_, user)| user.activity.last_login),
+            "failed_logins" => users.sort_by_key(|(_, user)| user.activity.failed_logins),
+            _ => users.sort_by_key(|(uid, _)| *uid),
+        }
+        if self.descending {
+            users.reverse();
+        }
+        let total = users.len();
+        let start = (self.page.min(self.pages(total)) - 1) * self.per_page;
+        (users.into_iter().skip(start).take(self.per_page).collect(), total)
+    }
+}
+
+impl FromQuery for UserQuery {
+    fn from_query(query: &mut Query) -> Result<Self, Rejection> {
+        let page = Page::from_query(query)?;
+        Ok(Self::with_page(query, page))
+    }
 }
 
 fn admin_error_status(error: &FopError) -> StatusCode {
     match error {
-        FopError::UserNameConflict | FopError::EmailConflict => StatusCode::CONFLICT,
+        FopError::UserNameConflict | FopError::UserNameReserved | FopError::EmailConflict => StatusCode::CONFLICT,
         FopError::UserNameNotValid | FopError::EmailNotValid | FopError::PasswordMismatch => {
             StatusCode::BAD_REQUEST
         }
@@ -38,23 +235,58 @@ endpoint! {
 
     #[instrument(level = "info", skip(req))]
     pub admin_users <HTTP> {
+        let Some(format) = Format::of(req) else {
+            return jsonapi::not_acceptable();
+        };
         if !check_is_admin(req).await {
-            return json_response(object!({ success: false, message: "Unauthorized" }))
-                .status(StatusCode::UNAUTHORIZED);
+            return format.error(StatusCode::UNAUTHORIZED, "Unauthorized");
         }
 
-        match req.method() {
+        methods!(req, {
             GET => {
                 info!(path = %req.path(), "list_admin_users handler start");
-                let users: Vec<Value> = LOCAL_AUTH
-                    .admin_list_users()
-                    .await
-                    .into_iter()
-                    .map(|(uid, user)| admin_user_json(uid, &user))
-                    .collect();
-                let total = users.len();
-                json_response(object!({ success: true, users: users, total: total }))
-                    .status(StatusCode::OK)
+                let query = match UserQuery::from_query(&mut Query::new(&mut |key| req.query(key))) {
+                    Ok(query) => query,
+                    Err(rejection) => return format.error(StatusCode::BAD_REQUEST, &rejection.to_string()),
+                };
+                let (page, total) = query.apply(LOCAL_AUTH.admin_list_users().await);
+                let pages = query.pages(total);
+                if format == Format::JsonApi {
+                    let include = req.query_arg::<String>("include").ok().flatten();
+                    let include_sessions = jsonapi::includes(include.as_deref(), "sessions");
+                    let (users, included) = user_resources(&page, include_sessions).await;
+                    let link = |page: usize| {
+                        let query = UserQuery { page, ..query.clone() }.to_query_string();
+                        match include_sessions {
+                            true => format!("/admin/users?{}&include=sessions", query),
+                            false => format!("/admin/users?{}", query),
+                        }
+                    };
+                    return jsonapi::Document::many(users)
+                        .include(included)
+                        .links(jsonapi::page_links(query.page, pages, link))
+                        .meta(object!({ total: total, pages: pages, page: query.page.min(pages), per_page: query.per_page }))
+                        .into_response()
+                        .add_header("Vary", "Accept");
+                }
+                let mut users: Vec<Value> = Vec::with_capacity(page.len());
+                for (uid, user) in &page {
+                    let sessions = LOCAL_AUTH.admin_session_count(*uid).await;
+                    users.push(admin_user_json(*uid, user, sessions));
+                }
+                json_response(object!({
+                    success: true,
+                    users: users,
+                    total: total,
+                    page: query.page.min(pages),
+                    pages: pages,
+                    per_page: query.per_page,
+                    sort: &query.sort,
+                    order: if query.descending { "desc" } else { "asc" },
+                    q: &query.search,
+                }))
+                .status(StatusCode::OK)
+                .add_header("Vary", "Accept")
             }
             POST => {
                 info!(path = %req.path(), "create_admin_user handler start");
@@ -62,7 +294,14 @@ endpoint! {
                 let username = form.get_or_default("username");
                 let password = form.get_or_default("password");
                 let email = form.get_or_default("email");
-                match LOCAL_AUTH.register_user(&username, &email, &password).await {
+                let profile = match age::check(form.get_or_default(age::BIRTH_FIELD)) {
+                    Ok(profile) => profile,
+                    Err(err) => {
+                        return json_response(object!({ success: false, message: err.to_string() }))
+                            .status(StatusCode::BAD_REQUEST);
+                    }
+                };
+                match LOCAL_AUTH.register_user_with_profile(&username, &email, &password, profile).await {
                     Ok(()) => json_response(object!({ success: true, username: username }))
                         .status(StatusCode::CREATED),
                     Err(e) => {
@@ -75,9 +314,7 @@ endpoint! {
                     }
                 }
             }
-            _ => json_response(object!({ success: false, message: "Method not allowed" }))
-                .status(StatusCode::METHOD_NOT_ALLOWED),
-        }
+        })
     }
 }
 
@@ -86,53 +323,44 @@ endpoint! {
 
     #[instrument(level = "info", skip(req))]
     pub admin_user_detail <HTTP> {
+        let Some(format) = Format::of(req) else {
+            return jsonapi::not_acceptable();
+        };
         if !check_is_admin(req).await {
-            return json_response(object!({ success: false, message: "Unauthorized" }))
-                .status(StatusCode::UNAUTHORIZED);
+            return format.error(StatusCode::UNAUTHORIZED, "Unauthorized");
         }
 
-        let uid = match req.param("uid").and_then(|uid| uid.parse::<u32>().ok()) {
-            Some(uid) => uid,
-            None => {
-                return json_response(object!({ success: false, message: "Invalid uid" }))
-                    .status(StatusCode::BAD_REQUEST);
-            }
+        let uid = match parse_arg::<u32>("uid", &req.param("uid").unwrap_or_default()) {
+            Ok(uid) => uid,
+            Err(rejection) => return format.error(StatusCode::BAD_REQUEST, &rejection.to_string()),
         };
 
-        match req.method() {
+        methods!(req, {
             GET => {
-                match LOCAL_AUTH.admin_get_user(uid).await {
-                    Some(user) => json_response(object!({
-                        success: true,
-                        user: admin_user_json(uid, &user),
-                    })).status(StatusCode::OK),
-                    None => json_response(object!({ success: false, message: "User not found" }))
-                        .status(StatusCode::NOT_FOUND),
+                let Some(user) = LOCAL_AUTH.admin_get_user(uid).await else {
+                    return format.error(StatusCode::NOT_FOUND, "User not found");
+                };
+                if format == Format::JsonApi {
+                    let include = req.query_arg::<String>("include").ok().flatten();
+                    let (mut users, included) = user_resources(&[(uid, user)], jsonapi::includes(include.as_deref(), "sessions")).await;
+                    return jsonapi::Document::one(users.remove(0)).include(included).into_response().add_header("Vary", "Accept");
                 }
+                json_response(object!({
+                    success: true,
+                    user: admin_user_json(uid, &user, LOCAL_AUTH.admin_session_count(uid).await),
+                }))
+                .status(StatusCode::OK)
+                .add_header("Vary", "Accept")
             }
             POST => {
-                let form = req.form_or_default().await.clone();
-                let username = form
-                    .get("username")
-                    .cloned()
-                    .filter(|value| !value.is_empty());
-                let email = form
-                    .get("email")
-                    .cloned()
-                    .filter(|value| !value.is_empty());
-                let is_active = form.get("is_active").map(|raw| {
-                    matches!(raw.as_str(), "1" | "true" | "on" | "yes")
-                });
-
-                match LOCAL_AUTH.admin_edit_user(uid, username, email, is_active).await {
+                let edit = UserEdit::from_form(req.form_or_default().await);
+                match LOCAL_AUTH.admin_edit_user(uid, edit.username, edit.email, edit.is_active).await {
                     Ok(()) => json_response(object!({ success: true })).status(StatusCode::OK),
                     Err(e) => json_response(object!({ success: false, message: e.to_string() }))
                         .status(admin_error_status(&e)),
                 }
             }
-            _ => json_response(object!({ success: false, message: "Method not allowed" }))
-                .status(StatusCode::METHOD_NOT_ALLOWED),
-        }
+        })
     }
 }
 
@@ -145,23 +373,24 @@ endpoint! {
             return json_response(object!({ success: false, message: "Unauthorized" }))
                 .status(StatusCode::UNAUTHORIZED);
         }
-        if req.method() != POST {
-            return json_response(object!({ success: false, message: "Method not allowed" }))
-                .status(StatusCode::METHOD_NOT_ALLOWED);
-        }
+        methods!(req, POST);
 
-        let uid = match req.param("uid").and_then(|uid| uid.parse::<u32>().ok()) {
-            Some(uid) => uid,
-            None => {
-                return json_response(object!({ success: false, message: "Invalid uid" }))
-                    .status(StatusCode::BAD_REQUEST);
-            }
+        let uid = match req.path_arg::<u32>("uid") {
+            Ok(uid) => uid,
+            Err(res) => return res,
+        };
+        let sudo = match sudo::require_sudo(req).await {
+            Ok(sudo) => sudo,
+            Err(response) => return response,
         };
         let form = req.form_or_default().await.clone();
         let new_password = form.get_or_default("new_password");
 
         match LOCAL_AUTH.admin_reset_password(uid, &new_password).await {
-            Ok(()) => json_response(object!({ success: true })).status(StatusCode::OK),
+            Ok(()) => {
+                sudo::audit("reset_password", "user", &format!("{}@local", uid), &sudo);
+                json_response(object!({ success: true })).status(StatusCode::OK)
+            }
             Err(e) => json_response(object!({ success: false, message: e.to_string() }))
                 .status(admin_error_status(&e)),
         }
@@ -177,23 +406,155 @@ endpoint! {
             return json_response(object!({ success: false, message: "Unauthorized" }))
                 .status(StatusCode::UNAUTHORIZED);
         }
-        if req.method() != POST {
-            return json_response(object!({ success: false, message: "Method not allowed" }))
-                .status(StatusCode::METHOD_NOT_ALLOWED);
-        }
+        methods!(req, POST);
 
-        let uid = match req.param("uid").and_then(|uid| uid.parse::<u32>().ok()) {
-            Some(uid) => uid,
-            None => {
-                return json_response(object!({ success: false, message: "Invalid uid" }))
-                    .status(StatusCode::BAD_REQUEST);
-            }
+        let uid = match req.path_arg::<u32>("uid") {
+            Ok(uid) => uid,
+            Err(res) => return res,
+        };
+        let sudo = match sudo::require_sudo(req).await {
+            Ok(sudo) => sudo,
+            Err(response) => return response,
         };
 
         match LOCAL_AUTH.admin_delete_user(uid).await {
-            Ok(()) => json_response(object!({ success: true })).status(StatusCode::OK),
+            Ok(()) => {
+                sudo::audit("delete", "user", &format!("{}@local", uid), &sudo);
+                json_response(object!({ success: true })).status(StatusCode::OK)
+            }
             Err(e) => json_response(object!({ success: false, message: e.to_string() }))
                 .status(admin_error_status(&e)),
         }
     }
 }
+
+endpoint! {
+    APP.url("/admin/users/<uid>/sessions"),
+
+    #[instrument(level = "info", skip(req))]
+    pub admin_user_sessions <HTTP> {
+        let Some(format) = Format::of(req) else {
+            return jsonapi::not_acceptable();
+        };
+        if !check_is_admin(req).await {
+            return format.error(StatusCode::UNAUTHORIZED, "Unauthorized");
+        }
+
+        let uid = match parse_arg::<u32>("uid", &req.param("uid").unwrap_or_default()) {
+            Ok(uid) => uid,
+            Err(rejection) => return format.error(StatusCode::BAD_REQUEST, &rejection.to_string()),
+        };
+        if format == Format::JsonApi {
+            return jsonapi::Document::many(session_resources(uid).await)
+                .links(jsonapi::self_link(format!("/admin/users/{}/sessions", uid)))
+                .into_response()
+                .add_header("Vary", "Accept");
+        }
+        let sessions: Vec<Value> = LOCAL_AUTH
+            .admin_list_sessions(uid)
+            .await
+            .into_iter()
+            .map(|session| {
+                let mut value = object!({ token: session.token, created: session.started, expires: session.expires });
+                session.client.add_to(&mut value);
+                value
+            })
+            .collect();
+        json_response(object!({ success: true, sessions: sessions })).status(StatusCode::OK).add_header("Vary", "Accept")
+    }
+}
+
+endpoint! {
+    APP.url("/admin/users/<uid>/sessions/revoke"),
+
+    #[instrument(level = "info", skip(req))]
+    pub admin_user_revoke_sessions <HTTP> {
+        if !check_is_admin(req).await {
+            return json_response(object!({ success: false, message: "Unauthorized" }))
+                .status(StatusCode::UNAUTHORIZED);
+        }
+        methods!(req, POST);
+
+        let uid = match req.path_arg::<u32>("uid") {
+            Ok(uid) => uid,
+            Err(res) => return res,
+        };
+        let revoked = LOCAL_AUTH.admin_revoke_sessions(uid).await;
+        logout::sessions_ended(uid);
+        info!(uid, revoked, "revoked sessions");
+        json_response(object!({ success: true, revoked: revoked })).status(StatusCode::OK)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(name: &str, active: bool) -> UserStorage {
+        UserStorage {
+            username: name.to_string(),
+            display_name: String::new(),
+            email: format!("{}@example.com", name.to_lowercase()),
+            password_hash: String::new(),
+            password_salt: String::new(),
+            profile: Value::None,
+            is_active: active,
+            activity: Default::default(),
+            former_usernames: Vec::new(),
+            pending_email: None,
+            emails: Vec::new(),
+            phone: None,
+            two_factor: Vec::new(),
+            backup_codes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn user_query_filters_sorts_and_pages() {
+        let users = vec![(1, user("Admin", true)), (2, user("bob", false)), (3, user("Carol", true)), (12, user("dave", true))];
+        let params = |pairs: &[(&str, &str)]| {
+            let pairs: Vec<(String, String)> = pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            UserQuery::parse(move |key| pairs.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone()))
+        };
+
+        let query = params(&[("sort", "username"), ("order", "desc"), ("per_page", "2"), ("page", "2")]);
+        let (page, total) = query.apply(users.clone());
+        assert_eq!(total, 4);
+        assert_eq!(page.iter().map(|(uid, _)| *uid).collect::<Vec<_>>(), vec![2, 1]);
+
+        // Search matches parts of names and emails, and whole uids only
+        assert_eq!(params(&[("q", "CAR")]).apply(users.clone()).1, 1);
+        assert_eq!(params(&[("q", "1")]).apply(users.clone()).1, 1);
+        assert_eq!(params(&[("q", "example")]).apply(users.clone()).1, 4);
+
+        // Out-of-range values fall back instead of failing
+        let query = params(&[("sort", "password_hash"), ("page", "9"), ("per_page", "0")]);
+        assert_eq!((query.sort.as_str(), query.per_page), ("uid", 1));
+        assert_eq!(query.apply(users).0[0].0, 12);
+        assert_eq!(params(&[("q", "a b")]).to_query_string(), "page=1&per_page=10&sort=uid&order=asc&q=a%20b");
+    }
+
+    #[test]
+    fn activity_flags_dormant_and_attacked_accounts() {
+        assert_eq!(ago(None, 1_000), "never");
+        assert_eq!(ago(Some(990), 1_000), "just now");
+        assert_eq!(ago(Some(1_000), 8_200), "2 h ago");
+        assert_eq!(ago(Some(0), 3 * 86_400 + 5), "3 d ago");
+
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        let mut recent = user("Admin", true);
+        recent.activity.last_login = Some(now - 60);
+        recent.activity.failed_logins = ATTACKED_AFTER;
+        let mut value = object!({});
+        add_activity(&mut value, &recent, 2);
+        assert!(!value.get("dormant").boolean());
+        assert!(value.get("attacked").boolean());
+        assert_eq!(value.get("sessions").integer(), 2);
+
+        let mut value = object!({});
+        add_activity(&mut value, &user("bob", true), 0);
+        assert!(value.get("dormant").boolean());
+        assert!(!value.get("attacked").boolean());
+        assert_eq!(value.get("last_login_ago").string(), "never");
+    }
+}
@@ -0,0 +1,79 @@
+use hotaru::http::*;
+use hotaru::prelude::*;
+
+use crate::APP;
+use crate::admin::{check_is_admin, sudo};
+use crate::backup;
+use crate::op::{into_path_l, pageprop};
+use crate::methods;
+
+endpoint! {
+    APP.url("/admin/backups"),
+
+    pub panel_backups <HTTP> {
+        if !check_is_admin(req).await {
+            return redirect_response("/user/unauthorized");
+        }
+        let settings = backup::settings();
+        let backups: Vec<Value> = backup::list(&settings.dir)
+            .iter()
+            .map(|b| object!({ name: &b.name, size_kb: b.size.div_ceil(1024) }))
+            .collect();
+        let schedule = match settings.interval {
+            Some(interval) => format!("every {} seconds, keeping {}", interval.as_secs(), settings.keep),
+            None => "off".to_string(),
+        };
+        akari_render!(
+            "admin/backups.html",
+            pageprop = pageprop(req, "Backups", "Archives of programfiles and uploads"),
+            path = into_path_l(req, vec!["home", "admin"]),
+            backups = Value::List(backups),
+            schedule = schedule,
+            audit_title = "Restores",
+            audit = Value::List(sudo::history("backup", "", 20)),
+        )
+    }
+}
+
+endpoint! {
+    APP.url("/admin/backups/create"),
+
+    pub create_backup <HTTP> {
+        if !check_is_admin(req).await {
+            return json_response(object!({ success: false, message: "Unauthorized" }))
+                .status(StatusCode::UNAUTHORIZED);
+        }
+        methods!(req, POST);
+        match backup::snapshot().await {
+            Ok(path) => {
+                let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+                json_response(object!({ success: true, name: name }))
+            }
+            Err(err) => {
+                tracing::error!(%err, "Backup from the admin panel failed");
+                json_response(object!({ success: false, message: err }))
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+}
+
+endpoint! {
+    APP.url("/admin/backups/download/<name>"),
+
+    pub download_backup <HTTP> {
+        if !check_is_admin(req).await {
+            return redirect_response("/user/unauthorized");
+        }
+        let name = req.param("name").unwrap_or_default();
+        if !backup::is_backup_name(&name) {
+            return text_response("Not Found").status(StatusCode::NOT_FOUND);
+        }
+        match tokio::fs::read(backup::settings().dir.join(&name)).await {
+            Ok(bytes) => normal_response(StatusCode::OK, bytes)
+                .content_type(HttpContentType::from_str("application/gzip"))
+                .add_header("Content-Disposition", format!("attachment; filename=\"{}\"", name)),
+            Err(_) => text_response("Not Found").status(StatusCode::NOT_FOUND),
+        }
+    }
+}
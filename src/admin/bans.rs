@@ -0,0 +1,146 @@
+use hotaru::http::*;
+use hotaru::prelude::*;
+
+use crate::APP;
+use crate::admin::{check_is_admin, local_token_admin};
+use crate::bans::{self, BanError};
+use crate::local_auth::analyze::get_auth_token;
+use crate::op::{into_path_l, pageprop};
+use crate::proxy;
+use crate::user::fetch::get_user_id;
+use crate::methods;
+
+/// How many audit entries the page and the JSON list show
+const HISTORY: usize = 50;
+
+/// The admin entry of the caller, for the audit trail
+pub(crate) async fn admin_entry(req: &mut HttpReqCtx) -> String {
+    if let Some(token) = get_auth_token(req)
+        && let Some((uid, _)) = local_token_admin(&token).await
+    {
+        return format!("{}@local", uid);
+    }
+    get_user_id(req).await.to_string()
+}
+
+/// The `duration` field in seconds; empty or `0` for a ban until lifted
+pub(crate) fn duration(form: &UrlEncodedForm) -> Result<Option<u64>, ()> {
+    match form.get_or_default("duration").trim() {
+        "" | "0" => Ok(None),
+        seconds => seconds.parse::<u64>().map(Some).map_err(|_| ()),
+    }
+}
+
+fn ban_error(err: BanError) -> HttpResponse {
+    let status = match err {
+        BanError::NotFound => StatusCode::NOT_FOUND,
+        BanError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        BanError::InvalidNetwork | BanError::OwnAddress => StatusCode::BAD_REQUEST,
+    };
+    json_response(object!({ success: false, message: err.to_string() })).status(status)
+}
+
+fn unauthorized() -> HttpResponse {
+    json_response(object!({ success: false, message: "Unauthorized" })).status(StatusCode::UNAUTHORIZED)
+}
+
+endpoint! {
+    APP.url("/admin/bans"),
+
+    /// GET: the list of bans and the audit trail
+    /// POST: Form -> network, reason, duration (seconds, empty for a ban until lifted)
+    /// Response: {"success": true, "ban": {...}} or {"success": false, "message": "..."}
+    pub admin_bans <HTTP> {
+        methods!(req, {
+            GET => {
+                if !check_is_admin(req).await {
+                    return redirect_response("/user/unauthorized");
+                }
+                akari_render!(
+                    "admin/bans.html",
+                    pageprop = pageprop(req, "Bans", "Banned addresses and networks"),
+                    path = into_path_l(req, vec!["home", "admin"]),
+                    bans = Value::List(bans::list().iter().map(bans::Ban::into_json).collect()),
+                    history = Value::List(bans::history(HISTORY))
+                )
+            }
+            POST => {
+                if !check_is_admin(req).await {
+                    return unauthorized();
+                }
+                let by = admin_entry(req).await;
+                let form = req.form_or_default().await.clone();
+                let Ok(duration) = duration(&form) else {
+                    return json_response(object!({ success: false, message: "Invalid duration" }))
+                        .status(StatusCode::BAD_REQUEST);
+                };
+                let from = proxy::client_ip(req);
+                match bans::add(form.get_or_default("network"), form.get_or_default("reason"), &by, duration, from) {
+                    Ok(ban) => json_response(object!({ success: true, ban: ban.into_json() })),
+                    Err(err) => ban_error(err),
+                }
+            }
+        })
+    }
+}
+
+endpoint! {
+    APP.url("/admin/bans/json"),
+
+    /// GET /admin/bans/json - The active bans and the latest audit entries
+    /// Response: {"success": true, "bans": [...], "history": [...]}
+    pub admin_bans_json <HTTP> {
+        if !check_is_admin(req).await {
+            return unauthorized();
+        }
+        json_response(object!({
+            success: true,
+            bans: Value::List(bans::list().iter().map(bans::Ban::into_json).collect()),
+            history: Value::List(bans::history(HISTORY)),
+        }))
+    }
+}
+
+endpoint! {
+    APP.url("/admin/bans/<id>"),
+
+    /// POST /admin/bans/<id> - Change the reason and duration of a ban
+    /// Form -> reason, duration (seconds from now, empty for a ban until lifted)
+    pub admin_ban_update <HTTP> {
+        if !check_is_admin(req).await {
+            return unauthorized();
+        }
+        methods!(req, POST);
+        let id = req.param("id").unwrap_or_default();
+        let by = admin_entry(req).await;
+        let form = req.form_or_default().await.clone();
+        let Ok(duration) = duration(&form) else {
+            return json_response(object!({ success: false, message: "Invalid duration" }))
+                .status(StatusCode::BAD_REQUEST);
+        };
+        match bans::update(&id, form.get_or_default("reason"), duration, &by) {
+            Ok(ban) => json_response(object!({ success: true, ban: ban.into_json() })),
+            Err(err) => ban_error(err),
+        }
+    }
+}
+
+endpoint! {
+    APP.url("/admin/bans/<id>/delete"),
+
+    /// POST /admin/bans/<id>/delete - Lift a ban
+    /// Form -> reason, kept in the audit trail (e.g. "appeal accepted")
+    pub admin_ban_delete <HTTP> {
+        if !check_is_admin(req).await {
+            return unauthorized();
+        }
+        methods!(req, POST);
+        let id = req.param("id").unwrap_or_default();
+        let by = admin_entry(req).await;
+        let reason = req.form_or_default().await.get_or_default("reason");
+        match bans::remove(&id, &by, reason) {
+            Ok(_) => json_response(object!({ success: true })),
+            Err(err) => ban_error(err),
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use hotaru::http::*;
+use hotaru::prelude::*;
+
+use crate::APP;
+use crate::admin::bans::admin_entry;
+use crate::admin::check_is_admin;
+use crate::blog::{self, BlogError, Post, PostDraft, PostStatus};
+use crate::op::{self, into_path_l, pageprop};
+use crate::methods;
+
+fn unauthorized() -> HttpResponse {
+    json_response(object!({ success: false, message: "Unauthorized" })).status(StatusCode::UNAUTHORIZED)
+}
+
+/// The answer to a change of a post
+fn changed(result: Result<Post, BlogError>) -> HttpResponse {
+    match result {
+        Ok(post) => json_response(object!({ success: true, post: post.into_json() })),
+        Err(err) => {
+            let status = match err {
+                BlogError::NotFound => StatusCode::NOT_FOUND,
+                BlogError::Exists => StatusCode::CONFLICT,
+                BlogError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                _ => StatusCode::BAD_REQUEST,
+            };
+            json_response(object!({ success: false, message: err.to_string() })).status(status)
+        }
+    }
+}
+
+endpoint! {
+    APP.url("/admin/blog"),
+
+    /// GET: every post, drafts first, with buttons to publish, edit or delete it
+    pub admin_blog <HTTP> {
+        if !check_is_admin(req).await {
+            return redirect_response("/user/unauthorized");
+        }
+        let mut posts = blog::posts(true);
+        posts.sort_by_key(|post| post.is_published());
+        let items: Vec<Value> = posts.iter().map(blog::entry).collect();
+        akari_render!(
+            "admin/blog.html",
+            pageprop = pageprop(req, "Blog", "Blog posts"),
+            path = into_path_l(req, vec!["home", "admin"]),
+            items = Value::List(items)
+        )
+    }
+}
+
+endpoint! {
+    APP.url("/admin/blog/edit"),
+
+    /// GET /admin/blog/edit?slug=<slug> - The editor, empty for a new post
+    pub admin_blog_edit <HTTP> {
+        if !check_is_admin(req).await {
+            return redirect_response("/user/unauthorized");
+        }
+        let slug = req.query("slug").unwrap_or_default();
+        let post = match slug.as_str() {
+            "" => object!({ new: true, slug: "", title: "", tags: "", summary: "", body: "", status: "draft" }),
+            slug => match blog::get(slug) {
+                Some(post) => object!({
+                    new: false,
+                    slug: &post.slug,
+                    title: op::escape_html(&post.title),
+                    tags: post.tags.join(", "),
+                    summary: op::escape_html(&post.summary),
+                    body: op::escape_html(&post.body),
+                    status: post.status.as_str(),
+                }),
+                None => return redirect_response("/admin/blog"),
+            },
+        };
+        akari_render!(
+            "admin/blog_edit.html",
+            pageprop = pageprop(req, "Edit post", "Blog post editor"),
+            path = into_path_l(req, vec!["home", "admin", "edit"]),
+            post = post
+        )
+    }
+}
+
+endpoint! {
+    APP.url("/admin/blog/json"),
+
+    /// GET /admin/blog/json - Every post without its text, newest first
+    /// Response: {"success": true, "posts": [{"slug": ..., "title": ..., "author": ..., "tags": [...], "status": ..., "published": ..., "updated": ...}]}
+    pub admin_blog_json <HTTP> {
+        if !check_is_admin(req).await {
+            return unauthorized();
+        }
+        let posts: Vec<Value> = blog::posts(true).iter().map(Post::into_json).collect();
+        json_response(object!({ success: true, posts: posts }))
+    }
+}
+
+endpoint! {
+    APP.url("/admin/blog/save"),
+
+    /// POST /admin/blog/save - Make or change a post. New posts are drafts.
+    /// Form -> original (slug of the edited post, empty for a new one), slug, title, tags, summary, body
+    pub admin_blog_save <HTTP> {
+        if !check_is_admin(req).await {
+            return unauthorized();
+        }
+        methods!(req, POST);
+        let author = admin_entry(req).await;
+        let author_name = op::get_user(req).await.get_username().to_string();
+        let form = req.form_or_default().await;
+        let draft = PostDraft {
+            original: form.get_or_default("original").clone(),
+            slug: form.get_or_default("slug").clone(),
+            title: form.get_or_default("title").clone(),
+            tags: form.get_or_default("tags").clone(),
+            summary: form.get_or_default("summary").clone(),
+            body: form.get_or_default("body").clone(),
+        };
+        changed(blog::save(draft, &author, &author_name))
+    }
+}
+
+endpoint! {
+    APP.url("/admin/blog/<slug>/publish"),
+
+    /// POST /admin/blog/<slug>/publish - Put a post on the blog and in the feed
+    pub admin_blog_publish <HTTP> {
+        if !check_is_admin(req).await {
+            return unauthorized();
+        }
+        methods!(req, POST);
+        let slug = req.param("slug").unwrap_or_default();
+        changed(blog::set_status(&slug, PostStatus::Published))
+    }
+}
+
+endpoint! {
+    APP.url("/admin/blog/<slug>/unpublish"),
+
+    /// POST /admin/blog/<slug>/unpublish - Take a post back to a draft
+    pub admin_blog_unpublish <HTTP> {
+        if !check_is_admin(req).await {
+            return unauthorized();
+        }
+        methods!(req, POST);
+        let slug = req.param("slug").unwrap_or_default();
+        changed(blog::set_status(&slug, PostStatus::Draft))
+    }
+}
+
+endpoint! {
+    APP.url("/admin/blog/<slug>/delete"),
+
+    /// POST /admin/blog/<slug>/delete - Remove a post and its file
+    pub admin_blog_delete <HTTP> {
+        if !check_is_admin(req).await {
+            return unauthorized();
+        }
+        methods!(req, POST);
+        let slug = req.param("slug").unwrap_or_default();
+        changed(blog::remove(&slug))
+    }
+}
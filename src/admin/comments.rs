@@ -0,0 +1,121 @@
+use hotaru::http::*;
+use hotaru::prelude::*;
+
+use crate::APP;
+use crate::admin::bans::admin_entry;
+use crate::moderation;
+use crate::comments::{self, Comment, CommentError, CommentStatus};
+use crate::op::{into_path_l, pageprop};
+use crate::methods;
+
+/// `status` of the query: `pending` unless one of the statuses or `all`
+fn status_filter(req: &mut HttpReqCtx) -> (String, Option<CommentStatus>) {
+    let status = req.query("status").unwrap_or_default();
+    match status.as_str() {
+        "all" => (status, None),
+        _ => match CommentStatus::from_string(&status) {
+            Some(filter) => (status, Some(filter)),
+            None => ("pending".to_string(), Some(CommentStatus::Pending)),
+        },
+    }
+}
+
+fn unauthorized() -> HttpResponse {
+    json_response(object!({ success: false, message: "Unauthorized" })).status(StatusCode::UNAUTHORIZED)
+}
+
+/// The answer to a moderation action
+fn moderated(result: Result<Comment, CommentError>) -> HttpResponse {
+    match result {
+        Ok(comment) => json_response(object!({ success: true, comment: comment.into_json() })),
+        Err(err) => {
+            let status = match err {
+                CommentError::NotFound => StatusCode::NOT_FOUND,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            json_response(object!({ success: false, message: err.to_string() })).status(status)
+        }
+    }
+}
+
+endpoint! {
+    APP.url("/admin/comments"),
+
+    /// GET: the moderation queue, `?status=approved|rejected|all` for the rest
+    pub admin_comments <HTTP> {
+        if !moderation::is_moderator(req).await {
+            return redirect_response("/user/unauthorized");
+        }
+        let (status, filter) = status_filter(req);
+        let items: Vec<Value> = comments::list(filter).iter().map(comments::entry).collect();
+        let pending = comments::list(Some(CommentStatus::Pending)).len();
+        akari_render!(
+            "admin/comments.html",
+            pageprop = pageprop(req, "Comments", "Comment moderation"),
+            path = into_path_l(req, vec!["home", "admin"]),
+            items = Value::List(items),
+            status = status,
+            pending = pending
+        )
+    }
+}
+
+endpoint! {
+    APP.url("/admin/comments/json"),
+
+    /// GET /admin/comments/json?status=pending|approved|rejected|all - Comments, newest first
+    /// Response: {"success": true, "comments": [{"id": ..., "content": ..., "author": ..., "name": ..., "body": ..., "status": ..., "reason": ...}]}
+    pub admin_comments_json <HTTP> {
+        if !moderation::is_moderator(req).await {
+            return unauthorized();
+        }
+        let (_, filter) = status_filter(req);
+        let items: Vec<Value> = comments::list(filter).iter().map(|comment| comment.into_json()).collect();
+        json_response(object!({ success: true, comments: items }))
+    }
+}
+
+endpoint! {
+    APP.url("/admin/comments/<id>/approve"),
+
+    /// POST /admin/comments/<id>/approve - Show a comment on its page
+    pub admin_comments_approve <HTTP> {
+        if !moderation::is_moderator(req).await {
+            return unauthorized();
+        }
+        methods!(req, POST);
+        let id = req.param("id").unwrap_or_default();
+        let by = admin_entry(req).await;
+        moderated(comments::set_status(&id, CommentStatus::Approved, &by))
+    }
+}
+
+endpoint! {
+    APP.url("/admin/comments/<id>/reject"),
+
+    /// POST /admin/comments/<id>/reject - Hide a comment and its replies
+    pub admin_comments_reject <HTTP> {
+        if !moderation::is_moderator(req).await {
+            return unauthorized();
+        }
+        methods!(req, POST);
+        let id = req.param("id").unwrap_or_default();
+        let by = admin_entry(req).await;
+        moderated(comments::set_status(&id, CommentStatus::Rejected, &by))
+    }
+}
+
+endpoint! {
+    APP.url("/admin/comments/<id>/delete"),
+
+    /// POST /admin/comments/<id>/delete - Remove a comment for good
+    pub admin_comments_delete <HTTP> {
+        if !moderation::is_moderator(req).await {
+            return unauthorized();
+        }
+        methods!(req, POST);
+        let id = req.param("id").unwrap_or_default();
+        let by = admin_entry(req).await;
+        moderated(comments::remove(&id, &by))
+    }
+}
@@ -0,0 +1,186 @@
+use hotaru::http::*;
+use hotaru::prelude::*;
+
+use crate::APP;
+use crate::admin::check_is_admin;
+use crate::forms::{self, FormError, FormSchema, Submission};
+use crate::op::{escape_html, into_path_l, pageprop};
+use crate::methods;
+
+/// Definition shown in the editor for a new form
+const NEW_FORM: &str = r#"{
+    "title": "",
+    "description": "",
+    "fields": [
+        { "name": "email", "label": "Email", "type": "email", "required": true }
+    ],
+    "open": true,
+    "guests": true,
+    "once": false,
+    "message": ""
+}"#;
+
+fn unauthorized() -> HttpResponse {
+    json_response(object!({ success: false, message: "Unauthorized" })).status(StatusCode::UNAUTHORIZED)
+}
+
+/// The answer to a change of a form
+fn changed(result: Result<FormSchema, FormError>) -> HttpResponse {
+    match result {
+        Ok(form) => json_response(object!({ success: true, form: form.into_json() })),
+        Err(err) => {
+            let status = match err {
+                FormError::NotFound => StatusCode::NOT_FOUND,
+                FormError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                _ => StatusCode::BAD_REQUEST,
+            };
+            json_response(object!({ success: false, message: err.to_string() })).status(status)
+        }
+    }
+}
+
+endpoint! {
+    APP.url("/admin/forms"),
+
+    /// GET /admin/forms?edit=<slug> - Every form with its number of answers,
+    /// and the editor of the given form, empty for a new one
+    pub admin_forms <HTTP> {
+        if !check_is_admin(req).await {
+            return redirect_response("/user/unauthorized");
+        }
+        let items: Vec<Value> = forms::list()
+            .iter()
+            .map(|form| {
+                object!({
+                    slug: &form.slug,
+                    title: escape_html(&form.title),
+                    fields: form.fields.len(),
+                    submissions: forms::submissions(&form.slug).len(),
+                    open: form.open,
+                })
+            })
+            .collect();
+        let slug = req.query("edit").unwrap_or_default();
+        let editor = match forms::source(&slug) {
+            Some(source) => object!({ new: false, slug: &slug, schema: escape_html(&source) }),
+            None => object!({ new: true, slug: "", schema: escape_html(NEW_FORM) }),
+        };
+        akari_render!(
+            "admin/forms.html",
+            pageprop = pageprop(req, "Forms", "Data-collection forms"),
+            path = into_path_l(req, vec!["home", "admin"]),
+            items = Value::List(items),
+            editor = editor
+        )
+    }
+}
+
+endpoint! {
+    APP.url("/admin/forms/json"),
+
+    /// GET /admin/forms/json - Every form with its fields and number of answers
+    /// Response: {"success": true, "forms": [{"slug": ..., "title": ..., "fields": [...], "open": ..., "guests": ..., "once": ..., "submissions": 3}]}
+    pub admin_forms_json <HTTP> {
+        if !check_is_admin(req).await {
+            return unauthorized();
+        }
+        let list: Vec<Value> = forms::list()
+            .iter()
+            .map(|form| {
+                let mut value = form.into_json();
+                value.set("submissions", forms::submissions(&form.slug).len());
+                value
+            })
+            .collect();
+        json_response(object!({ success: true, forms: list }))
+    }
+}
+
+endpoint! {
+    APP.url("/admin/forms/save"),
+
+    /// POST /admin/forms/save - Make or replace a form
+    /// Form -> slug, schema (the definition as JSON)
+    pub admin_forms_save <HTTP> {
+        if !check_is_admin(req).await {
+            return unauthorized();
+        }
+        methods!(req, POST);
+        let form = req.form_or_default().await;
+        let slug = form.get_or_default("slug").trim().to_string();
+        let schema = form.get_or_default("schema").clone();
+        changed(forms::save(&slug, &schema))
+    }
+}
+
+endpoint! {
+    APP.url("/admin/forms/<slug>/delete"),
+
+    /// POST /admin/forms/<slug>/delete - Remove a form with every answer to it
+    pub admin_forms_delete <HTTP> {
+        if !check_is_admin(req).await {
+            return unauthorized();
+        }
+        methods!(req, POST);
+        let slug = req.param("slug").unwrap_or_default();
+        changed(forms::remove(&slug))
+    }
+}
+
+endpoint! {
+    APP.url("/admin/forms/<slug>/submissions"),
+
+    /// GET: the answers to a form, newest first. With `Accept: application/json`,
+    /// {"success": true, "submissions": [{"id": ..., "time": ..., "user": ..., "values": {...}}]}
+    pub admin_forms_submissions <HTTP> {
+        let json = req.header_str("accept").is_some_and(|accept| accept.contains("application/json"));
+        if !check_is_admin(req).await {
+            return if json { unauthorized() } else { redirect_response("/user/unauthorized") };
+        }
+        let slug = req.param("slug").unwrap_or_default();
+        let Some(form) = forms::get(&slug) else {
+            return if json { changed(Err(FormError::NotFound)) } else { redirect_response("/admin/forms") };
+        };
+        let mut submissions = forms::submissions(&slug);
+        submissions.reverse();
+        if json {
+            let list: Vec<Value> = submissions.iter().map(Submission::into_json).collect();
+            return json_response(object!({ success: true, submissions: list }));
+        }
+        let columns: Vec<Value> = form.fields.iter().map(|field| Value::from(escape_html(&field.label))).collect();
+        let rows: Vec<Value> = submissions
+            .iter()
+            .map(|submission| {
+                let values: Vec<Value> = form.fields.iter().map(|field| Value::from(escape_html(submission.get(&field.name)))).collect();
+                object!({ id: &submission.id, time: forms::time(submission.time), user: &submission.user, values: values })
+            })
+            .collect();
+        akari_render!(
+            "admin/form_submissions.html",
+            pageprop = pageprop(req, "Submissions", "Answers to a form"),
+            path = into_path_l(req, vec!["home", "admin"]),
+            form = object!({ slug: &form.slug, title: escape_html(&form.title) }),
+            columns = Value::List(columns),
+            rows = Value::List(rows)
+        )
+    }
+}
+
+endpoint! {
+    APP.url("/admin/forms/<slug>/export"),
+
+    /// GET /admin/forms/<slug>/export - The answers as a CSV download, oldest first
+    pub admin_forms_export <HTTP> {
+        if !check_is_admin(req).await {
+            return unauthorized();
+        }
+        let slug = req.param("slug").unwrap_or_default();
+        let Some(form) = forms::get(&slug) else {
+            return changed(Err(FormError::NotFound));
+        };
+        let csv = forms::csv(&form, &forms::submissions(&slug));
+        normal_response(StatusCode::OK, csv)
+            .content_type(HttpContentType::from_str("text/csv; charset=utf-8"))
+            .add_header("Content-Disposition", format!("attachment; filename=\"{}.csv\"", slug))
+    }
+}
@@ -0,0 +1,150 @@
+//! internal.rs
+//!
+//! Signed calls of the admin pages to this server's own admin API.
+//!
+//! Pages like `/admin/panel` render what the JSON API (`/admin/users`)
+//! answers by calling it over the binding they were reached on. Those calls
+//! do not carry the admin's session cookies, which could be replayed by
+//! whoever sees the hop, but the acting admin and the time, signed with
+//! HMAC-SHA256 under a key made at startup that never leaves the process:
+//!
+//! ```text
+//! X-Sfx-Admin: 1@local
+//! X-Sfx-Timestamp: 1760000000
+//! X-Sfx-Signature: hex(HMAC(key, "GET\n/admin/users?page=1\n1@local\n1760000000"))
+//! ```
+//!
+//! [`SignedAdminCall`] accepts each signature once, within [`MAX_AGE`]
+//! seconds of its timestamp, and hands the admin to
+//! `crate::admin::check_is_admin`, which still checks `admins.json`.
+
+use hmac::{Hmac, Mac};
+use hotaru::http::*;
+use hotaru::prelude::*;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::local_auth::names::now;
+use crate::user::UserID;
+
+/// The signing key of this process
+static KEY: Lazy<String> = Lazy::new(|| hotaru_lib::random::random_alphanumeric_string(64));
+
+/// Signatures already used, as bytes -> their timestamp
+static SEEN: Lazy<Mutex<HashMap<Vec<u8>, u64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub const ADMIN_HEADER: &str = "x-sfx-admin";
+pub const TIMESTAMP_HEADER: &str = "x-sfx-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-sfx-signature";
+
+/// Seconds a signed call stays valid
+pub const MAX_AGE: u64 = 30;
+
+/// The admin a request was signed for, set by [`SignedAdminCall`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignedAdmin(pub UserID);
+
+fn signature(key: &str, method: &str, target: &str, admin: &str, timestamp: u64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(format!("{}\n{}\n{}\n{}", method, target, admin, timestamp).as_bytes());
+    mac
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// Sign `request` as made by `admin`
+pub fn sign(mut request: HttpRequest, admin: &UserID) -> HttpRequest {
+    let (method, target) = (request.meta.method().to_string(), request.meta.url());
+    let timestamp = now();
+    let mac = signature(&KEY, &method, &target, &admin.to_string(), timestamp);
+    request.meta.set_attribute(ADMIN_HEADER, admin.to_string());
+    request.meta.set_attribute(TIMESTAMP_HEADER, timestamp.to_string());
+    request.meta.set_attribute(SIGNATURE_HEADER, hex(&mac.finalize().into_bytes()));
+    request
+}
+
+/// The admin of a call signed with `sig` under `key`, unless it is too old,
+/// from the future, or its signature is in `seen` already
+fn verify(
+    key: &str,
+    call: (&str, &str),
+    admin: &str,
+    timestamp: &str,
+    sig: &str,
+    now: u64,
+    seen: &mut HashMap<Vec<u8>, u64>,
+) -> Result<UserID, &'static str> {
+    let (method, target) = call;
+    let timestamp: u64 = timestamp.parse().map_err(|_| "Bad timestamp")?;
+    if timestamp.abs_diff(now) > MAX_AGE {
+        return Err("Expired signature");
+    }
+    let sig_bytes = unhex(sig).ok_or("Bad signature")?;
+    signature(key, method, target, admin, timestamp).verify_slice(&sig_bytes).map_err(|_| "Bad signature")?;
+    seen.retain(|_, at| at.abs_diff(now) <= MAX_AGE);
+    // Keyed on the bytes, as the hex of a signature may be written in either case
+    if seen.insert(sig_bytes, timestamp).is_some() {
+        return Err("Replayed signature");
+    }
+    UserID::from_str(admin).ok_or("Bad admin")
+}
+
+middleware! {
+    /// Middleware checking the signature of calls made with [`sign`]. A good
+    /// one sets [`SignedAdmin`]; a bad one is answered `401 Unauthorized`.
+    /// Requests without one pass untouched.
+    pub SignedAdminCall <HTTP> {
+        let Some(sig) = req.header_str(SIGNATURE_HEADER).map(|sig| sig.to_string()) else {
+            return next(req).await
+        };
+        let admin = req.header_str(ADMIN_HEADER).unwrap_or_default().to_string();
+        let timestamp = req.header_str(TIMESTAMP_HEADER).unwrap_or_default().to_string();
+        let (method, target) = (req.request.meta.method().to_string(), req.request.meta.url());
+        let verified = verify(&KEY, (&method, &target), &admin, &timestamp, &sig, now(), &mut SEEN.lock().unwrap());
+        match verified {
+            Ok(admin) => {
+                req.params.set(SignedAdmin(admin));
+                next(req).await
+            }
+            Err(err) => {
+                tracing::warn!(%err, path = %req.path(), "Rejected internal admin call");
+                req.response = json_response(object!({ success: false, message: "Unauthorized" }))
+                    .status(StatusCode::UNAUTHORIZED);
+                Ok(req)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_calls_are_accepted_once_and_only_as_signed() {
+        let mut seen = HashMap::new();
+        let at = 1_760_000_000;
+        let sig = hex(&signature("k", "GET", "/admin/users?page=1", "1@local", at).finalize().into_bytes());
+        let call = ("GET", "/admin/users?page=1");
+        let verify = |seen: &mut HashMap<Vec<u8>, u64>, call, admin, now| verify("k", call, admin, &at.to_string(), &sig, now, seen);
+
+        assert_eq!(verify(&mut seen, ("GET", "/admin/users?page=2"), "1@local", at), Err("Bad signature"));
+        assert_eq!(verify(&mut seen, call, "2@local", at), Err("Bad signature"));
+        assert_eq!(verify(&mut seen, call, "1@local", at + MAX_AGE + 1), Err("Expired signature"));
+        assert_eq!(verify(&mut seen, call, "1@local", at + 5), Ok(UserID::from_str("1@local").unwrap()));
+        assert_eq!(verify(&mut seen, call, "1@local", at + 6), Err("Replayed signature"));
+        let upper = sig.to_ascii_uppercase();
+        assert_eq!(super::verify("k", call, "1@local", &at.to_string(), &upper, at + 7, &mut seen), Err("Replayed signature"));
+        assert_eq!(super::verify("other key", call, "1@local", &at.to_string(), &sig, at, &mut HashMap::new()), Err("Bad signature"));
+    }
+}
@@ -0,0 +1,270 @@
+//! jsonapi.rs
+//!
+//! The admin JSON API answered as [JSON:API](https://jsonapi.org/format/)
+//! documents for clients sending `Accept: application/vnd.api+json`, which
+//! admin frontend frameworks read without an adapter:
+//!
+//! ```json
+//! {
+//!     "jsonapi": { "version": "1.1" },
+//!     "data": [{
+//!         "type": "users", "id": "12",
+//!         "attributes": { "username": "bob", "email": "bob@example.com", "is_active": true },
+//!         "relationships": { "sessions": { "links": { "related": "/admin/users/12/sessions" }, "meta": { "count": 2 } } },
+//!         "links": { "self": "/admin/users/12" }
+//!     }],
+//!     "links": { "self": "/admin/users?page=1&per_page=10&sort=uid&order=asc", "next": "..." },
+//!     "meta": { "total": 40, "pages": 4 }
+//! }
+//! ```
+//!
+//! Other clients keep getting the plain `{"success": ...}` answers. An
+//! `Accept` naming the JSON:API type only with parameters other than `ext`
+//! and `profile` is answered `406 Not Acceptable`, as the format asks.
+
+use hotaru::http::*;
+use hotaru::prelude::*;
+
+/// The media type of JSON:API documents
+pub const MEDIA_TYPE: &str = "application/vnd.api+json";
+
+/// How an admin API answer is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// `{"success": true, ...}`
+    Json,
+    JsonApi,
+}
+
+impl Format {
+    /// The format an `Accept` header asks for, `None` when the server has
+    /// none of the JSON:API variants it names
+    pub fn from_accept(accept: Option<&str>) -> Option<Self> {
+        let mut named = false;
+        for range in accept.unwrap_or_default().split(',') {
+            let mut parts = range.split(';').map(str::trim);
+            if !parts.next().unwrap_or_default().eq_ignore_ascii_case(MEDIA_TYPE) {
+                continue;
+            }
+            named = true;
+            let supported = parts.all(|param| {
+                let name = param.split('=').next().unwrap_or_default().trim().to_ascii_lowercase();
+                matches!(name.as_str(), "ext" | "profile")
+            });
+            if supported {
+                return Some(Format::JsonApi);
+            }
+        }
+        if named { None } else { Some(Format::Json) }
+    }
+
+    /// The format `req` asks for
+    pub fn of(req: &HttpReqCtx) -> Option<Self> {
+        Self::from_accept(req.header_str("accept"))
+    }
+
+    /// A failed request: `{"success": false, "message": ...}` or a JSON:API
+    /// error document
+    pub fn error(self, status: StatusCode, message: &str) -> HttpResponse {
+        match self {
+            Format::Json => json_response(object!({ success: false, message: message })).status(status),
+            Format::JsonApi => {
+                let error = object!({ status: status.as_u16().to_string(), title: message });
+                response(object!({ jsonapi: version(), errors: vec![error] })).status(status)
+            }
+        }
+    }
+}
+
+/// `406 Not Acceptable`, for a request [`Format::of`] found no format for
+pub fn not_acceptable() -> HttpResponse {
+    json_response(object!({ success: false, message: format!("Only {} without media type parameters is served", MEDIA_TYPE) }))
+        .status(StatusCode::NOT_ACCEPTABLE)
+}
+
+fn version() -> Value {
+    object!({ version: "1.1" })
+}
+
+/// A resource object
+#[derive(Debug, Clone)]
+pub struct Resource {
+    ty: String,
+    id: String,
+    attributes: Value,
+    relationships: Value,
+    link: Option<String>,
+}
+
+impl Resource {
+    /// The resource `id` of type `ty`; `id` and `type` entries of
+    /// `attributes` are left out, the format keeping those names for itself
+    pub fn new(ty: &str, id: impl ToString, attributes: Value) -> Self {
+        let attributes = match attributes {
+            Value::Dict(mut attributes) => {
+                attributes.remove("id");
+                attributes.remove("type");
+                Value::Dict(attributes)
+            }
+            attributes => attributes,
+        };
+        Self { ty: ty.to_string(), id: id.to_string(), attributes, relationships: Value::new_dict(), link: None }
+    }
+
+    /// The `self` link of the resource
+    pub fn link(mut self, link: impl Into<String>) -> Self {
+        self.link = Some(link.into());
+        self
+    }
+
+    /// Add the relationship `name`; `data` is the linkage, `None` when the
+    /// related resources are only linked
+    pub fn relationship(mut self, name: &str, related: impl Into<String>, data: Option<Value>, meta: Option<Value>) -> Self {
+        let mut relationship = object!({ links: { related: related.into() } });
+        if let Some(data) = data {
+            relationship.set("data", data);
+        }
+        if let Some(meta) = meta {
+            relationship.set("meta", meta);
+        }
+        self.relationships.set(name, relationship);
+        self
+    }
+
+    /// The resource identifier object
+    pub fn identifier(&self) -> Value {
+        identifier(&self.ty, &self.id)
+    }
+
+    pub fn into_json(self) -> Value {
+        let mut resource = identifier(&self.ty, &self.id);
+        resource.set("attributes", self.attributes);
+        if matches!(&self.relationships, Value::Dict(relationships) if !relationships.is_empty()) {
+            resource.set("relationships", self.relationships);
+        }
+        if let Some(link) = self.link {
+            resource.set("links", self_link(link));
+        }
+        resource
+    }
+}
+
+/// `{"type": ty, "id": id}`
+pub fn identifier(ty: &str, id: &str) -> Value {
+    let mut identifier = Value::new_dict();
+    identifier.set("type", ty);
+    identifier.set("id", id);
+    identifier
+}
+
+/// `{"self": link}`
+pub fn self_link(link: impl Into<String>) -> Value {
+    let mut links = Value::new_dict();
+    links.set("self", link.into());
+    links
+}
+
+/// A top-level document
+#[derive(Debug, Clone)]
+pub struct Document {
+    document: Value,
+}
+
+impl Document {
+    /// A document holding one resource
+    pub fn one(resource: Resource) -> Self {
+        Self { document: object!({ jsonapi: version(), data: resource.into_json() }) }
+    }
+
+    /// A document holding a collection
+    pub fn many(resources: Vec<Resource>) -> Self {
+        let data: Vec<Value> = resources.into_iter().map(Resource::into_json).collect();
+        Self { document: object!({ jsonapi: version(), data: data }) }
+    }
+
+    /// Add resources of `?include=` to `included`
+    pub fn include(mut self, resources: Vec<Resource>) -> Self {
+        if !resources.is_empty() {
+            let included: Vec<Value> = resources.into_iter().map(Resource::into_json).collect();
+            self.document.set("included", included);
+        }
+        self
+    }
+
+    pub fn links(mut self, links: Value) -> Self {
+        self.document.set("links", links);
+        self
+    }
+
+    pub fn meta(mut self, meta: Value) -> Self {
+        self.document.set("meta", meta);
+        self
+    }
+
+    pub fn into_json(self) -> Value {
+        self.document
+    }
+
+    pub fn into_response(self) -> HttpResponse {
+        response(self.document)
+    }
+}
+
+fn response(document: Value) -> HttpResponse {
+    json_response(document).content_type(HttpContentType::from_str(MEDIA_TYPE))
+}
+
+/// The pagination links of page `page` of `pages`, each written by `link`;
+/// `prev` and `next` are `null` at the ends
+pub fn page_links(page: usize, pages: usize, link: impl Fn(usize) -> String) -> Value {
+    let pages = pages.max(1);
+    let page = page.clamp(1, pages);
+    let maybe = |page: Option<usize>| page.map(|page| Value::from(link(page))).unwrap_or(Value::None);
+    let mut links = self_link(link(page));
+    for (name, target) in [
+        ("first", Value::from(link(1))),
+        ("last", Value::from(link(pages))),
+        ("prev", maybe(Some(page - 1).filter(|prev| *prev >= 1))),
+        ("next", maybe(Some(page + 1).filter(|next| *next <= pages))),
+    ] {
+        links.set(name, target);
+    }
+    links
+}
+
+/// Whether `?include=` names `relationship`
+pub fn includes(include: Option<&str>, relationship: &str) -> bool {
+    include.unwrap_or_default().split(',').any(|path| path.trim() == relationship)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn documents_follow_the_accept_header() {
+        assert_eq!(Format::from_accept(None), Some(Format::Json));
+        assert_eq!(Format::from_accept(Some("application/json, */*")), Some(Format::Json));
+        assert_eq!(Format::from_accept(Some("application/vnd.api+json")), Some(Format::JsonApi));
+        assert_eq!(Format::from_accept(Some("application/vnd.api+json; profile=\"https://example.com/p\"")), Some(Format::JsonApi));
+        assert_eq!(Format::from_accept(Some("application/vnd.api+json; charset=utf-8")), None);
+        assert_eq!(Format::from_accept(Some("application/vnd.api+json; q=0.5, application/vnd.api+json")), Some(Format::JsonApi));
+
+        let user = Resource::new("users", 12, object!({ id: 12, username: "bob" }))
+            .link("/admin/users/12")
+            .relationship("sessions", "/admin/users/12/sessions", None, Some(object!({ count: 2 })));
+        let identifier = user.identifier();
+        assert_eq!((identifier.get("type").string(), identifier.get("id").string()), ("users".to_string(), "12".to_string()));
+        let document = Document::many(vec![user]).meta(object!({ total: 1 })).into_json();
+        let data = &document.get("data").list()[0];
+        assert_eq!((data.get("id").string(), data.get("attributes").try_get("id").is_err()), ("12".to_string(), true));
+        assert_eq!(data.get("relationships").get("sessions").get("meta").get("count").integer(), 2);
+        assert_eq!(document.get("jsonapi").get("version").string(), "1.1");
+
+        let links = page_links(1, 3, |page| format!("/admin/users?page={}", page));
+        assert!(matches!(links.get("prev"), Value::None));
+        assert_eq!((links.get("next").string(), links.get("last").string()), ("/admin/users?page=2".to_string(), "/admin/users?page=3".to_string()));
+        assert!(matches!(page_links(3, 3, |page| page.to_string()).get("next"), Value::None));
+        assert!(includes(Some("profile, sessions"), "sessions") && !includes(None, "sessions"));
+    }
+}
@@ -0,0 +1,161 @@
+use hotaru::http::*;
+use hotaru::prelude::*;
+
+use crate::APP;
+use crate::admin::check_is_admin;
+use crate::l10n::catalog::{self, Catalog};
+use crate::op::{escape_html, into_path_l, pageprop};
+use crate::methods;
+
+fn unauthorized() -> HttpResponse {
+    json_response(object!({ success: false, message: "Unauthorized" })).status(StatusCode::UNAUTHORIZED)
+}
+
+fn l10n_error(message: String) -> HttpResponse {
+    json_response(object!({ success: false, message: message })).status(StatusCode::BAD_REQUEST)
+}
+
+/// The rows of the editor: each key with its text in every language, only
+/// the keys with a missing language when `missing_only`, and only the keys
+/// containing `search`
+fn rows(catalog: &Catalog, missing_only: bool, search: &str) -> Vec<Value> {
+    catalog
+        .keys()
+        .into_iter()
+        .filter(|key| key.contains(search))
+        .filter_map(|key| {
+            let missing = catalog.missing(&key);
+            if missing_only && missing.is_empty() {
+                return None;
+            }
+            let cells: Vec<Value> = catalog
+                .langs()
+                .iter()
+                .map(|lang| {
+                    let text = catalog.text(&key, lang).unwrap_or_default();
+                    object!({ lang: lang, text: escape_html(&text), missing: missing.contains(lang) })
+                })
+                .collect();
+            Some(object!({ key: escape_html(&key), cells: cells, missing: !missing.is_empty() }))
+        })
+        .collect()
+}
+
+endpoint! {
+    APP.url("/admin/l10n"),
+
+    /// GET: every translation key in every supported language, the missing
+    /// ones highlighted; `?missing=1` shows only keys missing somewhere and
+    /// `?q=` those containing the text
+    pub admin_l10n <HTTP> {
+        if !check_is_admin(req).await {
+            return redirect_response("/user/unauthorized");
+        }
+        let missing_only = req.query("missing").as_deref() == Some("1");
+        let search = req.query("q").unwrap_or_default();
+        let catalog = Catalog::open();
+        let keys = catalog.keys();
+        let langs: Vec<Value> = catalog
+            .langs()
+            .iter()
+            .map(|lang| {
+                let missing = keys.iter().filter(|key| catalog.text(key, lang).is_none()).count();
+                object!({ code: lang, missing: missing })
+            })
+            .collect();
+        akari_render!(
+            "admin/l10n.html",
+            pageprop = pageprop(req, "Translations", "Translations of the site"),
+            path = into_path_l(req, vec!["home", "admin"]),
+            langs = Value::List(langs),
+            rows = Value::List(rows(&catalog, missing_only, &search)),
+            total = keys.len(),
+            missing_only = missing_only,
+            search = escape_html(&search),
+            backend = if catalog.is_fluent() { "fluent" } else { "json" }
+        )
+    }
+}
+
+endpoint! {
+    APP.url("/admin/l10n/set"),
+
+    /// POST /admin/l10n/set - Change, add or (with an empty text) remove the
+    /// text of a key in one language
+    /// Form -> key, lang, text
+    /// Response: {"success": true, "missing": ["ja"]}, or 400 with why the
+    /// text was refused
+    pub admin_l10n_set <HTTP> {
+        if !check_is_admin(req).await {
+            return unauthorized();
+        }
+        methods!(req, POST);
+        let form = req.form_or_default().await;
+        let key = form.get_or_default("key").trim().to_string();
+        let lang = form.get_or_default("lang").clone();
+        let text = form.get_or_default("text").clone();
+        match catalog::edit(|catalog| {
+            catalog.set(&key, &lang, &text)?;
+            Ok(catalog.missing(&key))
+        }) {
+            Ok(missing) => json_response(object!({ success: true, missing: missing })),
+            Err(message) => l10n_error(message),
+        }
+    }
+}
+
+endpoint! {
+    APP.url("/admin/l10n/export/<lang>"),
+
+    /// GET /admin/l10n/export/<lang> - The translations of one language as a
+    /// file: `<lang>.json` of key and text, or the `<lang>.ftl` file
+    pub admin_l10n_export <HTTP> {
+        if !check_is_admin(req).await {
+            return redirect_response("/user/unauthorized");
+        }
+        let lang = req.param("lang").unwrap_or_default();
+        let catalog = Catalog::open();
+        if !catalog.langs().contains(&lang) {
+            return text_response("404 Language not supported").status(StatusCode::NOT_FOUND);
+        }
+        let (extension, content_type) = if catalog.is_fluent() { ("ftl", "text/plain; charset=utf-8") } else { ("json", "application/json") };
+        normal_response(StatusCode::OK, catalog.export(&lang))
+            .content_type(HttpContentType::from_str(content_type))
+            .add_header("Content-Disposition", format!("attachment; filename=\"{}.{}\"", lang, extension))
+    }
+}
+
+endpoint! {
+    APP.url("/admin/l10n/import/<lang>"),
+
+    /// POST /admin/l10n/import/<lang> - Take the texts of an exported file;
+    /// nothing changes when one of them is refused
+    /// Form -> content
+    /// Response: {"success": true, "count": 42}
+    pub admin_l10n_import <HTTP> {
+        if !check_is_admin(req).await {
+            return unauthorized();
+        }
+        methods!(req, POST);
+        let lang = req.param("lang").unwrap_or_default();
+        let content = req.form_or_default().await.get_or_default("content").clone();
+        match catalog::edit(|catalog| catalog.import(&lang, &content)) {
+            Ok(count) => json_response(object!({ success: true, count: count })),
+            Err(message) => l10n_error(message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestRequest};
+
+    #[tokio::test]
+    async fn guests_cannot_change_translations() {
+        let mut req = TestRequest::post("/admin/l10n/set").form(&[("key", "home"), ("lang", "en"), ("text", "Start")]).build();
+        let res = testing::respond(admin_l10n_set(&mut req).await, &mut req);
+        assert_eq!(testing::status(&res), 401);
+        assert!(!testing::json(&res).get("success").boolean());
+    }
+}
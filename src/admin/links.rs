@@ -0,0 +1,114 @@
+use hotaru::http::*;
+use hotaru::prelude::*;
+
+use crate::APP;
+use crate::admin::bans::{admin_entry, duration};
+use crate::admin::check_is_admin;
+use crate::op::{into_path_l, pageprop};
+use crate::proxy;
+use crate::qr;
+use crate::shortlinks::{self, Link, LinkError};
+use crate::methods;
+
+/// Width of the QR codes on the page, in pixels
+const QR_PIXELS: u32 = 96;
+
+/// Width of the QR codes to download, in pixels
+const QR_DOWNLOAD_PIXELS: u32 = 512;
+
+/// `link` with its public `url`, and the `/op/qr` images of it: `qr` to
+/// show and `qr_png` to download
+fn entry(req: &HttpReqCtx, link: &Link) -> Value {
+    let url = format!("{}/l/{}", proxy::public_origin(req), link.code);
+    let mut value = link.into_json();
+    value.set("qr", qr::url(&url, qr::Format::Svg, QR_PIXELS));
+    value.set("qr_png", qr::url(&url, qr::Format::Png, QR_DOWNLOAD_PIXELS));
+    value.set("url", url);
+    value
+}
+
+fn link_error(err: LinkError) -> HttpResponse {
+    let status = match err {
+        LinkError::NotFound => StatusCode::NOT_FOUND,
+        LinkError::CodeTaken => StatusCode::CONFLICT,
+        LinkError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::BAD_REQUEST,
+    };
+    json_response(object!({ success: false, message: err.to_string() })).status(status)
+}
+
+fn unauthorized() -> HttpResponse {
+    json_response(object!({ success: false, message: "Unauthorized" })).status(StatusCode::UNAUTHORIZED)
+}
+
+endpoint! {
+    APP.url("/admin/links"),
+
+    /// GET: the list of short links with their QR codes
+    /// POST: Form -> code (empty for a random one), target, duration (seconds, empty for a lasting link)
+    /// Response: {"success": true, "link": {...}} or {"success": false, "message": "..."}
+    pub admin_links <HTTP> {
+        methods!(req, {
+            GET => {
+                if !check_is_admin(req).await {
+                    return redirect_response("/user/unauthorized");
+                }
+                let links: Vec<Value> = shortlinks::list().iter().map(|link| entry(req, link)).collect();
+                akari_render!(
+                    "admin/links.html",
+                    pageprop = pageprop(req, "Short links", "Short codes leading to pages and allowed sites"),
+                    path = into_path_l(req, vec!["home", "admin"]),
+                    links = Value::List(links),
+                    allowed_hosts = Value::List(shortlinks::settings().allowed_hosts.iter().map(Value::from).collect())
+                )
+            }
+            POST => {
+                if !check_is_admin(req).await {
+                    return unauthorized();
+                }
+                let by = admin_entry(req).await;
+                let form = req.form_or_default().await.clone();
+                let Ok(duration) = duration(&form) else {
+                    return json_response(object!({ success: false, message: "Invalid duration" }))
+                        .status(StatusCode::BAD_REQUEST);
+                };
+                match shortlinks::add(form.get_or_default("code"), form.get_or_default("target"), &by, duration) {
+                    Ok(link) => json_response(object!({ success: true, link: entry(req, &link) })),
+                    Err(err) => link_error(err),
+                }
+            }
+        })
+    }
+}
+
+endpoint! {
+    APP.url("/admin/links/json"),
+
+    /// GET /admin/links/json - Every short link with its clicks
+    /// Response: {"success": true, "links": [{"code": ..., "url": ..., "target": ..., "clicks": ...}]}
+    pub admin_links_json <HTTP> {
+        if !check_is_admin(req).await {
+            return unauthorized();
+        }
+        let links: Vec<Value> = shortlinks::list().iter().map(|link| entry(req, link)).collect();
+        json_response(object!({ success: true, links: links }))
+    }
+}
+
+endpoint! {
+    APP.url("/admin/links/<code>/delete"),
+
+    /// POST /admin/links/<code>/delete - Remove a short link
+    pub admin_link_delete <HTTP> {
+        if !check_is_admin(req).await {
+            return unauthorized();
+        }
+        methods!(req, POST);
+        let code = req.param("code").unwrap_or_default();
+        let by = admin_entry(req).await;
+        match shortlinks::remove(&code, &by) {
+            Ok(_) => json_response(object!({ success: true })),
+            Err(err) => link_error(err),
+        }
+    }
+}
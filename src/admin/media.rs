@@ -0,0 +1,136 @@
+use hotaru::http::*;
+use hotaru::prelude::*;
+
+use crate::APP;
+use crate::admin::bans::admin_entry;
+use crate::admin::check_is_admin;
+use crate::media::{self, MediaItem};
+use crate::op::{into_path_l, pageprop};
+use crate::proxy;
+use crate::methods;
+
+/// Seconds the previews of private images on the page stay valid
+const PREVIEW_TTL: u64 = 600;
+
+/// `item` with its `url`, and a `preview` the page can show when it is an
+/// image out of quarantine
+fn entry(item: &MediaItem) -> Value {
+    let mut value = item.into_json();
+    value.set("url", item.url());
+    if item.mime.starts_with("image/") && media::is_inline(&item.mime) && !item.quarantined {
+        let preview = if item.private { media::signed_url(&item.id, PREVIEW_TTL) } else { item.url() };
+        value.set("preview", preview);
+    }
+    value
+}
+
+fn unauthorized() -> HttpResponse {
+    json_response(object!({ success: false, message: "Unauthorized" })).status(StatusCode::UNAUTHORIZED)
+}
+
+endpoint! {
+    APP.url("/admin/media"),
+
+    /// GET: the media library
+    pub admin_media <HTTP> {
+        if !check_is_admin(req).await {
+            return redirect_response("/user/unauthorized");
+        }
+        let items: Vec<Value> = media::list().iter().map(entry).collect();
+        let total: u64 = media::list().iter().map(|item| item.size).sum();
+        akari_render!(
+            "admin/media.html",
+            pageprop = pageprop(req, "Media", "Uploaded files"),
+            path = into_path_l(req, vec!["home", "admin"]),
+            items = Value::List(items),
+            total = total,
+            link_ttl = media::settings().link_ttl
+        )
+    }
+}
+
+endpoint! {
+    APP.url("/admin/media/json"),
+
+    /// GET /admin/media/json - Every item of the library
+    /// Response: {"success": true, "items": [{"id": ..., "name": ..., "owner": ..., "mime": ..., "size": ..., "checksum": ..., "private": ..., "url": ...}]}
+    pub admin_media_json <HTTP> {
+        if !check_is_admin(req).await {
+            return unauthorized();
+        }
+        let items: Vec<Value> = media::list().iter().map(entry).collect();
+        json_response(object!({ success: true, items: items }))
+    }
+}
+
+endpoint! {
+    APP.url("/admin/media/<id>/replace"),
+
+    /// POST /admin/media/<id>/replace - New content for an item, same id
+    /// Multipart -> file
+    pub admin_media_replace <HTTP> {
+        if !check_is_admin(req).await {
+            return unauthorized();
+        }
+        methods!(req, POST);
+        let id = req.param("id").unwrap_or_default();
+        let by = admin_entry(req).await;
+        let Some(file) = req.files_or_default().await.get_files("file").and_then(|files| files.first()).cloned() else {
+            return json_response(object!({ success: false, message: "No file" })).status(StatusCode::BAD_REQUEST);
+        };
+        let name = file.filename().unwrap_or_default();
+        match media::replace(&id, file.data(), &name, file.content_type().as_deref(), &by).await {
+            Ok(item) => json_response(object!({ success: true, item: entry(&item) })),
+            Err(err) => media::error_response(err),
+        }
+    }
+}
+
+endpoint! {
+    APP.url("/admin/media/<id>/link"),
+
+    /// POST /admin/media/<id>/link - A signed URL of an item
+    /// Form -> ttl (seconds, default `link_ttl` of media.json)
+    /// Response: {"success": true, "url": "https://.../files/<id>?expires=...&sig=...", "expires_in": 3600}
+    pub admin_media_link <HTTP> {
+        if !check_is_admin(req).await {
+            return unauthorized();
+        }
+        methods!(req, POST);
+        let id = req.param("id").unwrap_or_default();
+        if media::get(&id).is_none() {
+            return media::error_response(media::MediaError::NotFound);
+        }
+        let ttl = match req.form_or_default().await.get_or_default("ttl").trim() {
+            "" => media::settings().link_ttl,
+            ttl => match ttl.parse::<u64>() {
+                Ok(ttl) if ttl > 0 => ttl,
+                _ => {
+                    return json_response(object!({ success: false, message: "Invalid ttl" }))
+                        .status(StatusCode::BAD_REQUEST);
+                }
+            },
+        };
+        let url = format!("{}{}", proxy::public_origin(req), media::signed_url(&id, ttl));
+        json_response(object!({ success: true, url: url, expires_in: ttl }))
+    }
+}
+
+endpoint! {
+    APP.url("/admin/media/<id>/delete"),
+
+    /// POST /admin/media/<id>/delete - Remove an item, and its bytes unless
+    /// another item has the same
+    pub admin_media_delete <HTTP> {
+        if !check_is_admin(req).await {
+            return unauthorized();
+        }
+        methods!(req, POST);
+        let id = req.param("id").unwrap_or_default();
+        let by = admin_entry(req).await;
+        match media::remove(&id, &by).await {
+            Ok(_) => json_response(object!({ success: true })),
+            Err(err) => media::error_response(err),
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use hotaru::http::*;
+use hotaru::prelude::*;
+
+use crate::APP;
+use crate::admin::bans::{admin_entry, duration};
+use crate::comments;
+use crate::moderation::{self, ModerationError, Report, ReportStatus, UserFlag};
+use crate::op::{escape_html, into_path_l, pageprop};
+use crate::methods;
+
+/// Entries of the audit trail shown on the page
+const HISTORY: usize = 50;
+
+/// `report` with its free text escaped for the page
+fn report_entry(report: &Report) -> Value {
+    let mut value = report.into_json();
+    for key in ["target", "details", "note"] {
+        value.set(key, escape_html(&value.get(key).string()));
+    }
+    value
+}
+
+fn flag_entry(flag: &UserFlag) -> Value {
+    let mut value = flag.into_json();
+    value.set("reason", escape_html(&flag.reason));
+    value
+}
+
+/// The open reports grouped by what they are about, most reported first,
+/// with the reported comment or the flag of the reported user
+fn groups() -> Vec<Value> {
+    let mut groups: Vec<Vec<Report>> = Vec::new();
+    for report in moderation::reports(Some(ReportStatus::Open)) {
+        match groups.iter_mut().find(|group| group[0].kind == report.kind && group[0].target == report.target) {
+            Some(group) => group.push(report),
+            None => groups.push(vec![report]),
+        }
+    }
+    groups.sort_by_key(|group| std::cmp::Reverse(group.len()));
+    groups
+        .iter()
+        .map(|group| {
+            let first = &group[0];
+            let mut reasons: Vec<&str> = group.iter().map(|report| report.reason.as_str()).collect();
+            reasons.sort_unstable();
+            reasons.dedup();
+            let mut value = object!({
+                kind: &first.kind,
+                target: escape_html(&first.target),
+                id: &first.id,
+                flags: group.len(),
+                reasons: reasons.join(", "),
+                reports: group.iter().map(report_entry).collect::<Vec<Value>>(),
+            });
+            if first.kind == "comment"
+                && let Some(comment) = comments::get(&first.target)
+            {
+                value.set("comment", comments::entry(&comment));
+            }
+            if first.kind == "user"
+                && let Some(flag) = moderation::flag(&first.target)
+            {
+                value.set("flag", flag_entry(&flag));
+            }
+            value
+        })
+        .collect()
+}
+
+fn unauthorized() -> HttpResponse {
+    json_response(object!({ success: false, message: "Unauthorized" })).status(StatusCode::UNAUTHORIZED)
+}
+
+fn moderation_error(err: ModerationError) -> HttpResponse {
+    let status = match err {
+        ModerationError::NotFound => StatusCode::NOT_FOUND,
+        ModerationError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::BAD_REQUEST,
+    };
+    json_response(object!({ success: false, message: err.to_string() })).status(status)
+}
+
+/// The answer to an action on a user flag
+fn flagged(result: Result<UserFlag, ModerationError>) -> HttpResponse {
+    match result {
+        Ok(flag) => json_response(object!({ success: true, flag: flag.into_json() })),
+        Err(err) => moderation_error(err),
+    }
+}
+
+endpoint! {
+    APP.url("/admin/moderation"),
+
+    /// GET: open reports, flagged users and the latest moderation actions
+    pub admin_moderation <HTTP> {
+        if !moderation::is_moderator(req).await {
+            return redirect_response("/user/unauthorized");
+        }
+        let flags: Vec<Value> = moderation::flags().iter().map(flag_entry).collect();
+        let history: Vec<Value> = moderation::history(HISTORY)
+            .into_iter()
+            .map(|mut entry| {
+                entry.set("note", escape_html(&entry.get("note").string()));
+                entry.set("target", escape_html(&entry.get("target").string()));
+                entry
+            })
+            .collect();
+        akari_render!(
+            "admin/moderation.html",
+            pageprop = pageprop(req, "Moderation", "Reports and user flags"),
+            path = into_path_l(req, vec!["home", "admin"]),
+            groups = Value::List(groups()),
+            flags = Value::List(flags),
+            history = Value::List(history)
+        )
+    }
+}
+
+endpoint! {
+    APP.url("/admin/moderation/json"),
+
+    /// GET /admin/moderation/json?status=open|resolved|dismissed|all - Reports, flagged users and the audit trail
+    /// Response: {"success": true, "reports": [...], "flags": [...], "history": [...]}
+    pub admin_moderation_json <HTTP> {
+        if !moderation::is_moderator(req).await {
+            return unauthorized();
+        }
+        let status = req.query("status").unwrap_or_default();
+        let filter = match status.as_str() {
+            "all" => None,
+            status => Some(ReportStatus::from_string(status).unwrap_or(ReportStatus::Open)),
+        };
+        let reports: Vec<Value> = moderation::reports(filter).iter().map(Report::into_json).collect();
+        let flags: Vec<Value> = moderation::flags().iter().map(UserFlag::into_json).collect();
+        json_response(object!({
+            success: true,
+            reports: reports,
+            flags: flags,
+            history: moderation::history(HISTORY),
+        }))
+    }
+}
+
+endpoint! {
+    APP.url("/admin/moderation/reports/<id>/resolve"),
+
+    /// POST /admin/moderation/reports/<id>/resolve - Close the open reports of the same target as acted upon
+    /// Form -> note
+    pub admin_moderation_resolve <HTTP> {
+        if !moderation::is_moderator(req).await {
+            return unauthorized();
+        }
+        methods!(req, POST);
+        let id = req.param("id").unwrap_or_default();
+        let by = admin_entry(req).await;
+        let note = req.form_or_default().await.get_or_default("note").trim().to_string();
+        match moderation::close(&id, ReportStatus::Resolved, &note, &by) {
+            Ok(closed) => json_response(object!({ success: true, closed: closed.len() })),
+            Err(err) => moderation_error(err),
+        }
+    }
+}
+
+endpoint! {
+    APP.url("/admin/moderation/reports/<id>/dismiss"),
+
+    /// POST /admin/moderation/reports/<id>/dismiss - Close the open reports of the same target as unfounded
+    /// Form -> note
+    pub admin_moderation_dismiss <HTTP> {
+        if !moderation::is_moderator(req).await {
+            return unauthorized();
+        }
+        methods!(req, POST);
+        let id = req.param("id").unwrap_or_default();
+        let by = admin_entry(req).await;
+        let note = req.form_or_default().await.get_or_default("note").trim().to_string();
+        match moderation::close(&id, ReportStatus::Dismissed, &note, &by) {
+            Ok(closed) => json_response(object!({ success: true, closed: closed.len() })),
+            Err(err) => moderation_error(err),
+        }
+    }
+}
+
+endpoint! {
+    APP.url("/admin/moderation/users/<user>/mute"),
+
+    /// POST /admin/moderation/users/<user>/mute - Mute a user
+    /// Form -> duration (seconds, empty or 0 until lifted), reason
+    pub admin_moderation_mute <HTTP> {
+        if !moderation::is_moderator(req).await {
+            return unauthorized();
+        }
+        methods!(req, POST);
+        let user = req.param("user").unwrap_or_default();
+        let by = admin_entry(req).await;
+        let form = req.form_or_default().await;
+        let Ok(duration) = duration(form) else {
+            return json_response(object!({ success: false, message: "Invalid duration" })).status(StatusCode::BAD_REQUEST);
+        };
+        let reason = form.get_or_default("reason").trim().to_string();
+        flagged(moderation::mute(&user, duration, &reason, &by))
+    }
+}
+
+endpoint! {
+    APP.url("/admin/moderation/users/<user>/unmute"),
+
+    /// POST /admin/moderation/users/<user>/unmute - Lift a mute
+    /// Form -> reason
+    pub admin_moderation_unmute <HTTP> {
+        if !moderation::is_moderator(req).await {
+            return unauthorized();
+        }
+        methods!(req, POST);
+        let user = req.param("user").unwrap_or_default();
+        let by = admin_entry(req).await;
+        let reason = req.form_or_default().await.get_or_default("reason").trim().to_string();
+        flagged(moderation::unmute(&user, &reason, &by))
+    }
+}
+
+endpoint! {
+    APP.url("/admin/moderation/users/<user>/shadowban"),
+
+    /// POST /admin/moderation/users/<user>/shadowban - Hide what a user posts from everybody else
+    /// Form -> reason
+    pub admin_moderation_shadowban <HTTP> {
+        if !moderation::is_moderator(req).await {
+            return unauthorized();
+        }
+        methods!(req, POST);
+        let user = req.param("user").unwrap_or_default();
+        let by = admin_entry(req).await;
+        let reason = req.form_or_default().await.get_or_default("reason").trim().to_string();
+        flagged(moderation::shadowban(&user, true, &reason, &by))
+    }
+}
+
+endpoint! {
+    APP.url("/admin/moderation/users/<user>/unshadowban"),
+
+    /// POST /admin/moderation/users/<user>/unshadowban - Lift a shadowban
+    /// Form -> reason
+    pub admin_moderation_unshadowban <HTTP> {
+        if !moderation::is_moderator(req).await {
+            return unauthorized();
+        }
+        methods!(req, POST);
+        let user = req.param("user").unwrap_or_default();
+        let by = admin_entry(req).await;
+        let reason = req.form_or_default().await.get_or_default("reason").trim().to_string();
+        flagged(moderation::shadowban(&user, false, &reason, &by))
+    }
+}
@@ -1,21 +1,27 @@
 use crate::APP;
 use crate::admin::check_is_admin;
+use crate::admin::api::{PER_PAGE_CHOICES, UserQuery, add_activity};
+use crate::admin::internal;
+use crate::admin::remote::remote_admin;
+use crate::admin::sudo;
+use crate::extract::Extract;
+use crate::user::AuthClient;
 use crate::local_auth::LOCAL_AUTH;
 use crate::op::{self, into_path_l, pageprop};
-use crate::user::fetch::send_http_request;
+use crate::user::fetch::{get_user_id, send_http_request};
 use hotaru::http::*;
 use hotaru::prelude::*;
 
 async fn admin_fetch_json(req: &mut HttpReqCtx, path: &str) -> Option<Value> {
-    let full_host: String = format!("http://{}", op::BINDING.clone());
-    let result = send_http_request(
-        full_host.clone(),
-        get_request(path)
-            .add_cookie("session_id", req.get_cookie_or_default("session_id"))
-            .add_cookie("session_cont", req.get_cookie_or_default("session_cont")),
-        HttpSafety::default(),
-    )
-    .await;
+    // Call back through the listener this request arrived on, which is the
+    // one `bindings.json` lets serve the admin routes.
+    let full_host: String = match req.local_addr() {
+        Some(addr) => format!("http://{}", addr),
+        None => format!("http://{}", op::BINDING.clone()),
+    };
+    // Signed for the acting admin rather than carrying the session cookies
+    let admin = get_user_id(req).await;
+    let result = send_http_request(full_host.clone(), internal::sign(get_request(path), &admin), HttpSafety::default()).await;
 
     let response = match result {
         Ok(r) => r,
@@ -31,21 +37,159 @@ async fn admin_fetch_json(req: &mut HttpReqCtx, path: &str) -> Option<Value> {
     None
 }
 
+/// The server whose users the panel manages
+enum Target {
+    /// The account store of this server
+    Local,
+    /// The MainAuth server the admin is signed in to
+    Remote(AuthClient),
+}
+
+impl Target {
+    fn host(&self) -> String {
+        match self {
+            Target::Local => "local".to_string(),
+            Target::Remote(client) => client.server().to_string(),
+        }
+    }
+
+    /// Base path of the JSON API managing the target's users
+    fn api(&self) -> &'static str {
+        match self {
+            Target::Local => "/admin/users",
+            Target::Remote(_) => "/admin/remote/users",
+        }
+    }
+
+    /// `host` query parameter selecting the target, empty for this server
+    fn host_param(&self) -> String {
+        match self {
+            Target::Local => String::new(),
+            Target::Remote(_) => format!("host={}", hotaru_lib::url_encoding::encode_url_owned(&self.host())),
+        }
+    }
+}
+
+/// The target picked with `?host=` (the first one by default) among the
+/// servers the signed-in user may manage, and the choices for the host
+/// selector. `None` when they may manage none of them.
+async fn panel_target(req: &mut HttpReqCtx) -> (Option<Target>, Value) {
+    let mut targets = Vec::new();
+    if check_is_admin(req).await {
+        targets.push(Target::Local);
+    }
+    if let Some(client) = remote_admin(req).await {
+        targets.push(Target::Remote(client));
+    }
+    let requested = req.query("host");
+    let choices: Vec<Value> = targets
+        .iter()
+        .enumerate()
+        .map(|(i, target)| {
+            let selected = match &requested {
+                Some(host) => *host == target.host(),
+                None => i == 0,
+            };
+            let url = format!("/admin/panel?{}", target.host_param());
+            object!({ name: target.host(), selected: selected, url: url.trim_end_matches('?') })
+        })
+        .collect();
+    let target = match requested {
+        Some(host) => targets.into_iter().find(|target| target.host() == host),
+        None => targets.into_iter().next(),
+    };
+    (target, Value::List(choices))
+}
+
+/// Sort links, page links and page sizes of the user list, rendered
+/// server-side so the panel works without JavaScript
+fn user_list_controls(query: &UserQuery, data: &Value, target: &Target) -> Value {
+    let host_param = target.host_param();
+    let url = |query: &UserQuery| match host_param.as_str() {
+        "" => format!("/admin/panel?{}", query.to_query_string()),
+        host => format!("/admin/panel?{}&{}", query.to_query_string(), host),
+    };
+    let page = (data.get("page").integer().max(1)) as usize;
+    let pages = (data.get("pages").integer().max(1)) as usize;
+    let columns: Vec<Value> = [
+        ("uid", "UID"),
+        ("username", "Username"),
+        ("email", "Email"),
+        ("is_active", "Active"),
+        ("last_login", "Last login"),
+        ("failed_logins", "Failed logins"),
+    ]
+        .iter()
+        .map(|(key, label)| {
+            let current = query.sort == *key;
+            // Clicking the sorted column flips the order, another one sorts ascending
+            let target = UserQuery { sort: key.to_string(), descending: current && !query.descending, page: 1, ..query.clone() };
+            let arrow = match (current, query.descending) {
+                (false, _) => "",
+                (true, false) => " ▲",
+                (true, true) => " ▼",
+            };
+            object!({ label: *label, url: url(&target), arrow: arrow })
+        })
+        .collect();
+    let per_page: Vec<Value> = PER_PAGE_CHOICES
+        .iter()
+        .map(|n| object!({ n: *n, selected: *n == query.per_page }))
+        .collect();
+    let detail_suffix = match host_param.as_str() {
+        "" => String::new(),
+        host => format!("?{}", host),
+    };
+    object!({
+        host: target.host(),
+        remote: matches!(target, Target::Remote(_)),
+        api: target.api(),
+        json_url: match target {
+            Target::Local => "/admin/users/json",
+            Target::Remote(_) => "/admin/remote/users",
+        },
+        detail_suffix: detail_suffix,
+        q: &query.search,
+        sort: &query.sort,
+        order: if query.descending { "desc" } else { "asc" },
+        page: page,
+        pages: pages,
+        total: data.get("total").clone(),
+        has_prev: page > 1,
+        has_next: page < pages,
+        prev_url: url(&UserQuery { page: page.saturating_sub(1).max(1), ..query.clone() }),
+        next_url: url(&UserQuery { page: page + 1, ..query.clone() }),
+        columns: Value::List(columns),
+        per_page: Value::List(per_page),
+    })
+}
+
 endpoint! {
     APP.url("/admin/panel"),
 
     pub panel_users <HTTP> {
-        if !check_is_admin(req).await {
+        let (target, hosts) = panel_target(req).await;
+        let Some(target) = target else {
             return redirect_response("/user/unauthorized");
+        };
+        let query = UserQuery::from_request(req);
+        let data = match &target {
+            Target::Local => admin_fetch_json(req, &format!("/admin/users?{}", query.to_query_string())).await,
+            Target::Remote(client) => client
+                .list_users(&query)
+                .await
+                .inspect_err(|err| tracing::error!(%err, host = %client.server(), "Listing remote users failed"))
+                .ok(),
         }
-        let users = admin_fetch_json(req, "/admin/users").await
-            .map(|j| j.get("users").clone())
-            .unwrap_or(object!([]));
+        .unwrap_or_else(|| object!({ users: [], total: 0 }));
+        let list = user_list_controls(&query, &data, &target);
         akari_render!(
             "admin/panel.html",
             pageprop  = pageprop(req, "Manage Users", "Create, view, and edit users"),
             path      = into_path_l(req, vec!["home", "admin", "user"]),
-            users     = users
+            users     = data.get("users").clone(),
+            list      = list,
+            hosts     = hosts
         )
     }
 }
@@ -61,6 +205,8 @@ endpoint! {
             "admin/admins.html",
             pageprop = pageprop(req, "Manage Admins", "Manage admin access"),
             path = into_path_l(req, vec!["home", "admin", "user"]),
+            audit_title = "Changes",
+            audit = Value::List(sudo::history("admin", "", 20)),
         )
     }
 }
@@ -68,35 +214,87 @@ endpoint! {
 endpoint! {
     APP.url("/admin/panel/<uid>"),
 
+    /// Old address of the user page, kept for bookmarks
     pub panel_user_edit <HTTP> {
-        if !check_is_admin(req).await {
+        let uid = req.param("uid").unwrap_or_default();
+        redirect_response(&format!("/admin/panel/users/{}", uid))
+    }
+}
+
+endpoint! {
+    APP.url("/admin/panel/users/<uid>"),
+
+    pub panel_user_detail <HTTP> {
+        let (target, _) = panel_target(req).await;
+        let Some(target) = target else {
             return redirect_response("/user/unauthorized");
-        }
+        };
 
         let uid = match req.param("uid").and_then(|uid| uid.parse::<u32>().ok()) {
             Some(uid) => uid,
             None => return text_response("404 User not found").status(StatusCode::NOT_FOUND),
         };
 
-        let user = match LOCAL_AUTH.admin_get_user(uid).await {
-            Some(user) => {
-                let admin_entry = object!(format!("{}@local", uid));
-                object!({
-                    uid: uid,
-                    username: &user.username,
-                    email: &user.email,
-                    is_active: user.is_active,
-                    is_admin: op::get_admin().contains(&admin_entry),
-                })
-            }
-            None => return text_response("404 User not found").status(StatusCode::NOT_FOUND),
+        let user = match &target {
+            // Roles and profile data are managed on the remote server itself
+            Target::Remote(client) => match client.get_user(uid).await {
+                Ok(json) => {
+                    let mut user = json.get("user").clone();
+                    user.set("admin_entry", "");
+                    user.set("roles", Value::List(Vec::new()));
+                    user.set("profile", "{}");
+                    user
+                }
+                Err(err) => {
+                    tracing::warn!(%err, uid, host = %client.server(), "Loading remote user failed");
+                    return text_response("404 User not found").status(StatusCode::NOT_FOUND);
+                }
+            },
+            Target::Local => match LOCAL_AUTH.admin_get_user(uid).await {
+                Some(user) => {
+                    let admin_entry = format!("{}@local", uid);
+                    let roles: Vec<Value> = op::read_admin_entries()
+                        .into_iter()
+                        .filter(|entry| *entry == admin_entry)
+                        .map(|entry| object!(entry))
+                        .collect();
+                    let profile = match &user.profile {
+                        Value::None => "{}".to_string(),
+                        profile => profile.into_json(),
+                    };
+                    let mut value = object!({
+                        uid: uid,
+                        username: &user.username,
+                        email: &user.email,
+                        is_active: user.is_active,
+                        is_admin: !roles.is_empty(),
+                        admin_entry: admin_entry,
+                        roles: Value::List(roles),
+                        profile: profile,
+                    });
+                    add_activity(&mut value, &user, LOCAL_AUTH.admin_session_count(uid).await);
+                    value
+                }
+                None => return text_response("404 User not found").status(StatusCode::NOT_FOUND),
+            },
+        };
+        let back_url = match target.host_param().as_str() {
+            "" => "/admin/panel".to_string(),
+            host => format!("/admin/panel?{}", host),
         };
 
         akari_render!(
-            "admin/user_edit.html",
-            pageprop = pageprop(req, "Edit User", "Edit user account"),
+            "admin/user_detail.html",
+            pageprop = pageprop(req, "User Details", "Profile, sessions and account actions"),
             path = into_path_l(req, vec!["home", "admin", "user"]),
             user = user,
+            remote = matches!(target, Target::Remote(_)),
+            host = target.host(),
+            api = target.api(),
+            back_url = back_url,
+            audit_title = "Admin actions",
+            // Actions on the account and its admin role alike
+            audit = Value::List(sudo::history("", &format!("{}@{}", uid, target.host()), 20)),
         )
     }
 }
@@ -109,13 +307,13 @@ endpoint! {
             return json_response(object!({ success: false, message: "Unauthorized" }))
                 .status(StatusCode::UNAUTHORIZED);
         }
-        let page = req.query("page").unwrap_or("1".to_string());
-        let path = format!("/admin/users?page={}", page);
+        let query = match req.query_as::<UserQuery>() {
+            Ok(query) => query,
+            Err(res) => return res,
+        };
+        let path = format!("/admin/users?{}", query.to_query_string());
         let data = admin_fetch_json(req, &path).await
             .unwrap_or_else(|| object!({ users: [], total: 0 }));
-        json_response(object!({
-            users: data.get("users").clone(),
-            total: data.get("total").clone(),
-        }))
+        json_response(data)
     }
 }
@@ -0,0 +1,137 @@
+//! read_only.rs
+//!
+//! Read-only mode of the admin API, for staging mirrors or while an
+//! incident is looked into. When on, every `/admin/*` request other than
+//! `GET`, `HEAD` and `OPTIONS` is answered `403` with
+//! `{"message": "read-only", "read_only": true}` by [`AdminReadOnly`], and
+//! the pages show a banner (`pageprop.admin_read_only`).
+//!
+//! It starts as set in `programfiles/op/admin.json`:
+//!
+//! ```json
+//! { "read_only": false }
+//! ```
+//!
+//! and admins switch it at `POST /admin/read_only`, the one mutation left
+//! open, in sudo mode (`super::sudo`). The switch is written back to the
+//! file, so a restart keeps it.
+
+use hotaru::http::*;
+use hotaru::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::APP;
+use crate::admin::{check_is_admin, sudo};
+use crate::methods;
+
+/// The path of the switch, let through in read-only mode
+pub const TOGGLE_PATH: &str = "/admin/read_only";
+
+static READ_ONLY: Lazy<AtomicBool> = Lazy::new(|| AtomicBool::new(read_config().get("read_only").boolean()));
+
+fn config_path() -> std::path::PathBuf {
+    crate::op::programfiles().join("op/admin.json")
+}
+
+fn read_config() -> Value {
+    Value::from_jsonf(config_path().to_str().unwrap()).unwrap_or(Value::None)
+}
+
+/// Whether the admin API is read-only
+pub fn enabled() -> bool {
+    READ_ONLY.load(Ordering::Relaxed)
+}
+
+/// Switch read-only mode, keeping it in `admin.json`
+pub fn set(read_only: bool) -> std::io::Result<()> {
+    let mut config = match read_config() {
+        config @ Value::Dict(_) => config,
+        _ => object!({}),
+    };
+    config.set("read_only", read_only);
+    config
+        .into_jsonf(config_path().to_str().unwrap())
+        .map_err(|err| std::io::Error::other(format!("{:?}", err)))?;
+    READ_ONLY.store(read_only, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Whether `path` is one of the admin panel and API
+pub fn is_admin_path(path: &str) -> bool {
+    path == "/admin" || path.starts_with("/admin/")
+}
+
+/// Whether read-only mode refuses a `method` request to `path`
+pub fn refuses(method: &HttpMethod, path: &str) -> bool {
+    let reads = matches!(method, HttpMethod::GET | HttpMethod::HEAD | HttpMethod::OPTIONS);
+    is_admin_path(path) && !reads && path.trim_end_matches('/') != TOGGLE_PATH
+}
+
+middleware! {
+    /// Middleware refusing the changes to `/admin/*` in read-only mode.
+    /// Add it before the session middleware; it needs no user.
+    pub AdminReadOnly <HTTP> {
+        if enabled() && refuses(&req.method(), &req.path()) {
+            req.response = json_response(object!({ success: false, message: "read-only", read_only: true }))
+                .status(StatusCode::FORBIDDEN);
+            return Ok(req)
+        }
+        next(req).await
+    }
+}
+
+endpoint! {
+    APP.url("/admin/read_only"),
+
+    /// Read-only mode of the admin API
+    ///
+    /// # Request
+    /// `GET /admin/read_only`
+    ///
+    /// `POST /admin/read_only` with the form fields `enabled` (`true` or
+    /// `false`) and `reason`, in sudo mode
+    ///
+    /// # Response
+    /// `{"success": true, "read_only": true}`
+    pub admin_read_only <HTTP> {
+        if !check_is_admin(req).await {
+            return json_response(object!({ success: false, message: "Unauthorized" }))
+                .status(StatusCode::UNAUTHORIZED);
+        }
+        methods!(req, {
+            GET => { json_response(object!({ success: true, read_only: enabled() })) }
+            POST => {
+                let sudo = match sudo::require_sudo(req).await {
+                    Ok(sudo) => sudo,
+                    Err(response) => return response,
+                };
+                let read_only = req.form_or_default().await.get_or_default("enabled") == "true";
+                match set(read_only) {
+                    Ok(()) => {
+                        let action = if read_only { "read_only" } else { "writable" };
+                        sudo::audit(action, "admin_api", "read_only", &sudo);
+                        json_response(object!({ success: true, read_only: read_only }))
+                    }
+                    Err(err) => json_response(object!({ success: false, message: err.to_string() }))
+                        .status(StatusCode::INTERNAL_SERVER_ERROR),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_admin_changes_are_refused() {
+        assert!(refuses(&HttpMethod::POST, "/admin/users/5/delete"));
+        assert!(refuses(&HttpMethod::DELETE, "/admin/media/a.png"));
+        assert!(!refuses(&HttpMethod::GET, "/admin/users/5"));
+        assert!(!refuses(&HttpMethod::HEAD, "/admin/panel"));
+        assert!(!refuses(&HttpMethod::POST, "/admin/read_only"));
+        assert!(!refuses(&HttpMethod::POST, "/administrator"));
+        assert!(!refuses(&HttpMethod::POST, "/user/login"));
+    }
+}
@@ -0,0 +1,168 @@
+//! remote.rs
+//!
+//! `/admin/remote/users/*` mirrors the `/admin/users/*` JSON API for the
+//! MainAuth server the admin is signed in to, so the panel pages drive
+//! either one by swapping the path. Every call goes through `AuthClient`
+//! with the session's token; the remote server checks it again, so this
+//! proxy grants nothing the token does not already allow there.
+//!
+//! Deleting a user and resetting their password need sudo mode here as
+//! well (`super::sudo`): the reason is passed on for the remote server to
+//! log, and logged here with the `uid@server` of the user.
+
+use hotaru::http::*;
+use hotaru::prelude::*;
+
+use crate::APP;
+use crate::admin::api::UserQuery;
+use crate::admin::sudo;
+use crate::extract::Extract;
+use crate::user::AuthClient;
+use crate::user::client::{ClientError, UserEdit};
+use crate::methods;
+
+/// The client for the MainAuth server of the session, once that server
+/// confirms the signed-in user is one of its admins
+pub async fn remote_admin(req: &HttpReqCtx) -> Option<AuthClient> {
+    let client = AuthClient::from_session(req)?;
+    client.is_admin().await.then_some(client)
+}
+
+fn unauthorized() -> HttpResponse {
+    json_response(object!({ success: false, message: "Unauthorized" })).status(StatusCode::UNAUTHORIZED)
+}
+
+/// Answer with what the remote server answered
+fn forward(result: Result<Value, ClientError>) -> HttpResponse {
+    match result {
+        Ok(json) => json_response(json),
+        Err(ClientError::Rejected { status, message }) => {
+            json_response(object!({ success: false, message: message })).status(status)
+        }
+        Err(err @ ClientError::Transport(_)) => {
+            tracing::error!(%err, "Remote admin call failed");
+            json_response(object!({ success: false, message: err.to_string() })).status(StatusCode::BAD_GATEWAY)
+        }
+        Err(err @ ClientError::Unsupported(_)) => {
+            tracing::error!(%err, "Remote admin call not offered by the server");
+            json_response(object!({ success: false, message: err.to_string() })).status(StatusCode::NOT_IMPLEMENTED)
+        }
+    }
+}
+
+endpoint! {
+    APP.url("/admin/remote/users"),
+
+    pub remote_users <HTTP> {
+        let Some(client) = remote_admin(req).await else { return unauthorized() };
+        methods!(req, {
+            GET => {
+                let query = match req.query_as::<UserQuery>() {
+                    Ok(query) => query,
+                    Err(res) => return res,
+                };
+                forward(client.list_users(&query).await)
+            }
+            POST => {
+                let form = req.form_or_default().await.clone();
+                forward(client.create_user(
+                    form.get_or_default("username"),
+                    form.get_or_default("email"),
+                    form.get_or_default("password"),
+                    form.get_or_default("date_of_birth"),
+                ).await)
+            }
+        })
+    }
+}
+
+endpoint! {
+    APP.url("/admin/remote/users/<uid>"),
+
+    pub remote_user <HTTP> {
+        let Some(client) = remote_admin(req).await else { return unauthorized() };
+        let uid = match req.path_arg::<u32>("uid") {
+            Ok(uid) => uid,
+            Err(res) => return res,
+        };
+        methods!(req, {
+            GET => { forward(client.get_user(uid).await) }
+            POST => {
+                let edit = UserEdit::from_form(req.form_or_default().await);
+                forward(client.edit_user(uid, edit).await)
+            }
+        })
+    }
+}
+
+endpoint! {
+    APP.url("/admin/remote/users/<uid>/password"),
+
+    pub remote_user_password <HTTP> {
+        let Some(client) = remote_admin(req).await else { return unauthorized() };
+        methods!(req, POST);
+        let uid = match req.path_arg::<u32>("uid") {
+            Ok(uid) => uid,
+            Err(res) => return res,
+        };
+        let sudo = match sudo::require_sudo(req).await {
+            Ok(sudo) => sudo,
+            Err(response) => return response,
+        };
+        let new_password = req.form_or_default().await.get_or_default("new_password").clone();
+        let result = client.reset_password(uid, &new_password, &sudo.reason).await;
+        if result.is_ok() {
+            sudo::audit("reset_password", "user", &format!("{}@{}", uid, client.server()), &sudo);
+        }
+        forward(result)
+    }
+}
+
+endpoint! {
+    APP.url("/admin/remote/users/<uid>/delete"),
+
+    pub remote_user_delete <HTTP> {
+        let Some(client) = remote_admin(req).await else { return unauthorized() };
+        methods!(req, POST);
+        let uid = match req.path_arg::<u32>("uid") {
+            Ok(uid) => uid,
+            Err(res) => return res,
+        };
+        let sudo = match sudo::require_sudo(req).await {
+            Ok(sudo) => sudo,
+            Err(response) => return response,
+        };
+        let result = client.delete_user(uid, &sudo.reason).await;
+        if result.is_ok() {
+            sudo::audit("delete", "user", &format!("{}@{}", uid, client.server()), &sudo);
+        }
+        forward(result)
+    }
+}
+
+endpoint! {
+    APP.url("/admin/remote/users/<uid>/sessions"),
+
+    pub remote_user_sessions <HTTP> {
+        let Some(client) = remote_admin(req).await else { return unauthorized() };
+        let uid = match req.path_arg::<u32>("uid") {
+            Ok(uid) => uid,
+            Err(res) => return res,
+        };
+        forward(client.sessions(uid).await)
+    }
+}
+
+endpoint! {
+    APP.url("/admin/remote/users/<uid>/sessions/revoke"),
+
+    pub remote_user_revoke_sessions <HTTP> {
+        let Some(client) = remote_admin(req).await else { return unauthorized() };
+        methods!(req, POST);
+        let uid = match req.path_arg::<u32>("uid") {
+            Ok(uid) => uid,
+            Err(res) => return res,
+        };
+        forward(client.revoke_sessions(uid).await)
+    }
+}
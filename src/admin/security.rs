@@ -0,0 +1,190 @@
+use hotaru::http::*;
+use hotaru::prelude::*;
+
+use crate::APP;
+use crate::admin::api::ATTACKED_AFTER;
+use crate::admin::check_is_admin;
+use crate::local_auth::LOCAL_AUTH;
+use crate::local_auth::rules::{self, RuleSet};
+use crate::op::{into_path_l, pageprop};
+use crate::methods;
+
+/// How many security events the dashboard shows
+const RECENT_EVENTS: usize = 20;
+
+/// The figures of the security dashboard
+///
+/// # Returns
+/// ```json
+/// {
+///     "logins": [{ "start": 1700000000, "succeeded": 3, "failed": 1, "blocked": 0 }],
+///     "totals": { "succeeded": 3, "failed": 1, "blocked": 0 },
+///     "rate_limited": 0,
+///     "sessions": 2,
+///     "active_bans": 1,
+///     "locked_accounts": [{ "uid": 4, "username": "bob" }],
+///     "attacked_accounts": [{ "uid": 1, "username": "Admin", "failed_logins": 7, "last_failed_login": 1700000000 }],
+///     "events": [{ "time": 1700000000, "uid": 1, "rule": "burst", "kind": "velocity", "action": "block", ... }]
+/// }
+/// ```
+/// `logins` holds the last 24 hours, oldest first; `rate_limited` counts
+/// the logins refused by `velocity` rules over the same day.
+pub async fn stats() -> Value {
+    let hours = LOCAL_AUTH.login_stats().await;
+    let since = hours.first().map(|hour| hour.start).unwrap_or_default();
+    let mut totals = (0, 0, 0);
+    let logins: Vec<Value> = hours
+        .iter()
+        .map(|hour| {
+            totals.0 += hour.succeeded;
+            totals.1 += hour.failed;
+            totals.2 += hour.blocked;
+            object!({ start: hour.start, succeeded: hour.succeeded, failed: hour.failed, blocked: hour.blocked })
+        })
+        .collect();
+
+    let users = LOCAL_AUTH.admin_list_users().await;
+    let locked: Vec<Value> = users
+        .iter()
+        .filter(|(_, user)| !user.is_active)
+        .map(|(uid, user)| object!({ uid: *uid, username: &user.username }))
+        .collect();
+    let mut attacked: Vec<_> = users.iter().filter(|(_, user)| user.activity.failed_logins >= ATTACKED_AFTER).collect();
+    attacked.sort_by_key(|(_, user)| std::cmp::Reverse(user.activity.failed_logins));
+    let attacked: Vec<Value> = attacked
+        .into_iter()
+        .map(|(uid, user)| {
+            object!({
+                uid: *uid,
+                username: &user.username,
+                failed_logins: user.activity.failed_logins,
+                last_failed_login: user.activity.last_failed_login.unwrap_or_default(),
+            })
+        })
+        .collect();
+
+    let events = rules::events();
+    let rate_limited = events
+        .iter()
+        .filter(|event| event.kind == "velocity" && event.action != rules::Action::Notify && event.time >= since)
+        .count();
+    let recent: Vec<Value> = events
+        .iter()
+        .take(RECENT_EVENTS)
+        .map(|event| {
+            let mut value = event.into_json();
+            value.set("place", event.location.as_ref().map(|location| location.to_string()).unwrap_or_default());
+            value
+        })
+        .collect();
+
+    object!({
+        logins: Value::List(logins),
+        totals: object!({ succeeded: totals.0, failed: totals.1, blocked: totals.2 }),
+        rate_limited: rate_limited,
+        sessions: LOCAL_AUTH.admin_total_sessions().await,
+        active_bans: crate::bans::list().len(),
+        locked_accounts: Value::List(locked),
+        attacked_accounts: Value::List(attacked),
+        events: Value::List(recent),
+    })
+}
+
+endpoint! {
+    APP.url("/admin/security"),
+
+    pub security_dashboard <HTTP> {
+        if !check_is_admin(req).await {
+            return redirect_response("/user/unauthorized");
+        }
+        let stats = stats().await;
+        let busiest = stats
+            .get("logins")
+            .list()
+            .iter()
+            .map(|hour| hour.get("failed").integer() + hour.get("blocked").integer())
+            .max()
+            .unwrap_or_default()
+            .max(1);
+        let logins: Vec<Value> = stats
+            .get("logins")
+            .list()
+            .into_iter()
+            .map(|mut hour| {
+                let height = (hour.get("failed").integer() + hour.get("blocked").integer()) * 100 / busiest;
+                hour.set("height", height);
+                hour
+            })
+            .collect();
+        akari_render!(
+            "admin/security.html",
+            pageprop = pageprop(req, "Security", "Failed logins, locked accounts and suspicious logins"),
+            path = into_path_l(req, vec!["home", "admin"]),
+            logins = Value::List(logins),
+            totals = stats.get("totals").clone(),
+            rate_limited = stats.get("rate_limited").clone(),
+            sessions = stats.get("sessions").clone(),
+            active_bans = stats.get("active_bans").clone(),
+            locked_accounts = stats.get("locked_accounts").clone(),
+            attacked_accounts = stats.get("attacked_accounts").clone(),
+            events = stats.get("events").clone()
+        )
+    }
+}
+
+endpoint! {
+    APP.url("/admin/security/stats"),
+
+    /// GET /admin/security/stats - The figures of the security dashboard, see `stats`
+    /// Response: {"success": true, "logins": [...], "totals": {...}, ...}
+    pub security_stats <HTTP> {
+        if !check_is_admin(req).await {
+            return json_response(object!({ success: false, message: "Unauthorized" }))
+                .status(StatusCode::UNAUTHORIZED);
+        }
+        let mut stats = stats().await;
+        stats.set("success", true);
+        json_response(stats)
+    }
+}
+
+endpoint! {
+    APP.url("/admin/security/rules"),
+
+    /// GET: the editor of `op/login_rules.json`
+    /// POST: Json -> the whole ruleset, put in force once it parses
+    /// Response: {"success": true} or {"success": false, "message": "..."}
+    pub security_rules <HTTP> {
+        methods!(req, {
+            GET => {
+                if !check_is_admin(req).await {
+                    return redirect_response("/user/unauthorized");
+                }
+                akari_render!(
+                    "admin/security_rules.html",
+                    pageprop = pageprop(req, "Login rules", "Rules flagging suspicious logins"),
+                    path = into_path_l(req, vec!["home", "admin"]),
+                    rules = rules::current().into_value().into_json()
+                )
+            }
+            POST => {
+                if !check_is_admin(req).await {
+                    return json_response(object!({ success: false, message: "Unauthorized" }))
+                        .status(StatusCode::UNAUTHORIZED);
+                }
+                let ruleset = match RuleSet::from_value(req.json_or_default().await) {
+                    Ok(ruleset) => ruleset,
+                    Err(err) => {
+                        return json_response(object!({ success: false, message: err }))
+                            .status(StatusCode::BAD_REQUEST);
+                    }
+                };
+                match rules::save(ruleset) {
+                    Ok(()) => json_response(object!({ success: true })),
+                    Err(err) => json_response(object!({ success: false, message: err.to_string() }))
+                        .status(StatusCode::INTERNAL_SERVER_ERROR),
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,149 @@
+use hotaru::http::*;
+use hotaru::prelude::*;
+
+use crate::APP;
+use crate::admin::check_is_admin;
+use crate::op::{escape_html, into_path_l, pageprop};
+use crate::settings::{self, SettingsError};
+use crate::methods;
+
+fn unauthorized() -> HttpResponse {
+    json_response(object!({ success: false, message: "Unauthorized" })).status(StatusCode::UNAUTHORIZED)
+}
+
+fn settings_error(err: SettingsError) -> HttpResponse {
+    let status = match err {
+        SettingsError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::BAD_REQUEST,
+    };
+    json_response(object!({ success: false, message: err.to_string() })).status(status)
+}
+
+/// The value typed in the editor: JSON, or else the text itself
+fn typed(text: &str) -> Value {
+    Value::from_json(text.trim()).unwrap_or_else(|_| Value::Str(text.to_string()))
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Str(_) => "string",
+        Value::Numerical(_) => "number",
+        Value::Boolean(_) => "boolean",
+        Value::List(_) => "list",
+        Value::Dict(_) => "object",
+        _ => "null",
+    }
+}
+
+endpoint! {
+    APP.url("/admin/settings"),
+
+    /// GET: every namespace with its settings, editable in place
+    pub admin_settings <HTTP> {
+        if !check_is_admin(req).await {
+            return redirect_response("/user/unauthorized");
+        }
+        let namespaces: Vec<Value> = settings::namespaces()
+            .iter()
+            .map(|name| {
+                let items: Vec<Value> = settings::namespace(name)
+                    .all()
+                    .iter()
+                    .map(|(key, value)| object!({ key: escape_html(key), value: escape_html(&value.into_json()), kind: type_name(value) }))
+                    .collect();
+                object!({ name: name, items: items })
+            })
+            .collect();
+        akari_render!(
+            "admin/settings.html",
+            pageprop = pageprop(req, "Settings", "Application settings"),
+            path = into_path_l(req, vec!["home", "admin"]),
+            namespaces = Value::List(namespaces)
+        )
+    }
+}
+
+endpoint! {
+    APP.url("/admin/settings/json"),
+
+    /// GET /admin/settings/json - Every setting by namespace and key
+    /// Response: {"success": true, "settings": {"shop": {"max_items": 20}}}
+    pub admin_settings_json <HTTP> {
+        if !check_is_admin(req).await {
+            return unauthorized();
+        }
+        let mut all = Value::new_dict();
+        for name in settings::namespaces() {
+            let mut values = Value::new_dict();
+            for (key, value) in settings::namespace(&name).all() {
+                values.set(&key, value);
+            }
+            all.set(&name, values);
+        }
+        json_response(object!({ success: true, settings: all }))
+    }
+}
+
+endpoint! {
+    APP.url("/admin/settings/<namespace>/set"),
+
+    /// POST /admin/settings/<namespace>/set - Make or change a setting
+    /// Form -> key, value (JSON like `20`, `true` or `"text"`; anything else is taken as text)
+    pub admin_settings_set <HTTP> {
+        if !check_is_admin(req).await {
+            return unauthorized();
+        }
+        methods!(req, POST);
+        let namespace = settings::namespace(&req.param("namespace").unwrap_or_default());
+        let form = req.form_or_default().await;
+        let key = form.get_or_default("key").clone();
+        let value = typed(form.get_or_default("value"));
+        match namespace.set(&key, value.clone()) {
+            Ok(()) => json_response(object!({ success: true, key: key, value: value })),
+            Err(err) => settings_error(err),
+        }
+    }
+}
+
+endpoint! {
+    APP.url("/admin/settings/<namespace>/delete"),
+
+    /// POST /admin/settings/<namespace>/delete - Remove a setting
+    /// Form -> key
+    pub admin_settings_delete <HTTP> {
+        if !check_is_admin(req).await {
+            return unauthorized();
+        }
+        methods!(req, POST);
+        let namespace = settings::namespace(&req.param("namespace").unwrap_or_default());
+        let key = req.form_or_default().await.get_or_default("key").clone();
+        match namespace.remove(&key) {
+            Ok(true) => json_response(object!({ success: true })),
+            Ok(false) => json_response(object!({ success: false, message: "Setting not found" })).status(StatusCode::NOT_FOUND),
+            Err(err) => settings_error(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestRequest};
+
+    #[test]
+    fn editor_values_are_json_or_text() {
+        assert_eq!(typed("20"), Value::from(20));
+        assert_eq!(typed(" true "), Value::Boolean(true));
+        assert_eq!(typed("\"20\""), Value::from("20"));
+        assert_eq!(typed("hello there"), Value::from("hello there"));
+        assert_eq!(type_name(&typed("[1, 2]")), "list");
+    }
+
+    #[tokio::test]
+    async fn guests_are_turned_away() {
+        let mut req = TestRequest::get("/admin/settings/json").build();
+        let res = testing::respond(admin_settings_json(&mut req).await, &mut req);
+        assert_eq!(testing::status(&res), 401);
+        assert!(!testing::json(&res).get("success").boolean());
+    }
+}
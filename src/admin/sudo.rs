@@ -0,0 +1,144 @@
+//! sudo.rs
+//!
+//! Sudo mode for the admin actions that cannot be taken back: deleting a
+//! user, resetting their password and granting or removing the admin role
+//! (and `sfx backup restore` on the command line). Each needs a `reason`
+//! with the request and an admin who confirmed their password within
+//! `SUDO_SECONDS`, at `/user/confirm` for the panel or with `/auth/reauth`
+//! for a bearer token; otherwise [`require_sudo`] answers `400` with
+//! `reason_required` or `403` with `sudo`, which the panel pages act on.
+//!
+//! Done actions are appended to `programfiles/admin_info/audit.log`, one
+//! JSON object a line with the time, action, kind, target, the admin and
+//! the reason. The user and admin pages show the entries of their target.
+
+use hotaru::http::*;
+use hotaru::prelude::*;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::local_auth::analyze::get_auth_token;
+use crate::local_auth::reauth;
+use crate::local_auth::LOCAL_AUTH;
+use crate::user::confirm::confirmed_within;
+use crate::user::fetch::get_user_id;
+
+/// Seconds a password confirmation holds for sudo mode
+pub const SUDO_SECONDS: u64 = 15 * 60;
+/// Characters a reason may have
+pub const MAX_REASON: usize = 500;
+
+/// Who takes an action in sudo mode, and why
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sudo {
+    /// `uid@server` of the admin, or `cli:<user>` for the command line
+    pub by: String,
+    pub reason: String,
+}
+
+fn log_path(programfiles: &Path) -> PathBuf {
+    programfiles.join("admin_info/audit.log")
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// `reason` trimmed, without control characters; `None` when it is empty
+pub fn reason(reason: &str) -> Option<String> {
+    let reason: String = reason.trim().chars().filter(|c| !c.is_control()).take(MAX_REASON).collect();
+    (!reason.is_empty()).then_some(reason)
+}
+
+/// The admin and reason of an action that needs sudo mode, once `req`
+/// checked as an admin. Otherwise the error is the response to send.
+pub async fn require_sudo(req: &mut HttpReqCtx) -> Result<Sudo, HttpResponse> {
+    let Some(reason) = reason(req.form_or_default().await.get_or_default("reason")) else {
+        return Err(json_response(object!({ success: false, message: "Give a reason for this action", reason_required: true }))
+            .status(StatusCode::BAD_REQUEST));
+    };
+    let by = match get_auth_token(req) {
+        Some(token) => match reauth::require_recent_auth(req, SUDO_SECONDS).await {
+            Ok(()) => LOCAL_AUTH.uid_of_token(&token).await.map(|uid| format!("{}@local", uid)),
+            Err(_) => None,
+        },
+        None if confirmed_within(req, SUDO_SECONDS) => Some(get_user_id(req).await.to_string()),
+        None => None,
+    };
+    match by {
+        Some(by) => Ok(Sudo { by, reason }),
+        None => Err(json_response(object!({ success: false, message: "Confirm your password to continue", sudo: true }))
+            .status(StatusCode::FORBIDDEN)),
+    }
+}
+
+/// Append `action` on `target` of `kind` to the audit log of `programfiles`
+pub fn record(programfiles: &Path, action: &str, kind: &str, target: &str, sudo: &Sudo) {
+    let entry = object!({
+        time: now(),
+        action: action,
+        kind: kind,
+        target: target,
+        by: &sudo.by,
+        reason: &sudo.reason,
+    });
+    let path = log_path(programfiles);
+    let written = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| std::fs::OpenOptions::new().create(true).append(true).open(&path))
+        .and_then(|mut log| writeln!(log, "{}", entry.into_json()));
+    match written {
+        Ok(()) => tracing::info!(action, kind, target, by = %sudo.by, reason = %sudo.reason, "Admin action"),
+        Err(err) => tracing::error!(%err, "Failed to write the admin audit log"),
+    }
+}
+
+/// Append `action` on `target` of `kind` to the audit log
+pub fn audit(action: &str, kind: &str, target: &str, sudo: &Sudo) {
+    record(&crate::op::programfiles(), action, kind, target, sudo);
+}
+
+/// The last `count` entries of `kind` on `target`, newest first; an empty
+/// `kind` or `target` matches any
+pub fn history_in(programfiles: &Path, kind: &str, target: &str, count: usize) -> Vec<Value> {
+    let log = std::fs::read_to_string(log_path(programfiles)).unwrap_or_default();
+    log.lines()
+        .rev()
+        .filter_map(|line| Value::from_json(line).ok())
+        .filter(|entry| kind.is_empty() || entry.get("kind").string() == kind)
+        .filter(|entry| target.is_empty() || entry.get("target").string() == target)
+        .take(count)
+        .collect()
+}
+
+/// [`history_in`] the audit log of this site
+pub fn history(kind: &str, target: &str, count: usize) -> Vec<Value> {
+    history_in(&crate::op::programfiles(), kind, target, count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn actions_are_logged_with_their_reason() {
+        assert_eq!(reason("  spam\u{7} account "), Some("spam account".to_string()));
+        assert_eq!(reason(" \n "), None);
+        assert_eq!(reason(&"x".repeat(MAX_REASON + 10)).map(|reason| reason.len()), Some(MAX_REASON));
+
+        let dir = std::env::temp_dir().join(format!("sfx-sudo-{}", std::process::id()));
+        let sudo = Sudo { by: "1@local".to_string(), reason: "Asked to leave".to_string() };
+        record(&dir, "delete", "user", "5@local", &sudo);
+        record(&dir, "grant", "admin", "5@local", &sudo);
+        record(&dir, "delete", "user", "6@local", &sudo);
+
+        let entries = history_in(&dir, "user", "5@local", 10);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].get("by").string(), "1@local");
+        assert_eq!(entries[0].get("reason").string(), "Asked to leave");
+        assert_eq!(history_in(&dir, "user", "", 10)[0].get("target").string(), "6@local");
+        assert_eq!(history_in(&dir, "", "5@local", 10).len(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,494 @@
+//! analytics.rs
+//!
+//! First-party page view and event counts, set up in
+//! `programfiles/op/analytics.json`:
+//!
+//! ```json
+//! { "enabled": true, "consent": "analytics", "flush_interval": 300, "max_keys": 500 }
+//! ```
+//!
+//! Pages post to `POST /op/analytics` (the snippet of
+//! `base/analytics.html`). Nothing about the visitor is kept: events are
+//! added to per-day counts of pages, referring sites, languages, custom
+//! event names and the exposures and conversions of the experiments of
+//! `crate::flags`, which are flushed every `flush_interval` seconds to
+//! `programfiles/analytics/YYYY-MM-DD.json`. Unique visitors are counted
+//! with a hash of the address and user agent salted with a random value
+//! that is never written and changes every day, so the count of a day
+//! starts over when the server restarts.
+//!
+//! Events are only recorded from visitors who agreed to the `consent`
+//! category of `consent.json` (an empty `consent` records everybody), and
+//! never from browsers sending `DNT: 1` or `Sec-GPC: 1`. Referrers are
+//! reduced to their host; links within the site are not counted. Each day
+//! keeps at most `max_keys` pages, referrers and event names, later ones
+//! are counted as `(other)`.
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+use std::collections::{BTreeMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::op::{self, APP};
+use crate::proxy;
+use crate::methods;
+
+static ANALYTICS: Lazy<AnalyticsSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/analytics.json");
+    AnalyticsSettings::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None))
+});
+
+static COLLECTOR: Lazy<Mutex<Collector>> = Lazy::new(|| Mutex::new(Collector::default()));
+
+/// Key the entries beyond `max_keys` are counted under
+pub const OTHER: &str = "(other)";
+
+/// Longest path or event name kept
+const MAX_LEN: usize = 200;
+
+/// Characters besides ASCII letters and digits allowed in paths. The counts
+/// end up in the admin pages unescaped, so nothing that could open markup.
+const PATH_CHARS: &str = "/-._~%!$&()*+,;=:@";
+
+fn now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// The UTC day of the unix time `secs` as `YYYY-MM-DD`
+pub fn date(secs: u64) -> String {
+    let stamp = crate::backup::timestamp(secs);
+    format!("{}-{}-{}", &stamp[..4], &stamp[4..6], &stamp[6..8])
+}
+
+fn day_path(date: &str) -> PathBuf {
+    crate::op::programfiles().join("analytics").join(format!("{}.json", date))
+}
+
+/// The parsed content of `analytics.json`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnalyticsSettings {
+    pub enabled: bool,
+    /// Category of `consent.json` visitors must agree to, empty for none
+    pub consent: String,
+    pub flush_interval: Duration,
+    pub max_keys: usize,
+}
+
+impl Default for AnalyticsSettings {
+    fn default() -> Self {
+        Self { enabled: false, consent: "analytics".to_string(), flush_interval: Duration::from_secs(300), max_keys: 500 }
+    }
+}
+
+impl AnalyticsSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let default = Self::default();
+        Self {
+            enabled: value.get("enabled").boolean(),
+            consent: match value.get("consent") {
+                Value::Str(consent) => consent.clone(),
+                _ => default.consent,
+            },
+            flush_interval: match value.get("flush_interval").integer() {
+                secs if secs > 0 => Duration::from_secs(secs as u64),
+                _ => default.flush_interval,
+            },
+            max_keys: match value.get("max_keys").integer() {
+                keys if keys > 0 => keys as usize,
+                _ => default.max_keys,
+            },
+        }
+    }
+}
+
+/// The loaded analytics settings
+pub fn settings() -> &'static AnalyticsSettings {
+    &ANALYTICS
+}
+
+/// What an [`Event`] counts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Kind {
+    PageView,
+    /// A custom event and its name
+    Custom(String),
+    /// A variant of an experiment shown, as `(experiment, variant)`
+    Exposure(String, String),
+    /// The goal of an experiment reached, as `(experiment, variant)`
+    Conversion(String, String),
+}
+
+/// A page view or another event, as posted by a page
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub path: String,
+    pub kind: Kind,
+    /// Host of the referring site
+    pub referrer: Option<String>,
+    pub locale: String,
+}
+
+impl Event {
+    /// Read `{"type": "pageview" | "event" | "exposure" | "conversion", "path": "/docs",
+    /// "referrer": "https://...", "name": "signup", "experiment": "signup_button"}`
+    /// posted from a page of `host` in `locale`. `assigned` gives the
+    /// variant of an experiment the visitor is in.
+    pub fn from_value(
+        value: &Value,
+        host: &str,
+        locale: &str,
+        assigned: impl FnOnce(&str) -> Option<String>,
+    ) -> Result<Self, String> {
+        let path = value.get("path").string();
+        let path = path.split(['?', '#']).next().unwrap_or_default();
+        if !path.starts_with('/') || !path.chars().all(|c| c.is_ascii_alphanumeric() || PATH_CHARS.contains(c)) {
+            return Err("`path` must be a percent-encoded path starting with /".to_string());
+        }
+        let experiment = || {
+            let experiment = value.get("experiment").string();
+            assigned(&experiment)
+                .map(|variant| (experiment, variant))
+                .ok_or_else(|| "Unknown `experiment`".to_string())
+        };
+        let kind = match value.get("type").string().as_str() {
+            "" | "pageview" => Kind::PageView,
+            "event" => match value.get("name").string().trim() {
+                "" => return Err("Events need a `name`".to_string()),
+                name if !name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.: ".contains(c)) => {
+                    return Err("Event names may only hold letters, digits, spaces and -_.:".to_string());
+                }
+                name => Kind::Custom(truncate(name)),
+            },
+            "exposure" => experiment().map(|(experiment, variant)| Kind::Exposure(experiment, variant))?,
+            "conversion" => experiment().map(|(experiment, variant)| Kind::Conversion(experiment, variant))?,
+            _ => return Err("`type` must be pageview, event, exposure or conversion".to_string()),
+        };
+        let referrer = referrer_host(&value.get("referrer").string()).filter(|referrer| referrer != host);
+        Ok(Self { path: truncate(path), kind, referrer, locale: locale.to_string() })
+    }
+}
+
+fn truncate(text: &str) -> String {
+    text.chars().take(MAX_LEN).collect()
+}
+
+/// The host of a referring URL
+fn referrer_host(url: &str) -> Option<String> {
+    let (_, rest) = url.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority.rsplit('@').next()?.to_ascii_lowercase();
+    (!host.is_empty() && host.chars().all(|c| c.is_ascii_alphanumeric() || "-.:[]".contains(c))).then_some(host)
+}
+
+/// The counts of one day
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Day {
+    pub date: String,
+    pub pageviews: u64,
+    pub visitors: u64,
+    pub paths: BTreeMap<String, u64>,
+    pub referrers: BTreeMap<String, u64>,
+    pub locales: BTreeMap<String, u64>,
+    pub events: BTreeMap<String, u64>,
+    /// Exposures and conversions, keyed `experiment:variant:exposure` and
+    /// `experiment:variant:conversion`
+    pub experiments: BTreeMap<String, u64>,
+}
+
+impl Day {
+    pub fn new(date: &str) -> Self {
+        Self { date: date.to_string(), ..Self::default() }
+    }
+
+    /// Count `event`, from a visitor not seen that day if `new_visitor`
+    pub fn record(&mut self, event: &Event, new_visitor: bool, max_keys: usize) {
+        if new_visitor {
+            self.visitors += 1;
+        }
+        match &event.kind {
+            Kind::Custom(name) => count(&mut self.events, name, 1, max_keys),
+            // Experiments come from flags.json, so their keys are bounded already
+            Kind::Exposure(experiment, variant) => {
+                count(&mut self.experiments, &format!("{}:{}:exposure", experiment, variant), 1, usize::MAX)
+            }
+            Kind::Conversion(experiment, variant) => {
+                count(&mut self.experiments, &format!("{}:{}:conversion", experiment, variant), 1, usize::MAX)
+            }
+            Kind::PageView => {
+                self.pageviews += 1;
+                count(&mut self.paths, &event.path, 1, max_keys);
+                count(&mut self.locales, &event.locale, 1, max_keys);
+                if let Some(referrer) = &event.referrer {
+                    count(&mut self.referrers, referrer, 1, max_keys);
+                }
+            }
+        }
+    }
+
+    /// Add the counts of `other`
+    pub fn merge(&mut self, other: &Day, max_keys: usize) {
+        self.pageviews += other.pageviews;
+        self.visitors += other.visitors;
+        for (mine, theirs) in [
+            (&mut self.paths, &other.paths),
+            (&mut self.referrers, &other.referrers),
+            (&mut self.locales, &other.locales),
+            (&mut self.events, &other.events),
+        ] {
+            for (key, n) in theirs {
+                count(mine, key, *n, max_keys);
+            }
+        }
+        for (key, n) in &other.experiments {
+            count(&mut self.experiments, key, *n, usize::MAX);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pageviews == 0 && self.visitors == 0 && self.events.is_empty() && self.experiments.is_empty()
+    }
+
+    pub fn from_json(value: &Value) -> Self {
+        let counts = |key: &str| match value.get(key) {
+            Value::Dict(map) => map.iter().map(|(k, n)| (k.clone(), n.integer().max(0) as u64)).collect(),
+            _ => BTreeMap::new(),
+        };
+        Self {
+            date: value.get("date").string(),
+            pageviews: value.get("pageviews").integer().max(0) as u64,
+            visitors: value.get("visitors").integer().max(0) as u64,
+            paths: counts("paths"),
+            referrers: counts("referrers"),
+            locales: counts("locales"),
+            events: counts("events"),
+            experiments: counts("experiments"),
+        }
+    }
+
+    pub fn into_json(&self) -> Value {
+        let counts = |map: &BTreeMap<String, u64>| {
+            let mut value = Value::new_dict();
+            for (key, n) in map {
+                value.set(key, *n);
+            }
+            value
+        };
+        object!({
+            date: &self.date,
+            pageviews: self.pageviews,
+            visitors: self.visitors,
+            paths: counts(&self.paths),
+            referrers: counts(&self.referrers),
+            locales: counts(&self.locales),
+            events: counts(&self.events),
+            experiments: counts(&self.experiments),
+        })
+    }
+}
+
+/// Add `n` to `key`, or to [`OTHER`] once `map` holds `max_keys` keys
+fn count(map: &mut BTreeMap<String, u64>, key: &str, n: u64, max_keys: usize) {
+    let key = if map.contains_key(key) || map.len() < max_keys { key } else { OTHER };
+    *map.entry(key.to_string()).or_default() += n;
+}
+
+/// Counts not yet flushed, and the visitors of the current day
+#[derive(Debug, Default)]
+struct Collector {
+    pending: Day,
+    salt: String,
+    seen: HashSet<u64>,
+}
+
+impl Collector {
+    fn record(&mut self, event: &Event, visitor: &str, now: u64) {
+        let today = date(now);
+        if self.pending.date != today {
+            self.flush();
+            self.pending = Day::new(&today);
+            self.salt = hotaru_lib::random::random_alphanumeric_string(32);
+            self.seen.clear();
+        }
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        (&self.salt, visitor).hash(&mut hasher);
+        let new_visitor = self.seen.insert(hasher.finish());
+        self.pending.record(event, new_visitor, ANALYTICS.max_keys);
+    }
+
+    /// Add the pending counts to the file of their day
+    fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let path = day_path(&self.pending.date);
+        let mut day = load(&self.pending.date);
+        day.merge(&self.pending, ANALYTICS.max_keys);
+        let written = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&path, day.into_json().into_json()));
+        match written {
+            Ok(()) => self.pending = Day::new(&self.pending.date),
+            Err(err) => tracing::error!(path = %path.display(), %err, "Failed to write the analytics"),
+        }
+    }
+}
+
+/// The flushed counts of `date`
+fn load(date: &str) -> Day {
+    match Value::from_jsonf(day_path(date).to_string_lossy()) {
+        Ok(value) => Day { date: date.to_string(), ..Day::from_json(&value) },
+        Err(_) => Day::new(date),
+    }
+}
+
+/// Count `event` from `visitor` (address and user agent), which is only
+/// hashed
+pub fn record(event: &Event, visitor: &str) {
+    COLLECTOR.lock().unwrap().record(event, visitor, now());
+}
+
+/// Write the pending counts
+pub fn flush() {
+    COLLECTOR.lock().unwrap().flush();
+}
+
+/// The counts of the last `days` days up to `now`, oldest first
+pub fn report(days: u64, now: u64) -> Vec<Day> {
+    flush();
+    (0..days).rev().map(|ago| load(&date(now.saturating_sub(ago * 86_400)))).collect()
+}
+
+/// Start flushing every `flush_interval` of `analytics.json`, if enabled
+pub fn start() {
+    if !ANALYTICS.enabled {
+        return;
+    }
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(ANALYTICS.flush_interval).await;
+            flush();
+        }
+    });
+}
+
+/// Whether the pages of `req` should carry the analytics snippet
+pub fn tracks(req: &HttpReqCtx) -> bool {
+    ANALYTICS.enabled
+        && (ANALYTICS.consent.is_empty() || crate::consent::consent_given(req, &ANALYTICS.consent))
+        && !["dnt", "sec-gpc"].iter().any(|header| req.header_str(header) == Some("1"))
+}
+
+endpoint! {
+    APP.url("/op/analytics"),
+
+    /// Count a page view, custom event, or experiment exposure or
+    /// conversion. Requests without consent, or sending `DNT: 1` /
+    /// `Sec-GPC: 1`, are accepted but not counted.
+    ///
+    /// # Request
+    /// `POST /op/analytics`
+    /// Json: `{"type": "pageview", "path": "/docs", "referrer": "https://example.com/"}`,
+    /// `{"type": "event", "path": "/docs", "name": "signup"}` or
+    /// `{"type": "exposure" | "conversion", "path": "/docs", "experiment": "signup_button"}`
+    ///
+    /// # Response
+    /// `{"success": true, "recorded": true}`, `400` with a `message` for a
+    /// malformed event, `404` while analytics are disabled
+    pub record_analytics <HTTP> {
+        if !ANALYTICS.enabled {
+            return json_response(object!({ success: false, message: "Not found" })).status(StatusCode::NOT_FOUND);
+        }
+        methods!(req, POST);
+        if !tracks(req) {
+            return json_response(object!({ success: true, recorded: false }));
+        }
+        let public = proxy::public_origin(req);
+        let host = public.split_once("://").map(|(_, host)| host.to_ascii_lowercase()).unwrap_or_default();
+        let locale = op::lang(req);
+        let visitor = format!(
+            "{}|{}",
+            proxy::client_ip(req).map(|ip| ip.to_string()).unwrap_or_default(),
+            req.header_str("user-agent").unwrap_or_default()
+        );
+        let body = req.json_or_default().await.clone();
+        let assigned = crate::flags::variant(req, &body.get("experiment").string());
+        let event = match Event::from_value(&body, &host, &locale, |_| assigned) {
+            Ok(event) => event,
+            Err(message) => {
+                return json_response(object!({ success: false, message: message })).status(StatusCode::BAD_REQUEST);
+            }
+        };
+        record(&event, &visitor);
+        json_response(object!({ success: true, recorded: true }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unassigned(_: &str) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn events_are_counted_without_the_visitor() {
+        let view = Event::from_value(
+            &object!({ path: "/docs?q=secret#top", referrer: "https://user@Search.example/?q=sfx" }),
+            "site.example",
+            "en",
+            unassigned,
+        )
+        .unwrap();
+        assert_eq!(view.path, "/docs");
+        assert_eq!(view.referrer.as_deref(), Some("search.example"));
+        let internal = object!({ path: "/", referrer: "https://site.example/docs" });
+        assert_eq!(Event::from_value(&internal, "site.example", "en", unassigned).unwrap().referrer, None);
+        assert!(Event::from_value(&object!({ path: "docs" }), "", "en", unassigned).is_err());
+        assert!(Event::from_value(&object!({ path: "/<script>" }), "", "en", unassigned).is_err());
+        let odd_referrer = object!({ path: "/", referrer: "https://a\"b.example/" });
+        assert_eq!(Event::from_value(&odd_referrer, "", "en", unassigned).unwrap().referrer, None);
+        let unnamed = Value::from_json(r#"{ "type": "event", "path": "/" }"#).unwrap();
+        assert!(Event::from_value(&unnamed, "", "en", unassigned).is_err());
+        let signup = Value::from_json(r#"{ "type": "event", "path": "/", "name": "signup" }"#).unwrap();
+        let signup = Event::from_value(&signup, "", "fr", unassigned).unwrap();
+
+        let mut day = Day::new("2026-10-16");
+        day.record(&view, true, 2);
+        day.record(&signup, false, 2);
+        day.record(&Event { path: "/a".into(), ..view.clone() }, false, 2);
+        day.record(&Event { path: "/b".into(), ..view.clone() }, false, 2);
+        assert_eq!((day.pageviews, day.visitors), (3, 1));
+        assert_eq!(day.paths.get(OTHER), Some(&1));
+        assert_eq!(day.events.get("signup"), Some(&1));
+        assert!(!day.locales.contains_key("fr"));
+
+        let mut merged = Day::from_json(&day.into_json());
+        assert_eq!(merged, day);
+        merged.merge(&day, 2);
+        assert_eq!((merged.pageviews, merged.paths.get("/docs")), (6, Some(&2)));
+        assert_eq!(date(1_792_108_800), "2026-10-16");
+    }
+
+    #[test]
+    fn experiment_events_count_under_the_assigned_variant() {
+        let exposure = Value::from_json(r#"{ "type": "exposure", "path": "/", "experiment": "button", "variant": "claimed" }"#).unwrap();
+        let assigned = |experiment: &str| (experiment == "button").then(|| "green".to_string());
+        let event = Event::from_value(&exposure, "", "en", assigned).unwrap();
+        assert_eq!(event.kind, Kind::Exposure("button".into(), "green".into()));
+        let unknown = Value::from_json(r#"{ "type": "conversion", "path": "/", "experiment": "other" }"#).unwrap();
+        assert!(Event::from_value(&unknown, "", "en", assigned).is_err());
+
+        let mut day = Day::new("2026-10-16");
+        day.record(&event, false, 1);
+        day.record(&Event { kind: Kind::Conversion("button".into(), "green".into()), ..event.clone() }, false, 1);
+        day.merge(&day.clone(), 1);
+        assert_eq!(day.experiments.get("button:green:exposure"), Some(&2));
+        assert_eq!(day.experiments.get("button:green:conversion"), Some(&2));
+        assert_eq!(day.pageviews, 0);
+    }
+}
@@ -0,0 +1,284 @@
+//! backup.rs
+//!
+//! Timestamped archives of the data an sfx site cannot regenerate: its
+//! configuration and account store (`programfiles/`) and anything uploaded
+//! by users. Configured in `programfiles/op/backup.json`:
+//!
+//! ```json
+//! {
+//!     "dir": "backups",
+//!     "include": ["programfiles", "uploads"],
+//!     "keep": 7,
+//!     "interval": 86400
+//! }
+//! ```
+//!
+//! Archives are `sfx-backup-YYYYMMDD-HHMMSS.tar.gz` files in `dir`, written
+//! with the system `tar` and holding the `include` paths that exist.
+//! Only the `keep` newest are kept (0 keeps them all). With a non-zero
+//! `interval` (seconds) the server takes one on that schedule; backups can
+//! also be taken from `/admin/backups` or with `sfx backup create`.
+//!
+//! When `storage.json` puts uploads off the machine (see [`crate::storage`]),
+//! every new archive is also copied there under `backups/`, where the same
+//! `keep` applies.
+
+use hotaru::prelude::*;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::local_auth::LOCAL_AUTH;
+use crate::modules;
+use crate::storage;
+
+static BACKUP: Lazy<BackupSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/backup.json");
+    BackupSettings::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None))
+});
+
+const PREFIX: &str = "sfx-backup-";
+const SUFFIX: &str = ".tar.gz";
+
+/// The parsed content of `backup.json`
+#[derive(Debug, Clone)]
+pub struct BackupSettings {
+    pub dir: PathBuf,
+    pub include: Vec<PathBuf>,
+    pub keep: usize,
+    pub interval: Option<Duration>,
+}
+
+impl BackupSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let dir = value.get("dir").string();
+        let include: Vec<PathBuf> = match value.get("include") {
+            Value::List(list) => list.iter().map(|p| p.string()).filter(|p| !p.is_empty()).map(PathBuf::from).collect(),
+            _ => Vec::new(),
+        };
+        let keep = match value.get("keep") {
+            Value::None => 7,
+            keep => keep.integer().max(0) as usize,
+        };
+        let interval = value.get("interval").integer();
+        Self {
+            dir: if dir.is_empty() { "backups".into() } else { dir.into() },
+            include: if include.is_empty() { vec!["programfiles".into(), "uploads".into()] } else { include },
+            keep,
+            interval: (interval > 0).then(|| Duration::from_secs(interval as u64)),
+        }
+    }
+}
+
+/// The loaded backup settings
+pub fn settings() -> &'static BackupSettings {
+    &BACKUP
+}
+
+/// One archive in the backup directory
+#[derive(Debug, Clone)]
+pub struct Backup {
+    pub name: String,
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// Whether `name` is the file name of an archive made here. Also keeps
+/// download requests inside the backup directory.
+pub fn is_backup_name(name: &str) -> bool {
+    name.strip_prefix(PREFIX)
+        .and_then(|rest| rest.strip_suffix(SUFFIX))
+        .is_some_and(|stamp| !stamp.is_empty() && stamp.chars().all(|c| c.is_ascii_digit() || c == '-'))
+}
+
+/// `secs` since the epoch as `YYYYMMDD-HHMMSS` in UTC
+pub fn timestamp(secs: u64) -> String {
+    // Days to civil date, from Howard Hinnant's `civil_from_days`
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let rem = secs % 86_400;
+    format!("{:04}{:02}{:02}-{:02}{:02}{:02}", year, month, day, rem / 3600, rem / 60 % 60, rem % 60)
+}
+
+/// The archives in `dir`, newest first
+pub fn list(dir: &Path) -> Vec<Backup> {
+    let mut backups: Vec<Backup> = std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_str()?.to_string();
+            is_backup_name(&name).then(|| Backup {
+                size: entry.metadata().map(|m| m.len()).unwrap_or(0),
+                path: entry.path(),
+                name,
+            })
+        })
+        .collect();
+    backups.sort_by_key(|backup| std::cmp::Reverse(order(&backup.name)));
+    backups
+}
+
+/// Sort key of an archive name: its timestamp, then the counter of backups
+/// taken within the same second
+fn order(name: &str) -> (String, u32) {
+    let stamp = name.trim_start_matches(PREFIX).trim_end_matches(SUFFIX);
+    match stamp.rsplit_once('-') {
+        Some((time, counter)) if time.contains('-') => (time.to_string(), counter.parse().unwrap_or(0)),
+        _ => (stamp.to_string(), 1),
+    }
+}
+
+/// Archive the `include` paths into a new backup and prune the old ones.
+/// Returns the new archive.
+pub fn create(settings: &BackupSettings) -> Result<PathBuf, String> {
+    let include: Vec<&PathBuf> = settings.include.iter().filter(|path| path.exists()).collect();
+    if include.is_empty() {
+        return Err("None of the paths to back up exist".to_string());
+    }
+    std::fs::create_dir_all(&settings.dir)
+        .map_err(|err| format!("Cannot create {}: {}", settings.dir.display(), err))?;
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let mut name = format!("{}{}{}", PREFIX, timestamp(secs), SUFFIX);
+    // Two backups in the same second get a counter
+    let mut n = 1;
+    while settings.dir.join(&name).exists() {
+        n += 1;
+        name = format!("{}{}-{}{}", PREFIX, timestamp(secs), n, SUFFIX);
+    }
+    let path = settings.dir.join(&name);
+    // Written under another name, so a listing never shows a partial archive
+    let partial = settings.dir.join(format!(".{}.partial", name));
+    let output = Command::new("tar")
+        .arg("-czf")
+        .arg(&partial)
+        .arg("--exclude=*.lock")
+        .arg(format!("--exclude={}", settings.dir.display()))
+        // Secret files stay out of archives that may be copied elsewhere
+        .args(include.iter().map(|path| format!("--exclude={}", path.join(crate::secrets::DIR).display())))
+        .arg("--")
+        .args(&include)
+        .output()
+        .map_err(|err| format!("Cannot run tar: {}", err))?;
+    if !output.status.success() {
+        let _ = std::fs::remove_file(&partial);
+        return Err(format!("tar failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    std::fs::rename(&partial, &path).map_err(|err| err.to_string())?;
+    prune(settings);
+    Ok(path)
+}
+
+/// Delete the archives beyond the `keep` newest. Returns the deleted ones.
+pub fn prune(settings: &BackupSettings) -> Vec<PathBuf> {
+    if settings.keep == 0 {
+        return Vec::new();
+    }
+    list(&settings.dir)
+        .into_iter()
+        .skip(settings.keep)
+        .filter(|backup| std::fs::remove_file(&backup.path).is_ok())
+        .map(|backup| backup.path)
+        .collect()
+}
+
+/// Extract `archive` over `root`, replacing the files it holds
+pub fn restore(archive: &Path, root: &Path) -> Result<(), String> {
+    let output = Command::new("tar")
+        .arg("-xzf")
+        .arg(archive)
+        .arg("-C")
+        .arg(root)
+        .output()
+        .map_err(|err| format!("Cannot run tar: {}", err))?;
+    if !output.status.success() {
+        return Err(format!("tar failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(())
+}
+
+/// Copy `archive` to the storage unless it is local, then delete the copies
+/// there beyond the `keep` newest
+pub async fn offsite(archive: &Path, settings: &BackupSettings) -> Result<(), String> {
+    let store = storage::store();
+    if store.is_local() {
+        return Ok(());
+    }
+    let name = archive.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    let bytes = tokio::fs::read(archive).await.map_err(|err| format!("Cannot read {}: {}", archive.display(), err))?;
+    store
+        .put(&format!("backups/{}", name), bytes, "application/gzip")
+        .await
+        .map_err(|err| format!("Cannot copy {} to the storage: {}", name, err))?;
+    if settings.keep == 0 {
+        return Ok(());
+    }
+    let mut copies: Vec<String> = store
+        .list("backups/")
+        .await
+        .map_err(|err| err.to_string())?
+        .into_iter()
+        .filter_map(|key| key.strip_prefix("backups/").filter(|name| is_backup_name(name)).map(str::to_string))
+        .collect();
+    copies.sort_by_key(|name| std::cmp::Reverse(order(name)));
+    for name in copies.into_iter().skip(settings.keep) {
+        if let Err(err) = store.delete(&format!("backups/{}", name)).await {
+            tracing::warn!(name, %err, "Failed to prune a stored backup");
+        }
+    }
+    Ok(())
+}
+
+/// Take a backup from the running server: the account store is flushed
+/// first so the archive holds its current state.
+pub async fn snapshot() -> Result<PathBuf, String> {
+    if modules::enabled(modules::LOCAL_AUTH) {
+        LOCAL_AUTH.flush().await.map_err(|err| err.to_string())?;
+    }
+    let path = tokio::task::spawn_blocking(|| create(&BACKUP)).await.map_err(|err| err.to_string())??;
+    offsite(&path, &BACKUP).await?;
+    Ok(path)
+}
+
+/// Start taking backups every `interval` of `backup.json`, if set
+pub fn start() {
+    let Some(interval) = BACKUP.interval else { return };
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            match snapshot().await {
+                Ok(path) => tracing::info!(path = %path.display(), "Backup created"),
+                Err(err) => tracing::error!(%err, "Scheduled backup failed"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archive_names_are_utc_timestamps() {
+        assert_eq!(timestamp(0), "19700101-000000");
+        assert_eq!(timestamp(951_782_400 + 3_661), "20000229-010101");
+        assert_eq!(timestamp(1_792_108_800), "20261016-000000");
+        assert!(is_backup_name("sfx-backup-20261016-000000.tar.gz"));
+        assert!(is_backup_name("sfx-backup-20261016-000000-2.tar.gz"));
+        assert!(!is_backup_name("sfx-backup-../users.tar.gz"));
+        assert!(!is_backup_name("sfx-backup-.tar.gz"));
+        assert!(order("sfx-backup-20261016-000000-2.tar.gz") > order("sfx-backup-20261016-000000.tar.gz"));
+        assert!(order("sfx-backup-20261016-000001.tar.gz") > order("sfx-backup-20261016-000000-2.tar.gz"));
+
+        let settings = BackupSettings::from_value(&object!({ keep: 0 }));
+        assert_eq!((settings.keep, settings.interval), (0, None));
+        assert_eq!(settings.dir, PathBuf::from("backups"));
+    }
+}
@@ -0,0 +1,288 @@
+//! bans.rs
+//!
+//! Bans of client addresses and networks, made by admins at `/admin/bans`
+//! or by the login rules (`ban` of a rule in `login_rules.json`, see
+//! `crate::local_auth::rules`). Bans live in
+//! `programfiles/admin_info/bans.json`, so they survive restarts, and may
+//! expire:
+//!
+//! ```json
+//! [{ "id": "k3v9...", "network": "203.0.113.0/24", "reason": "scraping",
+//!    "by": "1@local", "created": 1700000000, "expires": 1700086400 }]
+//! ```
+//!
+//! `by` is the admin who made the ban, or `rule:<name>` for automatic ones.
+//! A ban without `expires` lasts until it is lifted. Every ban, change,
+//! lift and expiry is appended to `programfiles/admin_info/bans.log`, one
+//! JSON object per line, with the admin and their reason (an accepted
+//! appeal, say), so the trail outlives the bans themselves.
+//!
+//! The [`Banned`] middleware answers banned clients `403` before sessions
+//! are loaded. The client address is the one resolved by [`crate::proxy`].
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use crate::ip_filter::Cidr;
+use crate::proxy;
+
+static BANS: Lazy<RwLock<Vec<Ban>>> = Lazy::new(|| {
+    let bans = match Value::from_jsonf(bans_path().to_string_lossy()) {
+        Ok(Value::List(bans)) => bans.iter().filter_map(Ban::from_json).collect(),
+        _ => Vec::new(),
+    };
+    RwLock::new(bans)
+});
+
+fn bans_path() -> PathBuf {
+    crate::op::programfiles().join("admin_info/bans.json")
+}
+
+fn log_path() -> PathBuf {
+    crate::op::programfiles().join("admin_info/bans.log")
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// A banned address or network
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ban {
+    pub id: String,
+    pub network: Cidr,
+    pub reason: String,
+    /// The admin entry who made the ban, or `rule:<name>`
+    pub by: String,
+    pub created: u64,
+    /// Unix time the ban ends, `None` for a ban lasting until it is lifted
+    pub expires: Option<u64>,
+}
+
+impl Ban {
+    pub fn from_json(value: &Value) -> Option<Self> {
+        Some(Self {
+            id: value.get("id").string(),
+            network: Cidr::parse(&value.get("network").string())?,
+            reason: value.get("reason").string(),
+            by: value.get("by").string(),
+            created: value.get("created").integer().max(0) as u64,
+            expires: value.try_get("expires").ok().map(|v| v.integer()).filter(|&t| t > 0).map(|t| t as u64),
+        })
+    }
+
+    pub fn into_json(&self) -> Value {
+        let mut value = object!({
+            id: &self.id,
+            network: self.network.to_string(),
+            reason: &self.reason,
+            by: &self.by,
+            created: self.created,
+        });
+        if let Some(expires) = self.expires {
+            value.set("expires", expires);
+        }
+        value
+    }
+
+    pub fn is_active(&self, now: u64) -> bool {
+        self.expires.is_none_or(|expires| expires > now)
+    }
+}
+
+/// Why a ban could not be made or changed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BanError {
+    InvalidNetwork,
+    NotFound,
+    /// The network holds the address of the admin making the ban
+    OwnAddress,
+    Io(String),
+}
+
+impl std::fmt::Display for BanError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BanError::InvalidNetwork => write!(f, "Not an address or network"),
+            BanError::NotFound => write!(f, "Ban not found"),
+            BanError::OwnAddress => write!(f, "The ban would lock out your own address"),
+            BanError::Io(err) => write!(f, "Failed to save the bans: {}", err),
+        }
+    }
+}
+
+/// The active ban covering `ip`
+pub fn find(ip: &IpAddr) -> Option<Ban> {
+    let now = now();
+    BANS.read().unwrap().iter().find(|ban| ban.is_active(now) && ban.network.contains(ip)).cloned()
+}
+
+/// The active bans, newest first. Expired bans are dropped and logged.
+pub fn list() -> Vec<Ban> {
+    prune();
+    let mut bans = BANS.read().unwrap().clone();
+    bans.reverse();
+    bans
+}
+
+/// Ban `network` for `duration` seconds, or until lifted. `from` is the
+/// address of the admin, which the network may not cover.
+pub fn add(
+    network: &str,
+    reason: &str,
+    by: &str,
+    duration: Option<u64>,
+    from: Option<IpAddr>,
+) -> Result<Ban, BanError> {
+    let network = Cidr::parse(network).ok_or(BanError::InvalidNetwork)?;
+    if from.is_some_and(|ip| network.contains(&ip)) {
+        return Err(BanError::OwnAddress);
+    }
+    let now = now();
+    let ban = Ban {
+        id: hotaru_lib::random::random_alphanumeric_string(12),
+        network,
+        reason: reason.to_string(),
+        by: by.to_string(),
+        created: now,
+        expires: duration.map(|duration| now + duration),
+    };
+    BANS.write().unwrap().push(ban.clone());
+    save()?;
+    audit("ban", &ban, by, reason);
+    tracing::warn!(network = %ban.network, by, reason, "Network banned");
+    Ok(ban)
+}
+
+/// Ban the client address `ip` on behalf of the login rule `rule`. An
+/// address already banned is left alone.
+pub fn add_by_rule(ip: IpAddr, rule: &str, duration: u64, detail: &str) {
+    if find(&ip).is_some() {
+        return;
+    }
+    if let Err(err) = add(&ip.to_string(), detail, &format!("rule:{}", rule), Some(duration), None) {
+        tracing::error!(%ip, rule, %err, "Automatic ban failed");
+    }
+}
+
+/// Change the reason and end of the ban `id`
+pub fn update(id: &str, reason: &str, duration: Option<u64>, by: &str) -> Result<Ban, BanError> {
+    let ban = {
+        let mut bans = BANS.write().unwrap();
+        let ban = bans.iter_mut().find(|ban| ban.id == id).ok_or(BanError::NotFound)?;
+        ban.reason = reason.to_string();
+        ban.expires = duration.map(|duration| now() + duration);
+        ban.clone()
+    };
+    save()?;
+    audit("update", &ban, by, reason);
+    Ok(ban)
+}
+
+/// Lift the ban `id`; `reason` goes to the audit trail
+pub fn remove(id: &str, by: &str, reason: &str) -> Result<Ban, BanError> {
+    let ban = {
+        let mut bans = BANS.write().unwrap();
+        let at = bans.iter().position(|ban| ban.id == id).ok_or(BanError::NotFound)?;
+        bans.remove(at)
+    };
+    save()?;
+    audit("unban", &ban, by, reason);
+    tracing::info!(network = %ban.network, by, reason, "Ban lifted");
+    Ok(ban)
+}
+
+/// The last `count` entries of the audit trail, newest first
+pub fn history(count: usize) -> Vec<Value> {
+    let log = std::fs::read_to_string(log_path()).unwrap_or_default();
+    log.lines().rev().filter_map(|line| Value::from_json(line).ok()).take(count).collect()
+}
+
+/// Drop the bans that ran out, logging each
+fn prune() {
+    let now = now();
+    let expired: Vec<Ban> = {
+        let mut bans = BANS.write().unwrap();
+        let (expired, active) = bans.drain(..).partition(|ban| !ban.is_active(now));
+        *bans = active;
+        expired
+    };
+    if expired.is_empty() {
+        return;
+    }
+    if let Err(err) = save() {
+        tracing::error!(%err, "Failed to drop expired bans");
+    }
+    for ban in &expired {
+        audit("expired", ban, "", "");
+    }
+}
+
+fn save() -> Result<(), BanError> {
+    let path = bans_path();
+    let bans = Value::List(BANS.read().unwrap().iter().map(Ban::into_json).collect());
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|err| BanError::Io(err.to_string()))?;
+    }
+    std::fs::write(&path, bans.into_json()).map_err(|err| BanError::Io(err.to_string()))
+}
+
+fn audit(action: &str, ban: &Ban, by: &str, reason: &str) {
+    let entry = object!({
+        time: now(),
+        action: action,
+        id: &ban.id,
+        network: ban.network.to_string(),
+        by: by,
+        reason: reason,
+    });
+    let written = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path())
+        .and_then(|mut log| writeln!(log, "{}", entry.into_json()));
+    if let Err(err) = written {
+        tracing::error!(%err, "Failed to write the ban audit trail");
+    }
+}
+
+middleware! {
+    /// Answers `403` to clients under an active ban. Add it right after
+    /// `IpFilter`, before the session middleware.
+    pub Banned <HTTP> {
+        if let Some(ip) = proxy::client_ip(&req)
+            && let Some(ban) = find(&ip)
+        {
+            tracing::info!(%ip, network = %ban.network, path = %req.path(), "Request from a banned address");
+            req.response = text_response("Forbidden").status(403);
+            return Ok(req)
+        }
+        next(req).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bans_round_trip_and_expire() {
+        let ban = Ban {
+            id: "abc".into(),
+            network: Cidr::parse("203.0.113.0/24").unwrap(),
+            reason: "scraping".into(),
+            by: "1@local".into(),
+            created: 100,
+            expires: Some(200),
+        };
+        assert_eq!(Ban::from_json(&ban.into_json()), Some(ban.clone()));
+        assert!(ban.is_active(199) && !ban.is_active(200));
+        assert!(Ban { expires: None, ..ban.clone() }.is_active(u64::MAX));
+        assert_eq!(ban.into_json().get("network").string(), "203.0.113.0/24");
+        assert!(Ban::from_json(&object!({ id: "x", network: "nope" })).is_none());
+    }
+}
@@ -0,0 +1,231 @@
+//! bindings.rs
+//!
+//! Additional listen addresses for `APP`, and route groups that are only
+//! reachable through some of them.
+//!
+//! The main binding stays in `binding.txt` (listener name `main`). Extra
+//! listeners and route groups are read from `programfiles/op/bindings.json`:
+//!
+//! ```json
+//! {
+//!     "listeners": [
+//!         { "name": "public", "address": "0.0.0.0:80" },
+//!         { "name": "internal", "address": "127.0.0.1:3004" }
+//!     ],
+//!     "routes": {
+//!         "/admin": ["internal"]
+//!     }
+//! }
+//! ```
+//!
+//! Every listener serves the same routes, except that a path under one of
+//! the `routes` prefixes is answered with `404 Not Found` on listeners not
+//! named for it.
+//!
+//! Listeners that `APP` cannot accept itself (the TLS front and the Unix
+//! socket) are "fronts": they forward each connection to the `APP` binding
+//! over loopback with [`forward`], which records where the connection really
+//! came from. Their requests are named `tls` and `unix`.
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+use hotaru::TcpStream;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+
+static BINDINGS: Lazy<BindingSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/bindings.json");
+    BindingSettings::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None))
+});
+
+/// Local addresses of the bound extra listeners, filled by [`start`]
+static BOUND: Lazy<RwLock<Vec<(String, SocketAddr)>>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+/// Loopback address of each forwarded connection -> the front it came from
+static FRONTS: Lazy<Mutex<HashMap<SocketAddr, Front>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Name of the listener configured by `binding.txt`
+pub const MAIN: &str = "main";
+/// Name given to connections coming from the TLS front
+pub const TLS: &str = "tls";
+/// Name given to connections coming from the Unix socket
+pub const UNIX: &str = "unix";
+
+/// The front a forwarded connection was accepted on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Front {
+    /// The TLS front, with the address of the TLS client and the SHA-256
+    /// of the client certificate it presented, if any
+    Tls(SocketAddr, Option<[u8; 32]>),
+    /// The Unix domain socket, whose peers have no network address
+    Unix,
+}
+
+/// One extra listen address
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listener {
+    pub name: String,
+    pub address: String,
+}
+
+/// The parsed content of `bindings.json`
+#[derive(Debug, Clone, Default)]
+pub struct BindingSettings {
+    pub listeners: Vec<Listener>,
+    /// `(path prefix, listener names)`, longest prefix first
+    pub routes: Vec<(String, Vec<String>)>,
+}
+
+impl BindingSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let listeners = match value.get("listeners") {
+            Value::None => Vec::new(),
+            list => list
+                .list()
+                .iter()
+                .map(|l| Listener {
+                    name: l.get("name").string(),
+                    address: l.get("address").string(),
+                })
+                .filter(|l| !l.name.is_empty() && !l.address.is_empty())
+                .collect(),
+        };
+        let mut routes: Vec<(String, Vec<String>)> = match value.get("routes") {
+            Value::Dict(map) => map
+                .iter()
+                .map(|(prefix, names)| {
+                    (
+                        prefix.trim_end_matches('/').to_string(),
+                        names.list().iter().map(|n| n.string()).collect(),
+                    )
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        routes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        Self { listeners, routes }
+    }
+
+    /// The listener names allowed to serve `path`, `None` when any may
+    pub fn allowed_for(&self, path: &str) -> Option<&[String]> {
+        self.routes
+            .iter()
+            .find(|(prefix, _)| {
+                path == prefix
+                    || prefix.is_empty()
+                    || path
+                        .strip_prefix(prefix.as_str())
+                        .is_some_and(|rest| rest.starts_with('/'))
+            })
+            .map(|(_, names)| names.as_slice())
+    }
+}
+
+/// The loaded binding settings
+pub fn settings() -> &'static BindingSettings {
+    &BINDINGS
+}
+
+/// Bind every extra listener of `bindings.json` and feed its connections
+/// to `app`. Fails if any address cannot be bound.
+pub async fn start(app: Arc<Server<TcpTransport, TokioRuntime>>) -> std::io::Result<()> {
+    for listener in &BINDINGS.listeners {
+        let tcp = TcpListener::bind(&listener.address).await?;
+        let local = tcp.local_addr()?;
+        BOUND.write().unwrap().push((listener.name.clone(), local));
+        tracing::info!(name = %listener.name, address = %local, "Listening");
+        let app = app.clone();
+        tokio::spawn(async move {
+            loop {
+                match tcp.accept().await {
+                    Ok((stream, _)) => app.clone().handle_wire(TcpStream::new(stream)),
+                    Err(err) => tracing::warn!(%local, %err, "Accept failed"),
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Forward a connection accepted by a front to the `APP` binding `upstream`
+/// and pipe bytes both ways until either side closes
+pub(crate) async fn forward<S>(mut stream: S, upstream: &str, front: Front)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut plain = match tokio::net::TcpStream::connect(upstream).await {
+        Ok(plain) => plain,
+        Err(err) => {
+            tracing::error!(%upstream, %err, "Front cannot reach the app binding");
+            return;
+        }
+    };
+    let Ok(local) = plain.local_addr() else { return };
+    FRONTS.lock().unwrap().insert(local, front);
+    let _ = tokio::io::copy_bidirectional(&mut stream, &mut plain).await;
+    FRONTS.lock().unwrap().remove(&local);
+}
+
+/// The front a request's connection was forwarded from, if any
+pub fn front_of(req: &HttpReqCtx) -> Option<Front> {
+    let remote = req.remote_addr()?;
+    FRONTS.lock().unwrap().get(&remote).copied()
+}
+
+/// The name of the listener the request arrived on
+pub fn listener_of(req: &HttpReqCtx) -> String {
+    match front_of(req) {
+        Some(Front::Tls(..)) => return TLS.to_string(),
+        Some(Front::Unix) => return UNIX.to_string(),
+        None => {}
+    }
+    let Some(local) = req.local_addr() else {
+        return MAIN.to_string();
+    };
+    BOUND
+        .read()
+        .unwrap()
+        .iter()
+        .find(|(_, addr)| {
+            addr.port() == local.port() && (addr.ip() == local.ip() || addr.ip().is_unspecified())
+        })
+        .map(|(name, _)| name.clone())
+        .unwrap_or_else(|| MAIN.to_string())
+}
+
+middleware! {
+    /// Middleware answering `404 Not Found` for paths whose route group in
+    /// `bindings.json` is not assigned to the listener the request came in on
+    pub BindingGuard <HTTP> {
+        if let Some(allowed) = BINDINGS.allowed_for(&req.path()) {
+            let listener = listener_of(&req);
+            if !allowed.contains(&listener) {
+                req.response = text_response("Not Found").status(StatusCode::NOT_FOUND);
+                return Ok(req)
+            }
+        }
+        next(req).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn route_groups_match_on_path_segments() {
+        let settings = BindingSettings::from_value(
+            &Value::from_json(r#"{"routes": {"/admin": ["internal"], "/admin/public/": ["main", "internal"]}}"#)
+                .unwrap(),
+        );
+        let internal = vec!["internal".to_string()];
+        assert_eq!(settings.allowed_for("/admin"), Some(internal.as_slice()));
+        assert_eq!(settings.allowed_for("/admin/panel"), Some(internal.as_slice()));
+        assert_eq!(settings.allowed_for("/admin/public/x").map(|n| n.len()), Some(2));
+        assert_eq!(settings.allowed_for("/administrator"), None);
+        assert_eq!(settings.allowed_for("/user/login"), None);
+    }
+}
@@ -0,0 +1,742 @@
+//! blog.rs
+//!
+//! A blog of markdown posts at `/blog`, with tag and archive indexes and an
+//! RSS feed at `/blog/feed.xml`. Each post is a file
+//! `programfiles/blog/<slug>.md` beginning with its metadata:
+//!
+//! ```text
+//! ---
+//! title: Hello, world
+//! author: 1@local
+//! author_name: Admin
+//! tags: news, release
+//! summary: What this post is about
+//! status: published
+//! published: 1700000000
+//! updated: 1700000000
+//! ---
+//! The post in *markdown*.
+//! ```
+//!
+//! Only `title` is needed; a file without metadata is a published post
+//! titled by its slug. Drafts are shown to admins only. Posts are written at
+//! `/admin/blog`, or by hand and picked up on restart. Read from
+//! `programfiles/op/blog.json`:
+//!
+//! ```json
+//! {
+//!     "title": "Blog",
+//!     "description": "News and notes",
+//!     "per_page": 10,
+//!     "feed_items": 20,
+//!     "raw_html": false
+//! }
+//! ```
+//!
+//! Without `raw_html`, HTML written in a post is shown as text.
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+use pulldown_cmark::{Event, Options, Parser, TagEnd};
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use crate::admin::check_is_admin;
+use crate::op::{self, APP};
+
+static BLOG_SETTINGS: Lazy<BlogSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/blog.json");
+    BlogSettings::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None))
+});
+
+static POSTS: Lazy<RwLock<Vec<Post>>> = Lazy::new(|| RwLock::new(load()));
+
+/// Longest slug
+const MAX_SLUG: usize = 80;
+
+/// Characters of the summary made from a post without one
+const SUMMARY_LENGTH: usize = 200;
+
+/// Paths under `/blog` that are not posts
+const RESERVED: &[&str] = &["tag", "archive"];
+
+fn posts_dir() -> PathBuf {
+    crate::op::programfiles().join("blog")
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// The parsed content of `blog.json`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlogSettings {
+    /// Title of the index and the feed
+    pub title: String,
+    pub description: String,
+    /// Posts per index page
+    pub per_page: usize,
+    /// Newest posts in the feed
+    pub feed_items: usize,
+    /// Pass HTML written in posts through instead of showing it as text
+    pub raw_html: bool,
+}
+
+impl Default for BlogSettings {
+    fn default() -> Self {
+        Self {
+            title: "Blog".to_string(),
+            description: String::new(),
+            per_page: 10,
+            feed_items: 20,
+            raw_html: false,
+        }
+    }
+}
+
+impl BlogSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let default = Self::default();
+        let number = |key: &str, fallback: usize| match value.get(key) {
+            Value::Numerical(_) => value.get(key).integer().max(1) as usize,
+            _ => fallback,
+        };
+        let text = |key: &str, fallback: String| match value.get(key) {
+            Value::Str(text) => text.trim().to_string(),
+            _ => fallback,
+        };
+        Self {
+            title: text("title", default.title),
+            description: text("description", default.description),
+            per_page: number("per_page", default.per_page),
+            feed_items: number("feed_items", default.feed_items),
+            raw_html: value.get("raw_html").boolean(),
+        }
+    }
+}
+
+/// Whether a post is out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PostStatus {
+    Draft,
+    Published,
+}
+
+impl PostStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PostStatus::Draft => "draft",
+            PostStatus::Published => "published",
+        }
+    }
+
+    pub fn from_string(status: &str) -> Option<Self> {
+        match status {
+            "draft" => Some(PostStatus::Draft),
+            "published" => Some(PostStatus::Published),
+            _ => None,
+        }
+    }
+}
+
+/// One post
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Post {
+    pub slug: String,
+    pub title: String,
+    /// User id of the author, e.g. `1@local`
+    pub author: String,
+    /// Name of the author when the post was made
+    pub author_name: String,
+    pub tags: Vec<String>,
+    pub summary: String,
+    pub status: PostStatus,
+    /// When the post was first published, 0 for drafts never published
+    pub published: u64,
+    pub updated: u64,
+    /// The text, in markdown
+    pub body: String,
+}
+
+impl Post {
+    /// Read a post file: metadata lines between `---`, then the body
+    pub fn parse(slug: &str, text: &str) -> Post {
+        let mut post = Post {
+            slug: slug.to_string(),
+            title: slug.to_string(),
+            author: String::new(),
+            author_name: String::new(),
+            tags: Vec::new(),
+            summary: String::new(),
+            status: PostStatus::Published,
+            published: 0,
+            updated: 0,
+            body: text.to_string(),
+        };
+        let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+        let Some(rest) = text.strip_prefix("---\n").or_else(|| text.strip_prefix("---\r\n")) else {
+            return post;
+        };
+        let mut offset = 0;
+        let mut closed = false;
+        for line in rest.split_inclusive('\n') {
+            offset += line.len();
+            let line = line.trim();
+            if line == "---" {
+                closed = true;
+                break;
+            }
+            let Some((key, value)) = line.split_once(':') else { continue };
+            let value = value.trim();
+            match key.trim() {
+                "title" => post.title = value.to_string(),
+                "author" => post.author = value.to_string(),
+                "author_name" => post.author_name = value.to_string(),
+                "tags" => post.tags = tags(value),
+                "summary" => post.summary = value.to_string(),
+                "status" => post.status = PostStatus::from_string(value).unwrap_or(PostStatus::Draft),
+                "published" => post.published = value.parse().unwrap_or(0),
+                "updated" => post.updated = value.parse().unwrap_or(0),
+                _ => {}
+            }
+        }
+        if closed {
+            post.body = rest[offset..].trim_start_matches(['\r', '\n']).to_string();
+        }
+        post
+    }
+
+    /// The file content of the post, as read by [`Post::parse`]
+    pub fn to_file(&self) -> String {
+        let line = |text: &str| text.replace(['\r', '\n'], " ");
+        format!(
+            "---\ntitle: {}\nauthor: {}\nauthor_name: {}\ntags: {}\nsummary: {}\nstatus: {}\npublished: {}\nupdated: {}\n---\n{}\n",
+            line(&self.title),
+            line(&self.author),
+            line(&self.author_name),
+            self.tags.join(", "),
+            line(&self.summary),
+            self.status.as_str(),
+            self.published,
+            self.updated,
+            self.body.trim_end(),
+        )
+    }
+
+    pub fn is_published(&self) -> bool {
+        self.status == PostStatus::Published
+    }
+
+    /// The body as HTML
+    pub fn html(&self, raw_html: bool) -> String {
+        let parser = Parser::new_ext(&self.body, Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_FOOTNOTES)
+            .map(|event| match event {
+                Event::Html(html) | Event::InlineHtml(html) if !raw_html => Event::Text(html),
+                event => event,
+            });
+        let mut html = String::new();
+        pulldown_cmark::html::push_html(&mut html, parser);
+        html
+    }
+
+    /// The summary, or the beginning of the text without its markup
+    pub fn summary(&self) -> String {
+        if !self.summary.is_empty() {
+            return self.summary.clone();
+        }
+        let mut text = String::new();
+        for event in Parser::new(&self.body) {
+            match event {
+                Event::Text(part) | Event::Code(part) => text.push_str(&part),
+                Event::SoftBreak | Event::HardBreak | Event::End(TagEnd::Paragraph | TagEnd::Heading(_) | TagEnd::Item)
+                    if !text.ends_with(' ') =>
+                {
+                    text.push(' ')
+                }
+                _ => {}
+            }
+            if text.chars().count() > SUMMARY_LENGTH {
+                break;
+            }
+        }
+        let text = text.trim();
+        match text.char_indices().nth(SUMMARY_LENGTH) {
+            Some((end, _)) => format!("{}…", text[..end].trim_end()),
+            None => text.to_string(),
+        }
+    }
+
+    /// When the post counts as written: first published, else last saved
+    pub fn date(&self) -> u64 {
+        if self.published > 0 { self.published } else { self.updated }
+    }
+
+    /// Everything but the body, for the admin API
+    pub fn into_json(&self) -> Value {
+        object!({
+            slug: &self.slug,
+            title: &self.title,
+            author: &self.author,
+            author_name: &self.author_name,
+            tags: self.tags.clone(),
+            summary: &self.summary,
+            status: self.status.as_str(),
+            published: self.published,
+            updated: self.updated,
+        })
+    }
+}
+
+/// Something that went wrong with a post
+#[derive(Debug)]
+pub enum BlogError {
+    InvalidSlug,
+    MissingTitle,
+    NotFound,
+    /// Another post has the slug
+    Exists,
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for BlogError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlogError::InvalidSlug => write!(f, "Slugs are lowercase letters, digits and dashes"),
+            BlogError::MissingTitle => write!(f, "A post needs a title"),
+            BlogError::NotFound => write!(f, "Post not found"),
+            BlogError::Exists => write!(f, "Another post has this slug"),
+            BlogError::Io(err) => write!(f, "Failed to save the post: {}", err),
+        }
+    }
+}
+
+impl From<std::io::Error> for BlogError {
+    fn from(err: std::io::Error) -> Self {
+        BlogError::Io(err)
+    }
+}
+
+/// Whether `slug` may name a post: lowercase letters, digits and inner dashes
+pub fn valid_slug(slug: &str) -> bool {
+    (1..=MAX_SLUG).contains(&slug.len())
+        && !slug.starts_with('-')
+        && !slug.ends_with('-')
+        && slug.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+        && !RESERVED.contains(&slug)
+}
+
+/// A slug made from `title`, empty when it has no letters or digits
+pub fn slugify(title: &str) -> String {
+    let mut slug = String::new();
+    for c in title.chars().flat_map(char::to_lowercase) {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.truncate(MAX_SLUG);
+    slug.trim_end_matches('-').to_string()
+}
+
+/// The tags of a comma separated list, made into slugs, without repeats
+pub fn tags(list: &str) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+    for tag in list.split(',').map(slugify) {
+        if !tag.is_empty() && !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    tags
+}
+
+/// Every post file of the posts directory
+fn load() -> Vec<Post> {
+    let mut posts: Vec<Post> = std::fs::read_dir(posts_dir())
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.path();
+            let slug = path.file_name()?.to_str()?.strip_suffix(".md")?.to_string();
+            if !valid_slug(&slug) {
+                return None;
+            }
+            let text = std::fs::read_to_string(&path).ok()?;
+            let mut post = Post::parse(&slug, &text);
+            if post.updated == 0 {
+                post.updated = entry
+                    .metadata()
+                    .and_then(|meta| meta.modified())
+                    .ok()
+                    .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map(|age| age.as_secs())
+                    .unwrap_or(0);
+            }
+            if post.is_published() && post.published == 0 {
+                post.published = post.updated;
+            }
+            Some(post)
+        })
+        .collect();
+    newest_first(&mut posts);
+    posts
+}
+
+fn newest_first(posts: &mut [Post]) {
+    posts.sort_by(|a, b| b.date().cmp(&a.date()).then_with(|| a.slug.cmp(&b.slug)));
+}
+
+fn write(post: &Post) -> Result<(), BlogError> {
+    let dir = posts_dir();
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join(format!("{}.md", post.slug)), post.to_file())?;
+    Ok(())
+}
+
+/// The fields of a post sent from the editor
+#[derive(Debug, Clone, Default)]
+pub struct PostDraft {
+    /// Slug of the post being edited, empty for a new one
+    pub original: String,
+    /// Slug to save under; made from the title when empty
+    pub slug: String,
+    pub title: String,
+    pub tags: String,
+    pub summary: String,
+    pub body: String,
+}
+
+/// Save a post from the editor. New posts start as drafts by `author`;
+/// edits keep the author, status and publishing date.
+pub fn save(draft: PostDraft, author: &str, author_name: &str) -> Result<Post, BlogError> {
+    let title = draft.title.trim().replace(['\r', '\n'], " ");
+    if title.is_empty() {
+        return Err(BlogError::MissingTitle);
+    }
+    let slug = match draft.slug.trim() {
+        "" => slugify(&title),
+        slug => slug.to_string(),
+    };
+    if !valid_slug(&slug) {
+        return Err(BlogError::InvalidSlug);
+    }
+    let original = draft.original.trim();
+    let mut posts = POSTS.write().unwrap();
+    if posts.iter().any(|post| post.slug == slug && post.slug != original) {
+        return Err(BlogError::Exists);
+    }
+    let mut post = match original {
+        "" => Post {
+            slug: String::new(),
+            title: String::new(),
+            author: author.to_string(),
+            author_name: author_name.to_string(),
+            tags: Vec::new(),
+            summary: String::new(),
+            status: PostStatus::Draft,
+            published: 0,
+            updated: 0,
+            body: String::new(),
+        },
+        original => posts.iter().find(|post| post.slug == original).cloned().ok_or(BlogError::NotFound)?,
+    };
+    post.slug = slug;
+    post.title = title;
+    post.tags = tags(&draft.tags);
+    post.summary = draft.summary.trim().replace(['\r', '\n'], " ");
+    post.body = draft.body.replace("\r\n", "\n");
+    post.updated = now();
+    write(&post)?;
+    if !original.is_empty() && original != post.slug {
+        std::fs::remove_file(posts_dir().join(format!("{}.md", original)))?;
+    }
+    posts.retain(|other| other.slug != original && other.slug != post.slug);
+    posts.push(post.clone());
+    newest_first(&mut posts);
+    Ok(post)
+}
+
+/// Publish a draft, or take a post back to a draft. The publishing date of
+/// the first time is kept.
+pub fn set_status(slug: &str, status: PostStatus) -> Result<Post, BlogError> {
+    let mut posts = POSTS.write().unwrap();
+    let post = posts.iter_mut().find(|post| post.slug == slug).ok_or(BlogError::NotFound)?;
+    let mut changed = post.clone();
+    changed.status = status;
+    if status == PostStatus::Published && changed.published == 0 {
+        changed.published = now();
+    }
+    write(&changed)?;
+    *post = changed.clone();
+    newest_first(&mut posts);
+    Ok(changed)
+}
+
+/// Delete a post for good
+pub fn remove(slug: &str) -> Result<Post, BlogError> {
+    let mut posts = POSTS.write().unwrap();
+    let index = posts.iter().position(|post| post.slug == slug).ok_or(BlogError::NotFound)?;
+    std::fs::remove_file(posts_dir().join(format!("{}.md", slug)))?;
+    Ok(posts.remove(index))
+}
+
+pub fn get(slug: &str) -> Option<Post> {
+    POSTS.read().unwrap().iter().find(|post| post.slug == slug).cloned()
+}
+
+/// The posts, newest first, with the drafts when `drafts`
+pub fn posts(drafts: bool) -> Vec<Post> {
+    POSTS.read().unwrap().iter().filter(|post| drafts || post.is_published()).cloned().collect()
+}
+
+/// The published posts with `tag`, newest first
+pub fn tagged(tag: &str) -> Vec<Post> {
+    posts(false).into_iter().filter(|post| post.tags.iter().any(|t| t == tag)).collect()
+}
+
+/// The published posts by month (`YYYY-MM`), newest first
+pub fn archive() -> Vec<(String, Vec<Post>)> {
+    let mut months: Vec<(String, Vec<Post>)> = Vec::new();
+    for post in posts(false) {
+        let month = crate::analytics::date(post.date())[..7].to_string();
+        match months.last_mut() {
+            Some((last, posts)) if *last == month => posts.push(post),
+            _ => months.push((month, vec![post])),
+        }
+    }
+    months
+}
+
+/// The tags of the published posts with how many posts have them, most used first
+pub fn tag_counts() -> Vec<(String, usize)> {
+    let mut counts: Vec<(String, usize)> = Vec::new();
+    for tag in posts(false).into_iter().flat_map(|post| post.tags) {
+        match counts.iter_mut().find(|(name, _)| *name == tag) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((tag, 1)),
+        }
+    }
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts
+}
+
+pub fn settings() -> &'static BlogSettings {
+    &BLOG_SETTINGS
+}
+
+/// `secs` as an RFC 822 date, as RSS wants it
+fn rfc822(secs: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let stamp = crate::backup::timestamp(secs);
+    let month: usize = stamp[4..6].parse().unwrap_or(1);
+    format!(
+        "{}, {} {} {} {}:{}:{} GMT",
+        WEEKDAYS[(secs / 86_400 % 7) as usize],
+        &stamp[6..8],
+        MONTHS[month - 1],
+        &stamp[..4],
+        &stamp[9..11],
+        &stamp[11..13],
+        &stamp[13..15],
+    )
+}
+
+/// The RSS 2.0 feed of the newest published posts, linking to `origin`
+pub fn feed(origin: &str) -> String {
+    let settings = settings();
+    let escape = op::escape_html;
+    let posts = posts(false);
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<rss version=\"2.0\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n<channel>\n");
+    xml.push_str(&format!(
+        "<title>{}</title>\n<link>{}/blog</link>\n<description>{}</description>\n",
+        escape(&settings.title),
+        escape(origin),
+        escape(&settings.description),
+    ));
+    if let Some(post) = posts.first() {
+        xml.push_str(&format!("<lastBuildDate>{}</lastBuildDate>\n", rfc822(post.date())));
+    }
+    for post in posts.iter().take(settings.feed_items) {
+        let link = escape(&format!("{}/blog/{}", origin, post.slug));
+        xml.push_str(&format!(
+            "<item>\n<title>{}</title>\n<link>{}</link>\n<guid isPermaLink=\"true\">{}</guid>\n<pubDate>{}</pubDate>\n",
+            escape(&post.title),
+            link,
+            link,
+            rfc822(post.date()),
+        ));
+        if !post.author_name.is_empty() {
+            xml.push_str(&format!("<dc:creator>{}</dc:creator>\n", escape(&post.author_name)));
+        }
+        for tag in &post.tags {
+            xml.push_str(&format!("<category>{}</category>\n", escape(tag)));
+        }
+        xml.push_str(&format!("<description>{}</description>\n</item>\n", escape(&post.html(settings.raw_html))));
+    }
+    xml.push_str("</channel>\n</rss>\n");
+    xml
+}
+
+/// `post` for the templates: escaped, with its date and summary
+pub fn entry(post: &Post) -> Value {
+    object!({
+        slug: &post.slug,
+        title: op::escape_html(&post.title),
+        author_name: op::escape_html(&post.author_name),
+        tags: post.tags.clone(),
+        summary: op::escape_html(&post.summary()),
+        draft: !post.is_published(),
+        date: crate::analytics::date(post.date()),
+    })
+}
+
+/// One page of `posts` for `blog/index.html`
+fn index_page(req: &mut HttpReqCtx, title: &str, heading: &str, tag: &str, posts: Vec<Post>) -> HttpResponse {
+    let settings = settings();
+    let pages = posts.len().div_ceil(settings.per_page).max(1);
+    let page = req.query("page").and_then(|page| page.parse::<usize>().ok()).unwrap_or(1).clamp(1, pages);
+    let items: Vec<Value> = posts.iter().skip((page - 1) * settings.per_page).take(settings.per_page).map(entry).collect();
+    let base = req.path();
+    akari_render!(
+        "blog/index.html",
+        pageprop = op::pageprop(req, title, &settings.description),
+        path = op::into_path_l(req, vec!["home", "blog"]),
+        blog = object!({
+            title: op::escape_html(&settings.title),
+            heading: op::escape_html(heading),
+            tag: tag,
+            posts: Value::List(items),
+            page: page,
+            pages: pages,
+            paged: pages > 1,
+            has_prev: page > 1,
+            has_next: page < pages,
+            prev_url: format!("{}?page={}", base, page.saturating_sub(1).max(1)),
+            next_url: format!("{}?page={}", base, (page + 1).min(pages)),
+        })
+    )
+}
+
+fn not_found() -> HttpResponse {
+    text_response("Not found").status(StatusCode::NOT_FOUND)
+}
+
+endpoint! {
+    APP.url("/blog"),
+
+    /// GET /blog?page=1 - The published posts, newest first
+    pub blog_index <HTTP> {
+        let title = settings().title.clone();
+        index_page(req, &title, "", "", posts(false))
+    }
+}
+
+endpoint! {
+    APP.url("/blog/feed.xml"),
+
+    /// GET /blog/feed.xml - RSS feed of the newest posts
+    pub blog_feed <HTTP> {
+        let origin = crate::proxy::public_origin(req);
+        normal_response(StatusCode::OK, feed(&origin))
+            .content_type(HttpContentType::from_str("application/rss+xml; charset=utf-8"))
+    }
+}
+
+endpoint! {
+    APP.url("/blog/archive"),
+
+    /// GET /blog/archive - Every published post by month, and the tags
+    pub blog_archive <HTTP> {
+        let months: Vec<Value> = archive()
+            .iter()
+            .map(|(month, posts)| object!({
+                month: month,
+                posts: posts.iter().map(entry).collect::<Vec<Value>>(),
+            }))
+            .collect();
+        let tags: Vec<Value> = tag_counts().into_iter().map(|(tag, count)| object!({ tag: tag, count: count })).collect();
+        let settings = settings();
+        akari_render!(
+            "blog/archive.html",
+            pageprop = op::pageprop(req, &format!("Archive - {}", settings.title), &settings.description),
+            path = op::into_path_l(req, vec!["home", "blog", "archive"]),
+            blog = object!({
+                title: op::escape_html(&settings.title),
+                months: Value::List(months),
+                tags: Value::List(tags),
+            })
+        )
+    }
+}
+
+endpoint! {
+    APP.url("/blog/tag/<tag>"),
+
+    /// GET /blog/tag/<tag>?page=1 - The published posts with a tag
+    pub blog_tag <HTTP> {
+        let tag = slugify(&req.param("tag").unwrap_or_default());
+        let posts = tagged(&tag);
+        if posts.is_empty() {
+            return not_found();
+        }
+        let heading = format!("Tagged {}", tag);
+        index_page(req, &heading, &heading, &tag, posts)
+    }
+}
+
+endpoint! {
+    APP.url("/blog/<slug>"),
+
+    /// GET /blog/<slug> - One post with its comments. Drafts are shown to admins only.
+    pub blog_post <HTTP> {
+        let slug = req.param("slug").unwrap_or_default();
+        let Some(post) = get(&slug) else {
+            return not_found();
+        };
+        if !post.is_published() && !check_is_admin(req).await {
+            return not_found();
+        }
+        let mut value = entry(&post);
+        value.set("html", post.html(settings().raw_html));
+        let comments = crate::comments::thread(req, &format!("blog/{}", post.slug));
+        akari_render!(
+            "blog/post.html",
+            pageprop = op::pageprop(req, &post.title, &post.summary()),
+            path = op::into_path_l(req, vec!["home", "blog", "post"]),
+            post = value,
+            comments = comments
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn posts_round_trip_through_their_files() {
+        let text = "---\ntitle: Hello, world\nauthor: 1@local\ntags: News, Rust Tips, news\nstatus: draft\n---\n\nSome *text* <b>here</b>\n";
+        let post = Post::parse("hello-world", text);
+        assert_eq!(post.title, "Hello, world");
+        assert_eq!(post.tags, vec!["news", "rust-tips"]);
+        assert_eq!(post.status, PostStatus::Draft);
+        assert_eq!(post.body, "Some *text* <b>here</b>\n");
+        assert_eq!(Post::parse("hello-world", &post.to_file()), post);
+        assert_eq!(post.html(false), "<p>Some <em>text</em> &lt;b&gt;here&lt;/b&gt;</p>\n");
+        assert!(post.html(true).contains("<b>here</b>"));
+        assert_eq!(post.summary(), "Some text here");
+
+        let plain = Post::parse("plain", "Just text");
+        assert_eq!((plain.title.as_str(), plain.status), ("plain", PostStatus::Published));
+    }
+
+    #[test]
+    fn slugs_and_feed_dates() {
+        assert_eq!(slugify("Hello, World! 2026"), "hello-world-2026");
+        assert!(valid_slug("hello-world") && !valid_slug("Hello") && !valid_slug("-a") && !valid_slug("tag"));
+        assert_eq!(rfc822(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(rfc822(1_700_000_000), "Tue, 14 Nov 2023 22:13:20 GMT");
+    }
+}
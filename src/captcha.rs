@@ -0,0 +1,330 @@
+//! captcha.rs
+//!
+//! Pluggable CAPTCHA verification used to slow down credential stuffing on
+//! the login endpoints and spam on the forms of `crate::forms`.
+//!
+//! The provider and the routes it guards are read from
+//! `programfiles/op/captcha.json`:
+//!
+//! ```json
+//! {
+//!     "provider": "turnstile",
+//!     "site_key": "<public site key>",
+//!     "secret": "env:SFX_CAPTCHA_SECRET",
+//!     "routes": ["login", "form"]
+//! }
+//! ```
+//!
+//! `provider` is one of `none`, `hcaptcha`, `turnstile` or `challenge`. The
+//! `challenge` provider is self-hosted: a small arithmetic question whose
+//! answer is kept in the cookie session, so it needs no third-party account.
+//!
+//! Templates render the widget with the value returned by [`widget`]
+//! (see `default/templates/base/captcha.html`), and endpoints call [`verify`]
+//! with the submitted response before doing any credential work.
+//!
+//! `/auth/login` checks the `login` route itself, so the API cannot be used
+//! to get around the form. The form of `/user/login` checks it first, with
+//! the widget it rendered, and vouches for the call it then makes to this
+//! server with a one-use [`pass`].
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+use hotaru_lib::random::random_alphanumeric_string;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::ctx::SfxCtx;
+use crate::proxy;
+use crate::user::fetch::send_http_request;
+
+static CAPTCHA: Lazy<Value> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/captcha.json");
+    Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None)
+});
+
+/// The `secret` of `captcha.json`, which may be a `crate::secrets` reference
+static SECRET: Lazy<String> = Lazy::new(|| crate::secrets::load(&CAPTCHA.get("secret").string(), "captcha.json secret"));
+
+/// Route name used by the `/user/login` and `/auth/login` endpoints
+pub const LOGIN: &str = "login";
+/// Route name used by the `/op/comments` endpoint
+pub const COMMENT: &str = "comment";
+/// Route name used by the `/forms/<slug>` endpoint
+pub const FORM: &str = "form";
+
+/// Session key prefix holding the expected answer of a self-hosted challenge
+const CHALLENGE_KEY: &str = "captcha_challenge_";
+
+/// Header carrying a [`pass`]
+pub const PASS_HEADER: &str = "x-sfx-checked";
+/// How long a [`pass`] stays valid
+const PASS_AGE: Duration = Duration::from_secs(60);
+/// Passes handed out -> when
+static PASSES: Lazy<Mutex<HashMap<String, Instant>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The CAPTCHA backends that can be configured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provider {
+    None,
+    HCaptcha,
+    Turnstile,
+    Challenge,
+}
+
+impl Provider {
+    pub fn from_string(provider: &str) -> Self {
+        match provider.trim().to_ascii_lowercase().as_str() {
+            "hcaptcha" => Provider::HCaptcha,
+            "turnstile" => Provider::Turnstile,
+            "challenge" => Provider::Challenge,
+            _ => Provider::None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Provider::None => "none",
+            Provider::HCaptcha => "hcaptcha",
+            Provider::Turnstile => "turnstile",
+            Provider::Challenge => "challenge",
+        }
+    }
+
+    /// The form field the widget submits its response under
+    pub fn response_field(&self) -> &'static str {
+        match self {
+            Provider::HCaptcha => "h-captcha-response",
+            Provider::Turnstile => "cf-turnstile-response",
+            _ => "captcha_response",
+        }
+    }
+
+    /// The client script that renders the widget, if the provider needs one
+    pub fn script(&self) -> &'static str {
+        match self {
+            Provider::HCaptcha => "https://js.hcaptcha.com/1/api.js",
+            Provider::Turnstile => "https://challenges.cloudflare.com/turnstile/v0/api.js",
+            _ => "",
+        }
+    }
+
+    /// `(base address, path)` of the provider's server-side verification API
+    fn verify_endpoint(&self) -> Option<(&'static str, &'static str)> {
+        match self {
+            Provider::HCaptcha => Some(("https://api.hcaptcha.com", "/siteverify")),
+            Provider::Turnstile => Some((
+                "https://challenges.cloudflare.com",
+                "/turnstile/v0/siteverify",
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Errors returned by [`verify`]
+#[derive(Debug, PartialEq)]
+pub enum CaptchaError {
+    /// No CAPTCHA response was submitted
+    Missing,
+    /// The response was rejected by the provider or did not match the challenge
+    Failed,
+    /// The provider could not be reached or answered with something unexpected
+    Unavailable(Box<str>),
+}
+
+impl std::fmt::Display for CaptchaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CaptchaError::Missing => write!(f, "Captcha required"),
+            CaptchaError::Failed => write!(f, "Captcha verification failed"),
+            CaptchaError::Unavailable(msg) => write!(f, "Captcha service unavailable: {}", msg),
+        }
+    }
+}
+
+/// The configured provider, `Provider::None` when the config file is absent
+pub fn provider() -> Provider {
+    Provider::from_string(&CAPTCHA.get("provider").string())
+}
+
+/// Whether the CAPTCHA must be solved on the given route
+pub fn is_enabled(route: &str) -> bool {
+    provider() != Provider::None
+        && CAPTCHA
+            .get("routes")
+            .list()
+            .iter()
+            .any(|r| r.string() == route)
+}
+
+/// Build the template value describing the widget of `route`.
+///
+/// For the self-hosted challenge this also stores a fresh answer in the
+/// session, so it should be called once per rendered form.
+///
+/// # Returns
+/// ```json
+/// { "enabled": bool, "provider": "...", "site_key": "...", "script": "...",
+///   "field": "...", "question": "..." }
+/// ```
+pub fn widget(req: &mut HttpReqCtx, route: &str) -> Value {
+    if !is_enabled(route) {
+        return object!({ enabled: false });
+    }
+    let provider = provider();
+    let question = if provider == Provider::Challenge {
+        new_challenge(req, route)
+    } else {
+        String::new()
+    };
+    object!({
+        enabled: true,
+        provider: provider.as_str(),
+        site_key: CAPTCHA.get("site_key").string(),
+        script: provider.script(),
+        field: provider.response_field(),
+        question: question,
+    })
+}
+
+/// Read the CAPTCHA response the widget of the configured provider submits
+/// from a urlencoded form
+pub fn response_from_form(form: &UrlEncodedForm) -> String {
+    form.get_or_default(provider().response_field()).clone()
+}
+
+/// Read the CAPTCHA response from a JSON body, accepting either the
+/// provider's field name or the generic `captcha_response`
+pub fn response_from_json(json: &Value) -> String {
+    let value = json.get(provider().response_field()).string();
+    if value.is_empty() {
+        json.get("captcha_response").string()
+    } else {
+        value
+    }
+}
+
+/// Verify the submitted CAPTCHA `response` for `route`.
+///
+/// Returns `Ok(())` immediately when the route is not guarded.
+pub async fn verify(req: &mut HttpReqCtx, route: &str, response: &str) -> Result<(), CaptchaError> {
+    if !is_enabled(route) {
+        return Ok(());
+    }
+    let provider = provider();
+    if provider == Provider::Challenge {
+        // The answer is single use, whatever the outcome
+        let expected = req
+            .session_mut()
+            .ok()
+            .and_then(|session| session.remove(&format!("{}{}", CHALLENGE_KEY, route)))
+            .map(|v| v.string())
+            .unwrap_or_default();
+        if response.trim().is_empty() {
+            return Err(CaptchaError::Missing);
+        }
+        if expected.is_empty() || expected != response.trim() {
+            return Err(CaptchaError::Failed);
+        }
+        return Ok(());
+    }
+    if response.is_empty() {
+        return Err(CaptchaError::Missing);
+    }
+    let Some((address, path)) = provider.verify_endpoint() else {
+        return Ok(());
+    };
+
+    let mut form = UrlEncodedForm::new();
+    form.insert("secret".into(), SECRET.clone());
+    form.insert("response".into(), response.to_string());
+    if let Some(ip) = proxy::client_ip(req) {
+        form.insert("remoteip".into(), ip.to_string());
+    }
+    let site_key = CAPTCHA.get("site_key").string();
+    if !site_key.is_empty() {
+        form.insert("sitekey".into(), site_key);
+    }
+
+    let response = send_http_request(address, form_post(path, form), HttpSafety::default())
+        .await
+        .map_err(|err| CaptchaError::Unavailable(format!("{:?}", err).into()))?;
+    match response.body.parse_buffer(&HttpSafety::new()) {
+        HttpBody::Json(json) if json.get("success").boolean() => Ok(()),
+        HttpBody::Json(json) => {
+            tracing::info!(errors = %json.get("error-codes").string(), "Captcha rejected");
+            Err(CaptchaError::Failed)
+        }
+        _ => Err(CaptchaError::Unavailable("invalid response".into())),
+    }
+}
+
+/// A one-use pass, sent as [`PASS_HEADER`], for a call this process makes
+/// to its own API after checking the CAPTCHA and honeypot of the form
+/// behind it
+pub fn pass() -> String {
+    let pass = random_alphanumeric_string(32);
+    let mut passes = PASSES.lock().unwrap();
+    passes.retain(|_, at| at.elapsed() <= PASS_AGE);
+    passes.insert(pass.clone(), Instant::now());
+    pass
+}
+
+/// Whether `req` carries a [`pass`], which it uses up
+pub fn take_pass(req: &HttpReqCtx) -> bool {
+    req.header_str(PASS_HEADER).is_some_and(take)
+}
+
+fn take(pass: &str) -> bool {
+    PASSES.lock().unwrap().remove(pass).is_some_and(|at| at.elapsed() <= PASS_AGE)
+}
+
+/// Generate a new arithmetic question and store its answer in the session
+fn new_challenge(req: &mut HttpReqCtx, route: &str) -> String {
+    let seed = random_alphanumeric_string(2).into_bytes();
+    let (a, b) = (seed[0] as u32 % 10 + 1, seed[1] as u32 % 10 + 1);
+    if let Ok(session) = req.session_mut() {
+        session.insert(
+            format!("{}{}", CHALLENGE_KEY, route),
+            (a + b).to_string().into(),
+        );
+    }
+    format!("{} + {}", a, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn provider_round_trips_through_its_name() {
+        for provider in [
+            Provider::None,
+            Provider::HCaptcha,
+            Provider::Turnstile,
+            Provider::Challenge,
+        ] {
+            assert_eq!(Provider::from_string(provider.as_str()), provider);
+        }
+        assert_eq!(Provider::from_string(" Turnstile "), Provider::Turnstile);
+        assert_eq!(Provider::from_string("recaptcha"), Provider::None);
+    }
+
+    #[test]
+    fn only_remote_providers_have_a_verify_endpoint() {
+        assert!(Provider::HCaptcha.verify_endpoint().is_some());
+        assert!(Provider::Turnstile.verify_endpoint().is_some());
+        assert!(Provider::Challenge.verify_endpoint().is_none());
+        assert!(Provider::None.verify_endpoint().is_none());
+    }
+
+    #[test]
+    fn passes_are_used_once() {
+        let first = pass();
+        assert!(take(&first));
+        assert!(!take(&first));
+        assert!(!take("forged"));
+    }
+}
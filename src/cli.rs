@@ -0,0 +1,14 @@
+//! Subcommands of the `sfx` binary that go beyond scaffolding
+pub mod auth;
+pub mod backup;
+pub mod config;
+pub mod diff;
+pub mod generate;
+pub mod hooks;
+pub mod migrate;
+pub mod placeholders;
+pub mod scaffold;
+pub mod seed;
+pub mod templates;
+pub mod upgrade;
+pub mod user;
@@ -0,0 +1,68 @@
+//! `sfx auth`: authentication tools for development.
+//!
+//! `mock` runs a mock MainAuth server (`sfx::testing::mock_auth_server`)
+//! with the accounts given as `--user name:password`, so frontends can be
+//! signed in to a second server during development.
+
+use anyhow::{Context, Result};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+
+pub fn command() -> Command {
+    Command::new("auth")
+        .about("Authentication tools for development")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("mock")
+                .about("Run a mock MainAuth server for local development")
+                .arg(
+                    Arg::new("bind")
+                        .long("bind")
+                        .value_name("ADDR")
+                        .default_value("127.0.0.1:3400")
+                        .help("Address to listen on"),
+                )
+                .arg(
+                    Arg::new("user")
+                        .long("user")
+                        .value_name("NAME:PASSWORD")
+                        .action(ArgAction::Append)
+                        .help("Account of the server, repeatable"),
+                )
+                .arg(
+                    Arg::new("admin")
+                        .long("admin")
+                        .value_name("NAME")
+                        .action(ArgAction::Append)
+                        .help("Account reported as an admin by /auth/admin, repeatable"),
+                ),
+        )
+}
+
+pub fn run(matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        Some(("mock", sub)) => mock(sub),
+        _ => unreachable!(),
+    }
+}
+
+fn mock(matches: &ArgMatches) -> Result<()> {
+    let bind = matches.get_one::<String>("bind").expect("defaulted argument");
+    let admins: Vec<&String> = matches.get_many::<String>("admin").unwrap_or_default().collect();
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+    runtime.block_on(async {
+        let auth = sfx::testing::mock_auth_server_on(bind)
+            .await
+            .with_context(|| format!("Cannot listen on {}", bind))?;
+        for user in matches.get_many::<String>("user").unwrap_or_default() {
+            let (name, password) = user
+                .split_once(':')
+                .with_context(|| format!("--user {} is not NAME:PASSWORD", user))?;
+            let uid = auth.add_user(name, password);
+            auth.set_admin(uid, admins.iter().any(|admin| admin.as_str() == name));
+            println!("uid {:<4} {}", uid, name);
+        }
+        println!("Mock MainAuth server on {}; use the host {} in hosts.json. Ctrl-C to stop.", auth.address(), auth.server());
+        tokio::signal::ctrl_c().await?;
+        Ok(())
+    })
+}
@@ -0,0 +1,108 @@
+//! `sfx backup`: take, list and restore the archives of `sfx::backup`.
+//!
+//! Run from the site directory, since the paths of `backup.json` are
+//! relative to it. A running server keeps the accounts in memory and writes
+//! them out every few minutes, so `create` warns that it may miss recent
+//! changes (`/admin/backups` flushes them first), and `restore` refuses to
+//! run until the server is stopped. A restore takes a `--reason`, written to
+//! the admin audit log of `sfx::admin::sudo` with the system user.
+
+use anyhow::{Context, Result};
+use clap::{Arg, ArgMatches, Command};
+use std::path::{Path, PathBuf};
+
+use sfx::admin::sudo::{self, Sudo};
+use sfx::backup::{self, BackupSettings};
+use sfx::local_auth::fop::lock_users_file;
+use sfx::prelude::Value;
+
+pub fn command() -> Command {
+    Command::new("backup")
+        .about("Archive and restore programfiles and uploads")
+        .subcommand_required(true)
+        .arg(
+            Arg::new("programfiles")
+                .long("programfiles")
+                .value_name("DIR")
+                .global(true)
+                .help("Configuration directory (default: ./programfiles)"),
+        )
+        .subcommand(Command::new("create").about("Take a backup now"))
+        .subcommand(Command::new("list").about("List the backups, newest first"))
+        .subcommand(
+            Command::new("restore")
+                .about("Extract a backup over the current files (the server must be stopped)")
+                .arg(Arg::new("archive").required(true).index(1).help("Archive name or path"))
+                .arg(
+                    Arg::new("reason")
+                        .long("reason")
+                        .value_name("TEXT")
+                        .required(true)
+                        .help("Why the backup is restored, for the audit log"),
+                ),
+        )
+}
+
+pub fn run(matches: &ArgMatches) -> Result<()> {
+    let programfiles = match matches.get_one::<String>("programfiles") {
+        Some(dir) => PathBuf::from(dir),
+        None => sfx::op::programfiles(),
+    };
+    let config = programfiles.join("op/backup.json");
+    let settings = BackupSettings::from_value(&Value::from_jsonf(config.to_string_lossy()).unwrap_or(Value::None));
+    let users_file = programfiles.join("local_auth/users").to_string_lossy().into_owned();
+
+    match matches.subcommand() {
+        Some(("create", _)) => {
+            let lock = lock_users_file(&users_file);
+            if lock.is_err() {
+                println!("The server is running: account changes of the last few minutes may not be on disk yet.");
+                println!("Use /admin/backups for a backup that includes them.");
+            }
+            let path = backup::create(&settings).map_err(anyhow::Error::msg)?;
+            println!("Created {}", path.display());
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+            runtime.block_on(backup::offsite(&path, &settings)).map_err(anyhow::Error::msg)?;
+        }
+        Some(("list", _)) => {
+            let backups = backup::list(&settings.dir);
+            if backups.is_empty() {
+                println!("No backups in {}", settings.dir.display());
+            }
+            for backup in backups {
+                println!("{:<40} {:>8} KiB", backup.name, backup.size.div_ceil(1024));
+            }
+        }
+        Some(("restore", sub)) => {
+            let archive = archive_path(&settings, sub.get_one::<String>("archive").expect("required argument"))?;
+            let reason = sudo::reason(sub.get_one::<String>("reason").expect("required argument"))
+                .context("Give a reason for the restore")?;
+            let _lock = lock_users_file(&users_file)
+                .with_context(|| format!("Cannot lock {}. Stop the running server first.", users_file))?;
+            // Whatever is replaced can be brought back
+            let current = backup::create(&settings).map_err(anyhow::Error::msg)?;
+            println!("Saved the current state as {}", current.display());
+            backup::restore(&archive, Path::new(".")).map_err(anyhow::Error::msg)?;
+            println!("Restored {}", archive.display());
+            // After the restore, which replaces the log with that of the backup
+            let by = format!("cli:{}", std::env::var("USER").unwrap_or_else(|_| "unknown".to_string()));
+            let name = archive.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+            sudo::record(&programfiles, "restore", "backup", &name, &Sudo { by, reason });
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+/// `archive` as given, or as a name in the backup directory
+fn archive_path(settings: &BackupSettings, archive: &str) -> Result<PathBuf> {
+    let path = PathBuf::from(archive);
+    if path.is_file() {
+        return Ok(path);
+    }
+    let in_dir = settings.dir.join(archive);
+    if in_dir.is_file() {
+        return Ok(in_dir);
+    }
+    anyhow::bail!("No backup named {} (see `sfx backup list`)", archive)
+}
@@ -0,0 +1,1164 @@
+//! `sfx config`: validate and scaffold the `programfiles` directory.
+//!
+//! Most configuration is read lazily and silently falls back to
+//! `Value::None`, which only shows up later as an empty navbar or a failed
+//! template render. `check` catches those mistakes up front; `init` writes the
+//! shipped defaults for every file that is missing.
+
+use anyhow::{Context, Result};
+use clap::{Arg, ArgMatches, Command};
+use include_dir::{Dir, DirEntry};
+use std::collections::HashSet;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use sfx::analytics::AnalyticsSettings;
+use sfx::blog::{self, Post, PostStatus};
+use sfx::captcha::{self, Provider};
+use sfx::comments::CommentSettings;
+use sfx::consent::ConsentSettings;
+use sfx::flags::FlagSettings;
+use sfx::forms::FormSchema;
+use sfx::graphql::{self, GraphqlSettings};
+use sfx::geo::{GeoDatabase, GeoSettings};
+use sfx::grpc::GrpcSettings;
+use sfx::honeypot::HoneypotSettings;
+use sfx::images::{self, ImageSettings};
+use sfx::ip_filter::Cidr;
+use sfx::l10n::fluent::Resource;
+use sfx::local_auth::at_rest::{self, StoreSettings};
+use sfx::media::MediaSettings;
+use sfx::moderation::ModerationSettings;
+use sfx::local_auth::rules::{RuleKind, RuleSet};
+use sfx::local_auth::sessions;
+use sfx::op::{Binding, Language};
+use sfx::outbound::{ClientCert, OutboundSettings};
+use sfx::security_headers;
+use sfx::session::{MIN_SECRET_LEN, SessionKey, SessionSettings};
+use sfx::settings;
+use sfx::shortlinks::ShortLinkSettings;
+use sfx::storage::{S3Store, StorageSettings};
+use sfx::prelude::Value;
+use sfx::qr::QrSettings;
+use sfx::scan::ScanSettings;
+use sfx::unix_socket::UnixSocketSettings;
+use sfx::user::UserID;
+
+use crate::TEMPLATE_DIR;
+use crate::cli::placeholders;
+
+/// Files whose absence breaks page rendering
+const REQUIRED: &[&str] = &[
+    "op/support_lang.json",
+    "op/navbar.json",
+    "op/footer.json",
+    "op/l10n.json",
+    "op/hosts.json",
+    "admin_info/admins.json",
+];
+
+/// Listener names that exist without being declared in `bindings.json`
+const BUILTIN_LISTENERS: &[&str] = &[
+    sfx::bindings::MAIN,
+    sfx::bindings::TLS,
+    sfx::bindings::UNIX,
+];
+
+pub fn command() -> Command {
+    Command::new("config")
+        .about("Validate or scaffold the programfiles directory")
+        .subcommand_required(true)
+        .arg(
+            Arg::new("programfiles")
+                .long("programfiles")
+                .value_name("DIR")
+                .global(true)
+                .help("Configuration directory (default: ./programfiles)"),
+        )
+        .subcommand(Command::new("check").about("Report invalid or missing configuration"))
+        .subcommand(Command::new("init").about("Write the default for every missing file"))
+        .subcommand(
+            Command::new("rotate-session-key")
+                .about("Add a new key sealing sessions; older keys keep opening theirs")
+                .arg(
+                    Arg::new("retire")
+                        .long("retire")
+                        .action(clap::ArgAction::SetTrue)
+                        .help("Retire every older key, ending the sessions it sealed"),
+                ),
+        )
+}
+
+pub fn run(matches: &ArgMatches) -> Result<()> {
+    let dir = match matches.get_one::<String>("programfiles") {
+        Some(dir) => PathBuf::from(dir),
+        None => std::env::current_dir()?.join("programfiles"),
+    };
+    match matches.subcommand() {
+        Some(("check", _)) => {
+            let report = check(&dir);
+            for issue in &report.issues {
+                let level = if issue.error { "error" } else { "warning" };
+                println!("{}: {}: {}", level, issue.file, issue.message);
+            }
+            let errors = report.issues.iter().filter(|i| i.error).count();
+            if errors > 0 {
+                anyhow::bail!("{} error(s) in {}", errors, dir.display());
+            }
+            println!("{} is valid", dir.display());
+        }
+        Some(("init", _)) => {
+            let defaults = TEMPLATE_DIR
+                .get_dir("programfiles")
+                .context("The embedded template has no programfiles")?;
+            let name = std::env::current_dir()?
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let mut vars = placeholders::defaults(&name);
+            placeholders::finish(&mut vars)?;
+            let created = init(defaults, &dir, &vars)?;
+            for path in &created {
+                println!("created {}", path.display());
+            }
+            if created.is_empty() {
+                println!("Nothing to do, every default file exists");
+            }
+        }
+        Some(("rotate-session-key", sub)) => {
+            let path = dir.join("op/session.json");
+            let current = Value::from_jsonf(path.to_str().unwrap_or_default()).unwrap_or(Value::None);
+            let settings = SessionSettings::from_value(&current);
+            let mut id = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs();
+            while settings.keys.iter().map(|key| &key.id).chain(&settings.retired).any(|used| *used == id.to_string()) {
+                id += 1;
+            }
+            let id = id.to_string();
+            // The new secret goes to its own private file, session.json only names it
+            let key_file = format!("{}/session-{}.key", sfx::secrets::DIR, id);
+            fs::create_dir_all(dir.join(sfx::secrets::DIR))?;
+            sfx::secrets::write_private(&dir.join(&key_file), hotaru_lib::random::random_alphanumeric_string(64))?;
+            let rotated = rotate_session_key(settings, &id, &format!("file:{}", key_file), sub.get_flag("retire"));
+            fs::create_dir_all(path.parent().unwrap_or(&dir))?;
+            fs::write(&path, session_json(&rotated))?;
+            println!("Sessions are now sealed with key '{}'; restart the server to use it", id);
+            if !rotated.retired.is_empty() {
+                println!("Retired: {}", rotated.retired.join(", "));
+            }
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+/// One problem found by [`check`]
+#[derive(Debug, PartialEq)]
+pub struct Issue {
+    pub file: String,
+    pub message: String,
+    /// Errors fail the check, warnings are only printed
+    pub error: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub issues: Vec<Issue>,
+}
+
+impl Report {
+    fn error(&mut self, file: &str, message: impl Into<String>) {
+        self.issues.push(Issue { file: file.to_string(), message: message.into(), error: true });
+    }
+
+    fn warn(&mut self, file: &str, message: impl Into<String>) {
+        self.issues.push(Issue { file: file.to_string(), message: message.into(), error: false });
+    }
+}
+
+/// Validate the `programfiles` directory `dir`
+pub fn check(dir: &Path) -> Report {
+    let mut report = Report::default();
+    if !dir.is_dir() {
+        report.error(&dir.display().to_string(), "directory not found, run `sfx config init`");
+        return report;
+    }
+
+    // Every JSON file must parse, wherever it is
+    let mut json_files = Vec::new();
+    collect_json(dir, &mut json_files);
+    for path in json_files {
+        if Value::from_jsonf(path.to_str().unwrap_or_default()).is_err() {
+            let name = path.strip_prefix(dir).unwrap_or(&path).display().to_string();
+            report.error(&name, "not valid JSON");
+        }
+    }
+    for file in REQUIRED {
+        if !dir.join(file).exists() {
+            report.error(file, "missing, run `sfx config init` to create the default");
+        }
+    }
+
+    let load = |file: &str| Value::from_jsonf(dir.join(file).to_str().unwrap_or_default()).ok();
+
+    let langs: Vec<String> = match load("op/support_lang.json") {
+        Some(Value::List(list)) => {
+            for entry in &list {
+                check_language(entry, &mut report);
+            }
+            Language::list(&Value::List(list)).into_iter().map(|language| language.code).collect()
+        }
+        Some(_) => {
+            report.error("op/support_lang.json", "must be a list of language codes, e.g. [\"en\", \"zh\"]");
+            Vec::new()
+        }
+        None => Vec::new(),
+    };
+    if langs.is_empty() && dir.join("op/support_lang.json").exists() {
+        report.error("op/support_lang.json", "lists no language; the first entry is the site default");
+    }
+
+    for file in ["op/navbar.json", "op/footer.json"] {
+        match load(file) {
+            Some(Value::Dict(map)) => {
+                for lang in &langs {
+                    if !map.contains_key(lang) {
+                        report.error(file, format!("no entry for language '{}' from support_lang.json", lang));
+                    }
+                }
+            }
+            Some(_) => report.error(file, "must be an object keyed by language code"),
+            None => {}
+        }
+    }
+
+    if let Some(l10n) = load("op/l10n.json") {
+        check_l10n(&l10n, &langs, &mut report);
+    }
+    if let Some(value) = load("op/translation.json") {
+        check_translation(&value, dir, &langs, &mut report);
+    }
+
+    let hosts: HashSet<String> = match load("op/hosts.json") {
+        Some(Value::List(list)) => list.iter().map(|h| h.string()).collect(),
+        Some(_) => {
+            report.error("op/hosts.json", "must be a list of trusted hosts, e.g. [\"local\"]");
+            HashSet::new()
+        }
+        None => HashSet::new(),
+    };
+    match load("admin_info/admins.json") {
+        Some(Value::List(list)) => {
+            for entry in list {
+                check_admin(&entry.string(), &hosts, &mut report);
+            }
+        }
+        Some(_) => report.error("admin_info/admins.json", "must be a list of `uid@host` strings"),
+        None => {}
+    }
+
+    check_binding(dir, &mut report);
+    for file in ["op/ip_filter.json", "op/proxy.json"] {
+        if let Some(Value::Dict(map)) = load(file) {
+            for (key, value) in map {
+                check_cidrs(file, &key, &value, &mut report);
+            }
+        }
+    }
+    if let Some(value) = load("op/bindings.json") {
+        check_bindings(&value, &mut report);
+    }
+    if let Some(value) = load("op/captcha.json") {
+        check_captcha(&value, &mut report);
+        check_secret("op/captcha.json", "`secret`", &value.get("secret").string(), dir, &mut report);
+    }
+    if let Some(database) = load("op/geo.json").and_then(|value| GeoSettings::from_value(&value).database)
+        && let Err(err) = GeoDatabase::open(&dir.join(&database))
+    {
+        report.error("op/geo.json", format!("cannot load {}: {}", database.display(), err));
+    }
+    if let Some(value) = load("op/honeypot.json") {
+        let settings = HoneypotSettings::from_value(&value);
+        if settings.max_age <= settings.min_seconds {
+            report.error("op/honeypot.json", "`max_age` must be longer than `min_seconds`");
+        }
+        check_secret("op/honeypot.json", "`secret`", &settings.secret, dir, &mut report);
+    }
+    if let Some(value) = load("op/login_rules.json") {
+        match RuleSet::from_value(&value) {
+            Ok(rules) => {
+                if !rules.webhooks.is_empty() {
+                    check_secret("op/login_rules.json", "`secret`", &rules.secret, dir, &mut report);
+                }
+                if rules.rules.iter().any(|rule| matches!(rule.kind, RuleKind::NewCountry | RuleKind::ImpossibleTravel { .. }))
+                    && load("op/geo.json").and_then(|value| GeoSettings::from_value(&value).database).is_none()
+                {
+                    report.warn("op/login_rules.json", "country and travel rules need a database in op/geo.json");
+                }
+            }
+            Err(err) => report.error("op/login_rules.json", err),
+        }
+    }
+    if let Some(value) = load("op/security_headers.json") {
+        check_security_headers(&value, &mut report);
+    }
+    if let Some(value) = load("op/consent.json") {
+        check_consent(&value, &langs, &mut report);
+    }
+    if let Some(value) = load("op/flags.json") {
+        check_flags(&value, &mut report);
+    }
+    if let Some(value) = load("op/shortlinks.json") {
+        check_shortlinks(&value, &mut report);
+    }
+    if let Some(value) = load("op/graphql.json") {
+        check_graphql(&value, &mut report);
+    }
+    if let Some(value) = load("op/qr.json") {
+        check_qr(&value, &mut report);
+    }
+    if let Some(value) = load("op/images.json") {
+        check_images(&value, &mut report);
+    }
+    if let Some(value) = load("op/media.json") {
+        let settings = MediaSettings::from_value(&value);
+        if check_secret("op/media.json", "`secret`", &settings.secret, dir, &mut report).is_empty() {
+            report.warn("op/media.json", "no `secret`, signed file URLs stop working on restart");
+        }
+        for kind in &settings.types {
+            if kind.split_once('/').is_none_or(|(family, sub)| family.is_empty() || sub.is_empty() || family == "*") {
+                report.error("op/media.json", format!("'{}' is not a MIME type like `application/pdf` or `image/*`", kind));
+            }
+            if kind == "text/html" || kind == "image/svg+xml" || kind == "text/*" {
+                report.warn("op/media.json", format!("'{}' files can carry script; they are only served as downloads", kind));
+            }
+        }
+    }
+    if let Some(value) = load("op/scan.json") {
+        check_scan(&value, dir, &mut report);
+    }
+    if let Some(value) = load("op/storage.json") {
+        check_storage(&value, dir, &mut report);
+    }
+    let honeypot = load("op/honeypot.json").map(|value| HoneypotSettings::from_value(&value)).unwrap_or_default();
+    if let Some(value) = load("op/comments.json") {
+        check_comments(&value, &honeypot, &mut report);
+    }
+    if let Some(value) = load("op/moderation.json") {
+        check_moderation(&value, &mut report);
+    }
+    if let Some(value) = load("op/blog.json")
+        && !matches!(value.get("title"), Value::Str(_) | Value::None)
+    {
+        report.error("op/blog.json", "`title` must be a string");
+    }
+    check_blog(dir, &mut report);
+    check_forms(dir, &honeypot, &mut report);
+    check_settings(dir, &mut report);
+    if let Some(value) = load("op/analytics.json") {
+        let settings = AnalyticsSettings::from_value(&value);
+        let categories = load("op/consent.json").map(|consent| ConsentSettings::from_value(&consent).categories).unwrap_or_default();
+        if settings.enabled && !settings.consent.is_empty() && !categories.iter().any(|category| category.name == settings.consent) {
+            report.warn(
+                "op/analytics.json",
+                format!("consent category '{}' is not in op/consent.json, nothing will be counted", settings.consent),
+            );
+        }
+    }
+    if let Some(value) = load("op/tls.json") {
+        check_tls(&value, dir, &mut report);
+    }
+    if let Some(value) = load("op/unix_socket.json")
+        && !value.get("mode").is_none()
+        && UnixSocketSettings::from_value(&value).mode.is_none()
+    {
+        report.error("op/unix_socket.json", "`mode` must be octal permissions, e.g. \"660\"");
+    }
+    if let Some(value) = load("op/grpc.json")
+        && value.get("enabled").boolean()
+    {
+        check_grpc(&value, dir, &mut report);
+    }
+    if let Some(value) = load("op/outbound.json") {
+        check_outbound(&value, dir, &mut report);
+    }
+    if let Some(value) = load("op/access.json")
+        && let Err(err) = sfx::access::AccessPolicy::from_value(&value)
+    {
+        report.error("op/access.json", err);
+    }
+    if let Some(Value::Dict(modules)) = load("op/modules.json") {
+        for (name, value) in &modules {
+            if !matches!(value, Value::Boolean(_)) {
+                report.error("op/modules.json", format!("`{}` must be true or false", name));
+            } else if !sfx::modules::ALL.iter().any(|module| module.name == name) {
+                // Modules of the application register only when it runs
+                report.warn("op/modules.json", format!("unknown module '{}', unless the application registers it", name));
+            }
+        }
+    }
+    if let Some(value) = load("op/database.json") {
+        match sfx::database::Backend::from_value(&value) {
+            Err(err) => report.error("op/database.json", err.trim_start_matches("database.json: ")),
+            // A plain URL is fine unless it holds a password; only references are checked
+            Ok(sfx::database::Backend::Postgres(url)) if sfx::secrets::is_reference(&url) => {
+                check_secret("op/database.json", "`url`", &url, dir, &mut report);
+            }
+            Ok(_) => {}
+        }
+    }
+    if let Some(value) = load("op/logout.json") {
+        let settings = sfx::user::logout::LogoutSettings::from_value(&value);
+        for url in &settings.notify {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                report.error("op/logout.json", format!("frontend '{}' must be an http:// or https:// URL", url));
+            }
+        }
+        check_secret("op/logout.json", "`secret`", &settings.secret, dir, &mut report);
+        for (host, secret) in &settings.hosts {
+            check_secret("op/logout.json", &format!("the secret of '{}'", host), secret, dir, &mut report);
+        }
+        if !settings.notify.is_empty() && settings.secret.is_empty() {
+            report.error("op/logout.json", "`notify` needs the `secret` the frontends expect");
+        }
+        if !settings.notify.is_empty() && !settings.everywhere {
+            report.warn("op/logout.json", "frontends are only notified when `everywhere` is true");
+        }
+    }
+    if let Some(value) = load("op/session.json") {
+        check_session(&value, dir, &mut report);
+    }
+    let users_key = load("op/local_auth.json").map(|value| StoreSettings::from_value(&value).users_key);
+    let key = check_secret("op/local_auth.json", "`users_key`", &users_key.unwrap_or_default(), dir, &mut report);
+    if !key.is_empty() && key.len() < at_rest::MIN_KEY_LEN {
+        report.error("op/local_auth.json", format!("`users_key` must have at least {} characters", at_rest::MIN_KEY_LEN));
+    }
+    if let Some(value) = load("op/local_auth.json")
+        && !matches!(value.get("age").get("minimum"), Value::None | Value::Numerical(0.0..))
+    {
+        report.error("op/local_auth.json", "`age.minimum` must be a number of years, 0 for no minimum");
+    }
+    if let Some(value) = load("op/local_auth.json") {
+        let limits = sessions::SessionSettings::from_value(value.get("sessions"));
+        if limits.sliding_minutes != 0 && limits.max_age_hours == 0 {
+            report.warn("op/local_auth.json", "`sessions.sliding_minutes` without `max_age_hours` keeps tokens in use from ever expiring");
+        }
+    }
+    if let Ok(contents) = fs::read_to_string(dir.join("local_auth/users")) {
+        match at_rest::unseal(&contents, Some(&key).filter(|key| !key.is_empty()).map(String::as_str)) {
+            Ok(users) => check_users(&users, &mut report),
+            Err(err) => report.error("local_auth/users", err),
+        }
+    }
+    report
+}
+
+/// Resolve the secret `value` of the setting `what`, reporting references
+/// that do not load and secrets written in the file
+fn check_secret(file: &str, what: &str, value: &str, dir: &Path, report: &mut Report) -> String {
+    if value.is_empty() {
+        return String::new();
+    }
+    if !sfx::secrets::is_reference(value) {
+        report.warn(file, format!("{} is written in the file, use an env:, file: or cmd: reference", what));
+    }
+    sfx::secrets::resolve_in(value, dir).unwrap_or_else(|err| {
+        report.error(file, format!("{}: {}", what, err));
+        String::new()
+    })
+}
+
+fn check_session(value: &Value, dir: &Path, report: &mut Report) {
+    let mut settings = SessionSettings::from_value(value);
+    let mut seen = HashSet::new();
+    for key in &mut settings.keys {
+        key.secret = check_secret("op/session.json", &format!("key '{}'", key.id), &key.secret, dir, report);
+        if !key.is_usable() {
+            report.error(
+                "op/session.json",
+                format!("key '{}' needs an id without '.' and a secret of {}+ characters", key.id, MIN_SECRET_LEN),
+            );
+        }
+        if !seen.insert(&key.id) {
+            report.error("op/session.json", format!("key id '{}' is used twice", key.id));
+        }
+    }
+    if settings.current().is_none() {
+        report.warn("op/session.json", "no usable key, sessions will not survive a restart");
+    }
+}
+
+/// Put a new key `id` in front of the ring, its secret given by `secret`.
+/// With `retire` the older keys move to `retired`, which ends their sessions.
+fn rotate_session_key(mut settings: SessionSettings, id: &str, secret: &str, retire: bool) -> SessionSettings {
+    let key = SessionKey { id: id.to_string(), secret: secret.to_string() };
+    if retire {
+        for old in settings.keys.drain(..) {
+            if !settings.retired.contains(&old.id) {
+                settings.retired.push(old.id);
+            }
+        }
+    }
+    settings.keys.insert(0, key);
+    settings
+}
+
+fn session_json(settings: &SessionSettings) -> String {
+    let keys: Vec<String> = settings
+        .keys
+        .iter()
+        .map(|key| {
+            format!(
+                "        {{ \"id\": {}, \"secret\": {} }}",
+                Value::Str(key.id.clone()).into_json(),
+                Value::Str(key.secret.clone()).into_json()
+            )
+        })
+        .collect();
+    let retired: Vec<String> = settings.retired.iter().map(|id| Value::Str(id.clone()).into_json()).collect();
+    format!("{{\n    \"keys\": [\n{}\n    ],\n    \"retired\": [{}]\n}}\n", keys.join(",\n"), retired.join(", "))
+}
+
+fn collect_json(dir: &Path, out: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else { return };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_json(&path, out);
+        } else if path.extension().is_some_and(|ext| ext == "json") {
+            out.push(path);
+        }
+    }
+}
+
+fn check_l10n(l10n: &Value, langs: &[String], report: &mut Report) {
+    let Value::Dict(map) = l10n else {
+        report.error("op/l10n.json", "must be an object of { key: { lang: text } }");
+        return;
+    };
+    let Some(default) = langs.first() else { return };
+    let mut keys: Vec<&String> = map.keys().collect();
+    keys.sort();
+    for key in keys {
+        let Value::Dict(texts) = &map[key] else { continue };
+        if !texts.contains_key(default) {
+            report.error(
+                "op/l10n.json",
+                format!("'{}' has no text in the default language '{}'", key, default),
+            );
+        }
+        for lang in &langs[1..] {
+            if !texts.contains_key(lang) {
+                report.warn(
+                    "op/l10n.json",
+                    format!("'{}' has no '{}' text, '{}' is shown instead", key, lang, default),
+                );
+            }
+        }
+    }
+}
+
+fn check_admin(entry: &str, hosts: &HashSet<String>, report: &mut Report) {
+    let file = "admin_info/admins.json";
+    match UserID::from_str(entry) {
+        Some(id) => {
+            let host = id.to_string().split_once('@').map(|(_, h)| h.to_string()).unwrap_or_default();
+            if host != "local" && !hosts.contains(&host) {
+                report.warn(file, format!("'{}' refers to host '{}', which is not in op/hosts.json", entry, host));
+            }
+        }
+        None => report.error(file, format!("'{}' is not `uid@host`, e.g. `1@local`", entry)),
+    }
+}
+
+fn check_binding(dir: &Path, report: &mut Report) {
+    let file = "op/binding.txt";
+    let Ok(content) = fs::read_to_string(dir.join(file)) else {
+        report.warn(file, "missing, the server binds localhost:3003");
+        return;
+    };
+    match Binding::parse(&content) {
+        Binding::Tcp(address) if address.rsplit_once(':').is_none_or(|(_, port)| port.parse::<u16>().is_err()) => {
+            report.error(file, format!("'{}' is not `host:port` or `unix:/path`", address));
+        }
+        Binding::Unix(path) if path.parent().is_some_and(|parent| !parent.as_os_str().is_empty() && !parent.is_dir()) => {
+            report.error(file, format!("the directory of socket {} does not exist", path.display()));
+        }
+        _ => {}
+    }
+}
+
+fn check_cidrs(file: &str, key: &str, value: &Value, report: &mut Report) {
+    let Value::List(list) = value else { return };
+    for entry in list {
+        if Cidr::parse(&entry.string()).is_none() {
+            report.error(file, format!("`{}` entry '{}' is not an address or CIDR network", key, entry.string()));
+        }
+    }
+}
+
+fn check_bindings(value: &Value, report: &mut Report) {
+    let file = "op/bindings.json";
+    let mut names: HashSet<String> = BUILTIN_LISTENERS.iter().map(|n| n.to_string()).collect();
+    if let Value::List(listeners) = value.get("listeners") {
+        for listener in listeners {
+            let (name, address) = (listener.get("name").string(), listener.get("address").string());
+            if name.is_empty() || address.is_empty() {
+                report.error(file, "every listener needs a `name` and an `address`");
+            }
+            names.insert(name);
+        }
+    }
+    if let Value::Dict(routes) = value.get("routes") {
+        for (prefix, allowed) in routes {
+            for name in allowed.list() {
+                if !names.contains(&name.string()) {
+                    report.error(file, format!("route '{}' names unknown listener '{}'", prefix, name.string()));
+                }
+            }
+        }
+    }
+}
+
+fn check_language(entry: &Value, report: &mut Report) {
+    let file = "op/support_lang.json";
+    if Language::from_value(entry).is_none() {
+        report.error(file, "entries are codes like \"en\" or objects like {\"code\": \"ar\", \"dir\": \"rtl\"}");
+        return;
+    }
+    let dir = entry.get("dir").string();
+    if matches!(entry, Value::Dict(_)) && !dir.is_empty() && dir != "rtl" && dir != "ltr" {
+        report.error(file, format!("`dir` of '{}' must be rtl or ltr", entry.get("code").string()));
+    }
+}
+
+fn check_translation(value: &Value, dir: &Path, langs: &[String], report: &mut Report) {
+    let backend = value.get("backend").string();
+    match backend.as_str() {
+        "" | "json" => {}
+        "fluent" => {
+            let ftl_dir = match value.get("dir") {
+                Value::Str(ftl_dir) if !ftl_dir.is_empty() => ftl_dir.clone(),
+                _ => "l10n".to_string(),
+            };
+            for lang in langs {
+                let file = format!("{}/{}.ftl", ftl_dir, lang);
+                match fs::read_to_string(dir.join(&file)) {
+                    Ok(source) => {
+                        for err in Resource::parse(&source).1 {
+                            report.error(&file, err);
+                        }
+                    }
+                    Err(_) => report.error(&file, format!("missing, the Fluent file of language '{}'", lang)),
+                }
+            }
+        }
+        _ => report.error("op/translation.json", format!("unknown backend '{}', use json or fluent", backend)),
+    }
+}
+
+fn check_captcha(value: &Value, report: &mut Report) {
+    let file = "op/captcha.json";
+    let name = value.get("provider").string();
+    let provider = Provider::from_string(&name);
+    if !name.is_empty() && provider.as_str() != name.trim().to_ascii_lowercase() {
+        report.error(file, format!("unknown provider '{}', use none, hcaptcha, turnstile or challenge", name));
+    }
+    if matches!(provider, Provider::HCaptcha | Provider::Turnstile) {
+        for key in ["site_key", "secret"] {
+            if value.get(key).string().is_empty() {
+                report.error(file, format!("provider '{}' needs `{}`", provider.as_str(), key));
+            }
+        }
+    }
+}
+
+fn check_security_headers(value: &Value, report: &mut Report) {
+    let file = "op/security_headers.json";
+    let policy = value.get("content_security_policy").string();
+    if policy.contains("'nonce-") && !policy.contains(security_headers::NONCE_PLACEHOLDER) {
+        report.error(file, "the policy has a fixed nonce, write 'nonce-{nonce}' for the nonce of each request");
+    }
+    if !policy.is_empty() && !policy.contains(security_headers::NONCE_PLACEHOLDER) {
+        report.warn(file, "the policy has no 'nonce-{nonce}', nonce-tagged inline scripts will not run");
+    }
+    match value.get("headers") {
+        Value::Dict(map) => {
+            for (name, header) in map {
+                if !matches!(header, Value::Str(_)) {
+                    report.error(file, format!("header '{}' must be a string", name));
+                }
+            }
+        }
+        Value::None => {}
+        _ => report.error(file, "`headers` must be an object of header names and values"),
+    }
+}
+
+fn check_consent(value: &Value, langs: &[String], report: &mut Report) {
+    let file = "op/consent.json";
+    let Value::List(categories) = value.get("categories") else {
+        report.error(file, "`categories` must be a list of { \"name\": ..., \"required\": ... }");
+        return;
+    };
+    let mut names = HashSet::new();
+    for category in categories {
+        let name = category.get("name").string();
+        if name.is_empty() {
+            report.error(file, "a category has no `name`");
+        } else if !names.insert(name.clone()) {
+            report.error(file, format!("category '{}' is listed twice", name));
+        }
+    }
+    if ConsentSettings::from_value(value).is_enabled() {
+        for lang in langs {
+            if !matches!(value.get("text").get(lang), Value::Dict(_)) {
+                report.warn(file, format!("no banner text for language '{}', the default one is shown", lang));
+            }
+        }
+    }
+}
+
+fn check_flags(value: &Value, report: &mut Report) {
+    let file = "op/flags.json";
+    if !matches!(value.get("flags"), Value::Dict(_) | Value::None) {
+        report.error(file, "`flags` must be an object of flag names and true/false");
+    }
+    if !matches!(value.get("experiments"), Value::List(_) | Value::None) {
+        report.error(file, "`experiments` must be a list of { \"name\": ..., \"variants\": [...] }");
+    }
+    let mut names = HashSet::new();
+    for experiment in FlagSettings::from_value(value).experiments {
+        if !names.insert(experiment.name.clone()) {
+            report.error(file, format!("experiment '{}' is listed twice", experiment.name));
+        }
+        if experiment.name.contains(':') || experiment.variants.iter().any(|variant| variant.name.contains(':')) {
+            report.error(file, format!("experiment '{}': names may not contain ':'", experiment.name));
+        }
+        if experiment.variants.iter().all(|variant| variant.weight == 0) {
+            report.error(file, format!("experiment '{}' has no variant with a weight", experiment.name));
+        }
+    }
+}
+
+fn check_graphql(value: &Value, report: &mut Report) {
+    let file = "op/graphql.json";
+    if !matches!(value.get("max_depth"), Value::None) && !matches!(value.get("max_depth"), Value::Numerical(depth) if *depth >= 1.0 && depth.fract() == 0.0) {
+        report.error(file, "`max_depth` must be a whole number of at least 1");
+    }
+    if !matches!(value.get("queries"), Value::Dict(_) | Value::None) {
+        report.error(file, "`queries` must map ids to queries");
+    }
+    let settings = GraphqlSettings::from_value(value);
+    for (id, query) in &settings.queries {
+        if let Err(err) = graphql::parse::parse(query) {
+            report.error(file, format!("query '{}' does not parse: {}", id, err));
+        }
+    }
+    if settings.persisted_only && settings.queries.is_empty() {
+        report.warn(file, "`persisted_only` with no `queries` runs only the queries clients persisted before");
+    }
+}
+
+fn check_grpc(value: &Value, dir: &Path, report: &mut Report) {
+    let file = "op/grpc.json";
+    let settings = GrpcSettings::from_value(value);
+    let Ok(binding) = settings.binding.parse::<SocketAddr>() else {
+        report.error(file, format!("`binding` '{}' is not an address like 127.0.0.1:50051", settings.binding));
+        return;
+    };
+    if check_secret(file, "`key`", &settings.key, dir, report).is_empty() && !binding.ip().is_loopback() {
+        report.error(file, "no `key` on a non-loopback binding, the server refuses to start the service");
+    }
+}
+
+fn check_tls(value: &Value, dir: &Path, report: &mut Report) {
+    let file = "op/tls.json";
+    let settings = sfx::tls::TlsSettings::from_value(value);
+    let root = dir.parent().unwrap_or(dir);
+    if settings.enabled {
+        for path in [Some(&settings.cert), Some(&settings.key), settings.client_ca.as_ref()].into_iter().flatten() {
+            if !root.join(path).exists() {
+                report.error(file, format!("TLS is enabled but {} does not exist", path.display()));
+            }
+        }
+    }
+    let asks_for_certs = settings.enabled && settings.client_ca.is_some();
+    if !settings.client_cert_routes.is_empty() && !asks_for_certs {
+        report.warn(file, "`client_cert_routes` without TLS and a `client_ca` are only reachable by the server's own loopback calls");
+    }
+    for fingerprint in &settings.client_fingerprints {
+        if fingerprint.len() != 64 || !fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+            report.error(file, format!("'{}' is not a SHA-256 fingerprint", fingerprint));
+        }
+    }
+}
+
+fn check_outbound(value: &Value, dir: &Path, report: &mut Report) {
+    let file = "op/outbound.json";
+    if !matches!(value.get("no_proxy"), Value::List(_) | Value::None) {
+        report.error(file, "`no_proxy` must be a list of hosts, domains or CIDRs");
+    }
+    if !matches!(value.get("hosts"), Value::Dict(_) | Value::None) {
+        report.error(file, "`hosts` must map hosts to a proxy URL or \"direct\"");
+    }
+    for err in OutboundSettings::from_value(value).errors {
+        report.error(file, err);
+    }
+    check_secret(file, "`credentials`", &value.get("credentials").string(), dir, report);
+    let root = dir.parent().unwrap_or(dir);
+    for (host, cert) in OutboundSettings::from_value(value).client_certs {
+        let cert = ClientCert { cert: root.join(&cert.cert), key: root.join(&cert.key), ca: cert.ca.map(|ca| root.join(ca)) };
+        if let Err(err) = cert.tls_config() {
+            report.error(file, format!("client certificate of '{}': {}", host, err));
+        }
+    }
+}
+
+fn check_shortlinks(value: &Value, report: &mut Report) {
+    let file = "op/shortlinks.json";
+    if !matches!(value.get("allowed_hosts"), Value::List(_) | Value::None) {
+        report.error(file, "`allowed_hosts` must be a list of host names");
+    }
+    for host in ShortLinkSettings::from_value(value).allowed_hosts {
+        if host.contains(['/', ':', '@']) || host.strip_prefix("*.").unwrap_or(&host).contains('*') {
+            report.error(file, format!("'{}' is not a host name, write `example.com` or `*.example.com`", host));
+        }
+    }
+}
+
+fn check_comments(value: &Value, honeypot: &HoneypotSettings, report: &mut Report) {
+    let file = "op/comments.json";
+    if !matches!(value.get("rate"), Value::Dict(_) | Value::None) {
+        report.error(file, "`rate` must be an object like {\"count\": 5, \"seconds\": 300}");
+    }
+    if !matches!(value.get("blocked_words"), Value::List(_) | Value::None) {
+        report.error(file, "`blocked_words` must be a list of strings");
+    }
+    let settings = CommentSettings::from_value(value);
+    if settings.rate_seconds == 0 {
+        report.warn(file, "`rate.seconds` is 0, posting is not rate-limited");
+    }
+    if settings.guests && !honeypot.is_enabled(captcha::COMMENT) {
+        report.warn(file, "guests may post, add \"comment\" to the `routes` of op/honeypot.json to turn away bots");
+    }
+}
+
+fn check_moderation(value: &Value, report: &mut Report) {
+    let file = "op/moderation.json";
+    for key in ["moderators", "reasons", "muted_paths"] {
+        if !matches!(value.get(key), Value::List(_) | Value::None) {
+            report.error(file, format!("`{}` must be a list of strings", key));
+        }
+    }
+    let settings = ModerationSettings::from_value(value);
+    for moderator in &settings.moderators {
+        if UserID::from_str(moderator).is_none_or(|id| id.is_guest()) {
+            report.error(file, format!("moderator '{}' is not a user id like 3@local", moderator));
+        }
+    }
+    if settings.reasons.is_empty() {
+        report.error(file, "`reasons` is empty, no report could be filed");
+    }
+    for path in &settings.muted_paths {
+        if !path.starts_with('/') {
+            report.error(file, format!("muted path '{}' must start with /", path));
+        }
+    }
+}
+
+/// The post files of `blog/`: named by a valid slug, with a title and a known status
+fn check_blog(dir: &Path, report: &mut Report) {
+    let Ok(entries) = fs::read_dir(dir.join("blog")) else { return };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let file = format!("blog/{}", name);
+        let Some(slug) = name.strip_suffix(".md") else {
+            report.warn(&file, "not a .md file, ignored");
+            continue;
+        };
+        if !blog::valid_slug(slug) {
+            report.error(&file, "name it by its slug: lowercase letters, digits and dashes, then .md");
+            continue;
+        }
+        let text = fs::read_to_string(entry.path()).unwrap_or_default();
+        if !text.trim_start_matches('\u{feff}').starts_with("---") {
+            report.warn(&file, "no metadata, the post is published under its slug as title");
+            continue;
+        }
+        let status = text.lines().skip(1).take_while(|line| line.trim() != "---").find_map(|line| {
+            line.split_once(':').filter(|(key, _)| key.trim() == "status").map(|(_, value)| value.trim().to_string())
+        });
+        if let Some(status) = status
+            && PostStatus::from_string(&status).is_none()
+        {
+            report.error(&file, format!("status '{}' must be draft or published; the post stays a draft", status));
+        }
+        if Post::parse(slug, &text).title == slug {
+            report.warn(&file, "no `title`, the slug is shown instead");
+        }
+    }
+}
+
+fn check_forms(dir: &Path, honeypot: &HoneypotSettings, report: &mut Report) {
+    let Ok(entries) = fs::read_dir(dir.join("forms")) else { return };
+    let mut guests = false;
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let file = format!("forms/{}", name);
+        let Some(slug) = name.strip_suffix(".json") else {
+            report.warn(&file, "not a .json file, ignored");
+            continue;
+        };
+        // Reported with the other JSON files
+        let Ok(value) = Value::from_jsonf(entry.path().to_string_lossy()) else { continue };
+        match FormSchema::from_value(slug, &value) {
+            Ok(form) => guests |= form.open && form.guests && !form.once,
+            Err(problem) => report.error(&file, format!("{}; the form is left out", problem)),
+        }
+    }
+    if guests && !honeypot.is_enabled(captcha::FORM) {
+        report.warn("op/honeypot.json", "forms take answers from guests, add \"form\" to `routes` to turn away bots");
+    }
+}
+
+fn check_settings(dir: &Path, report: &mut Report) {
+    let Ok(entries) = fs::read_dir(dir.join("settings")) else { return };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let file = format!("settings/{}", name);
+        let Some(namespace) = name.strip_suffix(".json") else {
+            report.warn(&file, "not a .json file, ignored");
+            continue;
+        };
+        if !settings::valid_namespace(namespace) {
+            report.error(&file, "name it by its namespace: lowercase letters, digits, - and _, then .json");
+            continue;
+        }
+        // Reported with the other JSON files
+        let Ok(value) = Value::from_jsonf(entry.path().to_string_lossy()) else { continue };
+        match settings::from_value(&value) {
+            Some(values) => {
+                for key in values.keys().filter(|key| !settings::valid_key(key)) {
+                    report.error(&file, format!("key '{}' cannot be changed, keys have no control characters or surrounding spaces", key));
+                }
+            }
+            None => report.error(&file, "must be an object of settings by key; the namespace is left out"),
+        }
+    }
+}
+
+fn check_qr(value: &Value, report: &mut Report) {
+    let file = "op/qr.json";
+    if !matches!(value.get("allowed_prefixes"), Value::List(_) | Value::None) {
+        report.error(file, "`allowed_prefixes` must be a list of strings");
+    }
+    for prefix in QrSettings::from_value(value).allowed_prefixes {
+        // A bare scheme, or a host without its trailing slash, lets anyone
+        // encode far more than meant
+        let rest = prefix.split_once("://").map_or("", |(_, rest)| rest);
+        if matches!(prefix.as_str(), "http" | "https" | "http:" | "https:") || (prefix.starts_with("http") && !rest.contains('/')) {
+            report.warn(file, format!("prefix '{}' allows codes for other sites, end it with a path like https://example.com/", prefix));
+        }
+    }
+}
+
+fn check_images(value: &Value, report: &mut Report) {
+    let file = "op/images.json";
+    if let Value::List(formats) = value.get("formats") {
+        for format in formats {
+            if images::format_named(&format.string()).is_none() {
+                report.error(file, format!("unknown format '{}', use jpeg, png, webp or gif", format.string()));
+            }
+        }
+    }
+    if let Value::Dict(sizes) = value.get("sizes") {
+        for (name, size) in sizes {
+            if !images::valid_name(name) || name == images::ORIGINAL {
+                report.error(file, format!("size '{}': names are letters, digits, '-' and '_', and not '{}'", name, images::ORIGINAL));
+            }
+            if size.get("edge").integer() <= 0 {
+                report.error(file, format!("size '{}' needs a positive `edge`", name));
+            }
+        }
+    }
+    let settings = ImageSettings::from_value(value);
+    for (name, size) in &settings.sizes {
+        if !size.crop && size.edge > settings.max_edge {
+            report.warn(file, format!("size '{}' is larger than max_edge, images are never enlarged to it", name));
+        }
+    }
+}
+
+fn check_scan(value: &Value, dir: &Path, report: &mut Report) {
+    let file = "op/scan.json";
+    let settings = ScanSettings::from_value(value);
+    match settings.scanner.as_str() {
+        "none" => {}
+        "clamav" => {
+            let address = &settings.clamav;
+            if let Some(socket) = address.strip_prefix("unix:") {
+                if !Path::new(socket).exists() {
+                    report.warn(file, format!("clamd socket {} does not exist (yet)", socket));
+                }
+            } else if address.rsplit_once(':').is_none_or(|(host, port)| host.is_empty() || port.parse::<u16>().is_err()) {
+                report.error(file, format!("clamav '{}' is neither unix:/path nor host:port", address));
+            }
+        }
+        "command" => {
+            if settings.command.split_whitespace().next().is_none() {
+                report.error(file, "scanner is command but no `command` is set");
+            }
+        }
+        other => report.error(file, format!("unknown scanner '{}', use none, clamav or command", other)),
+    }
+    if !matches!(value.get("on_error").string().as_str(), "" | "reject" | "accept") {
+        report.error(file, "`on_error` must be reject or accept");
+    }
+    for url in &settings.webhooks {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            report.error(file, format!("webhook '{}' is not an http(s) URL", url));
+        }
+    }
+    check_secret(file, "`secret`", &settings.secret, dir, report);
+}
+
+fn check_storage(value: &Value, dir: &Path, report: &mut Report) {
+    let file = "op/storage.json";
+    let settings = StorageSettings::from_value(value);
+    match settings.backend.as_str() {
+        "local" => {}
+        "s3" => {
+            let mut s3 = settings.s3.clone();
+            s3.access_key = check_secret(file, "`access_key`", &s3.access_key, dir, report);
+            s3.secret_key = check_secret(file, "`secret_key`", &s3.secret_key, dir, report);
+            if s3.access_key.is_empty() || s3.secret_key.is_empty() {
+                // Keys that failed to load are reported above
+                if settings.s3.access_key.is_empty() || settings.s3.secret_key.is_empty() {
+                    report.error(file, "S3 storage needs an access_key and a secret_key");
+                }
+            } else if let Err(err) = S3Store::new(s3) {
+                report.error(file, err);
+            }
+            let host = settings.s3.endpoint.strip_prefix("http://").unwrap_or_default();
+            if !host.is_empty() && !host.starts_with("localhost") && !host.starts_with("127.") {
+                report.warn(file, "the S3 endpoint is plain http, keys and files cross the network unencrypted");
+            }
+        }
+        other => report.error(file, format!("unknown backend '{}', use local or s3", other)),
+    }
+}
+
+fn check_users(users: &Value, report: &mut Report) {
+    let file = "local_auth/users";
+    let Value::Dict(map) = users else {
+        report.error(file, "must be an object keyed by uid");
+        return;
+    };
+    let (mut usernames, mut emails) = (HashSet::new(), HashSet::new());
+    for (uid, user) in map {
+        if uid.parse::<u32>().is_err() {
+            report.error(file, format!("key '{}' is not a numeric uid", uid));
+        }
+        for field in ["username", "email", "password_hash", "password_salt"] {
+            if user.get(field).string().is_empty() {
+                report.error(file, format!("user {} has no `{}`", uid, field));
+            }
+        }
+        if !usernames.insert(user.get("username").string()) {
+            report.error(file, format!("username '{}' is used twice", user.get("username").string()));
+        }
+        if !emails.insert(user.get("email").string()) {
+            report.error(file, format!("email '{}' is used twice", user.get("email").string()));
+        }
+    }
+}
+
+/// Copy every file of `defaults` that does not exist under `dir`, with the
+/// placeholders filled in from `vars`. Returns the created paths.
+fn init(defaults: &Dir, dir: &Path, vars: &placeholders::Vars) -> Result<Vec<PathBuf>> {
+    let mut created = Vec::new();
+    for entry in defaults.entries() {
+        match entry {
+            DirEntry::File(file) => {
+                let relative = file.path().strip_prefix("programfiles").unwrap_or(file.path());
+                let target = dir.join(relative);
+                if target.exists() {
+                    continue;
+                }
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let contents = match std::str::from_utf8(file.contents()) {
+                    Ok(text) => placeholders::render(text, vars)?.into_bytes(),
+                    Err(_) => file.contents().to_vec(),
+                };
+                if relative.starts_with(sfx::secrets::DIR) {
+                    sfx::secrets::write_private(&target, contents)?;
+                } else {
+                    fs::write(&target, contents)?;
+                }
+                created.push(target);
+            }
+            DirEntry::Dir(subdir) => created.extend(init(subdir, dir, vars)?),
+        }
+    }
+    Ok(created)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shipped_defaults_pass_the_check() {
+        let dir = std::env::temp_dir().join(format!("sfx-config-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut vars = placeholders::defaults("site");
+        placeholders::finish(&mut vars).unwrap();
+        let created = init(TEMPLATE_DIR.get_dir("programfiles").unwrap(), &dir, &vars).unwrap();
+        assert!(created.iter().any(|p| p.ends_with("op/navbar.json")));
+        assert!(init(TEMPLATE_DIR.get_dir("programfiles").unwrap(), &dir, &vars).unwrap().is_empty());
+
+        let report = check(&dir);
+        assert!(report.issues.iter().all(|i| !i.error), "{:?}", report.issues);
+
+        fs::write(dir.join("admin_info/admins.json"), r#"["1@local", "admin"]"#).unwrap();
+        fs::write(dir.join("op/support_lang.json"), r#"["en", {"code": "fr", "dir": "up"}]"#).unwrap();
+        let errors: Vec<String> = check(&dir)
+            .issues
+            .into_iter()
+            .filter(|i| i.error)
+            .map(|i| format!("{}: {}", i.file, i.message))
+            .collect();
+        assert!(errors.iter().any(|e| e.contains("'admin' is not `uid@host`")));
+        assert!(errors.iter().any(|e| e.starts_with("op/navbar.json") && e.contains("'fr'")));
+        assert!(errors.iter().any(|e| e.contains("`dir` of 'fr' must be rtl or ltr")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotated_session_keys_are_written_back() {
+        let shipped = SessionSettings::from_value(&Value::from_json(
+            r#"{ "keys": [{ "id": "1", "secret": "0123456789abcdef0123456789abcdef" }] }"#,
+        ).unwrap());
+        let rotated = rotate_session_key(shipped.clone(), "2", "env:SFX_TEST_KEY", false);
+        let reread = SessionSettings::from_value(&Value::from_json(&session_json(&rotated)).unwrap());
+        let ids: Vec<&str> = reread.keys.iter().map(|key| key.id.as_str()).collect();
+        assert_eq!(ids, vec!["2", "1"]);
+        assert_eq!(reread.keys.first(), rotated.keys.first());
+
+        let retired = rotate_session_key(shipped, "2", "fedcba9876543210fedcba9876543210", true);
+        assert_eq!((retired.keys.len(), retired.retired.clone()), (1, vec!["1".to_string()]));
+
+        let dir = std::env::temp_dir();
+        let mut report = Report::default();
+        check_session(&Value::from_json(&session_json(&retired)).unwrap(), &dir, &mut report);
+        assert!(report.issues.iter().all(|i| !i.error), "{:?}", report.issues);
+        assert!(report.issues.iter().any(|i| i.message.contains("written in the file")));
+        check_session(&Value::from_json(r#"{ "keys": [{ "id": "x", "secret": "short" }] }"#).unwrap(), &dir, &mut report);
+        assert!(report.issues.iter().any(|i| i.error && i.message.contains("'x'")));
+        check_session(&Value::from_json(&session_json(&rotated)).unwrap(), &dir, &mut report);
+        assert!(report.issues.iter().any(|i| i.error && i.message.contains("SFX_TEST_KEY")));
+    }
+}
@@ -0,0 +1,209 @@
+//! Line diffs for previewing generated files against existing ones, and the
+//! three-way merge `sfx upgrade` applies template changes with.
+//!
+//! A plain longest-common-subsequence table, which is plenty for project
+//! files of a few thousand lines. Bigger inputs are shown as a full
+//! replacement instead of spending quadratic memory on them.
+
+/// Above this many line pairs the diff degrades to remove-all/add-all
+const MAX_CELLS: usize = 16_000_000;
+
+/// Lines of context around each change in a unified diff
+const CONTEXT: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op<'a> {
+    Keep(&'a str),
+    Remove(&'a str),
+    Add(&'a str),
+}
+
+/// The edit script turning `old` into `new`
+pub fn lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<Op<'a>> {
+    let (n, m) = (old.len(), new.len());
+    if n.saturating_mul(m) > MAX_CELLS {
+        return old.iter().map(|l| Op::Remove(l)).chain(new.iter().map(|l| Op::Add(l))).collect();
+    }
+    // lcs[i][j]: length of the common subsequence of old[i..] and new[j..]
+    let mut lcs = vec![0u32; (n + 1) * (m + 1)];
+    let at = |i: usize, j: usize| i * (m + 1) + j;
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[at(i, j)] = if old[i] == new[j] {
+                lcs[at(i + 1, j + 1)] + 1
+            } else {
+                lcs[at(i + 1, j)].max(lcs[at(i, j + 1)])
+            };
+        }
+    }
+    let mut ops = Vec::with_capacity(n.max(m));
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && old[i] == new[j] {
+            ops.push(Op::Keep(old[i]));
+            i += 1;
+            j += 1;
+        } else if i < n && (j == m || lcs[at(i + 1, j)] >= lcs[at(i, j + 1)]) {
+            ops.push(Op::Remove(old[i]));
+            i += 1;
+        } else {
+            ops.push(Op::Add(new[j]));
+            j += 1;
+        }
+    }
+    ops
+}
+
+/// A unified diff of `old` and `new`, empty when they are equal
+pub fn unified(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let ops = lines(&old_lines, &new_lines);
+    if ops.iter().all(|op| matches!(op, Op::Keep(_))) {
+        return String::new();
+    }
+
+    let mut out = format!("--- {}\n+++ {}\n", old_name, new_name);
+    let changed: Vec<usize> = (0..ops.len()).filter(|&k| !matches!(ops[k], Op::Keep(_))).collect();
+    let mut k = 0;
+    while k < changed.len() {
+        // Grow the hunk while the next change is within reach of its context
+        let start = changed[k].saturating_sub(CONTEXT);
+        let mut last = changed[k];
+        while k + 1 < changed.len() && changed[k + 1] <= last + 2 * CONTEXT + 1 {
+            k += 1;
+            last = changed[k];
+        }
+        let end = (last + CONTEXT + 1).min(ops.len());
+        k += 1;
+
+        let (old_start, new_start) = ops[..start].iter().fold((0, 0), |(o, n), op| match op {
+            Op::Keep(_) => (o + 1, n + 1),
+            Op::Remove(_) => (o + 1, n),
+            Op::Add(_) => (o, n + 1),
+        });
+        let hunk = &ops[start..end];
+        let old_len = hunk.iter().filter(|op| !matches!(op, Op::Add(_))).count();
+        let new_len = hunk.iter().filter(|op| !matches!(op, Op::Remove(_))).count();
+        out.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            old_start + usize::from(old_len > 0),
+            old_len,
+            new_start + usize::from(new_len > 0),
+            new_len
+        ));
+        for op in hunk {
+            let (sign, line) = match op {
+                Op::Keep(line) => (' ', line),
+                Op::Remove(line) => ('-', line),
+                Op::Add(line) => ('+', line),
+            };
+            out.push(sign);
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// The result of a three-way merge
+pub struct Merge {
+    /// The merged text, with `<<<<<<<`/`=======`/`>>>>>>>` around conflicts
+    pub text: String,
+    pub conflicts: usize,
+}
+
+/// For each line of `base`, the index of the same line in `other` when the
+/// diff keeps it
+fn kept(base: &[&str], other: &[&str]) -> Vec<Option<usize>> {
+    let mut matched = vec![None; base.len()];
+    let (mut i, mut j) = (0, 0);
+    for op in lines(base, other) {
+        match op {
+            Op::Keep(_) => {
+                matched[i] = Some(j);
+                i += 1;
+                j += 1;
+            }
+            Op::Remove(_) => i += 1,
+            Op::Add(_) => j += 1,
+        }
+    }
+    matched
+}
+
+/// Merge the changes from `base` to `ours` and from `base` to `theirs`.
+/// Where both sides changed the same lines differently, both versions are
+/// kept between conflict markers.
+pub fn merge3(base: &str, ours: &str, theirs: &str) -> Merge {
+    let base: Vec<&str> = base.split_inclusive('\n').collect();
+    let ours: Vec<&str> = ours.split_inclusive('\n').collect();
+    let theirs: Vec<&str> = theirs.split_inclusive('\n').collect();
+    let in_ours = kept(&base, &ours);
+    let in_theirs = kept(&base, &theirs);
+
+    let mut merge = Merge { text: String::new(), conflicts: 0 };
+    let (mut i, mut j, mut k) = (0, 0, 0);
+    loop {
+        // The next base line both sides kept closes the current chunk
+        let stable = (i..base.len()).find_map(|b| Some((b, in_ours[b]?, in_theirs[b]?)));
+        let (b_end, o_end, t_end) = stable.unwrap_or((base.len(), ours.len(), theirs.len()));
+        let (b, o, t) = (&base[i..b_end], &ours[j..o_end], &theirs[k..t_end]);
+        let chunk = if o == b || o == t {
+            t
+        } else if t == b {
+            o
+        } else {
+            merge.conflicts += 1;
+            let mut conflict = vec!["<<<<<<< yours\n"];
+            conflict.extend(o);
+            conflict.push("=======\n");
+            conflict.extend(t);
+            conflict.push(">>>>>>> template\n");
+            for line in conflict {
+                if !merge.text.is_empty() && !merge.text.ends_with('\n') {
+                    merge.text.push('\n');
+                }
+                merge.text.push_str(line);
+            }
+            &[]
+        };
+        merge.text.extend(chunk.iter().copied());
+        let Some((b, o, t)) = stable else { break };
+        merge.text.push_str(ours[o]);
+        (i, j, k) = (b + 1, o + 1, t + 1);
+    }
+    merge
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unified_diff_has_hunks_with_context() {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\n";
+        let new = "a\nB\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\nm\n";
+        assert_eq!(
+            unified(old, new, "old", "new"),
+            "--- old\n+++ new\n@@ -1,5 +1,5 @@\n a\n-b\n+B\n c\n d\n e\n@@ -10,3 +10,4 @@\n j\n k\n l\n+m\n"
+        );
+        assert_eq!(unified(old, old, "old", "new"), "");
+        assert_eq!(unified("", "x\n", "old", "new"), "--- old\n+++ new\n@@ -0,0 +1,1 @@\n+x\n");
+    }
+
+    #[test]
+    fn merge3_combines_independent_changes_and_flags_overlaps() {
+        let base = "[package]\nname = \"app\"\nversion = \"0.1.0\"\nauthors = []\nedition = \"2021\"\n\n[dependencies]\nsfx = \"0.1\"\n";
+        let ours = base.replace("0.1.0", "0.2.0") + "serde = \"1\"\n";
+        let theirs = base.replace("2021", "2024").replace("sfx = \"0.1\"", "sfx = \"0.2\"");
+        let merged = merge3(base, &ours, &theirs);
+        assert_eq!(merged.conflicts, 1);
+        assert!(merged.text.starts_with("[package]\nname = \"app\"\nversion = \"0.2.0\"\nauthors = []\nedition = \"2024\"\n\n"));
+        assert!(merged.text.ends_with("<<<<<<< yours\nsfx = \"0.1\"\nserde = \"1\"\n=======\nsfx = \"0.2\"\n>>>>>>> template\n"));
+
+        let clean = merge3("a\nb\nc\n", "A\nb\nc\n", "a\nb\nC\n");
+        assert_eq!((clean.text.as_str(), clean.conflicts), ("A\nb\nC\n", 0));
+        assert_eq!(merge3("a\n", "b\n", "b\n").text, "b\n");
+    }
+}
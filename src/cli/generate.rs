@@ -0,0 +1,246 @@
+//! `sfx generate`: code generators for generated projects.
+//!
+//! `sfx generate route <name>` adds a page: `src/<name>.rs` with an
+//! endpoint rendering `templates/<name>.html`, a `pub mod <name>;` line in
+//! `src/lib.rs` (the endpoint registers itself on `APP` once the module is
+//! compiled in) and a `<name>` entry in `programfiles/op/l10n.json` used as
+//! the page title and breadcrumb.
+
+use anyhow::{Context, Result};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use sfx::prelude::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub fn command() -> Command {
+    Command::new("generate")
+        .about("Generate code in an sfx project")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("route")
+                .about("Add a page: endpoint module, template and l10n key")
+                .arg(
+                    Arg::new("name")
+                        .required(true)
+                        .index(1)
+                        .help("Module name, in snake_case (e.g. about_us)"),
+                )
+                .arg(
+                    Arg::new("path")
+                        .long("path")
+                        .value_name("URL")
+                        .help("URL of the page (default: /<name>)"),
+                )
+                .arg(
+                    Arg::new("dir")
+                        .long("dir")
+                        .value_name("DIR")
+                        .default_value(".")
+                        .help("Project directory"),
+                )
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .short('f')
+                        .action(ArgAction::SetTrue)
+                        .help("Overwrite the module and template if they exist"),
+                ),
+        )
+}
+
+pub fn run(matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        Some(("route", sub)) => route(
+            sub.get_one::<String>("name").expect("required argument"),
+            sub.get_one::<String>("path").map(String::as_str),
+            Path::new(sub.get_one::<String>("dir").expect("has default")),
+            sub.get_flag("force"),
+        ),
+        _ => unreachable!(),
+    }
+}
+
+fn is_module_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_lowercase())
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+/// `about_us` -> `About Us`, the placeholder title of every language
+fn display_name(name: &str) -> String {
+    name.split('_')
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut chars = word.chars();
+            chars.next().map(|c| c.to_ascii_uppercase().to_string() + chars.as_str()).unwrap_or_default()
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn route(name: &str, url: Option<&str>, project: &Path, force: bool) -> Result<()> {
+    if !is_module_name(name) {
+        anyhow::bail!("'{}' is not a valid module name, use snake_case like about_us", name);
+    }
+    let url = url.map(str::to_string).unwrap_or_else(|| format!("/{}", name));
+    if !url.starts_with('/') {
+        anyhow::bail!("The URL must start with '/', got '{}'", url);
+    }
+    let lib_path = project.join("src/lib.rs");
+    let lib = fs::read_to_string(&lib_path)
+        .with_context(|| format!("Cannot read {}. Run this in a project created by `sfx new`.", lib_path.display()))?;
+
+    let module_path = project.join("src").join(format!("{}.rs", name));
+    let template_path = project.join("templates").join(format!("{}.html", name));
+    for path in [&module_path, &template_path] {
+        if path.exists() && !force {
+            anyhow::bail!("{} already exists, use --force to overwrite it", path.display());
+        }
+    }
+
+    let mut written: Vec<PathBuf> = Vec::new();
+    fs::write(&module_path, module_source(name, &url))?;
+    written.push(module_path);
+    if let Some(parent) = template_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&template_path, template_source(name))?;
+    written.push(template_path);
+    if let Some(lib) = add_mod_line(&lib, name) {
+        fs::write(&lib_path, lib)?;
+        written.push(lib_path);
+    }
+
+    let l10n_path = project.join("programfiles/op/l10n.json");
+    let langs = fs::read_to_string(project.join("programfiles/op/support_lang.json"))
+        .ok()
+        .and_then(|json| Value::from_json(&json).ok())
+        .map(|langs| sfx::op::Language::list(&langs).into_iter().map(|language| language.code).collect::<Vec<_>>())
+        .unwrap_or_else(|| vec!["en".to_string()]);
+    let l10n = fs::read_to_string(&l10n_path).unwrap_or_else(|_| "{}\n".to_string());
+    if let Some(l10n) = add_l10n_key(&l10n, name, &langs, &display_name(name))? {
+        fs::write(&l10n_path, l10n)?;
+        written.push(l10n_path);
+    }
+
+    for path in &written {
+        println!("wrote {}", path.display());
+    }
+    println!("\nGET {} renders templates/{}.html. Translate the \"{}\" key in l10n.json.", url, name, name);
+    Ok(())
+}
+
+fn module_source(name: &str, url: &str) -> String {
+    format!(
+        r#"use sfx::prelude::*;
+use sfx::op;
+
+use crate::APP;
+
+endpoint! {{
+    APP.url("{url}"),
+
+    pub {name}_page <HTTP> {{
+        let title = op::get_localized_string("{name}", &op::lang(req));
+        akari_render!(
+            "{name}.html",
+            pageprop = op::pageprop(req, &title, ""),
+            path = op::into_path_l(req, vec!["home", "{name}"])
+        )
+    }}
+}}
+"#
+    )
+}
+
+fn template_source(name: &str) -> String {
+    format!(
+        r#"-[ template "/base/base.html" ]-
+
+-[ block body ]-
+
+-[ insert "/base/path.html" ]-
+
+<div class="container-func">
+
+    <h1>-[ pageprop.title ]-</h1>
+
+    <p>Edit templates/{name}.html to fill this page.</p>
+
+</div>
+
+-[ endblock ]-
+"#
+    )
+}
+
+/// `lib` with a `pub mod <name>;` line after its last `mod` line (or its
+/// `use` lines), or `None` if the module is already declared
+fn add_mod_line(lib: &str, name: &str) -> Option<String> {
+    let declared = |line: &str| {
+        let line = line.trim();
+        line == format!("mod {};", name) || line == format!("pub mod {};", name)
+    };
+    if lib.lines().any(declared) {
+        return None;
+    }
+    let lines: Vec<&str> = lib.lines().collect();
+    let is_mod = |line: &&str| {
+        let line = line.trim_start();
+        (line.starts_with("mod ") || line.starts_with("pub mod ")) && line.ends_with(';')
+    };
+    let after = lines
+        .iter()
+        .rposition(is_mod)
+        .or_else(|| lines.iter().rposition(|line| line.starts_with("use ") || line.starts_with("pub use ")))
+        .map_or(0, |i| i + 1);
+    let mut out: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+    out.insert(after, format!("pub mod {};", name));
+    Some(out.join("\n") + "\n")
+}
+
+/// `l10n` with a `name` entry holding `display` for every language, added
+/// right after the opening brace so the rest of the file keeps its layout.
+/// `None` if the key exists.
+fn add_l10n_key(l10n: &str, name: &str, langs: &[String], display: &str) -> Result<Option<String>> {
+    let parsed = Value::from_json(l10n).map_err(|err| anyhow::anyhow!("l10n.json is not valid JSON: {}", err))?;
+    if !parsed.get(name).is_none() {
+        return Ok(None);
+    }
+    let open = l10n.find('{').context("l10n.json is not a JSON object")?;
+    let empty = l10n[open + 1..].trim_start().starts_with('}');
+    let translations: Vec<String> = langs
+        .iter()
+        .map(|lang| format!("        \"{}\": \"{}\"", lang, display))
+        .collect();
+    let entry = format!(
+        "\n    \"{}\": {{\n{}\n    }}{}",
+        name,
+        translations.join(",\n"),
+        if empty { "\n" } else { "," }
+    );
+    Ok(Some(format!("{}{}{}", &l10n[..=open], entry, &l10n[open + 1..])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registers_module_and_l10n_key_once() {
+        let lib = "use sfx::prelude::*;\npub use sfx::APP;\npub use sfx::op;\n\nendpoint! {}\n";
+        let added = add_mod_line(lib, "about_us").unwrap();
+        assert!(added.starts_with("use sfx::prelude::*;\npub use sfx::APP;\npub use sfx::op;\npub mod about_us;\n\n"));
+        assert!(add_mod_line(&added, "about_us").is_none());
+        assert!(add_mod_line(&added, "blog").unwrap().contains("pub mod about_us;\npub mod blog;\n"));
+
+        let langs = vec!["en".to_string(), "ja".to_string()];
+        let l10n = "{\n    \"home\": { \"en\": \"Home\" }\n}\n";
+        let added = add_l10n_key(l10n, "about_us", &langs, &display_name("about_us")).unwrap().unwrap();
+        let value = Value::from_json(&added).unwrap();
+        assert_eq!(value.get("about_us").get("ja").string(), "About Us");
+        assert_eq!(value.get("home").get("en").string(), "Home");
+        assert!(add_l10n_key(&added, "about_us", &langs, "x").unwrap().is_none());
+        assert!(Value::from_json(&add_l10n_key("{}", "a", &langs, "A").unwrap().unwrap()).is_ok());
+    }
+}
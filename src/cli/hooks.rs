@@ -0,0 +1,116 @@
+//! What `sfx new`/`sfx init` do after the files are written: pin the sfx
+//! dependency to this CLI, `git init` with a first commit and `cargo check`.
+
+use anyhow::{Context, Result};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Crates whose dependency line is pinned to the version of this CLI. The
+/// templates are written against this exact sfx, so a newer one pulled in
+/// by a caret requirement can break a project that was never touched.
+const PINNED: &[&str] = &["sfx"];
+
+/// Rewrite the requirement of every `PINNED` dependency in a Cargo.toml to
+/// `=<version>`. Handles `name = "x"` and `name = { version = "x", ... }`.
+pub fn pin_dependencies(manifest: &str, version: &str) -> String {
+    let mut section = String::new();
+    let mut out = String::with_capacity(manifest.len());
+    for line in manifest.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            section = trimmed.trim_matches(['[', ']']).trim().to_string();
+        }
+        let pinned = (section == "dependencies" || section.ends_with(".dependencies"))
+            .then(|| pin_line(line, version))
+            .flatten();
+        out.push_str(pinned.as_deref().unwrap_or(line));
+    }
+    out
+}
+
+fn pin_line(line: &str, version: &str) -> Option<String> {
+    let (key, value) = line.split_once('=')?;
+    if !PINNED.contains(&key.trim()) {
+        return None;
+    }
+    let requirement = format!("\"={}\"", version);
+    let value = value.trim_start();
+    let rewritten = if let Some(quoted) = value.strip_prefix('"') {
+        let end = quoted.find('"')? + 1;
+        format!("{}{}", requirement, &quoted[end..])
+    } else if value.starts_with('{') {
+        let start = value.find("version")?;
+        let open = start + value[start..].find('"')?;
+        let close = open + 1 + value[open + 1..].find('"')?;
+        format!("{}{}{}", &value[..open], requirement, &value[close + 1..])
+    } else {
+        return None;
+    };
+    Some(format!("{}= {}", key, rewritten))
+}
+
+/// Whether `dir` is already inside a git work tree
+fn in_work_tree(dir: &Path) -> bool {
+    Command::new("git")
+        .args(["rev-parse", "--is-inside-work-tree"])
+        .current_dir(dir)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// Turn `dir` into a git repository and commit everything in it. Skipped
+/// with a note when it already belongs to one.
+pub fn git_init(dir: &Path) -> Result<()> {
+    if in_work_tree(dir) {
+        println!("{} is already in a git repository, not running git init", dir.display());
+        return Ok(());
+    }
+    let git = |args: &[&str]| -> Result<()> {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .context("Failed to run git, is it installed?")?;
+        if !output.status.success() {
+            anyhow::bail!("git {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim());
+        }
+        Ok(())
+    };
+    git(&["init", "--quiet"])?;
+    git(&["add", "--all"])?;
+    git(&["commit", "--quiet", "-m", "Initial commit from sfx"])
+        .context("The repository was created but the first commit failed")?;
+    println!("Initialized a git repository with a first commit");
+    Ok(())
+}
+
+/// Run `cargo check` in `dir`, with its output going to the terminal
+pub fn cargo_check(dir: &Path) -> Result<()> {
+    println!("Running cargo check...");
+    let status = Command::new("cargo")
+        .arg("check")
+        .current_dir(dir)
+        .status()
+        .context("Failed to run cargo")?;
+    if !status.success() {
+        anyhow::bail!("cargo check failed in {}", dir.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pins_sfx_in_dependency_sections_only() {
+        let manifest = "[package]\nname = \"sfx\"\nversion = \"0.1.0\"\n\n[dependencies]\nsfx = \"0.1.2\" # web\ntokio = \"1\"\n\n[target.'cfg(unix)'.dependencies]\nsfx = { version = \"0.1\", features = [\"https\"] }\n";
+        assert_eq!(
+            pin_dependencies(manifest, "0.1.3"),
+            "[package]\nname = \"sfx\"\nversion = \"0.1.0\"\n\n[dependencies]\nsfx = \"=0.1.3\" # web\ntokio = \"1\"\n\n[target.'cfg(unix)'.dependencies]\nsfx = { version = \"=0.1.3\", features = [\"https\"] }\n"
+        );
+        assert_eq!(pin_dependencies("[dependencies]\nsfx = { path = \"../sfx\" }\n", "0.1.3"), "[dependencies]\nsfx = { path = \"../sfx\" }\n");
+    }
+}
@@ -0,0 +1,94 @@
+//! `sfx migrate`: apply the schema migrations shipped with sfx to the
+//! database of `programfiles/op/database.json` (see `sfx::database`).
+//!
+//! Sites keeping their accounts in the JSON store have nothing to migrate;
+//! the commands say so and succeed, so deployment scripts can run
+//! `sfx migrate up` unconditionally.
+
+use anyhow::Result;
+use clap::{Arg, ArgMatches, Command, value_parser};
+use std::path::PathBuf;
+
+use sfx::database::{self, Backend};
+
+pub fn command() -> Command {
+    Command::new("migrate")
+        .about("Apply or revert database schema migrations")
+        .subcommand_required(true)
+        .arg(
+            Arg::new("programfiles")
+                .long("programfiles")
+                .value_name("DIR")
+                .global(true)
+                .help("Configuration directory (default: ./programfiles)"),
+        )
+        .subcommand(Command::new("status").about("List the migrations and whether they are applied"))
+        .subcommand(
+            Command::new("up").about("Apply pending migrations").arg(
+                Arg::new("to")
+                    .long("to")
+                    .value_name("VERSION")
+                    .value_parser(value_parser!(u32))
+                    .help("Stop after this version (default: apply all)"),
+            ),
+        )
+        .subcommand(
+            Command::new("down").about("Revert the latest migrations").arg(
+                Arg::new("steps")
+                    .long("steps")
+                    .value_name("N")
+                    .value_parser(value_parser!(usize))
+                    .default_value("1")
+                    .help("Number of migrations to revert"),
+            ),
+        )
+}
+
+pub fn run(matches: &ArgMatches) -> Result<()> {
+    let programfiles = match matches.get_one::<String>("programfiles") {
+        Some(dir) => PathBuf::from(dir),
+        None => sfx::op::programfiles(),
+    };
+    let backend = Backend::load(&programfiles).map_err(anyhow::Error::msg)?;
+    if backend == Backend::Json {
+        println!("Accounts are kept in the JSON store, there is no database to migrate.");
+        println!("Set a backend in {} to use one.", programfiles.join("op/database.json").display());
+        return Ok(());
+    }
+
+    match matches.subcommand() {
+        Some(("status", _)) => {
+            let applied = database::applied(&backend).map_err(anyhow::Error::msg)?;
+            println!("{}", backend);
+            let known = database::migrations();
+            for migration in &known {
+                let state = if applied.contains(&migration.version) { "applied" } else { "pending" };
+                println!("  {:04} {:<24} {}", migration.version, migration.name, state);
+            }
+            for version in applied.iter().filter(|v| !known.iter().any(|m| m.version == **v)) {
+                println!("  {:04} {:<24} applied, unknown to this sfx version", version, "?");
+            }
+        }
+        Some(("up", sub)) => {
+            let ran = database::up(&backend, sub.get_one::<u32>("to").copied()).map_err(anyhow::Error::msg)?;
+            if ran.is_empty() {
+                println!("{} is up to date.", backend);
+            }
+            for migration in ran {
+                println!("applied  {:04} {}", migration.version, migration.name);
+            }
+        }
+        Some(("down", sub)) => {
+            let steps = *sub.get_one::<usize>("steps").expect("has default");
+            let ran = database::down(&backend, steps).map_err(anyhow::Error::msg)?;
+            if ran.is_empty() {
+                println!("No migration is applied to {}.", backend);
+            }
+            for migration in ran {
+                println!("reverted {:04} {}", migration.version, migration.name);
+            }
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
@@ -0,0 +1,348 @@
+//! Placeholder substitution for generated projects.
+//!
+//! Template files may use:
+//!
+//! - `{{name}}` for a variable (`{{ name }}` works too). Names that are not
+//!   variables are left alone, so akari's own `{{ pageprop.path }}` survives.
+//! - `{{#if name}}...{{else}}...{{/if}}`, kept when the variable is set and is
+//!   not `false`, `no` or `0`. `{{#if name == "value"}}` and `!=` compare it.
+//! - `{{#unless name}}...{{/unless}}`, the negation.
+//!
+//! The same conditions decide whether a whole file is generated, see the
+//! `when` of `sfx-template.json` in `super::templates`.
+//!
+//! A block tag alone on its line takes the line with it. File and directory
+//! names are substituted the same way, so `src/{{crate_name}}.rs` works.
+
+use anyhow::Result;
+use clap::ArgMatches;
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+/// Variables asked for by `--interactive`, with their prompt
+pub const PROMPTED: &[(&str, &str)] = &[
+    ("author", "Author"),
+    ("version", "Crate version"),
+    ("port", "Port to listen on"),
+    ("default_lang", "Default language"),
+    ("license", "License"),
+];
+
+/// Subsystems picked while scaffolding interactively, with their question.
+/// Each is a variable holding `true` or `false`, used by `modules.json` and
+/// by the `when` conditions of template files.
+pub const FEATURES: &[(&str, &str)] = &[
+    ("local_auth", "Local accounts (login, registration, `sfx user`)"),
+    ("admin", "Admin panel under /admin/"),
+    ("blog", "Blog under /blog/"),
+    ("forms", "Data-collection forms under /forms/"),
+    ("graphql", "GraphQL API under /graphql"),
+];
+
+/// Generated variables holding secrets, never written to `.sfx/manifest.json`
+pub const SECRETS: &[&str] = &["session_secret", "media_secret"];
+
+/// Languages the built-in templates ship navbar, footer and l10n entries for
+const SHIPPED_LANGS: &[&str] = &["en", "zh", "ja"];
+
+pub type Vars = BTreeMap<String, String>;
+
+/// The variables of a project before any `--var` or prompt
+pub fn defaults(project_name: &str) -> Vars {
+    let author = std::process::Command::new("git")
+        .args(["config", "user.name"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .or_else(|| std::env::var("USER").ok())
+        .unwrap_or_default();
+    let mut vars = Vars::new();
+    for (key, value) in [
+        ("project_name", project_name.to_string()),
+        ("crate_name", project_name.replace('-', "_")),
+        ("author", author),
+        ("version", "0.1.0".to_string()),
+        ("port", "3003".to_string()),
+        ("default_lang", "en".to_string()),
+        ("license", "MIT".to_string()),
+        ("local_auth", "true".to_string()),
+        ("admin", "true".to_string()),
+        ("blog", "true".to_string()),
+        ("forms", "true".to_string()),
+        ("graphql", "false".to_string()),
+    ] {
+        vars.insert(key.to_string(), value);
+    }
+    vars
+}
+
+/// Parse a `--var key=value` argument
+pub fn parse_var(arg: &str) -> Result<(String, String), String> {
+    let (key, value) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected key=value, got '{}'", arg))?;
+    let key = key.trim();
+    if !is_name(key) {
+        return Err(format!("'{}' is not a valid variable name", key));
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// The variables for `project_name`: the defaults, then `--var`, then, when
+/// `interactive`, the answers to the feature questions and the prompts
+pub fn collect(matches: &ArgMatches, project_name: &str, interactive: bool) -> Result<Vars> {
+    let mut vars = defaults(project_name);
+    let given: Vec<(String, String)> = matches
+        .get_many::<(String, String)>("var")
+        .map(|values| values.cloned().collect())
+        .unwrap_or_default();
+    for (key, value) in &given {
+        vars.insert(key.clone(), value.clone());
+    }
+    if interactive {
+        let asked = |key: &str| !given.iter().any(|(k, _)| k == key);
+        for (key, question) in FEATURES.iter().filter(|(key, _)| asked(key)) {
+            let default = truthy(vars.get(*key));
+            let hint = if default { "Y/n" } else { "y/N" };
+            let Some(answer) = ask(&format!("{}? [{}]", question, hint))? else { break };
+            let enabled = match answer.to_ascii_lowercase().as_str() {
+                "" => default,
+                answer => answer.starts_with('y'),
+            };
+            vars.insert(key.to_string(), enabled.to_string());
+        }
+        for (key, prompt) in PROMPTED.iter().filter(|(key, _)| asked(key)) {
+            let Some(answer) = ask(&format!("{} [{}]", prompt, vars[*key]))? else { break };
+            if !answer.is_empty() {
+                vars.insert(key.to_string(), answer);
+            }
+        }
+    }
+    finish(&mut vars)?;
+    Ok(vars)
+}
+
+/// Print `prompt` and read one answer line. `None` at the end of the input.
+fn ask(prompt: &str) -> Result<Option<String>> {
+    print!("{}: ", prompt);
+    std::io::stdout().flush()?;
+    let mut line = String::new();
+    if std::io::stdin().lock().read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(line.trim().to_string()))
+}
+
+/// Check the variables and fill in the ones derived from them
+pub fn finish(vars: &mut Vars) -> Result<()> {
+    for (key, _) in FEATURES {
+        let enabled = truthy(vars.get(*key));
+        vars.insert(key.to_string(), enabled.to_string());
+    }
+    if let Some(port) = vars.get("port")
+        && !matches!(port.parse::<u16>(), Ok(port) if port > 0)
+    {
+        anyhow::bail!("port must be a number between 1 and 65535, got '{}'", port);
+    }
+    let lang = vars.get("default_lang").cloned().unwrap_or_default();
+    if lang.is_empty() {
+        anyhow::bail!("default_lang must not be empty");
+    }
+    if !SHIPPED_LANGS.contains(&lang.as_str()) {
+        println!(
+            "Note: add '{}' entries to programfiles/op/navbar.json, footer.json and l10n.json",
+            lang
+        );
+    }
+    let langs: Vec<String> = std::iter::once(lang.as_str())
+        .chain(SHIPPED_LANGS.iter().copied().filter(|l| *l != lang))
+        .map(|l| format!("\"{}\"", l))
+        .collect();
+    vars.insert("support_lang".to_string(), format!("[{}]", langs.join(", ")));
+    // Every project gets its own key for `session.json`
+    vars.entry("session_secret".to_string())
+        .or_insert_with(|| hotaru_lib::random::random_alphanumeric_string(64));
+    // and for signing the temporary URLs of `media.json`
+    vars.entry("media_secret".to_string())
+        .or_insert_with(|| hotaru_lib::random::random_alphanumeric_string(64));
+    Ok(())
+}
+
+fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn truthy(value: Option<&String>) -> bool {
+    value.is_some_and(|v| !v.is_empty() && !matches!(v.as_str(), "false" | "no" | "0"))
+}
+
+/// Evaluate the condition of an `#if`/`#unless` tag
+pub fn condition(expr: &str, vars: &Vars) -> bool {
+    for (op, equal) in [("==", true), ("!=", false)] {
+        if let Some((name, value)) = expr.split_once(op) {
+            let value = value.trim().trim_matches('"');
+            let actual = vars.get(name.trim()).map(String::as_str).unwrap_or("");
+            return (actual == value) == equal;
+        }
+    }
+    truthy(vars.get(expr.trim()))
+}
+
+struct Block {
+    tag: &'static str,
+    /// Whether the enclosing text is kept
+    outer: bool,
+    condition: bool,
+    in_else: bool,
+}
+
+impl Block {
+    fn active(&self) -> bool {
+        self.outer && self.condition != self.in_else
+    }
+}
+
+/// Substitute the variables and resolve the conditional blocks of `content`
+pub fn render(content: &str, vars: &Vars) -> Result<String> {
+    let mut out = String::with_capacity(content.len());
+    let mut blocks: Vec<Block> = Vec::new();
+    let active = |blocks: &[Block]| blocks.last().is_none_or(Block::active);
+    let mut rest = content;
+    let mut offset = 0;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start..].find("}}") else { break };
+        let tag = rest[start + 2..start + len].trim();
+        let end = start + len + 2;
+        let is_block = tag.starts_with("#if ")
+            || tag.starts_with("#unless ")
+            || matches!(tag, "else" | "/if" | "/unless");
+        if active(&blocks) {
+            out.push_str(&rest[..start]);
+        }
+        if !is_block {
+            // Anything else between braces is text; only a known variable
+            // name is replaced, and scanning resumes right after the `{{`
+            let (text, next) = match vars.get(tag).filter(|_| is_name(tag)) {
+                Some(value) => (value.as_str(), end),
+                None => ("{{", start + 2),
+            };
+            if active(&blocks) {
+                out.push_str(text);
+            }
+            offset += next;
+            rest = &rest[next..];
+            continue;
+        }
+
+        // A block tag alone on its line removes the whole line
+        let absolute = offset + start;
+        let line_start = content[..absolute].rfind('\n').map_or(0, |i| i + 1);
+        let before = &content[line_start..absolute];
+        let after = rest[end..].split('\n').next().unwrap_or("");
+        let standalone = before.trim().is_empty() && after.trim().is_empty();
+        if standalone && active(&blocks) {
+            out.truncate(out.len() - before.len());
+        }
+        let consumed = if standalone {
+            end + after.len() + usize::from(rest[end + after.len()..].starts_with('\n'))
+        } else {
+            end
+        };
+
+        let line = content[..absolute].matches('\n').count() + 1;
+        if let Some(expr) = tag.strip_prefix("#if ") {
+            let outer = active(&blocks);
+            blocks.push(Block { tag: "if", outer, condition: condition(expr, vars), in_else: false });
+        } else if let Some(expr) = tag.strip_prefix("#unless ") {
+            let outer = active(&blocks);
+            blocks.push(Block { tag: "unless", outer, condition: !condition(expr, vars), in_else: false });
+        } else if tag == "else" {
+            match blocks.last_mut() {
+                Some(block) if !block.in_else => block.in_else = true,
+                _ => anyhow::bail!("line {}: {{{{else}}}} outside of a block", line),
+            }
+        } else {
+            match blocks.pop() {
+                Some(block) if block.tag == &tag[1..] => {}
+                _ => anyhow::bail!("line {}: unexpected {{{{{}}}}}", line, tag),
+            }
+        }
+        offset += consumed;
+        rest = &rest[consumed..];
+    }
+    if let Some(block) = blocks.last() {
+        anyhow::bail!("unclosed {{{{#{}}}}} block", block.tag);
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Substitute the variables in every component of `path`
+pub fn render_path(path: &Path, vars: &Vars) -> Result<PathBuf> {
+    let mut rendered = PathBuf::new();
+    for component in path.iter() {
+        match component.to_str() {
+            Some(name) if name.contains("{{") => rendered.push(render(name, vars)?),
+            _ => rendered.push(component),
+        }
+    }
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> Vars {
+        let mut vars = defaults("my-app");
+        vars.insert("author".to_string(), "Jo".to_string());
+        vars.insert("metrics".to_string(), "false".to_string());
+        vars
+    }
+
+    #[test]
+    fn substitutes_variables_and_keeps_unknown_tags() {
+        let rendered = render("{{crate_name}} by {{ author }}: {{ pageprop.path }} {{nope}}", &vars()).unwrap();
+        assert_eq!(rendered, "my_app by Jo: {{ pageprop.path }} {{nope}}");
+        let code = "fn f() {{\n    #[inline] g({{crate_name}});\n}}";
+        assert_eq!(render(code, &vars()).unwrap(), "fn f() {{\n    #[inline] g(my_app);\n}}");
+        assert_eq!(
+            render_path(Path::new("src/{{crate_name}}/mod.rs"), &vars()).unwrap(),
+            PathBuf::from("src/my_app/mod.rs")
+        );
+    }
+
+    #[test]
+    fn resolves_conditional_blocks() {
+        let template = "a\n{{#if author}}\nby {{author}}\n{{else}}\nanonymous\n{{/if}}\n{{#unless metrics}}no metrics\n{{/unless}}{{#if license == \"MIT\"}}mit{{/if}}{{#if license != \"MIT\"}}other{{/if}}\n";
+        assert_eq!(render(template, &vars()).unwrap(), "a\nby Jo\nno metrics\nmit\n");
+
+        let mut anonymous = vars();
+        anonymous.insert("author".to_string(), String::new());
+        assert!(render(template, &anonymous).unwrap().contains("anonymous\n"));
+
+        assert!(render("{{#if author}}x", &vars()).is_err());
+        assert!(render("{{/if}}", &vars()).is_err());
+        assert!(render("{{#if a}}{{/unless}}", &vars()).is_err());
+    }
+
+    #[test]
+    fn derived_and_validated_variables() {
+        let mut vars = vars();
+        vars.insert("default_lang".to_string(), "ja".to_string());
+        finish(&mut vars).unwrap();
+        assert_eq!(vars["support_lang"], r#"["ja", "en", "zh"]"#);
+        assert_eq!(vars["session_secret"].len(), 64);
+        assert_ne!(vars["media_secret"], vars["session_secret"]);
+
+        vars.insert("port".to_string(), "70000".to_string());
+        assert!(finish(&mut vars).is_err());
+        assert!(parse_var("port=8080").is_ok());
+        assert!(parse_var("port").is_err());
+        assert!(parse_var("1x=2").is_err());
+    }
+}
@@ -0,0 +1,193 @@
+//! Rendering a template into a project directory, shared by `sfx init`,
+//! `sfx new` and `sfx upgrade`.
+//!
+//! Generated projects get a `.sfx/` directory: `manifest.json` records the
+//! sfx version, the template source and the variables, and `base/` keeps
+//! every file as the template rendered it. That copy is the common ancestor
+//! `sfx upgrade` merges against, so commit `.sfx/` along with the project.
+
+use anyhow::{Context, Result};
+use sfx::prelude::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cli::{diff, hooks, placeholders, templates};
+
+/// Directory of the sfx metadata in a generated project
+pub const META_DIR: &str = ".sfx";
+
+/// A template file rendered for the project, not yet written
+pub struct PlannedFile {
+    /// Path relative to the project root
+    pub relative: PathBuf,
+    pub target: PathBuf,
+    pub contents: Vec<u8>,
+    /// The current contents of `target`, if it exists
+    pub existing: Option<Vec<u8>>,
+}
+
+/// Render every template file and find where it goes
+pub fn plan(
+    files: &[templates::TemplateFile],
+    target_dir: &Path,
+    vars: &placeholders::Vars,
+) -> Result<Vec<PlannedFile>> {
+    let mut planned = Vec::with_capacity(files.len());
+    for file in files {
+        if file.when.as_deref().is_some_and(|condition| !placeholders::condition(condition, vars)) {
+            continue;
+        }
+        let mut relative = placeholders::render_path(&file.path, vars)?;
+
+        // Handle .template files by removing the .template extension
+        if let Some(new_name) = relative
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".template"))
+        {
+            relative = relative.with_file_name(new_name);
+        }
+
+        // Templated text files get their placeholders replaced, the rest is
+        // copied as is
+        let mut contents = match std::str::from_utf8(&file.contents) {
+            Ok(content) if file.templated => placeholders::render(content, vars)
+                .with_context(|| format!("Failed to render {}", file.path.display()))?
+                .into_bytes(),
+            _ => file.contents.to_vec(),
+        };
+        if relative == Path::new("Cargo.toml")
+            && let Ok(manifest) = std::str::from_utf8(&contents)
+        {
+            contents = hooks::pin_dependencies(manifest, env!("CARGO_PKG_VERSION")).into_bytes();
+        }
+        let target = target_dir.join(&relative);
+        let existing = fs::read(&target).ok();
+        planned.push(PlannedFile { relative, target, contents, existing });
+    }
+    Ok(planned)
+}
+
+/// Whether the project file `relative` is kept in `programfiles/secrets`
+pub fn is_secret(relative: &Path) -> bool {
+    relative.starts_with(Path::new("programfiles").join(sfx::secrets::DIR))
+}
+
+pub fn write(planned: &[PlannedFile], force: bool) -> Result<()> {
+    for file in planned {
+        // Skip if file exists and not forcing
+        if file.existing.is_some() && !force {
+            continue;
+        }
+
+        // Create parent directories
+        if let Some(parent) = file.target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if is_secret(&file.relative) {
+            sfx::secrets::write_private(&file.target, &file.contents)?;
+        } else {
+            fs::write(&file.target, &file.contents)?;
+        }
+    }
+    Ok(())
+}
+
+/// Print what `write_planned` would do, and with `diff` how existing files
+/// would change
+pub fn print_plan(planned: &[PlannedFile], target_dir: &Path, force: bool, diff: bool) -> Result<()> {
+    let (mut create, mut overwrite, mut keep) = (0, 0, 0);
+    for file in planned {
+        let action = match &file.existing {
+            None => {
+                create += 1;
+                "create"
+            }
+            Some(existing) if *existing == file.contents => {
+                keep += 1;
+                "same"
+            }
+            Some(_) if force => {
+                overwrite += 1;
+                "overwrite"
+            }
+            Some(_) => {
+                keep += 1;
+                "skip"
+            }
+        };
+        println!("{:<10} {}", action, file.relative.display());
+    }
+
+    if diff {
+        for file in planned {
+            let Some(existing) = &file.existing else { continue };
+            if *existing == file.contents {
+                continue;
+            }
+            let name = file.relative.display().to_string();
+            match (std::str::from_utf8(existing), std::str::from_utf8(&file.contents)) {
+                (Ok(old), Ok(new)) => print!(
+                    "\n{}",
+                    diff::unified(old, new, &format!("a/{}", name), &format!("b/{}", name))
+                ),
+                _ => println!("\nBinary file {} differs", name),
+            }
+        }
+    }
+
+    println!(
+        "\nDry run in {}: {} to create, {} to overwrite, {} left as is. Nothing was written.",
+        target_dir.display(),
+        create,
+        overwrite,
+        keep
+    );
+    if !force && planned.iter().any(|f| f.existing.as_ref().is_some_and(|e| *e != f.contents)) {
+        println!("Files marked 'skip' differ from the template and would only be replaced with --force.");
+    }
+    Ok(())
+}
+
+/// Where `.sfx/base/` keeps the rendered copy of `relative`
+pub fn base_path(project: &Path, relative: &Path) -> PathBuf {
+    project.join(META_DIR).join("base").join(relative)
+}
+
+/// Write `.sfx/manifest.json` and the `.sfx/base/` snapshot of `planned`
+pub fn record(
+    project: &Path,
+    source: &templates::TemplateSource,
+    vars: &placeholders::Vars,
+    planned: &[PlannedFile],
+) -> Result<()> {
+    let mut variables = Value::Dict(Default::default());
+    for (key, value) in vars.iter().filter(|(key, _)| !placeholders::SECRETS.contains(&key.as_str())) {
+        variables.set(key.clone(), value.clone());
+    }
+    let mut manifest = Value::Dict(Default::default());
+    manifest.set("sfx_version", env!("CARGO_PKG_VERSION"));
+    manifest.set("template", source.to_value());
+    manifest.set("vars", variables);
+    manifest.set(
+        "files",
+        Value::List(
+            planned
+                .iter()
+                .map(|file| Value::from(file.relative.to_string_lossy().into_owned()))
+                .collect(),
+        ),
+    );
+
+    let _ = fs::remove_dir_all(project.join(META_DIR).join("base"));
+    // Generated secrets stay out of `.sfx/`, which is meant to be committed
+    for file in planned.iter().filter(|file| !is_secret(&file.relative)) {
+        let base = base_path(project, &file.relative);
+        if let Some(parent) = base.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&base, &file.contents)?;
+    }
+    fs::write(project.join(META_DIR).join("manifest.json"), manifest.into_json())
+        .context("Failed to write .sfx/manifest.json")
+}
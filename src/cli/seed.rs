@@ -0,0 +1,89 @@
+//! `sfx seed`: demo users, admins, blog posts and comments for a local
+//! environment, see `sfx::seed`.
+//!
+//! Like `sfx user` it edits the account store directly, so the server has to
+//! be stopped; a running development server seeds at `/admin/dev/seed`.
+
+use anyhow::{Context, Result};
+use clap::{Arg, ArgMatches, Command, value_parser};
+use std::path::PathBuf;
+
+use sfx::local_auth::at_rest::users_key;
+use sfx::local_auth::fop::{AuthManager, lock_users_file};
+use sfx::seed::{self, SeedPlan};
+
+pub fn command() -> Command {
+    let count = |name: &'static str, help: &'static str| {
+        Arg::new(name).long(name).value_name("N").value_parser(value_parser!(usize)).help(help)
+    };
+    Command::new("seed")
+        .about("Fill programfiles with demo users and content")
+        .arg(
+            Arg::new("programfiles")
+                .long("programfiles")
+                .value_name("DIR")
+                .help("Configuration directory (default: ./programfiles)"),
+        )
+        .arg(count("users", "Demo accounts, demo1 to demoN (default: 10)"))
+        .arg(count("admins", "How many of them are admins (default: 1)"))
+        .arg(count("posts", "Published blog posts (default: 5)"))
+        .arg(count("comments", "Comments on each post (default: 3)"))
+        .arg(
+            Arg::new("password")
+                .long("password")
+                .value_name("PASSWORD")
+                .help("Password of every demo account (default: Demo1234)"),
+        )
+}
+
+pub fn run(matches: &ArgMatches) -> Result<()> {
+    if let Some(dir) = matches.get_one::<String>("programfiles") {
+        // The blog, comments and admins.json read the directory from the
+        // environment; nothing has read it yet and no other thread runs
+        unsafe { std::env::set_var(sfx::op::PROGRAMFILES_ENV, dir) };
+    }
+    let programfiles: PathBuf = sfx::op::programfiles();
+    let default = SeedPlan::default();
+    let count = |name: &str, default: usize| matches.get_one::<usize>(name).copied().unwrap_or(default);
+    let plan = SeedPlan {
+        users: count("users", default.users),
+        admins: count("admins", default.admins),
+        posts: count("posts", default.posts),
+        comments: count("comments", default.comments),
+        password: matches.get_one::<String>("password").cloned().unwrap_or(default.password),
+    };
+
+    let users_file = programfiles.join("local_auth/users").to_string_lossy().into_owned();
+    let key = users_key(&programfiles).map_err(|err| anyhow::anyhow!("op/local_auth.json `users_key`: {}", err))?;
+    let _lock = lock_users_file(&users_file)
+        .with_context(|| format!("Cannot lock {}. Stop the running server first.", users_file))?;
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    let report = runtime.block_on(async {
+        let manager = AuthManager::open(users_file.as_str(), key).map_err(|err| anyhow::anyhow!(err.to_string()))?;
+        let report = seed::seed(&manager, &plan).await;
+        manager.flush().await.map_err(|err| anyhow::anyhow!(err.to_string()))?;
+        anyhow::Ok(report)
+    })?;
+
+    for (uid, username) in &report.users {
+        println!("user     {:<4} {}", uid, username);
+    }
+    for entry in &report.admins {
+        println!("admin    {}", entry);
+    }
+    for slug in &report.posts {
+        println!("post     /blog/{}", slug);
+    }
+    println!(
+        "Seeded {} user(s), {} admin(s), {} post(s) and {} comment(s); demo accounts sign in with password {}",
+        report.users.len(),
+        report.admins.len(),
+        report.posts.len(),
+        report.comments,
+        plan.password
+    );
+    for err in &report.errors {
+        eprintln!("skipped  {}", err);
+    }
+    Ok(())
+}
@@ -0,0 +1,421 @@
+//! Project templates for `sfx new` / `sfx init`.
+//!
+//! `default/` is the full site. The other built-in templates are overlays in
+//! `variants/<name>/`: their files replace the ones of `default/` with the
+//! same path, and `skip` drops files of `default/` they have no use for.
+//!
+//! External templates are plain directories, given with `--template-path` or
+//! cloned from `--template-git`. An optional manifest, `sfx-template.json`,
+//! at their root says which files get placeholder substitution:
+//!
+//! ```json
+//! {
+//!     "description": "Our house style",
+//!     "templated": ["Cargo.toml.template", "src/**", "programfiles/op/*.json"],
+//!     "verbatim": ["templates/static/**"],
+//!     "exclude": ["target/**", "README.md"],
+//!     "when": { "templates/admin/**": "admin" }
+//! }
+//! ```
+//!
+//! Without `templated` every UTF-8 file is templated. `verbatim` wins over
+//! `templated`, and `.git/` and the manifest itself are never copied. In the
+//! patterns `*` matches within one path segment and `**` any number of them.
+//! `when` only generates the matching files if the condition holds, written
+//! like the one of an `{{#if}}` block (see `super::placeholders`).
+
+use anyhow::{Context, Result};
+use clap::{ArgMatches, Command};
+use include_dir::{Dir, DirEntry, include_dir};
+use sfx::prelude::Value;
+use std::borrow::Cow;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::TEMPLATE_DIR;
+
+static MINIMAL: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/variants/minimal");
+static ADMIN: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/variants/admin");
+
+/// Name of the template used when `--template` is not given
+pub const DEFAULT: &str = "full";
+
+/// File name of the manifest of an external template
+pub const MANIFEST: &str = "sfx-template.json";
+
+/// One file of a template, ready to be written into a project
+pub struct TemplateFile {
+    /// Path relative to the project root
+    pub path: PathBuf,
+    pub contents: Cow<'static, [u8]>,
+    /// Whether placeholders are substituted in it
+    pub templated: bool,
+    /// Condition on the variables for generating the file at all
+    pub when: Option<String>,
+}
+
+/// Files of the built-in templates that belong to an optional subsystem
+const FEATURE_FILES: &[(&str, &str)] = &[
+    ("templates/admin/blog*", "blog"),
+    ("templates/admin/form*", "forms"),
+    ("templates/admin/**", "admin"),
+    ("programfiles/local_auth/**", "local_auth"),
+    ("templates/blog/**", "blog"),
+    ("programfiles/blog/**", "blog"),
+    ("templates/forms/**", "forms"),
+    ("programfiles/forms/**", "forms"),
+    ("programfiles/op/graphql.json", "graphql"),
+];
+
+pub struct ProjectTemplate {
+    pub name: &'static str,
+    pub description: &'static str,
+    overlay: Option<&'static Dir<'static>>,
+    skip: &'static [&'static str],
+}
+
+pub static BUILTIN: &[ProjectTemplate] = &[
+    ProjectTemplate {
+        name: "full",
+        description: "Full site: home page, login and registration UI, admin panel",
+        overlay: None,
+        skip: &[],
+    },
+    ProjectTemplate {
+        name: "minimal",
+        description: "JSON API only: status and whoami endpoints, no home page",
+        overlay: Some(&MINIMAL),
+        skip: &["templates/index.html"],
+    },
+    ProjectTemplate {
+        name: "admin",
+        description: "Admin panel only: / redirects to /admin/",
+        overlay: Some(&ADMIN),
+        skip: &["templates/index.html"],
+    },
+];
+
+/// Look up a built-in template by name
+pub fn find(name: &str) -> Option<&'static ProjectTemplate> {
+    BUILTIN.iter().find(|template| template.name == name)
+}
+
+/// Names of the built-in templates, for argument validation
+pub fn names() -> Vec<&'static str> {
+    BUILTIN.iter().map(|template| template.name).collect()
+}
+
+impl ProjectTemplate {
+    /// The files making up the template, paths relative to the project root
+    pub fn files(&self) -> Vec<TemplateFile> {
+        let mut files = Vec::new();
+        collect(&TEMPLATE_DIR, &mut files);
+        files.retain(|file| {
+            !self.skip.iter().any(|skip| file.path == Path::new(skip))
+                && self.overlay.is_none_or(|overlay| overlay.get_file(&file.path).is_none())
+        });
+        if let Some(overlay) = self.overlay {
+            collect(overlay, &mut files);
+        }
+        files
+    }
+}
+
+fn collect(dir: &'static Dir<'static>, out: &mut Vec<TemplateFile>) {
+    for entry in dir.entries() {
+        match entry {
+            DirEntry::File(file) => out.push(TemplateFile {
+                path: file.path().to_path_buf(),
+                contents: Cow::Borrowed(file.contents()),
+                templated: std::str::from_utf8(file.contents()).is_ok(),
+                when: when(FEATURE_FILES.iter().copied(), &file.path().to_string_lossy()),
+            }),
+            DirEntry::Dir(subdir) => collect(subdir, out),
+        }
+    }
+}
+
+/// Whether the `/`-separated `path` matches `pattern`, where `*` matches
+/// within a segment and `**` matches any number of segments
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    fn segments(pattern: &[&str], path: &[&str]) -> bool {
+        match (pattern.first(), path.first()) {
+            (None, None) => true,
+            (Some(&"**"), _) => {
+                segments(&pattern[1..], path) || (!path.is_empty() && segments(pattern, &path[1..]))
+            }
+            (Some(p), Some(s)) => segment(p.as_bytes(), s.as_bytes()) && segments(&pattern[1..], &path[1..]),
+            _ => false,
+        }
+    }
+    fn segment(pattern: &[u8], name: &[u8]) -> bool {
+        match (pattern.first(), name.first()) {
+            (None, None) => true,
+            (Some(b'*'), _) => segment(&pattern[1..], name) || (!name.is_empty() && segment(pattern, &name[1..])),
+            (Some(b'?'), Some(_)) => segment(&pattern[1..], &name[1..]),
+            (Some(p), Some(n)) => p == n && segment(&pattern[1..], &name[1..]),
+            _ => false,
+        }
+    }
+    let pattern: Vec<&str> = pattern.trim_matches('/').split('/').collect();
+    let path: Vec<&str> = path.split('/').collect();
+    segments(&pattern, &path)
+}
+
+/// The condition of the first `(pattern, condition)` pair matching `path`
+fn when<'a>(mut conditions: impl Iterator<Item = (&'a str, &'a str)>, path: &str) -> Option<String> {
+    conditions
+        .find(|(pattern, _)| glob_match(pattern, path))
+        .map(|(_, condition)| condition.to_string())
+}
+
+/// The parsed `sfx-template.json` of an external template
+#[derive(Debug, Default)]
+pub struct Manifest {
+    pub description: String,
+    /// `None` when every UTF-8 file is templated
+    pub templated: Option<Vec<String>>,
+    pub verbatim: Vec<String>,
+    pub exclude: Vec<String>,
+    /// `(pattern, condition)` pairs
+    pub when: Vec<(String, String)>,
+}
+
+impl Manifest {
+    pub fn from_value(value: &Value) -> Self {
+        let list = |key: &str| match value.get(key) {
+            Value::List(list) => Some(list.iter().map(|p| p.string()).collect::<Vec<_>>()),
+            _ => None,
+        };
+        Self {
+            description: value.get("description").string(),
+            templated: list("templated"),
+            verbatim: list("verbatim").unwrap_or_default(),
+            exclude: list("exclude").unwrap_or_default(),
+            when: match value.get("when") {
+                Value::Dict(dict) => dict.iter().map(|(k, v)| (k.clone(), v.string())).collect(),
+                _ => Vec::new(),
+            },
+        }
+    }
+
+    fn excludes(&self, path: &str) -> bool {
+        path == MANIFEST
+            || path == ".git"
+            || path.starts_with(".git/")
+            || self.exclude.iter().any(|p| glob_match(p, path))
+    }
+
+    fn templates(&self, path: &str) -> bool {
+        !self.verbatim.iter().any(|p| glob_match(p, path))
+            && self
+                .templated
+                .as_ref()
+                .is_none_or(|patterns| patterns.iter().any(|p| glob_match(p, path)))
+    }
+}
+
+/// Load an external template from the directory `root`
+pub fn load_path(root: &Path) -> Result<Vec<TemplateFile>> {
+    if !root.is_dir() {
+        anyhow::bail!("Template directory {} not found", root.display());
+    }
+    let manifest_path = root.join(MANIFEST);
+    let manifest = if manifest_path.exists() {
+        let value = Value::from_jsonf(manifest_path.to_str().unwrap_or_default())
+            .map_err(|err| anyhow::anyhow!("Invalid {}: {}", manifest_path.display(), err))?;
+        Manifest::from_value(&value)
+    } else {
+        Manifest::default()
+    };
+    let mut files = Vec::new();
+    walk(root, root, &manifest, &mut files)?;
+    if files.is_empty() {
+        anyhow::bail!("Template {} has no files", root.display());
+    }
+    if !manifest.description.is_empty() {
+        println!("Template: {}", manifest.description);
+    }
+    Ok(files)
+}
+
+fn walk(root: &Path, dir: &Path, manifest: &Manifest, out: &mut Vec<TemplateFile>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let relative = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+        let key = relative.to_string_lossy().replace('\\', "/");
+        if manifest.excludes(&key) {
+            continue;
+        }
+        if path.is_dir() {
+            walk(root, &path, manifest, out)?;
+            continue;
+        }
+        let contents = fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let templated = std::str::from_utf8(&contents).is_ok() && manifest.templates(&key);
+        let when = when(manifest.when.iter().map(|(p, c)| (p.as_str(), c.as_str())), &key);
+        out.push(TemplateFile { path: relative, contents: Cow::Owned(contents), templated, when });
+    }
+    Ok(())
+}
+
+/// Where the files of a project come from, recorded in `.sfx/manifest.json`
+/// so `sfx upgrade` can fetch the same template again
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplateSource {
+    Builtin(String),
+    Path(PathBuf),
+    Git(String),
+}
+
+impl TemplateSource {
+    /// The source picked by `--template`, `--template-path` or `--template-git`
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        if let Some(dir) = matches.get_one::<String>("template_path") {
+            // Absolute, so the project can find it again from its own directory
+            let dir = fs::canonicalize(dir).with_context(|| format!("Template directory {} not found", dir))?;
+            return Ok(Self::Path(dir));
+        }
+        if let Some(url) = matches.get_one::<String>("template_git") {
+            return Ok(Self::Git(url.clone()));
+        }
+        let name = matches.get_one::<String>("template").expect("has default");
+        Ok(Self::Builtin(name.clone()))
+    }
+
+    pub fn files(&self) -> Result<Vec<TemplateFile>> {
+        match self {
+            Self::Builtin(name) => find(name)
+                .map(ProjectTemplate::files)
+                .ok_or_else(|| anyhow::anyhow!("Unknown template '{}', see `sfx templates list`", name)),
+            Self::Path(dir) => load_path(dir),
+            Self::Git(url) => load_git(url),
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        let (kind, location) = match self {
+            Self::Builtin(name) => ("builtin", name.clone()),
+            Self::Path(dir) => ("path", dir.to_string_lossy().into_owned()),
+            Self::Git(url) => ("git", url.clone()),
+        };
+        let mut value = Value::Dict(Default::default());
+        value.set("kind", kind);
+        value.set("location", location);
+        value
+    }
+
+    pub fn from_value(value: &Value) -> Option<Self> {
+        let location = value.get("location").string();
+        match value.get("kind").string().as_str() {
+            "builtin" => Some(Self::Builtin(location)),
+            "path" => Some(Self::Path(PathBuf::from(location))),
+            "git" => Some(Self::Git(location)),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for TemplateSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Builtin(name) => write!(f, "'{}' template", name),
+            Self::Path(dir) => write!(f, "template at {}", dir.display()),
+            Self::Git(url) => write!(f, "template cloned from {}", url),
+        }
+    }
+}
+
+/// Clone `url` (optionally suffixed with `#<branch or tag>`) with `git` and
+/// load it as a template
+pub fn load_git(url: &str) -> Result<Vec<TemplateFile>> {
+    let (url, reference) = match url.rsplit_once('#') {
+        Some((url, reference)) if !reference.is_empty() => (url, Some(reference)),
+        _ => (url, None),
+    };
+    let checkout = std::env::temp_dir().join(format!("sfx-template-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&checkout);
+    let mut git = std::process::Command::new("git");
+    git.args(["-c", "advice.detachedHead=false", "clone", "--quiet", "--depth", "1"]);
+    if let Some(reference) = reference {
+        git.args(["--branch", reference]);
+    }
+    let status = git
+        .arg(url)
+        .arg(&checkout)
+        .status()
+        .context("Failed to run git, is it installed?")?;
+    if !status.success() {
+        let _ = fs::remove_dir_all(&checkout);
+        anyhow::bail!("git clone {} failed with {}", url, status);
+    }
+    let files = load_path(&checkout);
+    let _ = fs::remove_dir_all(&checkout);
+    files
+}
+
+pub fn command() -> Command {
+    Command::new("templates")
+        .about("Inspect the project templates")
+        .subcommand_required(true)
+        .subcommand(Command::new("list").about("List the built-in templates"))
+}
+
+pub fn run(matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        Some(("list", _)) => {
+            for template in BUILTIN {
+                let marker = if template.name == DEFAULT { " (default)" } else { "" };
+                println!("{:<10} {}{}", template.name, template.description, marker);
+            }
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlays_replace_and_skip_default_files() {
+        let full = find("full").unwrap().files();
+        let minimal = find("minimal").unwrap().files();
+        let lib = |files: &[TemplateFile]| {
+            files
+                .iter()
+                .filter(|f| f.path == Path::new("src/lib.rs"))
+                .map(|f| String::from_utf8_lossy(&f.contents).into_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(lib(&minimal).len(), 1);
+        assert!(lib(&minimal)[0].contains("/api/whoami"));
+        assert_ne!(lib(&full), lib(&minimal));
+        assert!(full.iter().any(|f| f.path == Path::new("templates/index.html")));
+        assert!(!minimal.iter().any(|f| f.path == Path::new("templates/index.html")));
+        assert_eq!(full.len(), minimal.len() + 1);
+    }
+
+    #[test]
+    fn manifest_globs_select_templated_files() {
+        assert!(glob_match("src/**", "src/lib.rs"));
+        assert!(glob_match("src/**", "src/cli/user.rs"));
+        assert!(glob_match("**/*.json", "programfiles/op/navbar.json"));
+        assert!(glob_match("*.toml.template", "Cargo.toml.template"));
+        assert!(!glob_match("src/*", "src/cli/user.rs"));
+        assert!(!glob_match("*.json", "op/navbar.json"));
+
+        let manifest = Manifest::from_value(
+            &Value::from_json(r#"{"templated": ["src/**"], "verbatim": ["src/static/**"], "exclude": ["target/**"]}"#)
+                .unwrap(),
+        );
+        assert!(manifest.templates("src/lib.rs"));
+        assert!(!manifest.templates("src/static/app.js"));
+        assert!(!manifest.templates("README.md"));
+        assert!(manifest.excludes("target/debug/app"));
+        assert!(manifest.excludes(".git/HEAD"));
+        assert!(manifest.excludes(MANIFEST));
+        assert!(Manifest::default().templates("README.md"));
+    }
+}
@@ -0,0 +1,207 @@
+//! `sfx upgrade`: bring a generated project up to the template of this sfx
+//! version.
+//!
+//! Every file is merged three ways: the `.sfx/base/` copy is what the
+//! template produced last time, the project file is that plus the user's
+//! edits, and the template is rendered again with the variables of
+//! `.sfx/manifest.json`. Files the user never touched are replaced, files the
+//! template did not change are left alone, and the rest is merged line by
+//! line. A file whose merge conflicts is not written: the merge, with
+//! conflict markers, goes to `.sfx/conflicts/` and the file is listed.
+
+use anyhow::{Context, Result};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use sfx::prelude::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cli::{diff, placeholders, scaffold, templates::TemplateSource};
+
+pub fn command() -> Command {
+    Command::new("upgrade")
+        .about("Merge changes of the project template into a generated project")
+        .arg(
+            Arg::new("dir")
+                .long("dir")
+                .value_name("DIR")
+                .default_value(".")
+                .help("Project directory"),
+        )
+        .arg(
+            Arg::new("dry_run")
+                .long("dry-run")
+                .action(ArgAction::SetTrue)
+                .help("Show what would change without writing anything"),
+        )
+}
+
+/// What happens to one file
+#[derive(Debug, PartialEq, Eq)]
+enum Outcome {
+    Unchanged,
+    Created,
+    Updated,
+    Merged,
+    /// The user edited it and the template did not change it
+    Kept,
+    /// Deleted from the project, changed in the template
+    Deleted,
+    Conflict,
+}
+
+impl Outcome {
+    fn label(&self) -> &'static str {
+        match self {
+            Outcome::Unchanged => "same",
+            Outcome::Created => "create",
+            Outcome::Updated => "update",
+            Outcome::Merged => "merge",
+            Outcome::Kept => "keep",
+            Outcome::Deleted => "deleted",
+            Outcome::Conflict => "CONFLICT",
+        }
+    }
+}
+
+/// Decide what to do with a file from its base, project and template
+/// versions. Returns the outcome and the contents to write, if any.
+fn resolve(base: Option<&[u8]>, ours: Option<&[u8]>, theirs: &[u8]) -> (Outcome, Option<Vec<u8>>) {
+    let Some(ours) = ours else {
+        return match base {
+            None => (Outcome::Created, Some(theirs.to_vec())),
+            Some(base) if base == theirs => (Outcome::Unchanged, None),
+            Some(_) => (Outcome::Deleted, None),
+        };
+    };
+    if ours == theirs {
+        return (Outcome::Unchanged, None);
+    }
+    let base = base.unwrap_or_default();
+    if ours == base {
+        return (Outcome::Updated, Some(theirs.to_vec()));
+    }
+    if theirs == base {
+        return (Outcome::Kept, None);
+    }
+    match (std::str::from_utf8(base), std::str::from_utf8(ours), std::str::from_utf8(theirs)) {
+        (Ok(base), Ok(ours), Ok(theirs)) => {
+            let merge = diff::merge3(base, ours, theirs);
+            if merge.conflicts == 0 {
+                (Outcome::Merged, Some(merge.text.into_bytes()))
+            } else {
+                (Outcome::Conflict, Some(merge.text.into_bytes()))
+            }
+        }
+        _ => (Outcome::Conflict, Some(theirs.to_vec())),
+    }
+}
+
+pub fn run(matches: &ArgMatches) -> Result<()> {
+    let project = PathBuf::from(matches.get_one::<String>("dir").expect("has default"));
+    let dry_run = matches.get_flag("dry_run");
+    let manifest_path = project.join(scaffold::META_DIR).join("manifest.json");
+    let manifest = Value::from_jsonf(manifest_path.to_str().unwrap_or_default()).map_err(|err| {
+        anyhow::anyhow!(
+            "Cannot read {}: {}. Only projects created by `sfx new`/`sfx init` can be upgraded.",
+            manifest_path.display(),
+            err
+        )
+    })?;
+    let source = TemplateSource::from_value(manifest.get("template"))
+        .context("The manifest does not say which template the project came from")?;
+    let mut vars = placeholders::Vars::new();
+    if let Value::Dict(dict) = manifest.get("vars") {
+        for (key, value) in dict {
+            vars.insert(key.clone(), value.string());
+        }
+    }
+    placeholders::finish(&mut vars)?;
+
+    println!(
+        "Upgrading from the {} (sfx {} -> {})",
+        source,
+        manifest.get("sfx_version").string(),
+        env!("CARGO_PKG_VERSION")
+    );
+    let planned = scaffold::plan(&source.files()?, &project, &vars)?;
+    let conflicts_dir = project.join(scaffold::META_DIR).join("conflicts");
+    let mut conflicts = Vec::new();
+    for file in &planned {
+        // Secrets are generated anew each time; the project's own are kept
+        if scaffold::is_secret(&file.relative) && file.existing.is_some() {
+            continue;
+        }
+        let base = fs::read(scaffold::base_path(&project, &file.relative)).ok();
+        let (outcome, contents) = resolve(base.as_deref(), file.existing.as_deref(), &file.contents);
+        if outcome != Outcome::Unchanged {
+            println!("{:<10} {}", outcome.label(), file.relative.display());
+        }
+        if dry_run {
+            continue;
+        }
+        match (&outcome, contents) {
+            (Outcome::Conflict, Some(contents)) => {
+                write_file(&conflicts_dir.join(&file.relative), &contents)?;
+                conflicts.push(file.relative.clone());
+            }
+            (_, Some(contents)) if scaffold::is_secret(&file.relative) => {
+                fs::create_dir_all(file.target.parent().unwrap_or(&project))?;
+                sfx::secrets::write_private(&file.target, &contents)?;
+            }
+            (_, Some(contents)) => write_file(&file.target, &contents)?,
+            _ => {}
+        }
+    }
+
+    // Files the template no longer has are left to the user
+    let previous: Vec<String> = manifest.get("files").list().iter().map(Value::string).collect();
+    for relative in previous {
+        if !planned.iter().any(|file| file.relative == Path::new(&relative)) {
+            println!("{:<10} {} (no longer in the template)", "orphan", relative);
+        }
+    }
+
+    if dry_run {
+        println!("\nDry run, nothing was written.");
+        return Ok(());
+    }
+    scaffold::record(&project, &source, &vars, &planned)?;
+    if conflicts.is_empty() {
+        println!("\nUpgrade complete.");
+        return Ok(());
+    }
+    println!(
+        "\n{} file(s) conflict and were left untouched. Their merges, with conflict markers, are in {}:",
+        conflicts.len(),
+        conflicts_dir.display()
+    );
+    for relative in &conflicts {
+        println!("  {}", relative.display());
+    }
+    anyhow::bail!("Resolve the conflicts by hand, then delete {}", conflicts_dir.display())
+}
+
+fn write_file(path: &Path, contents: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, contents).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_respects_user_edits() {
+        let (outcome, _) = resolve(Some(b"a\n"), Some(b"a\n"), b"b\n");
+        assert_eq!(outcome, Outcome::Updated);
+        assert_eq!(resolve(Some(b"a\n"), Some(b"mine\n"), b"a\n").0, Outcome::Kept);
+        assert_eq!(resolve(Some(b"a\n"), None, b"a\n").0, Outcome::Unchanged);
+        assert_eq!(resolve(Some(b"a\n"), None, b"b\n").0, Outcome::Deleted);
+        assert_eq!(resolve(None, None, b"b\n").0, Outcome::Created);
+        assert_eq!(resolve(Some(b"a\n"), Some(b"mine\n"), b"b\n").0, Outcome::Conflict);
+        let (outcome, merged) = resolve(Some(b"a\nb\nc\n"), Some(b"A\nb\nc\n"), b"a\nb\nC\n");
+        assert_eq!((outcome, merged.as_deref()), (Outcome::Merged, Some(&b"A\nb\nC\n"[..])));
+    }
+}
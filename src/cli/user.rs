@@ -0,0 +1,140 @@
+//! `sfx user`: offline administration of the local account store
+//! (`programfiles/local_auth/users`).
+//!
+//! Changes go straight to the file, so the store is locked first (see
+//! `sfx::local_auth::fop::lock_users_file`). A running server keeps the lock
+//! and its own copy of the users, so it has to be stopped before editing.
+
+use anyhow::{Context, Result};
+use clap::{Arg, ArgMatches, Command};
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+
+use sfx::local_auth::at_rest::users_key;
+use sfx::local_auth::fop::{AuthManager, lock_users_file};
+
+pub fn command() -> Command {
+    let password = Arg::new("password")
+        .long("password")
+        .short('p')
+        .value_name("PASSWORD")
+        .help("Password to set (prompted for when omitted)");
+    Command::new("user")
+        .about("Manage local accounts without a running server")
+        .subcommand_required(true)
+        .arg(
+            Arg::new("programfiles")
+                .long("programfiles")
+                .value_name("DIR")
+                .global(true)
+                .help("Configuration directory (default: ./programfiles)"),
+        )
+        .subcommand(Command::new("list").about("List all accounts"))
+        .subcommand(
+            Command::new("add")
+                .about("Create an account")
+                .arg(Arg::new("username").required(true).index(1))
+                .arg(Arg::new("email").required(true).index(2))
+                .arg(password.clone()),
+        )
+        .subcommand(
+            Command::new("passwd")
+                .about("Set the password of an account")
+                .arg(Arg::new("user").required(true).index(1).help("Username, email or uid"))
+                .arg(password),
+        )
+        .subcommand(
+            Command::new("delete")
+                .about("Delete an account")
+                .arg(Arg::new("user").required(true).index(1).help("Username, email or uid")),
+        )
+}
+
+pub fn run(matches: &ArgMatches) -> Result<()> {
+    let programfiles = match matches.get_one::<String>("programfiles") {
+        Some(dir) => PathBuf::from(dir),
+        None => sfx::op::programfiles(),
+    };
+    let users_file = programfiles.join("local_auth/users").to_string_lossy().into_owned();
+    let key = users_key(&programfiles).map_err(|err| anyhow::anyhow!("op/local_auth.json `users_key`: {}", err))?;
+    let _lock = lock_users_file(&users_file).with_context(|| {
+        format!("Cannot lock {}. Stop the running server first.", users_file)
+    })?;
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    runtime.block_on(async {
+        let manager = AuthManager::open(users_file.as_str(), key).map_err(|err| anyhow::anyhow!(err.to_string()))?;
+        match matches.subcommand() {
+            Some(("list", _)) => {
+                println!("{:>6}  {:<20}  {:<32}  active", "uid", "username", "email");
+                for (uid, user) in manager.admin_list_users().await {
+                    println!("{:>6}  {:<20}  {:<32}  {}", uid, user.username, user.email, user.is_active);
+                }
+                return Ok(());
+            }
+            Some(("add", sub)) => {
+                let username = sub.get_one::<String>("username").expect("required argument");
+                let email = sub.get_one::<String>("email").expect("required argument");
+                let password = password(sub)?;
+                manager
+                    .register_user(username, email, &password)
+                    .await
+                    .map_err(|err| anyhow::anyhow!(err.to_string()))?;
+                println!("Created user '{}'", username);
+            }
+            Some(("passwd", sub)) => {
+                let uid = find(&manager, sub).await?;
+                let password = password(sub)?;
+                manager
+                    .admin_reset_password(uid, &password)
+                    .await
+                    .map_err(|err| anyhow::anyhow!(err.to_string()))?;
+                println!("Password of uid {} updated", uid);
+            }
+            Some(("delete", sub)) => {
+                let uid = find(&manager, sub).await?;
+                manager
+                    .admin_delete_user(uid)
+                    .await
+                    .map_err(|err| anyhow::anyhow!(err.to_string()))?;
+                println!("Deleted uid {}", uid);
+            }
+            _ => unreachable!(),
+        }
+        manager.flush().await.map_err(|err| anyhow::anyhow!(err.to_string()))
+    })
+}
+
+/// Resolve the `user` argument (username, email or uid) to a uid
+async fn find(manager: &AuthManager, matches: &ArgMatches) -> Result<u32> {
+    let user = matches.get_one::<String>("user").expect("required argument");
+    manager
+        .uid_from_username_or_email_or_uid(user.clone())
+        .await
+        .map_err(|err| anyhow::anyhow!("{}: {}", user, err.to_string()))
+}
+
+/// The `--password` flag, or a password read from stdin. Echo is turned off
+/// with `stty` while typing on a terminal.
+fn password(matches: &ArgMatches) -> Result<String> {
+    if let Some(password) = matches.get_one::<String>("password") {
+        return Ok(password.clone());
+    }
+    let tty = std::io::stdin().is_terminal();
+    if tty {
+        print!("Password: ");
+        std::io::stdout().flush()?;
+        let _ = std::process::Command::new("stty").arg("-echo").status();
+    }
+    let mut line = String::new();
+    let read = std::io::stdin().lock().read_line(&mut line);
+    if tty {
+        let _ = std::process::Command::new("stty").arg("echo").status();
+        println!();
+    }
+    read?;
+    let password = line.trim_end_matches(['\r', '\n']).to_string();
+    if password.is_empty() {
+        anyhow::bail!("The password must not be empty");
+    }
+    Ok(password)
+}
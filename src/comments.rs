@@ -0,0 +1,717 @@
+//! comments.rs
+//!
+//! Threaded comments attached to any page by a content key (`blog/hello`,
+//! `docs/install`). A page renders `default/templates/base/comments.html`
+//! with the value of [`thread`]; the form there posts to `/op/comments`.
+//! Read from `programfiles/op/comments.json`:
+//!
+//! ```json
+//! {
+//!     "guests": false,
+//!     "moderate": false,
+//!     "max_length": 4000,
+//!     "max_depth": 4,
+//!     "max_links": 2,
+//!     "rate": { "count": 5, "seconds": 300 },
+//!     "blocked_words": []
+//! }
+//! ```
+//!
+//! Signed-in users post right away. With `guests` anybody may post, but
+//! comments of guests wait in the moderation queue at `/admin/comments`,
+//! as do all comments with `moderate`. So does a comment that looks like
+//! spam: more than `max_links` links, a blocked word, or written in
+//! capitals. Each user (guests by address) posts at most `rate.count`
+//! comments per `rate.seconds`; the form is also guarded by the honeypot of
+//! [`crate::honeypot`] when its `routes` include `comment`.
+//!
+//! Comments live in `programfiles/admin_info/comments.json`. Rejecting or
+//! deleting a comment hides its replies as well.
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Mutex, RwLock};
+
+use crate::captcha;
+use crate::honeypot::{self, BotError};
+use crate::op::{self, APP};
+use crate::proxy;
+use crate::ctx::SfxCtx;
+use crate::methods;
+
+static COMMENTS_SETTINGS: Lazy<CommentSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/comments.json");
+    CommentSettings::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None))
+});
+
+static COMMENTS: Lazy<RwLock<Vec<Comment>>> = Lazy::new(|| {
+    let comments = match Value::from_jsonf(comments_path().to_string_lossy()) {
+        Ok(Value::List(comments)) => comments.iter().filter_map(Comment::from_json).collect(),
+        _ => Vec::new(),
+    };
+    RwLock::new(comments)
+});
+
+/// When each poster last posted, for the rate limit
+static RECENT: Lazy<Mutex<HashMap<String, Vec<u64>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Length of comment ids
+const ID_LENGTH: usize = 12;
+
+/// Longest content key
+const MAX_CONTENT: usize = 128;
+
+/// Longest name a guest may give
+const MAX_NAME: usize = 40;
+
+fn comments_path() -> PathBuf {
+    crate::op::programfiles().join("admin_info/comments.json")
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// The parsed content of `comments.json`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommentSettings {
+    /// Whether guests may post, into the moderation queue
+    pub guests: bool,
+    /// Hold the comments of signed-in users for moderation too
+    pub moderate: bool,
+    /// Longest comment, in characters
+    pub max_length: usize,
+    /// Deepest reply; 1 allows no replies
+    pub max_depth: usize,
+    /// Links a comment may have before it is held
+    pub max_links: usize,
+    /// Comments one poster may send per `rate_seconds`
+    pub rate_count: usize,
+    pub rate_seconds: u64,
+    /// Words that hold a comment, matched without regard to case
+    pub blocked_words: Vec<String>,
+}
+
+impl Default for CommentSettings {
+    fn default() -> Self {
+        Self {
+            guests: false,
+            moderate: false,
+            max_length: 4000,
+            max_depth: 4,
+            max_links: 2,
+            rate_count: 5,
+            rate_seconds: 300,
+            blocked_words: Vec::new(),
+        }
+    }
+}
+
+impl CommentSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let default = Self::default();
+        let number = |value: &Value, key: &str, fallback: u64| match value.get(key) {
+            Value::Numerical(_) => value.get(key).integer().max(0) as u64,
+            _ => fallback,
+        };
+        let rate = value.get("rate");
+        Self {
+            guests: value.get("guests").boolean(),
+            moderate: value.get("moderate").boolean(),
+            max_length: number(value, "max_length", default.max_length as u64).max(1) as usize,
+            max_depth: number(value, "max_depth", default.max_depth as u64).max(1) as usize,
+            max_links: number(value, "max_links", default.max_links as u64) as usize,
+            rate_count: number(rate, "count", default.rate_count as u64).max(1) as usize,
+            rate_seconds: number(rate, "seconds", default.rate_seconds),
+            blocked_words: match value.get("blocked_words") {
+                Value::List(words) => words
+                    .iter()
+                    .map(|word| word.string().trim().to_lowercase())
+                    .filter(|word| !word.is_empty())
+                    .collect(),
+                _ => Vec::new(),
+            },
+        }
+    }
+
+    /// Why `body` looks like spam, `None` when it does not
+    pub fn spam_reason(&self, body: &str) -> Option<String> {
+        let lower = body.to_lowercase();
+        let links = ["http://", "https://", "www."].iter().map(|marker| lower.matches(marker).count()).sum::<usize>();
+        if links > self.max_links {
+            return Some(format!("{} links", links));
+        }
+        if let Some(word) = self.blocked_words.iter().find(|word| lower.contains(word.as_str())) {
+            return Some(format!("blocked word '{}'", word));
+        }
+        let letters = body.chars().filter(|c| c.is_alphabetic()).count();
+        let capitals = body.chars().filter(|c| c.is_uppercase()).count();
+        if letters >= 20 && capitals * 5 > letters * 4 {
+            return Some("written in capitals".to_string());
+        }
+        None
+    }
+
+    /// Record a post of `poster` at `now` in `recent`, unless it already
+    /// posted `rate_count` times within `rate_seconds`
+    pub fn allow(&self, recent: &mut HashMap<String, Vec<u64>>, poster: &str, now: u64) -> bool {
+        let window = now.saturating_sub(self.rate_seconds);
+        recent.retain(|_, times| {
+            times.retain(|time| *time > window);
+            !times.is_empty()
+        });
+        let times = recent.entry(poster.to_string()).or_default();
+        if times.len() >= self.rate_count {
+            return false;
+        }
+        times.push(now);
+        true
+    }
+}
+
+/// Where a comment stands with moderation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommentStatus {
+    /// Waiting in the moderation queue
+    Pending,
+    Approved,
+    Rejected,
+}
+
+impl CommentStatus {
+    pub fn from_string(status: &str) -> Option<Self> {
+        match status {
+            "pending" => Some(CommentStatus::Pending),
+            "approved" => Some(CommentStatus::Approved),
+            "rejected" => Some(CommentStatus::Rejected),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CommentStatus::Pending => "pending",
+            CommentStatus::Approved => "approved",
+            CommentStatus::Rejected => "rejected",
+        }
+    }
+}
+
+/// A comment on a piece of content
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comment {
+    pub id: String,
+    /// The content key of the page it is on
+    pub content: String,
+    /// The comment it replies to
+    pub parent: Option<String>,
+    /// The user who posted it (`1@local`), empty for guests
+    pub author: String,
+    /// The name shown with it
+    pub name: String,
+    pub body: String,
+    pub created: u64,
+    pub status: CommentStatus,
+    /// Why it was held for moderation
+    pub reason: Option<String>,
+    /// Posted by a shadowbanned user, shown to nobody else
+    pub shadow: bool,
+}
+
+impl Comment {
+    pub fn from_json(value: &Value) -> Option<Self> {
+        let text = |key: &str| Some(value.get(key).string()).filter(|text| !text.is_empty());
+        Some(Self {
+            id: text("id")?,
+            content: text("content")?,
+            parent: text("parent"),
+            author: value.get("author").string(),
+            name: value.get("name").string(),
+            body: value.get("body").string(),
+            created: value.get("created").integer().max(0) as u64,
+            status: CommentStatus::from_string(&value.get("status").string())?,
+            reason: text("reason"),
+            shadow: value.get("shadow").boolean(),
+        })
+    }
+
+    pub fn into_json(&self) -> Value {
+        let mut value = object!({
+            id: &self.id,
+            content: &self.content,
+            author: &self.author,
+            name: &self.name,
+            body: &self.body,
+            created: self.created,
+            status: self.status.as_str(),
+            shadow: self.shadow,
+        });
+        if let Some(parent) = &self.parent {
+            value.set("parent", parent);
+        }
+        if let Some(reason) = &self.reason {
+            value.set("reason", reason);
+        }
+        value
+    }
+
+    pub fn is_guest(&self) -> bool {
+        self.author.is_empty()
+    }
+
+    /// Whether the page shows it to the user `viewer` (`None` for guests)
+    pub fn is_visible_to(&self, viewer: Option<&str>) -> bool {
+        self.status == CommentStatus::Approved && (!self.shadow || viewer == Some(self.author.as_str()))
+    }
+}
+
+/// Why a comment could not be posted or changed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommentError {
+    /// Guests may not post
+    SignInRequired,
+    InvalidContent,
+    Empty,
+    TooLong(usize),
+    /// The comment replied to is not on the same page or not visible
+    ParentNotFound,
+    TooDeep,
+    /// The poster sent the same comment before
+    Duplicate,
+    TooManyRequests,
+    Bot(BotError),
+    NotFound,
+    Io(String),
+}
+
+impl std::fmt::Display for CommentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommentError::SignInRequired => write!(f, "Sign in to comment"),
+            CommentError::InvalidContent => write!(f, "Invalid content key"),
+            CommentError::Empty => write!(f, "The comment is empty"),
+            CommentError::TooLong(max) => write!(f, "Comments are at most {} characters", max),
+            CommentError::ParentNotFound => write!(f, "The comment replied to does not exist"),
+            CommentError::TooDeep => write!(f, "Replies cannot go deeper here"),
+            CommentError::Duplicate => write!(f, "You already posted this comment"),
+            CommentError::TooManyRequests => write!(f, "Too many comments, try again later"),
+            CommentError::Bot(err) => write!(f, "{}", err),
+            CommentError::NotFound => write!(f, "Comment not found"),
+            CommentError::Io(err) => write!(f, "Failed to save comments: {}", err),
+        }
+    }
+}
+
+/// Whether `content` can be a content key: letters, digits and `/-_.:`
+pub fn valid_content(content: &str) -> bool {
+    !content.is_empty()
+        && content.len() <= MAX_CONTENT
+        && content.chars().all(|c| c.is_ascii_alphanumeric() || "/-_.:".contains(c))
+}
+
+/// `body` with Windows line breaks and control characters taken out
+fn clean_body(body: &str) -> String {
+    body.replace("\r\n", "\n").chars().filter(|c| *c == '\n' || !c.is_control()).collect::<String>().trim().to_string()
+}
+
+/// The name a guest gave, or `Guest`
+fn clean_name(name: &str) -> String {
+    let name: String = name.chars().filter(|c| c.is_alphanumeric() || " .-_".contains(*c)).take(MAX_NAME).collect();
+    if name.trim().is_empty() { "Guest".to_string() } else { name.trim().to_string() }
+}
+
+/// How deep `comment` sits in `comments`, 1 for a comment on the page
+fn depth(comments: &[Comment], comment: &Comment) -> usize {
+    let mut depth = 1;
+    let mut parent = comment.parent.as_deref();
+    while let Some(id) = parent {
+        depth += 1;
+        parent = comments.iter().find(|comment| comment.id == id).and_then(|comment| comment.parent.as_deref());
+    }
+    depth
+}
+
+/// The approved comments on `content` in reading order: each comment
+/// followed by its replies, with its depth. Replies to a comment that is
+/// not approved are left out, and so are shadowbanned comments except for
+/// their author, the user `viewer`.
+pub fn threaded(comments: &[Comment], content: &str, viewer: Option<&str>) -> Vec<(Comment, usize)> {
+    fn walk(visible: &[&Comment], parent: Option<&str>, depth: usize, out: &mut Vec<(Comment, usize)>) {
+        for comment in visible.iter().filter(|comment| comment.parent.as_deref() == parent) {
+            out.push(((*comment).clone(), depth));
+            walk(visible, Some(&comment.id), depth + 1, out);
+        }
+    }
+    let visible: Vec<&Comment> = comments
+        .iter()
+        .filter(|comment| comment.content == content && comment.is_visible_to(viewer))
+        .collect();
+    let mut out = Vec::new();
+    walk(&visible, None, 1, &mut out);
+    out
+}
+
+fn save() -> Result<(), CommentError> {
+    let path = comments_path();
+    let comments = Value::List(COMMENTS.read().unwrap().iter().map(Comment::into_json).collect());
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|err| CommentError::Io(err.to_string()))?;
+    }
+    std::fs::write(&path, comments.into_json()).map_err(|err| CommentError::Io(err.to_string()))
+}
+
+/// A comment being posted
+#[derive(Debug, Clone, Default)]
+pub struct NewComment {
+    pub content: String,
+    pub parent: Option<String>,
+    pub body: String,
+    /// The name a guest gives
+    pub name: String,
+}
+
+/// Who is posting: a signed-in user, or a guest from an address
+#[derive(Debug, Clone)]
+pub enum Poster {
+    User { id: String, name: String, shadowbanned: bool },
+    Guest { address: String },
+}
+
+/// Post `new` as `poster`
+pub fn post(new: NewComment, poster: &Poster) -> Result<Comment, CommentError> {
+    let settings = &*COMMENTS_SETTINGS;
+    if matches!(poster, Poster::Guest { .. }) && !settings.guests {
+        return Err(CommentError::SignInRequired);
+    }
+    if !valid_content(&new.content) {
+        return Err(CommentError::InvalidContent);
+    }
+    let body = clean_body(&new.body);
+    if body.is_empty() {
+        return Err(CommentError::Empty);
+    }
+    if body.chars().count() > settings.max_length {
+        return Err(CommentError::TooLong(settings.max_length));
+    }
+    let (author, name, rate_key) = match poster {
+        Poster::User { id, name, .. } => (id.clone(), name.clone(), id.clone()),
+        Poster::Guest { address } => (String::new(), clean_name(&new.name), format!("guest:{}", address)),
+    };
+    {
+        let comments = COMMENTS.read().unwrap();
+        if let Some(parent) = &new.parent {
+            let parent = comments
+                .iter()
+                .find(|comment| {
+                    &comment.id == parent
+                        && comment.content == new.content
+                        && comment.is_visible_to(Some(author.as_str()).filter(|author| !author.is_empty()))
+                })
+                .ok_or(CommentError::ParentNotFound)?;
+            if depth(&comments, parent) >= settings.max_depth {
+                return Err(CommentError::TooDeep);
+            }
+        }
+        if !author.is_empty()
+            && comments.iter().any(|comment| comment.author == author && comment.content == new.content && comment.body == body)
+        {
+            return Err(CommentError::Duplicate);
+        }
+    }
+    if !settings.allow(&mut RECENT.lock().unwrap(), &rate_key, now()) {
+        tracing::info!(poster = %rate_key, "Comment rate limit reached");
+        return Err(CommentError::TooManyRequests);
+    }
+    let reason = settings.spam_reason(&body).or_else(|| match poster {
+        Poster::Guest { .. } => Some("guest".to_string()),
+        Poster::User { .. } if settings.moderate => Some("moderated".to_string()),
+        Poster::User { .. } => None,
+    });
+    let comment = Comment {
+        id: hotaru_lib::random::random_alphanumeric_string(ID_LENGTH),
+        content: new.content,
+        parent: new.parent,
+        author,
+        name,
+        body,
+        created: now(),
+        status: if reason.is_some() { CommentStatus::Pending } else { CommentStatus::Approved },
+        reason,
+        shadow: matches!(poster, Poster::User { shadowbanned: true, .. }),
+    };
+    COMMENTS.write().unwrap().push(comment.clone());
+    save()?;
+    tracing::info!(id = %comment.id, content = %comment.content, status = comment.status.as_str(), "Comment posted");
+    crate::events::publish(crate::events::CommentPosted {
+        id: comment.id.clone(),
+        content: comment.content.clone(),
+        author: comment.author.clone(),
+        visible: comment.status == CommentStatus::Approved && !comment.shadow,
+    });
+    Ok(comment)
+}
+
+/// Approve or reject the comment `id`
+pub fn set_status(id: &str, status: CommentStatus, by: &str) -> Result<Comment, CommentError> {
+    moderate(id, status, None, by)
+}
+
+/// Send the comment `id` back to the moderation queue, for `reason`
+pub fn hold(id: &str, reason: &str, by: &str) -> Result<Comment, CommentError> {
+    moderate(id, CommentStatus::Pending, Some(reason), by)
+}
+
+fn moderate(id: &str, status: CommentStatus, reason: Option<&str>, by: &str) -> Result<Comment, CommentError> {
+    let comment = {
+        let mut comments = COMMENTS.write().unwrap();
+        let comment = comments.iter_mut().find(|comment| comment.id == id).ok_or(CommentError::NotFound)?;
+        comment.status = status;
+        if let Some(reason) = reason {
+            comment.reason = Some(reason.to_string());
+        }
+        comment.clone()
+    };
+    save()?;
+    let action = match status {
+        CommentStatus::Pending => "hold",
+        CommentStatus::Approved => "approve",
+        CommentStatus::Rejected => "reject",
+    };
+    crate::moderation::audit(action, "comment", id, by, reason.unwrap_or_default());
+    tracing::info!(id, by, status = status.as_str(), "Comment moderated");
+    Ok(comment)
+}
+
+/// Remove the comment `id`
+pub fn remove(id: &str, by: &str) -> Result<Comment, CommentError> {
+    let comment = {
+        let mut comments = COMMENTS.write().unwrap();
+        let at = comments.iter().position(|comment| comment.id == id).ok_or(CommentError::NotFound)?;
+        comments.remove(at)
+    };
+    save()?;
+    crate::moderation::audit("delete", "comment", id, by, "");
+    tracing::info!(id, by, "Comment removed");
+    Ok(comment)
+}
+
+/// The comment `id`
+pub fn get(id: &str) -> Option<Comment> {
+    COMMENTS.read().unwrap().iter().find(|comment| comment.id == id).cloned()
+}
+
+/// The comments with `status`, or all of them, newest first
+pub fn list(status: Option<CommentStatus>) -> Vec<Comment> {
+    COMMENTS
+        .read()
+        .unwrap()
+        .iter()
+        .rev()
+        .filter(|comment| status.is_none_or(|status| comment.status == status))
+        .cloned()
+        .collect()
+}
+
+/// The loaded comment settings
+pub fn settings() -> &'static CommentSettings {
+    &COMMENTS_SETTINGS
+}
+
+/// `comment` as a template value, its name and text escaped for HTML
+pub fn entry(comment: &Comment) -> Value {
+    let mut value = comment.into_json();
+    value.set("name", op::escape_html(&comment.name));
+    value.set("body", op::escape_html(&comment.body).replace('\n', "<br>"));
+    value.set("guest", comment.is_guest());
+    value
+}
+
+/// The poster of `req`, `None` for a guest without a known address
+fn poster(req: &HttpReqCtx) -> Option<Poster> {
+    match req.signed_in() {
+        Some(user) => Some(Poster::User {
+            id: user.get_user_id().to_string(),
+            name: user.get_display_name().to_string(),
+            shadowbanned: crate::moderation::user_flag(req).is_some_and(|flag| flag.shadowbanned),
+        }),
+        None => proxy::client_ip(req).map(|address| Poster::Guest { address: address.to_string() }),
+    }
+}
+
+/// Build the template value of the comments on `content` for
+/// `default/templates/base/comments.html`
+///
+/// # Returns
+/// ```json
+/// { "content": "blog/hello", "count": 2, "can_post": bool, "guest": bool,
+///   "max_length": 4000, "honeypot": {...}, "reasons": "spam, abuse, ...",
+///   "comments": [{ "id": ..., "name": ..., "body": ..., "created": ..., "depth": 1,
+///                  "indent": 0, "can_reply": bool }] }
+/// ```
+pub fn thread(req: &HttpReqCtx, content: &str) -> Value {
+    let viewer = match poster(req) {
+        Some(Poster::User { id, .. }) => Some(id),
+        _ => None,
+    };
+    let signed_in = viewer.is_some();
+    let can_post = signed_in || COMMENTS_SETTINGS.guests;
+    let comments: Vec<Value> = threaded(&COMMENTS.read().unwrap(), content, viewer.as_deref())
+        .iter()
+        .map(|(comment, depth)| {
+            let mut value = entry(comment);
+            // Pages show names, not the accounts behind them
+            for private in ["author", "status", "reason", "shadow"] {
+                value.delete(private);
+            }
+            value.set("depth", *depth);
+            value.set("indent", (depth - 1) * 2);
+            value.set("can_reply", can_post && *depth < COMMENTS_SETTINGS.max_depth);
+            value
+        })
+        .collect();
+    object!({
+        content: op::escape_html(content),
+        count: comments.len(),
+        comments: comments,
+        can_post: can_post,
+        guest: !signed_in,
+        max_length: COMMENTS_SETTINGS.max_length,
+        honeypot: honeypot::fields(captcha::COMMENT),
+        reasons: crate::moderation::settings().reasons.join(", "),
+    })
+}
+
+/// The answer to a failed post
+fn error_response(err: CommentError) -> HttpResponse {
+    let status = match err {
+        CommentError::SignInRequired => StatusCode::UNAUTHORIZED,
+        CommentError::NotFound | CommentError::ParentNotFound => StatusCode::NOT_FOUND,
+        CommentError::Duplicate => StatusCode::CONFLICT,
+        CommentError::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+        CommentError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::BAD_REQUEST,
+    };
+    json_response(object!({ success: false, message: err.to_string() })).status(status)
+}
+
+endpoint! {
+    APP.url("/op/comments"),
+
+    /// Read or post comments
+    ///
+    /// # Request
+    /// `GET /op/comments?content=<key>`, or `POST /op/comments` with the
+    /// form fields `content`, `body`, optionally `parent` (the id of the
+    /// comment replied to) and, for guests, `name`
+    ///
+    /// # Response
+    /// GET: `{"success": true, "comments": [{"id": ..., "name": ..., "body": ..., "depth": 1}]}`
+    /// in reading order, text escaped for HTML.
+    /// POST: a redirect to the page the form was on, or with
+    /// `Accept: application/json`, `{"success": true, "pending": bool, "comment": {...}}`;
+    /// failures are `{"success": false, "message": "..."}` with `400`,
+    /// `401`, `404`, `409` for a repeated comment or `429`
+    pub comments_endpoint <HTTP> {
+        methods!(req, GET | HEAD | POST);
+        if req.method() != POST {
+            let content = req.query("content").unwrap_or_default();
+            if !valid_content(&content) {
+                return error_response(CommentError::InvalidContent);
+            }
+            let comments = thread(req, &content).get("comments").clone();
+            return json_response(object!({ success: true, comments: comments }));
+        }
+        let wants_json = req.header_str("accept").is_some_and(|accept| accept.contains("application/json"));
+        let Some(poster) = poster(req) else {
+            return error_response(CommentError::SignInRequired);
+        };
+        let form = req.form_or_default().await;
+        if let Err(err) = honeypot::verify_form(captcha::COMMENT, form) {
+            return error_response(CommentError::Bot(err));
+        }
+        let new = NewComment {
+            content: form.get_or_default("content").trim().to_string(),
+            parent: Some(form.get_or_default("parent").trim().to_string()).filter(|parent| !parent.is_empty()),
+            body: form.get_or_default("body").to_string(),
+            name: form.get_or_default("name").to_string(),
+        };
+        match post(new, &poster) {
+            Ok(comment) if wants_json => json_response(object!({
+                success: true,
+                pending: comment.status == CommentStatus::Pending,
+                comment: entry(&comment),
+            })),
+            Ok(comment) => redirect_response(&format!("{}#comment-{}", op::from(req), comment.id)),
+            Err(err) => error_response(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn comment(id: &str, parent: Option<&str>, status: CommentStatus) -> Comment {
+        Comment {
+            id: id.into(),
+            content: "blog/hello".into(),
+            parent: parent.map(Into::into),
+            author: "1@local".into(),
+            name: "Admin".into(),
+            body: id.into(),
+            created: 0,
+            status,
+            reason: None,
+            shadow: false,
+        }
+    }
+
+    #[test]
+    fn threads_follow_replies_and_hide_unapproved_branches() {
+        let comments = vec![
+            comment("a", None, CommentStatus::Approved),
+            comment("b", None, CommentStatus::Approved),
+            comment("a1", Some("a"), CommentStatus::Approved),
+            comment("a1x", Some("a1"), CommentStatus::Approved),
+            comment("p", None, CommentStatus::Pending),
+            comment("p1", Some("p"), CommentStatus::Approved),
+        ];
+        let order: Vec<(String, usize)> =
+            threaded(&comments, "blog/hello", None).into_iter().map(|(comment, depth)| (comment.id, depth)).collect();
+        assert_eq!(
+            order,
+            vec![("a".into(), 1), ("a1".into(), 2), ("a1x".into(), 3), ("b".into(), 1)]
+        );
+        assert!(threaded(&comments, "blog/other", None).is_empty());
+        assert_eq!(depth(&comments, &comments[3]), 3);
+
+        let shadowed = vec![Comment { shadow: true, ..comment("s", None, CommentStatus::Approved) }];
+        assert!(threaded(&shadowed, "blog/hello", None).is_empty());
+        assert!(threaded(&shadowed, "blog/hello", Some("2@local")).is_empty());
+        assert_eq!(threaded(&shadowed, "blog/hello", Some("1@local")).len(), 1);
+    }
+
+    #[test]
+    fn spam_is_held_and_posters_are_rate_limited() {
+        let settings = CommentSettings {
+            blocked_words: vec!["casino".into()],
+            rate_count: 2,
+            rate_seconds: 60,
+            ..CommentSettings::default()
+        };
+        assert_eq!(settings.spam_reason("Nice post, thanks!"), None);
+        assert!(settings.spam_reason("see http://a http://b www.c").is_some());
+        assert!(settings.spam_reason("Best CASINO bonus").is_some());
+        assert!(settings.spam_reason("THIS IS THE BEST POST I HAVE EVER READ").is_some());
+
+        let mut recent = HashMap::new();
+        assert!(settings.allow(&mut recent, "1@local", 1000));
+        assert!(settings.allow(&mut recent, "1@local", 1010));
+        assert!(!settings.allow(&mut recent, "1@local", 1020));
+        assert!(settings.allow(&mut recent, "2@local", 1020));
+        assert!(settings.allow(&mut recent, "1@local", 1061));
+        assert_eq!(op::escape_html("<b>\"x\" & 'y'</b>"), "&lt;b&gt;&quot;x&quot; &amp; &#39;y&#39;&lt;/b&gt;");
+    }
+}
@@ -0,0 +1,289 @@
+//! consent.rs
+//!
+//! Cookie consent: the categories visitors may agree to, a banner asking
+//! them, and where their answer is kept. Categories and the banner text
+//! come from `programfiles/op/consent.json`:
+//!
+//! ```json
+//! {
+//!     "version": 1,
+//!     "categories": [
+//!         { "name": "necessary", "required": true },
+//!         { "name": "analytics" }
+//!     ],
+//!     "text": {
+//!         "en": {
+//!             "message": "We use cookies to run this site and, with your consent, to count visits.",
+//!             "accept": "Accept all", "reject": "Only necessary", "save": "Save choices",
+//!             "necessary": "Necessary", "analytics": "Analytics"
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! `required` categories are always on and cannot be declined. Until a
+//! visitor answers, every page carries the banner (`base/consent.html`, fed
+//! by `pageprop.consent`), which posts to `POST /op/consent`. The answer is
+//! kept in the session, and for signed-in local accounts also in the
+//! profile (`consent`), so it follows them to new sessions. Raising
+//! `version` asks everybody again.
+//!
+//! Code checks an answer with [`consent_given`], templates with
+//! `pageprop.consented`:
+//!
+//! ```html
+//! -[ if pageprop["consented"]["analytics"] ]- <script ...></script> -[ endif ]-
+//! ```
+//!
+//! Without the file, or without any optional category, no banner is shown.
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+
+use crate::local_auth::LOCAL_AUTH;
+use crate::op::{self, APP};
+use crate::ctx::SfxCtx;
+use crate::methods;
+
+static CONSENT: Lazy<ConsentSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/consent.json");
+    ConsentSettings::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None))
+});
+
+/// Session and profile key of the answer
+pub const CONSENT_KEY: &str = "consent";
+
+/// A kind of cookie or tracker visitors agree to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Category {
+    pub name: String,
+    /// Always on, not offered as a choice
+    pub required: bool,
+}
+
+/// The parsed content of `consent.json`
+#[derive(Debug, Clone)]
+pub struct ConsentSettings {
+    pub version: i64,
+    pub categories: Vec<Category>,
+    /// Banner text per language
+    pub text: Value,
+}
+
+impl ConsentSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let categories = match value.get("categories") {
+            Value::List(categories) => categories
+                .iter()
+                .map(|category| Category {
+                    name: category.get("name").string(),
+                    required: category.get("required").boolean(),
+                })
+                .filter(|category| !category.name.is_empty())
+                .collect(),
+            _ => Vec::new(),
+        };
+        Self {
+            version: value.get("version").integer(),
+            categories,
+            text: value.get("text").clone(),
+        }
+    }
+
+    /// Whether there is anything to ask
+    pub fn is_enabled(&self) -> bool {
+        self.categories.iter().any(|category| !category.required)
+    }
+
+    /// The answer choosing the optional categories for which `choose` is
+    /// true, as kept in the session
+    pub fn answer(&self, now: u64, choose: impl Fn(&str) -> bool) -> Value {
+        let mut given = Value::new_dict();
+        for category in &self.categories {
+            given.set(&category.name, category.required || choose(&category.name));
+        }
+        object!({ version: self.version, time: now, given: given })
+    }
+
+    /// Whether `answer` is one to the current categories
+    pub fn is_current(&self, answer: &Value) -> bool {
+        matches!(answer.get("version"), Value::Numerical(_)) && answer.get("version").integer() == self.version
+    }
+
+    /// Whether `category` is agreed to under `answer`. Unknown categories
+    /// never are.
+    pub fn given(&self, answer: &Value, category: &str) -> bool {
+        match self.categories.iter().find(|known| known.name == category) {
+            Some(known) if known.required => true,
+            Some(_) => self.is_current(answer) && answer.get("given").get(category).boolean(),
+            None => false,
+        }
+    }
+
+    /// The banner text in `lang`, falling back to the default language
+    fn text(&self, lang: &str) -> Value {
+        match self.text.get(lang) {
+            Value::Dict(_) => self.text.get(lang).clone(),
+            _ => self.text.get(op::default_lang()).clone(),
+        }
+    }
+
+    /// `pageprop.consent` and `pageprop.consented` for a visitor who gave
+    /// `answer` (`Value::None` before answering)
+    pub fn pageprop(&self, answer: &Value, lang: &str) -> (Value, Value) {
+        let text = self.text(lang);
+        let mut consented = Value::new_dict();
+        let categories: Vec<Value> = self
+            .categories
+            .iter()
+            .map(|category| {
+                let given = self.given(answer, &category.name);
+                consented.set(&category.name, given);
+                let label = match text.get(&category.name) {
+                    Value::Str(label) => label.clone(),
+                    _ => category.name.clone(),
+                };
+                object!({ name: &category.name, label: label, required: category.required, given: given })
+            })
+            .collect();
+        let consent = object!({
+            banner: self.is_enabled() && !self.is_current(answer),
+            categories: categories,
+            text: text,
+        });
+        (consent, consented)
+    }
+}
+
+/// The loaded consent settings
+pub fn settings() -> &'static ConsentSettings {
+    &CONSENT
+}
+
+/// The answer of the visitor of `req`, `Value::None` before they gave one
+pub fn answer(req: &HttpReqCtx) -> Value {
+    req.session()
+        .ok()
+        .and_then(|session| session.get(CONSENT_KEY))
+        .cloned()
+        .unwrap_or(Value::None)
+}
+
+/// Whether the visitor of `req` agreed to `category`, for instance before
+/// loading a tracker: `consent_given(req, "analytics")`
+pub fn consent_given(req: &HttpReqCtx, category: &str) -> bool {
+    CONSENT.given(&answer(req), category)
+}
+
+middleware! {
+    /// Brings the answer kept in the profile of a signed-in local account
+    /// into a session that has none. Add it after `UserFetch`.
+    pub RestoreConsent <HTTP> {
+        if CONSENT.is_enabled()
+            && answer(&req).is_none()
+            && let Some(uid) = req.local_uid()
+            && let Some(user) = LOCAL_AUTH.admin_get_user(uid).await
+            && CONSENT.is_current(user.profile.get(CONSENT_KEY))
+            && let Ok(session) = req.session_mut()
+        {
+            session.insert(CONSENT_KEY.to_string(), user.profile.get(CONSENT_KEY).clone());
+        }
+        next(req).await
+    }
+}
+
+endpoint! {
+    APP.url("/op/consent"),
+
+    /// Record the answer to the consent banner
+    ///
+    /// # Request
+    /// `POST /op/consent`
+    /// Form: `choice` = `accept` (every category), `reject` (only the
+    /// required ones) or `save` (the categories sent as checked fields,
+    /// `analytics=on`)
+    ///
+    /// # Response
+    /// A redirect to the page the banner was on, or with
+    /// `Accept: application/json`, `{"success": true, "consented": {"analytics": true, ...}}`
+    pub record_consent <HTTP> {
+        let wants_json = req.header_str("accept").is_some_and(|accept| accept.contains("application/json"));
+        methods!(req, POST);
+        let form = req.form_or_default().await;
+        let choice = form.get_or_default("choice").to_string();
+        let checked: Vec<String> = CONSENT
+            .categories
+            .iter()
+            .filter(|category| matches!(form.get_or_default(&category.name).as_str(), "on" | "true" | "1"))
+            .map(|category| category.name.clone())
+            .collect();
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        let answer = match choice.as_str() {
+            "accept" => CONSENT.answer(now, |_| true),
+            "reject" => CONSENT.answer(now, |_| false),
+            "save" => CONSENT.answer(now, |name| checked.iter().any(|checked| checked == name)),
+            _ => {
+                return json_response(object!({ success: false, message: "Unknown choice" }))
+                    .status(StatusCode::BAD_REQUEST);
+            }
+        };
+        if let Ok(session) = req.session_mut() {
+            session.insert(CONSENT_KEY.to_string(), answer.clone());
+        }
+        if let Some(uid) = req.local_uid()
+            && let Err(err) = LOCAL_AUTH.set_profile_entry(uid, CONSENT_KEY, answer.clone()).await
+        {
+            tracing::warn!(uid, ?err, "Failed to keep the consent in the profile");
+        }
+        if wants_json {
+            json_response(object!({ success: true, consented: answer.get("given").clone() }))
+        } else {
+            redirect_response(&op::from(req))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestRequest};
+
+    #[test]
+    fn answers_count_for_their_version_only() {
+        let settings = ConsentSettings::from_value(&object!({
+            version: 2,
+            categories: [{ name: "necessary", required: true }, { name: "analytics" }, { name: "ads" }],
+        }));
+        assert!(settings.is_enabled());
+        assert!(settings.given(&Value::None, "necessary"));
+        assert!(!settings.given(&Value::None, "analytics"));
+
+        let answer = settings.answer(10, |name| name == "analytics");
+        assert!(settings.given(&answer, "analytics"));
+        assert!(!settings.given(&answer, "ads"));
+        assert!(!settings.given(&answer, "unknown"));
+        assert!(settings.answer(10, |_| false).get("given").get("necessary").boolean());
+
+        let (consent, consented) = settings.pageprop(&answer, "en");
+        assert!(!consent.get("banner").boolean());
+        assert!(consented.get("analytics").boolean());
+        assert!(settings.pageprop(&Value::None, "en").0.get("banner").boolean());
+
+        let newer = ConsentSettings { version: 3, ..settings };
+        assert!(!newer.given(&answer, "analytics"));
+        assert!(newer.pageprop(&answer, "en").0.get("banner").boolean());
+        assert!(!ConsentSettings::from_value(&object!({ categories: [{ name: "necessary", required: true }] })).is_enabled());
+    }
+
+    #[tokio::test]
+    async fn answers_are_kept_in_the_session() {
+        let mut req = TestRequest::post("/op/consent").header("Accept", "application/json").form(&[("choice", "reject")]).build();
+        let res = testing::respond(record_consent(&mut req).await, &mut req);
+        assert!(testing::json(&res).get("success").boolean());
+        assert!(req.session().unwrap().get(CONSENT_KEY).is_some());
+
+        let mut req = TestRequest::post("/op/consent").form(&[("choice", "maybe")]).build();
+        let res = testing::respond(record_consent(&mut req).await, &mut req);
+        assert_eq!(testing::status(&res), 400);
+    }
+}
@@ -0,0 +1,128 @@
+//! ctx.rs
+//!
+//! What the middleware of sfx leaves on a request, read through
+//! [`SfxCtx`] rather than `req.params` lookups that panic when a
+//! middleware is missing:
+//!
+//! ```rust,ignore
+//! use sfx::prelude::*;
+//!
+//! endpoint! {
+//!     APP.url("/greet"),
+//!     pub greet <HTTP> {
+//!         let Some(user) = req.signed_in() else {
+//!             return redirect_response("/user/login");
+//!         };
+//!         text_response(format!("Hello {} ({})", user.get_username(), req.lang()))
+//!     }
+//! }
+//! ```
+
+use hotaru::http::*;
+use htmstd::session::CSessionRW;
+
+use crate::user::{Server, User};
+
+/// A middleware that should have run on the request did not
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CtxError {
+    /// `UserFetch` left no user
+    NoUser,
+    /// `KeyedSession` left no session
+    NoSession,
+}
+
+impl std::fmt::Display for CtxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CtxError::NoUser => write!(f, "No user on the request; is UserFetch in the middleware chain?"),
+            CtxError::NoSession => write!(f, "No session on the request; is KeyedSession in the middleware chain?"),
+        }
+    }
+}
+
+impl std::error::Error for CtxError {}
+
+/// The request as seen by sfx
+pub trait SfxCtx {
+    /// The user `UserFetch` found, a guest when nobody is signed in
+    fn user(&self) -> Result<&User, CtxError>;
+
+    /// The user, or a guest of [`SfxCtx::host`] when there is none
+    fn user_or_guest(&self) -> User;
+
+    /// The signed-in user, `None` for guests
+    fn signed_in(&self) -> Option<&User>;
+
+    /// The uid of the signed-in account of the local account store
+    fn local_uid(&self) -> Option<u32>;
+
+    /// The language of the page, see `op::lang`
+    fn lang(&mut self) -> String;
+
+    /// The auth server the session signed in with, the local one by default
+    fn host(&self) -> Server;
+
+    fn session(&self) -> Result<&CSessionRW, CtxError>;
+
+    fn session_mut(&mut self) -> Result<&mut CSessionRW, CtxError>;
+}
+
+impl SfxCtx for HttpReqCtx {
+    fn user(&self) -> Result<&User, CtxError> {
+        self.params.get::<User>().ok_or(CtxError::NoUser)
+    }
+
+    fn user_or_guest(&self) -> User {
+        self.user().cloned().unwrap_or_else(|_| User::guest(self.host()))
+    }
+
+    fn signed_in(&self) -> Option<&User> {
+        self.user().ok().filter(|user| !user.get_user_id().is_guest())
+    }
+
+    fn local_uid(&self) -> Option<u32> {
+        self.signed_in().filter(|user| user.get_server().is_local()).map(|user| user.get_uid() as u32)
+    }
+
+    fn lang(&mut self) -> String {
+        crate::op::lang(self)
+    }
+
+    fn host(&self) -> Server {
+        self.session()
+            .ok()
+            .and_then(|session| session.get("host"))
+            .map(|host| Server::from_string(&host.string()))
+            .unwrap_or(Server::Local)
+    }
+
+    fn session(&self) -> Result<&CSessionRW, CtxError> {
+        self.params.get::<CSessionRW>().ok_or(CtxError::NoSession)
+    }
+
+    fn session_mut(&mut self) -> Result<&mut CSessionRW, CtxError> {
+        self.params.get_mut::<CSessionRW>().ok_or(CtxError::NoSession)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user::UserID;
+
+    #[test]
+    fn missing_middleware_gives_errors_and_guests() {
+        let mut req = HttpReqCtx::new_client(String::new(), HttpSafety::default());
+        assert_eq!(req.user().err(), Some(CtxError::NoUser));
+        assert_eq!(req.session().err(), Some(CtxError::NoSession));
+        assert!(req.user_or_guest().get_user_id().is_guest());
+        assert!(req.host().is_local() && req.local_uid().is_none());
+
+        req.params.set(User::new(UserID::new(4, Server::Local), "u".into(), "e".into(), true, true));
+        assert_eq!(req.signed_in().map(User::get_username), Some("u"));
+        assert_eq!(req.local_uid(), Some(4));
+        req.params.set(User::guest(Server::Local));
+        assert!(req.user().is_ok() && req.signed_in().is_none());
+    }
+}
@@ -0,0 +1,178 @@
+//! dev.rs
+//!
+//! Development mode, on when the server runs with `SFX_ENV=development`:
+//!
+//! - the navbar, footer, supported languages and l10n strings of
+//!   `programfiles/op` are read again when their files change, and template
+//!   edits are logged (templates are read at each render, so they show on the
+//!   next request without a restart);
+//! - an `Err` of a handler or middleware answers a detailed HTML page with
+//!   the error and the request headers in place of the bare status page, as
+//!   do panics, with their backtrace, through [`crate::recovery`];
+//! - every response is sent `Cache-Control: no-store`, so the browser never
+//!   shows a stale page or asset.
+//!
+//! Nothing of it runs in production or beta: [`DevMode`] then passes the
+//! request on untouched and [`start`] does nothing.
+
+use hotaru::http::*;
+use hotaru::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::op::{self, escape_html};
+
+/// How often the watched files are checked
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Whether the server runs in development mode
+pub fn enabled() -> bool {
+    op::run_mode() == RunMode::Development
+}
+
+/// Start watching the op files and templates, in development mode
+pub fn start() {
+    if !enabled() {
+        return;
+    }
+    tracing::warn!("Development mode: detailed error pages, no caching, reloading op files");
+    tokio::spawn(async move {
+        let mut ui_files = latest_change(&ui_file_paths());
+        let mut templates = latest_change(&[PathBuf::from("templates")]);
+        loop {
+            tokio::time::sleep(WATCH_INTERVAL).await;
+            let current = latest_change(&ui_file_paths());
+            if current != ui_files {
+                ui_files = current;
+                op::reload_ui_files();
+                tracing::info!("Navbar, footer and l10n files reloaded");
+                crate::events::publish(crate::events::ConfigReloaded { source: "l10n".to_string() });
+            }
+            let current = latest_change(&[PathBuf::from("templates")]);
+            if current != templates {
+                templates = current;
+                tracing::info!("Templates changed, served from the next request");
+            }
+        }
+    });
+}
+
+fn ui_file_paths() -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = op::UI_FILES.iter().map(|name| op::programfiles().join("op").join(name)).collect();
+    paths.extend(crate::l10n::fluent_dir());
+    paths
+}
+
+/// The latest modification time of `paths` and the files under them
+fn latest_change(paths: &[PathBuf]) -> Option<SystemTime> {
+    fn walk(path: &Path, latest: &mut Option<SystemTime>) {
+        let Ok(meta) = std::fs::metadata(path) else {
+            return;
+        };
+        if let Ok(modified) = meta.modified() {
+            *latest = (*latest).max(Some(modified));
+        }
+        if meta.is_dir()
+            && let Ok(entries) = std::fs::read_dir(path)
+        {
+            for entry in entries.flatten() {
+                walk(&entry.path(), latest);
+            }
+        }
+    }
+    let mut latest = None;
+    for path in paths {
+        walk(path, &mut latest);
+    }
+    latest
+}
+
+/// The headers of `request`, for [`error_page`]
+pub fn headers(request: &HttpRequest) -> Vec<(String, String)> {
+    let mut headers: Vec<(String, String)> = request
+        .meta
+        .get_header_hashmap()
+        .keys()
+        .map(|name| (name.clone(), request.meta.get_header(name.as_str()).unwrap_or_default()))
+        .collect();
+    headers.sort();
+    headers
+}
+
+/// What went wrong with a request, for [`error_page`]
+#[derive(Debug, Clone)]
+pub struct Failure {
+    /// `Error` or `Panic`
+    pub kind: &'static str,
+    pub message: String,
+    /// The debug form of the error, or the backtrace of the panic
+    pub detail: String,
+}
+
+/// The development error page of `failure` on `method path`
+pub fn error_page(status: StatusCode, method: &str, path: &str, headers: &[(String, String)], failure: &Failure) -> HttpResponse {
+    let rows: String = headers
+        .iter()
+        .map(|(name, value)| format!("<tr><th>{}</th><td>{}</td></tr>", escape_html(name), escape_html(value)))
+        .collect();
+    let body = format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{status} {kind}</title>\
+         <style>body{{font-family:sans-serif;margin:2em}}pre{{background:#f6f6f6;padding:1em;overflow:auto}}\
+         th{{text-align:left;padding-right:1em}}</style></head><body>\
+         <h1>{status} &middot; {kind}</h1><p><code>{method} {path}</code></p>\
+         <h2>{message}</h2><pre>{detail}</pre><h3>Request headers</h3><table>{rows}</table>\
+         <p><small>Shown because SFX_ENV=development.</small></p></body></html>",
+        status = status.as_u16(),
+        kind = failure.kind,
+        method = escape_html(method),
+        path = escape_html(path),
+        message = escape_html(&failure.message),
+        detail = escape_html(&failure.detail),
+        rows = rows,
+    );
+    html_response(body).status(status)
+}
+
+middleware! {
+    /// Development mode around the whole chain: error pages for `Err`,
+    /// `Cache-Control: no-store` on every response. A pass-through
+    /// outside development mode.
+    pub DevMode <HTTP> {
+        if !enabled() {
+            return next(req).await;
+        }
+        let method = req.method().to_string();
+        let path = req.path();
+        let headers = headers(&req.request);
+        let err = match next(req).await {
+            Ok(mut req) => {
+                req.response.meta.set_attribute("Cache-Control", "no-store");
+                return Ok(req);
+            }
+            Err(err) => err,
+        };
+        let status: StatusCode = (&err).into();
+        let failure = Failure { kind: "Error", message: err.to_string(), detail: format!("{:#?}", err) };
+        tracing::error!(%method, %path, kind = failure.kind, message = %failure.message, "Request failed");
+        let mut req = HttpReqCtx::new_client(String::new(), HttpSafety::default());
+        req.response = error_page(status, &method, &path, &headers, &failure);
+        req.response.meta.set_attribute("Cache-Control", "no-store");
+        Ok(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_pages_escape_what_they_show() {
+        let failure = Failure { kind: "Panic", message: "bad <input>".to_string(), detail: "at src/x.rs".to_string() };
+        let headers = vec![("host".to_string(), "<evil>".to_string())];
+        let page = error_page(StatusCode::INTERNAL_SERVER_ERROR, "GET", "/x?a=<b>", &headers, &failure);
+        assert_eq!(crate::testing::status(&page), 500);
+        let body = crate::testing::text(&page);
+        assert!(body.contains("bad &lt;input&gt;") && body.contains("/x?a=&lt;b&gt;") && body.contains("&lt;evil&gt;"));
+        assert!(body.contains("at src/x.rs"));
+    }
+}
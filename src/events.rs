@@ -0,0 +1,373 @@
+//! events.rs
+//!
+//! An in-process event bus. Subsystems publish what happened and others
+//! subscribe to it, so that mail, webhooks, audit trails or notifications
+//! need no call from the code raising the event:
+//!
+//! ```rust,ignore
+//! use sfx::events::{self, UserRegistered};
+//!
+//! events::subscribe(|event: &UserRegistered| tracing::info!(uid = event.uid, "Welcome mail queued"));
+//! ```
+//!
+//! An application adds its own events by implementing [`Event`] and
+//! publishing them the same way:
+//!
+//! ```rust,ignore
+//! struct OrderPlaced { order: u64 }
+//!
+//! impl sfx::events::Event for OrderPlaced {
+//!     fn name(&self) -> &'static str { "shop.order_placed" }
+//!     fn to_json(&self) -> Value { object!({ order: self.order }) }
+//! }
+//!
+//! sfx::events::publish(OrderPlaced { order: 7 });
+//! ```
+//!
+//! Subscribers run in order on the task publishing the event, after the
+//! change it reports has been made. Work that may block or take long is
+//! better handed to `tokio::spawn`.
+
+use hotaru::prelude::*;
+use std::any::{Any, TypeId};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// Something that happened, passed to its subscribers by [`publish`]
+pub trait Event: Any + Send + Sync {
+    /// The name of the kind of event, like `user.registered`
+    fn name(&self) -> &'static str;
+
+    /// The event as JSON, for the subscribers of every event
+    fn to_json(&self) -> Value;
+}
+
+type Handler = Arc<dyn Fn(&dyn Event) + Send + Sync>;
+
+struct Subscriber {
+    id: u64,
+    /// The event followed, `None` for every event
+    kind: Option<TypeId>,
+    handler: Handler,
+}
+
+static SUBSCRIBERS: Lazy<RwLock<Vec<Subscriber>>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A subscription, to pass to [`unsubscribe`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(u64);
+
+fn add(kind: Option<TypeId>, handler: Handler) -> SubscriptionId {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    SUBSCRIBERS.write().unwrap().push(Subscriber { id, kind, handler });
+    SubscriptionId(id)
+}
+
+/// Call `handler` with every published `E`
+pub fn subscribe<E: Event>(handler: impl Fn(&E) + Send + Sync + 'static) -> SubscriptionId {
+    add(
+        Some(TypeId::of::<E>()),
+        Arc::new(move |event: &dyn Event| {
+            if let Some(event) = (event as &dyn Any).downcast_ref::<E>() {
+                handler(event)
+            }
+        }),
+    )
+}
+
+/// Call `handler` with every published event, whatever its type
+pub fn subscribe_all(handler: impl Fn(&dyn Event) + Send + Sync + 'static) -> SubscriptionId {
+    add(None, Arc::new(handler))
+}
+
+/// Stop a subscription. Returns whether it was still there.
+pub fn unsubscribe(id: SubscriptionId) -> bool {
+    let mut subscribers = SUBSCRIBERS.write().unwrap();
+    let before = subscribers.len();
+    subscribers.retain(|subscriber| subscriber.id != id.0);
+    subscribers.len() != before
+}
+
+/// Pass `event` to its subscribers
+pub fn publish<E: Event>(event: E) {
+    let kind = TypeId::of::<E>();
+    // Handlers may publish or subscribe in turn, so none runs under the lock
+    let handlers: Vec<Handler> = SUBSCRIBERS
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|subscriber| subscriber.kind.is_none_or(|followed| followed == kind))
+        .map(|subscriber| subscriber.handler.clone())
+        .collect();
+    for handler in handlers {
+        handler(&event);
+    }
+}
+
+fn address(from: &Option<IpAddr>) -> String {
+    from.map(|ip| ip.to_string()).unwrap_or_default()
+}
+
+/// A local account was made
+#[derive(Debug, Clone)]
+pub struct UserRegistered {
+    pub uid: u32,
+    pub username: String,
+}
+
+impl Event for UserRegistered {
+    fn name(&self) -> &'static str {
+        "user.registered"
+    }
+
+    fn to_json(&self) -> Value {
+        object!({ uid: self.uid, username: &self.username })
+    }
+}
+
+/// A local account signed in
+#[derive(Debug, Clone)]
+pub struct LoginSucceeded {
+    pub uid: u32,
+    /// The client address, when known
+    pub from: Option<IpAddr>,
+}
+
+impl Event for LoginSucceeded {
+    fn name(&self) -> &'static str {
+        "user.login_succeeded"
+    }
+
+    fn to_json(&self) -> Value {
+        object!({ uid: self.uid, from: address(&self.from) })
+    }
+}
+
+/// A sign in to a local account was refused
+#[derive(Debug, Clone)]
+pub struct LoginFailed {
+    pub uid: u32,
+    pub from: Option<IpAddr>,
+    /// Why, like "Password mismatch" or "Login blocked"
+    pub reason: String,
+}
+
+impl Event for LoginFailed {
+    fn name(&self) -> &'static str {
+        "user.login_failed"
+    }
+
+    fn to_json(&self) -> Value {
+        object!({ uid: self.uid, from: address(&self.from), reason: &self.reason })
+    }
+}
+
+/// A local account signed out
+#[derive(Debug, Clone)]
+pub struct LoggedOut {
+    pub uid: u32,
+    /// Whether every session of the account ended
+    pub everywhere: bool,
+}
+
+impl Event for LoggedOut {
+    fn name(&self) -> &'static str {
+        "user.logged_out"
+    }
+
+    fn to_json(&self) -> Value {
+        object!({ uid: self.uid, everywhere: self.everywhere })
+    }
+}
+
+/// A local account changed its password
+#[derive(Debug, Clone)]
+pub struct PasswordChanged {
+    pub uid: u32,
+}
+
+impl Event for PasswordChanged {
+    fn name(&self) -> &'static str {
+        "user.password_changed"
+    }
+
+    fn to_json(&self) -> Value {
+        object!({ uid: self.uid })
+    }
+}
+
+/// Configuration was read again while running
+#[derive(Debug, Clone)]
+pub struct ConfigReloaded {
+    /// What was reloaded, like `tls`
+    pub source: String,
+}
+
+impl Event for ConfigReloaded {
+    fn name(&self) -> &'static str {
+        "config.reloaded"
+    }
+
+    fn to_json(&self) -> Value {
+        object!({ source: &self.source })
+    }
+}
+
+/// A setting of [`crate::settings`] was made, changed or removed
+impl Event for crate::settings::Change {
+    fn name(&self) -> &'static str {
+        "setting.changed"
+    }
+
+    fn to_json(&self) -> Value {
+        let or_null = |value: &Option<Value>| value.clone().unwrap_or(Value::None);
+        object!({ namespace: &self.namespace, key: &self.key, old: or_null(&self.old), new: or_null(&self.new) })
+    }
+}
+
+/// A comment was posted, visible or held for moderation
+#[derive(Debug, Clone)]
+pub struct CommentPosted {
+    pub id: String,
+    /// The content key of the page it is on
+    pub content: String,
+    /// The user who posted it, empty for guests
+    pub author: String,
+    /// Whether others see it, not held for moderation or shadowbanned
+    pub visible: bool,
+}
+
+impl Event for CommentPosted {
+    fn name(&self) -> &'static str {
+        "comment.posted"
+    }
+
+    fn to_json(&self) -> Value {
+        object!({ id: &self.id, content: &self.content, author: &self.author, visible: self.visible })
+    }
+}
+
+/// Something was reported to the moderators
+#[derive(Debug, Clone)]
+pub struct ReportFiled {
+    pub id: String,
+    pub kind: String,
+    pub target: String,
+    pub reason: String,
+    pub by: String,
+}
+
+impl Event for ReportFiled {
+    fn name(&self) -> &'static str {
+        "moderation.report_filed"
+    }
+
+    fn to_json(&self) -> Value {
+        object!({ id: &self.id, kind: &self.kind, target: &self.target, reason: &self.reason, by: &self.by })
+    }
+}
+
+/// A form of [`crate::forms`] was answered
+#[derive(Debug, Clone)]
+pub struct FormSubmitted {
+    pub form: String,
+    /// The id of the stored submission
+    pub submission: String,
+    /// The user who answered, `guest` for guests
+    pub user: String,
+}
+
+impl Event for FormSubmitted {
+    fn name(&self) -> &'static str {
+        "form.submitted"
+    }
+
+    fn to_json(&self) -> Value {
+        object!({ form: &self.form, submission: &self.submission, user: &self.user })
+    }
+}
+
+/// The p95 latency of a route or an upstream host went over its threshold
+/// in `latency.json`, see [`crate::latency`]
+#[derive(Debug, Clone)]
+pub struct LatencyExceeded {
+    /// `route` or `upstream`
+    pub kind: String,
+    /// The route, like `GET /blog/*`, or the address of the host
+    pub key: String,
+    pub p95_ms: u64,
+    pub threshold_ms: u64,
+}
+
+impl Event for LatencyExceeded {
+    fn name(&self) -> &'static str {
+        "latency.exceeded"
+    }
+
+    fn to_json(&self) -> Value {
+        object!({ kind: &self.kind, key: &self.key, p95_ms: self.p95_ms, threshold_ms: self.threshold_ms })
+    }
+}
+
+/// A route or upstream host of [`LatencyExceeded`] is back under its threshold
+#[derive(Debug, Clone)]
+pub struct LatencyRecovered {
+    pub kind: String,
+    pub key: String,
+    pub p95_ms: u64,
+}
+
+impl Event for LatencyRecovered {
+    fn name(&self) -> &'static str {
+        "latency.recovered"
+    }
+
+    fn to_json(&self) -> Value {
+        object!({ kind: &self.kind, key: &self.key, p95_ms: self.p95_ms })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    struct Pinged(u32);
+
+    impl Event for Pinged {
+        fn name(&self) -> &'static str {
+            "test.pinged"
+        }
+
+        fn to_json(&self) -> Value {
+            object!({ count: self.0 })
+        }
+    }
+
+    #[test]
+    fn events_reach_their_subscribers_until_unsubscribed() {
+        let typed = Arc::new(Mutex::new(Vec::new()));
+        let any = Arc::new(Mutex::new(Vec::new()));
+        let seen = typed.clone();
+        let id = subscribe(move |event: &Pinged| seen.lock().unwrap().push(event.0));
+        let seen = any.clone();
+        let all = subscribe_all(move |event| {
+            if event.name() == "test.pinged" {
+                seen.lock().unwrap().push(event.to_json().get("count").integer());
+            }
+        });
+
+        publish(Pinged(1));
+        publish(PasswordChanged { uid: 0 });
+        assert!(unsubscribe(id));
+        assert!(!unsubscribe(id));
+        publish(Pinged(2));
+        unsubscribe(all);
+
+        assert_eq!(*typed.lock().unwrap(), vec![1]);
+        assert_eq!(*any.lock().unwrap(), vec![1, 2]);
+    }
+}
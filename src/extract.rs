@@ -0,0 +1,178 @@
+//! extract.rs
+//!
+//! Typed path arguments and query parameters. A value that does not parse
+//! answers the request `400` with `{"success": false, "message": "Invalid
+//! <name>"}`, so endpoints neither unwrap nor quietly fall back:
+//!
+//! ```rust,ignore
+//! use sfx::extract::{Extract, Page};
+//!
+//! endpoint! {
+//!     APP.url("/shop/orders/<id>"),
+//!     pub order <HTTP> {
+//!         let id = match req.path_arg::<u64>("id") {
+//!             Ok(id) => id,
+//!             Err(res) => return res,
+//!         };
+//!         let page = match req.query_as::<Page>() {
+//!             Ok(page) => page,
+//!             Err(res) => return res,
+//!         };
+//!         json_response(order_lines(id, page.page, page.per_page))
+//!     }
+//! }
+//! ```
+//!
+//! hotaru's own `req.query("name")` keeps returning the text, which it has
+//! percent-decoded already; the typed form is `req.query_as::<T>()` for a
+//! [`FromQuery`] type and `req.query_arg::<T>("name")` for a single
+//! parameter. Path segments are left encoded by hotaru and decoded here.
+
+use hotaru::http::*;
+use hotaru::prelude::*;
+use std::str::FromStr;
+
+/// The page size when `per_page` is not given
+pub const DEFAULT_PER_PAGE: usize = 10;
+/// The largest page size a request may ask for
+pub const MAX_PER_PAGE: usize = 100;
+
+/// A path argument or query parameter that did not parse
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejection {
+    pub name: String,
+}
+
+impl Rejection {
+    pub fn new(name: &str) -> Self {
+        Self { name: name.to_string() }
+    }
+
+    pub fn into_response(self) -> HttpResponse {
+        json_response(object!({ success: false, message: format!("Invalid {}", self.name) })).status(StatusCode::BAD_REQUEST)
+    }
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid {}", self.name)
+    }
+}
+
+/// `text` of the path argument `name` as a `T`, percent-decoded first
+pub fn parse_arg<T: FromStr>(name: &str, text: &str) -> Result<T, Rejection> {
+    hotaru_lib::url_encoding::decode_url_owned(text).parse().map_err(|_| Rejection::new(name))
+}
+
+/// The query parameters of a request, as read by [`FromQuery`]
+pub struct Query<'a> {
+    get: &'a mut dyn FnMut(&str) -> Option<String>,
+}
+
+impl<'a> Query<'a> {
+    /// Parameters looked up by `get`, which returns the decoded text
+    pub fn new(get: &'a mut dyn FnMut(&str) -> Option<String>) -> Self {
+        Self { get }
+    }
+
+    /// The text of `name`
+    pub fn text(&mut self, name: &str) -> Option<String> {
+        (self.get)(name)
+    }
+
+    /// `name` as a `T`, `None` when the request leaves it out
+    pub fn parse<T: FromStr>(&mut self, name: &str) -> Result<Option<T>, Rejection> {
+        self.text(name).map(|text| text.parse().map_err(|_| Rejection::new(name))).transpose()
+    }
+}
+
+/// A type made of several query parameters
+pub trait FromQuery: Sized {
+    fn from_query(query: &mut Query) -> Result<Self, Rejection>;
+}
+
+/// `?page=&per_page=`: the 1-based page, and between 1 and
+/// [`MAX_PER_PAGE`] entries on it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Page {
+    pub page: usize,
+    pub per_page: usize,
+}
+
+impl Default for Page {
+    fn default() -> Self {
+        Self { page: 1, per_page: DEFAULT_PER_PAGE }
+    }
+}
+
+impl Page {
+    /// The index of the first entry of the page
+    pub fn offset(&self) -> usize {
+        (self.page - 1).saturating_mul(self.per_page)
+    }
+}
+
+impl FromQuery for Page {
+    fn from_query(query: &mut Query) -> Result<Self, Rejection> {
+        Ok(Self {
+            page: query.parse("page")?.unwrap_or(1).max(1),
+            per_page: query.parse("per_page")?.unwrap_or(DEFAULT_PER_PAGE).clamp(1, MAX_PER_PAGE),
+        })
+    }
+}
+
+/// Typed extraction from a request; `Err` is the `400` answer, returned
+/// as is by the endpoint
+#[allow(clippy::result_large_err)]
+pub trait Extract {
+    /// The path argument `name` of the route pattern, e.g. `<uid>`
+    fn path_arg<T: FromStr>(&mut self, name: &str) -> Result<T, HttpResponse>;
+    /// The query parameter `name`, `None` when left out
+    fn query_arg<T: FromStr>(&mut self, name: &str) -> Result<Option<T>, HttpResponse>;
+    /// The query parameters as a `T`
+    fn query_as<T: FromQuery>(&mut self) -> Result<T, HttpResponse>;
+}
+
+#[allow(clippy::result_large_err)]
+impl Extract for HttpReqCtx {
+    fn path_arg<T: FromStr>(&mut self, name: &str) -> Result<T, HttpResponse> {
+        let text = self.param(name).unwrap_or_default();
+        parse_arg(name, &text).map_err(Rejection::into_response)
+    }
+
+    fn query_arg<T: FromStr>(&mut self, name: &str) -> Result<Option<T>, HttpResponse> {
+        Query::new(&mut |key| self.query(key)).parse(name).map_err(Rejection::into_response)
+    }
+
+    fn query_as<T: FromQuery>(&mut self) -> Result<T, HttpResponse> {
+        T::from_query(&mut Query::new(&mut |key| self.query(key))).map_err(Rejection::into_response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestRequest};
+
+    #[test]
+    fn values_that_do_not_parse_answer_400() {
+        let mut req = TestRequest::get("/shop/orders?page=3&per_page=500&from=%2Fcart").build();
+        assert_eq!(req.query_as::<Page>().ok(), Some(Page { page: 3, per_page: MAX_PER_PAGE }));
+        assert_eq!(req.query_arg::<String>("from").ok(), Some(Some("/cart".to_string())));
+        assert_eq!(req.query_arg::<u32>("sort").ok(), Some(None));
+        let mut req = TestRequest::get("/search?q=%2541%20off").build();
+        assert_eq!(req.query_arg::<String>("q").ok(), Some(Some("%41 off".to_string())));
+
+        let mut req = TestRequest::get("/shop/orders?page=two").build();
+        let res = req.query_as::<Page>().unwrap_err();
+        assert_eq!(testing::status(&res), 400);
+        assert_eq!(testing::json(&res).get("message").string(), "Invalid page");
+
+        let mut req = TestRequest::get("/shop/orders").build();
+        assert_eq!(req.query_as::<Page>().ok(), Some(Page::default()));
+        assert_eq!(Page { page: 3, per_page: 25 }.offset(), 50);
+        assert_eq!(parse_arg::<u32>("uid", "12"), Ok(12));
+        assert_eq!(parse_arg::<u32>("uid", "-1"), Err(Rejection::new("uid")));
+        assert_eq!(parse_arg::<String>("slug", "50%25%20off"), Ok("50% off".to_string()));
+    }
+}
@@ -0,0 +1,210 @@
+//! flags.rs
+//!
+//! Feature flags and A/B experiments, from `programfiles/op/flags.json`:
+//!
+//! ```json
+//! {
+//!     "flags": { "new_footer": true },
+//!     "experiments": [
+//!         { "name": "signup_button", "variants": [
+//!             { "name": "control", "weight": 50 },
+//!             { "name": "green", "weight": 50 }
+//!         ] }
+//!     ]
+//! }
+//! ```
+//!
+//! Flags are plain switches. Each visitor is put in one variant of every
+//! experiment, in proportion to the weights, and stays there: the variant
+//! follows from a hash of the experiment and the signed-in user
+//! (`1@local`), or for guests a random id kept in the session. Signing in
+//! may therefore move a guest to another variant.
+//!
+//! Templates read `pageprop.flags` and `pageprop.experiments`:
+//!
+//! ```html
+//! -[ if pageprop["experiments"]["signup_button"] == "green" ]- ... -[ endif ]-
+//! ```
+//!
+//! and handlers [`flag_enabled`] and [`variant`]. Exposures and conversions
+//! are posted to `/op/analytics` (`sfxExpose("signup_button")`,
+//! `sfxConvert("signup_button")` in the analytics snippet), which counts them
+//! under the variant the visitor is assigned, never one the page claims.
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+use std::collections::BTreeMap;
+
+use crate::ctx::SfxCtx;
+
+static FLAGS: Lazy<FlagSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/flags.json");
+    FlagSettings::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None))
+});
+
+/// Session key of the id guests are assigned by
+pub const SUBJECT_KEY: &str = "experiment_subject";
+
+/// One arm of an experiment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Variant {
+    pub name: String,
+    pub weight: u32,
+}
+
+/// An experiment and its variants
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Experiment {
+    pub name: String,
+    pub variants: Vec<Variant>,
+}
+
+impl Experiment {
+    /// The variant of `subject`, the same every time
+    pub fn assign(&self, subject: &str) -> Option<&str> {
+        let total: u64 = self.variants.iter().map(|variant| variant.weight as u64).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut point = fnv1a(format!("{}:{}", self.name, subject).as_bytes()) % total;
+        for variant in &self.variants {
+            if point < variant.weight as u64 {
+                return Some(&variant.name);
+            }
+            point -= variant.weight as u64;
+        }
+        None
+    }
+}
+
+/// 64-bit FNV-1a, stable across builds so assignments survive upgrades
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100_0000_01b3))
+}
+
+/// The parsed content of `flags.json`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FlagSettings {
+    pub flags: BTreeMap<String, bool>,
+    pub experiments: Vec<Experiment>,
+}
+
+impl FlagSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let flags = match value.get("flags") {
+            Value::Dict(map) => map.iter().map(|(name, on)| (name.clone(), on.boolean())).collect(),
+            _ => BTreeMap::new(),
+        };
+        let experiments = match value.get("experiments") {
+            Value::List(experiments) => experiments
+                .iter()
+                .map(|experiment| Experiment {
+                    name: experiment.get("name").string(),
+                    variants: match experiment.get("variants") {
+                        Value::List(variants) => variants
+                            .iter()
+                            .map(|variant| Variant {
+                                name: variant.get("name").string(),
+                                weight: match variant.get("weight") {
+                                    Value::None => 1,
+                                    weight => weight.integer().max(0) as u32,
+                                },
+                            })
+                            .filter(|variant| !variant.name.is_empty())
+                            .collect(),
+                        _ => Vec::new(),
+                    },
+                })
+                .filter(|experiment| !experiment.name.is_empty())
+                .collect(),
+            _ => Vec::new(),
+        };
+        Self { flags, experiments }
+    }
+
+    pub fn enabled(&self, flag: &str) -> bool {
+        self.flags.get(flag).copied().unwrap_or(false)
+    }
+
+    pub fn experiment(&self, name: &str) -> Option<&Experiment> {
+        self.experiments.iter().find(|experiment| experiment.name == name)
+    }
+}
+
+/// The loaded flags and experiments
+pub fn settings() -> &'static FlagSettings {
+    &FLAGS
+}
+
+/// Whether `flag` is on; unknown flags are off
+pub fn flag_enabled(flag: &str) -> bool {
+    FLAGS.enabled(flag)
+}
+
+/// Who `req` is assigned as: the signed-in user, or the id of the session,
+/// made on first use
+fn subject(req: &mut HttpReqCtx) -> String {
+    if let Some(user) = req.signed_in() {
+        return user.get_user_id().to_string();
+    }
+    let Ok(session) = req.session_mut() else {
+        return String::new();
+    };
+    match session.get(SUBJECT_KEY) {
+        Some(id) => id.string(),
+        None => {
+            let id = hotaru_lib::random::random_alphanumeric_string(16);
+            session.insert(SUBJECT_KEY.to_string(), id.clone().into());
+            id
+        }
+    }
+}
+
+/// The variant of `experiment` the visitor of `req` is in
+pub fn variant(req: &mut HttpReqCtx, experiment: &str) -> Option<String> {
+    let experiment = FLAGS.experiment(experiment)?;
+    experiment.assign(&subject(req)).map(str::to_string)
+}
+
+/// `pageprop.flags` and `pageprop.experiments` (experiment name to
+/// variant) for `req`
+pub fn pageprop(req: &mut HttpReqCtx) -> (Value, Value) {
+    let mut flags = Value::new_dict();
+    for (name, on) in &FLAGS.flags {
+        flags.set(name, *on);
+    }
+    let mut experiments = Value::new_dict();
+    if !FLAGS.experiments.is_empty() {
+        let subject = subject(req);
+        for experiment in &FLAGS.experiments {
+            if let Some(variant) = experiment.assign(&subject) {
+                experiments.set(&experiment.name, variant);
+            }
+        }
+    }
+    (flags, experiments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assignments_are_sticky_and_weighted() {
+        let settings = FlagSettings::from_value(&object!({
+            flags: { on: true, off: false },
+            experiments: [
+                { name: "button", variants: [{ name: "control", weight: 3 }, { name: "green", weight: 1 }] },
+                { name: "never", variants: [{ name: "a", weight: 0 }] },
+            ],
+        }));
+        assert!(settings.enabled("on") && !settings.enabled("off") && !settings.enabled("unknown"));
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+
+        let button = settings.experiment("button").unwrap();
+        assert_eq!(button.assign("1@local"), button.assign("1@local"));
+        let green = (0..4000).filter(|n| button.assign(&n.to_string()) == Some("green")).count();
+        assert!((800..1200).contains(&green), "{} of 4000 in green", green);
+        assert_eq!(settings.experiment("never").unwrap().assign("x"), None);
+    }
+}
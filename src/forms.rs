@@ -0,0 +1,705 @@
+//! forms.rs
+//!
+//! Data-collection forms defined without code: signups, surveys, contact
+//! forms. Each form is a file `programfiles/forms/<slug>.json`, written by
+//! hand or at `/admin/forms`, and is filled in at `/forms/<slug>`:
+//!
+//! ```json
+//! {
+//!     "title": "Newsletter",
+//!     "description": "Hear about new releases",
+//!     "fields": [
+//!         { "name": "email", "label": "Email", "type": "email", "required": true },
+//!         { "name": "topics", "label": "Topics", "type": "select", "options": ["News", "Events"] },
+//!         { "name": "note", "label": "Anything else?", "type": "textarea", "max_length": 500 }
+//!     ],
+//!     "open": true,
+//!     "guests": true,
+//!     "once": false,
+//!     "message": "Thanks, you are on the list."
+//! }
+//! ```
+//!
+//! Field types are `text`, `textarea`, `email`, `url`, `number` (with `min`
+//! and `max`), `select`, `radio` (both with `options`) and `checkbox`.
+//! `open: false` stops taking answers, `guests: false` asks visitors to sign
+//! in, and `once` takes one answer per user. The form is guarded by the
+//! CAPTCHA and the honeypot when their `routes` include `form`.
+//!
+//! Answers are appended to `programfiles/admin_info/forms/<slug>.jsonl` and
+//! exported as CSV from the admin panel.
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Mutex, RwLock};
+
+use crate::captcha;
+use crate::ctx::SfxCtx;
+use crate::honeypot;
+use crate::op::{self, APP};
+use crate::user::User;
+use crate::methods;
+
+static FORMS: Lazy<RwLock<Vec<FormSchema>>> = Lazy::new(|| RwLock::new(load()));
+
+/// Held while answers are appended or removed
+static SUBMISSIONS: Mutex<()> = Mutex::new(());
+
+/// Length of submission ids
+const ID_LENGTH: usize = 12;
+
+/// Longest slug and field name
+const MAX_NAME: usize = 64;
+
+/// Longest answer of a field without `max_length`
+const MAX_LENGTH: usize = 2000;
+
+fn forms_dir() -> PathBuf {
+    crate::op::programfiles().join("forms")
+}
+
+fn submissions_path(slug: &str) -> PathBuf {
+    crate::op::programfiles().join("admin_info/forms").join(format!("{}.jsonl", slug))
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Answers or problems, each with the name of its field
+pub type ByField = Vec<(String, String)>;
+
+/// What a field takes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldKind {
+    Text,
+    Textarea,
+    Email,
+    Url,
+    Number,
+    Select,
+    Radio,
+    Checkbox,
+}
+
+impl FieldKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FieldKind::Text => "text",
+            FieldKind::Textarea => "textarea",
+            FieldKind::Email => "email",
+            FieldKind::Url => "url",
+            FieldKind::Number => "number",
+            FieldKind::Select => "select",
+            FieldKind::Radio => "radio",
+            FieldKind::Checkbox => "checkbox",
+        }
+    }
+
+    pub fn from_string(kind: &str) -> Option<Self> {
+        match kind {
+            "text" => Some(FieldKind::Text),
+            "textarea" => Some(FieldKind::Textarea),
+            "email" => Some(FieldKind::Email),
+            "url" => Some(FieldKind::Url),
+            "number" => Some(FieldKind::Number),
+            "select" => Some(FieldKind::Select),
+            "radio" => Some(FieldKind::Radio),
+            "checkbox" => Some(FieldKind::Checkbox),
+            _ => None,
+        }
+    }
+}
+
+/// One field of a form
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    /// Key of the answer and CSV column
+    pub name: String,
+    pub label: String,
+    pub kind: FieldKind,
+    pub required: bool,
+    /// Choices of `select` and `radio`
+    pub options: Vec<String>,
+    /// Bounds of `number`
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    /// Longest answer, in characters
+    pub max_length: usize,
+    pub placeholder: String,
+    /// Hint shown below the field
+    pub help: String,
+}
+
+/// A form as defined in `programfiles/forms/<slug>.json`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormSchema {
+    pub slug: String,
+    pub title: String,
+    pub description: String,
+    pub fields: Vec<Field>,
+    /// Whether answers are taken
+    pub open: bool,
+    /// Whether visitors who are not signed in may answer
+    pub guests: bool,
+    /// One answer per signed-in user
+    pub once: bool,
+    /// Shown after an answer was taken
+    pub message: String,
+}
+
+/// Whether `name` may be a slug or field name: lowercase letters, digits, `-` and `_`
+pub fn valid_name(name: &str) -> bool {
+    (1..=MAX_NAME).contains(&name.len())
+        && name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_')
+}
+
+impl FormSchema {
+    /// Read the definition of form `slug`, or say what is wrong with it
+    pub fn from_value(slug: &str, value: &Value) -> Result<Self, String> {
+        if !valid_name(slug) {
+            return Err(format!("'{}' is not a slug of lowercase letters, digits, - and _", slug));
+        }
+        if !matches!(value, Value::Dict(_)) {
+            return Err("must be an object with `title` and `fields`".to_string());
+        }
+        let Value::List(list) = value.get("fields") else {
+            return Err("`fields` must be a list of fields".to_string());
+        };
+        let mut fields: Vec<Field> = Vec::new();
+        for (index, field) in list.iter().enumerate() {
+            let name = field.get("name").string();
+            let at = format!("field {} ('{}')", index + 1, name);
+            if !valid_name(&name) {
+                return Err(format!("{}: `name` must be lowercase letters, digits, - and _", at));
+            }
+            if fields.iter().any(|other| other.name == name) {
+                return Err(format!("{}: the name is used twice", at));
+            }
+            let kind = match field.get("type") {
+                Value::None => FieldKind::Text,
+                kind => FieldKind::from_string(&kind.string()).ok_or_else(|| format!("{}: unknown type '{}'", at, kind.string()))?,
+            };
+            let options: Vec<String> = match field.get("options") {
+                Value::List(options) => options.iter().map(|option| option.string().trim().to_string()).filter(|o| !o.is_empty()).collect(),
+                _ => Vec::new(),
+            };
+            if matches!(kind, FieldKind::Select | FieldKind::Radio) && options.is_empty() {
+                return Err(format!("{}: a {} needs `options`", at, kind.as_str()));
+            }
+            let bound = |key: &str| match field.get(key) {
+                Value::Numerical(bound) => Some(*bound),
+                _ => None,
+            };
+            let label = field.get("label").string();
+            fields.push(Field {
+                label: if label.trim().is_empty() { name.clone() } else { label.trim().to_string() },
+                name,
+                kind,
+                required: field.get("required").boolean(),
+                options,
+                min: bound("min"),
+                max: bound("max"),
+                max_length: match field.get("max_length") {
+                    Value::Numerical(_) => field.get("max_length").integer().max(1) as usize,
+                    _ => MAX_LENGTH,
+                },
+                placeholder: field.get("placeholder").string(),
+                help: field.get("help").string(),
+            });
+        }
+        if fields.is_empty() {
+            return Err("`fields` is empty".to_string());
+        }
+        let flag = |key: &str, fallback: bool| match value.get(key) {
+            Value::Boolean(flag) => *flag,
+            _ => fallback,
+        };
+        let title = value.get("title").string();
+        Ok(Self {
+            slug: slug.to_string(),
+            title: if title.trim().is_empty() { slug.to_string() } else { title.trim().to_string() },
+            description: value.get("description").string(),
+            fields,
+            open: flag("open", true),
+            guests: flag("guests", true),
+            once: flag("once", false),
+            message: match value.get("message").string() {
+                message if message.trim().is_empty() => "Thank you, your answer was sent.".to_string(),
+                message => message,
+            },
+        })
+    }
+
+    /// Check the answers read by `answer`, in the order of the fields, or
+    /// the problems by field name
+    pub fn validate(&self, answer: impl Fn(&str) -> String) -> Result<ByField, ByField> {
+        let mut values = Vec::new();
+        let mut errors = Vec::new();
+        for field in &self.fields {
+            let value = answer(&field.name).trim().replace("\r\n", "\n");
+            let value = match field.kind {
+                FieldKind::Checkbox => if value.is_empty() { String::new() } else { "yes".to_string() },
+                FieldKind::Textarea => value,
+                _ => value.replace('\n', " "),
+            };
+            if let Some(problem) = field.problem(&value) {
+                errors.push((field.name.clone(), problem.to_string()));
+            }
+            values.push((field.name.clone(), value));
+        }
+        if errors.is_empty() { Ok(values) } else { Err(errors) }
+    }
+
+    /// The definition for the admin API
+    pub fn into_json(&self) -> Value {
+        let fields: Vec<Value> = self
+            .fields
+            .iter()
+            .map(|field| {
+                let mut value = object!({
+                    name: &field.name,
+                    label: &field.label,
+                    required: field.required,
+                    options: field.options.clone(),
+                });
+                value.set("type", field.kind.as_str());
+                value
+            })
+            .collect();
+        object!({
+            slug: &self.slug,
+            title: &self.title,
+            description: &self.description,
+            fields: fields,
+            open: self.open,
+            guests: self.guests,
+            once: self.once,
+        })
+    }
+}
+
+impl Field {
+    /// What is wrong with `value` as the answer of this field
+    fn problem(&self, value: &str) -> Option<&'static str> {
+        if value.is_empty() {
+            return self.required.then_some("This field is required");
+        }
+        if value.chars().count() > self.max_length {
+            return Some("This answer is too long");
+        }
+        match self.kind {
+            FieldKind::Email => {
+                let valid = value.split_once('@').is_some_and(|(local, domain)| {
+                    !local.is_empty()
+                        && domain.contains('.')
+                        && !domain.starts_with('.')
+                        && !domain.ends_with('.')
+                        && !value.contains(char::is_whitespace)
+                        && !domain.contains('@')
+                });
+                (!valid).then_some("Enter an email address")
+            }
+            FieldKind::Url => {
+                let valid = (value.starts_with("https://") || value.starts_with("http://")) && !value.contains(char::is_whitespace);
+                (!valid).then_some("Enter a URL starting with https://")
+            }
+            FieldKind::Number => match value.parse::<f64>() {
+                Ok(number) if number.is_finite() => {
+                    if self.min.is_some_and(|min| number < min) {
+                        Some("This number is too small")
+                    } else if self.max.is_some_and(|max| number > max) {
+                        Some("This number is too large")
+                    } else {
+                        None
+                    }
+                }
+                _ => Some("Enter a number"),
+            },
+            FieldKind::Select | FieldKind::Radio => (!self.options.iter().any(|option| option == value)).then_some("Pick one of the choices"),
+            _ => None,
+        }
+    }
+}
+
+/// One answer to a form
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Submission {
+    pub id: String,
+    pub time: u64,
+    /// User id of who answered, `0@local` style for guests
+    pub user: String,
+    /// Answers by field name
+    pub values: ByField,
+}
+
+impl Submission {
+    pub fn into_json(&self) -> Value {
+        let mut values = Value::new_dict();
+        for (name, value) in &self.values {
+            values.set(name, value.as_str());
+        }
+        object!({ id: &self.id, time: self.time, user: &self.user, values: values })
+    }
+
+    pub fn from_json(value: &Value) -> Option<Self> {
+        let Value::Dict(values) = value.get("values") else { return None };
+        Some(Self {
+            id: value.get("id").string(),
+            time: value.get("time").integer().max(0) as u64,
+            user: value.get("user").string(),
+            values: values.iter().map(|(name, value)| (name.clone(), value.string())).collect(),
+        })
+    }
+
+    pub fn get(&self, name: &str) -> &str {
+        self.values.iter().find(|(field, _)| field == name).map_or("", |(_, value)| value.as_str())
+    }
+}
+
+/// Something that went wrong with a form or an answer
+#[derive(Debug)]
+pub enum FormError {
+    NotFound,
+    Closed,
+    SignInRequired,
+    AlreadySent,
+    Invalid(ByField),
+    /// A definition that does not make a form
+    Schema(String),
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for FormError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FormError::NotFound => write!(f, "Form not found"),
+            FormError::Closed => write!(f, "This form no longer takes answers"),
+            FormError::SignInRequired => write!(f, "Sign in to answer this form"),
+            FormError::AlreadySent => write!(f, "You already answered this form"),
+            FormError::Invalid(_) => write!(f, "Some answers need another look"),
+            FormError::Schema(problem) => write!(f, "{}", problem),
+            FormError::Io(err) => write!(f, "Failed to save: {}", err),
+        }
+    }
+}
+
+impl From<std::io::Error> for FormError {
+    fn from(err: std::io::Error) -> Self {
+        FormError::Io(err)
+    }
+}
+
+/// Every valid definition of the forms directory
+fn load() -> Vec<FormSchema> {
+    let mut forms: Vec<FormSchema> = std::fs::read_dir(forms_dir())
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.path();
+            let slug = path.file_name()?.to_str()?.strip_suffix(".json")?.to_string();
+            let value = Value::from_jsonf(path.to_str()?).ok()?;
+            match FormSchema::from_value(&slug, &value) {
+                Ok(form) => Some(form),
+                Err(problem) => {
+                    tracing::warn!(form = %slug, "Ignoring form definition: {}", problem);
+                    None
+                }
+            }
+        })
+        .collect();
+    forms.sort_by(|a, b| a.slug.cmp(&b.slug));
+    forms
+}
+
+pub fn get(slug: &str) -> Option<FormSchema> {
+    FORMS.read().unwrap().iter().find(|form| form.slug == slug).cloned()
+}
+
+/// Every form, by slug
+pub fn list() -> Vec<FormSchema> {
+    FORMS.read().unwrap().clone()
+}
+
+/// The definition file of form `slug` as written, for the editor
+pub fn source(slug: &str) -> Option<String> {
+    if !valid_name(slug) {
+        return None;
+    }
+    std::fs::read_to_string(forms_dir().join(format!("{}.json", slug))).ok()
+}
+
+/// Check and save the definition `text` of form `slug`, made or replaced
+pub fn save(slug: &str, text: &str) -> Result<FormSchema, FormError> {
+    let value = Value::from_json(text).map_err(|_| FormError::Schema("not valid JSON".to_string()))?;
+    let form = FormSchema::from_value(slug, &value).map_err(FormError::Schema)?;
+    let dir = forms_dir();
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join(format!("{}.json", slug)), text.trim_end().to_string() + "\n")?;
+    let mut forms = FORMS.write().unwrap();
+    forms.retain(|other| other.slug != slug);
+    forms.push(form.clone());
+    forms.sort_by(|a, b| a.slug.cmp(&b.slug));
+    Ok(form)
+}
+
+/// Delete form `slug` with its answers
+pub fn remove(slug: &str) -> Result<FormSchema, FormError> {
+    let form = get(slug).ok_or(FormError::NotFound)?;
+    let _lock = SUBMISSIONS.lock().unwrap();
+    std::fs::remove_file(forms_dir().join(format!("{}.json", slug)))?;
+    match std::fs::remove_file(submissions_path(slug)) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+    FORMS.write().unwrap().retain(|other| other.slug != slug);
+    Ok(form)
+}
+
+/// The answers to form `slug`, oldest first
+pub fn submissions(slug: &str) -> Vec<Submission> {
+    let _lock = SUBMISSIONS.lock().unwrap();
+    std::fs::read_to_string(submissions_path(slug))
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| Value::from_json(line).ok())
+        .filter_map(|value| Submission::from_json(&value))
+        .collect()
+}
+
+/// Take an answer to `form` by `user`, read by `answer`
+pub fn submit(form: &FormSchema, user: &User, answer: impl Fn(&str) -> String) -> Result<Submission, FormError> {
+    if !form.open {
+        return Err(FormError::Closed);
+    }
+    let user_id = user.get_user_id();
+    if user_id.is_guest() && (!form.guests || form.once) {
+        return Err(FormError::SignInRequired);
+    }
+    let values = form.validate(answer).map_err(FormError::Invalid)?;
+    let user = user_id.to_string();
+    if form.once && submissions(&form.slug).iter().any(|submission| submission.user == user) {
+        return Err(FormError::AlreadySent);
+    }
+    let submission = Submission { id: hotaru_lib::random::random_alphanumeric_string(ID_LENGTH), time: now(), user, values };
+    let _lock = SUBMISSIONS.lock().unwrap();
+    let path = submissions_path(&form.slug);
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", submission.into_json().into_json())?;
+    crate::events::publish(crate::events::FormSubmitted {
+        form: form.slug.clone(),
+        submission: submission.id.clone(),
+        user: submission.user.clone(),
+    });
+    Ok(submission)
+}
+
+/// `secs` as `YYYY-MM-DD HH:MM:SS` in UTC
+pub fn time(secs: u64) -> String {
+    let stamp = crate::backup::timestamp(secs);
+    format!("{} {}:{}:{}", crate::analytics::date(secs), &stamp[9..11], &stamp[11..13], &stamp[13..15])
+}
+
+/// One CSV cell, quoted when needed. Cells a spreadsheet would run as a
+/// formula get a leading `'`.
+fn csv_cell(text: &str) -> String {
+    let text = if text.starts_with(['=', '+', '-', '@', '\t', '\r']) { format!("'{}", text) } else { text.to_string() };
+    if text.contains([',', '"', '\n', '\r']) { format!("\"{}\"", text.replace('"', "\"\"")) } else { text }
+}
+
+/// The answers to `form` as CSV: id, time, user, then a column per field
+pub fn csv(form: &FormSchema, submissions: &[Submission]) -> String {
+    let mut header = vec!["id".to_string(), "time".to_string(), "user".to_string()];
+    header.extend(form.fields.iter().map(|field| field.name.clone()));
+    let mut out = header.iter().map(|cell| csv_cell(cell)).collect::<Vec<String>>().join(",") + "\r\n";
+    for submission in submissions {
+        let mut row = vec![csv_cell(&submission.id), time(submission.time), csv_cell(&submission.user)];
+        row.extend(form.fields.iter().map(|field| csv_cell(submission.get(&field.name))));
+        out.push_str(&row.join(","));
+        out.push_str("\r\n");
+    }
+    out
+}
+
+/// The fields of `form` for `forms/form.html`, escaped, holding the given
+/// answers and problems
+fn fields(form: &FormSchema, values: &[(String, String)], errors: &[(String, String)]) -> Value {
+    let lookup = |list: &[(String, String)], name: &str| list.iter().find(|(field, _)| field == name).map(|(_, text)| text.clone()).unwrap_or_default();
+    let fields: Vec<Value> = form
+        .fields
+        .iter()
+        .map(|field| {
+            let value = lookup(values, &field.name);
+            let options: Vec<Value> = field
+                .options
+                .iter()
+                .map(|option| object!({ value: op::escape_html(option), selected: *option == value }))
+                .collect();
+            object!({
+                name: &field.name,
+                label: op::escape_html(&field.label),
+                kind: field.kind.as_str(),
+                required: field.required,
+                options: options,
+                min: field.min.map(|min| min.to_string()).unwrap_or_default(),
+                max: field.max.map(|max| max.to_string()).unwrap_or_default(),
+                max_length: field.max_length,
+                placeholder: op::escape_html(&field.placeholder),
+                help: op::escape_html(&field.help),
+                value: op::escape_html(&value),
+                checked: !value.is_empty(),
+                error: lookup(errors, &field.name),
+            })
+        })
+        .collect();
+    Value::List(fields)
+}
+
+/// Render `form` with the answers so far and what to say about them
+fn page(req: &mut HttpReqCtx, form: &FormSchema, values: &[(String, String)], errors: &[(String, String)], message: &str, done: bool) -> HttpResponse {
+    let guest = req.signed_in().is_none();
+    let blocked = if !form.open {
+        FormError::Closed.to_string()
+    } else if guest && (!form.guests || form.once) {
+        FormError::SignInRequired.to_string()
+    } else {
+        String::new()
+    };
+    let value = object!({
+        slug: &form.slug,
+        title: op::escape_html(&form.title),
+        description: op::escape_html(&form.description),
+        fields: fields(form, values, errors),
+        message: op::escape_html(message),
+        done: done,
+        can_answer: blocked.is_empty() && !done,
+        blocked: blocked,
+        honeypot: honeypot::fields(captcha::FORM),
+    });
+    let captcha = captcha::widget(req, captcha::FORM);
+    akari_render!(
+        "forms/form.html",
+        pageprop = op::pageprop(req, &form.title, &form.description),
+        path = op::into_path_l(req, vec!["home", "form"]),
+        form = value,
+        captcha = captcha
+    )
+}
+
+endpoint! {
+    APP.url("/forms/<slug>"),
+
+    /// GET: the form. POST: answer it; the page comes back with the problems
+    /// of the answers, or the thank-you message. With `Accept: application/json`
+    /// POST answers `{"success": true, "id": ...}` or
+    /// `{"success": false, "message": ..., "errors": {"field": "problem"}}`.
+    pub form_page <HTTP> {
+        let slug = req.param("slug").unwrap_or_default();
+        let Some(form) = get(&slug) else {
+            return text_response("Not found").status(StatusCode::NOT_FOUND);
+        };
+        methods!(req, {
+            GET => { page(req, &form, &[], &[], "", false) }
+            POST => {
+                let json = req.header_str("accept").is_some_and(|accept| accept.contains("application/json"));
+                let user = op::get_user(req).await;
+                let form_data = req.form_or_default().await.clone();
+                let checked = match honeypot::verify_form(captcha::FORM, &form_data) {
+                    Ok(()) => captcha::verify(req, captcha::FORM, &captcha::response_from_form(&form_data)).await.map_err(|err| err.to_string()),
+                    Err(err) => Err(err.to_string()),
+                };
+                let answer = |name: &str| form_data.get_or_default(name).clone();
+                let result = match checked {
+                    Ok(()) => submit(&form, &user, answer),
+                    Err(message) => Err(FormError::Schema(message)),
+                };
+                let values: ByField = form.fields.iter().map(|field| (field.name.clone(), answer(&field.name))).collect();
+                match result {
+                    Ok(submission) if json => json_response(object!({ success: true, id: submission.id })),
+                    Ok(_) => page(req, &form, &[], &[], &form.message, true),
+                    Err(err) => {
+                        let status = match err {
+                            FormError::SignInRequired => StatusCode::UNAUTHORIZED,
+                            FormError::Closed => StatusCode::FORBIDDEN,
+                            FormError::AlreadySent => StatusCode::CONFLICT,
+                            FormError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                            _ => StatusCode::BAD_REQUEST,
+                        };
+                        let errors = match &err {
+                            FormError::Invalid(errors) => errors.clone(),
+                            _ => Vec::new(),
+                        };
+                        if json {
+                            let mut problems = Value::new_dict();
+                            for (name, problem) in &errors {
+                                problems.set(name, problem.as_str());
+                            }
+                            return json_response(object!({ success: false, message: err.to_string(), errors: problems })).status(status);
+                        }
+                        page(req, &form, &values, &errors, &err.to_string(), false).status(status)
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn survey() -> FormSchema {
+        let value = Value::from_json(
+            r#"{"title": "Survey", "fields": [
+                {"name": "email", "type": "email", "required": true},
+                {"name": "age", "type": "number", "min": 13, "max": 120},
+                {"name": "size", "type": "select", "options": ["S", "M"]},
+                {"name": "agree", "type": "checkbox", "required": true}
+            ]}"#,
+        )
+        .unwrap();
+        FormSchema::from_value("survey", &value).unwrap()
+    }
+
+    #[test]
+    fn definitions_are_checked() {
+        let form = survey();
+        assert_eq!(form.fields[0].label, "email");
+        assert!(form.open && form.guests && !form.once);
+        let broken = |json: &str| FormSchema::from_value("x", &Value::from_json(json).unwrap()).unwrap_err();
+        assert!(broken(r#"{"fields": []}"#).contains("empty"));
+        assert!(broken(r#"{"fields": [{"name": "a"}, {"name": "a"}]}"#).contains("twice"));
+        assert!(broken(r#"{"fields": [{"name": "a", "type": "radio"}]}"#).contains("options"));
+        assert!(broken(r#"{"fields": [{"name": "a", "type": "color"}]}"#).contains("unknown type"));
+        assert!(FormSchema::from_value("Bad Slug", &Value::from_json(r#"{"fields": [{"name": "a"}]}"#).unwrap()).is_err());
+    }
+
+    #[test]
+    fn answers_are_validated_and_exported() {
+        let form = survey();
+        let answers = |pairs: &'static [(&'static str, &'static str)]| {
+            move |name: &str| pairs.iter().find(|(key, _)| *key == name).map(|(_, value)| value.to_string()).unwrap_or_default()
+        };
+        let errors = form.validate(answers(&[("email", "nope"), ("age", "7"), ("size", "XL")])).unwrap_err();
+        let names: Vec<&str> = errors.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["email", "age", "size", "agree"]);
+
+        let values = form.validate(answers(&[("email", "a@example.com"), ("age", "30"), ("agree", "on")])).unwrap();
+        assert_eq!(values[3], ("agree".to_string(), "yes".to_string()));
+        let submission = Submission { id: "abc".to_string(), time: 0, user: "2@local".to_string(), values };
+        let read = Submission::from_json(&submission.into_json()).unwrap();
+        assert!(form.fields.iter().all(|field| read.get(&field.name) == submission.get(&field.name)));
+        let mut tricky = submission.clone();
+        tricky.values[2].1 = "=HYPERLINK(\"x\")".to_string();
+        assert_eq!(
+            csv(&form, &[tricky]),
+            "id,time,user,email,age,size,agree\r\nabc,1970-01-01 00:00:00,2@local,a@example.com,30,\"'=HYPERLINK(\"\"x\"\")\",yes\r\n"
+        );
+    }
+}
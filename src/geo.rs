@@ -0,0 +1,424 @@
+//! geo.rs
+//!
+//! Country and city of client addresses, for login history and the admin
+//! pages. The database is optional and named in `programfiles/op/geo.json`:
+//!
+//! ```json
+//! { "database": "geo/GeoLite2-City.mmdb" }
+//! ```
+//!
+//! The path is relative to `programfiles` unless absolute. Two formats are
+//! read, picked by the extension:
+//!
+//! - `.mmdb`: a MaxMind database (GeoLite2/GeoIP2 Country or City).
+//! - `.csv`: an IP2Location LITE CSV (DB1, DB3, DB5 or DB11, IPv4 or IPv6).
+//!   Every row is held in memory, so prefer the `.mmdb` for city data.
+//!
+//! Without a database, or when it fails to load, [`lookup`] answers `None`
+//! and nothing is annotated.
+
+use hotaru::prelude::*;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+static GEO: Lazy<Option<GeoDatabase>> = Lazy::new(|| {
+    let programfiles = crate::op::programfiles();
+    let path = programfiles.join("op/geo.json");
+    let settings = GeoSettings::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None));
+    let database = settings.database.as_ref()?;
+    let path = programfiles.join(database);
+    match GeoDatabase::open(&path) {
+        Ok(database) => {
+            tracing::info!(path = %path.display(), "Loaded the GeoIP database");
+            Some(database)
+        }
+        Err(err) => {
+            tracing::warn!(path = %path.display(), %err, "Cannot load the GeoIP database, locations are left out");
+            None
+        }
+    }
+});
+
+/// The parsed content of `geo.json`
+#[derive(Debug, Clone, Default)]
+pub struct GeoSettings {
+    pub database: Option<PathBuf>,
+}
+
+impl GeoSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let database = value.get("database").string();
+        Self { database: (!database.is_empty()).then(|| database.into()) }
+    }
+}
+
+/// Where an address is
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Location {
+    /// ISO 3166-1 alpha-2 code, e.g. `NL`
+    pub country_code: String,
+    /// English country name
+    pub country: String,
+    /// English city name, empty for country databases
+    pub city: String,
+    /// `(latitude, longitude)`, when the database has coordinates
+    pub coordinates: Option<(f64, f64)>,
+}
+
+impl Location {
+    pub fn from_json(value: &Value) -> Self {
+        let coordinate = |key: &str| match value.get(key) {
+            Value::Numerical(n) => Some(*n),
+            _ => None,
+        };
+        Location {
+            country_code: value.get("country_code").string(),
+            country: value.get("country").string(),
+            city: value.get("city").string(),
+            coordinates: coordinate("latitude").zip(coordinate("longitude")),
+        }
+    }
+
+    pub fn into_json(&self) -> Value {
+        let mut value = object!({
+            country_code: &self.country_code,
+            country: &self.country,
+            city: &self.city,
+        });
+        if let Some((latitude, longitude)) = self.coordinates {
+            value.set("latitude", latitude);
+            value.set("longitude", longitude);
+        }
+        value
+    }
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let country = if self.country.is_empty() { &self.country_code } else { &self.country };
+        if self.city.is_empty() {
+            write!(f, "{}", country)
+        } else {
+            write!(f, "{}, {}", self.city, country)
+        }
+    }
+}
+
+/// A loaded GeoIP database
+pub enum GeoDatabase {
+    MaxMind(MaxMindDb),
+    Ranges(RangeDb),
+}
+
+impl GeoDatabase {
+    /// Load the database at `path`, by its extension
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let bytes = std::fs::read(path).map_err(|err| err.to_string())?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("mmdb") => MaxMindDb::from_bytes(bytes).map(GeoDatabase::MaxMind),
+            Some("csv") => {
+                RangeDb::from_ip2location_csv(&String::from_utf8_lossy(&bytes)).map(GeoDatabase::Ranges)
+            }
+            _ => Err("unknown format, expected a .mmdb or .csv file".to_string()),
+        }
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<Location> {
+        match self {
+            GeoDatabase::MaxMind(db) => db.lookup(ip),
+            GeoDatabase::Ranges(db) => db.lookup(ip),
+        }
+    }
+}
+
+/// The location of `ip`, `None` without a database or for unknown addresses
+pub fn lookup(ip: IpAddr) -> Option<Location> {
+    GEO.as_ref()?.lookup(ip)
+}
+
+/// Whether a database is loaded
+pub fn is_enabled() -> bool {
+    GEO.is_some()
+}
+
+/// Marks the start of the metadata section of a MaxMind database
+const METADATA_MARKER: &[u8] = b"\xAB\xCD\xEFMaxMind.com";
+
+/// A MaxMind DB file (<https://maxmind.github.io/MaxMind-DB/>)
+pub struct MaxMindDb {
+    bytes: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u16,
+    data_start: usize,
+}
+
+impl MaxMindDb {
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, String> {
+        let marker = bytes
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .ok_or("no MaxMind metadata")?;
+        let metadata_start = marker + METADATA_MARKER.len();
+        let metadata = Decoder { bytes: &bytes, base: metadata_start }.value(metadata_start, 0)?.0;
+        let node_count = metadata.get("node_count").integer().max(0) as usize;
+        let record_size = metadata.get("record_size").integer().max(0) as usize;
+        let ip_version = metadata.get("ip_version").integer() as u16;
+        if !matches!(record_size, 24 | 28 | 32) {
+            return Err(format!("unsupported record size {}", record_size));
+        }
+        let tree_size = node_count * record_size / 4;
+        if tree_size + 16 > marker {
+            return Err("search tree larger than the file".to_string());
+        }
+        Ok(Self { bytes, node_count, record_size, ip_version, data_start: tree_size + 16 })
+    }
+
+    /// Record `side` (0 left, 1 right) of `node`
+    fn record(&self, node: usize, side: u8) -> usize {
+        let at = node * self.record_size / 4;
+        let b = &self.bytes[at..at + self.record_size / 4];
+        let be = |bytes: &[u8]| bytes.iter().fold(0usize, |acc, &b| (acc << 8) | b as usize);
+        match (self.record_size, side) {
+            (24, 0) => be(&b[0..3]),
+            (24, _) => be(&b[3..6]),
+            (28, 0) => ((b[3] as usize & 0xF0) << 20) | be(&b[0..3]),
+            (28, _) => ((b[3] as usize & 0x0F) << 24) | be(&b[4..7]),
+            (_, 0) => be(&b[0..4]),
+            (_, _) => be(&b[4..8]),
+        }
+    }
+
+    /// The data record of `ip`
+    pub fn record_of(&self, ip: IpAddr) -> Option<Value> {
+        let bits: Vec<u8> = match (ip, self.ip_version) {
+            (IpAddr::V4(ip), 4) => ip.octets().to_vec(),
+            (IpAddr::V6(_), 4) => return None,
+            (IpAddr::V4(ip), _) => ip.to_ipv6_compatible().octets().to_vec(),
+            (IpAddr::V6(ip), _) => ip.octets().to_vec(),
+        };
+        let mut node = 0;
+        for i in 0..bits.len() * 8 {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, (bits[i / 8] >> (7 - i % 8)) & 1);
+        }
+        if node <= self.node_count {
+            return None;
+        }
+        let offset = self.data_start + (node - self.node_count - 16);
+        Decoder { bytes: &self.bytes, base: self.data_start }.value(offset, 0).ok().map(|(value, _)| value)
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<Location> {
+        let record = self.record_of(ip)?;
+        let name = |value: &Value| value.get("names").get("en").string();
+        let country = match record.try_get("country") {
+            Ok(country) => country,
+            Err(_) => record.get("registered_country"),
+        };
+        let location = record.get("location");
+        let coordinate = |key: &str| match location.get(key) {
+            Value::Numerical(n) => Some(*n),
+            _ => None,
+        };
+        Some(Location {
+            country_code: country.get("iso_code").string(),
+            country: name(country),
+            city: name(record.get("city")),
+            coordinates: coordinate("latitude").zip(coordinate("longitude")),
+        })
+    }
+}
+
+/// Decoder of the MaxMind data section; pointers are relative to `base`
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    base: usize,
+}
+
+impl Decoder<'_> {
+    fn slice(&self, at: usize, len: usize) -> Result<&[u8], String> {
+        self.bytes.get(at..at + len).ok_or_else(|| "truncated data".to_string())
+    }
+
+    fn uint(&self, at: usize, len: usize) -> Result<u128, String> {
+        Ok(self.slice(at, len)?.iter().fold(0u128, |acc, &b| (acc << 8) | b as u128))
+    }
+
+    /// Decode the value at `at`, returning it with the offset following it
+    fn value(&self, at: usize, depth: usize) -> Result<(Value, usize), String> {
+        if depth > 32 {
+            return Err("data nested too deep".to_string());
+        }
+        let control = *self.bytes.get(at).ok_or("truncated data")?;
+        let mut at = at + 1;
+        let mut kind = control >> 5;
+        if kind == 1 {
+            let size = ((control >> 3) & 0x3) as usize;
+            let low = (control & 0x7) as usize;
+            let pointer = match size {
+                0 => (low << 8) | self.uint(at, 1)? as usize,
+                1 => ((low << 16) | self.uint(at, 2)? as usize) + 2048,
+                2 => ((low << 24) | self.uint(at, 3)? as usize) + 526336,
+                _ => self.uint(at, 4)? as usize,
+            };
+            let (value, _) = self.value(self.base + pointer, depth + 1)?;
+            return Ok((value, at + size + 1));
+        }
+        if kind == 0 {
+            kind = 7 + *self.bytes.get(at).ok_or("truncated data")?;
+            at += 1;
+        }
+        let mut size = (control & 0x1f) as usize;
+        if size >= 29 {
+            let extra = size - 28;
+            let base = [29, 285, 65821][extra - 1];
+            size = base + self.uint(at, extra)? as usize;
+            at += extra;
+        }
+        match kind {
+            2 => {
+                let text = String::from_utf8_lossy(self.slice(at, size)?).into_owned();
+                Ok((Value::Str(text), at + size))
+            }
+            3 => {
+                let bits = self.uint(at, 8)? as u64;
+                Ok((Value::Numerical(f64::from_bits(bits)), at + 8))
+            }
+            4 => Ok((Value::None, at + size)),
+            5 | 6 | 9 | 10 => Ok((Value::Numerical(self.uint(at, size)? as f64), at + size)),
+            8 => {
+                let raw = self.uint(at, size)? as u32;
+                // Shorter int32 values are not sign-extended
+                Ok((Value::Numerical(if size == 4 { raw as i32 as f64 } else { raw as f64 }), at + size))
+            }
+            7 => {
+                let mut map = HashMap::new();
+                for _ in 0..size {
+                    let (key, next) = self.value(at, depth + 1)?;
+                    let (value, next) = self.value(next, depth + 1)?;
+                    map.insert(key.string(), value);
+                    at = next;
+                }
+                Ok((Value::Dict(map), at))
+            }
+            11 => {
+                let mut list = Vec::with_capacity(size.min(1024));
+                for _ in 0..size {
+                    let (value, next) = self.value(at, depth + 1)?;
+                    list.push(value);
+                    at = next;
+                }
+                Ok((Value::List(list), at))
+            }
+            14 => Ok((Value::Boolean(size != 0), at)),
+            15 => {
+                let bits = self.uint(at, 4)? as u32;
+                Ok((Value::Numerical(f32::from_bits(bits) as f64), at + 4))
+            }
+            other => Err(format!("unsupported data type {}", other)),
+        }
+    }
+}
+
+/// Address ranges with their location, sorted by their first address
+pub struct RangeDb {
+    ranges: Vec<(u128, u128, Location)>,
+    /// Whether the file numbers IPv4 addresses as IPv4-mapped IPv6 ones
+    mapped: bool,
+}
+
+impl RangeDb {
+    /// Parse an IP2Location LITE CSV: `"ip_from","ip_to","country_code",
+    /// "country_name"`, then optionally region, city, latitude and longitude
+    pub fn from_ip2location_csv(csv: &str) -> Result<Self, String> {
+        let mut ranges = Vec::new();
+        for (number, line) in csv.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split("\",\"").map(|field| field.trim().trim_matches('"')).collect();
+            let parse = |i: usize| fields.get(i).and_then(|field| field.parse::<u128>().ok());
+            let (Some(from), Some(to)) = (parse(0), parse(1)) else {
+                return Err(format!("line {} is not an IP2Location row", number + 1));
+            };
+            let field = |i: usize| fields.get(i).map(|field| field.to_string()).unwrap_or_default();
+            let coordinate = |i: usize| fields.get(i).and_then(|field| field.parse::<f64>().ok());
+            let country_code = field(2);
+            if country_code == "-" {
+                continue;
+            }
+            ranges.push((from, to, Location {
+                country_code,
+                country: field(3),
+                city: field(5),
+                coordinates: coordinate(6).zip(coordinate(7)),
+            }));
+        }
+        ranges.sort_by_key(|(from, _, _)| *from);
+        let mapped = ranges.last().is_some_and(|(_, to, _)| *to > u32::MAX as u128);
+        Ok(Self { ranges, mapped })
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<Location> {
+        let number = match ip {
+            IpAddr::V4(ip) if self.mapped => u128::from(ip.to_ipv6_mapped()),
+            IpAddr::V4(ip) => u32::from(ip) as u128,
+            IpAddr::V6(ip) => u128::from(ip),
+        };
+        let index = self.ranges.partition_point(|(from, _, _)| *from <= number).checked_sub(1)?;
+        let (_, to, location) = &self.ranges[index];
+        (number <= *to).then(|| location.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ip2location_rows_are_looked_up_by_range() {
+        let db = RangeDb::from_ip2location_csv(concat!(
+            "\"0\",\"16777215\",\"-\",\"-\",\"-\",\"-\",\"0.000000\",\"0.000000\"\n",
+            "\"16777216\",\"16777471\",\"US\",\"United States of America\",\"California\",\"Los Angeles\",\"34.052230\",\"-118.243680\"\n",
+            "\"16777472\",\"16778239\",\"CN\",\"China\",\"Fujian\",\"Fuzhou\",\"26.061390\",\"119.306110\"\n",
+        ))
+        .unwrap();
+        let la = db.lookup("1.0.0.42".parse().unwrap()).unwrap();
+        assert_eq!(la.to_string(), "Los Angeles, United States of America");
+        assert_eq!(la.coordinates, Some((34.05223, -118.24368)));
+        assert_eq!(db.lookup("1.0.1.0".parse().unwrap()).unwrap().country_code, "CN");
+        assert!(db.lookup("0.0.0.1".parse().unwrap()).is_none());
+        assert!(db.lookup("8.8.8.8".parse().unwrap()).is_none());
+        assert_eq!(Location::from_json(&la.into_json()), la);
+    }
+
+    #[test]
+    fn maxmind_tree_leads_to_the_data_record() {
+        // One node: addresses starting with a 0 bit have {country: {iso_code: "NL"}}
+        let mut bytes = vec![0x00, 0x00, 17, 0x00, 0x00, 0x01];
+        bytes.extend([0u8; 16]);
+        bytes.extend([0xE1, 0x47]);
+        bytes.extend(b"country");
+        bytes.extend([0xE1, 0x48]);
+        bytes.extend(b"iso_code");
+        bytes.push(0x42);
+        bytes.extend(b"NL");
+        bytes.extend(METADATA_MARKER);
+        bytes.extend([0xE3, 0x4A]);
+        bytes.extend(b"node_count");
+        bytes.extend([0xC1, 0x01, 0x4B]);
+        bytes.extend(b"record_size");
+        bytes.extend([0xA1, 24, 0x4A]);
+        bytes.extend(b"ip_version");
+        bytes.extend([0xA1, 0x04]);
+
+        let db = MaxMindDb::from_bytes(bytes).unwrap();
+        assert_eq!(db.lookup("1.2.3.4".parse().unwrap()).unwrap().country_code, "NL");
+        assert!(db.lookup("200.0.0.1".parse().unwrap()).is_none());
+        assert!(db.lookup("::1".parse().unwrap()).is_none());
+    }
+}
@@ -0,0 +1,1017 @@
+//! graphql.rs
+//!
+//! A GraphQL API at `/graphql` for single-page frontends: the public
+//! fields of local accounts, the signed-in viewer with their notification
+//! settings, and the posts of the blog. Queries come as `GET
+//! /graphql?query=...&variables=...` or as a JSON `POST`:
+//!
+//! ```json
+//! { "query": "query Author($uid: Int!) { user(uid: $uid) { username avatar } }", "variables": { "uid": 1 } }
+//! ```
+//!
+//! The schema, in SDL, is served at `GET /graphql/schema`. Fields are
+//! resolved for whoever asks: the session of a signed-in local account, or
+//! a bearer token with the `profile:read` scope. `email` and `roles` of a
+//! user are given to the user themself and to admins only, drafts and
+//! `users` to admins only; asking for them otherwise answers `null` for the
+//! field with an error at its path. Mutations and subscriptions are not
+//! supported.
+//!
+//! Queries named in `programfiles/op/graphql.json` are run by their `id`,
+//! and clients may persist theirs with the `persistedQuery` extension of
+//! Apollo, sending the SHA-256 of the query instead of the query:
+//!
+//! ```json
+//! {
+//!     "persisted_only": false,
+//!     "max_depth": 10,
+//!     "queries": { "profile": "query($uid: Int!) { user(uid: $uid) { username profile } }" }
+//! }
+//! ```
+//!
+//! With `persisted_only` the server runs no other query, so a production
+//! frontend cannot be made to send arbitrary ones. `max_depth` caps how
+//! deeply objects may nest in a query. Switched off with `"graphql": false`
+//! in `modules.json`.
+
+pub mod parse;
+
+use hotaru::http::*;
+use hotaru::prelude::*;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+
+use crate::admin::check_is_admin;
+use crate::blog::{self, Post};
+use crate::ctx::SfxCtx;
+use crate::extract::{Extract, MAX_PER_PAGE};
+use crate::local_auth::LOCAL_AUTH;
+use crate::local_auth::analyze::get_auth_token;
+use crate::local_auth::fop::{AuthManager, UserStorage};
+use crate::local_auth::public::{self, Viewer};
+use crate::local_auth::scope;
+use crate::methods;
+use crate::modules;
+use crate::notifications::{self, DELIVERY_KEY, TIMEZONE_KEY};
+use crate::op::{self, APP};
+use crate::preferences::UserPrefs;
+use parse::{Document, Field, Input, OperationKind, Selection};
+
+static SETTINGS: Lazy<GraphqlSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/graphql.json");
+    GraphqlSettings::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None))
+});
+
+static AUTOMATIC: Lazy<Mutex<AutomaticQueries>> = Lazy::new(|| Mutex::new(AutomaticQueries::default()));
+
+/// How deeply objects may nest when `graphql.json` does not say
+pub const DEFAULT_MAX_DEPTH: usize = 10;
+/// Queries persisted by clients that are kept, the oldest dropped first
+const MAX_AUTOMATIC: usize = 1000;
+
+/// A field of an object type: its name, its arguments as `name: Type`
+/// (with `= default` when it has one), its type and what it is
+struct FieldDef {
+    name: &'static str,
+    args: &'static [(&'static str, &'static str)],
+    ty: &'static str,
+    doc: &'static str,
+}
+
+const fn field(name: &'static str, ty: &'static str, doc: &'static str) -> FieldDef {
+    FieldDef { name, args: &[], ty, doc }
+}
+
+/// The object types of the schema
+const TYPES: &[(&str, &str, &[FieldDef])] = &[
+    ("Query", "What may be asked", &[
+        field("viewer", "Viewer", "The signed-in user, null for guests"),
+        FieldDef { name: "user", args: &[("uid", "Int"), ("username", "String")], ty: "User", doc: "A local account, by uid or username" },
+        FieldDef {
+            name: "users",
+            args: &[("search", "String"), ("page", "Int = 1"), ("perPage", "Int = 10")],
+            ty: "UserPage",
+            doc: "The local accounts, for admins",
+        },
+        FieldDef { name: "post", args: &[("slug", "String!")], ty: "Post", doc: "A post of the blog; drafts for admins" },
+        FieldDef {
+            name: "posts",
+            args: &[("tag", "String"), ("first", "Int = 10"), ("offset", "Int = 0"), ("drafts", "Boolean = false")],
+            ty: "[Post!]!",
+            doc: "The posts of the blog, newest first; drafts for admins",
+        },
+    ]),
+    ("Viewer", "Who is asking", &[
+        field("user", "User", "Their account"),
+        field("isAdmin", "Boolean!", "Whether they are an admin"),
+        field("notifications", "Notifications!", "How they get notification mails"),
+    ]),
+    ("User", "A local account", &[
+        field("uid", "Int!", ""),
+        field("username", "String!", ""),
+        field("displayName", "String!", ""),
+        field("avatar", "String", "The URL of the avatar"),
+        field("isActive", "Boolean!", ""),
+        field("profile", "JSON!", "The profile entries the viewer may see"),
+        field("email", "String", "Only for the user themself and admins"),
+        field("roles", "[String!]", "Only for the user themself and admins"),
+    ]),
+    ("UserPage", "A page of accounts", &[
+        field("total", "Int!", "Accounts matching the search"),
+        field("page", "Int!", ""),
+        field("perPage", "Int!", ""),
+        field("users", "[User!]!", ""),
+    ]),
+    ("Post", "A post of the blog", &[
+        field("slug", "String!", ""),
+        field("title", "String!", ""),
+        field("summary", "String!", ""),
+        field("html", "String!", "The body, rendered"),
+        field("tags", "[String!]!", ""),
+        field("authorName", "String!", "The name of the author when they posted"),
+        field("author", "User", "The account of the author, when it is local"),
+        field("published", "Int", "Seconds since the epoch, null for drafts never published"),
+        field("updated", "Int!", "Seconds since the epoch"),
+        field("draft", "Boolean!", ""),
+    ]),
+    ("Notifications", "The notification settings of the viewer", &[
+        field("delivery", "String!", "`immediate` or `daily`"),
+        field("timezone", "String!", "The UTC offset digests are sent by"),
+        field("categories", "[NotificationCategory!]!", ""),
+        field("pending", "[PendingNotification!]!", "What waits for the next digest"),
+    ]),
+    ("NotificationCategory", "A kind of notification", &[
+        field("name", "String!", ""),
+        field("label", "String!", ""),
+        field("enabled", "Boolean!", ""),
+    ]),
+    ("PendingNotification", "A notification waiting for the digest", &[
+        field("category", "String!", ""),
+        field("subject", "String!", ""),
+        field("text", "String!", ""),
+        field("at", "Int!", "Seconds since the epoch"),
+    ]),
+];
+
+fn field_def(ty: &str, name: &str) -> Option<&'static FieldDef> {
+    TYPES.iter().find(|(object, _, _)| *object == ty)?.2.iter().find(|def| def.name == name)
+}
+
+/// `User` of `[User!]!`
+fn named_type(ty: &str) -> &str {
+    ty.trim_matches(|c| matches!(c, '[' | ']' | '!'))
+}
+
+fn is_object(ty: &str) -> bool {
+    TYPES.iter().any(|(object, _, _)| *object == named_type(ty))
+}
+
+/// The schema in SDL
+pub fn sdl() -> String {
+    let mut sdl = String::from("\"\"\"Any JSON value\"\"\"\nscalar JSON\n\nschema {\n  query: Query\n}\n");
+    for (name, doc, fields) in TYPES {
+        sdl.push_str(&format!("\n\"\"\"{}\"\"\"\ntype {} {{\n", doc, name));
+        for def in fields.iter() {
+            if !def.doc.is_empty() {
+                sdl.push_str(&format!("  \"{}\"\n", def.doc));
+            }
+            let args: Vec<String> = def.args.iter().map(|(arg, ty)| format!("{}: {}", arg, ty)).collect();
+            match args.is_empty() {
+                true => sdl.push_str(&format!("  {}: {}\n", def.name, def.ty)),
+                false => sdl.push_str(&format!("  {}({}): {}\n", def.name, args.join(", "), def.ty)),
+            }
+        }
+        sdl.push_str("}\n");
+    }
+    sdl
+}
+
+/// The parsed content of `graphql.json`
+#[derive(Debug, Clone, PartialEq)]
+pub struct GraphqlSettings {
+    /// Run only the queries of `queries` and the ones clients persisted
+    /// before it was set
+    pub persisted_only: bool,
+    pub max_depth: usize,
+    /// The named queries, as `(id, query)`
+    pub queries: Vec<(String, String)>,
+}
+
+impl Default for GraphqlSettings {
+    fn default() -> Self {
+        Self { persisted_only: false, max_depth: DEFAULT_MAX_DEPTH, queries: Vec::new() }
+    }
+}
+
+impl GraphqlSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let mut queries: Vec<(String, String)> = match value.get("queries") {
+            Value::Dict(queries) => queries.iter().map(|(id, query)| (id.clone(), query.string())).collect(),
+            _ => Vec::new(),
+        };
+        queries.sort();
+        Self {
+            persisted_only: value.get("persisted_only").boolean(),
+            max_depth: match value.get("max_depth") {
+                Value::Numerical(depth) if *depth >= 1.0 => *depth as usize,
+                _ => DEFAULT_MAX_DEPTH,
+            },
+            queries,
+        }
+    }
+
+    /// The named query `id`, or the one whose SHA-256 is `id`
+    pub fn query(&self, id: &str) -> Option<&str> {
+        self.queries
+            .iter()
+            .find(|(name, query)| name == id || sha256(query) == id)
+            .map(|(_, query)| query.as_str())
+    }
+}
+
+/// The loaded GraphQL settings
+pub fn settings() -> &'static GraphqlSettings {
+    &SETTINGS
+}
+
+fn sha256(text: &str) -> String {
+    Sha256::digest(text.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The queries clients persisted, by their SHA-256
+#[derive(Debug, Default)]
+pub struct AutomaticQueries {
+    queries: HashMap<String, String>,
+    order: VecDeque<String>,
+}
+
+impl AutomaticQueries {
+    pub fn get(&self, hash: &str) -> Option<&str> {
+        self.queries.get(hash).map(String::as_str)
+    }
+
+    pub fn insert(&mut self, hash: String, query: String) {
+        if self.queries.insert(hash.clone(), query).is_none() {
+            self.order.push_back(hash);
+        }
+        while self.order.len() > MAX_AUTOMATIC {
+            if let Some(oldest) = self.order.pop_front() {
+                self.queries.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// A request as sent by the client
+#[derive(Debug, Clone)]
+pub struct GraphqlRequest {
+    pub query: Option<String>,
+    /// A named query of `graphql.json`
+    pub id: Option<String>,
+    pub operation_name: Option<String>,
+    pub variables: Value,
+    pub extensions: Value,
+}
+
+impl Default for GraphqlRequest {
+    fn default() -> Self {
+        Self { query: None, id: None, operation_name: None, variables: Value::None, extensions: Value::None }
+    }
+}
+
+impl GraphqlRequest {
+    /// A request sent as a JSON body
+    pub fn from_json(body: &Value) -> Self {
+        let text = |key: &str| match body.get(key) {
+            Value::Str(text) if !text.is_empty() => Some(text.clone()),
+            _ => None,
+        };
+        Self {
+            query: text("query"),
+            id: text("id"),
+            operation_name: text("operationName"),
+            variables: body.get("variables").clone(),
+            extensions: body.get("extensions").clone(),
+        }
+    }
+}
+
+/// Why a request was not run at all
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestError {
+    pub message: String,
+    /// `extensions.code` of the error, for the clients acting on it
+    pub code: Option<&'static str>,
+}
+
+impl RequestError {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self { message: message.into(), code: None }
+    }
+
+    pub fn into_response(self) -> HttpResponse {
+        let mut error = object!({ message: self.message });
+        if let Some(code) = self.code {
+            error.set("extensions", object!({ code: code }));
+        }
+        // Apollo clients send the query again on PERSISTED_QUERY_NOT_FOUND,
+        // which they only read off a 200
+        let status = if self.code.is_some() { StatusCode::OK } else { StatusCode::BAD_REQUEST };
+        json_response(object!({ errors: Value::List(vec![error]) })).status(status)
+    }
+}
+
+/// The query `request` runs: its own, a named one, or a persisted one
+pub fn query_text(request: &GraphqlRequest, settings: &GraphqlSettings, automatic: &mut AutomaticQueries) -> Result<String, RequestError> {
+    if let Some(id) = &request.id {
+        return settings.query(id).map(str::to_string).ok_or_else(|| RequestError::new(format!("Unknown query id \"{}\"", id)));
+    }
+    let hash = match request.extensions.get("persistedQuery").get("sha256Hash") {
+        Value::Str(hash) => Some(hash.to_ascii_lowercase()),
+        _ => None,
+    };
+    if let Some(hash) = &hash {
+        if let Some(query) = settings.query(hash).or_else(|| automatic.get(hash)) {
+            return Ok(query.to_string());
+        }
+        if request.query.is_none() {
+            return Err(RequestError { message: "PersistedQueryNotFound".to_string(), code: Some("PERSISTED_QUERY_NOT_FOUND") });
+        }
+    }
+    let Some(query) = &request.query else {
+        return Err(RequestError::new("Must provide a query"));
+    };
+    if settings.persisted_only {
+        return Err(RequestError { message: "Only persisted queries are allowed".to_string(), code: Some("PERSISTED_QUERY_NOT_SUPPORTED") });
+    }
+    if let Some(hash) = hash {
+        if sha256(query) != hash {
+            return Err(RequestError::new("provided sha does not match query"));
+        }
+        automatic.insert(hash, query.clone());
+    }
+    Ok(query.clone())
+}
+
+/// Who a query is run for
+pub struct Context<'a> {
+    pub auth: &'a AuthManager,
+    /// The uid of the signed-in local account
+    pub viewer: Option<u32>,
+    pub is_admin: bool,
+}
+
+impl Context<'static> {
+    /// The viewer of `req`: the account of its bearer token when the token
+    /// may read profiles, otherwise the local account of its session
+    pub async fn of(req: &mut HttpReqCtx) -> Self {
+        let viewer = match get_auth_token(req) {
+            _ if !modules::enabled(modules::LOCAL_AUTH) => None,
+            Some(token) => match LOCAL_AUTH.token_scopes(&token).await {
+                Some((_, scopes)) if scopes.allows(scope::PROFILE_READ) => LOCAL_AUTH.uid_of_token(&token).await,
+                _ => None,
+            },
+            None => req.local_uid(),
+        };
+        Self { auth: &LOCAL_AUTH, viewer, is_admin: check_is_admin(req).await }
+    }
+}
+
+/// The arguments of a field, with their defaults
+struct Args(HashMap<String, Value>);
+
+impl Args {
+    fn int(&self, name: &str) -> Option<i64> {
+        match self.0.get(name) {
+            Some(Value::Numerical(n)) => Some(*n as i64),
+            _ => None,
+        }
+    }
+
+    fn string(&self, name: &str) -> Option<String> {
+        match self.0.get(name) {
+            Some(Value::Str(text)) => Some(text.clone()),
+            _ => None,
+        }
+    }
+
+    fn boolean(&self, name: &str) -> bool {
+        matches!(self.0.get(name), Some(Value::Boolean(true)))
+    }
+}
+
+/// An object being resolved
+enum Node {
+    Query,
+    Viewer(u32),
+    User(u32, Box<UserStorage>),
+    UserPage { total: usize, page: usize, per_page: usize, users: Vec<(u32, UserStorage)> },
+    Post(Box<Post>),
+    Notifications(u32),
+    Category { name: String, label: String, enabled: bool },
+    Pending(notifications::Item),
+}
+
+impl Node {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Node::Query => "Query",
+            Node::Viewer(_) => "Viewer",
+            Node::User(..) => "User",
+            Node::UserPage { .. } => "UserPage",
+            Node::Post(_) => "Post",
+            Node::Notifications(_) => "Notifications",
+            Node::Category { .. } => "NotificationCategory",
+            Node::Pending(_) => "PendingNotification",
+        }
+    }
+}
+
+/// What a field resolved to, before its selection is applied
+enum Resolved {
+    Value(Value),
+    Node(Node),
+    Nodes(Vec<Node>),
+}
+
+impl From<Value> for Resolved {
+    fn from(value: Value) -> Self {
+        Resolved::Value(value)
+    }
+}
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// One run of an operation
+struct Run<'a> {
+    ctx: &'a Context<'a>,
+    document: &'a Document,
+    variables: HashMap<String, Value>,
+    errors: Mutex<Vec<Value>>,
+}
+
+/// A value of the document as JSON, variables filled in
+fn input_value(input: &Input, variables: &HashMap<String, Value>) -> Result<Value, String> {
+    Ok(match input {
+        Input::Variable(name) => variables.get(name).cloned().ok_or_else(|| format!("Variable \"${}\" is not defined", name))?,
+        Input::Int(n) => Value::Numerical(*n as f64),
+        Input::Float(n) => Value::Numerical(*n),
+        Input::Str(text) | Input::Enum(text) => Value::Str(text.clone()),
+        Input::Bool(b) => Value::Boolean(*b),
+        Input::Null => Value::None,
+        Input::List(items) => Value::List(items.iter().map(|item| input_value(item, variables)).collect::<Result<_, _>>()?),
+        Input::Object(entries) => {
+            let mut object = Value::new_dict();
+            for (key, value) in entries {
+                object.set(key, input_value(value, variables)?);
+            }
+            object
+        }
+    })
+}
+
+/// Whether `value` may be given where `ty` is expected
+fn fits(ty: &str, value: &Value) -> bool {
+    if matches!(value, Value::None) {
+        return !ty.ends_with('!');
+    }
+    let ty = ty.trim_end_matches('!');
+    if let Some(item) = ty.strip_prefix('[').and_then(|ty| ty.strip_suffix(']')) {
+        return match value {
+            Value::List(items) => items.iter().all(|value| fits(item, value)),
+            value => fits(item, value),
+        };
+    }
+    match (ty, value) {
+        ("Int", Value::Numerical(n)) => n.fract() == 0.0,
+        ("Float", Value::Numerical(_)) | ("String" | "ID", Value::Str(_)) | ("Boolean", Value::Boolean(_)) | ("JSON", _) => true,
+        ("ID", Value::Numerical(n)) => n.fract() == 0.0,
+        _ => false,
+    }
+}
+
+/// The default written after `=` in [`TYPES`]
+fn default_value(literal: &str) -> Value {
+    match literal {
+        "true" => Value::Boolean(true),
+        "false" => Value::Boolean(false),
+        literal => literal.parse::<f64>().map(Value::Numerical).unwrap_or_else(|_| Value::Str(literal.to_string())),
+    }
+}
+
+impl<'a> Run<'a> {
+    fn error(&self, message: impl Into<String>, path: &[Value]) {
+        self.errors.lock().unwrap().push(object!({ message: message.into(), path: Value::List(path.to_vec()) }));
+    }
+
+    /// Whether `@skip` and `@include` keep a selection
+    fn included(&self, directives: &[parse::Directive]) -> bool {
+        directives.iter().all(|directive| {
+            let condition = directive
+                .arguments
+                .iter()
+                .find(|(name, _)| name == "if")
+                .and_then(|(_, input)| input_value(input, &self.variables).ok())
+                .is_some_and(|value| value.boolean());
+            match directive.name.as_str() {
+                "skip" => !condition,
+                "include" => condition,
+                _ => true,
+            }
+        })
+    }
+
+    /// The fields `selection` asks of an object of type `ty`, by key, with
+    /// the fields of the same key merged
+    fn collect<'d>(&self, ty: &str, selection: &'d [Selection], fields: &mut Vec<(String, Vec<&'d Field>)>)
+    where
+        'a: 'd,
+    {
+        for selection in selection {
+            match selection {
+                Selection::Field(field) if self.included(&field.directives) => {
+                    match fields.iter_mut().find(|(key, _)| key == field.key()) {
+                        Some((_, same)) => same.push(field),
+                        None => fields.push((field.key().to_string(), vec![field])),
+                    }
+                }
+                Selection::Spread { name, directives } if self.included(directives) => {
+                    if let Some(fragment) = self.document.fragment(name).filter(|fragment| fragment.on == ty) {
+                        self.collect(ty, &fragment.selection, fields);
+                    }
+                }
+                Selection::Inline { on, directives, selection } if self.included(directives) && on.as_deref().is_none_or(|on| on == ty) => {
+                    self.collect(ty, selection, fields);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn args(&self, ty: &str, field: &Field) -> Result<Args, String> {
+        let def = field_def(ty, &field.name).ok_or_else(|| format!("Cannot query field \"{}\" on type \"{}\"", field.name, ty))?;
+        let mut args = HashMap::new();
+        for (name, declared) in def.args {
+            let (arg_ty, default) = match declared.split_once(" = ") {
+                Some((arg_ty, default)) => (arg_ty, Some(default_value(default))),
+                None => (*declared, None),
+            };
+            let value = match field.arguments.iter().find(|(given, _)| given == name) {
+                Some((_, input)) => input_value(input, &self.variables)?,
+                None => Value::None,
+            };
+            let value = match (value, default) {
+                (Value::None, Some(default)) => default,
+                (value, _) => value,
+            };
+            if !fits(arg_ty, &value) {
+                return Err(format!("Argument \"{}\" of \"{}\" must be {}", name, field.name, arg_ty));
+            }
+            args.insert(name.to_string(), value);
+        }
+        Ok(Args(args))
+    }
+
+    /// The selection of `fields` on `node`
+    fn object<'r>(&'r self, node: &'r Node, selection: Vec<&'r Field>, path: Vec<Value>) -> BoxFuture<'r, Value> {
+        Box::pin(async move {
+            let ty = node.type_name();
+            let mut fields = Vec::new();
+            for field in selection {
+                self.collect(ty, &field.selection, &mut fields);
+            }
+            self.fields(node, fields, path).await
+        })
+    }
+
+    fn fields<'r>(&'r self, node: &'r Node, fields: Vec<(String, Vec<&'r Field>)>, path: Vec<Value>) -> BoxFuture<'r, Value> {
+        Box::pin(async move {
+            let ty = node.type_name();
+            let mut object = Value::new_dict();
+            for (key, same) in fields {
+                let field = same[0];
+                let mut path = path.clone();
+                path.push(Value::from(key.as_str()));
+                if field.name == "__typename" {
+                    object.set(key, ty);
+                    continue;
+                }
+                let resolved = match self.args(ty, field) {
+                    Ok(args) => self.resolve(node, &field.name, &args).await,
+                    Err(err) => Err(err),
+                };
+                let value = match resolved {
+                    Ok(Resolved::Value(value)) => value,
+                    Ok(Resolved::Node(child)) => self.object(&child, same.clone(), path.clone()).await,
+                    Ok(Resolved::Nodes(children)) => {
+                        let mut items = Vec::new();
+                        for (index, child) in children.iter().enumerate() {
+                            let mut path = path.clone();
+                            path.push(Value::Numerical(index as f64));
+                            items.push(self.object(child, same.clone(), path).await);
+                        }
+                        Value::List(items)
+                    }
+                    Err(err) => {
+                        self.error(err, &path);
+                        Value::None
+                    }
+                };
+                object.set(key, value);
+            }
+            object
+        })
+    }
+
+    /// Whether the viewer may see the private fields of `uid`
+    fn owns(&self, uid: u32) -> bool {
+        self.ctx.is_admin || self.ctx.viewer == Some(uid)
+    }
+
+    async fn user(&self, uid: u32) -> Option<Node> {
+        if !modules::enabled(modules::LOCAL_AUTH) {
+            return None;
+        }
+        self.ctx.auth.admin_get_user(uid).await.map(|user| Node::User(uid, Box::new(user)))
+    }
+
+    async fn resolve(&self, node: &Node, name: &str, args: &Args) -> Result<Resolved, String> {
+        let nullable = |node: Option<Node>| node.map(Resolved::Node).unwrap_or(Resolved::Value(Value::None));
+        Ok(match (node, name) {
+            (Node::Query, "viewer") => nullable(self.ctx.viewer.map(Node::Viewer)),
+            (Node::Query, "user") => {
+                let uid = match (args.int("uid"), args.string("username")) {
+                    (Some(uid), _) => u32::try_from(uid).ok(),
+                    (None, Some(username)) if modules::enabled(modules::LOCAL_AUTH) => {
+                        self.ctx.auth.resolve_username(&username).await.map(|(uid, _)| uid)
+                    }
+                    (None, Some(_)) => None,
+                    (None, None) => return Err("Give the uid or the username of the user".to_string()),
+                };
+                match uid {
+                    Some(uid) => nullable(self.user(uid).await),
+                    None => Value::None.into(),
+                }
+            }
+            (Node::Query, "users") => {
+                if !self.ctx.is_admin {
+                    return Err("Only admins may list the users".to_string());
+                }
+                let search = args.string("search").unwrap_or_default().to_lowercase();
+                let users: Vec<(u32, UserStorage)> = match modules::enabled(modules::LOCAL_AUTH) {
+                    true => self.ctx.auth.admin_list_users().await,
+                    false => Vec::new(),
+                };
+                let users: Vec<(u32, UserStorage)> = users
+                    .into_iter()
+                    .filter(|(_, user)| {
+                        search.is_empty()
+                            || user.username.to_lowercase().contains(&search)
+                            || user.display_name().to_lowercase().contains(&search)
+                            || user.email.to_lowercase().contains(&search)
+                    })
+                    .collect();
+                let page = args.int("page").unwrap_or(1).max(1) as usize;
+                let per_page = args.int("perPage").unwrap_or(10).clamp(1, MAX_PER_PAGE as i64) as usize;
+                let total = users.len();
+                let users = users.into_iter().skip((page - 1).saturating_mul(per_page)).take(per_page).collect();
+                Resolved::Node(Node::UserPage { total, page, per_page, users })
+            }
+            (Node::Query, "post") => {
+                let post = blog::get(&args.string("slug").unwrap_or_default())
+                    .filter(|post| modules::enabled(modules::BLOG) && (post.is_published() || self.ctx.is_admin));
+                nullable(post.map(|post| Node::Post(Box::new(post))))
+            }
+            (Node::Query, "posts") => {
+                let drafts = args.boolean("drafts");
+                if drafts && !self.ctx.is_admin {
+                    return Err("Only admins may list drafts".to_string());
+                }
+                if !modules::enabled(modules::BLOG) {
+                    return Ok(Resolved::Nodes(Vec::new()));
+                }
+                let posts = match args.string("tag") {
+                    Some(tag) => blog::posts(drafts).into_iter().filter(|post| post.tags.contains(&tag)).collect(),
+                    None => blog::posts(drafts),
+                };
+                let first = args.int("first").unwrap_or(10).clamp(0, MAX_PER_PAGE as i64) as usize;
+                let offset = args.int("offset").unwrap_or(0).max(0) as usize;
+                Resolved::Nodes(posts.into_iter().skip(offset).take(first).map(|post| Node::Post(Box::new(post))).collect())
+            }
+
+            (Node::Viewer(uid), "user") => nullable(self.user(*uid).await),
+            (Node::Viewer(_), "isAdmin") => Value::Boolean(self.ctx.is_admin).into(),
+            (Node::Viewer(uid), "notifications") => Resolved::Node(Node::Notifications(*uid)),
+
+            (Node::User(uid, _), "uid") => Value::from(*uid).into(),
+            (Node::User(_, user), "username") => Value::from(user.username.as_str()).into(),
+            (Node::User(_, user), "displayName") => Value::from(user.display_name()).into(),
+            (Node::User(_, user), "avatar") => public::avatar(user).into(),
+            (Node::User(_, user), "isActive") => Value::Boolean(user.is_active).into(),
+            (Node::User(uid, user), "profile") => {
+                let viewer = self.ctx.viewer.map(Viewer::User).unwrap_or(Viewer::Anonymous);
+                public::settings().visible_fields(*uid, &user.profile, viewer).into()
+            }
+            (Node::User(uid, user), "email") => match self.owns(*uid) {
+                true => Value::from(user.email.as_str()).into(),
+                false => return Err("Only the user and admins may see the email".to_string()),
+            },
+            (Node::User(uid, _), "roles") => match self.owns(*uid) {
+                true => {
+                    let admin = op::read_admin_entries().contains(&format!("{}@local", uid));
+                    Value::List(if admin { vec![Value::from("admin")] } else { Vec::new() }).into()
+                }
+                false => return Err("Only the user and admins may see the roles".to_string()),
+            },
+
+            (Node::UserPage { total, .. }, "total") => Value::from(*total).into(),
+            (Node::UserPage { page, .. }, "page") => Value::from(*page).into(),
+            (Node::UserPage { per_page, .. }, "perPage") => Value::from(*per_page).into(),
+            (Node::UserPage { users, .. }, "users") => {
+                Resolved::Nodes(users.iter().map(|(uid, user)| Node::User(*uid, Box::new(user.clone()))).collect())
+            }
+
+            (Node::Post(post), "slug") => Value::from(post.slug.as_str()).into(),
+            (Node::Post(post), "title") => Value::from(post.title.as_str()).into(),
+            (Node::Post(post), "summary") => Value::from(post.summary()).into(),
+            (Node::Post(post), "html") => Value::from(post.html(blog::settings().raw_html)).into(),
+            (Node::Post(post), "tags") => Value::from(post.tags.clone()).into(),
+            (Node::Post(post), "authorName") => Value::from(post.author_name.as_str()).into(),
+            (Node::Post(post), "author") => match post.author.strip_suffix("@local").and_then(|uid| uid.parse().ok()) {
+                Some(uid) => nullable(self.user(uid).await),
+                None => Value::None.into(),
+            },
+            (Node::Post(post), "published") => match post.published {
+                0 => Value::None.into(),
+                published => Value::from(published).into(),
+            },
+            (Node::Post(post), "updated") => Value::from(post.updated).into(),
+            (Node::Post(post), "draft") => Value::Boolean(!post.is_published()).into(),
+
+            (Node::Notifications(uid), "delivery") => {
+                let prefs = UserPrefs::for_uid(*uid).await;
+                Value::from(prefs.get::<String>(DELIVERY_KEY).unwrap_or_else(|| "immediate".to_string())).into()
+            }
+            (Node::Notifications(uid), "timezone") => {
+                let prefs = UserPrefs::for_uid(*uid).await;
+                Value::from(prefs.get::<String>(TIMEZONE_KEY).unwrap_or_else(|| "+00:00".to_string())).into()
+            }
+            (Node::Notifications(uid), "categories") => {
+                let prefs = UserPrefs::for_uid(*uid).await;
+                Resolved::Nodes(
+                    notifications::categories()
+                        .into_iter()
+                        .map(|category| Node::Category {
+                            enabled: prefs.get::<bool>(&notifications::toggle_key(&category.name)).unwrap_or(true),
+                            name: category.name,
+                            label: category.label,
+                        })
+                        .collect(),
+                )
+            }
+            (Node::Notifications(uid), "pending") => Resolved::Nodes(notifications::pending(*uid).into_iter().map(Node::Pending).collect()),
+
+            (Node::Category { name, .. }, "name") => Value::from(name.as_str()).into(),
+            (Node::Category { label, .. }, "label") => Value::from(label.as_str()).into(),
+            (Node::Category { enabled, .. }, "enabled") => Value::Boolean(*enabled).into(),
+
+            (Node::Pending(item), "category") => Value::from(item.category.as_str()).into(),
+            (Node::Pending(item), "subject") => Value::from(item.subject.as_str()).into(),
+            (Node::Pending(item), "text") => Value::from(item.text.as_str()).into(),
+            (Node::Pending(item), "at") => Value::from(item.at).into(),
+
+            (node, name) => return Err(format!("Cannot query field \"{}\" on type \"{}\"", name, node.type_name())),
+        })
+    }
+}
+
+/// Check `selection` against the schema before anything is resolved
+fn validate(document: &Document, ty: &str, selection: &[Selection], depth: usize, max_depth: usize, spread: &mut Vec<String>) -> Result<(), String> {
+    for selection in selection {
+        match selection {
+            Selection::Field(field) if field.name == "__typename" => {
+                if !field.selection.is_empty() {
+                    return Err("Field \"__typename\" must not have a selection".to_string());
+                }
+            }
+            Selection::Field(field) => {
+                let def = field_def(ty, &field.name).ok_or_else(|| format!("Cannot query field \"{}\" on type \"{}\"", field.name, ty))?;
+                for (name, input) in &field.arguments {
+                    let Some((_, declared)) = def.args.iter().find(|(arg, _)| arg == name) else {
+                        return Err(format!("Unknown argument \"{}\" on field \"{}.{}\"", name, ty, field.name));
+                    };
+                    // Variables are checked against their own type once given
+                    let arg_ty = declared.split(" = ").next().unwrap_or(declared);
+                    if let Ok(value) = input_value(input, &HashMap::new())
+                        && !fits(arg_ty, &value)
+                    {
+                        return Err(format!("Argument \"{}\" of \"{}\" must be {}", name, field.name, arg_ty));
+                    }
+                }
+                match (is_object(def.ty), field.selection.is_empty()) {
+                    (true, true) => return Err(format!("Field \"{}\" of type \"{}\" must have a selection of subfields", field.name, def.ty)),
+                    (false, false) => return Err(format!("Field \"{}\" must not have a selection since type \"{}\" has no subfields", field.name, def.ty)),
+                    (true, false) if depth + 1 > max_depth => return Err(format!("The query is nested deeper than {} levels", max_depth)),
+                    (true, false) => validate(document, named_type(def.ty), &field.selection, depth + 1, max_depth, spread)?,
+                    (false, true) => {}
+                }
+            }
+            Selection::Spread { name, .. } => {
+                let fragment = document.fragment(name).ok_or_else(|| format!("Unknown fragment \"{}\"", name))?;
+                if spread.contains(name) {
+                    return Err(format!("Cannot spread fragment \"{}\" within itself", name));
+                }
+                if fragment.on != ty {
+                    return Err(format!("Fragment \"{}\" on \"{}\" cannot be spread on type \"{}\"", name, fragment.on, ty));
+                }
+                spread.push(name.clone());
+                validate(document, ty, &fragment.selection, depth, max_depth, spread)?;
+                spread.pop();
+            }
+            Selection::Inline { on, selection, .. } => {
+                if let Some(on) = on.as_deref().filter(|on| *on != ty) {
+                    return Err(format!("Fragment on \"{}\" cannot be spread on type \"{}\"", on, ty));
+                }
+                validate(document, ty, selection, depth, max_depth, spread)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Run `query` for `ctx`: `{"data": ..., "errors": [...]}`, with `errors`
+/// only when a field failed. `Err` when nothing could be run.
+pub async fn execute(ctx: &Context<'_>, query: &str, operation_name: Option<&str>, variables: &Value, max_depth: usize) -> Result<Value, RequestError> {
+    let document = parse::parse(query).map_err(|err| RequestError::new(format!("Syntax error: {}", err)))?;
+    let operation = document.operation(operation_name).map_err(RequestError::new)?;
+    if operation.kind != OperationKind::Query {
+        return Err(RequestError::new("Only queries are supported"));
+    }
+    validate(&document, "Query", &operation.selection, 0, max_depth, &mut Vec::new()).map_err(RequestError::new)?;
+
+    let mut values = HashMap::new();
+    for definition in &operation.variables {
+        let value = match variables.get(&definition.name) {
+            Value::None => match &definition.default {
+                Some(default) => input_value(default, &HashMap::new()).map_err(RequestError::new)?,
+                None => Value::None,
+            },
+            value => value.clone(),
+        };
+        if !fits(&definition.ty, &value) {
+            return Err(RequestError::new(format!("Variable \"${}\" must be {}", definition.name, definition.ty)));
+        }
+        values.insert(definition.name.clone(), value);
+    }
+
+    let run = Run { ctx, document: &document, variables: values, errors: Mutex::new(Vec::new()) };
+    let mut fields = Vec::new();
+    run.collect("Query", &operation.selection, &mut fields);
+    let data = run.fields(&Node::Query, fields, Vec::new()).await;
+    let errors = run.errors.into_inner().unwrap();
+    let mut response = object!({ data: data });
+    if !errors.is_empty() {
+        response.set("errors", Value::List(errors));
+    }
+    Ok(response)
+}
+
+/// Run the request of a client
+async fn answer(req: &mut HttpReqCtx, request: GraphqlRequest) -> HttpResponse {
+    let query = {
+        let mut automatic = AUTOMATIC.lock().unwrap();
+        query_text(&request, settings(), &mut automatic)
+    };
+    let query = match query {
+        Ok(query) => query,
+        Err(err) => return err.into_response(),
+    };
+    let ctx = Context::of(req).await;
+    match execute(&ctx, &query, request.operation_name.as_deref(), &request.variables, settings().max_depth).await {
+        Ok(response) => json_response(response).add_header("Cache-Control", "no-store"),
+        Err(err) => err.into_response(),
+    }
+}
+
+endpoint! {
+    APP.url("/graphql"),
+
+    /// GET or POST /graphql - Run a GraphQL query (see the schema at `/graphql/schema`)
+    /// Request (GET): ?query=...&variables={...}&operationName=...&extensions={...}&id=...
+    /// Request (POST): Json -> {"query": "...", "variables": {...}, "operationName": "...", "extensions": {...}, "id": "..."}
+    /// Response (1): 400 {"errors": [{"message": "Syntax error: ..."}]}
+    /// Response (2): {"errors": [{"message": "PersistedQueryNotFound", "extensions": {"code": "PERSISTED_QUERY_NOT_FOUND"}}]}
+    /// Response (3): {"data": {...}, "errors": [{"message": "...", "path": ["user", "email"]}]}
+    pub graphql_route <HTTP> {
+        methods!(req, GET | POST);
+        let request = if req.method() == HttpMethod::POST {
+            match req.json_or_default().await {
+                body @ Value::Dict(_) => GraphqlRequest::from_json(&body.clone()),
+                _ => return RequestError::new("The body must be a JSON object").into_response(),
+            }
+        } else {
+            let mut text = |key: &str| req.query_arg::<String>(key).ok().flatten().filter(|text| !text.is_empty());
+            let query = text("query");
+            let id = text("id");
+            let operation_name = text("operationName");
+            let mut json = |key: &str| match text(key) {
+                Some(json) => Value::from_json(&json).map_err(|_| RequestError::new(format!("Invalid {}", key))),
+                None => Ok(Value::None),
+            };
+            let (variables, extensions) = match (json("variables"), json("extensions")) {
+                (Ok(variables), Ok(extensions)) => (variables, extensions),
+                (Err(err), _) | (_, Err(err)) => return err.into_response(),
+            };
+            GraphqlRequest { query, id, operation_name, variables, extensions }
+        };
+        answer(req, request).await
+    }
+}
+
+endpoint! {
+    APP.url("/graphql/schema"),
+
+    /// GET /graphql/schema - The schema of `/graphql`, in SDL
+    pub graphql_schema <HTTP> {
+        methods!(req, GET);
+        text_response(sdl())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    async fn run(ctx: &Context<'_>, query: &str, variables: Value) -> Value {
+        execute(ctx, query, None, &variables, DEFAULT_MAX_DEPTH).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn private_fields_are_resolved_for_their_owner() {
+        let auth = testing::auth_manager(&[("ann", "Aa111111"), ("bob", "Bb222222")]).await;
+        let query = r#"
+            query($uid: Int!) {
+                user(uid: $uid) { __typename uid username ...Private }
+                nobody: user(username: "carol") { uid }
+            }
+            fragment Private on User { email roles }
+        "#;
+        let variables = Value::from_json(r#"{"uid": 1}"#).unwrap();
+
+        let guest = Context { auth: &auth, viewer: None, is_admin: false };
+        let response = run(&guest, query, variables.clone()).await;
+        let user = response.get("data").get("user");
+        assert_eq!((user.get("username").string(), user.get("__typename").string()), ("ann".to_string(), "User".to_string()));
+        assert!(matches!(user.get("email"), Value::None) && matches!(response.get("data").get("nobody"), Value::None));
+        let errors = response.get("errors").list();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].get("path").into_json(), r#"["user","email"]"#);
+
+        let own = Context { auth: &auth, viewer: Some(1), is_admin: false };
+        let response = run(&own, query, variables.clone()).await;
+        assert_eq!(response.get("data").get("user").get("email").string(), "ann@example.com");
+        assert!(response.try_get("errors").is_err());
+        assert!(matches!(run(&own, "{ users { total } }", Value::None).await.get("data").get("users"), Value::None));
+
+        let admin = Context { auth: &auth, viewer: Some(2), is_admin: true };
+        let response = run(&admin, r#"{ users(search: "B") { total users { username email } } }"#, Value::None).await;
+        assert_eq!(response.get("data").get("users").get("total").integer(), 1);
+        assert_eq!(response.get("data").get("users").get("users").list()[0].get("email").string(), "bob@example.com");
+
+        // Nothing is run for queries the schema does not allow
+        for bad in ["{ user(uid: 1) { password } }", "{ user(uid: 1) }", "mutation { viewer { isAdmin } }", "{ user(uid: \"x\") { uid } }", "{ ...A } fragment A on Query { ...A }"] {
+            assert!(execute(&guest, bad, None, &Value::None, DEFAULT_MAX_DEPTH).await.is_err(), "{}", bad);
+        }
+        assert!(execute(&guest, "{ viewer { user { uid } } }", None, &Value::None, 1).await.is_err());
+        assert!(execute(&guest, "query($uid: Int!) { user(uid: $uid) { uid } }", None, &Value::None, 10).await.is_err());
+        assert!(sdl().contains("posts(tag: String, first: Int = 10, offset: Int = 0, drafts: Boolean = false): [Post!]!"));
+    }
+
+    #[test]
+    fn persisted_queries_are_found_by_id_and_hash() {
+        let query = "{ viewer { isAdmin } }";
+        let settings = GraphqlSettings::from_value(&Value::from_json(r#"{"persisted_only": true, "queries": {"admin": "{ viewer { isAdmin } }"}}"#).unwrap());
+        let mut automatic = AutomaticQueries::default();
+        let by_id = GraphqlRequest { id: Some("admin".to_string()), ..Default::default() };
+        assert_eq!(query_text(&by_id, &settings, &mut automatic).as_deref(), Ok(query));
+        let hashed = |query: Option<&str>, hash: &str| GraphqlRequest {
+            query: query.map(str::to_string),
+            extensions: Value::from_json(&format!(r#"{{"persistedQuery": {{"version": 1, "sha256Hash": "{}"}}}}"#, hash)).unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(query_text(&hashed(None, &sha256(query)), &settings, &mut automatic).as_deref(), Ok(query));
+        let other = "{ posts { slug } }";
+        assert_eq!(query_text(&hashed(Some(other), &sha256(other)), &settings, &mut automatic).unwrap_err().code, Some("PERSISTED_QUERY_NOT_SUPPORTED"));
+
+        // Clients register theirs by sending the query with its hash once
+        let settings = GraphqlSettings::default();
+        assert_eq!(query_text(&hashed(None, &sha256(other)), &settings, &mut automatic).unwrap_err().code, Some("PERSISTED_QUERY_NOT_FOUND"));
+        assert!(query_text(&hashed(Some(other), &sha256(query)), &settings, &mut automatic).is_err());
+        assert_eq!(query_text(&hashed(Some(other), &sha256(other)), &settings, &mut automatic).as_deref(), Ok(other));
+        assert_eq!(query_text(&hashed(None, &sha256(other)), &settings, &mut automatic).as_deref(), Ok(other));
+    }
+}
@@ -0,0 +1,552 @@
+//! parse.rs
+//!
+//! Reading GraphQL documents: operations with their variables, fragments,
+//! aliases, arguments and directives. Type definitions are not read; the
+//! schema of `super` is fixed in code.
+
+/// Selections, input values and types nested deeper than this are refused
+/// while parsing, before `max_depth` of `graphql.json` is looked at
+const MAX_NESTING: usize = 64;
+
+/// A value written in the document
+#[derive(Debug, Clone, PartialEq)]
+pub enum Input {
+    Variable(String),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Bool(bool),
+    Null,
+    Enum(String),
+    List(Vec<Input>),
+    Object(Vec<(String, Input)>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Directive {
+    pub name: String,
+    pub arguments: Vec<(String, Input)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub alias: Option<String>,
+    pub name: String,
+    pub arguments: Vec<(String, Input)>,
+    pub directives: Vec<Directive>,
+    pub selection: Vec<Selection>,
+}
+
+impl Field {
+    /// The key of the field in the answer
+    pub fn key(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Selection {
+    Field(Field),
+    /// `...Name`
+    Spread { name: String, directives: Vec<Directive> },
+    /// `... on Type { }` or `... { }`
+    Inline { on: Option<String>, directives: Vec<Directive>, selection: Vec<Selection> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperationKind {
+    Query,
+    Mutation,
+    Subscription,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VariableDefinition {
+    pub name: String,
+    /// As written, like `[Int!]!`
+    pub ty: String,
+    pub default: Option<Input>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Operation {
+    pub kind: OperationKind,
+    pub name: Option<String>,
+    pub variables: Vec<VariableDefinition>,
+    pub selection: Vec<Selection>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fragment {
+    pub name: String,
+    pub on: String,
+    pub selection: Vec<Selection>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Document {
+    pub operations: Vec<Operation>,
+    pub fragments: Vec<Fragment>,
+}
+
+impl Document {
+    /// The operation to run: the one named `name`, or the only one
+    pub fn operation(&self, name: Option<&str>) -> Result<&Operation, String> {
+        match name.filter(|name| !name.is_empty()) {
+            Some(name) => self
+                .operations
+                .iter()
+                .find(|operation| operation.name.as_deref() == Some(name))
+                .ok_or_else(|| format!("Unknown operation named \"{}\"", name)),
+            None if self.operations.len() == 1 => Ok(&self.operations[0]),
+            None if self.operations.is_empty() => Err("The document has no operation".to_string()),
+            None => Err("Must provide operation name if query contains multiple operations".to_string()),
+        }
+    }
+
+    pub fn fragment(&self, name: &str) -> Option<&Fragment> {
+        self.fragments.iter().find(|fragment| fragment.name == name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Punct(char),
+    Spread,
+    Name(String),
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+fn tokens(source: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            ' ' | '\t' | '\n' | '\r' | ',' | '\u{feff}' => i += 1,
+            '#' => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+            '!' | '$' | '(' | ')' | ':' | '=' | '@' | '[' | ']' | '{' | '}' | '|' | '&' => {
+                tokens.push(Token::Punct(c));
+                i += 1;
+            }
+            '.' => {
+                if chars.get(i + 1) != Some(&'.') || chars.get(i + 2) != Some(&'.') {
+                    return Err("Expected \"...\"".to_string());
+                }
+                tokens.push(Token::Spread);
+                i += 3;
+            }
+            '"' if chars.get(i + 1) == Some(&'"') && chars.get(i + 2) == Some(&'"') => {
+                let start = i + 3;
+                let mut end = start;
+                while end + 2 < chars.len() && !(chars[end] == '"' && chars[end + 1] == '"' && chars[end + 2] == '"') {
+                    end += 1;
+                }
+                if end + 2 >= chars.len() {
+                    return Err("Unterminated block string".to_string());
+                }
+                let text: String = chars[start..end].iter().collect();
+                tokens.push(Token::Str(block_string(&text.replace("\\\"\"\"", "\"\"\""))));
+                i = end + 3;
+            }
+            '"' => {
+                let mut text = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None | Some('\n') => return Err("Unterminated string".to_string()),
+                        Some('"') => break,
+                        Some('\\') => {
+                            let escaped = match chars.get(i + 1) {
+                                Some('n') => '\n',
+                                Some('t') => '\t',
+                                Some('r') => '\r',
+                                Some('b') => '\u{8}',
+                                Some('f') => '\u{c}',
+                                Some(c @ ('"' | '\\' | '/')) => *c,
+                                Some('u') => {
+                                    let hex: String = chars.iter().skip(i + 2).take(4).collect();
+                                    let code = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32);
+                                    i += 4;
+                                    code.ok_or_else(|| format!("Invalid unicode escape \\u{}", hex))?
+                                }
+                                _ => return Err("Invalid escape in string".to_string()),
+                            };
+                            text.push(escaped);
+                            i += 2;
+                        }
+                        Some(c) => {
+                            text.push(*c);
+                            i += 1;
+                        }
+                    }
+                }
+                tokens.push(Token::Str(text));
+                i += 1;
+            }
+            '-' | '0'..='9' => {
+                let start = i;
+                i += 1;
+                let mut float = false;
+                while let Some(&c) = chars.get(i) {
+                    match c {
+                        '0'..='9' => {}
+                        '.' | 'e' | 'E' => float = true,
+                        '+' | '-' if matches!(chars[i - 1], 'e' | 'E') => {}
+                        _ => break,
+                    }
+                    i += 1;
+                }
+                let text: String = chars[start..i].iter().collect();
+                let token = if float { text.parse().map(Token::Float).ok() } else { text.parse().map(Token::Int).ok() };
+                tokens.push(token.ok_or_else(|| format!("Invalid number {}", text))?);
+            }
+            c if c == '_' || c.is_ascii_alphabetic() => {
+                let start = i;
+                while chars.get(i).is_some_and(|c| *c == '_' || c.is_ascii_alphanumeric()) {
+                    i += 1;
+                }
+                tokens.push(Token::Name(chars[start..i].iter().collect()));
+            }
+            c => return Err(format!("Unexpected character \"{}\"", c)),
+        }
+    }
+    Ok(tokens)
+}
+
+/// The value of a `"""` string: common indentation and blank first and last
+/// lines removed
+fn block_string(raw: &str) -> String {
+    let lines: Vec<&str> = raw.lines().collect();
+    let indent = lines
+        .iter()
+        .skip(1)
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    let mut lines: Vec<String> = lines
+        .iter()
+        .enumerate()
+        .map(|(index, line)| if index == 0 { line.to_string() } else { line.chars().skip(indent).collect() })
+        .collect();
+    while lines.first().is_some_and(|line| line.trim().is_empty()) {
+        lines.remove(0);
+    }
+    while lines.last().is_some_and(|line| line.trim().is_empty()) {
+        lines.pop();
+    }
+    lines.join("\n")
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    at: usize,
+    nesting: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.at)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.at).cloned();
+        self.at += 1;
+        token
+    }
+
+    fn is_punct(&self, c: char) -> bool {
+        self.peek() == Some(&Token::Punct(c))
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let found = self.is_punct(c);
+        if found {
+            self.at += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.eat(c) { Ok(()) } else { Err(format!("Expected \"{}\", found {}", c, self.describe())) }
+    }
+
+    fn describe(&self) -> String {
+        match self.peek() {
+            None => "the end of the document".to_string(),
+            Some(Token::Punct(c)) => format!("\"{}\"", c),
+            Some(Token::Spread) => "\"...\"".to_string(),
+            Some(Token::Name(name)) => format!("\"{}\"", name),
+            Some(Token::Int(n)) => n.to_string(),
+            Some(Token::Float(n)) => n.to_string(),
+            Some(Token::Str(_)) => "a string".to_string(),
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        match self.peek() {
+            Some(Token::Name(name)) => {
+                let name = name.clone();
+                self.at += 1;
+                Ok(name)
+            }
+            _ => Err(format!("Expected a name, found {}", self.describe())),
+        }
+    }
+
+    fn document(&mut self) -> Result<Document, String> {
+        let mut document = Document::default();
+        while self.peek().is_some() {
+            match self.peek() {
+                Some(Token::Punct('{')) => {
+                    let selection = self.selection_set()?;
+                    document.operations.push(Operation { kind: OperationKind::Query, name: None, variables: Vec::new(), selection });
+                }
+                Some(Token::Name(keyword)) if keyword == "fragment" => {
+                    self.at += 1;
+                    let name = self.name()?;
+                    if name == "on" {
+                        return Err("A fragment cannot be named \"on\"".to_string());
+                    }
+                    match self.name()?.as_str() {
+                        "on" => {}
+                        _ => return Err(format!("Expected \"on\" after fragment {}", name)),
+                    }
+                    let on = self.name()?;
+                    self.directives()?;
+                    let selection = self.selection_set()?;
+                    document.fragments.push(Fragment { name, on, selection });
+                }
+                Some(Token::Name(keyword)) if matches!(keyword.as_str(), "query" | "mutation" | "subscription") => {
+                    let kind = match keyword.as_str() {
+                        "query" => OperationKind::Query,
+                        "mutation" => OperationKind::Mutation,
+                        _ => OperationKind::Subscription,
+                    };
+                    self.at += 1;
+                    let name = match self.peek() {
+                        Some(Token::Name(_)) => Some(self.name()?),
+                        _ => None,
+                    };
+                    let variables = self.variable_definitions()?;
+                    self.directives()?;
+                    let selection = self.selection_set()?;
+                    document.operations.push(Operation { kind, name, variables, selection });
+                }
+                _ => return Err(format!("Unexpected {}", self.describe())),
+            }
+        }
+        Ok(document)
+    }
+
+    fn variable_definitions(&mut self) -> Result<Vec<VariableDefinition>, String> {
+        let mut definitions = Vec::new();
+        if !self.eat('(') {
+            return Ok(definitions);
+        }
+        while !self.eat(')') {
+            self.expect('$')?;
+            let name = self.name()?;
+            self.expect(':')?;
+            let ty = self.type_reference()?;
+            let default = if self.eat('=') { Some(self.value(true)?) } else { None };
+            self.directives()?;
+            definitions.push(VariableDefinition { name, ty, default });
+        }
+        Ok(definitions)
+    }
+
+    fn type_reference(&mut self) -> Result<String, String> {
+        let mut ty = if self.eat('[') {
+            self.nest()?;
+            let inner = self.type_reference()?;
+            self.expect(']')?;
+            self.nesting -= 1;
+            format!("[{}]", inner)
+        } else {
+            self.name()?
+        };
+        if self.eat('!') {
+            ty.push('!');
+        }
+        Ok(ty)
+    }
+
+    fn directives(&mut self) -> Result<Vec<Directive>, String> {
+        let mut directives = Vec::new();
+        while self.eat('@') {
+            let name = self.name()?;
+            let arguments = self.arguments()?;
+            directives.push(Directive { name, arguments });
+        }
+        Ok(directives)
+    }
+
+    fn arguments(&mut self) -> Result<Vec<(String, Input)>, String> {
+        let mut arguments = Vec::new();
+        if !self.eat('(') {
+            return Ok(arguments);
+        }
+        while !self.eat(')') {
+            let name = self.name()?;
+            self.expect(':')?;
+            arguments.push((name, self.value(false)?));
+        }
+        Ok(arguments)
+    }
+
+    /// A value; `constant` ones may not hold variables
+    fn value(&mut self, constant: bool) -> Result<Input, String> {
+        match self.next() {
+            Some(Token::Punct('$')) if !constant => Ok(Input::Variable(self.name()?)),
+            Some(Token::Int(n)) => Ok(Input::Int(n)),
+            Some(Token::Float(n)) => Ok(Input::Float(n)),
+            Some(Token::Str(text)) => Ok(Input::Str(text)),
+            Some(Token::Name(name)) => Ok(match name.as_str() {
+                "true" => Input::Bool(true),
+                "false" => Input::Bool(false),
+                "null" => Input::Null,
+                _ => Input::Enum(name),
+            }),
+            Some(Token::Punct('[')) => {
+                self.nest()?;
+                let mut items = Vec::new();
+                while !self.eat(']') {
+                    items.push(self.value(constant)?);
+                }
+                self.nesting -= 1;
+                Ok(Input::List(items))
+            }
+            Some(Token::Punct('{')) => {
+                self.nest()?;
+                let mut entries = Vec::new();
+                while !self.eat('}') {
+                    let name = self.name()?;
+                    self.expect(':')?;
+                    entries.push((name, self.value(constant)?));
+                }
+                self.nesting -= 1;
+                Ok(Input::Object(entries))
+            }
+            _ => {
+                self.at -= 1;
+                Err(format!("Expected a value, found {}", self.describe()))
+            }
+        }
+    }
+
+    /// Goes one level deeper, which the caller leaves again once done
+    fn nest(&mut self) -> Result<(), String> {
+        self.nesting += 1;
+        if self.nesting > MAX_NESTING {
+            return Err("The query is nested too deeply".to_string());
+        }
+        Ok(())
+    }
+
+    fn selection_set(&mut self) -> Result<Vec<Selection>, String> {
+        self.expect('{')?;
+        self.nest()?;
+        let mut selection = Vec::new();
+        while !self.eat('}') {
+            if self.peek().is_none() {
+                return Err("Expected \"}\", found the end of the document".to_string());
+            }
+            selection.push(self.selection()?);
+        }
+        if selection.is_empty() {
+            return Err("A selection set cannot be empty".to_string());
+        }
+        self.nesting -= 1;
+        Ok(selection)
+    }
+
+    fn selection(&mut self) -> Result<Selection, String> {
+        if self.peek() == Some(&Token::Spread) {
+            self.at += 1;
+            return match self.peek() {
+                Some(Token::Name(name)) if name != "on" => {
+                    let name = self.name()?;
+                    Ok(Selection::Spread { name, directives: self.directives()? })
+                }
+                Some(Token::Name(_)) => {
+                    self.at += 1;
+                    let on = Some(self.name()?);
+                    let directives = self.directives()?;
+                    Ok(Selection::Inline { on, directives, selection: self.selection_set()? })
+                }
+                _ => {
+                    let directives = self.directives()?;
+                    Ok(Selection::Inline { on: None, directives, selection: self.selection_set()? })
+                }
+            };
+        }
+        let first = self.name()?;
+        let (alias, name) = if self.eat(':') { (Some(first), self.name()?) } else { (None, first) };
+        let arguments = self.arguments()?;
+        let directives = self.directives()?;
+        let selection = if self.is_punct('{') { self.selection_set()? } else { Vec::new() };
+        Ok(Selection::Field(Field { alias, name, arguments, directives, selection }))
+    }
+}
+
+/// Read a GraphQL document
+pub fn parse(source: &str) -> Result<Document, String> {
+    Parser { tokens: tokens(source)?, at: 0, nesting: 0 }.document()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn documents_are_read_with_fragments_and_variables() {
+        let document = parse(
+            r#"
+            # The profile page
+            query Profile($uid: Int!, $first: Int = 3) {
+                who: user(uid: $uid) { ...Card posts(first: $first) { title } }
+                viewer @include(if: true) { ... on Viewer { email } }
+            }
+            fragment Card on User { username displayName avatar }
+            "#,
+        )
+        .unwrap();
+        let operation = document.operation(None).unwrap();
+        assert_eq!(operation.name.as_deref(), Some("Profile"));
+        assert_eq!(operation.variables[1].default, Some(Input::Int(3)));
+        assert_eq!(operation.variables[0].ty, "Int!");
+        let Selection::Field(field) = &operation.selection[0] else { panic!() };
+        assert_eq!((field.key(), field.name.as_str()), ("who", "user"));
+        assert_eq!(field.arguments, vec![("uid".to_string(), Input::Variable("uid".to_string()))]);
+        assert!(matches!(&field.selection[0], Selection::Spread { name, .. } if name == "Card"));
+        assert_eq!(document.fragment("Card").unwrap().selection.len(), 3);
+
+        assert_eq!(parse(r#"{ post(slug: "a\"bé") { title } }"#).unwrap().operations[0].kind, OperationKind::Query);
+        assert!(parse("{ user(uid: 1) { } }").is_err());
+        assert!(parse("{ user(uid: 1) { name }").is_err());
+        assert!(parse(&format!("{}{}", "{ a ".repeat(80), "}".repeat(80))).is_err());
+        assert!(parse("query A { a } query B { b }").unwrap().operation(None).is_err());
+    }
+
+    #[test]
+    fn deeply_nested_values_and_types_are_refused() {
+        let list = format!("{{ user(uid: {}1{}) {{ username }} }}", "[".repeat(20_000), "]".repeat(20_000));
+        assert_eq!(parse(&list).unwrap_err(), "The query is nested too deeply");
+        let object = format!("{{ user(uid: {}1{}) {{ username }} }}", "{a: ".repeat(20_000), "}".repeat(20_000));
+        assert_eq!(parse(&object).unwrap_err(), "The query is nested too deeply");
+        let ty = format!("query($a: {}Int{}) {{ viewer {{ email }} }}", "[".repeat(20_000), "]".repeat(20_000));
+        assert_eq!(parse(&ty).unwrap_err(), "The query is nested too deeply");
+
+        let nested = format!("{{ user(uid: {}1{}) {{ username }} }}", "[".repeat(30), "]".repeat(30));
+        assert!(parse(&nested).is_ok());
+    }
+}
@@ -0,0 +1,325 @@
+//! grpc.rs
+//!
+//! The local account store as a gRPC service, for internal services that
+//! are not web frontends: `sfx.auth.v1.Auth` with `Login`, `Introspect`,
+//! `GetUser` and `Revoke`, described in `proto/auth.proto` of the crate.
+//! The calls run on the same [`AuthManager`](crate::local_auth::fop::AuthManager)
+//! as `/auth/*`, so tokens issued either way are good for both.
+//!
+//! The service is generated from the proto file by `tonic-prost-build`
+//! (see `build.rs`) and served by tonic on a binding of its own, in
+//! cleartext HTTP/2. Configured in `programfiles/op/grpc.json`:
+//!
+//! ```json
+//! {
+//!     "enabled": true,
+//!     "binding": "127.0.0.1:50051",
+//!     "key": "env:SFX_GRPC_KEY"
+//! }
+//! ```
+//!
+//! With a `key` (a secret or an `env:` / `file:` / `cmd:` reference, see
+//! `crate::secrets`) every call must carry `authorization: Bearer <key>`
+//! metadata. Without one, anything that reaches the binding may look up
+//! accounts, so the service only starts without a key on loopback. Only
+//! callers holding the key may name the end user's address in `client_ip`;
+//! otherwise the login rules see the address of the caller.
+//!
+//! ```sh
+//! grpcurl -plaintext -import-path proto -proto auth.proto \
+//!     -H "authorization: Bearer $SFX_GRPC_KEY" \
+//!     -d '{"id": "alice", "password": "..."}' 127.0.0.1:50051 sfx.auth.v1.Auth/Login
+//! ```
+
+use hotaru::prelude::*;
+use std::net::IpAddr;
+use tonic::{Request, Response, Status};
+
+use crate::events;
+use crate::local_auth::LOCAL_AUTH;
+use crate::local_auth::fop::{AuthManager, FopError};
+use crate::local_auth::scope::{self, Scopes};
+use crate::local_auth::sessions::ClientInfo;
+use crate::modules;
+use crate::op;
+use crate::user::logout;
+use proto::auth_server::{Auth, AuthServer};
+use proto::{GetUserRequest, IntrospectReply, IntrospectRequest, LoginReply, LoginRequest, RevokeReply, RevokeRequest, User};
+
+/// The messages and service trait generated from `proto/auth.proto`
+pub mod proto {
+    tonic::include_proto!("sfx.auth.v1");
+}
+
+static GRPC: Lazy<GrpcSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/grpc.json");
+    GrpcSettings::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None))
+});
+
+/// The full name of the service, the first segment of its call paths
+pub const SERVICE: &str = "sfx.auth.v1.Auth";
+
+/// Largest request message the service reads
+pub const MAX_MESSAGE: usize = 64 * 1024;
+
+/// The parsed content of `grpc.json`
+#[derive(Debug, Clone, PartialEq)]
+pub struct GrpcSettings {
+    pub enabled: bool,
+    pub binding: String,
+    /// The key callers present, or a reference to it; empty for none
+    pub key: String,
+}
+
+impl GrpcSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let binding = value.get("binding").string();
+        Self {
+            enabled: value.get("enabled").boolean(),
+            binding: if binding.is_empty() { "127.0.0.1:50051".to_string() } else { binding },
+            key: value.get("key").string(),
+        }
+    }
+}
+
+/// The loaded gRPC settings
+pub fn settings() -> &'static GrpcSettings {
+    &GRPC
+}
+
+/// The status of a failed account call
+fn status(err: FopError) -> Status {
+    match err {
+        FopError::UserNotFound | FopError::PasswordMismatch | FopError::TokenInvalid => Status::unauthenticated(err.to_string()),
+        FopError::UserInactive | FopError::LoginBlocked | FopError::SecondFactorRequired => {
+            Status::permission_denied(err.to_string())
+        }
+        FopError::TooManyRequest => Status::resource_exhausted(err.to_string()),
+        _ => Status::internal(err.to_string()),
+    }
+}
+
+/// The calls of `sfx.auth.v1.Auth` on an account store
+pub struct AuthService {
+    auth: &'static AuthManager,
+    key: Option<String>,
+}
+
+impl AuthService {
+    /// The service on `auth`, asking callers for `key` when there is one
+    pub fn new(auth: &'static AuthManager, key: Option<String>) -> Self {
+        Self { auth, key }
+    }
+
+    /// Refuse a call without the service key, when there is one
+    fn check_key<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        let Some(key) = &self.key else {
+            return Ok(());
+        };
+        let given = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        if logout::constant_time_eq(given.as_bytes(), key.as_bytes()) {
+            Ok(())
+        } else {
+            Err(Status::unauthenticated("Missing or wrong service key"))
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Auth for AuthService {
+    /// As `/auth/login`; an account with a second factor gets the challenge
+    /// to finish at `/auth/login/second_factor` instead of a token
+    async fn login(&self, request: Request<LoginRequest>) -> Result<Response<LoginReply>, Status> {
+        self.check_key(&request)?;
+        let peer = request.remote_addr().map(|addr| addr.ip());
+        let request = request.into_inner();
+        let scopes = match request.scope.as_str() {
+            "" => Scopes::All,
+            requested => Scopes::parse(requested).map_err(Status::invalid_argument)?,
+        };
+        // The end user's address, for the login rules and history. Without a
+        // service key anyone could name one, so the caller's is used
+        let from = match self.key {
+            Some(_) => request.client_ip.parse::<IpAddr>().ok().or(peer),
+            None => peer,
+        };
+        let client = ClientInfo::new(from, Some(&request.user_agent), Some(&request.label));
+        let granted = scopes.to_string();
+        let uid = self.auth.uid_from_username_or_email_or_uid(request.id).await.map_err(status)?;
+        let reply = match self.auth.login_user_from(uid, &request.password, scopes, client).await {
+            Ok(token) => LoginReply {
+                expires_at: self.auth.token_held_until(&token).await.unwrap_or(0),
+                access_token: token,
+                token_type: "Bearer".to_string(),
+                scope: granted,
+                uid,
+                ..Default::default()
+            },
+            Err(FopError::SecondFactorPending { challenge, method }) => {
+                LoginReply { uid, second_factor: method.as_str().to_string(), challenge, ..Default::default() }
+            }
+            Err(err) => return Err(status(err)),
+        };
+        Ok(Response::new(reply))
+    }
+
+    /// An unknown or expired token is an inactive answer rather than an error
+    async fn introspect(&self, request: Request<IntrospectRequest>) -> Result<Response<IntrospectReply>, Status> {
+        self.check_key(&request)?;
+        let token = request.into_inner().token;
+        let Some(uid) = self.auth.uid_of_token(&token).await else {
+            return Ok(Response::new(IntrospectReply::default()));
+        };
+        let scopes = self.auth.token_scopes(&token).await.map(|(_, scopes)| scopes).unwrap_or(Scopes::All);
+        let is_admin = scopes.allows(scope::USERS_ADMIN) && op::get_admin().contains(&object!(format!("{}@local", uid)));
+        Ok(Response::new(IntrospectReply {
+            active: true,
+            uid,
+            scope: scopes.to_string(),
+            expires_at: self.auth.token_held_until(&token).await.unwrap_or(0),
+            is_admin,
+        }))
+    }
+
+    /// By `uid`, or by a username or email in `id`
+    async fn get_user(&self, request: Request<GetUserRequest>) -> Result<Response<User>, Status> {
+        self.check_key(&request)?;
+        let request = request.into_inner();
+        let uid = match request.uid {
+            0 => self
+                .auth
+                .uid_from_username_or_email_or_uid(request.id)
+                .await
+                .map_err(|_| Status::not_found("User not found"))?,
+            uid => uid,
+        };
+        let user = self.auth.admin_get_user(uid).await.ok_or_else(|| Status::not_found("User not found"))?;
+        Ok(Response::new(User {
+            uid,
+            display_name: user.display_name().to_string(),
+            username: user.username,
+            email: user.email,
+            is_active: user.is_active,
+            is_admin: op::get_admin().contains(&object!(format!("{}@local", uid))),
+        }))
+    }
+
+    /// With `everywhere` every token of the account ends, as with
+    /// `everywhere` of `logout.json`
+    async fn revoke(&self, request: Request<RevokeRequest>) -> Result<Response<RevokeReply>, Status> {
+        self.check_key(&request)?;
+        let request = request.into_inner();
+        let Some(uid) = self.auth.uid_of_token(&request.token).await else {
+            return Ok(Response::new(RevokeReply::default()));
+        };
+        if request.everywhere {
+            let ended = self.auth.admin_revoke_sessions(uid).await;
+            logout::sessions_ended(uid);
+            events::publish(events::LoggedOut { uid, everywhere: true });
+            return Ok(Response::new(RevokeReply { revoked: true, sessions: ended as u32 }));
+        }
+        let revoked = self.auth.logout_user(&request.token).await.is_ok();
+        Ok(Response::new(RevokeReply { revoked, sessions: revoked as u32 }))
+    }
+}
+
+/// Start the service when `grpc.json` enables it and the local account
+/// store is on; an unreadable `key`, or none on a binding other than
+/// loopback, fails the start rather than leaving the service open
+pub async fn start() -> std::io::Result<()> {
+    if !GRPC.enabled {
+        return Ok(());
+    }
+    if !modules::enabled(modules::LOCAL_AUTH) {
+        tracing::warn!("grpc.json is enabled but the local_auth module is off, the gRPC service is not started");
+        return Ok(());
+    }
+    let key = match GRPC.key.as_str() {
+        "" => None,
+        key => Some(
+            crate::secrets::resolve(key)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("grpc.json key: {}", err)))?,
+        ),
+    };
+    let listener = tokio::net::TcpListener::bind(&GRPC.binding).await?;
+    if key.is_none() && !listener.local_addr()?.ip().is_loopback() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "grpc.json needs a `key` for a binding other than loopback",
+        ));
+    }
+    let service = AuthServer::new(AuthService::new(&LOCAL_AUTH, key)).max_decoding_message_size(MAX_MESSAGE);
+    let incoming = tonic::transport::server::TcpIncoming::from(listener).with_nodelay(Some(true));
+    tracing::info!(binding = %GRPC.binding, "gRPC auth service listening");
+    tokio::spawn(async move {
+        if let Err(err) = tonic::transport::Server::builder().add_service(service).serve_with_incoming(incoming).await {
+            tracing::warn!(%err, "gRPC auth service stopped");
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::auth_manager;
+    use tonic::Code;
+
+    fn call<T>(message: T, key: &str) -> Request<T> {
+        let mut request = Request::new(message);
+        request.metadata_mut().insert("authorization", format!("Bearer {}", key).parse().unwrap());
+        request
+    }
+
+    #[tokio::test]
+    async fn auth_calls_are_answered() {
+        let auth: &'static AuthManager = Box::leak(Box::new(auth_manager(&[("alice", "Aa333333")]).await));
+        let service = AuthService::new(auth, Some("internal".to_string()));
+        let login = || LoginRequest { id: "alice".to_string(), password: "Aa333333".to_string(), scope: "profile:read".to_string(), ..Default::default() };
+        assert_eq!(service.login(call(login(), "wrong")).await.unwrap_err().code(), Code::Unauthenticated);
+        assert_eq!(service.login(Request::new(login())).await.unwrap_err().code(), Code::Unauthenticated);
+
+        let reply = service.login(call(login(), "internal")).await.unwrap().into_inner();
+        assert_eq!((reply.token_type.as_str(), reply.scope.as_str(), reply.uid), ("Bearer", "profile:read", 1));
+        assert!(reply.expires_at > 0);
+        let token = reply.access_token;
+
+        let introspect = || call(IntrospectRequest { token: token.clone() }, "internal");
+        let reply = service.introspect(introspect()).await.unwrap().into_inner();
+        assert_eq!((reply.active, reply.uid, reply.is_admin), (true, 1, false));
+
+        let reply = service.get_user(call(GetUserRequest { id: "alice".to_string(), ..Default::default() }, "internal")).await.unwrap();
+        assert_eq!((reply.get_ref().uid, reply.get_ref().email.as_str()), (1, "alice@example.com"));
+        let missing = service.get_user(call(GetUserRequest { uid: 9, ..Default::default() }, "internal")).await;
+        assert_eq!(missing.unwrap_err().code(), Code::NotFound);
+
+        let reply = service.revoke(call(RevokeRequest { token: token.clone(), everywhere: false }, "internal")).await.unwrap();
+        assert!(reply.get_ref().revoked);
+        assert!(!service.introspect(introspect()).await.unwrap().get_ref().active);
+
+        let wrong = LoginRequest { id: "alice".to_string(), password: "nope".to_string(), ..Default::default() };
+        assert_eq!(service.login(call(wrong, "internal")).await.unwrap_err().code(), Code::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn only_keyed_callers_name_the_client_address() {
+        let auth: &'static AuthManager = Box::leak(Box::new(auth_manager(&[("alice", "Aa333333")]).await));
+        let login = || LoginRequest {
+            id: "alice".to_string(),
+            password: "Aa333333".to_string(),
+            client_ip: "198.51.100.9".to_string(),
+            ..Default::default()
+        };
+        let last_ip = || async { auth.admin_get_user(1).await.unwrap().activity.last_login_ip };
+
+        AuthService::new(auth, None).login(Request::new(login())).await.unwrap();
+        assert_eq!(last_ip().await, None);
+        AuthService::new(auth, Some("internal".to_string())).login(call(login(), "internal")).await.unwrap();
+        assert_eq!(last_ip().await.as_deref(), Some("198.51.100.9"));
+    }
+}
@@ -0,0 +1,210 @@
+//! honeypot.rs
+//!
+//! Cheap checks that turn away form-filling bots before a submission costs
+//! a password hash or a call to an auth server. Read from
+//! `programfiles/op/honeypot.json`:
+//!
+//! ```json
+//! {
+//!     "routes": ["login"],
+//!     "field": "website",
+//!     "min_seconds": 2,
+//!     "max_age": 3600,
+//!     "secret": "env:SFX_FORM_SECRET"
+//! }
+//! ```
+//!
+//! A guarded form carries two extra fields, rendered by
+//! `default/templates/base/honeypot.html` from [`fields`]:
+//!
+//! - `field`, an input hidden from people, which bots fill in like any other.
+//! - `form_token`, the route and render time sealed with `secret`. A form
+//!   sent back sooner than `min_seconds` after it was rendered, or later
+//!   than `max_age`, is rejected.
+//!
+//! Without a `secret` a random one is made at startup, so forms rendered
+//! before a restart (or by another instance) are rejected once. Route names
+//! are the ones of `crate::captcha`; endpoints call [`verify`] first.
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+use hotaru_lib::ende::aes;
+
+static HONEYPOT: Lazy<HoneypotSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/honeypot.json");
+    let mut settings = HoneypotSettings::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None));
+    settings.secret = crate::secrets::load(&settings.secret, "honeypot.json secret");
+    if settings.secret.is_empty() {
+        settings.secret = hotaru_lib::random::random_string(64);
+    }
+    settings
+});
+
+/// Form field carrying the sealed render time
+pub const TOKEN_FIELD: &str = "form_token";
+
+/// Why a submission looks automated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BotError {
+    /// The hidden field was filled in
+    Honeypot,
+    /// The token is missing, forged or belongs to another form
+    Token,
+    /// Sent back faster than a person types
+    TooFast,
+    /// The form was rendered more than `max_age` ago
+    Expired,
+}
+
+impl std::fmt::Display for BotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            // Bots are not told which check they failed
+            BotError::Honeypot | BotError::Token | BotError::TooFast => write!(f, "Submission rejected"),
+            BotError::Expired => write!(f, "The form has expired, reload the page"),
+        }
+    }
+}
+
+/// The parsed content of `honeypot.json`
+#[derive(Debug, Clone)]
+pub struct HoneypotSettings {
+    pub routes: Vec<String>,
+    pub field: String,
+    pub min_seconds: u64,
+    pub max_age: u64,
+    pub secret: String,
+}
+
+impl Default for HoneypotSettings {
+    fn default() -> Self {
+        Self { routes: Vec::new(), field: "website".into(), min_seconds: 2, max_age: 3600, secret: String::new() }
+    }
+}
+
+impl HoneypotSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let default = Self::default();
+        let field = value.get("field").string();
+        let seconds = |key: &str, default: u64| match value.get(key) {
+            Value::Numerical(n) if *n >= 0.0 => *n as u64,
+            _ => default,
+        };
+        Self {
+            routes: match value.get("routes") {
+                Value::List(routes) => routes.iter().map(|route| route.string()).collect(),
+                _ => Vec::new(),
+            },
+            field: if field.is_empty() { default.field } else { field },
+            min_seconds: seconds("min_seconds", default.min_seconds),
+            max_age: seconds("max_age", default.max_age),
+            secret: value.get("secret").string(),
+        }
+    }
+
+    pub fn is_enabled(&self, route: &str) -> bool {
+        self.routes.iter().any(|r| r == route)
+    }
+
+    /// A token for a form of `route` rendered at `now`
+    pub fn token(&self, route: &str, now: u64) -> String {
+        aes::encrypt(&format!("{}.{}", route, now), &self.secret).unwrap_or_default()
+    }
+
+    /// Check a submission of a form of `route` at `now`
+    pub fn check(&self, route: &str, honeypot: &str, token: &str, now: u64) -> Result<(), BotError> {
+        if !honeypot.is_empty() {
+            return Err(BotError::Honeypot);
+        }
+        let issued = aes::decrypt(token, &self.secret)
+            .ok()
+            .and_then(|sealed| {
+                let (sealed_route, issued) = sealed.rsplit_once('.')?;
+                (sealed_route == route).then(|| issued.parse::<u64>().ok())?
+            })
+            .ok_or(BotError::Token)?;
+        if now < issued + self.min_seconds {
+            return Err(BotError::TooFast);
+        }
+        if now > issued + self.max_age {
+            return Err(BotError::Expired);
+        }
+        Ok(())
+    }
+}
+
+/// The loaded honeypot settings
+pub fn settings() -> &'static HoneypotSettings {
+    &HONEYPOT
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Build the template value of the extra fields of a form of `route`
+///
+/// # Returns
+/// ```json
+/// { "enabled": bool, "field": "website", "token_field": "form_token", "token": "..." }
+/// ```
+pub fn fields(route: &str) -> Value {
+    if !HONEYPOT.is_enabled(route) {
+        return object!({ enabled: false });
+    }
+    object!({
+        enabled: true,
+        field: HONEYPOT.field.clone(),
+        token_field: TOKEN_FIELD,
+        token: HONEYPOT.token(route, now()),
+    })
+}
+
+/// Verify a submission of `route` from a urlencoded form
+pub fn verify_form(route: &str, form: &UrlEncodedForm) -> Result<(), BotError> {
+    verify(route, form.get_or_default(&HONEYPOT.field), form.get_or_default(TOKEN_FIELD))
+}
+
+/// Verify a submission of `route` from a JSON body
+pub fn verify_json(route: &str, json: &Value) -> Result<(), BotError> {
+    verify(route, &json.get(&HONEYPOT.field).string(), &json.get(TOKEN_FIELD).string())
+}
+
+/// Verify the submitted `honeypot` field and `token` for `route`.
+///
+/// Returns `Ok(())` immediately when the route is not guarded.
+pub fn verify(route: &str, honeypot: &str, token: &str) -> Result<(), BotError> {
+    if !HONEYPOT.is_enabled(route) {
+        return Ok(());
+    }
+    let result = HONEYPOT.check(route, honeypot, token, now());
+    if let Err(err) = &result {
+        tracing::info!(route, reason = ?err, "Form submission rejected as automated");
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn submissions_need_an_empty_honeypot_and_a_timely_token() {
+        let settings = HoneypotSettings {
+            routes: vec!["login".into()],
+            secret: "s".repeat(32),
+            ..HoneypotSettings::default()
+        };
+        let token = settings.token("login", 1000);
+        assert_eq!(settings.check("login", "", &token, 1005), Ok(()));
+        assert_eq!(settings.check("login", "http://spam", &token, 1005), Err(BotError::Honeypot));
+        assert_eq!(settings.check("login", "", &token, 1001), Err(BotError::TooFast));
+        assert_eq!(settings.check("login", "", &token, 1000 + 3601), Err(BotError::Expired));
+        assert_eq!(settings.check("register", "", &token, 1005), Err(BotError::Token));
+        assert_eq!(settings.check("login", "", "", 1005), Err(BotError::Token));
+
+        let other = HoneypotSettings { secret: "t".repeat(32), ..settings.clone() };
+        assert_eq!(other.check("login", "", &token, 1005), Err(BotError::Token));
+        assert!(settings.is_enabled("login") && !settings.is_enabled("register"));
+    }
+}
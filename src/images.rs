@@ -0,0 +1,408 @@
+//! images.rs
+//!
+//! Uploaded images and their derivatives. An upload is decoded, turned
+//! upright by its EXIF orientation and stored again as WebP, which leaves
+//! EXIF (camera, GPS position) and other metadata behind. Limits and the
+//! derivative sizes come from `programfiles/op/images.json`:
+//!
+//! ```json
+//! {
+//!     "max_bytes": 5242880,
+//!     "max_pixels": 40000000,
+//!     "max_edge": 2048,
+//!     "formats": ["jpeg", "png", "webp", "gif"],
+//!     "sizes": {
+//!         "avatar-64": { "edge": 64, "crop": true },
+//!         "thumb": { "edge": 320 }
+//!     }
+//! }
+//! ```
+//!
+//! A size fits the image within `edge` pixels, or with `crop` fills an
+//! `edge` square from its center, as avatars want. Sizes are made on upload
+//! and, for sizes added later, on first request; either way they are kept
+//! next to the image under `images/<id>/<size>.webp` of the configured
+//! [`crate::storage`] and served at `GET /media/<id>/<size>` (`original` for the image itself).
+//!
+//! Signed-in users upload at `POST /op/images`.
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits};
+use std::collections::BTreeMap;
+use std::io::Cursor;
+
+use crate::op::APP;
+use crate::storage::{self, StorageError};
+use crate::ctx::SfxCtx;
+use crate::methods;
+
+static IMAGES: Lazy<ImageSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/images.json");
+    match Value::from_jsonf(path.to_str().unwrap()) {
+        Ok(value) => ImageSettings::from_value(&value),
+        Err(_) => ImageSettings::default(),
+    }
+});
+
+/// Name of the stored image among its sizes
+pub const ORIGINAL: &str = "original";
+
+/// Length of image ids
+const ID_LENGTH: usize = 16;
+
+/// One derivative of every image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Size {
+    /// Longest side, or the side of the square with `crop`
+    pub edge: u32,
+    /// Fill an `edge` square, cutting off what sticks out
+    pub crop: bool,
+}
+
+/// The parsed content of `images.json`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageSettings {
+    /// Largest upload accepted, in bytes
+    pub max_bytes: usize,
+    /// Largest decoded image accepted, in pixels, against decompression bombs
+    pub max_pixels: u64,
+    /// Longest side of the stored image; larger uploads are scaled down
+    pub max_edge: u32,
+    /// Formats accepted for upload
+    pub formats: Vec<ImageFormat>,
+    pub sizes: BTreeMap<String, Size>,
+}
+
+impl Default for ImageSettings {
+    fn default() -> Self {
+        let sizes = [("avatar-32", 32, true), ("avatar-64", 64, true), ("avatar-128", 128, true), ("thumb", 320, false)];
+        Self {
+            max_bytes: 5 * 1024 * 1024,
+            max_pixels: 40_000_000,
+            max_edge: 2048,
+            formats: vec![ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::WebP, ImageFormat::Gif],
+            sizes: sizes
+                .into_iter()
+                .map(|(name, edge, crop)| (name.to_string(), Size { edge, crop }))
+                .collect(),
+        }
+    }
+}
+
+impl ImageSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let default = Self::default();
+        let number = |key: &str, fallback: u64| match value.get(key) {
+            Value::Numerical(_) => value.get(key).integer().max(1) as u64,
+            _ => fallback,
+        };
+        let formats = match value.get("formats") {
+            Value::List(formats) => formats.iter().filter_map(|format| format_named(&format.string())).collect(),
+            _ => default.formats,
+        };
+        let sizes = match value.get("sizes") {
+            Value::Dict(sizes) => sizes
+                .iter()
+                .filter(|(name, size)| valid_name(name) && name.as_str() != ORIGINAL && size.get("edge").integer() > 0)
+                .map(|(name, size)| {
+                    let edge = size.get("edge").integer().min(u32::MAX as i64) as u32;
+                    (name.clone(), Size { edge, crop: size.get("crop").boolean() })
+                })
+                .collect(),
+            _ => default.sizes,
+        };
+        Self {
+            max_bytes: number("max_bytes", default.max_bytes as u64) as usize,
+            max_pixels: number("max_pixels", default.max_pixels),
+            max_edge: number("max_edge", default.max_edge as u64).min(u32::MAX as u64) as u32,
+            formats,
+            sizes,
+        }
+    }
+}
+
+/// The upload format called `name` in `images.json`
+pub fn format_named(name: &str) -> Option<ImageFormat> {
+    match name.to_ascii_lowercase().as_str() {
+        "jpeg" | "jpg" => Some(ImageFormat::Jpeg),
+        "png" => Some(ImageFormat::Png),
+        "webp" => Some(ImageFormat::WebP),
+        "gif" => Some(ImageFormat::Gif),
+        _ => None,
+    }
+}
+
+/// Whether `name` may name an image or a size: letters, digits, `-`, `_`
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 64 && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Why an image could not be stored or served
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UploadError {
+    TooLarge,
+    UnsupportedFormat,
+    Invalid(String),
+    NotFound,
+    Io(String),
+}
+
+impl std::fmt::Display for UploadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UploadError::TooLarge => write!(f, "The image is too large"),
+            UploadError::UnsupportedFormat => write!(f, "Unsupported image format"),
+            UploadError::Invalid(err) => write!(f, "Not a readable image: {}", err),
+            UploadError::NotFound => write!(f, "Image not found"),
+            UploadError::Io(err) => write!(f, "Failed to store the image: {}", err),
+        }
+    }
+}
+
+impl From<StorageError> for UploadError {
+    fn from(err: StorageError) -> Self {
+        match err {
+            StorageError::NotFound => UploadError::NotFound,
+            err => UploadError::Io(err.to_string()),
+        }
+    }
+}
+
+fn decode_error(err: image::ImageError) -> UploadError {
+    match err {
+        image::ImageError::Limits(_) => UploadError::TooLarge,
+        err => UploadError::Invalid(err.to_string()),
+    }
+}
+
+/// Decode `bytes` within the limits of `settings`, upright and no larger
+/// than `max_edge`
+pub fn decode(bytes: &[u8], settings: &ImageSettings) -> Result<DynamicImage, UploadError> {
+    if bytes.len() > settings.max_bytes {
+        return Err(UploadError::TooLarge);
+    }
+    let format = image::guess_format(bytes).map_err(|_| UploadError::UnsupportedFormat)?;
+    if !settings.formats.contains(&format) {
+        return Err(UploadError::UnsupportedFormat);
+    }
+    let mut reader = ImageReader::with_format(Cursor::new(bytes), format);
+    let mut limits = Limits::default();
+    // Eight bytes a pixel, for 16-bit RGBA
+    limits.max_alloc = Some(settings.max_pixels.saturating_mul(8));
+    reader.limits(limits);
+    let mut decoder = reader.into_decoder().map_err(decode_error)?;
+    let (width, height) = decoder.dimensions();
+    if width as u64 * height as u64 > settings.max_pixels {
+        return Err(UploadError::TooLarge);
+    }
+    let orientation = decoder.orientation().map_err(decode_error)?;
+    let mut image = DynamicImage::from_decoder(decoder).map_err(decode_error)?;
+    image.apply_orientation(orientation);
+    Ok(fit(image, Size { edge: settings.max_edge, crop: false }))
+}
+
+/// `image` made to `size`. Images already within the edge are not enlarged
+/// unless cropped.
+pub fn fit(image: DynamicImage, size: Size) -> DynamicImage {
+    if size.crop {
+        image.resize_to_fill(size.edge, size.edge, FilterType::Lanczos3)
+    } else if image.width() > size.edge || image.height() > size.edge {
+        image.resize(size.edge, size.edge, FilterType::Lanczos3)
+    } else {
+        image
+    }
+}
+
+/// `image` as lossless WebP, without any metadata
+pub fn webp(image: &DynamicImage) -> Result<Vec<u8>, UploadError> {
+    let image = if image.color().has_alpha() {
+        DynamicImage::ImageRgba8(image.to_rgba8())
+    } else {
+        DynamicImage::ImageRgb8(image.to_rgb8())
+    };
+    let mut out = Vec::new();
+    image
+        .write_with_encoder(WebPEncoder::new_lossless(&mut out))
+        .map_err(|err| UploadError::Io(err.to_string()))?;
+    Ok(out)
+}
+
+/// The storage key of `size` of the image `id`
+fn key(id: &str, size: &str) -> String {
+    format!("images/{}/{}.webp", id, size)
+}
+
+/// Run the decoding and encoding of `work` off the async threads
+async fn blocking<T: Send + 'static>(work: impl FnOnce() -> Result<T, UploadError> + Send + 'static) -> Result<T, UploadError> {
+    tokio::task::spawn_blocking(work).await.unwrap_or_else(|err| {
+        tracing::error!(%err, "Image processing failed");
+        Err(UploadError::Io("image processing failed".to_string()))
+    })
+}
+
+/// Store the image `bytes` with every configured size, returning its id
+pub async fn store(bytes: Vec<u8>, owner: &str) -> Result<String, UploadError> {
+    let (encoded, width, height) = blocking(move || {
+        let image = decode(&bytes, &IMAGES)?;
+        let mut encoded = vec![(ORIGINAL.to_string(), webp(&image)?)];
+        for (name, size) in &IMAGES.sizes {
+            encoded.push((name.clone(), webp(&fit(image.clone(), *size))?));
+        }
+        Ok((encoded, image.width(), image.height()))
+    })
+    .await?;
+    let id = hotaru_lib::random::random_alphanumeric_string(ID_LENGTH);
+    let store = storage::store();
+    for (size, bytes) in encoded {
+        store.put(&key(&id, &size), bytes, "image/webp").await?;
+    }
+    let meta = object!({
+        owner: owner,
+        created: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs(),
+        width: width,
+        height: height,
+    });
+    store.put(&format!("images/{}/meta.json", id), meta.into_json().into_bytes(), "application/json").await?;
+    tracing::info!(id, owner, width, height, "Image stored");
+    Ok(id)
+}
+
+/// The WebP of `size` of the image `id`, made and kept on first request
+pub async fn derivative(id: &str, size: &str) -> Result<Vec<u8>, UploadError> {
+    if !valid_name(id) || !valid_name(size) {
+        return Err(UploadError::NotFound);
+    }
+    let store = storage::store();
+    match store.get(&key(id, size)).await {
+        Err(StorageError::NotFound) => {}
+        kept => return Ok(kept?),
+    }
+    let fit_to = *IMAGES.sizes.get(size).ok_or(UploadError::NotFound)?;
+    let original = store.get(&key(id, ORIGINAL)).await?;
+    let bytes = blocking(move || {
+        let image = image::load_from_memory_with_format(&original, ImageFormat::WebP)
+            .map_err(|err| UploadError::Invalid(err.to_string()))?;
+        webp(&fit(image, fit_to))
+    })
+    .await?;
+    store.put(&key(id, size), bytes.clone(), "image/webp").await?;
+    Ok(bytes)
+}
+
+/// The URL of every size of the image `id`
+pub fn urls(id: &str) -> Value {
+    let mut urls = object!({ original: format!("/media/{}/{}", id, ORIGINAL) });
+    for name in IMAGES.sizes.keys() {
+        urls.set(name, format!("/media/{}/{}", id, name));
+    }
+    urls
+}
+
+/// The loaded image settings
+pub fn settings() -> &'static ImageSettings {
+    &IMAGES
+}
+
+endpoint! {
+    APP.url("/op/images"),
+
+    /// Upload an image
+    ///
+    /// # Request
+    /// `POST /op/images`, `multipart/form-data` with the image as `file`,
+    /// from a signed-in user
+    ///
+    /// # Response
+    /// `{"success": true, "id": "...", "urls": {"original": "/media/<id>/original", "thumb": ...}}`,
+    /// or `{"success": false, "message": "..."}` with `400`, `401`, `413`
+    /// or `415`
+    pub upload_image <HTTP> {
+        methods!(req, POST);
+        let owner = match req.signed_in() {
+            Some(user) => user.get_user_id().to_string(),
+            None => {
+                return json_response(object!({ success: false, message: "Sign in to upload images" }))
+                    .status(StatusCode::UNAUTHORIZED);
+            }
+        };
+        let Some(file) = req.files_or_default().await.get_files("file").and_then(|files| files.first()) else {
+            return json_response(object!({ success: false, message: "No file" })).status(StatusCode::BAD_REQUEST);
+        };
+        let bytes = file.data().to_vec();
+        match store(bytes, &owner).await {
+            Ok(id) => json_response(object!({ success: true, urls: urls(&id), id: id })),
+            Err(err) => {
+                let status = match err {
+                    UploadError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+                    UploadError::UnsupportedFormat => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    UploadError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                    UploadError::Invalid(_) | UploadError::NotFound => StatusCode::BAD_REQUEST,
+                };
+                json_response(object!({ success: false, message: err.to_string() })).status(status)
+            }
+        }
+    }
+}
+
+endpoint! {
+    APP.url("/media/<id>/<size>"),
+
+    /// Serve a size of an image as WebP; `404` for unknown images or sizes
+    pub serve_image <HTTP> {
+        let id = req.param("id").unwrap_or_default();
+        let size = req.param("size").unwrap_or_default();
+        match derivative(&id, &size).await {
+            Ok(bytes) => normal_response(StatusCode::OK, bytes)
+                .content_type(HttpContentType::from_str("image/webp"))
+                // Ids are never reused, so the bytes behind a URL never change
+                .add_header("Cache-Control", "public, max-age=31536000, immutable"),
+            Err(UploadError::Io(err)) => {
+                tracing::error!(id, size, %err, "Cannot read an image");
+                text_response("Storage unavailable").status(StatusCode::SERVICE_UNAVAILABLE)
+            }
+            Err(_) => text_response("Not found").status(StatusCode::NOT_FOUND),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageEncoder, Rgb, RgbImage};
+
+    #[test]
+    fn uploads_are_limited_oriented_and_resized() {
+        // A wide image whose EXIF says to turn it a quarter clockwise
+        let exif = b"MM\x00\x2a\x00\x00\x00\x08\x00\x01\x01\x12\x00\x03\x00\x00\x00\x01\x00\x06\x00\x00\x00\x00\x00\x00";
+        let mut png = Vec::new();
+        let mut encoder = image::codecs::png::PngEncoder::new(&mut png);
+        encoder.set_exif_metadata(exif.to_vec()).unwrap();
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(300, 100, Rgb([200, 10, 10]))).write_with_encoder(encoder).unwrap();
+
+        let settings = ImageSettings { max_edge: 150, ..ImageSettings::default() };
+        let image = decode(&png, &settings).unwrap();
+        assert_eq!((image.width(), image.height()), (50, 150));
+        let avatar = fit(image.clone(), Size { edge: 64, crop: true });
+        assert_eq!((avatar.width(), avatar.height()), (64, 64));
+        assert_eq!(fit(image.clone(), Size { edge: 320, crop: false }).height(), 150);
+
+        // The stored WebP keeps no EXIF
+        let stored = webp(&image).unwrap();
+        assert_eq!(image::guess_format(&stored).unwrap(), ImageFormat::WebP);
+        assert!(!stored.windows(4).any(|chunk| chunk == b"EXIF"));
+
+        assert_eq!(decode(&png, &ImageSettings { max_bytes: 10, ..settings.clone() }), Err(UploadError::TooLarge));
+        assert_eq!(decode(&png, &ImageSettings { max_pixels: 1000, ..settings.clone() }), Err(UploadError::TooLarge));
+        assert_eq!(decode(&png, &ImageSettings { formats: vec![ImageFormat::Jpeg], ..settings }), Err(UploadError::UnsupportedFormat));
+
+        let parsed = ImageSettings::from_value(
+            &Value::from_json(r#"{"formats": ["png", "bmp"], "sizes": {"a": {"edge": 10, "crop": true}, "original": {"edge": 5}, "b/c": {"edge": 5}}}"#)
+                .unwrap(),
+        );
+        assert_eq!(parsed.formats, vec![ImageFormat::Png]);
+        assert_eq!(parsed.sizes.keys().collect::<Vec<_>>(), vec!["a"]);
+    }
+}
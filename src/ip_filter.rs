@@ -0,0 +1,211 @@
+//! ip_filter.rs
+//!
+//! Network-level access control: CIDR allow/deny lists evaluated for every
+//! request, plus a stricter pair of lists that only applies to `/admin/*`.
+//!
+//! The lists are read from `programfiles/op/ip_filter.json`:
+//!
+//! ```json
+//! {
+//!     "allow": [],
+//!     "deny": ["203.0.113.0/24"],
+//!     "admin_allow": ["127.0.0.1/32", "::1/128", "10.0.0.0/8"],
+//!     "admin_deny": []
+//! }
+//! ```
+//!
+//! A `deny` match always wins. An empty `allow` list allows everyone, a
+//! non-empty one only allows the addresses it covers. `/admin/*` requests
+//! must pass both the global lists and the `admin_*` lists.
+//!
+//! The client address is the one resolved by [`crate::proxy`], so forwarded
+//! headers only count when they come from a trusted proxy.
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+use std::net::IpAddr;
+
+use crate::admin::read_only::is_admin_path;
+use crate::proxy;
+
+static IP_FILTER: Lazy<IpFilterSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/ip_filter.json");
+    IpFilterSettings::from_value(
+        &Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None),
+    )
+});
+
+/// An IPv4 or IPv6 network written as `addr/prefix`. A bare address is a
+/// single-host network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(cidr: &str) -> Option<Self> {
+        let cidr = cidr.trim();
+        let (addr, prefix) = match cidr.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse::<u8>().ok()?)),
+            None => (cidr.parse::<IpAddr>().ok()?, None),
+        };
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        if prefix > max {
+            return None;
+        }
+        Some(Self { addr, prefix })
+    }
+
+    /// Whether `ip` lies inside this network. IPv4-mapped IPv6 addresses
+    /// (`::ffff:a.b.c.d`) are matched against IPv4 networks.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(*ip),
+            IpAddr::V4(_) => *ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Parse a JSON list of CIDR strings, skipping (and logging) invalid entries
+pub fn parse_cidr_list(value: &Value) -> Vec<Cidr> {
+    if value.is_none() {
+        return Vec::new();
+    }
+    value
+        .list()
+        .iter()
+        .filter_map(|entry| {
+            let parsed = Cidr::parse(&entry.string());
+            if parsed.is_none() {
+                tracing::warn!(entry = %entry.string(), "Ignoring invalid CIDR entry");
+            }
+            parsed
+        })
+        .collect()
+}
+
+/// An allow/deny pair of network lists
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    pub fn permits(&self, ip: &IpAddr) -> bool {
+        if self.deny.iter().any(|net| net.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip))
+    }
+}
+
+/// The parsed content of `ip_filter.json`
+#[derive(Debug, Clone, Default)]
+pub struct IpFilterSettings {
+    pub global: AccessList,
+    pub admin: AccessList,
+}
+
+impl IpFilterSettings {
+    pub fn from_value(value: &Value) -> Self {
+        Self {
+            global: AccessList {
+                allow: parse_cidr_list(value.get("allow")),
+                deny: parse_cidr_list(value.get("deny")),
+            },
+            admin: AccessList {
+                allow: parse_cidr_list(value.get("admin_allow")),
+                deny: parse_cidr_list(value.get("admin_deny")),
+            },
+        }
+    }
+
+    /// Whether `ip` may access `path`
+    pub fn permits(&self, ip: &IpAddr, path: &str) -> bool {
+        self.global.permits(ip) && (!is_admin_path(path) || self.admin.permits(ip))
+    }
+}
+
+/// The loaded filter settings
+pub fn settings() -> &'static IpFilterSettings {
+    &IP_FILTER
+}
+
+middleware! {
+    /// Middleware rejecting requests whose client address is not permitted
+    /// by `ip_filter.json`, with the `admin_*` lists applied to `/admin/*`.
+    /// Add it after `ProxyHeaders` and before the session middleware so
+    /// rejected requests stay cheap.
+    pub IpFilter <HTTP> {
+        if let Some(ip) = proxy::client_ip(&req)
+            && !IP_FILTER.permits(&ip, &req.path())
+        {
+            tracing::warn!(%ip, path = %req.path(), "Request rejected by ip filter");
+            req.response = text_response("Forbidden").status(403);
+            return Ok(req)
+        }
+        next(req).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_matches_networks() {
+        let net = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(net.contains(&ip("10.1.200.3")));
+        assert!(!net.contains(&ip("10.2.0.1")));
+        assert!(net.contains(&ip("::ffff:10.1.0.9")));
+
+        let host = Cidr::parse("192.168.0.5").unwrap();
+        assert!(host.contains(&ip("192.168.0.5")));
+        assert!(!host.contains(&ip("192.168.0.6")));
+
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(&ip("8.8.8.8")));
+        assert!(Cidr::parse("fd00::/8").unwrap().contains(&ip("fd12::1")));
+        assert!(Cidr::parse("10.0.0.0/33").is_none());
+        assert!(Cidr::parse("not-an-ip").is_none());
+    }
+
+    #[test]
+    fn deny_wins_and_admin_lists_only_apply_to_admin() {
+        let settings = IpFilterSettings::from_value(&object!({
+            allow: [],
+            deny: ["10.0.0.66"],
+            admin_allow: ["127.0.0.1/32"],
+            admin_deny: []
+        }));
+        assert!(settings.permits(&ip("10.0.0.1"), "/user/login"));
+        assert!(!settings.permits(&ip("10.0.0.66"), "/user/login"));
+        assert!(!settings.permits(&ip("10.0.0.1"), "/admin/panel"));
+        assert!(!settings.permits(&ip("10.0.0.1"), "/admin"));
+        assert!(settings.permits(&ip("127.0.0.1"), "/admin/panel"));
+        assert!(settings.permits(&ip("10.0.0.1"), "/administrator"));
+    }
+}
@@ -0,0 +1,176 @@
+//! l10n.rs
+//!
+//! Where the translated strings of the site come from. `op::get_localized_string`,
+//! `op::get_localized_string_with` and the mail templates ask the backend
+//! chosen in `programfiles/op/translation.json`:
+//!
+//! ```json
+//! { "backend": "fluent", "dir": "l10n" }
+//! ```
+//!
+//! - `json` (the default) reads `op/l10n.json`, one entry a key with a
+//!   string per language. `{name}` in a string is replaced by the argument
+//!   `name`.
+//! - `fluent` reads one [Fluent](https://projectfluent.org) file a
+//!   supported language, `<dir>/<lang>.ftl` under `programfiles`, with
+//!   placeables and plurals (see [`fluent`]):
+//!
+//! ```ftl
+//! comments = { $count ->
+//!     [one] One comment
+//!    *[other] { $count } comments
+//! }
+//! ```
+//!
+//! A string missing in a language falls back to the default language.
+//! Applications bring their own backend, such as gettext catalogs, by
+//! implementing [`Translations`] and passing it to [`set_backend`].
+
+use hotaru::prelude::*;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+pub mod catalog;
+pub mod fluent;
+
+static BACKEND: Lazy<RwLock<Box<dyn Translations>>> = Lazy::new(|| RwLock::new(load()));
+
+/// A source of translated strings
+pub trait Translations: Send + Sync {
+    /// The name of the backend, for the logs
+    fn name(&self) -> &'static str;
+
+    /// The message `key` in `lang`, with the arguments of the dict `args`
+    /// put in; `None` when the language has no such message
+    fn message(&self, key: &str, lang: &str, args: &Value) -> Option<String>;
+
+    /// The keys of the messages, in any language
+    fn keys(&self) -> Vec<String>;
+}
+
+/// `text` with each `{key}` of `vars` replaced by its value
+pub fn fill(text: &str, vars: &Value) -> String {
+    let mut filled = text.to_string();
+    if let Value::Dict(vars) = vars {
+        for (key, value) in vars {
+            filled = filled.replace(&format!("{{{}}}", key), &value.string());
+        }
+    }
+    filled
+}
+
+/// The strings of `l10n.json`
+#[derive(Debug, Clone)]
+pub struct JsonTranslations {
+    strings: Value,
+}
+
+impl JsonTranslations {
+    pub fn new(strings: Value) -> Self {
+        Self { strings }
+    }
+}
+
+impl Translations for JsonTranslations {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn message(&self, key: &str, lang: &str, args: &Value) -> Option<String> {
+        let text = match self.strings.get(key) {
+            Value::Dict(by_lang) => by_lang.get(lang)?.string(),
+            Value::None => return None,
+            // A key with one string for every language
+            text => text.string(),
+        };
+        Some(fill(&text, args))
+    }
+
+    fn keys(&self) -> Vec<String> {
+        match &self.strings {
+            Value::Dict(strings) => strings.keys().cloned().collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+fn config() -> Value {
+    Value::from_jsonf(crate::op::programfiles().join("op/translation.json").to_string_lossy()).unwrap_or(Value::None)
+}
+
+/// The directory of the `.ftl` files when `translation.json` picks Fluent
+pub fn fluent_dir() -> Option<PathBuf> {
+    let config = config();
+    if config.get("backend").string() != "fluent" {
+        return None;
+    }
+    let dir = match config.get("dir") {
+        Value::Str(dir) if !dir.is_empty() => dir.clone(),
+        _ => "l10n".to_string(),
+    };
+    Some(crate::op::programfiles().join(dir))
+}
+
+/// The backend of `translation.json`
+fn load() -> Box<dyn Translations> {
+    if let Some(dir) = fluent_dir() {
+        return Box::new(fluent::FluentTranslations::load(&dir, &crate::op::supported_langs()));
+    }
+    let backend = config().get("backend").string();
+    if !backend.is_empty() && backend != "json" {
+        tracing::warn!(%backend, "translation.json: unknown backend, using l10n.json");
+    }
+    let strings = Value::from_jsonf(crate::op::programfiles().join("op/l10n.json").to_string_lossy()).unwrap_or(Value::None);
+    Box::new(JsonTranslations::new(strings))
+}
+
+/// Read the strings again from the backend of `translation.json`
+pub fn reload() {
+    *BACKEND.write().unwrap() = load();
+}
+
+/// Take the strings from `backend` from now on
+pub fn set_backend(backend: Box<dyn Translations>) {
+    tracing::info!(backend = backend.name(), "Translation backend set");
+    *BACKEND.write().unwrap() = backend;
+}
+
+/// The message `key` in `lang`, else in the default language, else empty
+pub fn localize(key: &str, lang: &str, args: &Value) -> String {
+    let backend = BACKEND.read().unwrap();
+    backend
+        .message(key, lang, args)
+        .or_else(|| backend.message(key, &crate::op::default_lang(), args))
+        .unwrap_or_default()
+}
+
+/// The messages whose key starts with `prefix`, in `lang`, as a dict from
+/// key to string
+pub fn strings(prefix: &str, lang: &str, args: &Value) -> Value {
+    let mut keys: Vec<String> = BACKEND.read().unwrap().keys().into_iter().filter(|key| key.starts_with(prefix)).collect();
+    keys.sort();
+    let mut strings = Value::new_dict();
+    for key in keys {
+        let text = localize(&key, lang, args);
+        strings.set(key, text);
+    }
+    strings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_strings_take_arguments() {
+        let json = JsonTranslations::new(object!({ greet: { en: "Hi {name}", ja: "{name}さん" }, brand: "SFX" }));
+        let args = object!({ name: "Alice" });
+        assert_eq!(json.message("greet", "ja", &args).as_deref(), Some("Aliceさん"));
+        assert_eq!(json.message("greet", "zh", &args), None);
+        assert_eq!(json.message("brand", "zh", &args).as_deref(), Some("SFX"));
+        assert_eq!(json.message("missing", "en", &args), None);
+        let mut keys = json.keys();
+        keys.sort();
+        assert_eq!(keys, vec!["brand", "greet"]);
+    }
+}
@@ -0,0 +1,414 @@
+//! catalog.rs
+//!
+//! The translations as the files they live in, for `/admin/l10n`: every key
+//! in every supported language, which languages miss it, and changes written
+//! back to `op/l10n.json` or to the `.ftl` files of the Fluent backend.
+//!
+//! A change is checked before it is kept: the key must be a valid key, the
+//! text may only use the variables of the same string in the default
+//! language (`{name}` in `l10n.json`, `{ $name }` in Fluent), and Fluent
+//! text must parse. [`edit`] opens the catalog, applies a change, saves it
+//! and has the backend read the strings again.
+
+use hotaru::prelude::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::fluent::{self, Resource};
+
+/// One edit at a time, so two admins do not overwrite each other's change
+static EDITS: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// The translations of the site, read from their files
+#[derive(Debug, Clone)]
+pub struct Catalog {
+    langs: Vec<String>,
+    source: Source,
+}
+
+#[derive(Debug, Clone)]
+enum Source {
+    /// `l10n.json`: each key a dict from language to string, or one string
+    /// for every language
+    Json { path: PathBuf, strings: BTreeMap<String, Value> },
+    /// One `.ftl` file a language
+    Fluent { dir: PathBuf, files: BTreeMap<String, String> },
+}
+
+impl Catalog {
+    /// The catalog of the backend in `translation.json`
+    pub fn open() -> Self {
+        let langs = crate::op::supported_langs();
+        match super::fluent_dir() {
+            Some(dir) => Self::fluent(&dir, langs),
+            None => Self::json(&crate::op::programfiles().join("op/l10n.json"), langs),
+        }
+    }
+
+    /// The strings of the `l10n.json` at `path`
+    pub fn json(path: &Path, langs: Vec<String>) -> Self {
+        let mut strings = BTreeMap::new();
+        if let Ok(Value::Dict(dict)) = Value::from_jsonf(path.to_string_lossy()) {
+            strings.extend(dict);
+        }
+        Self { langs, source: Source::Json { path: path.to_path_buf(), strings } }
+    }
+
+    /// The `<lang>.ftl` files in `dir`
+    pub fn fluent(dir: &Path, langs: Vec<String>) -> Self {
+        let files = langs
+            .iter()
+            .map(|lang| (lang.clone(), std::fs::read_to_string(dir.join(format!("{}.ftl", lang))).unwrap_or_default()))
+            .collect();
+        Self { langs, source: Source::Fluent { dir: dir.to_path_buf(), files } }
+    }
+
+    /// The supported languages, the default first
+    pub fn langs(&self) -> &[String] {
+        &self.langs
+    }
+
+    pub fn is_fluent(&self) -> bool {
+        matches!(self.source, Source::Fluent { .. })
+    }
+
+    /// Every key of any language, sorted; Fluent terms keep their `-`
+    pub fn keys(&self) -> Vec<String> {
+        match &self.source {
+            Source::Json { strings, .. } => strings.keys().cloned().collect(),
+            Source::Fluent { files, .. } => {
+                let keys: BTreeSet<String> = files.values().flat_map(|source| source.lines().filter_map(entry_id).map(String::from)).collect();
+                keys.into_iter().collect()
+            }
+        }
+    }
+
+    /// The text of `key` in `lang` as written in the file; `None` when the
+    /// language has none
+    pub fn text(&self, key: &str, lang: &str) -> Option<String> {
+        let text = match &self.source {
+            Source::Json { strings, .. } => match strings.get(key)? {
+                Value::Dict(by_lang) => by_lang.get(lang)?.string(),
+                Value::None => return None,
+                text => text.string(),
+            },
+            Source::Fluent { files, .. } => {
+                let lines: Vec<&str> = files.get(lang)?.lines().collect();
+                raw_value(&lines, entry_range(&lines, key)?)?
+            }
+        };
+        (!text.is_empty()).then_some(text)
+    }
+
+    /// The languages without a text for `key`
+    pub fn missing(&self, key: &str) -> Vec<String> {
+        self.langs.iter().filter(|lang| self.text(key, lang).is_none()).cloned().collect()
+    }
+
+    /// Set the text of `key` in `lang`; an empty text removes it
+    pub fn set(&mut self, key: &str, lang: &str, text: &str) -> Result<(), String> {
+        if !self.langs.iter().any(|supported| supported == lang) {
+            return Err(format!("`{}` is not a supported language", lang));
+        }
+        let text = text.replace("\r\n", "\n").trim_end().to_string();
+        self.check(key, lang, &text)?;
+        let langs = self.langs.clone();
+        match &mut self.source {
+            Source::Json { strings, .. } => {
+                let entry = strings.entry(key.to_string()).or_insert_with(Value::new_dict);
+                if !matches!(entry, Value::Dict(_)) {
+                    // One string for every language becomes one a language
+                    let shared = entry.string();
+                    *entry = Value::new_dict();
+                    for lang in &langs {
+                        entry.set(lang, shared.as_str());
+                    }
+                }
+                if let Value::Dict(by_lang) = entry {
+                    if text.is_empty() {
+                        by_lang.remove(lang);
+                    } else {
+                        by_lang.insert(lang.to_string(), Value::from(text));
+                    }
+                    if by_lang.is_empty() {
+                        strings.remove(key);
+                    }
+                }
+            }
+            Source::Fluent { files, .. } => {
+                let source = files.entry(lang.to_string()).or_default();
+                let mut lines: Vec<String> = source.lines().map(String::from).collect();
+                let entry: Vec<String> = if text.is_empty() { Vec::new() } else { entry_source(key, &text).lines().map(String::from).collect() };
+                let borrowed: Vec<&str> = lines.iter().map(String::as_str).collect();
+                match entry_range(&borrowed, key) {
+                    Some(range) => {
+                        lines.splice(range, entry);
+                    }
+                    None => lines.extend(entry),
+                }
+                *source = if lines.is_empty() { String::new() } else { lines.join("\n") + "\n" };
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `text` may be the text of `key` in `lang`
+    fn check(&self, key: &str, lang: &str, text: &str) -> Result<(), String> {
+        let fluent = self.is_fluent();
+        let valid_key = if fluent {
+            fluent::is_identifier(key.strip_prefix('-').unwrap_or(key))
+        } else {
+            !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+        };
+        if !valid_key {
+            return Err(format!("`{}` is not a valid key", key));
+        }
+        if text.is_empty() {
+            return Ok(());
+        }
+        if fluent {
+            let (_, errors) = Resource::parse(&entry_source(key, text));
+            if let Some(err) = errors.first() {
+                return Err(format!("The text does not parse: {}", err));
+            }
+        }
+        let default = self.langs.first().map(String::as_str).unwrap_or_default();
+        if lang == default {
+            return Ok(());
+        }
+        let Some(reference) = self.text(key, default) else {
+            return Ok(());
+        };
+        let known = variables(&reference, fluent);
+        match variables(text, fluent).into_iter().find(|variable| !known.contains(variable)) {
+            Some(variable) if fluent => Err(format!("`${}` is not a variable of `{}` in {}", variable, key, default)),
+            Some(variable) => Err(format!("`{{{}}}` is not a variable of `{}` in {}", variable, key, default)),
+            None => Ok(()),
+        }
+    }
+
+    /// The file of `lang`: a JSON dict from key to text, or the `.ftl` file
+    pub fn export(&self, lang: &str) -> String {
+        match &self.source {
+            Source::Json { .. } => {
+                let strings: BTreeMap<String, Value> =
+                    self.keys().into_iter().filter_map(|key| self.text(&key, lang).map(|text| (key, Value::from(text)))).collect();
+                json_file(&strings)
+            }
+            Source::Fluent { files, .. } => files.get(lang).cloned().unwrap_or_default(),
+        }
+    }
+
+    /// Take the texts of an exported file of `lang`, all or none of them: the
+    /// keys of a JSON file are set, a `.ftl` file replaces the one there.
+    /// The number of texts read.
+    pub fn import(&mut self, lang: &str, content: &str) -> Result<usize, String> {
+        let mut changed = self.clone();
+        let count = match &self.source {
+            Source::Json { .. } => {
+                let Ok(Value::Dict(strings)) = Value::from_json(content.trim()) else {
+                    return Err("The file is not a JSON object of keys and texts".to_string());
+                };
+                for (key, text) in &strings {
+                    let Value::Str(text) = text else {
+                        return Err(format!("The text of `{}` is not a string", key));
+                    };
+                    changed.set(key, lang, text)?;
+                }
+                strings.len()
+            }
+            Source::Fluent { .. } => {
+                let content = content.replace("\r\n", "\n");
+                let (_, errors) = Resource::parse(&content);
+                if !errors.is_empty() {
+                    return Err(format!("The file does not parse: {}", errors.join("; ")));
+                }
+                let lines: Vec<&str> = content.lines().collect();
+                let ids: Vec<&str> = lines.iter().filter_map(|line| entry_id(line)).collect();
+                for id in &ids {
+                    let text = entry_range(&lines, id).and_then(|range| raw_value(&lines, range)).unwrap_or_default();
+                    changed.set(id, lang, &text)?;
+                }
+                if let Source::Fluent { files, .. } = &mut changed.source {
+                    files.insert(lang.to_string(), content.clone());
+                }
+                ids.len()
+            }
+        };
+        *self = changed;
+        Ok(count)
+    }
+
+    /// Write the catalog to its files, each replaced once fully written
+    pub fn save(&self) -> std::io::Result<()> {
+        match &self.source {
+            Source::Json { path, strings } => write(path, &json_file(strings)),
+            Source::Fluent { dir, files } => {
+                std::fs::create_dir_all(dir)?;
+                for (lang, source) in files {
+                    let path = dir.join(format!("{}.ftl", lang));
+                    if !source.is_empty() || path.exists() {
+                        write(&path, source)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Open the catalog, apply `change`, save it and reload the strings
+pub fn edit<T>(change: impl FnOnce(&mut Catalog) -> Result<T, String>) -> Result<T, String> {
+    let _guard = EDITS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut catalog = Catalog::open();
+    let result = change(&mut catalog)?;
+    catalog.save().map_err(|err| format!("The translations could not be saved: {}", err))?;
+    super::reload();
+    Ok(result)
+}
+
+fn write(path: &Path, content: &str) -> std::io::Result<()> {
+    let temporary = path.with_extension("tmp");
+    std::fs::write(&temporary, content)?;
+    std::fs::rename(&temporary, path)
+}
+
+/// `l10n.json` laid out as people write it, a key and a language a line
+fn json_file(strings: &BTreeMap<String, Value>) -> String {
+    let entries: Vec<String> = strings
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Value::Dict(by_lang) => {
+                    let mut langs: Vec<&String> = by_lang.keys().collect();
+                    langs.sort();
+                    let lines: Vec<String> =
+                        langs.iter().map(|lang| format!("        {}: {}", Value::from(lang.as_str()).into_json(), by_lang[*lang].into_json())).collect();
+                    format!("{{\n{}\n    }}", lines.join(",\n"))
+                }
+                value => value.into_json(),
+            };
+            format!("    {}: {}", Value::from(key.as_str()).into_json(), value)
+        })
+        .collect();
+    format!("{{\n{}\n}}\n", entries.join(",\n"))
+}
+
+/// The names of the variables in `text`: `{name}`, or `$name` in Fluent
+fn variables(text: &str, fluent: bool) -> BTreeSet<String> {
+    let name_char = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+    let mut names = BTreeSet::new();
+    if fluent {
+        for (index, _) in text.match_indices('$') {
+            let name: String = text[index + 1..].chars().take_while(|c| name_char(*c)).collect();
+            if !name.is_empty() {
+                names.insert(name);
+            }
+        }
+    } else {
+        for (index, _) in text.match_indices('{') {
+            let rest = &text[index + 1..];
+            if let Some(end) = rest.find('}') {
+                let name = &rest[..end];
+                if !name.is_empty() && name.chars().all(name_char) {
+                    names.insert(name.to_string());
+                }
+            }
+        }
+    }
+    names
+}
+
+/// The id of the Fluent entry starting on `line`
+fn entry_id(line: &str) -> Option<&str> {
+    if fluent::continues(line) || line.starts_with('#') {
+        return None;
+    }
+    let id = line.split_once('=')?.0.trim_end();
+    fluent::is_identifier(id.strip_prefix('-').unwrap_or(id)).then_some(id)
+}
+
+/// The lines of the entry `key`: its first line and those going on with it
+fn entry_range(lines: &[&str], key: &str) -> Option<Range<usize>> {
+    let start = lines.iter().position(|line| entry_id(line) == Some(key))?;
+    let mut end = start + 1;
+    while end < lines.len() && fluent::continues(lines[end]) {
+        end += 1;
+    }
+    while end > start + 1 && lines[end - 1].trim().is_empty() {
+        end -= 1;
+    }
+    Some(start..end)
+}
+
+/// The value of the entry at `range`, its lines unindented
+fn raw_value(lines: &[&str], range: Range<usize>) -> Option<String> {
+    let first = lines[range.start].split_once('=')?.1.trim();
+    let rest = &lines[range.start + 1..range.end];
+    let indent = rest.iter().filter(|line| !line.trim().is_empty()).map(|line| line.len() - line.trim_start().len()).min().unwrap_or(0);
+    let mut value: Vec<&str> = Vec::new();
+    if !first.is_empty() {
+        value.push(first);
+    }
+    value.extend(rest.iter().map(|line| if line.trim().is_empty() { "" } else { &line[indent..] }));
+    Some(value.join("\n"))
+}
+
+/// The Fluent entry of `key` with the value `text`
+fn entry_source(key: &str, text: &str) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    match lines.as_slice() {
+        [line] => format!("{} = {}", key, line),
+        _ => {
+            let indented: Vec<String> = lines.iter().map(|line| if line.is_empty() { String::new() } else { format!("    {}", line) }).collect();
+            format!("{} =\n{}", key, indented.join("\n"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edits_are_checked_and_written_back() {
+        let dir = std::env::temp_dir().join(format!("sfx-catalog-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let langs = vec!["en".to_string(), "ja".to_string()];
+
+        let path = dir.join("l10n.json");
+        std::fs::write(&path, r#"{ "greet": { "en": "Hi {name}" }, "brand": "SFX" }"#).unwrap();
+        let mut json = Catalog::json(&path, langs.clone());
+        assert_eq!(json.missing("greet"), vec!["ja"]);
+        assert!(json.missing("brand").is_empty());
+        assert!(json.set("greet", "ja", "{user}さん").is_err());
+        assert!(json.set("greet", "fr", "Salut").is_err());
+        assert!(json.set("bad key", "en", "x").is_err());
+        json.set("greet", "ja", "{name}さん").unwrap();
+        json.set("brand", "ja", "エス").unwrap();
+        json.save().unwrap();
+        let json = Catalog::json(&path, langs.clone());
+        assert_eq!(json.text("greet", "ja").as_deref(), Some("{name}さん"));
+        assert_eq!(json.text("brand", "en").as_deref(), Some("SFX"));
+        assert_eq!(json.export("ja"), "{\n    \"brand\": \"エス\",\n    \"greet\": \"{name}さん\"\n}\n");
+
+        std::fs::write(dir.join("en.ftl"), "# Site\nabout =\n    Two\n    lines\nnew = { $count ->\n    [one] One\n   *[other] { $count } new\n}\n").unwrap();
+        let mut ftl = Catalog::fluent(&dir, langs);
+        assert_eq!(ftl.keys(), vec!["about", "new"]);
+        assert_eq!(ftl.text("about", "en").as_deref(), Some("Two\nlines"));
+        assert_eq!(ftl.text("new", "en").as_deref(), Some("{ $count ->\n    [one] One\n   *[other] { $count } new\n}"));
+        assert!(ftl.set("new", "ja", "{ $n } 件").is_err());
+        assert!(ftl.set("about", "ja", "{ $count ->").is_err());
+        ftl.set("new", "ja", "{ $count } 件").unwrap();
+        ftl.set("about", "en", "One line").unwrap();
+        assert_eq!(ftl.export("en"), "# Site\nabout = One line\nnew = { $count ->\n    [one] One\n   *[other] { $count } new\n}\n");
+        assert!(ftl.import("ja", "broken").is_err());
+        assert_eq!(ftl.import("ja", "about = 二行\nnew = { $count } 件\n"), Ok(2));
+        assert!(ftl.missing("about").is_empty());
+        ftl.save().unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("ja.ftl")).unwrap(), "about = 二行\nnew = { $count } 件\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,538 @@
+//! fluent.rs
+//!
+//! The `fluent` backend of `crate::l10n`: one `.ftl` file a language, in the
+//! [Fluent syntax](https://projectfluent.org/fluent/guide/). The parts sites
+//! use are read:
+//!
+//! ```ftl
+//! # Comments
+//! -brand = SFX
+//! welcome = Welcome to { -brand }, { $username }!
+//! about =
+//!     A value over
+//!     several lines.
+//! new-comments = { $count ->
+//!     [0] No new comments
+//!     [one] One new comment
+//!    *[other] { $count } new comments
+//! }
+//! ```
+//!
+//! that is messages, terms, comments, multiline values, placeables of
+//! arguments, strings, numbers, messages and terms, and selects on them.
+//! A number picks the variant of its exact value first, then of its plural
+//! category in the language (see [`plural_category`]). Attributes
+//! (`.title = ...`) are skipped, and functions such as `NUMBER()` are an
+//! error of the entry.
+
+use hotaru::prelude::*;
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::Translations;
+
+/// How deep messages and terms may refer to one another
+const MAX_DEPTH: usize = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Element {
+    Text(String),
+    Placeable(Expression),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expression {
+    Argument(String),
+    Message(String),
+    Term(String),
+    Literal(String),
+    Number(f64),
+    Select { selector: Box<Expression>, variants: Vec<(String, Pattern)>, default: usize },
+}
+
+type Pattern = Vec<Element>;
+
+/// The messages and terms of one `.ftl` file
+#[derive(Debug, Clone, Default)]
+pub struct Resource {
+    messages: HashMap<String, Pattern>,
+    terms: HashMap<String, Pattern>,
+}
+
+impl Resource {
+    /// The entries of `source`, and what could not be read, by line
+    pub fn parse(source: &str) -> (Self, Vec<String>) {
+        let mut resource = Self::default();
+        let mut errors = Vec::new();
+        // The entry being read: its line, id and value so far
+        let mut entry: Option<(usize, String, String)> = None;
+        let mut in_attribute = false;
+        for (index, line) in source.lines().enumerate() {
+            if continues(line) {
+                let Some((_, _, value)) = entry.as_mut() else {
+                    continue;
+                };
+                let text = line.trim_start();
+                if text.starts_with('.') {
+                    in_attribute = true;
+                }
+                if !in_attribute {
+                    value.push('\n');
+                    value.push_str(text);
+                }
+                continue;
+            }
+            resource.finish(entry.take(), &mut errors);
+            in_attribute = false;
+            if line.starts_with('#') {
+                continue;
+            }
+            match line.split_once('=') {
+                Some((id, value)) if is_identifier(id.trim_end().trim_start_matches('-')) => {
+                    entry = Some((index + 1, id.trim_end().to_string(), value.to_string()));
+                }
+                _ => errors.push(format!("line {}: expected `key = value`", index + 1)),
+            }
+        }
+        resource.finish(entry.take(), &mut errors);
+        (resource, errors)
+    }
+
+    /// Parse the value of `entry`, kept under its id
+    fn finish(&mut self, entry: Option<(usize, String, String)>, errors: &mut Vec<String>) {
+        let Some((line, id, value)) = entry else {
+            return;
+        };
+        match Parser::new(&value).parse() {
+            Ok(pattern) => match id.strip_prefix('-') {
+                Some(term) => self.terms.insert(term.to_string(), pattern),
+                None => self.messages.insert(id, pattern),
+            },
+            Err(err) => {
+                errors.push(format!("line {}: {}: {}", line, id, err));
+                None
+            }
+        };
+    }
+
+    /// The message `id` with `args`; `None` when there is no such message
+    pub fn format(&self, id: &str, lang: &str, args: &Value) -> Option<String> {
+        let pattern = self.messages.get(id)?;
+        let mut out = String::new();
+        self.write(pattern, lang, args, 0, &mut out);
+        Some(out)
+    }
+
+    fn write(&self, pattern: &Pattern, lang: &str, args: &Value, depth: usize, out: &mut String) {
+        for element in pattern {
+            match element {
+                Element::Text(text) => out.push_str(text),
+                Element::Placeable(expression) => self.write_expression(expression, lang, args, depth, out),
+            }
+        }
+    }
+
+    fn write_expression(&self, expression: &Expression, lang: &str, args: &Value, depth: usize, out: &mut String) {
+        match expression {
+            Expression::Argument(name) => match args.get(name) {
+                Value::None => out.push_str(&format!("{{${}}}", name)),
+                value => out.push_str(&value.string()),
+            },
+            Expression::Literal(text) => out.push_str(text),
+            Expression::Number(number) => out.push_str(&number.to_string()),
+            Expression::Message(id) | Expression::Term(id) => {
+                let (entries, shown) = match expression {
+                    Expression::Term(_) => (&self.terms, format!("{{-{}}}", id)),
+                    _ => (&self.messages, format!("{{{}}}", id)),
+                };
+                match entries.get(id) {
+                    Some(pattern) if depth < MAX_DEPTH => self.write(pattern, lang, args, depth + 1, out),
+                    _ => out.push_str(&shown),
+                }
+            }
+            Expression::Select { selector, variants, default } => {
+                let selected = match self.selector(selector, args) {
+                    Value::Numerical(number) => {
+                        let category = plural_category(lang, number);
+                        variants
+                            .iter()
+                            .position(|(key, _)| key.parse::<f64>() == Ok(number))
+                            .or_else(|| variants.iter().position(|(key, _)| key == category))
+                    }
+                    Value::None => None,
+                    value => {
+                        let value = value.string();
+                        variants.iter().position(|(key, _)| *key == value)
+                    }
+                };
+                self.write(&variants[selected.unwrap_or(*default)].1, lang, args, depth, out);
+            }
+        }
+    }
+
+    /// The value a select chooses on
+    fn selector(&self, selector: &Expression, args: &Value) -> Value {
+        match selector {
+            Expression::Argument(name) => match args.get(name) {
+                // Numbers passed as strings, as query parameters are, still pick plurals
+                Value::Str(text) => text.parse::<f64>().map_or_else(|_| Value::from(text.as_str()), Value::Numerical),
+                value => value.clone(),
+            },
+            Expression::Number(number) => Value::Numerical(*number),
+            Expression::Literal(text) => Value::from(text.as_str()),
+            _ => Value::None,
+        }
+    }
+}
+
+/// Whether `line` goes on with the entry above it rather than starting one
+pub(super) fn continues(line: &str) -> bool {
+    line.starts_with([' ', '\t', '}', '[', '*']) || line.trim().is_empty()
+}
+
+pub(super) fn is_identifier(id: &str) -> bool {
+    let mut chars = id.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic()) && chars.all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Reads the value of one entry, its lines joined by `\n` and unindented
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn new(value: &str) -> Self {
+        Self { chars: value.chars().collect(), pos: 0 }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_blank(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(c) => Err(format!("expected `{}`, found `{}`", expected, c)),
+            None => Err(format!("expected `{}` before the end", expected)),
+        }
+    }
+
+    fn parse(mut self) -> Result<Pattern, String> {
+        let pattern = self.pattern(false)?;
+        match self.peek() {
+            Some(c) => Err(format!("unexpected `{}`", c)),
+            None => Ok(pattern),
+        }
+    }
+
+    /// Text and placeables up to the end, or in a variant up to the next
+    /// variant or the `}` of the select
+    fn pattern(&mut self, in_variant: bool) -> Result<Pattern, String> {
+        let mut pattern = Vec::new();
+        let mut text = String::new();
+        while let Some(c) = self.peek() {
+            match c {
+                '{' => {
+                    self.pos += 1;
+                    if !text.is_empty() {
+                        pattern.push(Element::Text(std::mem::take(&mut text)));
+                    }
+                    pattern.push(Element::Placeable(self.placeable()?));
+                }
+                '}' if in_variant => break,
+                '}' => return Err("`}` without `{`".to_string()),
+                '\n' if in_variant && self.variant_ahead() => break,
+                c => {
+                    text.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+        if !text.is_empty() {
+            pattern.push(Element::Text(text));
+        }
+        // A value starts after the blanks past `=` or `]` and ends before the
+        // blanks of its last line
+        if let Some(Element::Text(first)) = pattern.first_mut() {
+            *first = first.trim_start().to_string();
+        }
+        if let Some(Element::Text(last)) = pattern.last_mut() {
+            *last = last.trim_end().to_string();
+        }
+        pattern.retain(|element| *element != Element::Text(String::new()));
+        Ok(pattern)
+    }
+
+    /// Whether the next line starts another variant or ends the select
+    fn variant_ahead(&self) -> bool {
+        self.chars[self.pos..].iter().find(|c| !c.is_whitespace()).is_some_and(|c| matches!(c, '[' | '*' | '}'))
+    }
+
+    fn identifier(&mut self) -> String {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    /// The inside of `{ }`, the `{` read
+    fn placeable(&mut self) -> Result<Expression, String> {
+        self.skip_blank();
+        let expression = self.inline()?;
+        self.skip_blank();
+        if self.chars[self.pos..].starts_with(&['-', '>']) {
+            self.pos += 2;
+            return self.select(expression);
+        }
+        self.expect('}')?;
+        Ok(expression)
+    }
+
+    fn inline(&mut self) -> Result<Expression, String> {
+        let expression = match self.peek() {
+            Some('$') => {
+                self.pos += 1;
+                Expression::Argument(self.identifier())
+            }
+            Some('"') => {
+                self.pos += 1;
+                let mut text = String::new();
+                loop {
+                    match self.peek() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            self.pos += 1;
+                            text.extend(self.peek());
+                        }
+                        Some('\n') | None => return Err("unterminated string".to_string()),
+                        Some(c) => text.push(c),
+                    }
+                    self.pos += 1;
+                }
+                self.pos += 1;
+                Expression::Literal(text)
+            }
+            Some(c) if c.is_ascii_digit() || (c == '-' && self.chars.get(self.pos + 1).is_some_and(char::is_ascii_digit)) => {
+                let start = self.pos;
+                self.pos += 1;
+                while self.peek().is_some_and(|c| c.is_ascii_digit() || c == '.') {
+                    self.pos += 1;
+                }
+                let number: String = self.chars[start..self.pos].iter().collect();
+                Expression::Number(number.parse().map_err(|_| format!("bad number `{}`", number))?)
+            }
+            Some('-') => {
+                self.pos += 1;
+                Expression::Term(self.identifier())
+            }
+            Some(c) if c.is_ascii_alphabetic() => Expression::Message(self.identifier()),
+            Some(c) => return Err(format!("unexpected `{}` in a placeable", c)),
+            None => return Err("`{` without `}`".to_string()),
+        };
+        match expression {
+            Expression::Argument(ref name) | Expression::Message(ref name) | Expression::Term(ref name) if name.is_empty() => {
+                Err("a placeable names nothing".to_string())
+            }
+            Expression::Message(ref name) if self.peek() == Some('(') => Err(format!("function `{}` is not supported", name)),
+            Expression::Message(ref name) if self.peek() == Some('.') => Err(format!("attribute of `{}` is not supported", name)),
+            expression => Ok(expression),
+        }
+    }
+
+    /// The variants of a select on `selector`, the `->` read
+    fn select(&mut self, selector: Expression) -> Result<Expression, String> {
+        let mut variants = Vec::new();
+        let mut default = None;
+        loop {
+            self.skip_blank();
+            match self.peek() {
+                Some('}') => {
+                    self.pos += 1;
+                    break;
+                }
+                Some('*') => {
+                    self.pos += 1;
+                    if default.replace(variants.len()).is_some() {
+                        return Err("a select has one default variant".to_string());
+                    }
+                }
+                _ => {}
+            }
+            self.expect('[')?;
+            let start = self.pos;
+            while self.peek().is_some_and(|c| c != ']' && c != '\n') {
+                self.pos += 1;
+            }
+            let key: String = self.chars[start..self.pos].iter().collect();
+            self.expect(']')?;
+            let value = self.pattern(true)?;
+            variants.push((key.trim().to_string(), value));
+        }
+        match default {
+            Some(default) => Ok(Expression::Select { selector: Box::new(selector), variants, default }),
+            None => Err("a select needs a default variant, `*[other]`".to_string()),
+        }
+    }
+}
+
+/// The CLDR plural category of `number` in `lang`: `zero`, `one`, `two`,
+/// `few`, `many` or `other`. The rules of the common languages are known;
+/// the others count as English.
+pub fn plural_category(lang: &str, number: f64) -> &'static str {
+    let base = lang.split(['-', '_']).next().unwrap_or(lang).to_ascii_lowercase();
+    let integer = number.fract() == 0.0;
+    let i = number.abs().trunc() as u64;
+    let (i10, i100) = (i % 10, i % 100);
+    match base.as_str() {
+        "zh" | "ja" | "ko" | "th" | "vi" | "id" | "ms" | "lo" | "my" => "other",
+        "fr" | "pt" => {
+            if i <= 1 {
+                "one"
+            } else {
+                "other"
+            }
+        }
+        "ru" | "uk" | "be" | "pl" if integer => {
+            if i10 == 1 && i100 != 11 {
+                if base == "pl" && i != 1 { "many" } else { "one" }
+            } else if (2..=4).contains(&i10) && !(12..=14).contains(&i100) {
+                "few"
+            } else {
+                "many"
+            }
+        }
+        "cs" | "sk" => match i {
+            _ if !integer => "many",
+            1 => "one",
+            2..=4 => "few",
+            _ => "other",
+        },
+        "ar" if integer => match i {
+            0 => "zero",
+            1 => "one",
+            2 => "two",
+            _ if (3..=10).contains(&i100) => "few",
+            _ if (11..=99).contains(&i100) => "many",
+            _ => "other",
+        },
+        _ => {
+            if integer && i == 1 {
+                "one"
+            } else {
+                "other"
+            }
+        }
+    }
+}
+
+/// The `.ftl` files of the supported languages
+#[derive(Debug, Clone, Default)]
+pub struct FluentTranslations {
+    resources: HashMap<String, Resource>,
+}
+
+impl FluentTranslations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read `source` as the file of `lang`; what could not be read, by line
+    pub fn add(&mut self, lang: &str, source: &str) -> Vec<String> {
+        let (resource, errors) = Resource::parse(source);
+        self.resources.insert(lang.to_string(), resource);
+        errors
+    }
+
+    /// `<dir>/<lang>.ftl` of each of `langs`, logging the entries with errors
+    pub fn load(dir: &Path, langs: &[String]) -> Self {
+        let mut translations = Self::new();
+        for lang in langs {
+            let path = dir.join(format!("{}.ftl", lang));
+            match std::fs::read_to_string(&path) {
+                Ok(source) => {
+                    for err in translations.add(lang, &source) {
+                        tracing::warn!(path = %path.display(), %err, "Fluent entry skipped");
+                    }
+                }
+                Err(err) => tracing::warn!(path = %path.display(), %err, "No Fluent file for the language"),
+            }
+        }
+        translations
+    }
+}
+
+impl Translations for FluentTranslations {
+    fn name(&self) -> &'static str {
+        "fluent"
+    }
+
+    fn message(&self, key: &str, lang: &str, args: &Value) -> Option<String> {
+        self.resources.get(lang)?.format(key, lang, args)
+    }
+
+    fn keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self.resources.values().flat_map(|resource| resource.messages.keys().cloned()).collect();
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fluent_files_have_placeables_and_plurals() {
+        let mut fluent = FluentTranslations::new();
+        let errors = fluent.add(
+            "en",
+            r#"# Comments are skipped
+-brand = SFX
+welcome = Welcome to { -brand }, { $username }!
+about =
+    A value over
+    several lines.
+    .title = About
+new-comments = { $count ->
+    [0] No new comments
+    [one] One new comment
+   *[other] { $count } new comments on { "{" }{ $post }{ "}" }
+}
+broken = { NUMBER($count) }
+also broken
+"#,
+        );
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors[0].starts_with("line 13: broken"));
+        assert_eq!(fluent.add("ru", "files = { $count ->\n    [one] { $count } файл\n    [few] { $count } файла\n   *[many] { $count } файлов\n}\n"), Vec::<String>::new());
+
+        let args = object!({ username: "alice", post: "Hello", count: 0 });
+        let message = |key: &str, lang: &str, args: &Value| fluent.message(key, lang, args).unwrap_or_default();
+        assert_eq!(message("welcome", "en", &args), "Welcome to SFX, alice!");
+        assert_eq!(message("welcome", "en", &Value::None), "Welcome to SFX, {$username}!");
+        assert_eq!(message("about", "en", &args), "A value over\nseveral lines.");
+        assert_eq!(message("new-comments", "en", &args), "No new comments");
+        assert_eq!(message("new-comments", "en", &object!({ count: 1 })), "One new comment");
+        assert_eq!(message("new-comments", "en", &object!({ count: "3", post: "Hi" })), "3 new comments on {Hi}");
+        assert_eq!(message("files", "ru", &object!({ count: 21 })), "21 файл");
+        assert_eq!(message("files", "ru", &object!({ count: 22 })), "22 файла");
+        assert_eq!(message("files", "ru", &object!({ count: 11 })), "11 файлов");
+        assert_eq!(fluent.message("welcome", "ru", &args), None);
+        assert_eq!(fluent.keys(), vec!["about", "files", "new-comments", "welcome"]);
+
+        assert_eq!((plural_category("fr", 0.0), plural_category("en", 0.0), plural_category("ja", 1.0)), ("one", "other", "other"));
+        assert_eq!((plural_category("pl", 1.0), plural_category("pl", 5.0), plural_category("ar", 2.0)), ("one", "many", "two"));
+    }
+}
@@ -0,0 +1,280 @@
+//! latency.rs
+//!
+//! Latency of the routes of the app and of the upstream hosts it calls,
+//! MainAuth servers first, with thresholds from
+//! `programfiles/op/latency.json`:
+//!
+//! ```json
+//! {
+//!     "route_p95_ms": 2000,
+//!     "upstream_p95_ms": 1000,
+//!     "window": 100,
+//!     "min_samples": 20
+//! }
+//! ```
+//!
+//! The last `window` timings of each route and host are kept. Once there
+//! are `min_samples` of them and their 95th percentile goes over the
+//! threshold, [`LatencyExceeded`] is published (for the notifications and
+//! webhooks following events) and the host is listed as degraded by
+//! `/health`; [`LatencyRecovered`] follows when it is back under. A
+//! threshold of `0` turns that side off.
+//!
+//! Routes are keyed by method and path, with the segments holding a digit
+//! written `*`, so that `/blog/post-2` and `/blog/post-3` count together.
+//! Requests are timed by [`crate::access_log::AccessLog`], upstream calls by
+//! `user::fetch::send_http_request`. Admins read the figures at
+//! `/admin/latency/json`.
+
+use hotaru::http::*;
+use hotaru::prelude::*;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::admin::check_is_admin;
+use crate::events::{self, LatencyExceeded, LatencyRecovered};
+use crate::op::APP;
+
+static LATENCY: Lazy<LatencySettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/latency.json");
+    LatencySettings::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None))
+});
+
+static ROUTES: Lazy<Tracker> = Lazy::new(|| Tracker::new("route"));
+
+static UPSTREAMS: Lazy<Tracker> = Lazy::new(|| Tracker::new("upstream"));
+
+/// Routes or hosts tracked at most, so that odd paths cannot grow the table
+const MAX_KEYS: usize = 1000;
+
+/// The parsed content of `latency.json`
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencySettings {
+    pub route_p95_ms: u64,
+    pub upstream_p95_ms: u64,
+    /// Timings kept per route or host
+    pub window: usize,
+    /// Timings needed before the percentile is judged
+    pub min_samples: usize,
+}
+
+impl Default for LatencySettings {
+    fn default() -> Self {
+        Self { route_p95_ms: 2000, upstream_p95_ms: 1000, window: 100, min_samples: 20 }
+    }
+}
+
+impl LatencySettings {
+    pub fn from_value(value: &Value) -> Self {
+        let default = Self::default();
+        let number = |key: &str, fallback: u64| match value.get(key) {
+            Value::Numerical(number) if *number >= 0.0 => *number as u64,
+            _ => fallback,
+        };
+        let window = number("window", default.window as u64).max(1) as usize;
+        Self {
+            route_p95_ms: number("route_p95_ms", default.route_p95_ms),
+            upstream_p95_ms: number("upstream_p95_ms", default.upstream_p95_ms),
+            window,
+            min_samples: (number("min_samples", default.min_samples as u64) as usize).clamp(1, window),
+        }
+    }
+}
+
+/// The loaded latency settings
+pub fn settings() -> &'static LatencySettings {
+    &LATENCY
+}
+
+#[derive(Debug, Default)]
+struct Series {
+    samples: VecDeque<u64>,
+    degraded: bool,
+}
+
+/// The 95th percentile of `samples`, nearest rank
+pub fn p95(samples: &[u64]) -> Option<u64> {
+    if samples.is_empty() {
+        return None;
+    }
+    let mut sorted = samples.to_vec();
+    sorted.sort_unstable();
+    let rank = (sorted.len() * 95).div_ceil(100);
+    Some(sorted[rank.saturating_sub(1)])
+}
+
+/// A change of state of a route or host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transition {
+    Exceeded { key: String, p95_ms: u64, threshold_ms: u64 },
+    Recovered { key: String, p95_ms: u64 },
+}
+
+/// The timings of routes or of hosts
+pub struct Tracker {
+    kind: &'static str,
+    series: Mutex<HashMap<String, Series>>,
+}
+
+impl Tracker {
+    pub fn new(kind: &'static str) -> Self {
+        Self { kind, series: Mutex::new(HashMap::new()) }
+    }
+
+    /// Add a timing of `key`; the transition it causes, if any
+    pub fn record(&self, key: &str, elapsed: Duration, threshold_ms: u64, settings: &LatencySettings) -> Option<Transition> {
+        if threshold_ms == 0 {
+            return None;
+        }
+        let mut table = self.series.lock().unwrap();
+        if !table.contains_key(key) && table.len() >= MAX_KEYS {
+            return None;
+        }
+        let series = table.entry(key.to_string()).or_default();
+        series.samples.push_back(elapsed.as_millis() as u64);
+        while series.samples.len() > settings.window {
+            series.samples.pop_front();
+        }
+        if series.samples.len() < settings.min_samples {
+            return None;
+        }
+        let p95_ms = p95(series.samples.make_contiguous())?;
+        let slow = p95_ms > threshold_ms;
+        if slow == series.degraded {
+            return None;
+        }
+        series.degraded = slow;
+        let key = key.to_string();
+        Some(if slow { Transition::Exceeded { key, p95_ms, threshold_ms } } else { Transition::Recovered { key, p95_ms } })
+    }
+
+    /// The keys over their threshold
+    pub fn degraded(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self
+            .series
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, series)| series.degraded)
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    /// `{"key": {"p95_ms": 120, "samples": 100, "degraded": false}}`
+    pub fn to_json(&self) -> Value {
+        let table = self.series.lock().unwrap();
+        let entries = table
+            .iter()
+            .map(|(key, series)| {
+                let samples: Vec<u64> = series.samples.iter().copied().collect();
+                let p95_ms = p95(&samples).map(Value::from).unwrap_or(Value::None);
+                (key.clone(), object!({ p95_ms: p95_ms, samples: samples.len(), degraded: series.degraded }))
+            })
+            .collect();
+        Value::Dict(entries)
+    }
+
+    fn publish(&self, transition: Option<Transition>) {
+        match transition {
+            Some(Transition::Exceeded { key, p95_ms, threshold_ms }) => {
+                tracing::warn!(kind = self.kind, %key, p95_ms, threshold_ms, "Latency over threshold");
+                events::publish(LatencyExceeded { kind: self.kind.to_string(), key, p95_ms, threshold_ms });
+            }
+            Some(Transition::Recovered { key, p95_ms }) => {
+                tracing::info!(kind = self.kind, %key, p95_ms, "Latency back under threshold");
+                events::publish(LatencyRecovered { kind: self.kind.to_string(), key, p95_ms });
+            }
+            None => {}
+        }
+    }
+}
+
+/// The key of the route of `method path`
+pub fn route_key(method: &str, path: &str) -> String {
+    let path = path.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path
+        .split('/')
+        .map(|segment| if segment.chars().any(|c| c.is_ascii_digit()) { "*" } else { segment })
+        .collect();
+    format!("{} {}", method, segments.join("/"))
+}
+
+/// Time a request to `method path`
+pub fn record_route(method: &str, path: &str, elapsed: Duration) {
+    let settings = settings();
+    ROUTES.publish(ROUTES.record(&route_key(method, path), elapsed, settings.route_p95_ms, settings));
+}
+
+/// Time a call to the upstream `host`, failed ones included
+pub fn record_upstream(host: &str, elapsed: Duration) {
+    let settings = settings();
+    UPSTREAMS.publish(UPSTREAMS.record(host, elapsed, settings.upstream_p95_ms, settings));
+}
+
+/// The upstream hosts over their threshold, for `/health`
+pub fn degraded_upstreams() -> Vec<String> {
+    UPSTREAMS.degraded()
+}
+
+/// The routes over their threshold
+pub fn degraded_routes() -> Vec<String> {
+    ROUTES.degraded()
+}
+
+/// The latency of every tracked route and host
+pub fn report() -> Value {
+    object!({ routes: ROUTES.to_json(), upstreams: UPSTREAMS.to_json() })
+}
+
+endpoint! {
+    APP.url("/admin/latency/json"),
+
+    /// GET /admin/latency/json - The p95 latency of the tracked routes and upstream hosts
+    /// Response: {"success": true, "routes": {"GET /blog": {"p95_ms": 12, "samples": 100, "degraded": false}},
+    ///            "upstreams": {"https://auth.example.com": {...}}}
+    pub admin_latency <HTTP> {
+        if !check_is_admin(req).await {
+            return json_response(object!({ success: false, message: "Unauthorized" })).status(StatusCode::UNAUTHORIZED);
+        }
+        let mut response = report();
+        response.set("success", true);
+        json_response(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn p95_over_the_threshold_flags_the_host() {
+        assert_eq!(p95(&(1..=100).collect::<Vec<u64>>()), Some(95));
+        assert_eq!(p95(&[]), None);
+
+        let settings = LatencySettings { window: 20, min_samples: 10, ..LatencySettings::default() };
+        let tracker = Tracker::new("upstream");
+        let fast = Duration::from_millis(100);
+        let slow = Duration::from_millis(3000);
+        for _ in 0..9 {
+            assert_eq!(tracker.record("https://auth", slow, 1000, &settings), None);
+        }
+        assert!(matches!(tracker.record("https://auth", slow, 1000, &settings), Some(Transition::Exceeded { p95_ms: 3000, .. })));
+        assert_eq!(tracker.degraded(), vec!["https://auth"]);
+        assert_eq!(tracker.record("https://auth", slow, 1000, &settings), None);
+
+        let recovered = (0..20).filter_map(|_| tracker.record("https://auth", fast, 1000, &settings)).collect::<Vec<_>>();
+        assert_eq!(recovered, vec![Transition::Recovered { key: "https://auth".to_string(), p95_ms: 100 }]);
+        assert!(tracker.degraded().is_empty());
+        assert_eq!(tracker.record("https://auth", slow, 0, &settings), None);
+    }
+
+    #[test]
+    fn routes_group_ids() {
+        assert_eq!(route_key("GET", "/blog/post-2?x=1"), "GET /blog/*");
+        assert_eq!(route_key("POST", "/admin/users/12/edit"), "POST /admin/users/*/edit");
+        assert_eq!(LatencySettings::from_value(&Value::None), LatencySettings::default());
+    }
+}
@@ -1,6 +1,6 @@
 use hotaru::prelude::*;
 use hotaru::http::*;
-use htmstd::{CookieSession, PreferredLanguageMiddleware, PreferredLanguageSettings, PrintLog};
+use htmstd::{PreferredLanguageMiddleware, PreferredLanguageSettings};
 
 pub mod prelude {
     pub use hotaru::prelude::*;
@@ -9,6 +9,9 @@ pub mod prelude {
         CookieSession, Cors, PreferredLanguage, PreferredLanguageMiddleware,
         PreferredLanguageRequestExt, PreferredLanguageSettings, PrintLog, cors_settings,
     };
+    pub use crate::ctx::SfxCtx;
+    pub use crate::methods;
+    pub use crate::extract::Extract;
     pub use hotaru;
 }
 
@@ -18,17 +21,86 @@ pub mod op;
 pub mod user;
 pub mod local_auth;
 pub mod admin;
+pub mod captcha;
+pub mod honeypot;
+pub mod ip_filter;
+pub mod bans;
+pub mod proxy;
+pub mod tls;
+pub mod bindings;
+pub mod unix_socket;
+pub mod logging;
+pub mod modules;
+pub mod database;
+pub mod backup;
+pub mod session;
+pub mod secrets;
+pub mod geo;
+pub mod security_headers;
+pub mod consent;
+pub mod analytics;
+pub mod flags;
+pub mod qr;
+pub mod shortlinks;
+pub mod images;
+pub mod media;
+pub mod scan;
+pub mod storage;
+pub mod comments;
+pub mod moderation;
+pub mod blog;
+pub mod forms;
+pub mod settings;
+pub mod preferences;
+pub mod events;
+pub mod nav;
+pub mod ctx;
+pub mod testing;
+pub mod seed;
+pub mod dev;
+pub mod recovery;
+pub mod access_log;
+pub mod latency;
+pub mod outbound;
+pub mod mail;
+pub mod notifications;
+pub mod locale_urls;
+pub mod l10n;
+pub mod access;
+pub mod routing;
+pub mod extract;
+pub mod graphql;
+pub mod grpc;
+pub mod sms;
 
 pub static APP: SServer = Lazy::new(|| {
     Server::new()
-        // .mode(RunMode::Build)
+        .mode(op::run_mode())
         .binding(op::BINDING.clone())
         .max_connection_time(TimeoutSetting::Seconds(10))
         .single_protocol(ProtocolBuilder::new(HTTP::server(HttpSafety::default()))
-            .append_middleware::<PrintLog>()
-            .append_middleware::<CookieSession>()
+            .append_middleware::<locale_urls::LocalePrefix>()
+            .append_middleware::<access_log::AccessLog>()
+            .append_middleware::<recovery::PanicRecovery>()
+            .append_middleware::<dev::DevMode>()
+            .append_middleware::<proxy::ProxyHeaders>()
+            .append_middleware::<ip_filter::IpFilter>()
+            .append_middleware::<bans::Banned>()
+            .append_middleware::<tls::HttpsRedirect>()
+            .append_middleware::<bindings::BindingGuard>()
+            .append_middleware::<tls::ClientCertGuard>()
+            .append_middleware::<modules::ModuleGuard>()
+            .append_middleware::<admin::read_only::AdminReadOnly>()
+            .append_middleware::<security_headers::SecurityHeaders>()
+            .append_middleware::<session::KeyedSession>()
             .append_middleware::<PreferredLanguageMiddleware>()
             .append_middleware::<user::UserFetch>()
+            .append_middleware::<admin::internal::SignedAdminCall>()
+            .append_middleware::<access::AccessGuard>()
+            .append_middleware::<moderation::ModerationGuard>()
+            .append_middleware::<consent::RestoreConsent>()
+            .append_middleware::<preferences::LoadPreferences>()
+            .append_middleware::<modules::ModuleLayers>()
         )
         .set_config(
             prelude::cors_settings::AppCorsSettings::new()
@@ -39,6 +111,69 @@ pub static APP: SServer = Lazy::new(|| {
         .build()
 });
 
+/// Run the app together with its auxiliary listeners (the extra bindings of
+/// `bindings.json`, the TLS front when `tls.json` enables it, the Unix
+/// socket when `binding.txt` names one and the gRPC auth service when
+/// `grpc.json` enables it) until Ctrl+C or SIGTERM.
+///
+/// `SFX_BINDING`, `SFX_PROGRAMFILES` and `SFX_LOG` override the binding, the
+/// configuration directory and the log level; `sfx serve` sets them from its
+/// flags. `SFX_ENV=development` turns on the development mode of [`dev`].
+///
+/// Fails when one of the auxiliary listeners cannot bind.
+///
+/// ```rust,ignore
+/// #[tokio::main]
+/// async fn main() -> std::io::Result<()> {
+///     sfx::serve(APP.clone()).await
+/// }
+/// ```
+pub async fn serve(app: std::sync::Arc<Server<TcpTransport, TokioRuntime>>) -> std::io::Result<()> {
+    logging::init_from_env();
+    // Load the user store now so it is locked against `sfx user` from the start
+    if modules::enabled(modules::LOCAL_AUTH) {
+        Lazy::force(&local_auth::LOCAL_AUTH);
+    }
+    dev::start();
+    backup::start();
+    mail::queue::start();
+    notifications::start();
+    analytics::start();
+    shortlinks::start();
+    modules::start();
+    let started = start_listeners(&app).await;
+    if started.is_ok() {
+        app.run_until(shutdown_signal()).await;
+        analytics::flush();
+        shortlinks::flush();
+    }
+    unix_socket::cleanup();
+    started
+}
+
+/// Start the listeners of [`serve`] besides the binding of `app`
+async fn start_listeners(app: &std::sync::Arc<Server<TcpTransport, TokioRuntime>>) -> std::io::Result<()> {
+    let failed = |what: String| move |err: std::io::Error| std::io::Error::new(err.kind(), format!("Failed to {}: {}", what, err));
+    bindings::start(app.clone()).await.map_err(failed("bind the listeners of bindings.json".to_string()))?;
+    tls::start(app.binding.clone())
+        .await
+        .map_err(failed(format!("start the TLS listener on {}", tls::settings().binding)))?;
+    unix_socket::start(app.binding.clone())
+        .await
+        .map_err(failed("listen on the Unix socket of binding.txt".to_string()))?;
+    grpc::start().await.map_err(failed(format!("start the gRPC service on {}", grpc::settings().binding)))
+}
+
+/// Resolves on Ctrl+C, or on SIGTERM from a service manager
+async fn shutdown_signal() {
+    let mut term = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("Failed to install the SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = term.recv() => {}
+    }
+}
+
 // endpoint! {
 //     APP.url("/"),
 //     pub home_route <HTTP> { 
@@ -1,10 +1,35 @@
 pub mod fop; 
+pub mod at_rest;
+pub mod backup_codes;
+pub mod rules;
+pub mod stats;
+pub mod age;
 pub mod endpoints; 
 pub mod analyze; 
+pub mod scope;
+pub mod device;
+pub mod names;
+pub mod email_change;
+pub mod emails;
+pub mod phone;
+pub mod second_factor;
+pub mod reauth;
+pub mod security;
+pub mod sessions;
+pub mod public;
 
 use std::time::Duration;
 
 use hotaru::prelude::Lazy;
 
-pub static LOCAL_AUTH: Lazy<fop::AuthManager> =
-    Lazy::new(|| fop::AuthManager::new("programfiles/local_auth/users", Duration::from_secs(180))); 
+pub static LOCAL_AUTH: Lazy<fop::AuthManager> = Lazy::new(|| {
+    let key = at_rest::users_key(&crate::op::programfiles())
+        .unwrap_or_else(|err| panic!("Cannot load the `users_key` of op/local_auth.json: {}", err));
+    fop::AuthManager::new(users_file(), key, Duration::from_secs(180))
+        .unwrap_or_else(|err| panic!("Cannot load the user store: {}", err.to_string()))
+});
+
+/// Path of the local user store under `programfiles`
+pub fn users_file() -> String {
+    crate::op::programfiles().join("local_auth/users").to_string_lossy().into_owned()
+}
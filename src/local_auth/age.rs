@@ -0,0 +1,160 @@
+//! age.rs
+//!
+//! Optional minimum age for new accounts, set under `age` in
+//! `programfiles/op/local_auth.json`:
+//!
+//! ```json
+//! { "users_key": "", "age": { "minimum": 16, "privacy": true } }
+//! ```
+//!
+//! With a `minimum`, registrations (`POST /users` and the admin panel) must
+//! carry a `date_of_birth` as `YYYY-MM-DD`, and people younger than the
+//! minimum are turned away. The profile of the new account then holds the
+//! date of birth, or with `privacy` only `over_minimum_age: true` and the
+//! `minimum_age` it was checked against, so the date itself is never stored.
+//! Without a `minimum`, or with `0`, the field is ignored. `sfx user add` is
+//! not gated.
+
+use hotaru::prelude::*;
+
+static AGE: Lazy<AgePolicy> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/local_auth.json");
+    AgePolicy::from_value(Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None).get("age"))
+});
+
+/// Field of the registration carrying the date of birth
+pub const BIRTH_FIELD: &str = "date_of_birth";
+
+/// Why a registration fails the age gate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AgeError {
+    Required,
+    Invalid,
+    TooYoung(u32),
+}
+
+impl std::fmt::Display for AgeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AgeError::Required => write!(f, "Date of birth required"),
+            AgeError::Invalid => write!(f, "Date of birth is not valid"),
+            AgeError::TooYoung(minimum) => write!(f, "You must be at least {} years old to register", minimum),
+        }
+    }
+}
+
+/// The `age` entry of `local_auth.json`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AgePolicy {
+    pub minimum: Option<u32>,
+    /// Keep only whether the minimum was met, not the date of birth
+    pub privacy: bool,
+}
+
+impl AgePolicy {
+    pub fn from_value(value: &Value) -> Self {
+        Self {
+            minimum: match value.get("minimum") {
+                Value::Numerical(n) if *n > 0.0 => Some(*n as u32),
+                _ => None,
+            },
+            privacy: value.get("privacy").boolean(),
+        }
+    }
+
+    /// Check the date of birth given at registration on `today`, returning
+    /// the entries to put in the profile of the new account
+    pub fn check(&self, birth: &str, today: (i64, u32, u32)) -> Result<Value, AgeError> {
+        let mut profile = Value::new_dict();
+        let Some(minimum) = self.minimum else {
+            return Ok(profile);
+        };
+        if birth.trim().is_empty() {
+            return Err(AgeError::Required);
+        }
+        let date = parse_date(birth.trim()).filter(|&date| date <= today).ok_or(AgeError::Invalid)?;
+        if age_on(date, today) < minimum {
+            return Err(AgeError::TooYoung(minimum));
+        }
+        if self.privacy {
+            profile.set("over_minimum_age", true);
+            profile.set("minimum_age", minimum);
+        } else {
+            profile.set(BIRTH_FIELD, birth.trim());
+        }
+        Ok(profile)
+    }
+}
+
+/// The loaded age policy
+pub fn settings() -> &'static AgePolicy {
+    &AGE
+}
+
+/// Check `birth` against the loaded policy today
+pub fn check(birth: &str) -> Result<Value, AgeError> {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+    AGE.check(birth, date_of(now))
+}
+
+/// Parse `YYYY-MM-DD`
+fn parse_date(date: &str) -> Option<(i64, u32, u32)> {
+    let mut parts = date.splitn(3, '-');
+    let year = parts.next()?.parse::<i64>().ok()?;
+    let month = parts.next()?.parse::<u32>().ok()?;
+    let day = parts.next()?.parse::<u32>().ok()?;
+    let leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let days = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => return None,
+    };
+    (year >= 1900 && (1..=days).contains(&day)).then_some((year, month, day))
+}
+
+/// Full years from `birth` to `today`
+fn age_on(birth: (i64, u32, u32), today: (i64, u32, u32)) -> u32 {
+    let birthday_passed = (today.1, today.2) >= (birth.1, birth.2);
+    (today.0 - birth.0 - if birthday_passed { 0 } else { 1 }).max(0) as u32
+}
+
+/// The UTC date of the unix time `secs`
+fn date_of(secs: u64) -> (i64, u32, u32) {
+    // Days to civil date, after H. Hinnant's `civil_from_days`
+    let z = (secs / 86_400) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registrations_are_checked_against_the_minimum_age() {
+        assert_eq!(date_of(0), (1970, 1, 1));
+        assert_eq!(date_of(951_782_400), (2000, 2, 29));
+
+        let today = (2024, 6, 15);
+        let policy = AgePolicy { minimum: Some(18), privacy: false };
+        assert_eq!(policy.check("2006-06-15", today).unwrap().get(BIRTH_FIELD).string(), "2006-06-15");
+        assert_eq!(policy.check("2006-06-16", today), Err(AgeError::TooYoung(18)));
+        assert_eq!(policy.check("", today), Err(AgeError::Required));
+        assert_eq!(policy.check("2001-02-29", today), Err(AgeError::Invalid));
+        assert_eq!(policy.check("2030-01-01", today), Err(AgeError::Invalid));
+
+        let private = AgePolicy { privacy: true, ..policy }.check("1990-01-01", today).unwrap();
+        assert!(private.get("over_minimum_age").boolean());
+        assert!(private.try_get(BIRTH_FIELD).is_err());
+        assert_eq!(AgePolicy::default().check("", today), Ok(Value::new_dict()));
+    }
+}
@@ -0,0 +1,137 @@
+//! at_rest.rs
+//!
+//! Optional encryption of the user store `programfiles/local_auth/users`,
+//! switched on in `programfiles/op/local_auth.json`:
+//!
+//! ```json
+//! { "users_key": "file:secrets/users.key" }
+//! ```
+//!
+//! The key is a secret reference (see `crate::secrets`) of at least
+//! [`MIN_KEY_LEN`] characters. With a key the store is written as
+//! `{"encrypted": "aes-256-gcm", "data": "..."}`, so a copy of
+//! `programfiles` (backups leave out `secrets`) does not give away the
+//! accounts. A plain store is still read and gets encrypted by the next
+//! flush. Once encrypted, the store cannot be read without its key, so the
+//! key must be kept safe apart from the backups. A store that cannot be read
+//! is an error rather than an empty store, which the next flush would write
+//! over the accounts.
+
+use hotaru::prelude::*;
+use hotaru_lib::ende::aes;
+use std::path::Path;
+
+use crate::secrets::{self, SecretError};
+
+/// Shorter keys are refused; the key derivation does no stretching
+pub const MIN_KEY_LEN: usize = 32;
+
+/// The cipher named in the envelope
+const CIPHER: &str = "aes-256-gcm";
+
+/// The parsed content of `local_auth.json`
+#[derive(Debug, Clone, Default)]
+pub struct StoreSettings {
+    /// Reference to the key of the user store; empty for a plain store
+    pub users_key: String,
+}
+
+impl StoreSettings {
+    pub fn from_value(value: &Value) -> Self {
+        Self { users_key: value.get("users_key").string() }
+    }
+}
+
+/// The key of the user store configured under `programfiles`, `None` when
+/// the store is kept in plain
+pub fn users_key(programfiles: &Path) -> Result<Option<String>, SecretError> {
+    let path = programfiles.join("op/local_auth.json");
+    let settings = StoreSettings::from_value(&Value::from_jsonf(path.to_string_lossy()).unwrap_or(Value::None));
+    if settings.users_key.is_empty() {
+        return Ok(None);
+    }
+    let key = secrets::resolve_in(&settings.users_key, programfiles)?;
+    if key.len() < MIN_KEY_LEN {
+        return Err(SecretError::Insecure(format!(
+            "the key of the user store must have at least {} characters",
+            MIN_KEY_LEN
+        )));
+    }
+    Ok(Some(key))
+}
+
+/// The file content storing `users`, encrypted when there is a key
+pub fn seal(users: &Value, key: Option<&str>) -> Result<String, String> {
+    let json = users.into_json();
+    let Some(key) = key else {
+        return Ok(json);
+    };
+    let data = aes::encrypt(&json, key).map_err(|_| "Failed to encrypt the user store".to_string())?;
+    let mut envelope = Value::new_dict();
+    envelope.set("encrypted", CIPHER);
+    envelope.set("data", data);
+    Ok(envelope.into_json())
+}
+
+/// The users stored in `contents`, plain or encrypted
+pub fn unseal(contents: &str, key: Option<&str>) -> Result<Value, String> {
+    let value = Value::from_json(contents).map_err(|_| "not valid JSON".to_string())?;
+    let Ok(cipher) = value.try_get("encrypted") else {
+        return Ok(value);
+    };
+    if cipher.string() != CIPHER {
+        return Err(format!("encrypted with unknown cipher '{}'", cipher.string()));
+    }
+    let Some(key) = key else {
+        return Err("encrypted, but op/local_auth.json has no `users_key`".to_string());
+    };
+    let json = aes::decrypt(&value.get("data").string(), key)
+        .map_err(|_| "cannot be decrypted with the configured `users_key`".to_string())?;
+    Value::from_json(&json).map_err(|_| "decrypts to invalid JSON".to_string())
+}
+
+/// Whether `contents` is an encrypted store
+pub fn is_sealed(contents: &str) -> bool {
+    Value::from_json(contents).is_ok_and(|value| value.try_get("encrypted").is_ok())
+}
+
+/// Read the user store at `path`; a missing file is an empty store
+pub fn read(path: &str, key: Option<&str>) -> Result<Value, String> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => unseal(&contents, key).map_err(|err| format!("{} {}", path, err)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Value::Dict(Default::default())),
+        Err(err) => Err(format!("Failed to read {}: {}", path, err)),
+    }
+}
+
+/// Write the user store at `path`, replacing the file only once the new
+/// content is fully written
+pub fn write(path: &str, users: &Value, key: Option<&str>) -> Result<(), String> {
+    let contents = seal(users, key)?;
+    let temporary = format!("{}.tmp", path);
+    secrets::write_private(Path::new(&temporary), contents)
+        .and_then(|_| std::fs::rename(&temporary, path))
+        .map_err(|err| format!("Failed to write {}: {}", path, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stores_are_sealed_with_the_key_and_plain_ones_still_open() {
+        let key = "0123456789abcdef0123456789abcdef";
+        let users = Value::from_json(r#"{"1": {"username": "Admin", "email": "admin@example.com"}}"#).unwrap();
+
+        let plain = seal(&users, None).unwrap();
+        assert!(!is_sealed(&plain));
+        assert_eq!(unseal(&plain, Some(key)).unwrap(), users);
+
+        let sealed = seal(&users, Some(key)).unwrap();
+        assert!(is_sealed(&sealed));
+        assert!(!sealed.contains("admin@example.com"));
+        assert_eq!(unseal(&sealed, Some(key)).unwrap(), users);
+        assert!(unseal(&sealed, None).unwrap_err().contains("no `users_key`"));
+        assert!(unseal(&sealed, Some("fedcba9876543210fedcba9876543210")).is_err());
+    }
+}
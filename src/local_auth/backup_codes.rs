@@ -0,0 +1,114 @@
+//! backup_codes.rs
+//!
+//! Single-use recovery codes for the second factor, for when the phone is
+//! out of reach. Turning the first method of `super::second_factor` on
+//! gives the account `COUNT` codes, shown that once; the account keeps only
+//! their hashes. Each may answer one login challenge in place of the texted
+//! code, and is gone once used. `POST /users/me/two_factor/backup_codes`
+//! replaces the set, voiding the old codes; turning the last method off
+//! drops it.
+
+use hotaru::http::*;
+use hotaru::prelude::*;
+use hotaru_lib::random::random_alphanumeric_string;
+
+use super::LOCAL_AUTH;
+use super::email_change::hash;
+use super::reauth::{SENSITIVE_MAX_AGE, require_recent_auth};
+use super::scope::{self, require_scope_or_session};
+use crate::op::APP;
+use crate::methods;
+
+/// Codes in a set
+pub const COUNT: usize = 10;
+
+/// Lowercase letters and digits, without those read alike (0/o, 1/l/i)
+const ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+/// A new set of codes, like `k7mq-3xhz`, and their hashes to store
+pub fn generate() -> (Vec<String>, Vec<String>) {
+    let codes: Vec<String> = (0..COUNT)
+        .map(|_| {
+            let chars: String = random_alphanumeric_string(8)
+                .bytes()
+                .map(|byte| ALPHABET[byte as usize % ALPHABET.len()] as char)
+                .collect();
+            format!("{}-{}", &chars[..4], &chars[4..])
+        })
+        .collect();
+    let hashes = codes.iter().map(|code| hash(&normalize(code))).collect();
+    (codes, hashes)
+}
+
+/// `code` as hashed: lowercase, without spaces and dashes
+pub fn normalize(code: &str) -> String {
+    code.chars().filter(|c| !matches!(c, ' ' | '-')).collect::<String>().to_lowercase()
+}
+
+/// Use up the code of `hashes` that `code` is; whether there was one
+pub fn consume(hashes: &mut Vec<String>, code: &str) -> bool {
+    let code = normalize(code);
+    if code.is_empty() {
+        return false;
+    }
+    let hashed = hash(&code);
+    match hashes.iter().position(|stored| *stored == hashed) {
+        Some(at) => {
+            hashes.remove(at);
+            true
+        }
+        None => false,
+    }
+}
+
+endpoint! {
+    APP.url("/users/me/two_factor/backup_codes"),
+
+    /// GET /users/me/two_factor/backup_codes - How many backup codes are left
+    /// POST /users/me/two_factor/backup_codes - Replace them with a new set, shown this once
+    /// A bearer token with the `profile:read` (GET) or `profile:write` scope, or the session of a local account
+    /// Response (1): {"success": false, "error": "Method not allowed"/"Sign in with a local account"/"Turn a second factor on first"}
+    /// Response (2): {"success": true, "remaining": 10}
+    /// Response (3): {"success": true, "remaining": 10, "backup_codes": ["k7mq-3xhz", ...]}
+    /// POST takes a password confirmed within 15 minutes, else 403 with `"reauth": true` (see `super::reauth`)
+    pub backup_codes <HTTP> {
+        methods!(req, GET | POST; error);
+        let method = req.method();
+        let scope = if method == GET { scope::PROFILE_READ } else { scope::PROFILE_WRITE };
+        let uid = match require_scope_or_session(req, scope).await {
+            Ok(uid) => uid,
+            Err(response) => return response,
+        };
+        if method == GET {
+            return match LOCAL_AUTH.admin_get_user(uid).await {
+                Some(user) => akari_json!({ success: true, remaining: user.backup_codes.len() }),
+                None => akari_json!({ success: false, error: "User not found" }).status(404),
+            };
+        }
+        if let Err(response) = require_recent_auth(req, SENSITIVE_MAX_AGE).await {
+            return response;
+        }
+        match LOCAL_AUTH.regenerate_backup_codes(uid).await {
+            Ok(codes) => akari_json!({ success: true, remaining: codes.len(), backup_codes: Value::new(codes) }),
+            Err(err) => akari_json!({ success: false, error: err.to_string() }).status(400),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_are_used_once() {
+        let (codes, mut hashes) = generate();
+        assert_eq!((codes.len(), hashes.len()), (COUNT, COUNT));
+        assert!(codes.iter().all(|code| code.len() == 9 && code.as_bytes()[4] == b'-'));
+        assert!(!hashes.contains(&codes[0]));
+
+        assert!(consume(&mut hashes, &codes[0].to_uppercase().replace('-', " ")));
+        assert!(!consume(&mut hashes, &codes[0]));
+        assert!(!consume(&mut hashes, "") && !consume(&mut hashes, "wrong"));
+        assert_eq!(hashes.len(), COUNT - 1);
+    }
+}
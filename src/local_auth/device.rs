@@ -0,0 +1,337 @@
+//! device.rs
+//!
+//! The device authorization grant (RFC 8628) for clients that cannot show a
+//! login form, such as CLI tools:
+//!
+//! 1. The client calls `POST /auth/device/code` and shows the user the
+//!    `user_code` and `verification_uri` it gets back.
+//! 2. The user opens `/activate` in a browser where they are signed in with
+//!    a local account, enters the code and approves (or denies) the device.
+//! 3. Meanwhile the client polls `POST /auth/device/token` with the
+//!    `device_code`, at most every `interval` seconds, until it gets a token.
+//!
+//! Grants live in memory only and are dropped once used or expired.
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+use hotaru_lib::random::random_alphanumeric_string;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use super::LOCAL_AUTH;
+use super::scope::Scopes;
+use crate::modules;
+use crate::op::{self, APP};
+use crate::{proxy, qr};
+use crate::user::fetch::get_user;
+use crate::methods;
+
+/// Seconds a device code stays valid
+pub const EXPIRES_IN: u64 = 600;
+/// Seconds a client should wait between two polls
+pub const INTERVAL: u64 = 5;
+
+/// Letters of user codes; no vowels or look-alike characters
+const USER_CODE_ALPHABET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+
+static GRANTS: Lazy<DeviceGrants> = Lazy::new(DeviceGrants::new);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Decision {
+    Pending,
+    Approved(u32),
+    Denied,
+}
+
+#[derive(Debug, Clone)]
+struct DeviceGrant {
+    user_code: String,
+    scopes: Scopes,
+    expires: u64,
+    last_poll: Option<u64>,
+    decision: Decision,
+}
+
+/// What polling a device code found
+#[derive(Debug, Clone, PartialEq)]
+pub enum Poll {
+    /// The user has not decided yet
+    Pending,
+    /// Polled again before `INTERVAL` passed
+    SlowDown,
+    Denied,
+    /// Unknown, expired or already used
+    Expired,
+    /// Approved by `uid`; the grant is used up
+    Approved(u32, Scopes),
+}
+
+/// The pending device grants, by device code
+pub struct DeviceGrants(RwLock<HashMap<String, DeviceGrant>>);
+
+impl Default for DeviceGrants {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeviceGrants {
+    pub fn new() -> Self {
+        DeviceGrants(RwLock::new(HashMap::new()))
+    }
+
+    /// Start a grant for `scopes`, returning its device code and user code
+    pub fn start(&self, scopes: Scopes, now: u64) -> (String, String) {
+        let device_code = random_alphanumeric_string(40);
+        let user_code = new_user_code();
+        let mut grants = self.0.write().unwrap();
+        grants.retain(|_, grant| grant.expires > now);
+        grants.insert(device_code.clone(), DeviceGrant {
+            user_code: user_code.clone(),
+            scopes,
+            expires: now + EXPIRES_IN,
+            last_poll: None,
+            decision: Decision::Pending,
+        });
+        (device_code, user_code)
+    }
+
+    /// The scopes asked for by the undecided grant of `user_code`
+    pub fn pending(&self, user_code: &str, now: u64) -> Option<Scopes> {
+        let user_code = normalize_user_code(user_code);
+        self.0
+            .read()
+            .unwrap()
+            .values()
+            .find(|grant| grant.user_code == user_code && grant.expires > now && grant.decision == Decision::Pending)
+            .map(|grant| grant.scopes.clone())
+    }
+
+    /// Approve the grant of `user_code` for `uid`, or deny it with `None`.
+    /// False when there is no undecided grant with that code.
+    pub fn decide(&self, user_code: &str, uid: Option<u32>, now: u64) -> bool {
+        let user_code = normalize_user_code(user_code);
+        let mut grants = self.0.write().unwrap();
+        let Some(grant) = grants
+            .values_mut()
+            .find(|grant| grant.user_code == user_code && grant.expires > now && grant.decision == Decision::Pending)
+        else {
+            return false;
+        };
+        grant.decision = match uid {
+            Some(uid) => Decision::Approved(uid),
+            None => Decision::Denied,
+        };
+        true
+    }
+
+    /// Poll the grant of `device_code`
+    pub fn poll(&self, device_code: &str, now: u64) -> Poll {
+        let mut grants = self.0.write().unwrap();
+        let Some(grant) = grants.get_mut(device_code).filter(|grant| grant.expires > now) else {
+            grants.remove(device_code);
+            return Poll::Expired;
+        };
+        let too_soon = grant.last_poll.is_some_and(|last| now < last + INTERVAL);
+        grant.last_poll = Some(now);
+        match grant.decision.clone() {
+            Decision::Approved(uid) => {
+                let scopes = grant.scopes.clone();
+                grants.remove(device_code);
+                Poll::Approved(uid, scopes)
+            }
+            Decision::Denied => {
+                grants.remove(device_code);
+                Poll::Denied
+            }
+            Decision::Pending if too_soon => Poll::SlowDown,
+            Decision::Pending => Poll::Pending,
+        }
+    }
+}
+
+fn new_user_code() -> String {
+    let code: String = random_alphanumeric_string(8)
+        .bytes()
+        .map(|byte| USER_CODE_ALPHABET[byte as usize % USER_CODE_ALPHABET.len()] as char)
+        .collect();
+    format!("{}-{}", &code[..4], &code[4..])
+}
+
+/// Users may type the code in lower case, without the dash or with spaces
+fn normalize_user_code(code: &str) -> String {
+    let code: String = code.chars().filter(|c| c.is_ascii_alphanumeric()).map(|c| c.to_ascii_uppercase()).collect();
+    if code.len() == 8 {
+        format!("{}-{}", &code[..4], &code[4..])
+    } else {
+        code
+    }
+}
+
+/// A field of the request body, sent either as a form, as RFC 8628 has it,
+/// or as JSON like the rest of `/auth`
+async fn body_field(req: &mut HttpReqCtx, name: &str) -> Option<String> {
+    if let Some(form) = req.form().await {
+        return form.get(name).cloned();
+    }
+    req.json_or_default().await.try_get(name).ok().map(|value| value.string())
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+}
+
+endpoint! {
+    APP.url("/auth/device/code"),
+
+    /// POST /auth/device/code - Start a device authorization
+    /// Request body: Json or form -> {"scope": "profile:read"} (optional, every scope when left out)
+    /// Response (1): {"success": false, "error": "Method not allowed"/"Unknown scope: ..."}
+    /// Response (2): {"success": true, "device_code": "...", "user_code": "BCDF-GHJK",
+    ///     "verification_uri": "/activate", "verification_uri_complete": "/activate?code=BCDF-GHJK",
+    ///     "verification_uri_qr": "/op/qr?data=...", "expires_in": 600, "interval": 5}
+    /// `verification_uri_qr` is a PNG QR code of the complete URI, for
+    /// clients that can show an image to scan with a phone
+    pub device_code <HTTP> {
+        methods!(req, POST; error);
+        let scopes = match body_field(req, "scope").await {
+            Some(requested) => match Scopes::parse(&requested) {
+                Ok(scopes) => scopes,
+                Err(err) => return akari_json!({ success: false, error: err }).status(400),
+            },
+            None => Scopes::All,
+        };
+        let (device_code, user_code) = GRANTS.start(scopes, now());
+        let complete = format!("{}/activate?code={}", proxy::public_origin(req), user_code);
+        akari_json!({
+            success: true,
+            device_code: device_code,
+            user_code: user_code.clone(),
+            verification_uri: "/activate",
+            verification_uri_complete: format!("/activate?code={}", user_code),
+            verification_uri_qr: qr::url(&complete, qr::Format::Png, 256),
+            expires_in: EXPIRES_IN,
+            interval: INTERVAL,
+        })
+    }
+}
+
+endpoint! {
+    APP.url("/auth/device/token"),
+
+    /// POST /auth/device/token - Poll for the token of a device authorization
+    /// Request body: Json or form -> {"device_code": "..."}
+    /// Response (1): {"success": false, "error": "Method not allowed"/"authorization_pending"/"slow_down"/"access_denied"/"expired_token"}
+    /// Response (2): {"success": true, "access_token": access, "token_type": "Bearer", "scope": "profile:read ..."}
+    pub device_token <HTTP> {
+        methods!(req, POST; error);
+        let device_code = body_field(req, "device_code").await.unwrap_or_default();
+        match GRANTS.poll(&device_code, now()) {
+            Poll::Approved(uid, scopes) => {
+                let granted = scopes.to_string();
+                match LOCAL_AUTH.issue_token(uid, scopes).await {
+                    Ok(token) => akari_json!({ success: true, access_token: token, token_type: "Bearer", scope: granted }),
+                    Err(_) => akari_json!({ success: false, error: "access_denied" }).status(400),
+                }
+            }
+            Poll::Pending => akari_json!({ success: false, error: "authorization_pending" }).status(400),
+            Poll::SlowDown => akari_json!({ success: false, error: "slow_down" }).status(400),
+            Poll::Denied => akari_json!({ success: false, error: "access_denied" }).status(400),
+            Poll::Expired => akari_json!({ success: false, error: "expired_token" }).status(400),
+        }
+    }
+}
+
+endpoint! {
+    APP.url("/activate"),
+
+    /// The page approving a device with its user code
+    ///
+    /// # Request
+    /// `GET /activate?code=<user code>`, the code being optional
+    /// `POST /activate`, UrlCodedForm with `code` and `decision` ("approve" or "deny")
+    ///
+    /// # Response
+    /// The activation page. Only users signed in with a local account can
+    /// approve, since the device gets a token of this server.
+    pub activate <HTTP> {
+        if !modules::enabled(modules::LOCAL_AUTH) {
+            return text_response("Not Found").status(StatusCode::NOT_FOUND);
+        }
+        methods!(req, GET | HEAD | POST);
+        let user = get_user(req).await;
+        let signed_in = !user.get_user_id().is_guest() && user.get_server().is_local();
+        let mut code = req.query("code").unwrap_or_default();
+        let mut message = String::new();
+        let mut approved = false;
+        if req.method() == POST && signed_in {
+            let form = req.form_or_default().await;
+            code = form.get_or_default("code").clone();
+            let decision = match form.get_or_default("decision").as_str() {
+                "approve" => Some(user.get_uid() as u32),
+                _ => None,
+            };
+            if GRANTS.decide(&code, decision, now()) {
+                approved = decision.is_some();
+                message = if approved {
+                    "Device approved. You can return to it now.".to_string()
+                } else {
+                    "Device denied.".to_string()
+                };
+                code = String::new();
+            } else {
+                message = "This code is invalid or has expired.".to_string();
+            }
+        }
+        let scope = GRANTS.pending(&code, now()).map(|scopes| scopes.to_string()).unwrap_or_default();
+        akari_render!(
+            "user/activate.html",
+            pageprop = op::pageprop(req, "Activate Device", "Approve a device signing in to your account"),
+            path = op::into_path_l(req, vec!["home", "user", "activate"]),
+            signed_in = signed_in,
+            username = user.get_username().to_string(),
+            code = code,
+            scope = scope,
+            message = message,
+            approved = approved,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_is_approved_then_polled_once() {
+        let grants = DeviceGrants::new();
+        let (device_code, user_code) = grants.start(Scopes::parse("profile:read").unwrap(), 1000);
+        assert_eq!(user_code.len(), 9);
+        assert!(user_code.bytes().all(|b| b == b'-' || USER_CODE_ALPHABET.contains(&b)));
+
+        assert_eq!(grants.poll(&device_code, 1000), Poll::Pending);
+        assert_eq!(grants.poll(&device_code, 1002), Poll::SlowDown);
+
+        // Typed in lower case without the dash
+        let typed = user_code.replace('-', "").to_lowercase();
+        assert!(grants.pending(&typed, 1003).is_some());
+        assert!(grants.decide(&typed, Some(4), 1003));
+        assert!(!grants.decide(&user_code, Some(5), 1003));
+
+        assert_eq!(grants.poll(&device_code, 1010), Poll::Approved(4, Scopes::parse("profile:read").unwrap()));
+        assert_eq!(grants.poll(&device_code, 1020), Poll::Expired);
+    }
+
+    #[test]
+    fn denied_and_expired_grants_give_no_token() {
+        let grants = DeviceGrants::new();
+        let (denied, user_code) = grants.start(Scopes::All, 1000);
+        assert!(grants.decide(&user_code, None, 1001));
+        assert_eq!(grants.poll(&denied, 1002), Poll::Denied);
+
+        let (late, user_code) = grants.start(Scopes::All, 1000);
+        assert!(!grants.decide(&user_code, Some(1), 1000 + EXPIRES_IN));
+        assert_eq!(grants.poll(&late, 1000 + EXPIRES_IN), Poll::Expired);
+        assert_eq!(grants.poll("unknown", 1000), Poll::Expired);
+    }
+}
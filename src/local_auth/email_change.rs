@@ -0,0 +1,190 @@
+//! email_change.rs
+//!
+//! A new email address only replaces the old one once it is confirmed.
+//! `POST /users/me/email` keeps the address as pending and mails a link
+//! with a one-time token to it, and a notice to the current addresses so
+//! that their owner hears of a change they did not ask for. Opening the link and
+//! confirming applies the change; a new request replaces the pending one,
+//! `DELETE /users/me/email` drops it. `GET /users/me` shows it under
+//! `pending_email` until then.
+//!
+//! The token is kept hashed with the account. How long it holds is set
+//! under `email_change` in `programfiles/op/local_auth.json`:
+//!
+//! ```json
+//! { "users_key": "", "email_change": { "expires_hours": 24 } }
+//! ```
+
+use hotaru::http::*;
+use hotaru::prelude::*;
+use sha2::{Digest, Sha256};
+
+use super::LOCAL_AUTH;
+use super::fop::FopError;
+use crate::mail;
+use crate::modules;
+use crate::op::{self, APP};
+use crate::methods;
+
+static EMAIL_CHANGE: Lazy<EmailChangeSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/local_auth.json");
+    EmailChangeSettings::from_value(Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None).get("email_change"))
+});
+
+/// Length of the confirmation tokens
+pub const TOKEN_LENGTH: usize = 32;
+
+/// The `email_change` entry of `local_auth.json`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmailChangeSettings {
+    pub expires_hours: u64,
+}
+
+impl Default for EmailChangeSettings {
+    fn default() -> Self {
+        Self { expires_hours: 24 }
+    }
+}
+
+impl EmailChangeSettings {
+    pub fn from_value(value: &Value) -> Self {
+        match value.get("expires_hours") {
+            Value::Numerical(hours) if *hours >= 1.0 => Self { expires_hours: *hours as u64 },
+            _ => Self::default(),
+        }
+    }
+}
+
+/// The loaded settings
+pub fn settings() -> &'static EmailChangeSettings {
+    &EMAIL_CHANGE
+}
+
+/// An address waiting for its confirmation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingEmail {
+    pub email: String,
+    /// SHA-256 of the token mailed to `email`, in hex
+    pub token_hash: String,
+    /// Unix time the token stops holding
+    pub expires: u64,
+}
+
+impl PendingEmail {
+    /// A pending change to `email` at `now`, with the token to mail
+    pub fn new(email: &str, now: u64) -> (Self, String) {
+        let token = hotaru_lib::random::random_alphanumeric_string(TOKEN_LENGTH);
+        let pending = Self { email: email.to_string(), token_hash: hash(&token), expires: now + settings().expires_hours * 3600 };
+        (pending, token)
+    }
+
+    /// The `pending_email` entry of a stored account
+    pub fn from_json(value: &Value) -> Option<Self> {
+        match value {
+            Value::Dict(_) => Some(Self {
+                email: value.get("email").string(),
+                token_hash: value.get("token_hash").string(),
+                expires: value.get("expires").integer().max(0) as u64,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn into_json(&self) -> Value {
+        object!({ email: &self.email, token_hash: &self.token_hash, expires: self.expires })
+    }
+
+    /// What the account is shown of it
+    pub fn public_json(&self) -> Value {
+        object!({ email: &self.email, expires: self.expires })
+    }
+
+    /// Whether `token` confirms it at `now`
+    pub fn confirms(&self, token: &str, now: u64) -> bool {
+        now < self.expires && !token.is_empty() && hash(token) == self.token_hash
+    }
+}
+
+/// The stored form of `token`
+pub fn hash(token: &str) -> String {
+    Sha256::digest(token.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Mail the confirmation link to the new address and the notice to the
+/// current ones (`notice_to`)
+pub fn notify(username: &str, notice_to: &[String], pending: &PendingEmail, token: &str) {
+    let lang = op::default_lang();
+    let link = mail::settings().link(&format!("/email/confirm?token={}", token));
+    let vars = object!({ username: username, email: &pending.email, link: link, hours: settings().expires_hours });
+    mail::templates::send(&pending.email, "verification", &lang, &vars);
+    for old_email in notice_to {
+        mail::templates::send(old_email, "email_change", &lang, &object!({ username: username, email: &pending.email }));
+    }
+}
+
+endpoint! {
+    APP.url("/email/confirm"),
+
+    /// The page confirming a new email address, opened from the mailed link
+    ///
+    /// # Request
+    /// `GET /email/confirm?token=<token>` shows the page,
+    /// `POST /email/confirm`, UrlCodedForm with `token`, confirms the
+    /// change or verifies the secondary address the token was mailed to
+    ///
+    /// # Response
+    /// The confirmation page. Opening the link changes nothing, so that mail
+    /// scanners following links do not confirm in the place of the user.
+    pub confirm_email <HTTP> {
+        if !modules::enabled(modules::LOCAL_AUTH) {
+            return text_response("Not Found").status(StatusCode::NOT_FOUND);
+        }
+        methods!(req, GET | HEAD | POST);
+        let mut token = req.query("token").unwrap_or_default();
+        let mut message = String::new();
+        let mut confirmed = false;
+        if req.method() == POST {
+            token = req.form_or_default().await.get_or_default("token").clone();
+            // The same links verify the secondary addresses of `super::emails`
+            let result = match LOCAL_AUTH.confirm_email_change(&token).await {
+                Err(FopError::TokenInvalid) => LOCAL_AUTH.verify_email(&token).await.map(|email| format!("{} is verified.", email)),
+                result => result.map(|email| format!("Your email address is now {}.", email)),
+            };
+            match result {
+                Ok(done) => {
+                    confirmed = true;
+                    message = done;
+                }
+                Err(FopError::EmailConflict) => message = "This address was taken by another account in the meantime.".to_string(),
+                Err(_) => message = "This link is invalid or has expired.".to_string(),
+            }
+        }
+        akari_render!(
+            "user/confirm_email.html",
+            pageprop = op::pageprop(req, "Confirm Email", "Confirm the new email address of your account"),
+            path = op::into_path_l(req, vec!["home", "user", "email", "confirm"]),
+            token = token,
+            message = message,
+            confirmed = confirmed,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_confirm_until_they_expire() {
+        let (pending, token) = PendingEmail::new("new@example.com", 1000);
+        assert_eq!(token.len(), TOKEN_LENGTH);
+        assert_ne!(pending.token_hash, token);
+        assert!(pending.confirms(&token, 1000));
+        assert!(!pending.confirms(&token, pending.expires));
+        assert!(!pending.confirms("", 1000) && !pending.confirms("wrong", 1000));
+        assert_eq!(PendingEmail::from_json(&pending.into_json()), Some(pending.clone()));
+        assert_eq!(PendingEmail::from_json(&Value::None), None);
+        assert!(pending.public_json().try_get("token_hash").is_err());
+        assert_eq!(EmailChangeSettings::from_value(&object!({ expires_hours: 2 })).expires_hours, 2);
+    }
+}
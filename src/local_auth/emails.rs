@@ -0,0 +1,199 @@
+//! emails.rs
+//!
+//! Secondary email addresses of a local account, for password recovery
+//! and the notices about the account. An added address is mailed a link
+//! (the same `/email/confirm` page as email changes) and counts once
+//! verified; a verified one can then become the primary email, the old
+//! primary staying as a verified secondary.
+//!
+//! An address belongs to one account at most: the primary of an account
+//! or a verified secondary cannot be added by another. Users manage theirs
+//! at `/users/me/emails`, with a bearer token or from the user home page.
+
+use hotaru::http::*;
+use hotaru::prelude::*;
+
+use super::LOCAL_AUTH;
+use super::email_change::{self, hash};
+use super::fop::FopError;
+use super::reauth::{SENSITIVE_MAX_AGE, require_recent_auth};
+use super::scope::{self, require_scope_or_session};
+use crate::mail;
+use crate::op::{self, APP};
+use crate::methods;
+
+/// Secondary addresses an account may have
+pub const MAX_SECONDARY: usize = 5;
+
+/// An address of an account besides its primary email
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecondaryEmail {
+    pub email: String,
+    pub verified: bool,
+    /// Unix time it was added
+    pub added_at: u64,
+    /// SHA-256 of the token mailed to it, empty once verified
+    pub token_hash: String,
+    /// Unix time the token stops holding
+    pub expires: u64,
+}
+
+impl SecondaryEmail {
+    /// An unverified address added at `now`, with the token to mail
+    pub fn new(email: &str, now: u64) -> (Self, String) {
+        let token = hotaru_lib::random::random_alphanumeric_string(email_change::TOKEN_LENGTH);
+        let expires = now + email_change::settings().expires_hours * 3600;
+        (Self { email: email.to_string(), verified: false, added_at: now, token_hash: hash(&token), expires }, token)
+    }
+
+    /// A former primary email, verified already
+    pub fn verified(email: &str, now: u64) -> Self {
+        Self { email: email.to_string(), verified: true, added_at: now, token_hash: String::new(), expires: 0 }
+    }
+
+    pub fn from_json(value: &Value) -> Self {
+        Self {
+            email: value.get("email").string(),
+            verified: value.get("verified").boolean(),
+            added_at: value.get("added_at").integer().max(0) as u64,
+            token_hash: value.get("token_hash").string(),
+            expires: value.get("expires").integer().max(0) as u64,
+        }
+    }
+
+    pub fn into_json(&self) -> Value {
+        let mut value = object!({ email: &self.email, verified: self.verified, added_at: self.added_at });
+        if !self.verified {
+            value.set("token_hash", self.token_hash.as_str());
+            value.set("expires", self.expires);
+        }
+        value
+    }
+
+    /// What the account is shown of it
+    pub fn public_json(&self) -> Value {
+        object!({ email: &self.email, verified: self.verified, added_at: self.added_at })
+    }
+
+    /// Whether `token` verifies it at `now`
+    pub fn verifies(&self, token: &str, now: u64) -> bool {
+        !self.verified && now < self.expires && !token.is_empty() && hash(token) == self.token_hash
+    }
+}
+
+/// Read the `emails` entry of a stored account
+pub fn list_from_json(value: &Value) -> Vec<SecondaryEmail> {
+    match value {
+        Value::List(emails) => emails.iter().map(SecondaryEmail::from_json).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Mail the verification link to a newly added address
+pub fn notify(username: &str, added: &SecondaryEmail, token: &str) {
+    let link = mail::settings().link(&format!("/email/confirm?token={}", token));
+    let vars = object!({ username: username, email: &added.email, link: link, hours: email_change::settings().expires_hours });
+    mail::templates::send(&added.email, "verification", &op::default_lang(), &vars);
+}
+
+/// The addresses of `uid`, as answered by `/users/me/emails`
+async fn listing(uid: u32) -> HttpResponse {
+    match LOCAL_AUTH.admin_get_user(uid).await {
+        Some(user) => akari_json!({
+            success: true,
+            primary: &user.email,
+            emails: Value::new(user.emails.iter().map(SecondaryEmail::public_json).collect::<Vec<Value>>()),
+        }),
+        None => akari_json!({ success: false, error: "User not found" }).status(404),
+    }
+}
+
+fn error_response(err: FopError) -> HttpResponse {
+    let status = match err {
+        FopError::EmailConflict => 409,
+        FopError::UserNotFound => 404,
+        _ => 400,
+    };
+    akari_json!({ success: false, error: err.to_string() }).status(status)
+}
+
+endpoint! {
+    APP.url("/users/me/emails"),
+
+    /// GET /users/me/emails - The primary and secondary emails of the account
+    /// POST /users/me/emails - Add an address, or mail its link again; it counts once verified
+    /// DELETE /users/me/emails - Remove a secondary address
+    /// A bearer token with the `profile:read` (GET) or `profile:write` scope, or the session of a local account
+    /// Request (POST, DELETE): {"email": "backup@example.com"}
+    /// Response (1): {"success": false, "error": "Method not allowed"/"Sign in with a local account"/"Email is not valid"/"Email already exists"/"At most 5 secondary emails"/"Email not found"}
+    /// Response (2): {"success": true, "primary": "me@example.com", "emails": [{"email": "backup@example.com", "verified": false, "added_at": 1700000000}]}
+    pub user_emails <HTTP> {
+        methods!(req, GET | POST | DELETE; error);
+        let method = req.method();
+        let scope = if method == GET { scope::PROFILE_READ } else { scope::PROFILE_WRITE };
+        let uid = match require_scope_or_session(req, scope).await {
+            Ok(uid) => uid,
+            Err(response) => return response,
+        };
+        if method != GET {
+            let email = req.json_or_default().await.get("email").string();
+            let email = email.trim();
+            let result = if method == POST {
+                LOCAL_AUTH.add_email(uid, email).await
+            } else {
+                LOCAL_AUTH.remove_email(uid, email).await
+            };
+            if let Err(err) = result {
+                return error_response(err);
+            }
+        }
+        listing(uid).await
+    }
+}
+
+endpoint! {
+    APP.url("/users/me/emails/primary"),
+
+    /// POST /users/me/emails/primary - Make a verified secondary address the primary email
+    /// The same authentication as `/users/me/emails` with `profile:write`; the old primary stays as a verified secondary
+    /// Request: {"email": "backup@example.com"}
+    /// Response (1): {"success": false, "error": "Method not allowed"/"Sign in with a local account"/"Email not found"/"Email is not verified"}
+    /// Response (2): the listing of `GET /users/me/emails`
+    /// It takes a password confirmed within 15 minutes, else 403 with `"reauth": true` (see `super::reauth`)
+    pub primary_email <HTTP> {
+        methods!(req, POST; error);
+        let uid = match require_scope_or_session(req, scope::PROFILE_WRITE).await {
+            Ok(uid) => uid,
+            Err(response) => return response,
+        };
+        if let Err(response) = require_recent_auth(req, SENSITIVE_MAX_AGE).await {
+            return response;
+        }
+        let email = req.json_or_default().await.get("email").string();
+        match LOCAL_AUTH.set_primary_email(uid, email.trim()).await {
+            Ok(()) => listing(uid).await,
+            Err(err) => error_response(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secondary_emails_verify_once() {
+        let (mut added, token) = SecondaryEmail::new("backup@example.com", 1000);
+        assert!(added.verifies(&token, 1000) && !added.verifies("wrong", 1000));
+        assert!(!added.verifies(&token, added.expires));
+        let stored = added.into_json();
+        assert_eq!(SecondaryEmail::from_json(&stored), added);
+        assert!(added.public_json().try_get("token_hash").is_err());
+
+        added = SecondaryEmail::verified("old@example.com", 2000);
+        assert!(!added.verifies("", 2000));
+        assert!(added.into_json().try_get("token_hash").is_err());
+        assert_eq!(list_from_json(&Value::new(vec![added.into_json()])), vec![added]);
+        assert!(list_from_json(&Value::None).is_empty());
+    }
+}
@@ -1,10 +1,22 @@
 pub use hotaru::prelude::*; 
 use hotaru::http::*; 
 use crate::op::APP;
+use super::age;
 use super::analyze::get_auth_token; 
-use crate::admin::check_is_admin; 
+use super::scope::{self, Scopes, require_scope};
+use super::sessions::ClientInfo;
+use super::reauth::{SENSITIVE_MAX_AGE, require_recent_auth};
+use crate::admin::{check_is_admin, local_token_admin}; 
+use crate::captcha; 
+use crate::proxy;
+use crate::honeypot;
+use crate::user::logout;
+use crate::user::cache::CachePolicy;
+use crate::events;
+use crate::methods;
 
 use super::LOCAL_AUTH; 
+use super::fop::FopError;
 
 endpoint! {
     APP.url("/users"),
@@ -12,20 +24,27 @@ endpoint! {
     /// POST /users - Register a new user 
     /// Request body: Json -> {"username": "Aaa", "email": "example@example.com", "password": "Aa333333"} 
     /// Auth token of a admin should be included in the request header 
-    /// Response (1): {"success": false, "error": "Method not allowed"/"Missing information"/"Unauthorized"} 
+    /// With a minimum age in `local_auth.json`, it carries "date_of_birth": "YYYY-MM-DD" 
+    /// Response (1): {"success": false, "error": "Method not allowed"/"Missing information"/"Unauthorized"/"Date of birth required"/"You must be at least 18 years old to register"} 
     /// Response (2): {"success": true, "username": "Aaa"} 
     pub create_user <HTTP> { 
-        if req.method() != POST {
-            return akari_json!({ success: false, error: "Method not allowed" }).status(401);
-        } 
+        methods!(req, POST; error);
         if !check_is_admin(req).await {
             return akari_json!({ success: false, error: "Unauthorized" }).status(403);
         } 
-        let mut json = req.json_or_default().await; 
+        let json = req.json_or_default().await; 
         let username = json.get("username").string(); 
         let email = json.get("email").string(); 
         let password = json.get("password").string(); 
-        let result = LOCAL_AUTH.register_user(&username, &email, &password).await; 
+        let birth = json.get(age::BIRTH_FIELD).string();
+        let profile = match age::check(&birth) {
+            Ok(profile) => profile,
+            Err(err @ age::AgeError::TooYoung(_)) => {
+                return akari_json!({ success: false, error: err.to_string() }).status(403);
+            }
+            Err(err) => return akari_json!({ success: false, error: err.to_string() }).status(400),
+        };
+        let result = LOCAL_AUTH.register_user_with_profile(&username, &email, &password, profile).await; 
         match result {
             Ok(_) => akari_json!({ success: true, username: username }),
             Err(err) => akari_json!({ success: false, error: err.to_string() }),
@@ -37,10 +56,17 @@ endpoint! {
     APP.url("/users/me"),
 
     /// GET /users/me - Get current user info
-    /// Request header should include a bearer token
-    /// Response (1): {"success": false, "error": "Token invalid"/"System Error"/"Error fetching uid"}
-    /// Response (2): {"success": true, "username": username, "uid": userid, "email": email}
+    /// Request header should include a bearer token with the `profile:read` scope
+    /// Response (1): {"success": false, "error": "Token invalid"/"Insufficient scope"/"System Error"/"Error fetching uid"}
+    /// Response (2): {"success": true, "username": username, "uid": userid, "email": email,
+    ///                "pending_email": {"email": "new@example.com", "expires": 1700000000} or null,
+    ///                "lang": "zh" when the user chose one}
+    /// Response header `Cache-Control: private, max-age=..., stale-while-revalidate=...`
+    /// for the session cache of frontends, never past the time the token stops holding
     pub user_me <HTTP> {
+        if let Err(response) = require_scope(req, scope::PROFILE_READ).await {
+            return response;
+        }
         let token = get_auth_token(req);
         println!("[/users/me] Authorization header token: {:?}", token);
         if token.is_none() {
@@ -53,7 +79,9 @@ endpoint! {
             Ok(mut user) => {
                 println!("[/users/me] SUCCESS - found user: {:?}", user);
                 user += object!({ is_verified: true });
-                akari_json!({ success: true, user: user })
+                let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs();
+                let held_for = LOCAL_AUTH.token_held_until(&token).await.map_or(0, |until| until.saturating_sub(now));
+                akari_json!({ success: true, user: user }).add_header("Cache-Control", CachePolicy::for_token(held_for).header())
             },
             Err(err) => {
                 println!("[/users/me] ERROR - get_user_info failed: {}", err.to_string());
@@ -67,11 +95,14 @@ endpoint! {
     APP.url("/users/me/password"),
 
     /// POST /users/me/password - Change user's password 
-    /// Request header should include a bearer token 
+    /// Request header should include a bearer token with the `profile:write` scope 
     /// Request: {"old_password": old_password, "new_password": new_password} 
-    /// Response (1): {"success": false, "error": "Token invalid"/"System Error"/"Error fetching uid"/"Invalid old or new password"} 
+    /// Response (1): {"success": false, "error": "Token invalid"/"Insufficient scope"/"System Error"/"Error fetching uid"/"Invalid old or new password"} 
     /// Response (2): {"success": true} 
     pub change_password <HTTP> { 
+        if let Err(response) = require_scope(req, scope::PROFILE_WRITE).await {
+            return response;
+        }
         let token = get_auth_token(req); 
         if token.is_none() {
             return akari_json!({ success: false, error: "Token invalid" }).status(403);
@@ -94,6 +125,137 @@ endpoint! {
     }
 }
 
+endpoint! {
+    APP.url("/users/me/display_name"),
+
+    /// POST /users/me/display_name - Change the name shown to others, the username staying the login
+    /// Request header should include a bearer token with the `profile:write` scope
+    /// Request: {"display_name": "Alice Liddell"} (empty goes back to the username)
+    /// Response (1): {"success": false, "error": "Method not allowed"/"Token invalid"/"Insufficient scope"/"Display name must be at most 50 visible characters"}
+    /// Response (2): {"success": true, "display_name": "Alice Liddell"}
+    pub change_display_name <HTTP> {
+        methods!(req, POST; error);
+        if let Err(response) = require_scope(req, scope::PROFILE_WRITE).await {
+            return response;
+        }
+        let Some(token) = get_auth_token(req) else {
+            return akari_json!({ success: false, error: "Token invalid" }).status(401);
+        };
+        let display_name = req.json_or_default().await.get("display_name").string();
+        match LOCAL_AUTH.change_display_name(&token, &display_name).await {
+            Ok(display_name) => akari_json!({ success: true, display_name: display_name }),
+            Err(err) => akari_json!({ success: false, error: err.to_string() }).status(400),
+        }
+    }
+}
+
+endpoint! {
+    APP.url("/users/me/lang"),
+
+    /// POST /users/me/lang - Change the language of the account, followed by every frontend it signs in to
+    /// Request header should include a bearer token with the `profile:write` scope
+    /// Request: {"lang": "zh"} (empty goes back to the language of the browser)
+    /// Response (1): {"success": false, "error": "Method not allowed"/"Token invalid"/"Insufficient scope"/"Language must be a code like `en` or `zh-TW`"}
+    /// Response (2): {"success": true, "lang": "zh"}
+    pub change_lang <HTTP> {
+        methods!(req, POST; error);
+        if let Err(response) = require_scope(req, scope::PROFILE_WRITE).await {
+            return response;
+        }
+        let Some(token) = get_auth_token(req) else {
+            return akari_json!({ success: false, error: "Token invalid" }).status(401);
+        };
+        let lang = req.json_or_default().await.get("lang").string();
+        match LOCAL_AUTH.change_lang(&token, &lang).await {
+            Ok(lang) => akari_json!({ success: true, lang: lang }),
+            Err(err) => akari_json!({ success: false, error: err.to_string() }).status(400),
+        }
+    }
+}
+
+endpoint! {
+    APP.url("/users/me/email"),
+
+    /// POST /users/me/email - Ask to change the email; it changes once confirmed from the new address
+    /// DELETE /users/me/email - Drop the pending change
+    /// Request header should include a bearer token with the `profile:write` scope
+    /// Request (POST): {"email": "new@example.com"}
+    /// Response (1): {"success": false, "error": "Method not allowed"/"Token invalid"/"Insufficient scope"/"Email is not valid"/"Email already exists"}
+    /// Response (2): {"success": true, "pending_email": {"email": "new@example.com", "expires": 1700000000}} (POST)
+    /// Response (3): {"success": true, "cancelled": true} (DELETE)
+    /// Asking takes a password confirmed within 15 minutes, else 403 with `"reauth": true` (see `super::reauth`)
+    pub change_email <HTTP> {
+        if let Err(response) = require_scope(req, scope::PROFILE_WRITE).await {
+            return response;
+        }
+        let Some(token) = get_auth_token(req) else {
+            return akari_json!({ success: false, error: "Token invalid" }).status(401);
+        };
+        methods!(req, {
+            POST => {
+                if let Err(response) = require_recent_auth(req, SENSITIVE_MAX_AGE).await {
+                    return response;
+                }
+                let email = req.json_or_default().await.get("email").string();
+                match LOCAL_AUTH.change_email(&token, email.trim()).await {
+                    Ok(pending) => akari_json!({ success: true, pending_email: pending.public_json() }).status(202),
+                    Err(err @ FopError::EmailConflict) => akari_json!({ success: false, error: err.to_string() }).status(409),
+                    Err(err) => akari_json!({ success: false, error: err.to_string() }).status(400),
+                }
+            }
+            DELETE => {
+                match LOCAL_AUTH.cancel_email_change(&token).await {
+                    Ok(cancelled) => akari_json!({ success: true, cancelled: cancelled }),
+                    Err(err) => akari_json!({ success: false, error: err.to_string() }).status(400),
+                }
+            }
+        }; error)
+    }
+}
+
+/// Users a single `POST /users/lookup` may ask for
+pub const LOOKUP_LIMIT: usize = 100;
+
+endpoint! {
+    APP.url("/users/lookup"),
+
+    /// POST /users/lookup - Public info of several users in one call, for frontends showing
+    /// the authors of comments or the members of a list 
+    /// Request: {"users": [1, "alice", 7]} (uids or usernames, at most 100) 
+    /// Response (1): {"success": false, "error": "Method not allowed"/"No users requested"/"Too many users requested"} 
+    /// Response (2): {"success": true, "users": [{"uid": 1, "username": "Admin", "is_active": true}, ...], "missing": [7]} 
+    pub lookup_users <HTTP> {
+        methods!(req, POST; error);
+        let json = req.json_or_default().await;
+        let requested = match json.get("users") {
+            Value::List(keys) => keys.clone(),
+            _ => Vec::new(),
+        };
+        if requested.is_empty() {
+            return akari_json!({ success: false, error: "No users requested" }).status(400);
+        }
+        if requested.len() > LOOKUP_LIMIT {
+            return akari_json!({ success: false, error: "Too many users requested", limit: LOOKUP_LIMIT }).status(400);
+        }
+        let keys: Vec<String> = requested
+            .iter()
+            .map(|key| match key {
+                Value::Numerical(uid) => (*uid as u32).to_string(),
+                key => key.string(),
+            })
+            .collect();
+        let (users, missing) = LOCAL_AUTH.lookup_users(&keys).await;
+        // Missing keys are answered the way they were asked
+        let missing: Vec<Value> = requested
+            .into_iter()
+            .zip(&keys)
+            .filter(|(_, key)| missing.contains(key))
+            .map(|(requested, _)| requested)
+            .collect();
+        akari_json!({ success: true, users: users, missing: missing })
+    }
+}
+
 endpoint! {
     APP.url("/auth/refresh"),
 
@@ -120,28 +282,55 @@ endpoint! {
     /// POST /auth/login - Login to the server and return a token 
     /// Request (1): {"id": uid/username/email, "password": password} 
     /// Request (2): {"username": username, "password": password} (Legacy support) 
-    /// Response (1): {success: false, message: "Invalid username or password"/"Error during authing"} 
-    /// Response (2): {success: true, access_token: access, token_type: "Bearer"}
+    /// Either may add "scope": "profile:read users:admin" to get a token limited to those 
+    /// scopes; without it the token carries every scope, and "label": "Work laptop" to name 
+    /// the session. The label, `User-Agent` and client address are listed at `/users/me/sessions` 
+    /// When the CAPTCHA or the honeypot is enabled for the `login` route, the body also carries their 
+    /// fields as for `POST /user/login` (the CAPTCHA response may be sent as `captcha_response`) 
+    /// Response (1): {success: false, message: "Invalid username or password"/"Error during authing"/"Unknown scope: ..."/"Captcha required"/"Captcha verification failed"/"Submission rejected"} 
+    /// Response (2): {success: true, access_token: access, token_type: "Bearer", scope: "profile:read ..."}
+    /// Response (3): {success: false, message: "Enter the code texted to your phone", second_factor: "sms", challenge: "..."} 
+    /// for an account with a second factor; `/auth/login/second_factor` completes the login 
     pub login <HTTP> { 
-        if req.method() != POST {
-            return akari_json!({ success: false, message: "Method not allowed" }).status(405);
+        methods!(req, POST);
+        let json = req.json_or_default().await.clone();
+        // The login page of this server checked its form before calling
+        if !captcha::take_pass(req) {
+            if let Err(err) = honeypot::verify_json(captcha::LOGIN, &json) {
+                return akari_json!({ success: false, message: err.to_string() }).status(400);
+            }
+            if let Err(err) = captcha::verify(req, captcha::LOGIN, &captcha::response_from_json(&json)).await {
+                return akari_json!({ success: false, message: err.to_string() }).status(400);
+            }
         }
-        let json = req.json_or_default().await;
         let id = match json.try_get("id") { 
             Ok(value) => value.string(),
             Err(_) => json.get("username").string(),
         };
         let password = json.get("password").string(); 
+        let scopes = match json.try_get("scope") {
+            Ok(requested) => match Scopes::parse(&requested.string()) {
+                Ok(scopes) => scopes,
+                Err(err) => return akari_json!({ success: false, message: err }).status(400),
+            },
+            Err(_) => Scopes::All,
+        };
+        let granted = scopes.to_string();
         let uid = LOCAL_AUTH.uid_from_username_or_email_or_uid(id).await; 
         if let Err(err) = uid {
             return akari_json!({ success: false, message: err.to_string() }).status(400);
         } 
         let uid = uid.unwrap();
         println!("[/auth/login] Attempting login for uid: {}", uid);
-        match LOCAL_AUTH.login_user(uid, &password).await {
+        let label = json.try_get("label").ok().map(|label| label.string());
+        let client = ClientInfo::new(proxy::client_ip(req), req.header_str("user-agent"), label.as_deref());
+        match LOCAL_AUTH.login_user_from(uid, &password, scopes, client).await {
             Ok(token) => {
                 println!("[/auth/login] SUCCESS - generated token: {}", token);
-                akari_json!({ success: true, access_token: token, token_type: "Bearer" })
+                akari_json!({ success: true, access_token: token, token_type: "Bearer", scope: granted })
+            },
+            Err(FopError::SecondFactorPending { challenge, method }) => {
+                akari_json!({ success: false, message: method.prompt(), second_factor: method.as_str(), challenge: challenge })
             },
             Err(err) => {
                 println!("[/auth/login] ERROR - login failed: {}", err.to_string());
@@ -156,6 +345,8 @@ endpoint! {
 
     /// POST auth/logout - Logout and deactivate the auth token 
     /// A bearer token included in header 
+    /// With `everywhere` set in `logout.json`, every token of the user is deactivated and the 
+    /// frontends listed there are notified 
     /// Response (1): {"success": false, "error": ""Invalid authorization header"/"Error during logout"} 
     /// Response (2): { success: true, message: "Logged out" } 
     pub logout <HTTP> { 
@@ -164,6 +355,14 @@ endpoint! {
             return akari_json!({ success: false, error: "Invalid authorization header" }).status(401);
         }
         let token = token.unwrap();
+        if logout::settings().everywhere
+            && let Some(uid) = LOCAL_AUTH.uid_of_token(&token).await
+        {
+            LOCAL_AUTH.admin_revoke_sessions(uid).await;
+            logout::sessions_ended(uid);
+            events::publish(events::LoggedOut { uid, everywhere: true });
+            return akari_json!({ success: true, message: "Logged out" });
+        }
         match LOCAL_AUTH.logout_user(&token).await {
             Ok(_) => akari_json!({ success: true, message: "Logged out" }),
             Err(err) => akari_json!({ success: false, error: err.to_string() }),
@@ -171,10 +370,44 @@ endpoint! {
     }
 }  
 
+endpoint! {
+    APP.url("/auth/admin"),
+
+    /// GET /auth/admin - Check whether the bearer token belongs to an admin of this server 
+    /// Frontends call it before offering to manage this server's users, which they then do 
+    /// through the `/admin/users` API with the same token. Tokens without the `users:admin` 
+    /// scope are never reported as admin 
+    /// Response (1): {"success": false, "error": "Token invalid"} 
+    /// Response (2): {"success": true, "uid": uid, "is_admin": true/false} 
+    pub admin_check <HTTP> { 
+        let token = get_auth_token(req);
+        if token.is_none() {
+            return akari_json!({ success: false, error: "Token invalid" }).status(401);
+        }
+        match local_token_admin(&token.unwrap()).await {
+            Some((uid, is_admin)) => akari_json!({ success: true, uid: uid, is_admin: is_admin }),
+            None => akari_json!({ success: false, error: "Token invalid" }).status(401),
+        } 
+    }
+}
+
 endpoint! {
     APP.url("/health"),
 
+    /// GET /health - Liveness, with the number of requests that panicked
+    /// since the start (see `recovery`) and the upstream hosts answering
+    /// slower than `latency.json` allows or whose circuit is open
+    /// (`user::breaker`)
+    /// Response: {"status": "ok", "panics": 0, "degraded": ["https://auth.example.com"]}
     pub health_check <HTTP> {
-        akari_json!({ status: "ok" })
+        let mut degraded = crate::latency::degraded_upstreams();
+        degraded.extend(crate::user::breaker::open_hosts());
+        degraded.sort();
+        degraded.dedup();
+        akari_json!({
+            status: "ok",
+            panics: crate::recovery::panics(),
+            degraded: degraded
+        })
     }
 } 
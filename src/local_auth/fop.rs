@@ -15,7 +15,7 @@
 //! #[tokio::main]
 //! async fn main() -> Result<(), Box<dyn std::error::Error>> {
 //!     // Create a manager that flushes every 5 minutes
-//!     let manager = AuthManager::new("programfiles/local_auth/users.json", Duration::from_secs(300));
+//!     let manager = AuthManager::new("programfiles/local_auth/users.json", None, Duration::from_secs(300))?;
 //!
 //!     // Register a new user
 //!     assert!(manager.register_user("alice", "secret", "alice@example.com").await?);
@@ -44,46 +44,250 @@ use tokio::sync::RwLock;
 use std::sync::Arc;
 use tokio::time; 
 
+use super::at_rest;
+use super::backup_codes;
+use super::email_change::{self, PendingEmail};
+use super::emails::{self, SecondaryEmail};
+use super::names::{self, FormerName};
+use super::phone::{self, Phone};
+use super::rules;
+use super::second_factor::{self, Challenge, Method};
+use super::stats::{self, LoginStats, Outcome};
+use crate::events;
+use crate::geo::{self, Location};
+use std::net::IpAddr;
+use super::scope::Scopes;
+use super::security;
+use super::sessions::{self, ClientInfo, Session, SessionInfo};
+
+/// Characters a display name may have
+pub const DISPLAY_NAME_MAX: usize = 50;
+
+/// Profile key holding the language the user chose, see `crate::op::lang`
+pub const LANG_KEY: &str = "lang";
+
 const DEFAULT_ITER: NonZeroU32 = NonZeroU32::new(100_000).unwrap(); 
 
-/// A user record stored in memory.
+/// A user record stored in memory. Every stored key has a column of
+/// `sfx_users` in `migrations/`, so a new field needs a migration too.
 #[derive(Clone, Debug)]
 pub struct UserStorage { 
     pub username: String, 
+    /// The name shown to others, the username when empty
+    pub display_name: String,
     pub email: String, 
     pub password_hash: String,
     pub password_salt: String,
     pub profile: Value, 
     pub is_active: bool,
+    pub activity: Activity,
+    /// Usernames the account had before, see `super::names`
+    pub former_usernames: Vec<FormerName>,
+    /// A new email waiting for its confirmation, see `super::email_change`
+    pub pending_email: Option<PendingEmail>,
+    /// Secondary addresses, see `super::emails`
+    pub emails: Vec<SecondaryEmail>,
+    /// The phone number, see `super::phone`
+    pub phone: Option<Phone>,
+    /// The second factors turned on, see `super::second_factor`
+    pub two_factor: Vec<Method>,
+    /// Hashes of the unused backup codes, see `super::backup_codes`
+    pub backup_codes: Vec<String>,
+}
+
+/// Login history of a user, stored with the account so it survives restarts
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Activity {
+    /// Unix time of the last successful login
+    pub last_login: Option<u64>,
+    /// Client address of the last successful login, when known
+    pub last_login_ip: Option<String>,
+    /// Where `last_login_ip` is, when `crate::geo` has a database
+    pub last_login_location: Option<Location>,
+    /// Wrong passwords since the last successful login
+    pub failed_logins: u32,
+    /// Unix time of the last wrong password
+    pub last_failed_login: Option<u64>,
+    /// Country codes of successful logins, oldest first, see `super::rules`
+    pub countries: Vec<String>,
+    /// The last `MAX_RECENT_LOGINS` login attempts, newest first
+    pub recent: Vec<LoginRecord>,
+}
+
+/// How many login attempts an account remembers in [`Activity::recent`]
+pub const MAX_RECENT_LOGINS: usize = 10;
+
+/// A login attempt, as shown to the account
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoginRecord {
+    /// Unix time of the attempt
+    pub at: u64,
+    pub ip: Option<String>,
+    pub location: Option<Location>,
+    pub user_agent: Option<String>,
+    pub success: bool,
+}
+
+impl LoginRecord {
+    fn from_json(value: &Value) -> Self {
+        LoginRecord {
+            at: value.get("at").integer().max(0) as u64,
+            ip: value.try_get("ip").ok().map(|ip| ip.string()),
+            location: value.try_get("location").ok().map(Location::from_json),
+            user_agent: value.try_get("user_agent").ok().map(|user_agent| user_agent.string()),
+            success: value.get("success").boolean(),
+        }
+    }
+
+    pub fn into_json(&self) -> Value {
+        let mut value = object!({ at: self.at, success: self.success });
+        if let Some(ip) = &self.ip {
+            value.set("ip", ip.as_str());
+        }
+        if let Some(location) = &self.location {
+            value.set("location", location.into_json());
+        }
+        if let Some(user_agent) = &self.user_agent {
+            value.set("user_agent", user_agent.as_str());
+        }
+        value
+    }
+}
+
+impl Activity {
+    fn from_json(value: &Value) -> Self {
+        let time = |key: &str| value.try_get(key).ok().map(|v| v.integer()).filter(|&t| t > 0).map(|t| t as u64);
+        Activity {
+            last_login: time("last_login"),
+            last_login_ip: value.try_get("last_login_ip").ok().map(|v| v.string()),
+            last_login_location: value.try_get("last_login_location").ok().map(Location::from_json),
+            failed_logins: value.try_get("failed_logins").map(|v| v.integer().max(0) as u32).unwrap_or(0),
+            last_failed_login: time("last_failed_login"),
+            countries: match value.get("login_countries") {
+                Value::List(countries) => countries.iter().map(|country| country.string()).collect(),
+                _ => Vec::new(),
+            },
+            recent: match value.get("recent_logins") {
+                Value::List(logins) => logins.iter().map(LoginRecord::from_json).collect(),
+                _ => Vec::new(),
+            },
+        }
+    }
 }
 
 impl UserStorage {
     fn from_json(value: Value) -> Self {
         UserStorage {
             username: value.get("username").string(),
+            display_name: value.get("display_name").string(),
             email: value.get("email").string(), 
             password_hash: value.get("password_hash").string(),
             password_salt: value.get("password_salt").string(),
             profile: value.get("profile").clone(),
             is_active: value.try_get("is_active").map(|v| v.boolean()).unwrap_or(true),
+            activity: Activity::from_json(&value),
+            former_usernames: names::history_from_json(value.get("former_usernames")),
+            pending_email: PendingEmail::from_json(value.get("pending_email")),
+            emails: emails::list_from_json(value.get("emails")),
+            phone: Phone::from_json(value.get("phone")),
+            two_factor: second_factor::list_from_json(value.get("two_factor")),
+            backup_codes: match value.get("backup_codes") {
+                Value::List(hashes) => hashes.iter().map(|hash| hash.string()).collect(),
+                _ => Vec::new(),
+            },
         }
     }
 
     fn into_json(&self) -> Value {
-        object!({
+        let mut value = object!({
             username: &self.username, 
             email: &self.email, 
             password_hash: &self.password_hash,
             password_salt: &self.password_salt,
             profile: self.profile.clone(),
             is_active: self.is_active,
-        })
+            failed_logins: self.activity.failed_logins,
+        });
+        if !self.display_name.is_empty() {
+            value.set("display_name", self.display_name.as_str());
+        }
+        if !self.former_usernames.is_empty() {
+            value.set("former_usernames", Value::new(self.former_usernames.iter().map(FormerName::into_json).collect::<Vec<Value>>()));
+        }
+        if let Some(pending) = &self.pending_email {
+            value.set("pending_email", pending.into_json());
+        }
+        if !self.emails.is_empty() {
+            value.set("emails", Value::new(self.emails.iter().map(SecondaryEmail::into_json).collect::<Vec<Value>>()));
+        }
+        if let Some(phone) = &self.phone {
+            value.set("phone", phone.into_json());
+        }
+        if !self.two_factor.is_empty() {
+            value.set("two_factor", second_factor::list_into_json(&self.two_factor));
+        }
+        if !self.backup_codes.is_empty() {
+            value.set("backup_codes", Value::new(self.backup_codes.clone()));
+        }
+        if let Some(time) = self.activity.last_login {
+            value.set("last_login", time);
+        }
+        if let Some(ip) = &self.activity.last_login_ip {
+            value.set("last_login_ip", ip.as_str());
+        }
+        if let Some(location) = &self.activity.last_login_location {
+            value.set("last_login_location", location.into_json());
+        }
+        if let Some(time) = self.activity.last_failed_login {
+            value.set("last_failed_login", time);
+        }
+        if !self.activity.countries.is_empty() {
+            value.set("login_countries", Value::new(self.activity.countries.clone()));
+        }
+        if !self.activity.recent.is_empty() {
+            value.set("recent_logins", Value::new(self.activity.recent.iter().map(LoginRecord::into_json).collect::<Vec<Value>>()));
+        }
+        value
     } 
 
+    /// The name shown to others: the display name, or the username
+    pub fn display_name(&self) -> &str {
+        if self.display_name.is_empty() { &self.username } else { &self.display_name }
+    }
+
+    /// The addresses mail about the account goes to: the primary email,
+    /// then the verified secondary ones
+    pub fn recovery_emails(&self) -> Vec<String> {
+        std::iter::once(self.email.clone())
+            .chain(self.emails.iter().filter(|secondary| secondary.verified).map(|secondary| secondary.email.clone()))
+            .filter(|email| !email.is_empty())
+            .collect()
+    }
+
+    /// The second factors the account can use now, turned on or not
+    pub fn second_factors(&self) -> Vec<Method> {
+        Method::ALL
+            .into_iter()
+            .filter(|method| match method {
+                Method::Sms => self.phone.as_ref().is_some_and(|phone| phone.verified),
+            })
+            .collect()
+    }
+
+    /// Turn the second factor `method` off, and the backup codes with the
+    /// last one
+    fn turn_off(&mut self, method: Method) {
+        self.two_factor.retain(|turned_on| *turned_on != method);
+        if self.two_factor.is_empty() {
+            self.backup_codes.clear();
+        }
+    }
+
     fn into_json_without_password(&self, uid: u32) -> Value {
         object!({
             uid: uid,
             username: &self.username, 
+            display_name: self.display_name(),
             email: &self.email, 
             profile: self.profile.clone(),
             is_active: self.is_active,
@@ -91,7 +295,11 @@ impl UserStorage {
     } 
 } 
 
-pub struct TokenList(RwLock<HashMap<String, (u32, u64)>>); // token -> (uid, expires) 
+pub struct TokenList(RwLock<HashMap<String, Session>>); // token -> session 
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+}
 
 impl TokenList { 
     pub fn new() -> Self {
@@ -100,7 +308,45 @@ impl TokenList {
 
     /// Add a token to the list with user id and expiration time 
     pub async fn add(&self, token: String, uid: u32, expires: u64) {
-        self.0.write().await.insert(token, (uid, expires));
+        self.add_scoped(token, uid, expires, Scopes::All).await;
+    }
+
+    /// Add a token that may only be used for `scopes`, starting a login.
+    /// Past `max_per_user` of `super::sessions`, the oldest logins of
+    /// `uid` are signed out.
+    pub async fn add_scoped(&self, token: String, uid: u32, expires: u64, scopes: Scopes) {
+        self.add_for(token, uid, expires, scopes, ClientInfo::default()).await;
+    }
+
+    /// Add a token issued to `client`, see [`TokenList::add_scoped`]
+    pub async fn add_for(&self, token: String, uid: u32, expires: u64, scopes: Scopes, client: ClientInfo) {
+        let now = unix_now();
+        let login = security::session_id(&token);
+        self.insert(token, Session::new(uid, expires, scopes, login, now, now).with_client(client)).await;
+    }
+
+    /// Add `new_token` as a refresh of `old_token`, with its user, scopes
+    /// and login; `None` when `old_token` does not hold
+    pub async fn refresh(&self, old_token: &str, new_token: String, expires: u64) -> Option<(u32, Scopes)> {
+        let now = unix_now();
+        let session = {
+            let guard = self.0.read().await;
+            let old = guard.get(old_token).filter(|session| sessions::settings().holds(session, now))?;
+            old.refreshed(expires, now)
+        };
+        let refreshed = (session.uid, session.scopes.clone());
+        self.insert(new_token, session).await;
+        Some(refreshed)
+    }
+
+    async fn insert(&self, token: String, session: Session) {
+        let mut guard = self.0.write().await;
+        if !guard.values().any(|other| other.login == session.login) {
+            for evicted in sessions::settings().evicted(&guard, session.uid, session.last_used()) {
+                guard.remove(&evicted);
+            }
+        }
+        guard.insert(token, session);
     }
 
     /// Remove a token from the list 
@@ -110,26 +356,85 @@ impl TokenList {
 
     /// Get the user's id by using the token 
     pub async fn authenticate_user(&self, token: &str) -> Option<u32> {
+        self.scopes(token).await.map(|(uid, _)| uid)
+    } 
+
+    /// The user id and scopes of a token that still holds, marking it used
+    pub async fn scopes(&self, token: &str) -> Option<(u32, Scopes)> {
+        let now = unix_now();
         let guard = self.0.read().await;
-        if let Some(&(uid, expires)) = guard.get(token) {
-            if expires > std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() {
-                return Some(uid);
-            }
+        let session = guard.get(token).filter(|session| sessions::settings().holds(session, now))?;
+        session.touch(now);
+        Some((session.uid, session.scopes.clone()))
+    }
+
+    /// Unix time a token that still holds stops holding if left unused,
+    /// see `SessionSettings::held_until`
+    pub async fn held_until(&self, token: &str) -> Option<u64> {
+        let now = unix_now();
+        let guard = self.0.read().await;
+        guard.get(token).filter(|session| sessions::settings().holds(session, now)).map(|session| sessions::settings().held_until(session, now))
+    }
+
+    /// Unix time the user of a token that still holds last proved who they
+    /// are, see `super::reauth`
+    pub async fn authenticated_at(&self, token: &str) -> Option<u64> {
+        let now = unix_now();
+        let guard = self.0.read().await;
+        guard.get(token).filter(|session| sessions::settings().holds(session, now)).map(Session::authenticated)
+    }
+
+    /// Note the user of `token` proved who they are again at `now`
+    pub async fn reauthenticate(&self, token: &str, now: u64) {
+        if let Some(session) = self.0.read().await.get(token) {
+            session.reauthenticate(now);
         }
-        None
-    } 
+    }
 
-    /// Search through all tokens and cleans up those are expired 
+    /// Search through all tokens and cleans up those no longer holding 
     pub async fn cleanup_expired(&self) {
-        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        let now = unix_now();
         let mut guard = self.0.write().await;
-        guard.retain(|_, &mut (_, expires)| expires > now);
+        guard.retain(|_, session| sessions::settings().holds(session, now));
     } 
+
+    /// The tokens of `uid` that still hold, soonest to expire first
+    pub async fn sessions_of(&self, uid: u32) -> Vec<SessionInfo> {
+        let now = unix_now();
+        let guard = self.0.read().await;
+        let mut tokens: Vec<SessionInfo> = guard
+            .iter()
+            .filter(|(_, session)| session.uid == uid && sessions::settings().holds(session, now))
+            .map(|(token, session)| SessionInfo { expires: sessions::settings().expires(session), ..session.info(token) })
+            .collect();
+        tokens.sort_by_key(|session| session.expires);
+        tokens
+    }
+
+    /// The tokens of `uid` that still hold with their expiration times,
+    /// soonest to expire first
+    pub async fn of_user(&self, uid: u32) -> Vec<(String, u64)> {
+        self.sessions_of(uid).await.into_iter().map(|session| (session.token, session.expires)).collect()
+    }
+
+    /// The number of tokens that still hold
+    pub async fn count(&self) -> usize {
+        let now = unix_now();
+        self.0.read().await.values().filter(|session| sessions::settings().holds(session, now)).count()
+    }
+
+    /// Remove every token of `uid`, returning how many there were
+    pub async fn remove_user(&self, uid: u32) -> usize {
+        let mut guard = self.0.write().await;
+        let before = guard.len();
+        guard.retain(|_, session| session.uid != uid);
+        before - guard.len()
+    }
 } 
 
 #[cfg(test)]
 mod tests {
-    use super::TokenList;
+    use super::{Scopes, TokenList};
     use std::{
         collections::HashMap, 
         time::{SystemTime, UNIX_EPOCH},
@@ -207,6 +512,39 @@ mod tests {
         list.remove(&token).await;
         assert_eq!(list.authenticate_user(&token).await, None);
     }
+
+    #[tokio::test]
+    async fn test_sessions_of_user() {
+        let list = TokenList(RwLock::new(HashMap::new()));
+        list.add("late".to_string(), 5, now_secs() + 200).await;
+        list.add("soon".to_string(), 5, now_secs() + 100).await;
+        list.add("gone".to_string(), 5, now_secs() - 1).await;
+        list.add("other".to_string(), 6, now_secs() + 100).await;
+
+        // Unexpired tokens of the user only, soonest first
+        let tokens: Vec<String> = list.of_user(5).await.into_iter().map(|(token, _)| token).collect();
+        assert_eq!(tokens, vec!["soon".to_string(), "late".to_string()]);
+
+        // Revoking counts every token of the user and spares the others
+        assert_eq!(list.remove_user(5).await, 3);
+        assert!(list.of_user(5).await.is_empty());
+        assert_eq!(list.authenticate_user("other").await, Some(6));
+    }
+
+    #[tokio::test]
+    async fn test_scoped_token() {
+        let list = TokenList(RwLock::new(HashMap::new()));
+        let read_only = Scopes::parse("profile:read").unwrap();
+        list.add_scoped("narrow".to_string(), 8, now_secs() + 100, read_only.clone()).await;
+        list.add("full".to_string(), 8, now_secs() + 100).await;
+        list.add_scoped("old".to_string(), 8, now_secs() - 1, read_only.clone()).await;
+
+        assert_eq!(list.scopes("narrow").await, Some((8, read_only)));
+        assert_eq!(list.scopes("full").await, Some((8, Scopes::All)));
+        assert_eq!(list.scopes("old").await, None);
+        // Scoped tokens still authenticate their user
+        assert_eq!(list.authenticate_user("narrow").await, Some(8));
+    }
 } 
 
 /// The authentication manager.
@@ -220,13 +558,59 @@ pub struct AuthManager {
     email_map: Arc<RwLock<HashMap<String, u32>>>, 
     token_list: Arc<TokenList>, 
     path: String,
+    /// Key of the encrypted user store, see `super::at_rest`
+    key: Option<String>,
+    /// Login attempts of the last day, see `super::stats`
+    login_stats: Arc<RwLock<LoginStats>>,
+    /// Logins waiting for their second factor, by challenge
+    challenges: Arc<RwLock<HashMap<String, Challenge>>>,
     max_uid: Arc<RwLock<u32>> 
 } 
 
 impl AuthManager { 
     /// Create a new `AuthManager` that reads `users_file` on startup and
-    /// spawns a background task to flush every `interval`.
-    pub fn new(users_file: impl Into<String>, interval: Duration) -> Self {
+    /// spawns a background task to flush every `interval`. With a `key` the
+    /// file is encrypted, see `super::at_rest`.
+    ///
+    /// The task holds the lock of [`lock_users_file`] for the life of the
+    /// process, so `sfx user` refuses to edit the file behind its back.
+    pub fn new(users_file: impl Into<String>, key: Option<String>, interval: Duration) -> Result<Self, FopError> {
+        let manager = Self::open(users_file, key)?;
+        let lock = match lock_users_file(&manager.path) {
+            Ok(lock) => Some(lock),
+            Err(err) => {
+                eprintln!("Failed to lock {}: {}", &manager.path, err);
+                None
+            }
+        };
+
+        let users_clone = Arc::clone(&manager.users);
+        let token_clone = Arc::clone(&manager.token_list);
+        let path_clone = manager.path.clone();
+        let key_clone = manager.key.clone();
+
+        // Spawn periodic flush
+        let _flush_task = tokio::spawn(async move {
+            let _lock = lock;
+            let mut ticker = time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let list = users_into_json(&*users_clone.read().await);
+                if let Err(err) = at_rest::write(&path_clone, &list, key_clone.as_deref()) {
+                    eprintln!("{}", err);
+                } 
+                token_clone.cleanup_expired().await; // Clean up expired tokens periodically 
+            }
+        });
+
+        Ok(manager)
+    }
+
+    /// Load `users_file` without the background flush. Changes stay in
+    /// memory until [`AuthManager::flush`] is called; used by offline tools.
+    /// Fails when the file exists but cannot be read or decrypted, rather
+    /// than starting from an empty store.
+    pub fn open(users_file: impl Into<String>, key: Option<String>) -> Result<Self, FopError> {
         let path = users_file.into(); 
         let mut user_map: HashMap<u32, UserStorage> = HashMap::new(); 
         let mut username_map: HashMap<String, u32> = HashMap::new(); 
@@ -234,7 +618,8 @@ impl AuthManager {
         let mut max_uid = 0_u32; 
 
         // Load users once
-        if let Ok(Value::Dict(initial)) = Value::from_jsonf(&path) { 
+        let initial = at_rest::read(&path, key.as_deref()).map_err(|err| FopError::Other(err.into()))?;
+        if let Value::Dict(initial) = initial { 
             initial.into_iter().for_each(|(uid, value)| { 
                 if let Ok(uid) = uid.parse::<u32>(){ 
                     let user_storage: UserStorage = UserStorage::from_json(value); 
@@ -248,29 +633,42 @@ impl AuthManager {
             });
         }
 
-        let users = Arc::new(RwLock::new(user_map));
-        let username_map = Arc::new(RwLock::new(username_map)); 
-        let email_map = Arc::new(RwLock::new(email_map));
-        let token_list = Arc::new(TokenList::new());
-        let users_clone = Arc::clone(&users); 
-        let token_clone = Arc::clone(&token_list); 
-        let path_clone = path.clone(); 
+        Ok(AuthManager {
+            users: Arc::new(RwLock::new(user_map)),
+            username_map: Arc::new(RwLock::new(username_map)),
+            email_map: Arc::new(RwLock::new(email_map)),
+            token_list: Arc::new(TokenList::new()),
+            path,
+            key,
+            login_stats: Arc::new(RwLock::new(LoginStats::default())),
+            challenges: Arc::new(RwLock::new(HashMap::new())),
+            max_uid: Arc::new(RwLock::new(max_uid)),
+        })
+    }
 
-        // Spawn periodic flush
-        let _flush_task = tokio::spawn(async move {
-            let mut ticker = time::interval(interval);
-            loop {
-                ticker.tick().await;
-                let guard = users_clone.read().await;
-                let list = Value::Dict(guard.iter().map(|(uid, value)| (uid.to_string(), value.into_json())).collect());
-                if let Err(err) = list.into_jsonf(&path_clone) {
-                    eprintln!("Failed to flush users to {}: {}", &path_clone, err);
-                } 
-                token_clone.cleanup_expired().await; // Clean up expired tokens periodically 
-            }
-        });
+    /// An empty store kept only in memory, for tests; [`AuthManager::flush`]
+    /// writes nothing
+    pub fn in_memory() -> Self {
+        AuthManager {
+            users: Arc::new(RwLock::new(HashMap::new())),
+            username_map: Arc::new(RwLock::new(HashMap::new())),
+            email_map: Arc::new(RwLock::new(HashMap::new())),
+            token_list: Arc::new(TokenList::new()),
+            path: String::new(),
+            key: None,
+            login_stats: Arc::new(RwLock::new(LoginStats::default())),
+            challenges: Arc::new(RwLock::new(HashMap::new())),
+            max_uid: Arc::new(RwLock::new(0)),
+        }
+    }
 
-        AuthManager { users, username_map, email_map, token_list, path, max_uid: Arc::new(RwLock::new(max_uid)) }
+    /// Write the users to disk now
+    pub async fn flush(&self) -> Result<(), FopError> {
+        if self.path.is_empty() {
+            return Ok(());
+        }
+        at_rest::write(&self.path, &users_into_json(&*self.users.read().await), self.key.as_deref())
+            .map_err(|err| FopError::Other(err.into()))
     }
 
     /// Use the uid to auth the user 
@@ -307,26 +705,330 @@ impl AuthManager {
         }
     } 
 
+    /// The uid of an active user the unexpired `token` was issued to
+    pub async fn uid_of_token(&self, token: &str) -> Option<u32> {
+        let uid = self.token_list.authenticate_user(token).await?;
+        self.users.read().await.get(&uid).filter(|user| user.is_active).map(|_| uid)
+    }
+
+    /// The uid and scopes of the unexpired `token`
+    pub async fn token_scopes(&self, token: &str) -> Option<(u32, Scopes)> {
+        self.token_list.scopes(token).await
+    }
+
+    /// Generate a token for an active user without checking a password, for
+    /// grants the user approved while already signed in
+    pub async fn issue_token(&self, uid: u32, scopes: Scopes) -> Result<String, FopError> {
+        self.issue_token_for(uid, scopes, ClientInfo::default()).await
+    }
+
+    /// [`AuthManager::issue_token`] to `client`
+    pub async fn issue_token_for(&self, uid: u32, scopes: Scopes, client: ClientInfo) -> Result<String, FopError> {
+        match self.users.read().await.get(&uid) {
+            Some(user) if user.is_active => {}
+            Some(_) => return Err(FopError::UserInactive),
+            None => return Err(FopError::UserNotFound),
+        }
+        let token = random_alphanumeric_string(32);
+        let expires = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() + 3600; // 1 hour
+        self.token_list.add_for(token.clone(), uid, expires, scopes, client).await;
+        Ok(token)
+    }
+
     /// Login the user while generating a token for the user
     pub async fn login_user(&self, uid: u32, password: &str) -> Result<String, FopError> {
+        self.login_user_scoped(uid, password, Scopes::All).await
+    }
+
+    /// Login the user with a token limited to `scopes`
+    pub async fn login_user_scoped(&self, uid: u32, password: &str, scopes: Scopes) -> Result<String, FopError> {
+        self.login_user_from(uid, password, scopes, ClientInfo::default()).await
+    }
+
+    /// Login the user from `client`, whose address is kept in the login
+    /// history with its location and user agent; the token it gets keeps
+    /// all of `client`. The rules of `super::rules` may
+    /// refuse the login even with the right password, and an account with a
+    /// second factor gets `FopError::SecondFactorPending` instead of a token,
+    /// see [`AuthManager::complete_second_factor`].
+    pub async fn login_user_from(
+        &self,
+        uid: u32,
+        password: &str,
+        scopes: Scopes,
+        client: ClientInfo,
+    ) -> Result<String, FopError> {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        let from = client.ip;
+        let result = self.try_login(uid, password, scopes, client, now).await;
+        if let Err(FopError::SecondFactorPending { .. }) = result {
+            // Counted once the second factor is given
+            return result;
+        }
+        let outcome = match &result {
+            Ok(_) => Outcome::Succeeded,
+            Err(FopError::LoginBlocked | FopError::SecondFactorRequired) => Outcome::Blocked,
+            Err(_) => Outcome::Failed,
+        };
+        self.login_stats.write().await.record(outcome, now);
+        match &result {
+            Ok(_) => events::publish(events::LoginSucceeded { uid, from }),
+            Err(err) => events::publish(events::LoginFailed { uid, from, reason: err.to_string() }),
+        }
+        result
+    }
+
+    async fn try_login(
+        &self,
+        uid: u32,
+        password: &str,
+        scopes: Scopes,
+        client: ClientInfo,
+        now: u64,
+    ) -> Result<String, FopError> {
+        let ruleset = rules::current();
+        let from = client.ip;
+        let hits = ruleset.before_password(uid, from, now);
+        let before = rules::report(&ruleset, hits, uid, &client, None, now);
+        if before == Some(rules::Action::Block) {
+            return Err(FopError::LoginBlocked);
+        }
+
         println!("[AuthManager::login_user] Checking password for uid: {}", uid);
-        if self.check_password(uid, password).await {
-            let token = random_alphanumeric_string(32);
-            let expires = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() + 3600; // 1 hour
-            println!("[AuthManager::login_user] Generated token: {}, expires: {}", token, expires);
-            self.token_list.add(token.clone(), uid, expires).await;
-            println!("[AuthManager::login_user] Token added to token_list");
-            Ok(token)
-        } else {
+        if !self.check_password(uid, password).await {
             println!("[AuthManager::login_user] Password mismatch");
-            Err(FopError::PasswordMismatch)
+            self.record_login(uid, false, &client, None).await;
+            return Err(FopError::PasswordMismatch);
+        }
+
+        let location = from.and_then(geo::lookup);
+        let previous = self.users.read().await.get(&uid).map(|user| user.activity.clone()).unwrap_or_default();
+        let hits = ruleset.after_password(&previous, client.user_agent.as_deref(), location.as_ref(), now);
+        let after = rules::report(&ruleset, hits, uid, &client, location.as_ref(), now);
+        let asked = match before.max(after) {
+            Some(rules::Action::Block) => return Err(FopError::LoginBlocked),
+            Some(rules::Action::RequireSecondFactor) => true,
+            Some(rules::Action::Notify) | None => false,
+        };
+
+        // A method turned on, or any the account has when a rule asks for one
+        let method = self.users.read().await.get(&uid).and_then(|user| {
+            let available = user.second_factors();
+            let enabled = user.two_factor.iter().find(|method| available.contains(method)).copied();
+            enabled.or(if asked { available.first().copied() } else { None })
+        });
+        match method {
+            Some(method) => return Err(self.challenge(Challenge::new(uid, method, scopes, client, location, now), now).await),
+            None if asked => return Err(FopError::SecondFactorRequired),
+            None => {}
         }
+
+        self.record_login(uid, true, &client, location).await;
+        let token = random_alphanumeric_string(32);
+        let expires = now + 3600; // 1 hour
+        println!("[AuthManager::login_user] Generated token: {}, expires: {}", token, expires);
+        self.token_list.add_for(token.clone(), uid, expires, scopes, client).await;
+        println!("[AuthManager::login_user] Token added to token_list");
+        Ok(token)
     } 
 
+    /// Login attempts per hour over the last day, oldest first
+    pub async fn login_stats(&self) -> Vec<stats::Hour> {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        self.login_stats.read().await.last_day(now)
+    }
+
+    /// The number of unexpired tokens of every user
+    pub async fn admin_total_sessions(&self) -> usize {
+        self.token_list.count().await
+    }
+
+    /// Start a challenge made by `Challenge::new` at `now`, sending its
+    /// code; the error answering the login
+    async fn challenge(&self, (id, challenge, code): (String, Challenge, String), now: u64) -> FopError {
+        let (uid, method) = (challenge.uid, challenge.method);
+        let sent = match method {
+            Method::Sms => {
+                let number = self.users.read().await.get(&uid).and_then(|user| user.phone.as_ref().map(|phone| phone.number.clone()));
+                match number {
+                    Some(number) => crate::sms::send(&number, &second_factor::message(&code)).await.is_ok(),
+                    None => false,
+                }
+            }
+        };
+        if !sent {
+            return FopError::SmsNotSent;
+        }
+        let mut challenges = self.challenges.write().await;
+        challenges.retain(|_, challenge| challenge.expires > now);
+        challenges.insert(id.clone(), challenge);
+        FopError::SecondFactorPending { challenge: id, method }
+    }
+
+    /// Complete the login waiting on `challenge` with the `code` sent for
+    /// it, or a backup code of the account; the token and its scopes. A
+    /// challenge takes `second_factor::MAX_ATTEMPTS` wrong codes.
+    pub async fn complete_second_factor(&self, challenge: &str, code: &str) -> Result<(String, Scopes), FopError> {
+        let now = names::now();
+        let mut challenges = self.challenges.write().await;
+        let Some(pending) = challenges.get_mut(challenge).filter(|pending| pending.confirms.is_none()) else {
+            return Err(FopError::CodeInvalid);
+        };
+        let accepted = pending.accepts(code, now)
+            || (pending.usable(now)
+                && self.users.write().await.get_mut(&pending.uid).is_some_and(|user| backup_codes::consume(&mut user.backup_codes, code)));
+        if !accepted {
+            pending.attempts += 1;
+            let (uid, from) = (pending.uid, pending.client.ip);
+            if pending.attempts >= second_factor::MAX_ATTEMPTS || pending.expires <= now {
+                challenges.remove(challenge);
+            }
+            drop(challenges);
+            self.login_stats.write().await.record(Outcome::Failed, now);
+            events::publish(events::LoginFailed { uid, from, reason: FopError::CodeInvalid.to_string() });
+            return Err(FopError::CodeInvalid);
+        }
+        let Some(pending) = challenges.remove(challenge) else {
+            return Err(FopError::CodeInvalid);
+        };
+        drop(challenges);
+        let from = pending.client.ip;
+        let token = self.issue_token_for(pending.uid, pending.scopes.clone(), pending.client.clone()).await?;
+        self.record_login(pending.uid, true, &pending.client, pending.location).await;
+        self.login_stats.write().await.record(Outcome::Succeeded, now);
+        events::publish(events::LoginSucceeded { uid: pending.uid, from });
+        Ok((token, pending.scopes))
+    }
+
+    /// Unix time the user of `token` last gave their password or second
+    /// factor: the login it comes from, or its latest re-authentication
+    pub async fn authenticated_at(&self, token: &str) -> Option<u64> {
+        self.token_list.authenticated_at(token).await
+    }
+
+    /// Unix time `token` stops holding if left unused, `None` when it does
+    /// not hold now
+    pub async fn token_held_until(&self, token: &str) -> Option<u64> {
+        self.token_list.held_until(token).await
+    }
+
+    /// Confirm the user of `token` with their password, for `super::reauth`.
+    /// It is held to the same rules as a login, and a wrong password counts
+    /// as a failed login.
+    pub async fn reauthenticate(&self, token: &str, password: &str, from: Option<IpAddr>) -> Result<(), FopError> {
+        self.reauthenticate_under(&rules::current(), token, password, from, names::now()).await
+    }
+
+    async fn reauthenticate_under(
+        &self,
+        ruleset: &rules::RuleSet,
+        token: &str,
+        password: &str,
+        from: Option<IpAddr>,
+        now: u64,
+    ) -> Result<(), FopError> {
+        let uid = self.token_list.authenticate_user(token).await.ok_or(FopError::TokenInvalid)?;
+        let client = ClientInfo::from_ip(from);
+        let hits = ruleset.before_password(uid, from, now);
+        let result = if rules::report(ruleset, hits, uid, &client, None, now) == Some(rules::Action::Block) {
+            Err(FopError::LoginBlocked)
+        } else if !self.check_password(uid, password).await {
+            self.record_login(uid, false, &client, None).await;
+            Err(FopError::PasswordMismatch)
+        } else {
+            self.token_list.reauthenticate(token, now).await;
+            return Ok(());
+        };
+        let outcome = if result == Err(FopError::LoginBlocked) { Outcome::Blocked } else { Outcome::Failed };
+        self.login_stats.write().await.record(outcome, now);
+        if let Err(err) = &result {
+            events::publish(events::LoginFailed { uid, from, reason: err.to_string() });
+        }
+        result
+    }
+
+    /// Send the user of `token` a code of their second factor to confirm
+    /// with instead of the password; the challenge to answer with it
+    pub async fn reauth_challenge(&self, token: &str, from: Option<IpAddr>) -> Result<(String, Method), FopError> {
+        let (uid, scopes) = self.token_list.scopes(token).await.ok_or(FopError::TokenInvalid)?;
+        let method = self
+            .users
+            .read()
+            .await
+            .get(&uid)
+            .and_then(|user| user.two_factor.iter().find(|method| user.second_factors().contains(method)).copied())
+            .ok_or(FopError::SecondFactorNotEnabled)?;
+        let now = names::now();
+        let (id, mut challenge, code) = Challenge::new(uid, method, scopes, ClientInfo::from_ip(from), None, now);
+        challenge.confirms = Some(token.to_string());
+        match self.challenge((id, challenge, code), now).await {
+            FopError::SecondFactorPending { challenge, method } => Ok((challenge, method)),
+            err => Err(err),
+        }
+    }
+
+    /// Confirm the user of `token` with the code sent for `challenge` by
+    /// [`AuthManager::reauth_challenge`]
+    pub async fn reauthenticate_with_code(&self, token: &str, challenge: &str, code: &str) -> Result<(), FopError> {
+        let now = names::now();
+        let mut challenges = self.challenges.write().await;
+        let Some(pending) = challenges.get_mut(challenge).filter(|pending| pending.confirms.as_deref() == Some(token)) else {
+            return Err(FopError::CodeInvalid);
+        };
+        if !pending.accepts(code, now) {
+            pending.attempts += 1;
+            if !pending.usable(now) {
+                challenges.remove(challenge);
+            }
+            return Err(FopError::CodeInvalid);
+        }
+        challenges.remove(challenge);
+        drop(challenges);
+        self.token_list.reauthenticate(token, now).await;
+        Ok(())
+    }
+
+    /// Update the login history of `uid` after a login attempt
+    async fn record_login(&self, uid: u32, success: bool, client: &ClientInfo, location: Option<Location>) {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        let from = client.ip;
+        if let Some(user) = self.users.write().await.get_mut(&uid) {
+            let record = LoginRecord {
+                at: now,
+                ip: from.map(|ip| ip.to_string()),
+                location: location.clone(),
+                user_agent: client.user_agent.clone(),
+                success,
+            };
+            user.activity.recent.insert(0, record);
+            user.activity.recent.truncate(MAX_RECENT_LOGINS);
+            if success {
+                let activity = &mut user.activity;
+                activity.last_login = Some(now);
+                activity.last_login_ip = from.map(|ip| ip.to_string());
+                if let Some(country) = location.as_ref().map(|location| &location.country_code)
+                    && !country.is_empty()
+                    && !activity.countries.contains(country)
+                {
+                    if activity.countries.len() >= rules::MAX_COUNTRIES {
+                        activity.countries.remove(0);
+                    }
+                    activity.countries.push(country.clone());
+                }
+                activity.last_login_location = location;
+                activity.failed_logins = 0;
+            } else {
+                user.activity.failed_logins += 1;
+                user.activity.last_failed_login = Some(now);
+            }
+        }
+    }
+
     /// Logout the user by removing the token 
     pub async fn logout_user(&self, token: &str) -> Result<(), FopError> {
-        if self.token_list.authenticate_user(token).await.is_some() {
+        if let Some(uid) = self.token_list.authenticate_user(token).await {
             self.token_list.remove(token).await;
+            events::publish(events::LoggedOut { uid, everywhere: false });
             Ok(())
         } else {
             Err(FopError::TokenInvalid)
@@ -339,10 +1041,25 @@ impl AuthManager {
         guard.get(email).cloned() 
     } 
 
+    /// The account `email` is the primary or a verified secondary address
+    /// of, to recover it
+    pub async fn get_uid_by_recovery_email(&self, email: &str) -> Option<u32> {
+        Self::email_owner(&*self.users.read().await, &*self.email_map.read().await, email)
+    }
+
+    fn email_owner(users: &HashMap<u32, UserStorage>, email_map: &HashMap<String, u32>, email: &str) -> Option<u32> {
+        email_map.get(email).copied().or_else(|| {
+            users
+                .iter()
+                .find(|(_, user)| user.emails.iter().any(|secondary| secondary.verified && secondary.email == email))
+                .map(|(uid, _)| *uid)
+        })
+    }
+
     /// Refresh a new token by using a old token
-    /// The old token should be valid
+    /// The old token should be valid; the new one carries the same scopes
     pub async fn refresh_token(&self, old_token: &str) -> Result<String, FopError> {
-        if let Some(uid) = self.token_list.authenticate_user(old_token).await {
+        if let Some((uid, _)) = self.token_list.scopes(old_token).await {
             let users = self.users.read().await;
             match users.get(&uid) {
                 Some(user) if user.is_active => {}
@@ -352,7 +1069,7 @@ impl AuthManager {
             drop(users);
             let new_token = random_alphanumeric_string(32);
             let expires = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() + 3600; // 1 hour
-            self.token_list.add(new_token.clone(), uid, expires).await;
+            self.token_list.refresh(old_token, new_token.clone(), expires).await.ok_or(FopError::TokenInvalid)?;
             Ok(new_token)
         } else {
             Err(FopError::TokenInvalid)
@@ -389,9 +1106,40 @@ impl AuthManager {
         }
         let usernames = self.username_map.read().await;
         println!("Checking against existing usernames: {:?}", usernames);
-        !usernames.contains_key(username)
+        !usernames.contains_key(username) && self.username_reserved_by(username).await.is_none()
     } 
 
+    /// The account that left `username` less than the cooldown of
+    /// `super::names` ago
+    pub async fn username_reserved_by(&self, username: &str) -> Option<u32> {
+        Self::reserved_by(&*self.users.read().await, username, names::now())
+    }
+
+    fn reserved_by(users: &HashMap<u32, UserStorage>, username: &str, now: u64) -> Option<u32> {
+        let policy = names::policy();
+        users
+            .iter()
+            .find(|(_, user)| user.former_usernames.iter().any(|former| former.username == username && policy.reserves(former, now)))
+            .map(|(uid, _)| *uid)
+    }
+
+    /// The account `username` leads to: the one holding it, else the last
+    /// one that left it. The flag tells whether it is the current name.
+    pub async fn resolve_username(&self, username: &str) -> Option<(u32, bool)> {
+        if let Some(uid) = self.get_uid_by_username(username).await {
+            return Some((uid, true));
+        }
+        let users = self.users.read().await;
+        users
+            .iter()
+            .filter_map(|(uid, user)| {
+                let former = user.former_usernames.iter().find(|former| former.username == username)?;
+                Some((former.changed_at, *uid))
+            })
+            .max()
+            .map(|(_, uid)| (uid, false))
+    }
+
     fn validate_username_format(username: &str) -> bool {
         println!("Validating username: {}/", username);
         // Rule #1: non-empty and first char is ASCII letter
@@ -428,9 +1176,8 @@ impl AuthManager {
         if !Self::validate_email_format(email) {
             return false;
         }
-        // Rule #4: must not already exist
-        let emails = self.email_map.read().await;
-        !emails.contains_key(email)
+        // Rule #4: must not already exist, as a primary or verified secondary email
+        Self::email_owner(&*self.users.read().await, &*self.email_map.read().await, email).is_none()
     } 
 
     fn validate_email_format(email: &str) -> bool {
@@ -479,49 +1226,275 @@ impl AuthManager {
             Some(uid) => uid,
             None => return Err(FopError::TokenInvalid),
         }; 
-        if !self.validate_username(new_username).await {
+        if !Self::validate_username_format(new_username) {
             return Err(FopError::UserNameNotValid);
         }
         let mut username_map = self.username_map.write().await;
-        if let Some(old_username) = username_map.iter().find(|(_, v)| v == &&uid).map(|(k, _)| k.clone()) {
-            username_map.remove(&old_username);
-            username_map.insert(new_username.to_string(), uid); 
-        } else {
-            return Err(FopError::UserNotFound)
-        } 
         let mut users = self.users.write().await; 
-        if let Some(user) = users.get_mut(&uid) {
-            user.username = new_username.to_string();
-            Ok(())
-        } else {
-            Err(FopError::UserNotFound)
-        } 
+        if username_map.get(new_username).is_some_and(|owner| *owner != uid) {
+            return Err(FopError::UserNameConflict);
+        }
+        if Self::reserved_by(&users, new_username, names::now()).is_some_and(|owner| owner != uid) {
+            return Err(FopError::UserNameReserved);
+        }
+        let user = users.get_mut(&uid).ok_or(FopError::UserNotFound)?;
+        Self::rename(&mut username_map, user, uid, new_username);
+        Ok(())
     } 
 
-    /// Change the email 
-    pub async fn change_email(&self, token: &str, new_email: &str) -> Result<(), FopError> {
-        let uid = match self.token_list.authenticate_user(token).await {
-            Some(uid) => uid,
-            None => return Err(FopError::TokenInvalid),
-        }; 
-        if !self.validate_email(new_email).await {
+    /// Give `user` the name `new_username`, keeping the old one in its
+    /// history
+    fn rename(username_map: &mut HashMap<String, u32>, user: &mut UserStorage, uid: u32, new_username: &str) {
+        if user.username == new_username {
+            return;
+        }
+        username_map.remove(&user.username);
+        username_map.insert(new_username.to_string(), uid);
+        names::record(&mut user.former_usernames, &user.username, new_username, names::now());
+        user.username = new_username.to_string();
+    }
+
+    /// Ask to change the email of the holder of `token` to `new_email`.
+    /// Nothing changes yet: the address is kept as pending, replacing any
+    /// earlier one, and mailed a link confirming it, the old address a
+    /// notice (see `super::email_change`).
+    pub async fn change_email(&self, token: &str, new_email: &str) -> Result<PendingEmail, FopError> {
+        let uid = self.token_list.authenticate_user(token).await.ok_or(FopError::TokenInvalid)?;
+        if !Self::validate_email_format(new_email) {
             return Err(FopError::EmailNotValid);
         }
+        let email_map = self.email_map.read().await;
+        let mut users = self.users.write().await;
+        // Its own secondary addresses become primary with `set_primary_email`
+        if Self::email_owner(&users, &email_map, new_email).is_some() {
+            return Err(FopError::EmailConflict);
+        }
+        let user = users.get_mut(&uid).ok_or(FopError::UserNotFound)?;
+        let (pending, confirm_token) = PendingEmail::new(new_email, names::now());
+        email_change::notify(&user.username, &user.recovery_emails(), &pending, &confirm_token);
+        user.pending_email = Some(pending.clone());
+        Ok(pending)
+    }
+
+    /// Apply the pending email confirmed by `confirm_token`; the new email
+    pub async fn confirm_email_change(&self, confirm_token: &str) -> Result<String, FopError> {
+        let now = names::now();
         let mut email_map = self.email_map.write().await;
-        if let Some(old_email) = email_map.iter().find(|(_, v)| v == &&uid).map(|(k, _)| k.clone()) {
-            email_map.remove(&old_email);
-            email_map.insert(new_email.to_string(), uid);
-        } else {
-            return Err(FopError::UserNotFound);
+        let mut users = self.users.write().await;
+        let (uid, user) = users
+            .iter_mut()
+            .find(|(_, user)| user.pending_email.as_ref().is_some_and(|pending| pending.confirms(confirm_token, now)))
+            .ok_or(FopError::TokenInvalid)?;
+        let uid = *uid;
+        let email = user.pending_email.take().map(|pending| pending.email).unwrap_or_default();
+        // Taken by someone else since it was asked for
+        if Self::email_owner(&users, &email_map, &email).is_some_and(|owner| owner != uid) {
+            return Err(FopError::EmailConflict);
         }
+        let user = users.get_mut(&uid).ok_or(FopError::UserNotFound)?;
+        user.emails.retain(|secondary| secondary.email != email);
+        email_map.remove(&user.email);
+        email_map.insert(email.clone(), uid);
+        user.email = email.clone();
+        Ok(email)
+    }
+
+    /// Drop the pending email of the holder of `token`; whether there was one
+    pub async fn cancel_email_change(&self, token: &str) -> Result<bool, FopError> {
+        let uid = self.token_list.authenticate_user(token).await.ok_or(FopError::TokenInvalid)?;
         let mut users = self.users.write().await;
-        if let Some(user) = users.get_mut(&uid) {
-            user.email = new_email.to_string();
-            Ok(())
-        } else {
-            Err(FopError::UserNotFound)
+        let user = users.get_mut(&uid).ok_or(FopError::UserNotFound)?;
+        Ok(user.pending_email.take().is_some())
+    }
+
+    /// Add `email` as a secondary address of `uid`, mailing it its
+    /// verification link; an unverified one gets a new link
+    pub async fn add_email(&self, uid: u32, email: &str) -> Result<(), FopError> {
+        if !Self::validate_email_format(email) {
+            return Err(FopError::EmailNotValid);
         }
-    } 
+        let email_map = self.email_map.read().await;
+        let mut users = self.users.write().await;
+        if Self::email_owner(&users, &email_map, email).is_some_and(|owner| owner != uid) {
+            return Err(FopError::EmailConflict);
+        }
+        let user = users.get_mut(&uid).ok_or(FopError::UserNotFound)?;
+        if user.email == email || user.emails.iter().any(|secondary| secondary.verified && secondary.email == email) {
+            return Ok(());
+        }
+        user.emails.retain(|secondary| secondary.email != email);
+        if user.emails.len() >= emails::MAX_SECONDARY {
+            return Err(FopError::TooManyEmails);
+        }
+        let (added, token) = SecondaryEmail::new(email, names::now());
+        emails::notify(&user.username, &added, &token);
+        user.emails.push(added);
+        Ok(())
+    }
+
+    /// Remove the secondary address `email` of `uid`
+    pub async fn remove_email(&self, uid: u32, email: &str) -> Result<(), FopError> {
+        let mut users = self.users.write().await;
+        let user = users.get_mut(&uid).ok_or(FopError::UserNotFound)?;
+        let before = user.emails.len();
+        user.emails.retain(|secondary| secondary.email != email);
+        if user.emails.len() == before {
+            return Err(FopError::EmailNotFound);
+        }
+        Ok(())
+    }
+
+    /// Verify the secondary address `token` was mailed to; the address
+    pub async fn verify_email(&self, token: &str) -> Result<String, FopError> {
+        let now = names::now();
+        let email_map = self.email_map.read().await;
+        let mut users = self.users.write().await;
+        let (uid, email) = users
+            .iter()
+            .find_map(|(uid, user)| user.emails.iter().find(|secondary| secondary.verifies(token, now)).map(|secondary| (*uid, secondary.email.clone())))
+            .ok_or(FopError::TokenInvalid)?;
+        if Self::email_owner(&users, &email_map, &email).is_some_and(|owner| owner != uid) {
+            return Err(FopError::EmailConflict);
+        }
+        let user = users.get_mut(&uid).ok_or(FopError::UserNotFound)?;
+        if let Some(secondary) = user.emails.iter_mut().find(|secondary| secondary.email == email) {
+            *secondary = SecondaryEmail { added_at: secondary.added_at, ..SecondaryEmail::verified(&email, now) };
+        }
+        Ok(email)
+    }
+
+    /// Make the verified secondary address `email` the primary email of
+    /// `uid`, the old primary becoming a verified secondary
+    pub async fn set_primary_email(&self, uid: u32, email: &str) -> Result<(), FopError> {
+        let mut email_map = self.email_map.write().await;
+        let mut users = self.users.write().await;
+        let user = users.get_mut(&uid).ok_or(FopError::UserNotFound)?;
+        let at = user.emails.iter().position(|secondary| secondary.email == email).ok_or(FopError::EmailNotFound)?;
+        if !user.emails[at].verified {
+            return Err(FopError::EmailNotVerified);
+        }
+        if email_map.get(email).is_some_and(|owner| *owner != uid) {
+            return Err(FopError::EmailConflict);
+        }
+        let old = std::mem::replace(&mut user.email, email.to_string());
+        user.emails[at] = SecondaryEmail::verified(&old, names::now());
+        email_map.remove(&old);
+        email_map.insert(email.to_string(), uid);
+        Ok(())
+    }
+
+    /// Set the phone number of `uid` and text it a verification code; the
+    /// same number again, while unverified, gets a new code. A new number
+    /// turns the `sms` second factor off.
+    pub async fn set_phone(&self, uid: u32, number: &str) -> Result<Phone, FopError> {
+        let number = phone::normalize(number).ok_or(FopError::PhoneNotValid)?;
+        let (added, code) = Phone::new(&number, names::now());
+        {
+            let mut users = self.users.write().await;
+            let user = users.get_mut(&uid).ok_or(FopError::UserNotFound)?;
+            if let Some(current) = &user.phone
+                && current.verified
+                && current.number == number
+            {
+                return Ok(current.clone());
+            }
+            user.phone = Some(added.clone());
+            user.turn_off(Method::Sms);
+        }
+        crate::sms::send(&number, &phone::message(&code)).await.map_err(|_| FopError::SmsNotSent)?;
+        Ok(added)
+    }
+
+    /// Verify the phone number of `uid` with the code texted to it
+    pub async fn verify_phone(&self, uid: u32, code: &str) -> Result<(), FopError> {
+        let mut users = self.users.write().await;
+        let user = users.get_mut(&uid).ok_or(FopError::UserNotFound)?;
+        let verified = user.phone.as_mut().is_some_and(|phone| phone.verify(code, names::now()));
+        if verified { Ok(()) } else { Err(FopError::CodeInvalid) }
+    }
+
+    /// Remove the phone number of `uid`, and the `sms` second factor with it;
+    /// whether it had one
+    pub async fn remove_phone(&self, uid: u32) -> Result<bool, FopError> {
+        let mut users = self.users.write().await;
+        let user = users.get_mut(&uid).ok_or(FopError::UserNotFound)?;
+        user.turn_off(Method::Sms);
+        Ok(user.phone.take().is_some())
+    }
+
+    /// Turn the second factor `method` of `uid` on or off. Turning the first
+    /// one on gives the account backup codes, returned this once.
+    pub async fn set_two_factor(&self, uid: u32, method: Method, enabled: bool) -> Result<Option<Vec<String>>, FopError> {
+        let mut users = self.users.write().await;
+        let user = users.get_mut(&uid).ok_or(FopError::UserNotFound)?;
+        if !enabled {
+            user.turn_off(method);
+            return Ok(None);
+        }
+        if !user.second_factors().contains(&method) {
+            return Err(match method {
+                Method::Sms => FopError::PhoneNotVerified,
+            });
+        }
+        if !user.two_factor.contains(&method) {
+            user.two_factor.push(method);
+        }
+        if !user.backup_codes.is_empty() {
+            return Ok(None);
+        }
+        let (codes, hashes) = backup_codes::generate();
+        user.backup_codes = hashes;
+        Ok(Some(codes))
+    }
+
+    /// Give `uid` a new set of backup codes, voiding the old ones
+    pub async fn regenerate_backup_codes(&self, uid: u32) -> Result<Vec<String>, FopError> {
+        let mut users = self.users.write().await;
+        let user = users.get_mut(&uid).ok_or(FopError::UserNotFound)?;
+        if user.two_factor.is_empty() {
+            return Err(FopError::SecondFactorNotEnabled);
+        }
+        let (codes, hashes) = backup_codes::generate();
+        user.backup_codes = hashes;
+        Ok(codes)
+    }
+
+    /// Check a display name, which unlike a username may hold spaces and
+    /// any script: at most `DISPLAY_NAME_MAX` characters once trimmed, no
+    /// control characters nor the invisible ones used to fake another name
+    /// (zero-width, direction overrides). Empty goes back to the username.
+    pub fn validate_display_name(name: &str) -> Result<String, FopError> {
+        let name = name.trim();
+        let invisible = |c: char| c.is_control() || matches!(c, '\u{200B}'..='\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}' | '\u{FEFF}');
+        if name.chars().count() > DISPLAY_NAME_MAX || name.chars().any(invisible) {
+            return Err(FopError::DisplayNameNotValid);
+        }
+        Ok(name.to_string())
+    }
+
+    /// Change the display name of the holder of `token`; the name kept
+    pub async fn change_display_name(&self, token: &str, display_name: &str) -> Result<String, FopError> {
+        let uid = self.token_list.authenticate_user(token).await.ok_or(FopError::TokenInvalid)?;
+        let display_name = Self::validate_display_name(display_name)?;
+        let mut users = self.users.write().await;
+        let user = users.get_mut(&uid).ok_or(FopError::UserNotFound)?;
+        user.display_name = display_name;
+        Ok(user.display_name().to_string())
+    }
+
+    /// Change the language of the holder of `token`, a code like `en` or
+    /// `zh-TW`, empty to follow the browser again; the language kept
+    pub async fn change_lang(&self, token: &str, lang: &str) -> Result<String, FopError> {
+        let uid = self.token_list.authenticate_user(token).await.ok_or(FopError::TokenInvalid)?;
+        let lang = lang.trim();
+        let valid = lang.len() <= 16 && lang.split('-').all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric()));
+        if !lang.is_empty() && !valid {
+            return Err(FopError::LangNotValid);
+        }
+        let value = if lang.is_empty() { Value::None } else { Value::from(lang) };
+        self.set_profile_entry(uid, LANG_KEY, value).await?;
+        Ok(lang.to_string())
+    }
 
     /// Change the password for a user 
     pub async fn change_password(&self, token: &str, old_password: &str, new_password: &str) -> Result<(), FopError> {
@@ -535,6 +1508,7 @@ impl AuthManager {
         let mut users = self.users.write().await;
         if let Some(user) = users.get_mut(&uid) {
             user.password_hash = aes::encrypt(new_password, &user.password_salt).unwrap(); // Use the existing salt 
+            events::publish(events::PasswordChanged { uid });
             Ok(())
         } else {
             Err(FopError::UserNotFound)
@@ -543,6 +1517,20 @@ impl AuthManager {
 
     /// Register a new user 
     pub async fn register_user(&self, username: &str, email: &str, password: &str) -> Result<(), FopError> { 
+        self.register_user_with_profile(username, email, password, object!({})).await
+    }
+
+    /// Register a new user starting with `profile`
+    pub async fn register_user_with_profile(
+        &self,
+        username: &str,
+        email: &str,
+        password: &str,
+        profile: Value,
+    ) -> Result<(), FopError> {
+        if self.username_reserved_by(username).await.is_some() {
+            return Err(FopError::UserNameReserved);
+        }
         if !self.validate_username(username).await { 
             return Err(FopError::UserNameNotValid)
         }; 
@@ -555,13 +1543,22 @@ impl AuthManager {
         let salt = random_alphanumeric_string(16); // Generate a random salt 
         let user = UserStorage { 
             username: username.to_string(), 
+            display_name: String::new(),
             email: email.to_string(), 
             password_hash: aes::encrypt(password, &salt).unwrap(), // Use a random salt
             password_salt: salt, 
-            profile: object!({}),
+            profile,
             is_active: true,
+            activity: Activity::default(),
+            former_usernames: Vec::new(),
+            pending_email: None,
+            emails: Vec::new(),
+            phone: None,
+            two_factor: Vec::new(),
+            backup_codes: Vec::new(),
         }; 
         self.users.write().await.insert(new_uid, user); 
+        events::publish(events::UserRegistered { uid: new_uid, username: username.to_string() });
         Ok(()) 
     } 
 
@@ -638,12 +1635,22 @@ impl AuthManager {
                         return Err(FopError::UserInactive);
                     }
                     println!("[AuthManager::get_user_info] Found user: {}", user.username);
-                    Ok(object!({
+                    let mut info = object!({
                         username: &user.username,
+                        display_name: user.display_name(),
                         email: &user.email,
                         uid: auth_uid,
                         is_active: user.is_active,
-                    }))
+                    });
+                    if let Value::Str(avatar) = user.profile.get(super::public::AVATAR_KEY) {
+                        info.set("avatar", avatar.as_str());
+                    }
+                    if let Value::Str(lang) = user.profile.get(LANG_KEY) {
+                        info.set("lang", lang.as_str());
+                    }
+                    let pending = user.pending_email.as_ref().filter(|pending| pending.expires > names::now());
+                    info.set("pending_email", pending.map(PendingEmail::public_json).unwrap_or(Value::None));
+                    Ok(info)
                 } else {
                     println!("[AuthManager::get_user_info] User not found for uid: {}", auth_uid);
                     Err(FopError::UserTooBig)
@@ -656,6 +1663,32 @@ impl AuthManager {
         }
     } 
 
+    /// The public part of the accounts named by `keys`, uids or usernames:
+    /// what anonymous clients see at `GET /users/<uid>`, minus the profile
+    /// entries (see `super::public`), in the order asked and once each. The
+    /// keys naming no account come second.
+    pub async fn lookup_users(&self, keys: &[String]) -> (Vec<Value>, Vec<String>) {
+        let users = self.users.read().await;
+        let username_map = self.username_map.read().await;
+        let mut found = Vec::new();
+        let mut seen = std::collections::HashSet::new();
+        let mut missing = Vec::new();
+        for key in keys {
+            let uid = key.parse::<u32>().ok().or_else(|| username_map.get(key).copied());
+            match uid.and_then(|uid| users.get(&uid).map(|user| (uid, user))) {
+                Some((uid, user)) => {
+                    if seen.insert(uid) {
+                        let mut info = super::public::public_user(uid, user, super::public::Viewer::Anonymous);
+                        info.delete("profile");
+                        found.push(info);
+                    }
+                }
+                None => missing.push(key.clone()),
+            }
+        }
+        (found, missing)
+    }
+
     pub async fn list_users(&self) -> Vec<Value> {
         self.admin_list_users()
             .await
@@ -676,6 +1709,17 @@ impl AuthManager {
         self.users.read().await.get(&uid).cloned()
     }
 
+    /// Set the profile entry `key` of `uid`, leaving the others alone
+    pub async fn set_profile_entry(&self, uid: u32, key: &str, value: Value) -> Result<(), FopError> {
+        let mut users = self.users.write().await;
+        let user = users.get_mut(&uid).ok_or(FopError::UserNotFound)?;
+        if !matches!(user.profile, Value::Dict(_)) {
+            user.profile = Value::new_dict();
+        }
+        user.profile.set(key, value);
+        Ok(())
+    }
+
     pub async fn admin_edit_user(
         &self,
         uid: u32,
@@ -698,33 +1742,36 @@ impl AuthManager {
         let mut username_map = self.username_map.write().await;
         let mut email_map = self.email_map.write().await;
         let mut users = self.users.write().await;
-        let user = users.get_mut(&uid).ok_or(FopError::UserNotFound)?;
+        if !users.contains_key(&uid) {
+            return Err(FopError::UserNotFound);
+        }
 
         if let Some(username) = &new_username {
             if username_map.get(username).is_some_and(|owner| *owner != uid) {
                 return Err(FopError::UserNameConflict);
             }
+            if Self::reserved_by(&users, username, names::now()).is_some_and(|owner| owner != uid) {
+                return Err(FopError::UserNameReserved);
+            }
         }
         if let Some(email) = &new_email {
-            if email_map.get(email).is_some_and(|owner| *owner != uid) {
+            if Self::email_owner(&users, &email_map, email).is_some_and(|owner| owner != uid) {
                 return Err(FopError::EmailConflict);
             }
         }
+        let user = users.get_mut(&uid).ok_or(FopError::UserNotFound)?;
 
         if let Some(username) = &new_username {
-            username_map.remove(&user.username);
-            username_map.insert(username.clone(), uid);
+            Self::rename(&mut username_map, user, uid, username);
         }
         if let Some(email) = &new_email {
             email_map.remove(&user.email);
             email_map.insert(email.clone(), uid);
         }
 
-        if let Some(username) = new_username {
-            user.username = username;
-        }
         if let Some(email) = new_email {
             user.email = email;
+            user.pending_email = None;
         }
         if let Some(is_active) = new_is_active {
             user.is_active = is_active;
@@ -742,6 +1789,58 @@ impl AuthManager {
         Ok(())
     }
 
+    /// The sessions of `uid`, each with only the first characters of its
+    /// token, enough to tell them apart.
+    pub async fn admin_list_sessions(&self, uid: u32) -> Vec<SessionInfo> {
+        self.token_list
+            .sessions_of(uid)
+            .await
+            .into_iter()
+            .map(|session| SessionInfo { token: session.token.chars().take(6).collect(), ..session })
+            .collect()
+    }
+
+    /// Number of unexpired sessions of `uid`
+    pub async fn admin_session_count(&self, uid: u32) -> usize {
+        self.token_list.of_user(uid).await.len()
+    }
+
+    /// The unexpired tokens of `uid` with their expiry, scopes and client,
+    /// soonest to expire first
+    pub async fn sessions_of(&self, uid: u32) -> Vec<SessionInfo> {
+        self.token_list.sessions_of(uid).await
+    }
+
+    /// Revoke the token of `uid` whose `super::security::session_id` is
+    /// `id`; whether there was one
+    pub async fn revoke_session(&self, uid: u32, id: &str) -> bool {
+        let token = self.token_list.of_user(uid).await.into_iter().map(|(token, _)| token).find(|token| security::session_id(token) == id);
+        match token {
+            Some(token) => {
+                self.token_list.remove(&token).await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Revoke every token of `uid` but `keep`; how many there were
+    pub async fn revoke_other_sessions(&self, uid: u32, keep: &str) -> usize {
+        let mut revoked = 0;
+        for (token, _) in self.token_list.of_user(uid).await {
+            if token != keep {
+                self.token_list.remove(&token).await;
+                revoked += 1;
+            }
+        }
+        revoked
+    }
+
+    /// Log `uid` out everywhere. Returns the number of sessions ended.
+    pub async fn admin_revoke_sessions(&self, uid: u32) -> usize {
+        self.token_list.remove_user(uid).await
+    }
+
     pub async fn admin_delete_user(&self, uid: u32) -> Result<(), FopError> {
         let mut username_map = self.username_map.write().await;
         let mut email_map = self.email_map.write().await;
@@ -753,18 +1852,65 @@ impl AuthManager {
     }
 }
 
+fn users_into_json(users: &HashMap<u32, UserStorage>) -> Value {
+    Value::Dict(users.iter().map(|(uid, value)| (uid.to_string(), value.into_json())).collect())
+}
+
+/// Take the advisory lock guarding `users_file` against a second writer,
+/// held on `<users_file>.lock`. Fails with `WouldBlock` while another
+/// process (a running server or `sfx user`) holds it; the lock is released
+/// when the returned file is dropped.
+pub fn lock_users_file(users_file: &str) -> std::io::Result<std::fs::File> {
+    let lock_path = format!("{}.lock", users_file);
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)?;
+    file.try_lock().map_err(|err| match err {
+        std::fs::TryLockError::WouldBlock => std::io::Error::new(
+            std::io::ErrorKind::WouldBlock,
+            format!("{} is held by another process", lock_path),
+        ),
+        std::fs::TryLockError::Error(err) => err,
+    })?;
+    Ok(file)
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum FopError { 
     TooManyRequest, 
     UserNameNotValid, 
     UserNameConflict,
+    /// Left by another account less than the cooldown of `super::names` ago
+    UserNameReserved,
+    DisplayNameNotValid,
+    LangNotValid,
     EmailNotValid, 
     EmailConflict,
+    /// An account has at most `super::emails::MAX_SECONDARY` secondary emails
+    TooManyEmails,
+    EmailNotFound,
+    EmailNotVerified,
     PasswordMismatch, 
     UserTooBig, 
     UserNotFound, 
     UserInactive,
     TokenInvalid, 
+    /// Refused by a rule of `super::rules`
+    LoginBlocked,
+    /// A rule of `super::rules` asks for a second factor the account has not
+    SecondFactorRequired,
+    /// The password was right; the login waits on `challenge`, see
+    /// `super::second_factor`
+    SecondFactorPending { challenge: String, method: Method },
+    PhoneNotValid,
+    PhoneNotVerified,
+    /// A texted code that is wrong, expired or used up
+    CodeInvalid,
+    SmsNotSent,
+    /// Backup codes need a second factor turned on
+    SecondFactorNotEnabled,
     Other(Box<str>) 
 } 
 
@@ -774,13 +1920,27 @@ impl ToString for FopError {
             FopError::TooManyRequest => "Too many requests".to_string(),
             FopError::UserNameNotValid => "Username is not valid".to_string(),
             FopError::UserNameConflict => "Username already exists".to_string(),
+            FopError::UserNameReserved => "Username was recently used by another account".to_string(),
+            FopError::DisplayNameNotValid => format!("Display name must be at most {} visible characters", DISPLAY_NAME_MAX),
+            FopError::LangNotValid => "Language must be a code like `en` or `zh-TW`".to_string(),
             FopError::EmailNotValid => "Email is not valid".to_string(),
             FopError::EmailConflict => "Email already exists".to_string(),
+            FopError::TooManyEmails => format!("At most {} secondary emails", emails::MAX_SECONDARY),
+            FopError::EmailNotFound => "Email not found".to_string(),
+            FopError::EmailNotVerified => "Email is not verified".to_string(),
             FopError::PasswordMismatch => "Password mismatch".to_string(),
             FopError::UserTooBig => "User data too big".to_string(),
             FopError::UserNotFound => "User not found".to_string(), 
             FopError::UserInactive => "User is inactive".to_string(),
             FopError::TokenInvalid => "Token is invalid".to_string(),
+            FopError::LoginBlocked => "Login blocked as suspicious".to_string(),
+            FopError::SecondFactorRequired => "A second factor is required to log in from here".to_string(),
+            FopError::SecondFactorPending { method, .. } => method.prompt().to_string(),
+            FopError::PhoneNotValid => "Phone number is not valid".to_string(),
+            FopError::PhoneNotVerified => "Phone number is not verified".to_string(),
+            FopError::CodeInvalid => "Code is invalid or has expired".to_string(),
+            FopError::SmsNotSent => "The text message could not be sent".to_string(),
+            FopError::SecondFactorNotEnabled => "Turn a second factor on first".to_string(),
             FopError::Other(msg) => msg.to_string(),
         }
     }
@@ -797,6 +1957,8 @@ mod test {
     use crate::local_auth::fop::AuthManager; 
     use crate::local_auth::fop::TokenList;
     use crate::local_auth::fop::UserStorage; 
+    use crate::local_auth::fop::Activity;
+    use crate::local_auth::stats::LoginStats;
 
     #[test] 
     pub fn test_user_from_json() { 
@@ -816,15 +1978,68 @@ mod test {
     pub fn test_user_into_json() { 
         let user = UserStorage { 
             username: "Admin".to_string(), 
+            display_name: String::new(),
             email: "redstone@fds.moe".to_string(), 
             password_hash: "123456".to_string(), 
             password_salt: "Aa333333".to_string(), 
             profile: object!({}),
             is_active: true,
+            activity: Activity::default(),
+            former_usernames: Vec::new(),
+            pending_email: None,
+            emails: Vec::new(),
+            phone: None,
+            two_factor: Vec::new(),
+            backup_codes: Vec::new(),
         }; 
         let value = user.into_json(); 
         println!("{}, {}", value.to_string(), value.into_json()) 
     }
+
+    #[test]
+    fn a_full_user_round_trips_and_fits_the_schema() {
+        let location = r#"{"country_code": "DE", "country": "Germany", "city": "Berlin", "latitude": 52.5, "longitude": 13.4}"#;
+        let stored = Value::from_json(&format!(
+            r#"{{
+                "username": "alice", "display_name": "Alice", "email": "alice@example.com",
+                "password_hash": "hash", "password_salt": "salt", "profile": {{"lang": "en"}}, "is_active": false,
+                "former_usernames": [{{"username": "ally", "changed_at": 100}}],
+                "pending_email": {{"email": "new@example.com", "token_hash": "t", "expires": 300}},
+                "emails": [{{"email": "second@example.com", "verified": false, "added_at": 200, "token_hash": "u", "expires": 400}}],
+                "phone": {{"number": "+4915112345678", "verified": false, "code_hash": "c", "expires": 500, "attempts": 1}},
+                "two_factor": ["sms"], "backup_codes": ["h1", "h2"],
+                "last_login": 1000, "last_login_ip": "203.0.113.7", "last_login_location": {location},
+                "failed_logins": 2, "last_failed_login": 1100, "login_countries": ["DE"],
+                "recent_logins": [{{"at": 1000, "success": true, "ip": "203.0.113.7", "location": {location}, "user_agent": "curl"}}]
+            }}"#
+        ))
+        .unwrap();
+        let user = UserStorage::from_json(stored.clone());
+        assert_eq!(user.into_json(), stored);
+
+        // Every stored key has its column in sfx_users
+        let mut columns = vec!["uid".to_string()];
+        for migration in crate::database::migrations() {
+            let mut creating = false;
+            for line in migration.up.lines().map(str::trim) {
+                let word = |text: &str| text.split_whitespace().next().unwrap_or_default().to_string();
+                if let Some(column) = line.strip_prefix("ALTER TABLE sfx_users ADD COLUMN ") {
+                    columns.push(word(column));
+                } else if line.starts_with("CREATE TABLE sfx_users") {
+                    creating = true;
+                } else if line.starts_with(')') {
+                    creating = false;
+                } else if creating {
+                    columns.push(word(line));
+                }
+            }
+        }
+        if let Value::Dict(keys) = &stored {
+            for key in keys.keys() {
+                assert!(columns.contains(key), "sfx_users has no column for `{}`", key);
+            }
+        }
+    }
  
     #[tokio::test] 
     pub async fn test_auth_user() { 
@@ -858,6 +2073,9 @@ mod test {
             email_map: Arc::new(RwLock::new(email_map)), 
             token_list: Arc::new(TokenList::new()),
             path: "test.json".to_string(),
+            key: None,
+            login_stats: Arc::new(RwLock::new(LoginStats::default())),
+            challenges: Arc::new(RwLock::new(HashMap::new())),
             max_uid: Arc::new(RwLock::new(2_u32))
         };
 
@@ -880,7 +2098,9 @@ mod password_verification_tests {
     use hotaru::prelude::*;
     use hotaru_lib::ende::aes;
 
-    use crate::local_auth::fop::{AuthManager, FopError, TokenList, UserStorage};
+    use crate::local_auth::fop::{Activity, AuthManager, FopError, TokenList, UserStorage};
+    use crate::local_auth::scope::Scopes;
+    use crate::local_auth::stats::LoginStats;
 
     /// Build a one-user in-memory AuthManager. The user is uid=1.
     async fn manager_with_one_user(
@@ -895,11 +2115,19 @@ mod password_verification_tests {
             1_u32,
             UserStorage {
                 username: username.to_string(),
+                display_name: String::new(),
                 email: format!("{}@test.example", username),
                 password_hash: hash,
                 password_salt: salt,
                 profile: object!({}),
                 is_active,
+                activity: Activity::default(),
+                former_usernames: Vec::new(),
+                pending_email: None,
+                emails: Vec::new(),
+                phone: None,
+                two_factor: Vec::new(),
+                backup_codes: Vec::new(),
             },
         );
         let mut username_map = HashMap::new();
@@ -912,6 +2140,9 @@ mod password_verification_tests {
             email_map: Arc::new(RwLock::new(email_map)),
             token_list: Arc::new(TokenList::new()),
             path: "test.json".to_string(),
+            key: None,
+            login_stats: Arc::new(RwLock::new(LoginStats::default())),
+            challenges: Arc::new(RwLock::new(HashMap::new())),
             max_uid: Arc::new(RwLock::new(1_u32)),
         }
     }
@@ -990,6 +2221,285 @@ mod password_verification_tests {
             .expect("post-reset login should succeed");
         assert!(!token.is_empty());
     }
+
+    /// Step 9 — login attempts are counted in the user's activity, which
+    /// survives a round trip through the users file.
+    #[tokio::test]
+    async fn step9_login_attempts_update_activity() {
+        let auth = manager_with_one_user("Alice", "secret123", true).await;
+        auth.login_user(1, "wrong").await.unwrap_err();
+        auth.login_user(1, "wrong").await.unwrap_err();
+        let activity = auth.admin_get_user(1).await.unwrap().activity;
+        assert_eq!((activity.failed_logins, activity.last_login), (2, None));
+        assert!(activity.last_failed_login.is_some());
+
+        auth.login_user(1, "secret123").await.unwrap();
+        let user = auth.admin_get_user(1).await.unwrap();
+        assert_eq!(user.activity.failed_logins, 0);
+        assert!(user.activity.last_login.is_some());
+        assert_eq!(auth.admin_session_count(1).await, 1);
+        assert_eq!(UserStorage::from_json(user.into_json()).activity, user.activity);
+
+        let last_hour = *auth.login_stats().await.last().unwrap();
+        assert_eq!((last_hour.succeeded, last_hour.failed, last_hour.blocked), (1, 2, 0));
+    }
+
+    /// A left username is kept from others, leads to the account and can be
+    /// taken back by it.
+    #[tokio::test]
+    async fn former_usernames_are_reserved_and_resolved() {
+        let auth = manager_with_one_user("Alice", "secret123", true).await;
+        auth.admin_edit_user(1, Some("Alicia".to_string()), None, None).await.unwrap();
+        assert_eq!(auth.resolve_username("Alice").await, Some((1, false)));
+        assert_eq!(auth.resolve_username("Alicia").await, Some((1, true)));
+        assert_eq!(auth.register_user("Alice", "other@test.example", "Pw123456").await, Err(FopError::UserNameReserved));
+
+        let token = auth.login_user(1, "secret123").await.unwrap();
+        auth.change_username(&token, "Alice").await.unwrap();
+        let user = auth.admin_get_user(1).await.unwrap();
+        assert_eq!(user.former_usernames.iter().map(|former| former.username.as_str()).collect::<Vec<_>>(), vec!["Alicia"]);
+        assert_eq!(UserStorage::from_json(user.into_json()).former_usernames, user.former_usernames);
+    }
+
+    /// Display names are checked apart from usernames and fall back to them.
+    #[tokio::test]
+    async fn display_names_fall_back_to_the_username() {
+        let auth = manager_with_one_user("Alice", "secret123", true).await;
+        let token = auth.login_user(1, "secret123").await.unwrap();
+        assert_eq!(auth.change_display_name(&token, "  Alice Liddell ").await.unwrap(), "Alice Liddell");
+        assert_eq!(auth.admin_get_user(1).await.unwrap().display_name(), "Alice Liddell");
+        assert_eq!(auth.change_display_name(&token, "Al\u{202E}ice").await, Err(FopError::DisplayNameNotValid));
+        assert_eq!(auth.change_display_name(&token, &"あ".repeat(51)).await, Err(FopError::DisplayNameNotValid));
+        assert_eq!(auth.change_display_name(&token, "").await.unwrap(), "Alice");
+        assert!(auth.check_password(1, "secret123").await);
+    }
+
+    /// The language follows the account into `/users/me`.
+    #[tokio::test]
+    async fn languages_are_kept_in_the_profile() {
+        let auth = manager_with_one_user("Alice", "secret123", true).await;
+        let token = auth.login_user(1, "secret123").await.unwrap();
+        assert_eq!(auth.get_user_info(token.clone()).await.unwrap().get("lang"), &Value::None);
+        assert_eq!(auth.change_lang(&token, " zh-TW ").await.unwrap(), "zh-TW");
+        assert_eq!(auth.get_user_info(token.clone()).await.unwrap().get("lang").string(), "zh-TW");
+        assert_eq!(auth.change_lang(&token, "zh_TW").await, Err(FopError::LangNotValid));
+        assert_eq!(auth.change_lang(&token, "en-").await, Err(FopError::LangNotValid));
+        assert_eq!(auth.change_lang(&token, "").await.unwrap(), "");
+        assert_eq!(auth.get_user_info(token).await.unwrap().get("lang"), &Value::None);
+    }
+
+    /// An email change waits for the token mailed to the new address.
+    #[tokio::test]
+    async fn email_changes_wait_for_their_confirmation() {
+        use crate::local_auth::email_change::PendingEmail;
+        let auth = manager_with_one_user("Alice", "secret123", true).await;
+        let token = auth.login_user(1, "secret123").await.unwrap();
+        assert_eq!(auth.change_email(&token, "Alice@test.example").await, Err(FopError::EmailConflict));
+        let pending = auth.change_email(&token, "alice@new.example").await.unwrap();
+        assert_eq!(pending.email, "alice@new.example");
+        let info = auth.get_user_info(token.clone()).await.unwrap();
+        assert_eq!((info.get("email").string(), info.get("pending_email").get("email").string()), ("Alice@test.example".to_string(), "alice@new.example".to_string()));
+
+        // The mailed token is only known hashed; put one the test knows
+        let (pending, confirm) = PendingEmail::new("alice@new.example", crate::local_auth::names::now());
+        auth.users.write().await.get_mut(&1).unwrap().pending_email = Some(pending);
+        assert_eq!(auth.confirm_email_change("wrong").await, Err(FopError::TokenInvalid));
+        assert_eq!(auth.confirm_email_change(&confirm).await.unwrap(), "alice@new.example");
+        assert_eq!(auth.get_uid_by_email("alice@new.example").await, Some(1));
+        assert_eq!(auth.get_uid_by_email("Alice@test.example").await, None);
+        assert_eq!(auth.confirm_email_change(&confirm).await, Err(FopError::TokenInvalid));
+
+        auth.change_email(&token, "alice@third.example").await.unwrap();
+        assert_eq!(auth.cancel_email_change(&token).await, Ok(true));
+        assert!(matches!(auth.get_user_info(token).await.unwrap().get("pending_email"), Value::None));
+    }
+
+    /// Secondary emails count once verified and may become the primary.
+    #[tokio::test]
+    async fn secondary_emails_are_verified_then_made_primary() {
+        use crate::local_auth::emails::SecondaryEmail;
+        let auth = manager_with_one_user("Alice", "secret123", true).await;
+        auth.add_email(1, "backup@test.example").await.unwrap();
+        assert_eq!(auth.set_primary_email(1, "backup@test.example").await, Err(FopError::EmailNotVerified));
+        assert_eq!(auth.get_uid_by_recovery_email("backup@test.example").await, None);
+        // Unverified, it does not keep others from registering it
+        assert!(auth.validate_email("backup@test.example").await);
+
+        let (added, token) = SecondaryEmail::new("backup@test.example", crate::local_auth::names::now());
+        auth.users.write().await.get_mut(&1).unwrap().emails = vec![added];
+        assert_eq!(auth.verify_email(&token).await.unwrap(), "backup@test.example");
+        assert_eq!(auth.get_uid_by_recovery_email("backup@test.example").await, Some(1));
+        assert!(!auth.validate_email("backup@test.example").await);
+        assert_eq!(auth.admin_get_user(1).await.unwrap().recovery_emails(), vec!["Alice@test.example", "backup@test.example"]);
+
+        auth.set_primary_email(1, "backup@test.example").await.unwrap();
+        let user = auth.admin_get_user(1).await.unwrap();
+        assert_eq!((user.email.as_str(), user.emails[0].email.as_str(), user.emails[0].verified), ("backup@test.example", "Alice@test.example", true));
+        assert_eq!(auth.get_uid_by_email("backup@test.example").await, Some(1));
+        assert_eq!(auth.remove_email(1, "Alice@test.example").await, Ok(()));
+        assert_eq!(auth.remove_email(1, "Alice@test.example").await, Err(FopError::EmailNotFound));
+    }
+
+    /// A verified phone carries the SMS second factor; the login waits for its code.
+    #[tokio::test]
+    async fn sms_second_factor_holds_the_token_back() {
+        use crate::local_auth::phone::Phone;
+        use crate::local_auth::second_factor::{self, Method};
+        let auth = manager_with_one_user("Alice", "secret123", true).await;
+        assert_eq!(auth.set_phone(1, "555-0100").await, Err(FopError::PhoneNotValid));
+        assert_eq!(auth.set_two_factor(1, Method::Sms, true).await, Err(FopError::PhoneNotVerified));
+
+        let (phone, code) = Phone::new("+15550100199", crate::local_auth::names::now());
+        auth.users.write().await.get_mut(&1).unwrap().phone = Some(phone);
+        assert_eq!(auth.verify_phone(1, "wrong").await, Err(FopError::CodeInvalid));
+        auth.verify_phone(1, &code).await.unwrap();
+        auth.set_two_factor(1, Method::Sms, true).await.unwrap();
+
+        let challenge = match auth.login_user(1, "secret123").await {
+            Err(FopError::SecondFactorPending { challenge, method: Method::Sms }) => challenge,
+            other => panic!("expected a challenge, got {:?}", other),
+        };
+        auth.challenges.write().await.get_mut(&challenge).unwrap().code_hash = crate::local_auth::email_change::hash("123456");
+        assert_eq!(auth.complete_second_factor(&challenge, "654321").await, Err(FopError::CodeInvalid));
+        let (token, scopes) = auth.complete_second_factor(&challenge, "123456").await.unwrap();
+        assert_eq!((auth.uid_of_token(&token).await, scopes), (Some(1), Scopes::All));
+        // Used once
+        assert_eq!(auth.complete_second_factor(&challenge, "123456").await, Err(FopError::CodeInvalid));
+
+        // Wrong codes use a challenge up
+        let Err(FopError::SecondFactorPending { challenge, .. }) = auth.login_user(1, "secret123").await else { panic!() };
+        for _ in 0..second_factor::MAX_ATTEMPTS {
+            assert!(auth.complete_second_factor(&challenge, "000000x").await.is_err());
+        }
+        assert!(!auth.challenges.read().await.contains_key(&challenge));
+
+        // Without the phone the factor is off
+        auth.remove_phone(1).await.unwrap();
+        assert!(auth.login_user(1, "secret123").await.is_ok());
+    }
+
+    /// Turning a second factor on gives backup codes, each good for one challenge.
+    #[tokio::test]
+    async fn backup_codes_answer_a_challenge_once() {
+        use crate::local_auth::phone::Phone;
+        use crate::local_auth::second_factor::Method;
+        let auth = manager_with_one_user("Alice", "secret123", true).await;
+        assert_eq!(auth.regenerate_backup_codes(1).await, Err(FopError::SecondFactorNotEnabled));
+        let (mut phone, code) = Phone::new("+15550100199", crate::local_auth::names::now());
+        phone.verify(&code, crate::local_auth::names::now());
+        auth.users.write().await.get_mut(&1).unwrap().phone = Some(phone);
+        let codes = auth.set_two_factor(1, Method::Sms, true).await.unwrap().unwrap();
+        assert_eq!(auth.set_two_factor(1, Method::Sms, true).await, Ok(None));
+
+        let Err(FopError::SecondFactorPending { challenge, .. }) = auth.login_user(1, "secret123").await else { panic!() };
+        assert!(auth.complete_second_factor(&challenge, &codes[0]).await.is_ok());
+        let Err(FopError::SecondFactorPending { challenge, .. }) = auth.login_user(1, "secret123").await else { panic!() };
+        assert_eq!(auth.complete_second_factor(&challenge, &codes[0]).await, Err(FopError::CodeInvalid));
+        assert_eq!(auth.admin_get_user(1).await.unwrap().backup_codes.len(), codes.len() - 1);
+
+        // A new set voids the old one
+        let renewed = auth.regenerate_backup_codes(1).await.unwrap();
+        assert_eq!(auth.complete_second_factor(&challenge, &codes[1]).await, Err(FopError::CodeInvalid));
+        assert!(auth.complete_second_factor(&challenge, &renewed[0]).await.is_ok());
+
+        auth.set_two_factor(1, Method::Sms, false).await.unwrap();
+        assert!(auth.admin_get_user(1).await.unwrap().backup_codes.is_empty());
+    }
+
+    /// Accounts see their last login attempts and end their other sessions.
+    #[tokio::test]
+    async fn accounts_see_their_logins_and_sessions() {
+        use crate::local_auth::security::session_id;
+        let auth = manager_with_one_user("Alice", "secret123", true).await;
+        auth.login_user(1, "wrong").await.unwrap_err();
+        let first = auth.login_user(1, "secret123").await.unwrap();
+        let second = auth.login_user(1, "secret123").await.unwrap();
+        let recent = auth.admin_get_user(1).await.unwrap().activity.recent;
+        assert_eq!(recent.iter().map(|login| login.success).collect::<Vec<_>>(), vec![true, true, false]);
+
+        assert_eq!(auth.sessions_of(1).await.len(), 2);
+        assert!(auth.revoke_session(1, &session_id(&first)).await);
+        assert!(!auth.revoke_session(1, &session_id(&first)).await);
+        auth.login_user(1, "secret123").await.unwrap();
+        assert_eq!(auth.revoke_other_sessions(1, &second).await, 1);
+        assert_eq!(auth.sessions_of(1).await.into_iter().map(|session| session.token).collect::<Vec<_>>(), vec![second]);
+    }
+
+    /// Confirming the password again keeps the token and moves the time it
+    /// last proved its user, which refreshing carries over.
+    #[tokio::test]
+    async fn tokens_are_confirmed_again() {
+        use crate::local_auth::phone::Phone;
+        use crate::local_auth::second_factor::Method;
+        use crate::local_auth::sessions::Session;
+        let auth = manager_with_one_user("Alice", "secret123", true).await;
+        let token = auth.login_user(1, "secret123").await.unwrap();
+        let now = crate::local_auth::names::now();
+        // As if the password was given an hour ago
+        auth.token_list.0.write().await.insert(token.clone(), Session::new(1, now + 3600, Scopes::All, "old".to_string(), now - 3600, now));
+        let refreshed = auth.refresh_token(&token).await.unwrap();
+        assert_eq!(auth.authenticated_at(&refreshed).await, Some(now - 3600));
+
+        assert_eq!(auth.reauthenticate(&refreshed, "wrong", None).await, Err(FopError::PasswordMismatch));
+        auth.reauthenticate(&refreshed, "secret123", None).await.unwrap();
+        assert!(auth.authenticated_at(&refreshed).await.unwrap() >= now);
+        assert_eq!(auth.authenticated_at(&token).await, Some(now - 3600));
+        assert_eq!(auth.reauth_challenge(&token, None).await, Err(FopError::SecondFactorNotEnabled));
+
+        // A code sent to confirm a token completes neither a login nor another token
+        let (mut phone, code) = Phone::new("+15550100199", now);
+        phone.verify(&code, now);
+        auth.users.write().await.get_mut(&1).unwrap().phone = Some(phone);
+        let codes = auth.set_two_factor(1, Method::Sms, true).await.unwrap().unwrap();
+        let (challenge, _) = auth.reauth_challenge(&token, None).await.unwrap();
+        assert_eq!(auth.complete_second_factor(&challenge, &codes[0]).await, Err(FopError::CodeInvalid));
+        assert_eq!(auth.reauthenticate_with_code(&refreshed, &challenge, "000000").await, Err(FopError::CodeInvalid));
+    }
+
+    /// Guessing the password of a stolen token runs into the login rules.
+    #[tokio::test]
+    async fn reauthentication_is_held_to_the_login_rules() {
+        let auth = manager_with_one_user("Alice", "secret123", true).await;
+        let token = auth.login_user(1, "secret123").await.unwrap();
+        let rules = crate::local_auth::rules::RuleSet::from_value(&Value::from_json(r#"{"rules": [
+            {"name": "burst", "kind": "velocity", "max_attempts": 2, "window": 60, "action": "block"}
+        ]}"#).unwrap()).unwrap();
+        let from = Some("192.0.2.77".parse().unwrap());
+        let (at, before) = (crate::local_auth::names::now(), auth.authenticated_at(&token).await);
+
+        let guess = |password: &'static str, now| auth.reauthenticate_under(&rules, &token, password, from, now);
+        assert_eq!(guess("wrong", at).await, Err(FopError::PasswordMismatch));
+        assert_eq!(guess("wrong", at + 1).await, Err(FopError::PasswordMismatch));
+        assert_eq!(guess("secret123", at + 2).await, Err(FopError::LoginBlocked));
+        assert_eq!(auth.authenticated_at(&token).await, before);
+        assert_eq!(guess("secret123", at + 100).await, Ok(()));
+        assert_eq!(auth.authenticated_at(&token).await, Some(at + 100));
+    }
+
+    /// Lookups take uids and usernames and show no email.
+    #[tokio::test]
+    async fn lookup_users_by_uid_or_username() {
+        let auth = manager_with_one_user("Alice", "secret123", true).await;
+        let keys = ["1", "Alice", "Bob", "7"].map(String::from);
+        let (found, missing) = auth.lookup_users(&keys).await;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].get("username").string(), "Alice");
+        assert!(found[0].try_get("email").is_err());
+        assert_eq!(missing, vec!["Bob", "7"]);
+    }
+
+    /// Step 10 — a token asked for with fewer scopes keeps them when refreshed.
+    #[tokio::test]
+    async fn step10_refresh_keeps_scopes() {
+        let auth = manager_with_one_user("Alice", "secret123", true).await;
+        let scopes = Scopes::parse("profile:read").unwrap();
+        let token = auth.login_user_scoped(1, "secret123", scopes.clone()).await.unwrap();
+        let refreshed = auth.refresh_token(&token).await.unwrap();
+        assert_eq!(auth.token_scopes(&refreshed).await, Some((1, scopes)));
+
+        let full = auth.login_user(1, "secret123").await.unwrap();
+        assert_eq!(auth.token_scopes(&full).await, Some((1, Scopes::All)));
+    }
 }
 
 /// One single fixture test guarding the shipped admin credentials.
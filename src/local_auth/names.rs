@@ -0,0 +1,116 @@
+//! names.rs
+//!
+//! What happens to a username once its account takes another one. The
+//! old name is kept in the account with the time of the change, and:
+//!
+//! - nobody else may register or take it for `cooldown_days`, so that links
+//!   and mentions of the old name cannot be picked up by someone else right
+//!   away; the account itself may take it back at any time;
+//! - `GET /users/name/<old>` redirects to the current name for as long as
+//!   nobody else holds the old one.
+//!
+//! The cooldown is set under `usernames` in `programfiles/op/local_auth.json`:
+//!
+//! ```json
+//! { "users_key": "", "usernames": { "cooldown_days": 30 } }
+//! ```
+
+use hotaru::prelude::*;
+
+static NAMES: Lazy<NamePolicy> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/local_auth.json");
+    NamePolicy::from_value(Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None).get("usernames"))
+});
+
+/// Former names kept per account, the oldest dropped first
+pub const HISTORY_MAX: usize = 20;
+
+/// A username an account had before
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormerName {
+    pub username: String,
+    /// Unix time the account stopped using it
+    pub changed_at: u64,
+}
+
+impl FormerName {
+    pub fn from_json(value: &Value) -> Self {
+        Self { username: value.get("username").string(), changed_at: value.get("changed_at").integer().max(0) as u64 }
+    }
+
+    pub fn into_json(&self) -> Value {
+        object!({ username: &self.username, changed_at: self.changed_at })
+    }
+}
+
+/// Read the `former_usernames` entry of a stored account
+pub fn history_from_json(value: &Value) -> Vec<FormerName> {
+    match value {
+        Value::List(names) => names.iter().map(FormerName::from_json).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Record that an account with `history` left `old` for `new` at `now`
+pub fn record(history: &mut Vec<FormerName>, old: &str, new: &str, now: u64) {
+    history.retain(|former| former.username != old && former.username != new);
+    history.push(FormerName { username: old.to_string(), changed_at: now });
+    if history.len() > HISTORY_MAX {
+        history.remove(0);
+    }
+}
+
+/// The `usernames` entry of `local_auth.json`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamePolicy {
+    pub cooldown_days: u64,
+}
+
+impl Default for NamePolicy {
+    fn default() -> Self {
+        Self { cooldown_days: 30 }
+    }
+}
+
+impl NamePolicy {
+    pub fn from_value(value: &Value) -> Self {
+        match value.get("cooldown_days") {
+            Value::Numerical(days) if *days >= 0.0 => Self { cooldown_days: *days as u64 },
+            _ => Self::default(),
+        }
+    }
+
+    /// Whether `former` is still kept from other accounts at `now`
+    pub fn reserves(&self, former: &FormerName, now: u64) -> bool {
+        now < former.changed_at.saturating_add(self.cooldown_days * 86_400)
+    }
+}
+
+pub(crate) fn now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// The loaded policy
+pub fn policy() -> &'static NamePolicy {
+    &NAMES
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn former_names_are_kept_for_the_cooldown() {
+        let mut history = Vec::new();
+        record(&mut history, "alice", "alice2", 1000);
+        record(&mut history, "alice2", "alice", 2000);
+        // Taking a name back drops it from the history
+        assert_eq!(history, vec![FormerName { username: "alice2".to_string(), changed_at: 2000 }]);
+        assert_eq!(history_from_json(&Value::new(history.iter().map(FormerName::into_json).collect::<Vec<_>>())), history);
+
+        let policy = NamePolicy::from_value(&object!({ cooldown_days: 1 }));
+        assert!(policy.reserves(&history[0], 2000 + 86_399));
+        assert!(!policy.reserves(&history[0], 2000 + 86_400));
+        assert_eq!(NamePolicy::from_value(&Value::None), NamePolicy::default());
+    }
+}
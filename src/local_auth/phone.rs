@@ -0,0 +1,216 @@
+//! phone.rs
+//!
+//! An optional phone number of a local account, verified by a code texted
+//! to it through `crate::sms`. `POST /users/me/phone` sets the number and
+//! sends the code, `POST /users/me/phone/verify` takes it back; once
+//! verified the number can carry the `sms` second factor of
+//! `super::second_factor`.
+//!
+//! Numbers are kept in E.164 form, `+` and the country code first. A new
+//! number, or none, turns the `sms` second factor off until it is verified
+//! and turned on again.
+
+use hotaru::http::*;
+use hotaru::prelude::*;
+
+use super::LOCAL_AUTH;
+use super::email_change::hash;
+use super::fop::FopError;
+use super::reauth::{SENSITIVE_MAX_AGE, require_recent_auth};
+use super::scope::{self, require_scope_or_session};
+use super::second_factor::{self, MAX_ATTEMPTS};
+use crate::op::APP;
+use crate::methods;
+
+/// Seconds a verification code holds
+pub const CODE_SECONDS: u64 = 600;
+
+/// The phone number of an account
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Phone {
+    /// In E.164 form, `+81901234567`
+    pub number: String,
+    pub verified: bool,
+    /// SHA-256 of the code texted to it, empty once verified
+    pub code_hash: String,
+    /// Unix time the code stops holding
+    pub expires: u64,
+    /// Wrong codes given so far
+    pub attempts: u32,
+}
+
+impl Phone {
+    /// An unverified `number` set at `now`, with the code to text it
+    pub fn new(number: &str, now: u64) -> (Self, String) {
+        let code = second_factor::code();
+        let phone = Self { number: number.to_string(), verified: false, code_hash: hash(&code), expires: now + CODE_SECONDS, attempts: 0 };
+        (phone, code)
+    }
+
+    /// The `phone` entry of a stored account
+    pub fn from_json(value: &Value) -> Option<Self> {
+        match value {
+            Value::Dict(_) => Some(Self {
+                number: value.get("number").string(),
+                verified: value.get("verified").boolean(),
+                code_hash: value.get("code_hash").string(),
+                expires: value.get("expires").integer().max(0) as u64,
+                attempts: value.get("attempts").integer().max(0) as u32,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn into_json(&self) -> Value {
+        let mut value = object!({ number: &self.number, verified: self.verified });
+        if !self.verified {
+            value.set("code_hash", self.code_hash.as_str());
+            value.set("expires", self.expires);
+            value.set("attempts", self.attempts);
+        }
+        value
+    }
+
+    /// What the account is shown of it
+    pub fn public_json(&self) -> Value {
+        object!({ number: &self.number, verified: self.verified })
+    }
+
+    /// Verify it with `code` at `now`; a wrong code counts as an attempt
+    pub fn verify(&mut self, code: &str, now: u64) -> bool {
+        if self.verified || now >= self.expires || self.attempts >= MAX_ATTEMPTS {
+            return false;
+        }
+        if code.is_empty() || hash(code.trim()) != self.code_hash {
+            self.attempts += 1;
+            return false;
+        }
+        self.verified = true;
+        self.code_hash.clear();
+        true
+    }
+}
+
+/// `input` in E.164 form, without the spaces, dashes, dots and parentheses
+/// people write numbers with; `None` unless it is `+` and 7 to 15 digits
+pub fn normalize(input: &str) -> Option<String> {
+    let number: String = input.chars().filter(|c| !matches!(c, ' ' | '-' | '.' | '(' | ')')).collect();
+    let digits = number.strip_prefix('+')?;
+    let valid = (7..=15).contains(&digits.len()) && digits.bytes().all(|byte| byte.is_ascii_digit()) && !digits.starts_with('0');
+    valid.then_some(number)
+}
+
+/// The text carrying a verification code
+pub fn message(code: &str) -> String {
+    format!("Your verification code is {}. It holds for {} minutes.", code, CODE_SECONDS / 60)
+}
+
+fn error_response(err: FopError) -> HttpResponse {
+    let status = match err {
+        FopError::UserNotFound => 404,
+        FopError::SmsNotSent => 502,
+        _ => 400,
+    };
+    akari_json!({ success: false, error: err.to_string() }).status(status)
+}
+
+/// The phone of `uid`, as answered by `/users/me/phone`
+async fn listing(uid: u32) -> HttpResponse {
+    match LOCAL_AUTH.admin_get_user(uid).await {
+        Some(user) => akari_json!({ success: true, phone: user.phone.as_ref().map(Phone::public_json).unwrap_or(Value::None) }),
+        None => akari_json!({ success: false, error: "User not found" }).status(404),
+    }
+}
+
+endpoint! {
+    APP.url("/users/me/phone"),
+
+    /// GET /users/me/phone - The phone number of the account
+    /// POST /users/me/phone - Set the number and text it a verification code, or text a new code
+    /// DELETE /users/me/phone - Remove the number
+    /// A bearer token with the `profile:read` (GET) or `profile:write` scope, or the session of a local account
+    /// Request (POST): {"phone": "+81 90-1234-5678"}
+    /// Response (1): {"success": false, "error": "Method not allowed"/"Sign in with a local account"/"Phone number is not valid"/"The text message could not be sent"}
+    /// Response (2): {"success": true, "phone": {"number": "+819012345678", "verified": false}} (`null` without one)
+    /// POST and DELETE take a password confirmed within 15 minutes, else 403 with `"reauth": true` (see `super::reauth`)
+    pub user_phone <HTTP> {
+        methods!(req, GET | POST | DELETE; error);
+        let method = req.method();
+        let scope = if method == GET { scope::PROFILE_READ } else { scope::PROFILE_WRITE };
+        let uid = match require_scope_or_session(req, scope).await {
+            Ok(uid) => uid,
+            Err(response) => return response,
+        };
+        if method != GET
+            && let Err(response) = require_recent_auth(req, SENSITIVE_MAX_AGE).await
+        {
+            return response;
+        }
+        let result = if method == POST {
+            let phone = req.json_or_default().await.get("phone").string();
+            LOCAL_AUTH.set_phone(uid, &phone).await.map(|_| ())
+        } else if method == DELETE {
+            LOCAL_AUTH.remove_phone(uid).await.map(|_| ())
+        } else {
+            Ok(())
+        };
+        match result {
+            Ok(()) => listing(uid).await,
+            Err(err) => error_response(err),
+        }
+    }
+}
+
+endpoint! {
+    APP.url("/users/me/phone/verify"),
+
+    /// POST /users/me/phone/verify - Verify the phone number with the code texted to it
+    /// The same authentication as `/users/me/phone` with `profile:write`
+    /// Request: {"code": "123456"}
+    /// Response (1): {"success": false, "error": "Method not allowed"/"Sign in with a local account"/"Code is invalid or has expired"}
+    /// Response (2): the answer of `GET /users/me/phone`
+    pub verify_phone <HTTP> {
+        methods!(req, POST; error);
+        let uid = match require_scope_or_session(req, scope::PROFILE_WRITE).await {
+            Ok(uid) => uid,
+            Err(response) => return response,
+        };
+        let code = req.json_or_default().await.get("code").string();
+        match LOCAL_AUTH.verify_phone(uid, &code).await {
+            Ok(()) => listing(uid).await,
+            Err(err) => error_response(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_are_kept_in_e164() {
+        assert_eq!(normalize("+81 90-1234-5678").as_deref(), Some("+819012345678"));
+        assert_eq!(normalize("+1 (555) 010.0199").as_deref(), Some("+15550100199"));
+        assert_eq!(normalize("090-1234-5678"), None);
+        assert_eq!(normalize("+0123456789"), None);
+        assert_eq!(normalize("+12345"), None);
+        assert_eq!(normalize("+1555abc0100"), None);
+    }
+
+    #[test]
+    fn phones_verify_with_their_code() {
+        let (mut phone, code) = Phone::new("+15550100199", 1000);
+        assert!(!phone.verify("000000x", 1000));
+        assert_eq!(phone.attempts, 1);
+        assert_eq!(Phone::from_json(&phone.into_json()), Some(phone.clone()));
+        assert!(!phone.clone().verify(&code, phone.expires));
+        assert!(phone.verify(&code, 1000) && phone.verified);
+        assert!(!phone.verify(&code, 1000));
+        assert!(phone.into_json().try_get("code_hash").is_err());
+
+        let (mut locked, code) = Phone::new("+15550100199", 1000);
+        locked.attempts = MAX_ATTEMPTS;
+        assert!(!locked.verify(&code, 1000));
+        assert_eq!(Phone::from_json(&Value::None), None);
+    }
+}
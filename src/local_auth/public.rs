@@ -0,0 +1,266 @@
+//! public.rs
+//!
+//! What others may see of a local account, at `GET /users/<uid>` and
+//! `GET /users/name/<username>`: the
+//! username, the avatar and the profile entries listed in
+//! `programfiles/op/public_profile.json`, each with who may see it:
+//!
+//! ```json
+//! { "fields": { "bio": "everyone", "website": "everyone", "location": "users" } }
+//! ```
+//!
+//! `everyone` includes anonymous clients, `users` the holders of any valid
+//! bearer token, `private` only the account itself. Users narrow or widen
+//! a listed entry in the `visibility` entry of their profile, like
+//! `{"location": "private"}`; entries the file does not list are never
+//! shown. The avatar is the `avatar` entry of the profile, a URL, or the
+//! fallback of `crate::user::avatar`.
+//!
+//! Answers carry an `ETag`, and a request sending it back in
+//! `If-None-Match` gets `304 Not Modified`, so profile pages and frontends
+//! caching authors revalidate without downloading the user again.
+
+use hotaru::http::*;
+use hotaru::prelude::*;
+use sha2::{Digest, Sha256};
+
+use super::LOCAL_AUTH;
+use super::analyze::get_auth_token;
+use super::fop::UserStorage;
+use crate::op::APP;
+use crate::methods;
+
+static PUBLIC_PROFILE: Lazy<PublicProfile> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/public_profile.json");
+    PublicProfile::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None))
+});
+
+/// Profile key holding the URL of the avatar
+pub const AVATAR_KEY: &str = "avatar";
+/// Profile key holding the choices of the user about their entries
+pub const VISIBILITY_KEY: &str = "visibility";
+
+/// Who may see a profile entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Visibility {
+    Everyone,
+    Users,
+    Private,
+}
+
+impl Visibility {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "everyone" | "public" => Some(Visibility::Everyone),
+            "users" => Some(Visibility::Users),
+            "private" => Some(Visibility::Private),
+            _ => None,
+        }
+    }
+}
+
+/// Who asks for the user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Viewer {
+    Anonymous,
+    User(u32),
+}
+
+impl Viewer {
+    /// The viewer of the bearer token of `req`, anonymous without a valid one
+    pub async fn of(req: &mut HttpReqCtx) -> Self {
+        match get_auth_token(req) {
+            Some(token) => LOCAL_AUTH.uid_of_token(&token).await.map(Viewer::User).unwrap_or(Viewer::Anonymous),
+            None => Viewer::Anonymous,
+        }
+    }
+
+    fn sees(&self, owner: u32, visibility: Visibility) -> bool {
+        match (self, visibility) {
+            (_, Visibility::Everyone) => true,
+            (Viewer::User(_), Visibility::Users) => true,
+            (Viewer::User(uid), Visibility::Private) => *uid == owner,
+            (Viewer::Anonymous, _) => false,
+        }
+    }
+}
+
+/// The parsed content of `public_profile.json`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PublicProfile {
+    /// The profile entries that may be shown, with their default visibility
+    pub fields: Vec<(String, Visibility)>,
+}
+
+impl PublicProfile {
+    pub fn from_value(value: &Value) -> Self {
+        let fields = match value.get("fields") {
+            Value::Dict(fields) => fields
+                .iter()
+                .filter_map(|(key, visibility)| Some((key.clone(), Visibility::parse(&visibility.string())?)))
+                .collect(),
+            _ => Vec::new(),
+        };
+        Self { fields }
+    }
+
+    /// The entries of `profile` `viewer` may see, of the account `uid`
+    pub fn visible_fields(&self, uid: u32, profile: &Value, viewer: Viewer) -> Value {
+        let mut shown = Value::new_dict();
+        for (key, default) in &self.fields {
+            let visibility = match profile.get(VISIBILITY_KEY).get(key) {
+                Value::Str(name) => Visibility::parse(name).unwrap_or(*default),
+                _ => *default,
+            };
+            if let Ok(entry) = profile.try_get(key)
+                && !matches!(entry, Value::None)
+                && viewer.sees(uid, visibility)
+            {
+                shown.set(key, entry.clone());
+            }
+        }
+        shown
+    }
+}
+
+/// The loaded public profile settings
+pub fn settings() -> &'static PublicProfile {
+    &PUBLIC_PROFILE
+}
+
+/// The avatar of `user`, `null` without one
+pub fn avatar(user: &UserStorage) -> Value {
+    let uploaded = match user.profile.get(AVATAR_KEY) {
+        Value::Str(url) => Some(url.as_str()),
+        _ => None,
+    };
+    crate::user::avatar::resolve(uploaded, &user.email).map(Value::from).unwrap_or(Value::None)
+}
+
+/// What `viewer` may see of the account `uid`
+pub fn public_user(uid: u32, user: &UserStorage, viewer: Viewer) -> Value {
+    object!({
+        uid: uid,
+        username: &user.username,
+        display_name: user.display_name(),
+        avatar: avatar(user),
+        is_active: user.is_active,
+        profile: settings().visible_fields(uid, &user.profile, viewer),
+    })
+}
+
+/// `value` as JSON with the keys of its dicts sorted; `into_json` writes
+/// them in no set order, which would change the ETag of the same user
+fn canonical(value: &Value) -> String {
+    match value {
+        Value::Dict(entries) => {
+            let mut keys: Vec<&String> = entries.keys().collect();
+            keys.sort();
+            let entries: Vec<String> =
+                keys.into_iter().map(|key| format!("{}:{}", Value::from(key.as_str()).into_json(), canonical(&entries[key]))).collect();
+            format!("{{{}}}", entries.join(","))
+        }
+        Value::List(items) => format!("[{}]", items.iter().map(canonical).collect::<Vec<_>>().join(",")),
+        value => value.into_json(),
+    }
+}
+
+/// A strong ETag of `body`
+pub fn etag(body: &Value) -> String {
+    let digest = Sha256::digest(canonical(body).as_bytes());
+    let hex: String = digest.iter().take(16).map(|byte| format!("{:02x}", byte)).collect();
+    format!("\"{}\"", hex)
+}
+
+/// Whether the `If-None-Match` header `header` matches `etag`
+pub fn etag_matches(header: &str, etag: &str) -> bool {
+    header.split(',').map(str::trim).any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+endpoint! {
+    APP.url("/users/<uid>"),
+
+    /// GET /users/<uid> - Public info of a user, for profile pages and frontends showing authors
+    /// A bearer token is optional; with one, the entries visible to users (or to the account itself) are added
+    /// Request header `If-None-Match` with a previous `ETag` is answered 304 when nothing changed
+    /// Response (1): {"success": false, "error": "Method not allowed"/"Invalid uid"/"User not found"}
+    /// Response (2): {"success": true, "user": {"uid": 1, "username": "Admin", "display_name": "Admin", "avatar": null, "is_active": true, "profile": {"bio": "..."}}}
+    pub public_user_info <HTTP> {
+        methods!(req, GET; error);
+        let Some(uid) = req.param("uid").and_then(|uid| uid.parse::<u32>().ok()) else {
+            return akari_json!({ success: false, error: "Invalid uid" }).status(400);
+        };
+        answer(req, uid).await
+    }
+}
+
+endpoint! {
+    APP.url("/users/name/<username>"),
+
+    /// GET /users/name/<username> - The same as `GET /users/<uid>`, by username
+    /// A username the account left answers 301 to `/users/name/<current>` (see `names`)
+    /// Response (1): {"success": false, "error": "Method not allowed"/"User not found"}
+    pub public_user_by_name <HTTP> {
+        methods!(req, GET; error);
+        let username = req.param("username").unwrap_or_default();
+        match LOCAL_AUTH.resolve_username(&username).await {
+            Some((uid, true)) => answer(req, uid).await,
+            Some((uid, false)) => match LOCAL_AUTH.admin_get_user(uid).await {
+                Some(user) => redirect_response(&format!("/users/name/{}", user.username)).status(StatusCode::MOVED_PERMANENTLY),
+                None => akari_json!({ success: false, error: "User not found" }).status(404),
+            },
+            None => akari_json!({ success: false, error: "User not found" }).status(404),
+        }
+    }
+}
+
+/// The public info of `uid` for the viewer of `req`, with its ETag
+async fn answer(req: &mut HttpReqCtx, uid: u32) -> HttpResponse {
+    let Some(user) = LOCAL_AUTH.admin_get_user(uid).await else {
+        return akari_json!({ success: false, error: "User not found" }).status(404);
+    };
+    let viewer = Viewer::of(req).await;
+    let body = object!({ success: true, user: public_user(uid, &user, viewer) });
+    let tag = etag(&body);
+    let response = match req.header_str("if-none-match") {
+        Some(header) if etag_matches(header, &tag) => normal_response(StatusCode::NOT_MODIFIED, Vec::new()),
+        _ => json_response(body),
+    };
+    response
+        .add_header("ETag", tag)
+        .add_header("Cache-Control", "no-cache")
+        .add_header("Vary", "Authorization")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_are_shown_to_whom_they_may_be() {
+        let settings = PublicProfile::from_value(&object!({ fields: { bio: "everyone", location: "users", phone: "nope" } }));
+        assert_eq!(settings.fields.len(), 2);
+        let profile = object!({
+            bio: "Hi",
+            location: "Kyoto",
+            secret: "x",
+            visibility: { bio: "private" },
+        });
+        assert_eq!(settings.visible_fields(1, &profile, Viewer::Anonymous).into_json(), "{}");
+        let seen = settings.visible_fields(1, &profile, Viewer::User(2));
+        assert_eq!((seen.get("location").string(), seen.try_get("bio").is_err()), ("Kyoto".to_string(), true));
+        let own = settings.visible_fields(1, &profile, Viewer::User(1));
+        assert_eq!(own.get("bio").string(), "Hi");
+        assert!(own.try_get("secret").is_err());
+    }
+
+    #[test]
+    fn etags_match_if_none_match() {
+        let tag = etag(&object!({ a: 1, b: { c: [1, 2], d: "x" } }));
+        assert_eq!(tag, etag(&object!({ b: { d: "x", c: [1, 2] }, a: 1 })));
+        assert_ne!(tag, etag(&object!({ a: 2 })));
+        assert!(etag_matches(&tag, &tag));
+        assert!(etag_matches(&format!("\"x\", W/{}", tag), &tag));
+        assert!(!etag_matches("\"x\"", &tag) && etag_matches("*", &tag));
+    }
+}
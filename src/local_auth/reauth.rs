@@ -0,0 +1,108 @@
+//! reauth.rs
+//!
+//! Step-up re-authentication. A stolen session should not be enough to
+//! lock its owner out, so the endpoints that change how an account signs in
+//! call [`require_recent_auth`] after their scope check: unless the user
+//! gave their password or second factor within `max_age` seconds, they are
+//! answered `403` with `reauth: true`. `POST /auth/reauth` confirms them
+//! again, keeping the token; the frontend offers it at `/user/confirm`
+//! (`crate::user::confirm`) and comes back to the page that asked. Its
+//! password checks are held to the login rules (`super::rules`), so the
+//! token does not buy unlimited guesses.
+//!
+//! When they last did is kept with the token (`super::sessions::Session`),
+//! starting at its login and carried over by `/auth/refresh`.
+
+use hotaru::http::*;
+use hotaru::prelude::*;
+
+use super::LOCAL_AUTH;
+use super::analyze;
+use super::fop::FopError;
+use super::names;
+use crate::ctx::SfxCtx;
+use crate::op::APP;
+use crate::proxy;
+use crate::methods;
+
+/// How long a confirmation lasts for the endpoints of this crate
+pub const SENSITIVE_MAX_AGE: u64 = 15 * 60;
+
+/// Whether a user last confirmed at `authenticated` is recent enough at `now`
+pub fn is_recent(authenticated: u64, now: u64, max_age: u64) -> bool {
+    now < authenticated.saturating_add(max_age)
+}
+
+/// The bearer token of `req`, or the token of the session of a local account
+fn token_of(req: &mut HttpReqCtx) -> Option<String> {
+    match analyze::get_auth_token(req) {
+        Some(token) => Some(token),
+        None => req.local_uid().and_then(|_| crate::user::fetch::get_auth_token(req)),
+    }
+}
+
+/// Pass when the user of `req` gave their password or second factor within
+/// `max_age` seconds. Otherwise the error is the response to send: 403 with
+/// `reauth: true` to ask for it again, 401 without a token.
+pub async fn require_recent_auth(req: &mut HttpReqCtx, max_age: u64) -> Result<(), HttpResponse> {
+    let Some(token) = token_of(req) else {
+        return Err(akari_json!({ success: false, error: "Token invalid" }).status(401));
+    };
+    match LOCAL_AUTH.authenticated_at(&token).await {
+        Some(at) if is_recent(at, names::now(), max_age) => Ok(()),
+        Some(_) => Err(akari_json!({ success: false, error: "Confirm your password to continue", reauth: true, max_age: max_age }).status(403)),
+        None => Err(akari_json!({ success: false, error: "Token invalid" }).status(401)),
+    }
+}
+
+endpoint! {
+    APP.url("/auth/reauth"),
+
+    /// POST /auth/reauth - Confirm the user of the token again before a sensitive action
+    /// A bearer token, or the session of a local account
+    /// Request (1): {"password": "..."}
+    /// Request (2): {"second_factor": true} texts a code of the second factor
+    /// Request (3): {"challenge": "<challenge>", "code": "123456"}
+    /// Response (1): {"success": false, "error": "Method not allowed"/"Token invalid"/"Password mismatch"/"Login blocked as suspicious"/"Code is invalid or has expired"/"Turn a second factor on first"}
+    /// Response (2): {"success": true, "authenticated_at": 1700000000}
+    /// Response (3): {"success": true, "message": "Enter the code texted to your phone", "second_factor": "sms", "challenge": "..."}
+    pub reauth <HTTP> {
+        methods!(req, POST; error);
+        let Some(token) = token_of(req) else {
+            return akari_json!({ success: false, error: "Token invalid" }).status(401);
+        };
+        let json = req.json_or_default().await.clone();
+        if json.get("second_factor").boolean() {
+            return match LOCAL_AUTH.reauth_challenge(&token, proxy::client_ip(req)).await {
+                Ok((challenge, method)) => {
+                    akari_json!({ success: true, message: method.prompt(), second_factor: method.as_str(), challenge: challenge })
+                }
+                Err(err @ FopError::SmsNotSent) => akari_json!({ success: false, error: err.to_string() }).status(502),
+                Err(err) => akari_json!({ success: false, error: err.to_string() }).status(400),
+            };
+        }
+        let challenge = json.get("challenge").string();
+        let result = if challenge.is_empty() {
+            LOCAL_AUTH.reauthenticate(&token, &json.get("password").string(), proxy::client_ip(req)).await
+        } else {
+            LOCAL_AUTH.reauthenticate_with_code(&token, &challenge, &json.get("code").string()).await
+        };
+        match result {
+            Ok(()) => akari_json!({ success: true, authenticated_at: names::now() }),
+            Err(FopError::TokenInvalid) => akari_json!({ success: false, error: "Token invalid" }).status(401),
+            Err(err) => akari_json!({ success: false, error: err.to_string() }).status(403),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn confirmations_last_max_age() {
+        assert!(is_recent(1000, 1000 + SENSITIVE_MAX_AGE - 1, SENSITIVE_MAX_AGE));
+        assert!(!is_recent(1000, 1000 + SENSITIVE_MAX_AGE, SENSITIVE_MAX_AGE));
+        assert!(is_recent(1000, 2000, u64::MAX));
+    }
+}
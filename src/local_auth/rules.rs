@@ -0,0 +1,518 @@
+//! rules.rs
+//!
+//! Rules flagging suspicious logins to the local user store, read from
+//! `programfiles/op/login_rules.json` and edited at `/admin/security/rules`:
+//!
+//! ```json
+//! {
+//!     "rules": [
+//!         { "name": "burst", "kind": "velocity", "max_attempts": 10, "window": 300, "action": "block", "ban": 3600 },
+//!         { "name": "new country", "kind": "new_country", "action": "notify" },
+//!         { "name": "travel", "kind": "impossible_travel", "max_speed_kmh": 1000, "action": "require_2fa" },
+//!         { "name": "new browser", "kind": "new_user_agent", "action": "notify" }
+//!     ],
+//!     "webhooks": ["https://alerts.example.com/sfx/login"],
+//!     "secret": "env:SFX_LOGIN_WEBHOOK_SECRET"
+//! }
+//! ```
+//!
+//! Kinds of rule:
+//!
+//! - `velocity`: more than `max_attempts` logins to one account, or from one
+//!   address, within `window` seconds. Checked before the password, so a
+//!   blocked burst costs no password hash.
+//! - `new_country`: a correct password from a country the account has not
+//!   logged in from before. Accounts without a known country yet are not
+//!   flagged.
+//! - `impossible_travel`: the last login was further away than
+//!   `max_speed_kmh` allows in the time since. Moves under `min_distance_km`
+//!   (100 by default) are put down to the inaccuracy of the GeoIP database.
+//! - `new_user_agent`: a correct password from a user agent none of the
+//!   recent successful logins of the account used. Like `new_country`,
+//!   accounts without one known yet are not flagged, and neither are clients
+//!   that send none.
+//!
+//! The country and travel rules need a database in `op/geo.json` and a
+//! client address, see `crate::geo`. A rule that matches records a
+//! [`SecurityEvent`], which is logged, kept in memory for the admin panel
+//! and posted to each of `webhooks` with `secret` as bearer token. Its
+//! action then decides the login: `notify` lets it through, `block` refuses
+//! it and `require_2fa` asks for a second factor of `super::second_factor`,
+//! refusing it with `FopError::SecondFactorRequired` when the account has
+//! none. A refused login leaves
+//! the login history of the account untouched. A rule with `ban` also bans
+//! the client address for that many seconds, see `crate::bans`.
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+
+use super::fop::Activity;
+use super::sessions::ClientInfo;
+use crate::geo::Location;
+
+static RULES: Lazy<RwLock<Arc<RuleSet>>> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/login_rules.json");
+    let rules = match Value::from_jsonf(path.to_str().unwrap()) {
+        Ok(value) => RuleSet::from_value(&value).unwrap_or_else(|err| {
+            tracing::error!(%err, "Ignoring op/login_rules.json");
+            RuleSet::default()
+        }),
+        Err(_) => RuleSet::default(),
+    };
+    RwLock::new(Arc::new(rules))
+});
+
+/// Times of recent login attempts, by `uid:<uid>` and `ip:<address>`
+static ATTEMPTS: Lazy<Mutex<HashMap<String, VecDeque<u64>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The latest security events, newest last
+static EVENTS: Lazy<RwLock<VecDeque<SecurityEvent>>> = Lazy::new(|| RwLock::new(VecDeque::new()));
+
+/// How many security events are kept in memory
+pub const MAX_EVENTS: usize = 1000;
+
+/// How many countries an account remembers for `new_country`
+pub const MAX_COUNTRIES: usize = 32;
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+/// What happens to a login matching a rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Action {
+    /// Only record the event
+    Notify,
+    /// Refuse until a second factor is given
+    RequireSecondFactor,
+    /// Refuse the login
+    Block,
+}
+
+impl Action {
+    pub fn parse(action: &str) -> Option<Self> {
+        match action {
+            "notify" => Some(Action::Notify),
+            "require_2fa" => Some(Action::RequireSecondFactor),
+            "block" => Some(Action::Block),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Notify => "notify",
+            Action::RequireSecondFactor => "require_2fa",
+            Action::Block => "block",
+        }
+    }
+}
+
+/// What a rule looks for
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuleKind {
+    Velocity { max_attempts: u32, window: u64 },
+    NewCountry,
+    ImpossibleTravel { max_speed_kmh: f64, min_distance_km: f64 },
+    NewUserAgent,
+}
+
+impl RuleKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RuleKind::Velocity { .. } => "velocity",
+            RuleKind::NewCountry => "new_country",
+            RuleKind::ImpossibleTravel { .. } => "impossible_travel",
+            RuleKind::NewUserAgent => "new_user_agent",
+        }
+    }
+}
+
+/// One entry of `login_rules.json`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub name: String,
+    pub kind: RuleKind,
+    pub action: Action,
+    /// Seconds to ban the client address for, see `crate::bans`
+    pub ban: Option<u64>,
+}
+
+impl Rule {
+    pub fn from_value(value: &Value) -> Result<Self, String> {
+        let name = value.get("name").string();
+        if name.is_empty() {
+            return Err("every rule needs a `name`".to_string());
+        }
+        let number = |key: &str| match value.get(key) {
+            Value::Numerical(n) if *n > 0.0 => Ok(Some(*n)),
+            Value::None => Ok(None),
+            _ => Err(format!("rule '{}': `{}` must be a positive number", name, key)),
+        };
+        let required = |key: &str| number(key)?.ok_or_else(|| format!("rule '{}' needs `{}`", name, key));
+        let kind = match value.get("kind").string().as_str() {
+            "velocity" => RuleKind::Velocity {
+                max_attempts: required("max_attempts")? as u32,
+                window: required("window")? as u64,
+            },
+            "new_country" => RuleKind::NewCountry,
+            "impossible_travel" => RuleKind::ImpossibleTravel {
+                max_speed_kmh: required("max_speed_kmh")?,
+                min_distance_km: number("min_distance_km")?.unwrap_or(100.0),
+            },
+            "new_user_agent" => RuleKind::NewUserAgent,
+            other => return Err(format!("rule '{}' has unknown kind '{}'", name, other)),
+        };
+        let action = value.get("action").string();
+        let action = Action::parse(&action)
+            .ok_or_else(|| format!("rule '{}' has unknown action '{}'", name, action))?;
+        let ban = number("ban")?.map(|seconds| seconds as u64);
+        Ok(Self { name, kind, action, ban })
+    }
+
+    pub fn into_value(&self) -> Value {
+        let mut value = object!({ name: &self.name, kind: self.kind.as_str(), action: self.action.as_str() });
+        match &self.kind {
+            RuleKind::Velocity { max_attempts, window } => {
+                value.set("max_attempts", *max_attempts);
+                value.set("window", *window);
+            }
+            RuleKind::NewCountry | RuleKind::NewUserAgent => {}
+            RuleKind::ImpossibleTravel { max_speed_kmh, min_distance_km } => {
+                value.set("max_speed_kmh", *max_speed_kmh);
+                value.set("min_distance_km", *min_distance_km);
+            }
+        }
+        if let Some(ban) = self.ban {
+            value.set("ban", ban);
+        }
+        value
+    }
+}
+
+/// A rule that matched a login
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hit {
+    pub rule: String,
+    pub kind: &'static str,
+    pub action: Action,
+    pub ban: Option<u64>,
+    pub detail: String,
+}
+
+/// The parsed content of `login_rules.json`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RuleSet {
+    pub rules: Vec<Rule>,
+    pub webhooks: Vec<String>,
+    /// Reference to the bearer token of the webhooks, see `crate::secrets`
+    pub secret: String,
+}
+
+impl RuleSet {
+    /// Parse a ruleset, refusing anything it does not understand
+    pub fn from_value(value: &Value) -> Result<Self, String> {
+        let rules = match value.get("rules") {
+            Value::List(rules) => rules.iter().map(Rule::from_value).collect::<Result<Vec<_>, _>>()?,
+            Value::None => Vec::new(),
+            _ => return Err("`rules` must be a list".to_string()),
+        };
+        let webhooks = match value.get("webhooks") {
+            Value::List(urls) => urls.iter().map(|url| url.string()).collect::<Vec<_>>(),
+            Value::None => Vec::new(),
+            _ => return Err("`webhooks` must be a list".to_string()),
+        };
+        if let Some(url) = webhooks.iter().find(|url| !url.starts_with("http://") && !url.starts_with("https://")) {
+            return Err(format!("webhook '{}' is not an http(s) URL", url));
+        }
+        Ok(Self { rules, webhooks, secret: value.get("secret").string() })
+    }
+
+    pub fn into_value(&self) -> Value {
+        let mut value = Value::new_dict();
+        value.set("rules", Value::List(self.rules.iter().map(Rule::into_value).collect()));
+        value.set("webhooks", Value::new(self.webhooks.clone()));
+        value.set("secret", self.secret.as_str());
+        value
+    }
+
+    /// The velocity rules matched by a login attempt to `uid` from `from`
+    /// at `now`, which is counted first
+    pub fn before_password(&self, uid: u32, from: Option<IpAddr>, now: u64) -> Vec<Hit> {
+        let window = self
+            .rules
+            .iter()
+            .filter_map(|rule| match rule.kind {
+                RuleKind::Velocity { window, .. } => Some(window),
+                _ => None,
+            })
+            .max();
+        let Some(longest) = window else {
+            return Vec::new();
+        };
+        let mut keys = vec![format!("uid:{}", uid)];
+        keys.extend(from.map(|ip| format!("ip:{}", ip)));
+
+        let mut attempts = ATTEMPTS.lock().unwrap();
+        if attempts.len() > 10_000 {
+            attempts.retain(|_, times| times.back().is_some_and(|&last| last + longest > now));
+        }
+        let mut hits = Vec::new();
+        for key in keys {
+            let times = attempts.entry(key.clone()).or_default();
+            times.push_back(now);
+            while times.front().is_some_and(|&time| time + longest <= now) {
+                times.pop_front();
+            }
+            for rule in &self.rules {
+                if let RuleKind::Velocity { max_attempts, window } = rule.kind {
+                    let recent = times.iter().filter(|&&time| time + window > now).count();
+                    if recent > max_attempts as usize {
+                        hits.push(Hit {
+                            rule: rule.name.clone(),
+                            kind: rule.kind.as_str(),
+                            action: rule.action,
+                            ban: rule.ban,
+                            detail: format!("{} attempts for {} within {} seconds", recent, key, window),
+                        });
+                    }
+                }
+            }
+        }
+        hits
+    }
+
+    /// The rules matched by a correct password from `user_agent` and
+    /// `location` at `now`, given the login history before this login
+    pub fn after_password(&self, previous: &Activity, user_agent: Option<&str>, location: Option<&Location>, now: u64) -> Vec<Hit> {
+        let mut hits = Vec::new();
+        for rule in &self.rules {
+            let detail = match (&rule.kind, location) {
+                (RuleKind::NewUserAgent, _) => user_agent.filter(|user_agent| {
+                    let known: Vec<&str> = previous
+                        .recent
+                        .iter()
+                        .filter(|login| login.success)
+                        .filter_map(|login| login.user_agent.as_deref())
+                        .collect();
+                    !known.is_empty() && !known.contains(user_agent)
+                })
+                .map(|user_agent| format!("first login with {}", user_agent)),
+                (_, None) => None,
+                (RuleKind::NewCountry, Some(location)) => (!location.country_code.is_empty()
+                    && !previous.countries.is_empty()
+                    && !previous.countries.contains(&location.country_code))
+                .then(|| format!("first login from {}", location)),
+                (&RuleKind::ImpossibleTravel { max_speed_kmh, min_distance_km }, Some(location)) => {
+                    let from = previous.last_login_location.as_ref().and_then(|last| last.coordinates);
+                    match (from, location.coordinates, previous.last_login) {
+                        (Some(from), Some(to), Some(last)) => {
+                            let km = distance_km(from, to);
+                            let hours = now.saturating_sub(last).max(1) as f64 / 3600.0;
+                            (km >= min_distance_km && km / hours > max_speed_kmh).then(|| {
+                                let last_location = previous.last_login_location.as_ref().unwrap();
+                                format!("{:.0} km from {} in {:.1} hours", km, last_location, hours)
+                            })
+                        }
+                        _ => None,
+                    }
+                }
+                (RuleKind::Velocity { .. }, _) => None,
+            };
+            if let Some(detail) = detail {
+                hits.push(Hit { rule: rule.name.clone(), kind: rule.kind.as_str(), action: rule.action, ban: rule.ban, detail });
+            }
+        }
+        hits
+    }
+}
+
+/// Great-circle distance between two `(latitude, longitude)` points
+pub fn distance_km(from: (f64, f64), to: (f64, f64)) -> f64 {
+    let (lat1, lon1) = (from.0.to_radians(), from.1.to_radians());
+    let (lat2, lon2) = (to.0.to_radians(), to.1.to_radians());
+    let a = ((lat2 - lat1) / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * ((lon2 - lon1) / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+}
+
+/// A suspicious login seen by a rule
+#[derive(Debug, Clone, PartialEq)]
+pub struct SecurityEvent {
+    pub time: u64,
+    pub uid: u32,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub location: Option<Location>,
+    pub rule: String,
+    /// The [`RuleKind`] of `rule`
+    pub kind: &'static str,
+    pub action: Action,
+    pub detail: String,
+}
+
+impl SecurityEvent {
+    pub fn into_json(&self) -> Value {
+        let mut value = object!({
+            event: "suspicious_login",
+            time: self.time,
+            uid: self.uid,
+            rule: &self.rule,
+            kind: self.kind,
+            action: self.action.as_str(),
+            detail: &self.detail,
+        });
+        if let Some(ip) = &self.ip {
+            value.set("ip", ip.as_str());
+        }
+        if let Some(user_agent) = &self.user_agent {
+            value.set("user_agent", user_agent.as_str());
+        }
+        if let Some(location) = &self.location {
+            value.set("location", location.into_json());
+        }
+        value
+    }
+}
+
+/// The ruleset in force
+pub fn current() -> Arc<RuleSet> {
+    RULES.read().unwrap().clone()
+}
+
+/// Write `rules` to `op/login_rules.json` and put them in force
+pub fn save(rules: RuleSet) -> std::io::Result<()> {
+    let path = crate::op::programfiles().join("op/login_rules.json");
+    std::fs::write(&path, rules.into_value().into_json())?;
+    *RULES.write().unwrap() = Arc::new(rules);
+    Ok(())
+}
+
+/// The security events kept in memory, newest first
+pub fn events() -> Vec<SecurityEvent> {
+    EVENTS.read().unwrap().iter().rev().cloned().collect()
+}
+
+/// Record the `hits` of a login of `uid` from `client` and return the
+/// strictest action
+pub fn report(
+    rules: &RuleSet,
+    hits: Vec<Hit>,
+    uid: u32,
+    client: &ClientInfo,
+    location: Option<&Location>,
+    now: u64,
+) -> Option<Action> {
+    let strictest = hits.iter().map(|hit| hit.action).max();
+    let from = client.ip;
+    for hit in hits {
+        if let (Some(seconds), Some(ip)) = (hit.ban, from) {
+            crate::bans::add_by_rule(ip, &hit.rule, seconds, &hit.detail);
+        }
+        let event = SecurityEvent {
+            time: now,
+            uid,
+            ip: from.map(|ip| ip.to_string()),
+            user_agent: client.user_agent.clone(),
+            location: location.cloned(),
+            rule: hit.rule,
+            kind: hit.kind,
+            action: hit.action,
+            detail: hit.detail,
+        };
+        tracing::warn!(uid, rule = %event.rule, action = event.action.as_str(), detail = %event.detail, "Suspicious login");
+        notify(rules, &event);
+        let mut events = EVENTS.write().unwrap();
+        if events.len() >= MAX_EVENTS {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+    strictest
+}
+
+/// Post `event` to the webhooks of `rules`
+fn notify(rules: &RuleSet, event: &SecurityEvent) {
+    if rules.webhooks.is_empty() {
+        return;
+    }
+    let secret = crate::secrets::load(&rules.secret, "login_rules.json secret");
+    for url in &rules.webhooks {
+        let (origin, path) = split_url(url);
+        let body = event.into_json();
+        let secret = secret.clone();
+        tokio::spawn(async move {
+            let mut meta = HttpMeta::new(HttpStartLine::request_post(&path), HashMap::new());
+            meta.set_content_type(HttpContentType::ApplicationJson());
+            let mut request = HttpRequest::new(meta, HttpBody::Json(body));
+            if !secret.is_empty() {
+                request = request.add_header("Authorization", format!("Bearer {}", secret));
+            }
+            if let Err(err) = crate::user::fetch::send_http_request(origin.clone(), request, HttpSafety::default()).await {
+                tracing::warn!(%origin, %path, ?err, "Login webhook failed");
+            }
+        });
+    }
+}
+
+/// Split `https://host:port/path` into the origin and the path
+pub(crate) fn split_url(url: &str) -> (String, String) {
+    let scheme_end = url.find("://").map(|at| at + 3).unwrap_or(0);
+    match url[scheme_end..].find('/') {
+        Some(slash) => (url[..scheme_end + slash].to_string(), url[scheme_end + slash..].to_string()),
+        None => (url.to_string(), "/".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local_auth::fop::LoginRecord;
+
+    fn location(country_code: &str, coordinates: (f64, f64)) -> Location {
+        Location { country_code: country_code.into(), coordinates: Some(coordinates), ..Location::default() }
+    }
+
+    #[test]
+    fn rules_flag_bursts_new_countries_and_travel() {
+        let rules = RuleSet::from_value(&Value::from_json(r#"{"rules": [
+            {"name": "burst", "kind": "velocity", "max_attempts": 2, "window": 60, "action": "block", "ban": 600},
+            {"name": "country", "kind": "new_country", "action": "notify"},
+            {"name": "travel", "kind": "impossible_travel", "max_speed_kmh": 1000, "action": "require_2fa"},
+            {"name": "browser", "kind": "new_user_agent", "action": "notify"}
+        ]}"#).unwrap()).unwrap();
+        assert_eq!(RuleSet::from_value(&rules.into_value()), Ok(rules.clone()));
+
+        assert!(rules.before_password(4_000_001, None, 100).is_empty());
+        assert!(rules.before_password(4_000_001, None, 101).is_empty());
+        assert_eq!(rules.before_password(4_000_001, None, 102)[0].action, Action::Block);
+        assert!(rules.before_password(4_000_001, None, 200).is_empty());
+
+        let amsterdam = location("NL", (52.37, 4.89));
+        let previous = Activity {
+            last_login: Some(0),
+            last_login_location: Some(amsterdam.clone()),
+            countries: vec!["NL".into()],
+            ..Activity::default()
+        };
+        assert!(rules.after_password(&previous, None, Some(&amsterdam), 3600).is_empty());
+        let sydney = location("AU", (-33.87, 151.21));
+        let hits = rules.after_password(&previous, None, Some(&sydney), 3600);
+        assert_eq!(hits.iter().map(|hit| hit.rule.as_str()).collect::<Vec<_>>(), ["country", "travel"]);
+        assert_eq!(hits.iter().map(|hit| hit.action).max(), Some(Action::RequireSecondFactor));
+        // A day is long enough to fly there
+        assert_eq!(rules.after_password(&previous, None, Some(&sydney), 86_400).len(), 1);
+
+        // Only once a user agent is known, and without a location too
+        assert!(rules.after_password(&previous, Some("curl/8.0"), None, 3600).is_empty());
+        let login = |user_agent: &str, success| LoginRecord { at: 0, ip: None, location: None, user_agent: Some(user_agent.into()), success };
+        let previous = Activity { recent: vec![login("curl/8.0", false), login("Firefox/130", true)], ..previous };
+        assert!(rules.after_password(&previous, Some("Firefox/130"), None, 3600).is_empty());
+        let hits = rules.after_password(&previous, Some("curl/8.0"), None, 3600);
+        assert_eq!((hits[0].rule.as_str(), hits[0].detail.as_str()), ("browser", "first login with curl/8.0"));
+
+        assert!(RuleSet::from_value(&Value::from_json(r#"{"rules": [{"name": "x", "kind": "velocity", "action": "block"}]}"#).unwrap()).is_err());
+        assert!(RuleSet::from_value(&Value::from_json(r#"{"webhooks": ["ftp://example.com"]}"#).unwrap()).is_err());
+        assert_eq!(split_url("https://a.example.com:8443/hooks/sfx"), ("https://a.example.com:8443".into(), "/hooks/sfx".into()));
+    }
+}
@@ -0,0 +1,112 @@
+//! scope.rs
+//!
+//! What a bearer token may be used for. A token from `/auth/login` can do
+//! everything its user can unless the client asks for fewer scopes, e.g.
+//! `{"username": "bot", "password": "...", "scope": "profile:read"}` for an
+//! integration that only ever reads the profile. Endpoints taking a bearer
+//! token call `require_scope` before doing anything else.
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+use std::collections::BTreeSet;
+
+use super::LOCAL_AUTH;
+use super::analyze::get_auth_token;
+use crate::ctx::SfxCtx;
+
+/// `GET /users/me` and `GET /users/me/preferences`
+pub const PROFILE_READ: &str = "profile:read";
+/// `POST /users/me/password` and `PATCH /users/me/preferences`
+pub const PROFILE_WRITE: &str = "profile:write";
+/// The `/admin/users` API and `/auth/admin`, for tokens of admins
+pub const USERS_ADMIN: &str = "users:admin";
+
+/// Every scope a token can carry
+pub const KNOWN: [&str; 3] = [PROFILE_READ, PROFILE_WRITE, USERS_ADMIN];
+
+/// The scopes granted to a token
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Scopes {
+    /// Everything the user may do; what a login without `scope` gets
+    #[default]
+    All,
+    Only(BTreeSet<String>),
+}
+
+impl Scopes {
+    /// Parse the space separated scopes a client asked for. Unknown scopes
+    /// are an error rather than dropped, so a typo does not go unnoticed.
+    pub fn parse(requested: &str) -> Result<Self, String> {
+        let mut scopes = BTreeSet::new();
+        for scope in requested.split_whitespace() {
+            if !KNOWN.contains(&scope) {
+                return Err(format!("Unknown scope: {}", scope));
+            }
+            scopes.insert(scope.to_string());
+        }
+        if scopes.is_empty() {
+            return Err("No scope requested".to_string());
+        }
+        Ok(Scopes::Only(scopes))
+    }
+
+    pub fn allows(&self, scope: &str) -> bool {
+        match self {
+            Scopes::All => true,
+            Scopes::Only(scopes) => scopes.contains(scope),
+        }
+    }
+}
+
+impl std::fmt::Display for Scopes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Scopes::All => KNOWN.join(" ").fmt(f),
+            Scopes::Only(scopes) => scopes.iter().cloned().collect::<Vec<_>>().join(" ").fmt(f),
+        }
+    }
+}
+
+/// The uid the bearer token of `req` was issued to, if the token carries
+/// `scope`. Otherwise the error is the response to send: 401 for a missing
+/// or unknown token, 403 for a token lacking the scope.
+pub async fn require_scope(req: &mut HttpReqCtx, scope: &str) -> Result<u32, HttpResponse> {
+    let Some(token) = get_auth_token(req) else {
+        return Err(akari_json!({ success: false, error: "Token invalid" }).status(401));
+    };
+    match LOCAL_AUTH.token_scopes(&token).await {
+        Some((uid, scopes)) if scopes.allows(scope) => Ok(uid),
+        Some(_) => Err(akari_json!({ success: false, error: "Insufficient scope", scope: scope }).status(403)),
+        None => Err(akari_json!({ success: false, error: "Token invalid" }).status(401)),
+    }
+}
+
+/// Like `require_scope` when `req` has a bearer token; without one, the
+/// uid of the local account the session is signed in with, for the pages
+/// of this server calling the API
+pub async fn require_scope_or_session(req: &mut HttpReqCtx, scope: &str) -> Result<u32, HttpResponse> {
+    if get_auth_token(req).is_some() {
+        return require_scope(req, scope).await;
+    }
+    req.local_uid().ok_or_else(|| akari_json!({ success: false, error: "Sign in with a local account" }).status(401))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requested_scopes_are_checked_and_narrowed() {
+        let scopes = Scopes::parse("profile:read  users:admin").unwrap();
+        assert!(scopes.allows(PROFILE_READ));
+        assert!(scopes.allows(USERS_ADMIN));
+        assert!(!scopes.allows(PROFILE_WRITE));
+        assert_eq!(scopes.to_string(), "profile:read users:admin");
+
+        assert_eq!(Scopes::parse("profile:read users:root"), Err("Unknown scope: users:root".to_string()));
+        assert!(Scopes::parse(" ").is_err());
+
+        assert!(KNOWN.iter().all(|scope| Scopes::All.allows(scope)));
+        assert_eq!(Scopes::All.to_string(), "profile:read profile:write users:admin");
+    }
+}
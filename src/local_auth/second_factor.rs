@@ -0,0 +1,227 @@
+//! second_factor.rs
+//!
+//! A second step to logging in. An account turns a method on at
+//! `/users/me/two_factor`; a login with the right password then gets a
+//! challenge instead of a token, and `/auth/login/second_factor` trades the
+//! challenge and the code for the token. The `require_2fa` rules of
+//! `super::rules` put the same step in front of the accounts having a
+//! method available, turned on or not, and refuse the others.
+//!
+//! The only method so far is `sms`: a code texted to the verified phone
+//! number of the account (see `super::phone`). There is no TOTP in this
+//! tree; new methods are added to [`Method`].
+//!
+//! Challenges live in memory for five minutes and take five wrong codes.
+
+use hotaru::http::*;
+use hotaru::prelude::*;
+use hotaru_lib::random::random_alphanumeric_string;
+
+use super::LOCAL_AUTH;
+use super::email_change::hash;
+use super::reauth::{SENSITIVE_MAX_AGE, require_recent_auth};
+use super::scope::{self, Scopes, require_scope_or_session};
+use super::sessions::ClientInfo;
+use crate::geo::Location;
+use crate::op::APP;
+use crate::methods;
+
+/// Seconds a challenge holds
+pub const CHALLENGE_SECONDS: u64 = 300;
+/// Wrong codes a challenge or a phone verification takes
+pub const MAX_ATTEMPTS: u32 = 5;
+/// Digits of a texted code
+pub const CODE_LENGTH: usize = 6;
+
+/// A way to prove the login a second time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    /// A code texted to the verified phone number
+    Sms,
+}
+
+impl Method {
+    /// Every method, in the order they are asked for
+    pub const ALL: [Method; 1] = [Method::Sms];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "sms" => Some(Method::Sms),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Sms => "sms",
+        }
+    }
+
+    /// What the user is asked for at the second step
+    pub fn prompt(&self) -> &'static str {
+        match self {
+            Method::Sms => "Enter the code texted to your phone",
+        }
+    }
+}
+
+/// Read the `two_factor` entry of a stored account
+pub fn list_from_json(value: &Value) -> Vec<Method> {
+    match value {
+        Value::List(methods) => methods.iter().filter_map(|method| Method::parse(&method.string())).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// `methods` as a JSON list of their names
+pub fn list_into_json(methods: &[Method]) -> Value {
+    Value::new(methods.iter().map(|method| Value::from(method.as_str())).collect::<Vec<Value>>())
+}
+
+/// A new code of `CODE_LENGTH` digits
+pub fn code() -> String {
+    random_alphanumeric_string(CODE_LENGTH).bytes().map(|byte| (b'0' + byte % 10) as char).collect()
+}
+
+/// The text carrying a login code
+pub fn message(code: &str) -> String {
+    format!("Your login code is {}. It holds for {} minutes; do not share it.", code, CHALLENGE_SECONDS / 60)
+}
+
+/// A login waiting for its second factor
+#[derive(Debug, Clone)]
+pub struct Challenge {
+    pub uid: u32,
+    pub method: Method,
+    /// The scopes of the token asked for
+    pub scopes: Scopes,
+    /// SHA-256 of the code sent
+    pub code_hash: String,
+    /// Unix time it stops holding
+    pub expires: u64,
+    /// Wrong codes given so far
+    pub attempts: u32,
+    /// The client the login came from, recorded once it completes
+    pub client: ClientInfo,
+    pub location: Option<Location>,
+    /// The token a re-authentication of `super::reauth` confirms; `None`
+    /// for a login
+    pub confirms: Option<String>,
+}
+
+impl Challenge {
+    /// A challenge started at `now`, with its id and the code to send
+    pub fn new(uid: u32, method: Method, scopes: Scopes, client: ClientInfo, location: Option<Location>, now: u64) -> (String, Self, String) {
+        let code = code();
+        let challenge = Self { uid, method, scopes, code_hash: hash(&code), expires: now + CHALLENGE_SECONDS, attempts: 0, client, location, confirms: None };
+        (random_alphanumeric_string(32), challenge, code)
+    }
+
+    /// Whether it may still be completed at `now`
+    pub fn usable(&self, now: u64) -> bool {
+        now < self.expires && self.attempts < MAX_ATTEMPTS
+    }
+
+    /// Whether `code` completes it at `now`
+    pub fn accepts(&self, code: &str, now: u64) -> bool {
+        self.usable(now) && !code.is_empty() && hash(code.trim()) == self.code_hash
+    }
+}
+
+/// The second factors of `uid`, as answered by `/users/me/two_factor`,
+/// with the backup codes just given
+async fn listing(uid: u32, new_codes: Option<Vec<String>>) -> HttpResponse {
+    match LOCAL_AUTH.admin_get_user(uid).await {
+        Some(user) => {
+            let mut listing = object!({
+                success: true,
+                methods: list_into_json(&user.two_factor),
+                available: list_into_json(&user.second_factors()),
+                backup_codes_remaining: user.backup_codes.len(),
+            });
+            if let Some(codes) = new_codes {
+                listing.set("backup_codes", Value::new(codes));
+            }
+            json_response(listing)
+        }
+        None => akari_json!({ success: false, error: "User not found" }).status(404),
+    }
+}
+
+endpoint! {
+    APP.url("/users/me/two_factor"),
+
+    /// GET /users/me/two_factor - The second factors turned on, and those the account could turn on
+    /// POST /users/me/two_factor - Turn a method on or off; `sms` needs a verified phone number
+    /// A bearer token with the `profile:read` (GET) or `profile:write` scope, or the session of a local account
+    /// Request (POST): {"method": "sms", "enabled": true}
+    /// Response (1): {"success": false, "error": "Method not allowed"/"Sign in with a local account"/"Unknown method"/"Phone number is not verified"}
+    /// Response (2): {"success": true, "methods": ["sms"], "available": ["sms"], "backup_codes_remaining": 10}
+    /// Turning the first method on adds `"backup_codes": ["k7mq-3xhz", ...]`, shown this once (see `super::backup_codes`)
+    /// POST takes a password confirmed within 15 minutes, else 403 with `"reauth": true` (see `super::reauth`)
+    pub two_factor <HTTP> {
+        methods!(req, GET | POST; error);
+        let method = req.method();
+        let scope = if method == GET { scope::PROFILE_READ } else { scope::PROFILE_WRITE };
+        let uid = match require_scope_or_session(req, scope).await {
+            Ok(uid) => uid,
+            Err(response) => return response,
+        };
+        if method == POST
+            && let Err(response) = require_recent_auth(req, SENSITIVE_MAX_AGE).await
+        {
+            return response;
+        }
+        let mut new_codes = None;
+        if method == POST {
+            let json = req.json_or_default().await;
+            let Some(second_factor) = Method::parse(&json.get("method").string()) else {
+                return akari_json!({ success: false, error: "Unknown method" }).status(400);
+            };
+            match LOCAL_AUTH.set_two_factor(uid, second_factor, json.get("enabled").boolean()).await {
+                Ok(codes) => new_codes = codes,
+                Err(err) => return akari_json!({ success: false, error: err.to_string() }).status(400),
+            }
+        }
+        listing(uid, new_codes).await
+    }
+}
+
+endpoint! {
+    APP.url("/auth/login/second_factor"),
+
+    /// POST /auth/login/second_factor - Complete a login `/auth/login` answered with a challenge
+    /// Request: {"challenge": "<challenge>", "code": "123456"}, or a backup code as `code`
+    /// Response (1): {success: false, message: "Code is invalid or has expired"/"User is inactive"}
+    /// Response (2): {success: true, access_token: access, token_type: "Bearer", scope: "profile:read ..."}
+    pub login_second_factor <HTTP> {
+        methods!(req, POST);
+        let json = req.json_or_default().await;
+        match LOCAL_AUTH.complete_second_factor(&json.get("challenge").string(), &json.get("code").string()).await {
+            Ok((token, scopes)) => akari_json!({ success: true, access_token: token, token_type: "Bearer", scope: scopes.to_string() }),
+            Err(err) => akari_json!({ success: false, message: err.to_string() }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn challenges_take_the_code_sent() {
+        let code = code();
+        assert!(code.len() == CODE_LENGTH && code.bytes().all(|byte| byte.is_ascii_digit()));
+
+        let (id, mut challenge, code) = Challenge::new(1, Method::Sms, Scopes::All, ClientInfo::default(), None, 1000);
+        assert_eq!(id.len(), 32);
+        assert!(challenge.accepts(&code, 1000) && challenge.accepts(&format!(" {} ", code), 1000));
+        assert!(!challenge.accepts(&code, challenge.expires) && !challenge.accepts("", 1000));
+        challenge.attempts = MAX_ATTEMPTS;
+        assert!(!challenge.accepts(&code, 1000));
+
+        let stored = list_into_json(&[Method::Sms]);
+        assert_eq!(list_from_json(&stored), vec![Method::Sms]);
+        assert!(list_from_json(&Value::new(vec![Value::from("totp")])).is_empty());
+    }
+}
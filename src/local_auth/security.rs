@@ -0,0 +1,118 @@
+//! security.rs
+//!
+//! What an account can see and end of its own sign-ins: the tokens issued
+//! to it at `/users/me/sessions`, with the client each login was made from
+//! (see `super::sessions::ClientInfo`), and its last login attempts at
+//! `/users/me/activity`. Tokens are never shown back; each is named by
+//! [`session_id`], a digest of it, which is what revoking takes.
+//!
+//! The security page of the frontend (`crate::user::security`) reads these
+//! with the token of its session, like the second factors of
+//! `super::second_factor`.
+
+use hotaru::http::*;
+use hotaru::prelude::*;
+
+use super::LOCAL_AUTH;
+use super::analyze::get_auth_token;
+use super::email_change::hash;
+use super::fop::LoginRecord;
+use super::scope::{self, require_scope};
+use crate::op::APP;
+use crate::methods;
+
+/// The name of `token` in the session list
+pub fn session_id(token: &str) -> String {
+    hash(token)[..16].to_string()
+}
+
+/// The sessions of `uid`, marking the one of `current`
+async fn listing(uid: u32, current: &str) -> HttpResponse {
+    let sessions: Vec<Value> = LOCAL_AUTH
+        .sessions_of(uid)
+        .await
+        .into_iter()
+        .map(|session| {
+            let mut value = object!({
+                id: session_id(&session.token),
+                created: session.started,
+                expires: session.expires,
+                scope: session.scopes.to_string(),
+                current: session.token == current,
+            });
+            session.client.add_to(&mut value);
+            value
+        })
+        .collect();
+    akari_json!({ success: true, sessions: Value::new(sessions) })
+}
+
+endpoint! {
+    APP.url("/users/me/sessions"),
+
+    /// GET /users/me/sessions - The unexpired tokens of the account, soonest to expire first
+    /// DELETE /users/me/sessions - End one by its id, or every other one with `others`
+    /// A bearer token with the `profile:read` (GET) or `profile:write` scope
+    /// Request (DELETE): {"id": "9f86d081884c7d65"} or {"others": true}
+    /// Response (1): {"success": false, "error": "Method not allowed"/"Token invalid"/"Insufficient scope"/"Session not found"}
+    /// Response (2): {"success": true, "sessions": [{"id": "9f86d081884c7d65", "created": 1700000000, "expires": 1700003600, "scope": "profile:read",
+    /// "current": true, "ip": "203.0.113.7", "user_agent": "Mozilla/5.0 ...", "label": "Work laptop"}]}, the last three when known
+    pub user_sessions <HTTP> {
+        methods!(req, GET | DELETE; error);
+        let method = req.method();
+        let scope = if method == GET { scope::PROFILE_READ } else { scope::PROFILE_WRITE };
+        let uid = match require_scope(req, scope).await {
+            Ok(uid) => uid,
+            Err(response) => return response,
+        };
+        let current = get_auth_token(req).unwrap_or_default();
+        if method == DELETE {
+            let json = req.json_or_default().await;
+            if json.get("others").boolean() {
+                LOCAL_AUTH.revoke_other_sessions(uid, &current).await;
+            } else if !LOCAL_AUTH.revoke_session(uid, &json.get("id").string()).await {
+                return akari_json!({ success: false, error: "Session not found" }).status(404);
+            }
+        }
+        listing(uid, &current).await
+    }
+}
+
+endpoint! {
+    APP.url("/users/me/activity"),
+
+    /// GET /users/me/activity - The last login attempts of the account, newest first
+    /// A bearer token with the `profile:read` scope
+    /// Response (1): {"success": false, "error": "Method not allowed"/"Token invalid"/"Insufficient scope"/"User not found"}
+    /// Response (2): {"success": true, "failed_logins": 0, "recent": [{"at": 1700000000, "success": true, "ip": "203.0.113.7", "location": {...}, "user_agent": "..."}]}
+    /// `failed_logins` counts the wrong passwords since the last login
+    pub user_activity <HTTP> {
+        methods!(req, GET; error);
+        let uid = match require_scope(req, scope::PROFILE_READ).await {
+            Ok(uid) => uid,
+            Err(response) => return response,
+        };
+        match LOCAL_AUTH.admin_get_user(uid).await {
+            Some(user) => akari_json!({
+                success: true,
+                failed_logins: user.activity.failed_logins,
+                recent: Value::new(user.activity.recent.iter().map(LoginRecord::into_json).collect::<Vec<Value>>()),
+            }),
+            None => akari_json!({ success: false, error: "User not found" }).status(404),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_are_named_without_their_token() {
+        let id = session_id("token123");
+        assert_eq!(id.len(), 16);
+        assert!(!id.contains("token123"));
+        assert_eq!(id, session_id("token123"));
+        assert_ne!(id, session_id("token124"));
+    }
+}
@@ -0,0 +1,322 @@
+//! sessions.rs
+//!
+//! Limits on the sessions of local accounts, set under `sessions` in
+//! `programfiles/op/local_auth.json`:
+//!
+//! ```json
+//! { "users_key": "", "sessions": { "idle_minutes": 30, "max_age_hours": 720, "max_per_user": 5, "sliding_minutes": 60 } }
+//! ```
+//!
+//! A token left unused for `idle_minutes` stops holding, and so does every
+//! token of a login older than `max_age_hours`, however often it was
+//! refreshed. A login past `max_per_user` signs the oldest session of the
+//! account out. 0, the default, leaves a limit off.
+//!
+//! Tokens expire an hour after they are issued. With `sliding_minutes`
+//! every use moves that on to `sliding_minutes` after the use, so a token in
+//! use keeps holding without `/auth/refresh` until `max_age_hours` ends its
+//! login.
+//!
+//! `super::fop::TokenList` holds the tokens to these; the session middleware
+//! of the frontend (`crate::user::middleware`) also signs out browser
+//! sessions left idle, without waiting for its cached user to expire.
+//!
+//! Each token also keeps the [`ClientInfo`] its login was made with, shown
+//! to the account at `/users/me/sessions` and kept by every refresh.
+
+use hotaru::prelude::*;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::scope::Scopes;
+
+static SESSIONS: Lazy<SessionSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/local_auth.json");
+    SessionSettings::from_value(Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None).get("sessions"))
+});
+
+/// The `sessions` entry of `local_auth.json`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionSettings {
+    pub idle_minutes: u64,
+    pub max_age_hours: u64,
+    pub max_per_user: usize,
+    /// Minutes a use of a token keeps it from expiring; 0 for tokens that
+    /// expire when issued to
+    pub sliding_minutes: u64,
+}
+
+impl SessionSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let limit = |key: &str| match value.get(key) {
+            Value::Numerical(number) if *number >= 1.0 => *number as u64,
+            _ => 0,
+        };
+        Self {
+            idle_minutes: limit("idle_minutes"),
+            max_age_hours: limit("max_age_hours"),
+            max_per_user: limit("max_per_user") as usize,
+            sliding_minutes: limit("sliding_minutes"),
+        }
+    }
+
+    /// Unix time `session` expires: when it was issued to, or with
+    /// `sliding_minutes` that long after its last use if later
+    pub fn expires(&self, session: &Session) -> u64 {
+        if self.sliding_minutes == 0 {
+            return session.expires;
+        }
+        session.expires.max(session.last_used() + self.sliding_minutes * 60)
+    }
+
+    /// Whether `session` may still be used at `now`
+    pub fn holds(&self, session: &Session, now: u64) -> bool {
+        self.expires(session) > now
+            && (self.idle_minutes == 0 || now < session.last_used() + self.idle_minutes * 60)
+            && (self.max_age_hours == 0 || now < session.started + self.max_age_hours * 3600)
+    }
+
+    /// Unix time `session` stops holding unless it is used again before,
+    /// as seen at `now`
+    pub fn held_until(&self, session: &Session, now: u64) -> u64 {
+        let mut until = self.expires(session);
+        if self.idle_minutes != 0 {
+            until = until.min(session.last_used().max(now) + self.idle_minutes * 60);
+        }
+        if self.max_age_hours != 0 {
+            until = until.min(session.started + self.max_age_hours * 3600);
+        }
+        until
+    }
+
+    /// The tokens to end so that `uid` may sign in once more at `now`: every
+    /// token of its oldest logins, until fewer than `max_per_user` are left
+    pub fn evicted(&self, sessions: &HashMap<String, Session>, uid: u32, now: u64) -> Vec<String> {
+        if self.max_per_user == 0 {
+            return Vec::new();
+        }
+        let mut logins: Vec<(u64, &str)> = sessions
+            .values()
+            .filter(|session| session.uid == uid && self.holds(session, now))
+            .map(|session| (session.started, session.login.as_str()))
+            .collect();
+        logins.sort();
+        logins.dedup();
+        let excess = (logins.len() + 1).saturating_sub(self.max_per_user);
+        let ended: Vec<&str> = logins.into_iter().take(excess).map(|(_, login)| login).collect();
+        sessions
+            .iter()
+            .filter(|(_, session)| session.uid == uid && ended.contains(&session.login.as_str()))
+            .map(|(token, _)| token.clone())
+            .collect()
+    }
+}
+
+/// The loaded settings
+pub fn settings() -> &'static SessionSettings {
+    &SESSIONS
+}
+
+/// Characters kept of a user agent or label
+pub const MAX_CLIENT_FIELD: usize = 256;
+
+/// What a login told of the client it was made from
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+    /// A name the user gave the session, like "Work laptop"
+    pub label: Option<String>,
+}
+
+impl ClientInfo {
+    /// Blank fields are dropped and long ones cut at [`MAX_CLIENT_FIELD`]
+    pub fn new(ip: Option<IpAddr>, user_agent: Option<&str>, label: Option<&str>) -> Self {
+        let field = |text: Option<&str>| {
+            text.map(|text| text.trim().chars().filter(|c| !c.is_control()).take(MAX_CLIENT_FIELD).collect::<String>())
+                .filter(|text| !text.is_empty())
+        };
+        Self { ip, user_agent: field(user_agent), label: field(label) }
+    }
+
+    pub fn from_ip(ip: Option<IpAddr>) -> Self {
+        Self { ip, ..Self::default() }
+    }
+
+    /// Set `ip`, `user_agent` and `label` on `value`, those known
+    pub fn add_to(&self, value: &mut Value) {
+        if let Some(ip) = self.ip {
+            value.set("ip", ip.to_string());
+        }
+        if let Some(user_agent) = &self.user_agent {
+            value.set("user_agent", user_agent.as_str());
+        }
+        if let Some(label) = &self.label {
+            value.set("label", label.as_str());
+        }
+    }
+}
+
+/// A token that holds, as listed to its account
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionInfo {
+    pub token: String,
+    pub expires: u64,
+    pub scopes: Scopes,
+    /// Unix time of the login it comes from
+    pub started: u64,
+    pub client: ClientInfo,
+}
+
+/// A token issued to an account
+#[derive(Debug)]
+pub struct Session {
+    pub uid: u32,
+    /// Unix time the token expires
+    pub expires: u64,
+    pub scopes: Scopes,
+    /// The login the token comes from, shared by the tokens refreshed from it
+    pub login: String,
+    /// Unix time of that login
+    pub started: u64,
+    /// The client of that login
+    pub client: ClientInfo,
+    last_used: AtomicU64,
+    /// Unix time the user last gave their password or second factor
+    authenticated: AtomicU64,
+}
+
+impl Session {
+    /// A token of the login `login`, started at `started`, issued at `now`
+    pub fn new(uid: u32, expires: u64, scopes: Scopes, login: String, started: u64, now: u64) -> Self {
+        Self {
+            uid,
+            expires,
+            scopes,
+            login,
+            started,
+            client: ClientInfo::default(),
+            last_used: AtomicU64::new(now),
+            authenticated: AtomicU64::new(started),
+        }
+    }
+
+    /// The token issued to `client`
+    pub fn with_client(self, client: ClientInfo) -> Self {
+        Self { client, ..self }
+    }
+
+    /// The same token refreshed as `expires` at `now`
+    pub fn refreshed(&self, expires: u64, now: u64) -> Self {
+        let session = Self::new(self.uid, expires, self.scopes.clone(), self.login.clone(), self.started, now).with_client(self.client.clone());
+        session.reauthenticate(self.authenticated());
+        session
+    }
+
+    /// Unix time the token was last used
+    pub fn last_used(&self) -> u64 {
+        self.last_used.load(Ordering::Relaxed)
+    }
+
+    /// Mark the token used at `now`
+    pub fn touch(&self, now: u64) {
+        self.last_used.fetch_max(now, Ordering::Relaxed);
+    }
+
+    /// Unix time the user last proved who they are with it, see `super::reauth`
+    pub fn authenticated(&self) -> u64 {
+        self.authenticated.load(Ordering::Relaxed)
+    }
+
+    /// Note the user proved who they are again at `now`
+    pub fn reauthenticate(&self, now: u64) {
+        self.authenticated.fetch_max(now, Ordering::Relaxed);
+    }
+
+    /// How it is listed under `token`
+    pub fn info(&self, token: &str) -> SessionInfo {
+        SessionInfo { token: token.to_string(), expires: self.expires, scopes: self.scopes.clone(), started: self.started, client: self.client.clone() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(uid: u32, login: &str, started: u64) -> Session {
+        Session::new(uid, started + 3600, Scopes::All, login.to_string(), started, started)
+    }
+
+    #[test]
+    fn sessions_end_when_idle_or_too_old() {
+        let settings = SessionSettings::from_value(&object!({ idle_minutes: 30, max_age_hours: 2, max_per_user: "3" }));
+        assert_eq!(settings, SessionSettings { idle_minutes: 30, max_age_hours: 2, max_per_user: 0, sliding_minutes: 0 });
+
+        let mut refreshed = session(1, "a", 1000);
+        assert!(settings.holds(&refreshed, 1000 + 29 * 60));
+        assert!(!settings.holds(&refreshed, 1000 + 30 * 60));
+        refreshed.touch(1000 + 20 * 60);
+        assert!(settings.holds(&refreshed, 1000 + 49 * 60));
+
+        // Refreshing keeps the start of the login
+        refreshed.expires = 1000 + 3 * 3600;
+        refreshed.touch(1000 + 2 * 3600 - 1);
+        assert!(settings.holds(&refreshed, 1000 + 2 * 3600 - 1));
+        assert!(!settings.holds(&refreshed, 1000 + 2 * 3600));
+        assert!(SessionSettings::default().holds(&refreshed, 1000 + 2 * 3600));
+    }
+
+    #[test]
+    fn the_oldest_login_is_signed_out() {
+        let settings = SessionSettings { max_per_user: 2, ..SessionSettings::default() };
+        let mut sessions = HashMap::new();
+        sessions.insert("first".to_string(), session(1, "a", 1000));
+        sessions.insert("first-refreshed".to_string(), session(1, "a", 1000));
+        sessions.insert("second".to_string(), session(1, "b", 1100));
+        sessions.insert("elsewhere".to_string(), session(2, "c", 900));
+
+        let mut evicted = settings.evicted(&sessions, 1, 1200);
+        evicted.sort();
+        assert_eq!(evicted, vec!["first".to_string(), "first-refreshed".to_string()]);
+        assert!(settings.evicted(&sessions, 2, 1200).is_empty());
+        assert!(SessionSettings::default().evicted(&sessions, 1, 1200).is_empty());
+    }
+
+    #[test]
+    fn refreshes_keep_the_client_of_the_login() {
+        let client = ClientInfo::new("203.0.113.7".parse().ok(), Some(" Mozilla/5.0\n(X11) "), Some("  "));
+        assert_eq!(client, ClientInfo { ip: "203.0.113.7".parse().ok(), user_agent: Some("Mozilla/5.0(X11)".into()), label: None });
+        assert_eq!(ClientInfo::new(None, None, Some(&"x".repeat(1000))).label.map(|label| label.len()), Some(MAX_CLIENT_FIELD));
+
+        let refreshed = session(1, "a", 1000).with_client(client.clone()).refreshed(5000, 2000);
+        let info = refreshed.info("t");
+        assert_eq!((info.started, info.expires, info.client), (1000, 5000, client));
+        let mut value = Value::new_dict();
+        refreshed.client.add_to(&mut value);
+        assert_eq!((value.get("ip").string(), value.get("label")), ("203.0.113.7".to_string(), &Value::None));
+    }
+
+    #[test]
+    fn sliding_tokens_last_while_used_up_to_the_login_age() {
+        let settings = SessionSettings::from_value(&object!({ max_age_hours: 3, sliding_minutes: 60 }));
+        assert_eq!(settings.sliding_minutes, 60);
+        let used = session(1, "a", 1000);
+        assert!(!SessionSettings::default().holds(&used, 1000 + 3600));
+
+        // Each use, every 50 minutes, moves the expiry on
+        for now in [4000, 7000, 10_000] {
+            assert!(settings.holds(&used, now));
+            used.touch(now);
+        }
+        assert_eq!(settings.expires(&used), 10_000 + 3600);
+        assert_eq!(settings.held_until(&used, 10_000), 1000 + 3 * 3600);
+        assert!(!settings.holds(&used, 1000 + 3 * 3600));
+
+        // Left unused, it expires an hour after its last use
+        let unused = session(1, "b", 1000);
+        unused.touch(1500);
+        assert!(settings.holds(&unused, 1500 + 3599));
+        assert!(!settings.holds(&unused, 1500 + 3600));
+    }
+}
@@ -0,0 +1,92 @@
+//! stats.rs
+//!
+//! Hourly counts of login attempts over the last day, kept by the
+//! `AuthManager` for the security dashboard of the admin panel. Counts live
+//! in memory only and start over with the process.
+
+/// Number of hourly buckets kept
+pub const HOURS: usize = 24;
+
+/// How a login attempt ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Succeeded,
+    /// Wrong password, or an unknown or deactivated account
+    Failed,
+    /// Refused by a rule of `super::rules`
+    Blocked,
+}
+
+/// The login attempts of one hour
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Hour {
+    /// Unix time the hour starts at
+    pub start: u64,
+    pub succeeded: u32,
+    pub failed: u32,
+    pub blocked: u32,
+}
+
+/// Login attempts of the last [`HOURS`] hours
+#[derive(Debug, Clone, Default)]
+pub struct LoginStats {
+    hours: [Hour; HOURS],
+}
+
+impl LoginStats {
+    /// Count an attempt ending in `outcome` at `now`
+    pub fn record(&mut self, outcome: Outcome, now: u64) {
+        let start = now - now % 3600;
+        let hour = &mut self.hours[(start / 3600) as usize % HOURS];
+        if hour.start > start {
+            return;
+        }
+        if hour.start != start {
+            *hour = Hour { start, ..Hour::default() };
+        }
+        match outcome {
+            Outcome::Succeeded => hour.succeeded += 1,
+            Outcome::Failed => hour.failed += 1,
+            Outcome::Blocked => hour.blocked += 1,
+        }
+    }
+
+    /// The last [`HOURS`] hours up to `now`, oldest first; hours without
+    /// attempts are included with zero counts
+    pub fn last_day(&self, now: u64) -> Vec<Hour> {
+        let current = now - now % 3600;
+        (0..HOURS as u64)
+            .rev()
+            .map(|ago| current.saturating_sub(ago * 3600))
+            .map(|start| {
+                let hour = self.hours[(start / 3600) as usize % HOURS];
+                if hour.start == start { hour } else { Hour { start, ..Hour::default() } }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attempts_are_counted_per_hour_for_a_day() {
+        let mut stats = LoginStats::default();
+        let now = 1_000 * 3600 + 10;
+        stats.record(Outcome::Failed, now - 3600);
+        stats.record(Outcome::Failed, now);
+        stats.record(Outcome::Succeeded, now);
+        stats.record(Outcome::Blocked, now - 25 * 3600);
+
+        let day = stats.last_day(now);
+        assert_eq!(day.len(), HOURS);
+        assert_eq!(day[HOURS - 1], Hour { start: now - 10, succeeded: 1, failed: 1, blocked: 0 });
+        assert_eq!(day[HOURS - 2].failed, 1);
+        assert_eq!(day.iter().map(|hour| hour.blocked).sum::<u32>(), 0);
+
+        // The bucket of an hour a day back is reused
+        stats.record(Outcome::Blocked, now + 23 * 3600);
+        assert_eq!(stats.last_day(now + 23 * 3600)[HOURS - 1].failed, 0);
+    }
+}
@@ -0,0 +1,171 @@
+//! locale_urls.rs
+//!
+//! Language prefixes in URLs, so that each language of a page has an
+//! address of its own for search engines: `/zh/user/login` is the login
+//! page in Chinese. Off by default; `programfiles/op/locale_urls.json`
+//! turns it on:
+//!
+//! ```json
+//! { "enabled": true, "base_url": "https://example.com" }
+//! ```
+//!
+//! A request whose first segment is a supported language is answered by
+//! the endpoint of the rest of the path, in that language, ahead of the
+//! `?lang=` query, cookie and `Accept-Language` of `crate::op::lang`. Pages
+//! get `pageprop.canonical` and `pageprop.alternates` (the page in each
+//! language, and `x-default` without a prefix) for their `<link>` tags,
+//! absolute when `base_url` is set, and `/op/lang/<lang>` sends the user
+//! to the same page in the other language.
+//!
+//! The routes do not change: the prefix is taken off before the endpoint
+//! runs, so `req.path()` is `/user/login` there too.
+
+use hotaru::http::*;
+use hotaru::prelude::*;
+
+use crate::op::{self, APP};
+
+static LOCALE_URLS: Lazy<LocaleUrlSettings> = Lazy::new(|| {
+    let path = op::programfiles().join("op/locale_urls.json");
+    LocaleUrlSettings::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None))
+});
+
+/// The parsed content of `locale_urls.json`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LocaleUrlSettings {
+    pub enabled: bool,
+    /// The site address put before canonical links, without a trailing slash
+    pub base_url: String,
+}
+
+impl LocaleUrlSettings {
+    pub fn from_value(value: &Value) -> Self {
+        Self {
+            enabled: value.get("enabled").boolean(),
+            base_url: value.get("base_url").string().trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+/// The loaded settings
+pub fn settings() -> &'static LocaleUrlSettings {
+    &LOCALE_URLS
+}
+
+/// The language of the prefix the request came with, set by [`LocalePrefix`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathLang(pub String);
+
+/// `url` split into a language of `supported` in its first segment and the
+/// rest, query included; `/zh` alone is the home page
+pub fn split<'a>(url: &'a str, supported: &[String]) -> Option<(&'a str, String)> {
+    let rest = url.strip_prefix('/')?;
+    let end = rest.find(['/', '?']).unwrap_or(rest.len());
+    let (lang, rest) = rest.split_at(end);
+    if !supported.iter().any(|supported| supported == lang) {
+        return None;
+    }
+    Some((lang, if rest.starts_with('/') { rest.to_string() } else { format!("/{}", rest) }))
+}
+
+/// `path` in `lang`: `/zh/user/login` for `/user/login`
+pub fn localized(path: &str, lang: &str) -> String {
+    format!("/{}{}", lang, path)
+}
+
+/// `url` without a language prefix of `supported`
+pub fn unprefixed(url: &str, supported: &[String]) -> String {
+    split(url, supported).map_or_else(|| url.to_string(), |(_, rest)| rest)
+}
+
+/// `pageprop.canonical` and `pageprop.alternates` of the page at `path` in
+/// `lang`: nothing while prefixes are off
+pub fn pageprop(path: &str, lang: &str) -> (Value, Value) {
+    let settings = settings();
+    if !settings.enabled {
+        return (Value::None, Value::List(Vec::new()));
+    }
+    let href = |path: String| format!("{}{}", settings.base_url, path);
+    let mut alternates: Vec<Value> = op::supported_langs()
+        .iter()
+        .map(|alternate| object!({ lang: alternate, href: op::escape_html(&href(localized(path, alternate))) }))
+        .collect();
+    alternates.push(object!({ lang: "x-default", href: op::escape_html(&href(path.to_string())) }));
+    (Value::from(op::escape_html(&href(localized(path, lang)))), Value::List(alternates))
+}
+
+middleware! {
+    /// Answers a request with a language prefix by the endpoint of the rest
+    /// of its path. Add it first, so the other middleware run once, on the
+    /// path without the prefix.
+    pub LocalePrefix <HTTP> {
+        if !settings().enabled || req.params.get::<PathLang>().is_some() {
+            return next(req).await;
+        }
+        let url = req.request.meta.url();
+        let Some((lang, rest)) = split(&url, &op::supported_langs()).map(|(lang, rest)| (lang.to_string(), rest)) else {
+            return next(req).await;
+        };
+        let path = rest.split('?').next().unwrap_or("/").to_string();
+        let (Some(root), Some(runtime)) = (APP.registry.url::<HTTP>(), req.runtime()) else {
+            return next(req).await;
+        };
+        let Some(endpoint) = root.walk_str(&path).await else {
+            return next(req).await;
+        };
+        let mut request = std::mem::take(&mut req.request);
+        request.meta.start_line.set_path(rest);
+        let mut inner = HttpReqCtx::new_server(runtime, endpoint.clone(), request, req.remote_addr(), req.local_addr(), req.safety.clone());
+        inner.params.set(PathLang(lang));
+        match endpoint.run(inner).await {
+            Ok(inner) => req.response = inner.response,
+            Err(err) => {
+                tracing::error!(%url, ?err, "Request with a language prefix failed");
+                req.response = text_response("500 Internal Server Error").status(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+        Ok(req)
+    }
+}
+
+endpoint! {
+    APP.url("/<lang>/<**path>"),
+
+    /// Catches the paths with a language prefix for [`LocalePrefix`]; other
+    /// paths that reach it have no page
+    pub locale_prefixed <HTTP> {
+        text_response("404 Not Found").status(StatusCode::NOT_FOUND)
+    }
+}
+
+endpoint! {
+    APP.url("/<lang>"),
+
+    /// [`locale_prefixed`] for paths of one segment, which stop here
+    pub locale_prefixed_root <HTTP> {
+        text_response("404 Not Found").status(StatusCode::NOT_FOUND)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefixes_are_split_off_supported_languages() {
+        let supported = vec!["en".to_string(), "zh".to_string()];
+        assert_eq!(split("/zh/user/login", &supported), Some(("zh", "/user/login".to_string())));
+        assert_eq!(split("/zh", &supported), Some(("zh", "/".to_string())));
+        assert_eq!(split("/zh?x=1", &supported), Some(("zh", "/?x=1".to_string())));
+        assert_eq!(split("/en/blog/?page=2", &supported), Some(("en", "/blog/?page=2".to_string())));
+        assert_eq!(split("/zhx/user", &supported), None);
+        assert_eq!(split("/user/login", &supported), None);
+        assert_eq!(unprefixed("/en/user/home", &supported), "/user/home");
+        assert_eq!(unprefixed("/ja/user/home", &supported), "/ja/user/home");
+        assert_eq!(localized("/user/home", "zh"), "/zh/user/home");
+
+        let settings = LocaleUrlSettings::from_value(&object!({ enabled: true, base_url: "https://example.com/" }));
+        assert_eq!(settings.base_url, "https://example.com");
+        assert_eq!(LocaleUrlSettings::from_value(&Value::None), LocaleUrlSettings::default());
+    }
+}
@@ -0,0 +1,116 @@
+//! logging.rs
+//!
+//! A minimal `tracing` subscriber printing events to stderr, so the
+//! `tracing::info!`/`warn!` calls of sfx are visible without the app pulling
+//! in its own subscriber. Installed by `crate::serve` when `SFX_LOG` is set
+//! (`error`, `warn`, `info`, `debug` or `trace`), which is what
+//! `sfx serve --log-level` does.
+//!
+//! Apps that install their own subscriber before calling `serve` keep it.
+
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Metadata, Subscriber};
+
+/// Environment variable holding the log level
+pub const LOG_ENV: &str = "SFX_LOG";
+
+/// Parse a level name, case-insensitively
+pub fn parse_level(level: &str) -> Option<Level> {
+    match level.trim().to_ascii_lowercase().as_str() {
+        "error" => Some(Level::ERROR),
+        "warn" | "warning" => Some(Level::WARN),
+        "info" => Some(Level::INFO),
+        "debug" => Some(Level::DEBUG),
+        "trace" => Some(Level::TRACE),
+        _ => None,
+    }
+}
+
+/// Prints every event at or above `level` as one line on stderr
+struct StderrLogger {
+    level: Level,
+    next_span: AtomicU64,
+}
+
+/// Collects the fields of an event into `message key=value ...`
+#[derive(Default)]
+struct Line {
+    message: String,
+    fields: String,
+}
+
+impl Visit for Line {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            let _ = write!(self.fields, " {}={}", field.name(), value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            let _ = write!(self.fields, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+impl Subscriber for StderrLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        *metadata.level() <= self.level
+    }
+
+    fn max_level_hint(&self) -> Option<tracing::level_filters::LevelFilter> {
+        Some(self.level.into())
+    }
+
+    fn new_span(&self, _: &Attributes<'_>) -> Id {
+        Id::from_u64(self.next_span.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn record(&self, _: &Id, _: &Record<'_>) {}
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut line = Line::default();
+        event.record(&mut line);
+        eprintln!(
+            "[{}] {}: {}{}",
+            event.metadata().level(),
+            event.metadata().target(),
+            line.message,
+            line.fields
+        );
+    }
+
+    fn enter(&self, _: &Id) {}
+
+    fn exit(&self, _: &Id) {}
+}
+
+/// Install the stderr logger at `level`. Returns `false` when a global
+/// subscriber is already set.
+pub fn init(level: Level) -> bool {
+    tracing::subscriber::set_global_default(StderrLogger {
+        level,
+        next_span: AtomicU64::new(1),
+    })
+    .is_ok()
+}
+
+/// Install the stderr logger if `SFX_LOG` names a level
+pub fn init_from_env() {
+    let Ok(value) = std::env::var(LOG_ENV) else { return };
+    match parse_level(&value) {
+        Some(level) => {
+            init(level);
+        }
+        None => eprintln!("Ignoring {}={}: not a log level", LOG_ENV, value),
+    }
+}
@@ -0,0 +1,241 @@
+//! mail.rs
+//!
+//! Mail sent to users, like the confirmation of a new email address,
+//! handed to the transport set in `programfiles/op/mail.json`:
+//!
+//! ```json
+//! {
+//!     "transport": "log",
+//!     "from": "SFX <noreply@example.com>",
+//!     "base_url": "https://example.com",
+//!     "webhook": "https://mail.example.com/send",
+//!     "secret": "env:MAIL_SECRET",
+//!     "dir": "mail/outbox"
+//! }
+//! ```
+//!
+//! - `log` writes the mail to the log, for development; the default.
+//! - `webhook` posts `{"from", "to", "subject", "text"}` to `webhook`, with
+//!   `secret` (see `crate::secrets`) as bearer token, for a mail relay;
+//!   `html` too for mails that have it.
+//! - `dir` writes each mail as an `.eml` file into `dir` (relative to
+//!   `programfiles`), for a local MTA to pick up.
+//!
+//! `base_url` is the address of the site, put before the paths of the links
+//! of a mail. Each ends with the site name, support address and legal text
+//! of `branding.json` (`crate::op::Branding`). The running server sends
+//! mails through the queue of [`queue`], retried on failure; elsewhere they
+//! are sent in the background and a failure is logged.
+//!
+//! The mails of sfx come from the templates of [`templates`], with an HTML
+//! part next to the text.
+
+use hotaru::http::*;
+use hotaru::prelude::*;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use queue::QueueSettings;
+
+pub mod queue;
+pub mod templates;
+
+static MAIL: Lazy<MailSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/mail.json");
+    MailSettings::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None))
+});
+
+/// Where mails go
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transport {
+    Log,
+    /// URL posted to
+    Webhook(String),
+    /// Directory the `.eml` files are written to
+    Dir(PathBuf),
+}
+
+/// The parsed content of `mail.json`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MailSettings {
+    pub transport: Transport,
+    pub from: String,
+    /// The site address, without a trailing slash
+    pub base_url: String,
+    /// Reference to the bearer token of the webhook, see `crate::secrets`
+    pub secret: String,
+    pub queue: QueueSettings,
+}
+
+impl Default for MailSettings {
+    fn default() -> Self {
+        Self { transport: Transport::Log, from: "noreply@localhost".to_string(), base_url: String::new(), secret: String::new(), queue: QueueSettings::default() }
+    }
+}
+
+impl MailSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let default = Self::default();
+        let transport = match value.get("transport").string().as_str() {
+            "webhook" => match value.get("webhook") {
+                Value::Str(url) if url.starts_with("http://") || url.starts_with("https://") => Transport::Webhook(url.clone()),
+                _ => {
+                    tracing::warn!("mail.json: `webhook` must be an http(s) URL, mails are logged instead");
+                    Transport::Log
+                }
+            },
+            "dir" => Transport::Dir(crate::op::programfiles().join(match value.get("dir") {
+                Value::Str(dir) if !dir.is_empty() => dir.as_str(),
+                _ => "mail/outbox",
+            })),
+            _ => Transport::Log,
+        };
+        Self {
+            transport,
+            from: match value.get("from") {
+                Value::Str(from) if !from.is_empty() => from.clone(),
+                _ => default.from,
+            },
+            base_url: value.get("base_url").string().trim_end_matches('/').to_string(),
+            secret: value.get("secret").string(),
+            queue: QueueSettings::from_value(value.get("queue")),
+        }
+    }
+
+    /// The address of `path` on the site
+    pub fn link(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+}
+
+/// The loaded mail settings
+pub fn settings() -> &'static MailSettings {
+    &MAIL
+}
+
+/// A plain text mail, with an HTML version when `html` is set
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+    pub html: Option<String>,
+}
+
+impl Mail {
+    pub fn new(to: impl Into<String>, subject: impl Into<String>, text: impl Into<String>) -> Self {
+        Self { to: to.into(), subject: subject.into(), text: text.into(), html: None }
+    }
+
+    /// The mail with `html` as its HTML version
+    pub fn with_html(mut self, html: impl Into<String>) -> Self {
+        self.html = Some(html.into());
+        self
+    }
+
+    /// The mail with `footer` below its text
+    pub fn with_footer(mut self, footer: &str) -> Self {
+        self.text = format!("{}\n\n{}\n", self.text.trim_end(), footer);
+        self
+    }
+
+    /// The mail as an RFC 5322 message from `from`
+    pub fn to_eml(&self, from: &str) -> String {
+        // Header values never span lines, whatever the caller passed
+        let line = |value: &str| value.replace(['\r', '\n'], " ");
+        let crlf = |body: &str| body.replace("\r\n", "\n").replace('\n', "\r\n");
+        let headers = format!("From: {}\r\nTo: {}\r\nSubject: {}\r\nMIME-Version: 1.0\r\n", line(from), line(&self.to), line(&self.subject));
+        match &self.html {
+            None => format!("{}Content-Type: text/plain; charset=utf-8\r\n\r\n{}\r\n", headers, crlf(&self.text)),
+            Some(html) => {
+                let boundary = format!("sfx-{}", hotaru_lib::random::random_alphanumeric_string(16));
+                format!(
+                    "{headers}Content-Type: multipart/alternative; boundary=\"{b}\"\r\n\r\n\
+                     --{b}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{text}\r\n\
+                     --{b}\r\nContent-Type: text/html; charset=utf-8\r\n\r\n{html}\r\n\
+                     --{b}--\r\n",
+                    b = boundary,
+                    text = crlf(&self.text),
+                    html = crlf(html),
+                )
+            }
+        }
+    }
+}
+
+/// Hand `mail` to the transport, as it is
+pub async fn deliver(mail: &Mail) -> Result<(), String> {
+    let settings = settings();
+    match &settings.transport {
+        Transport::Log => {
+            tracing::info!(to = %mail.to, subject = %mail.subject, text = %mail.text, "Mail (logged, no transport set)");
+            Ok(())
+        }
+        Transport::Dir(dir) => {
+            let name = format!("{}-{}.eml", crate::local_auth::names::now(), hotaru_lib::random::random_alphanumeric_string(8));
+            std::fs::create_dir_all(dir)
+                .and_then(|_| std::fs::write(dir.join(&name), mail.to_eml(&settings.from)))
+                .map_err(|err| format!("{} could not be written: {}", dir.display(), err))
+        }
+        Transport::Webhook(url) => {
+            let (origin, path) = crate::local_auth::rules::split_url(url);
+            let secret = crate::secrets::load(&settings.secret, "mail.json secret");
+            let mut body = object!({ from: &settings.from, to: &mail.to, subject: &mail.subject, text: &mail.text });
+            if let Some(html) = &mail.html {
+                body.set("html", html);
+            }
+            let mut meta = HttpMeta::new(HttpStartLine::request_post(&path), HashMap::new());
+            meta.set_content_type(HttpContentType::ApplicationJson());
+            let mut request = HttpRequest::new(meta, HttpBody::Json(body));
+            if !secret.is_empty() {
+                request = request.add_header("Authorization", format!("Bearer {}", secret));
+            }
+            let response = crate::user::fetch::send_http_request(origin.clone(), request, HttpSafety::default())
+                .await
+                .map_err(|err| format!("{}{}: {:?}", origin, path, err))?;
+            match response.meta.start_line.status_code().as_u16() {
+                200..=299 => Ok(()),
+                status => Err(format!("{}{} answered {}", origin, path, status)),
+            }
+        }
+    }
+}
+
+/// Send `mail`, through the queue while the server runs
+pub fn send(mail: Mail) {
+    let mail = mail.with_footer(&crate::op::branding().mail_footer());
+    if queue::running() {
+        return queue::push(mail);
+    }
+    tokio::spawn(async move {
+        if let Err(err) = deliver(&mail).await {
+            tracing::warn!(to = %mail.to, %err, "Mail could not be sent");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mails_are_written_as_eml() {
+        let mail = Mail::new("a@b.c\r\nBcc: x@y.z", "Hi", "Line one\nLine two");
+        let eml = mail.to_eml("SFX <noreply@example.com>");
+        assert!(eml.starts_with("From: SFX <noreply@example.com>\r\nTo: a@b.c  Bcc: x@y.z\r\nSubject: Hi\r\n"));
+        assert!(eml.ends_with("\r\n\r\nLine one\r\nLine two\r\n"));
+
+        let branding = crate::op::Branding::from_value(&object!({ site_name: "Example", support_email: "help@example.com", color: "red;}" }));
+        assert_eq!(branding.color, "pink");
+        let signed = Mail::new("a@b.c", "Hi", "Hello\n").with_footer(&branding.mail_footer());
+        let eml = signed.clone().with_html("<p>Hello</p>").to_eml("x@y.z");
+        assert!(eml.contains("Content-Type: multipart/alternative; boundary="));
+        assert!(eml.contains("Content-Type: text/html; charset=utf-8\r\n\r\n<p>Hello</p>\r\n"));
+        assert_eq!(signed.text, "Hello\n\n-- \nExample\nQuestions? Write to help@example.com\n");
+
+        let settings = MailSettings::from_value(&object!({ transport: "webhook", webhook: "ftp://x", base_url: "https://example.com/" }));
+        assert_eq!(settings.transport, Transport::Log);
+        assert_eq!(settings.link("/a?b=c"), "https://example.com/a?b=c");
+        assert_eq!(MailSettings::from_value(&Value::None), MailSettings::default());
+    }
+}
@@ -0,0 +1,387 @@
+//! queue.rs
+//!
+//! The outbox of the running server. [`super::send`] puts each mail here,
+//! kept in `programfiles/mail/queue.json` so a restart loses none, and the
+//! `mail queue` job (see `crate::modules::Job`) hands due mails to the
+//! transport every few seconds. A mail the transport refuses is tried again
+//! after `backoff` seconds, doubled at each attempt; after `retries` it is
+//! moved to the failed list, which admins see at `/admin/mail/failed` and
+//! send again from there. The settings sit under `queue` in `mail.json`:
+//!
+//! ```json
+//! { "queue": { "retries": 5, "backoff": 60, "per_minute": 60 } }
+//! ```
+//!
+//! `per_minute` keeps under the quota of the mail provider, `0` for no
+//! limit. Outside the server (the CLI, tests) mails skip the queue.
+
+use hotaru::http::*;
+use hotaru::prelude::*;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::Mail;
+use crate::APP;
+use crate::admin::check_is_admin;
+use crate::modules::Job;
+use crate::op::{into_path_l, pageprop};
+use crate::methods;
+
+/// How often the queue is looked at
+const TICK: Duration = Duration::from_secs(5);
+/// Longest wait between two attempts
+const MAX_BACKOFF: u64 = 6 * 60 * 60;
+/// Failed mails kept, the oldest dropped first
+const MAX_FAILED: usize = 500;
+
+static QUEUE: Lazy<Mutex<Queue>> = Lazy::new(|| Mutex::new(Queue::open(crate::op::programfiles().join("mail/queue.json"))));
+
+/// When the last mails were handed to the transport, for `per_minute`
+static SENT: Lazy<Mutex<VecDeque<u64>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+
+/// Whether [`start`] ran, so that mails are queued
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+fn now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// The `queue` part of `mail.json`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueSettings {
+    /// Attempts after the first before a mail counts as failed
+    pub retries: u32,
+    /// Seconds before the first retry
+    pub backoff: u64,
+    /// Mails handed to the transport a minute, `0` for no limit
+    pub per_minute: usize,
+}
+
+impl Default for QueueSettings {
+    fn default() -> Self {
+        Self { retries: 5, backoff: 60, per_minute: 60 }
+    }
+}
+
+impl QueueSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let default = Self::default();
+        let number = |key: &str, default: u64| match value.get(key) {
+            Value::Numerical(n) if *n >= 0.0 => *n as u64,
+            _ => default,
+        };
+        Self {
+            retries: number("retries", default.retries as u64).min(50) as u32,
+            backoff: number("backoff", default.backoff).max(1),
+            per_minute: number("per_minute", default.per_minute as u64) as usize,
+        }
+    }
+
+    /// Seconds to wait after the `attempts`-th failed attempt
+    pub fn delay(&self, attempts: u32) -> u64 {
+        self.backoff.saturating_mul(1 << attempts.saturating_sub(1).min(20)).min(MAX_BACKOFF)
+    }
+}
+
+/// A mail waiting in the queue or on the failed list
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Queued {
+    pub id: String,
+    pub mail: Mail,
+    /// Failed attempts so far
+    pub attempts: u32,
+    /// When the next attempt is due
+    pub next_at: u64,
+    pub queued_at: u64,
+    /// What the transport answered the last attempt
+    pub error: String,
+}
+
+impl Queued {
+    fn from_json(value: &Value) -> Option<Self> {
+        let mail = value.get("mail");
+        let to = mail.get("to").string();
+        if to.is_empty() {
+            return None;
+        }
+        let mut parsed = Mail::new(to, mail.get("subject").string(), mail.get("text").string());
+        if let Value::Str(html) = mail.get("html") {
+            parsed = parsed.with_html(html.clone());
+        }
+        Some(Self {
+            id: value.get("id").string(),
+            mail: parsed,
+            attempts: value.get("attempts").integer() as u32,
+            next_at: value.get("next_at").integer() as u64,
+            queued_at: value.get("queued_at").integer() as u64,
+            error: value.get("error").string(),
+        })
+    }
+
+    fn to_json(&self) -> Value {
+        let mut mail = object!({ to: &self.mail.to, subject: &self.mail.subject, text: &self.mail.text });
+        if let Some(html) = &self.mail.html {
+            mail.set("html", html);
+        }
+        object!({
+            id: &self.id,
+            mail: mail,
+            attempts: self.attempts,
+            next_at: self.next_at,
+            queued_at: self.queued_at,
+            error: &self.error,
+        })
+    }
+}
+
+/// The pending and failed mails, written to their file at each change
+#[derive(Debug)]
+pub struct Queue {
+    path: PathBuf,
+    pending: Vec<Queued>,
+    failed: Vec<Queued>,
+}
+
+impl Queue {
+    /// The queue kept in `path`, empty when the file is missing
+    pub fn open(path: PathBuf) -> Self {
+        let value = Value::from_jsonf(path.to_string_lossy()).unwrap_or(Value::None);
+        let list = |key: &str| match value.get(key) {
+            Value::List(entries) => entries.iter().filter_map(Queued::from_json).collect(),
+            _ => Vec::new(),
+        };
+        Self { pending: list("pending"), failed: list("failed"), path }
+    }
+
+    fn save(&self) {
+        let value = object!({
+            pending: Value::List(self.pending.iter().map(Queued::to_json).collect()),
+            failed: Value::List(self.failed.iter().map(Queued::to_json).collect()),
+        });
+        let written = self
+            .path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&self.path, value.into_json()));
+        if let Err(err) = written {
+            tracing::error!(path = %self.path.display(), %err, "Failed to write the mail queue");
+        }
+    }
+
+    /// Queue `mail`, due at once; the id of the entry
+    pub fn push(&mut self, mail: Mail, now: u64) -> String {
+        let id = hotaru_lib::random::random_alphanumeric_string(16);
+        self.pending.push(Queued { id: id.clone(), mail, attempts: 0, next_at: now, queued_at: now, error: String::new() });
+        self.save();
+        id
+    }
+
+    /// Up to `count` mails due at `now`, the oldest first
+    pub fn due(&self, now: u64, count: usize) -> Vec<Queued> {
+        self.pending.iter().filter(|queued| queued.next_at <= now).take(count).cloned().collect()
+    }
+
+    /// Drop the delivered mail `id`
+    pub fn delivered(&mut self, id: &str) {
+        self.pending.retain(|queued| queued.id != id);
+        self.save();
+    }
+
+    /// Note a failed attempt at `id`: it is retried later, or moved to the
+    /// failed list once out of retries, which is then `true`
+    pub fn attempt_failed(&mut self, id: &str, error: &str, now: u64, settings: &QueueSettings) -> bool {
+        let Some(index) = self.pending.iter().position(|queued| queued.id == id) else {
+            return false;
+        };
+        let queued = &mut self.pending[index];
+        queued.attempts += 1;
+        queued.error = error.to_string();
+        queued.next_at = now + settings.delay(queued.attempts);
+        let dead = queued.attempts > settings.retries;
+        if dead {
+            self.failed.push(self.pending.remove(index));
+            let over = self.failed.len().saturating_sub(MAX_FAILED);
+            self.failed.drain(..over);
+        }
+        self.save();
+        dead
+    }
+
+    /// The mails waiting to be sent
+    pub fn pending(&self) -> &[Queued] {
+        &self.pending
+    }
+
+    /// The mails given up on, the oldest first
+    pub fn failed(&self) -> &[Queued] {
+        &self.failed
+    }
+
+    /// Put the failed mail `id` back in the queue, due at once
+    pub fn resend(&mut self, id: &str, now: u64) -> bool {
+        let Some(index) = self.failed.iter().position(|queued| queued.id == id) else {
+            return false;
+        };
+        let mut queued = self.failed.remove(index);
+        queued.attempts = 0;
+        queued.next_at = now;
+        self.pending.push(queued);
+        self.save();
+        true
+    }
+}
+
+/// Whether mails go through the queue
+pub fn running() -> bool {
+    RUNNING.load(Ordering::SeqCst)
+}
+
+/// Queue `mail` for the job to send
+pub fn push(mail: Mail) {
+    QUEUE.lock().unwrap().push(mail, now());
+}
+
+/// How many more mails `per_minute` lets through at `now`
+fn budget(now: u64, per_minute: usize) -> usize {
+    if per_minute == 0 {
+        return usize::MAX;
+    }
+    let mut sent = SENT.lock().unwrap();
+    while sent.front().is_some_and(|at| *at + 60 <= now) {
+        sent.pop_front();
+    }
+    per_minute.saturating_sub(sent.len())
+}
+
+/// Hand the due mails to the transport, within `per_minute`
+pub async fn process() {
+    let settings = &super::settings().queue;
+    let now = now();
+    let due = QUEUE.lock().unwrap().due(now, budget(now, settings.per_minute));
+    for queued in due {
+        SENT.lock().unwrap().push_back(now);
+        match super::deliver(&queued.mail).await {
+            Ok(()) => QUEUE.lock().unwrap().delivered(&queued.id),
+            Err(err) => {
+                if QUEUE.lock().unwrap().attempt_failed(&queued.id, &err, now, settings) {
+                    tracing::error!(to = %queued.mail.to, subject = %queued.mail.subject, %err, "Mail failed, moved to /admin/mail/failed");
+                } else {
+                    tracing::warn!(to = %queued.mail.to, attempt = queued.attempts + 1, %err, "Mail failed, retrying later");
+                }
+            }
+        }
+    }
+}
+
+/// Queue the mails from now on and start the job sending them. Called by
+/// `serve`.
+pub fn start() {
+    if RUNNING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    Lazy::force(&QUEUE);
+    Job::every("mail queue", TICK, process).spawn();
+}
+
+/// `queue` for the admin page, newest first and escaped
+fn entries(queue: &[Queued]) -> Value {
+    Value::List(
+        queue
+            .iter()
+            .rev()
+            .map(|queued| {
+                object!({
+                    id: &queued.id,
+                    to: crate::op::escape_html(&queued.mail.to),
+                    subject: crate::op::escape_html(&queued.mail.subject),
+                    attempts: queued.attempts,
+                    queued_at: queued.queued_at,
+                    error: crate::op::escape_html(&queued.error),
+                })
+            })
+            .collect(),
+    )
+}
+
+endpoint! {
+    APP.url("/admin/mail/failed"),
+
+    /// The mails given up on, newest first, with the size of the queue
+    pub mail_failed <HTTP> {
+        if !check_is_admin(req).await {
+            return redirect_response("/user/unauthorized");
+        }
+        let (pending, failed) = {
+            let queue = QUEUE.lock().unwrap();
+            (queue.pending().len(), entries(queue.failed()))
+        };
+        akari_render!(
+            "admin/mail_failed.html",
+            pageprop = pageprop(req, "Failed mails", "Mails the transport refused"),
+            path = into_path_l(req, vec!["home", "admin"]),
+            failed = failed,
+            pending = pending,
+            running = running(),
+        )
+    }
+}
+
+endpoint! {
+    APP.url("/admin/mail/failed/<id>/resend"),
+
+    /// Put a failed mail back in the queue
+    ///
+    /// # Request
+    /// `POST /admin/mail/failed/<id>/resend`
+    ///
+    /// # Response
+    /// `{"success": true}`, `404` for an unknown id
+    pub mail_resend <HTTP> {
+        if !check_is_admin(req).await {
+            return json_response(object!({ success: false, message: "Unauthorized" }))
+                .status(StatusCode::UNAUTHORIZED);
+        }
+        methods!(req, POST);
+        let id = req.param("id").unwrap_or_default();
+        if QUEUE.lock().unwrap().resend(&id, now()) {
+            json_response(object!({ success: true }))
+        } else {
+            json_response(object!({ success: false, message: "No failed mail with this id" }))
+                .status(StatusCode::NOT_FOUND)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mails_are_retried_then_given_up() {
+        let path = std::env::temp_dir().join(format!("sfx-mail-queue-{}/queue.json", std::process::id()));
+        let settings = QueueSettings::from_value(&object!({ retries: 1, backoff: 30 }));
+        assert_eq!((settings.retries, settings.backoff, settings.per_minute), (1, 30, 60));
+        assert_eq!((settings.delay(1), settings.delay(3), settings.delay(40)), (30, 120, MAX_BACKOFF));
+
+        let mut queue = Queue::open(path.clone());
+        let id = queue.push(Mail::new("a@b.c", "Hi", "Hello").with_html("<p>Hello</p>"), 100);
+        queue.push(Mail::new("d@e.f", "Hi", "Hello"), 100);
+        assert_eq!(queue.due(100, 1).len(), 1);
+        assert!(!queue.attempt_failed(&id, "503", 100, &settings));
+        assert_eq!(queue.due(100, 10).len(), 1);
+        assert_eq!(queue.due(130, 10)[0].id, id);
+        assert!(queue.attempt_failed(&id, "503", 130, &settings));
+        assert_eq!((queue.pending().len(), queue.failed()[0].error.as_str()), (1, "503"));
+
+        // The file keeps both lists across restarts
+        let mut queue = Queue::open(path.clone());
+        assert_eq!(queue.failed()[0].mail.html.as_deref(), Some("<p>Hello</p>"));
+        assert!(queue.resend(&id, 200));
+        assert!(!queue.resend(&id, 200));
+        assert_eq!((queue.pending().len(), queue.failed().len()), (2, 0));
+        assert_eq!(queue.due(200, 10).len(), 2);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
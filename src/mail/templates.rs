@@ -0,0 +1,227 @@
+//! templates.rs
+//!
+//! The mails of sfx, each a pair of akari templates under `templates/mail/`:
+//! `<name>.html` and `<name>.txt`. Their wording is in `l10n.json` (or the
+//! backend of `crate::l10n`), under `mail_<name>_subject`, `mail_<name>_body`
+//! and so on, and `{var}` in a string is replaced by the variable of the
+//! mail:
+//!
+//! ```json
+//! { "mail_reset_subject": { "en": "Reset your password", "ja": "パスワードの再設定" } }
+//! ```
+//!
+//! Templates get the strings as `l10n` (all `mail_*` keys, in the language
+//! of the mail), the variables themselves, `brand` and `color` as pages do
+//! (see `crate::op::Branding`), `lang`, its `dir` (`rtl` or `ltr`) and
+//! `subject`. Values are escaped for the HTML part. Admins check how a mail
+//! looks at `/admin/mail/preview/<name>`, with the sample variables of
+//! [`sample`].
+
+use hotaru::http::*;
+use hotaru::prelude::*;
+use hotaru::TemplateManager;
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::Mail;
+use crate::APP;
+use crate::admin::check_is_admin;
+use crate::op;
+
+/// The mails sfx sends or ships ready for applications
+pub const TEMPLATES: &[&str] = &["verification", "reset", "new_device", "invite", "email_change", "digest"];
+
+/// Prefix of the l10n keys of mails
+const L10N_PREFIX: &str = "mail_";
+
+/// A mail rendered from its templates
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rendered {
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+impl Rendered {
+    /// The mail to `to`
+    pub fn to(self, to: impl Into<String>) -> Mail {
+        Mail::new(to, self.subject, self.text).with_html(self.html)
+    }
+}
+
+pub use crate::l10n::fill;
+
+/// `value` with every string in it passed through `escape`
+fn escaped(value: &Value, escape: fn(&str) -> String) -> Value {
+    match value {
+        Value::List(values) => Value::List(values.iter().map(|value| escaped(value, escape)).collect()),
+        Value::Dict(values) => {
+            let mut escaped_values = Value::new_dict();
+            for (key, value) in values {
+                escaped_values.set(key.clone(), escaped(value, escape));
+            }
+            escaped_values
+        }
+        value => Value::from(escape(&value.string())),
+    }
+}
+
+/// `vars` with `site`, the name of the site unless given
+fn with_site(vars: &Value) -> Value {
+    let mut vars = vars.clone();
+    if vars.get("site").string().is_empty() {
+        vars.set("site", op::branding().site_name.as_str());
+    }
+    vars
+}
+
+/// `strings` and `vars` filled in, escaped with `escape` when the part is HTML
+fn data(strings: &Value, vars: &Value, lang: &str, escape: fn(&str) -> String) -> HashMap<String, Value> {
+    let brand = op::branding();
+    let vars = with_site(vars);
+    let mut l10n = Value::new_dict();
+    if let Value::Dict(strings) = strings {
+        for (key, text) in strings {
+            l10n.set(key.clone(), escape(&fill(&text.string(), &vars)));
+        }
+    }
+    let mut data = HashMap::new();
+    if let Value::Dict(vars) = &vars {
+        for (key, value) in vars {
+            data.insert(key.clone(), escaped(value, escape));
+        }
+    }
+    data.insert("l10n".to_string(), l10n);
+    data.insert("lang".to_string(), Value::from(lang));
+    data.insert("dir".to_string(), Value::from(op::language(lang).dir()));
+    data.insert("color".to_string(), Value::from(brand.color.as_str()));
+    data.insert(
+        "brand".to_string(),
+        object!({
+            name: escape(&brand.site_name),
+            logo: escape(&brand.logo),
+            support_email: escape(&brand.support_email),
+            legal: escape(&brand.legal),
+        }),
+    );
+    data
+}
+
+/// Render the mail `name` from the templates in `dir`, with the l10n
+/// `strings` of its language
+pub fn render_in(dir: &Path, name: &str, strings: &Value, lang: &str, vars: &Value) -> Result<Rendered, String> {
+    if !TEMPLATES.contains(&name) {
+        return Err(format!("Unknown mail template `{}`", name));
+    }
+    let templates = TemplateManager::new(dir);
+    let mut text = data(strings, vars, lang, |text| text.to_string());
+    let subject = text.get("l10n").map(|l10n| l10n.get(format!("{}{}_subject", L10N_PREFIX, name)).string()).unwrap_or_default();
+    text.insert("subject".to_string(), Value::from(subject.as_str()));
+    let mut html = data(strings, vars, lang, op::escape_html);
+    html.insert("subject".to_string(), Value::from(op::escape_html(&subject)));
+    Ok(Rendered {
+        // Header values never span lines, whatever a translation holds
+        subject: subject.replace(['\r', '\n'], " "),
+        text: templates.render(&format!("mail/{}.txt", name), &text)?,
+        html: templates.render(&format!("mail/{}.html", name), &html)?,
+    })
+}
+
+/// Render the mail `name` in `lang` from the templates of the site
+pub fn render(name: &str, lang: &str, vars: &Value) -> Result<Rendered, String> {
+    // Fluent strings take the variables as arguments, `{ $username }`
+    let strings = crate::l10n::strings(L10N_PREFIX, lang, &with_site(vars));
+    render_in(Path::new("templates"), name, &strings, lang, vars)
+}
+
+/// Send the mail `name` to `to`, in `lang`; a template that does not render
+/// is logged and nothing is sent
+pub fn send(to: &str, name: &str, lang: &str, vars: &Value) {
+    match render(name, lang, vars) {
+        Ok(rendered) => super::send(rendered.to(to)),
+        Err(err) => tracing::error!(template = name, %err, "Mail template could not be rendered"),
+    }
+}
+
+/// Example variables of the mail `name`, for the preview
+pub fn sample(name: &str) -> Value {
+    let link = |path: &str| super::settings().link(path);
+    match name {
+        "verification" => object!({ username: "alice", email: "alice@example.com", link: link("/email/confirm?token=sample"), hours: 24 }),
+        "reset" => object!({ username: "alice", link: link("/user/reset?token=sample"), hours: 1 }),
+        "new_device" => object!({ username: "alice", ip: "203.0.113.7", location: "Tokyo, JP", link: link("/user/home/security") }),
+        "invite" => object!({ inviter: "alice", link: link("/user/register?invite=sample"), hours: 72 }),
+        "email_change" => object!({ username: "alice", email: "new@example.com" }),
+        "digest" => object!({
+            username: "alice",
+            count: 2,
+            link: link("/user/home"),
+            items: [
+                { subject: "New reply", text: "bob answered your comment on Hello world." },
+                { subject: "New follower", text: "carol follows you now." }
+            ],
+        }),
+        _ => Value::new_dict(),
+    }
+}
+
+endpoint! {
+    APP.url("/admin/mail/preview/<template>"),
+
+    /// Preview a mail template with example variables
+    ///
+    /// # Request
+    /// `GET /admin/mail/preview/<template>?lang=<lang>&format=text`
+    ///
+    /// # Response
+    /// The HTML part, or with `format=text` the subject and text part; `404`
+    /// for an unknown template
+    pub mail_preview <HTTP> {
+        if !check_is_admin(req).await {
+            return redirect_response("/user/unauthorized");
+        }
+        let name = req.param("template").unwrap_or_default();
+        if !TEMPLATES.contains(&name.as_str()) {
+            return text_response("404 Mail template not found").status(StatusCode::NOT_FOUND);
+        }
+        let lang = op::lang(req);
+        match render(&name, &lang, &sample(&name)) {
+            Ok(rendered) if req.query("format").as_deref() == Some("text") => {
+                text_response(format!("Subject: {}\n\n{}", rendered.subject, rendered.text))
+            }
+            Ok(rendered) => html_response(rendered.html),
+            Err(err) => text_response(format!("The template did not render: {}", err)).status(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mails_are_rendered_in_their_language() {
+        let strings = object!({
+            mail_hello: "Hello {username},",
+            mail_reset_subject: "Reset your {site}\npassword",
+            mail_reset_body: "Reset it within {hours} hours.",
+            mail_reset_action: "Choose a password",
+            mail_reset_ignore: "Ignore <this>.",
+        });
+        let vars = object!({ username: "<alice>", link: "https://example.com/r?a=1&b=2", hours: 1 });
+        let rendered = render_in(Path::new("default/templates"), "reset", &strings, "en", &vars).unwrap();
+        assert_eq!(rendered.subject, "Reset your SFX password");
+        assert!(rendered.text.contains("Hello <alice>,"));
+        assert!(rendered.text.contains("Reset it within 1 hours."));
+        assert!(rendered.text.contains("https://example.com/r?a=1&b=2"));
+        assert!(rendered.html.contains("Hello &lt;alice&gt;,"));
+        assert!(rendered.html.contains("https://example.com/r?a=1&amp;b=2"));
+        assert!(rendered.html.contains("Ignore &lt;this&gt;."));
+        assert!(render_in(Path::new("default/templates"), "../base", &strings, "en", &vars).is_err());
+
+        let vars = object!({ username: "alice", count: 1, link: "https://example.com", items: [{ subject: "<b>Reply</b>", text: "Hi" }] });
+        let digest = render_in(Path::new("default/templates"), "digest", &strings, "en", &vars).unwrap();
+        assert!(digest.text.contains("* <b>Reply</b>\nHi"));
+        assert!(digest.html.contains("<strong>&lt;b&gt;Reply&lt;/b&gt;</strong>"));
+    }
+}
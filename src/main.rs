@@ -1,6 +1,6 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::{Arg, ArgAction, Command};
-use include_dir::{include_dir, Dir, DirEntry};
+use include_dir::{include_dir, Dir};
 use std::{
     fs,
     path::{Path, PathBuf},
@@ -8,6 +8,9 @@ use std::{
 
 static TEMPLATE_DIR: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/default");
 
+/// Set when `sfx serve` has re-executed itself with the overrides applied
+const SERVE_EXEC_ENV: &str = "SFX_SERVE_EXEC";
+
 fn main() -> Result<()> {
     let matches = Command::new("sfx")
         .about("SFX project scaffolding tool")
@@ -21,7 +24,11 @@ fn main() -> Result<()> {
                         .short('f')
                         .action(ArgAction::SetTrue)
                         .help("Overwrite existing files"),
-                ),
+                )
+                .args(template_args())
+                .args(variable_args())
+                .args(preview_args())
+                .args(hook_args()),
         )
         .subcommand(
             Command::new("new")
@@ -37,15 +44,65 @@ fn main() -> Result<()> {
                         .index(2)
                         .default_value(".")
                         .help("Target directory (default: current)"),
+                )
+                .args(template_args())
+                .args(variable_args())
+                .args(preview_args())
+                .args(hook_args()),
+        )
+        .subcommand(
+            Command::new("serve")
+                .about("Run the built-in sfx app, or the project in the current directory")
+                .arg(
+                    Arg::new("bind")
+                        .long("bind")
+                        .short('b')
+                        .value_name("ADDR")
+                        .help("Listen address, overrides binding.txt (e.g. 0.0.0.0:8080 or unix:/run/sfx.sock)"),
+                )
+                .arg(
+                    Arg::new("programfiles")
+                        .long("programfiles")
+                        .value_name("DIR")
+                        .help("Configuration directory (default: ./programfiles)"),
+                )
+                .arg(
+                    Arg::new("log_level")
+                        .long("log-level")
+                        .value_name("LEVEL")
+                        .default_value("info")
+                        .value_parser(["error", "warn", "info", "debug", "trace"])
+                        .help("Minimum level of the log printed to stderr"),
+                )
+                .arg(
+                    Arg::new("project")
+                        .long("project")
+                        .action(ArgAction::SetTrue)
+                        .help("Build and run the project in the current directory with `cargo run`"),
                 ),
         )
+        .subcommand(cli::user::command())
+        .subcommand(cli::config::command())
+        .subcommand(cli::templates::command())
+        .subcommand(cli::upgrade::command())
+        .subcommand(cli::generate::command())
+        .subcommand(cli::migrate::command())
+        .subcommand(cli::auth::command())
+        .subcommand(cli::backup::command())
+        .subcommand(cli::seed::command())
         .get_matches();
 
     match matches.subcommand() {
         Some(("init", sub_matches)) => {
             let force = sub_matches.get_flag("force");
             let target_dir = std::env::current_dir()?;
-            create_project("my_project", &target_dir, force)?;
+            let source = cli::templates::TemplateSource::from_matches(sub_matches)?;
+            let vars = cli::placeholders::collect(sub_matches, "my_project", sub_matches.get_flag("interactive"))?;
+            let preview = Preview::from_matches(sub_matches);
+            create_project("my_project", &target_dir, &source, &vars, force, preview)?;
+            if preview == Preview::Off {
+                run_hooks(sub_matches, &target_dir, false)?;
+            }
         }
         Some(("new", sub_matches)) => {
             let program_name = sub_matches
@@ -55,15 +112,221 @@ fn main() -> Result<()> {
                 .get_one::<String>("folder")
                 .expect("has default");
             let target_dir = PathBuf::from(folder).join(program_name);
-            create_project(program_name, &target_dir, false)?;
+            let source = cli::templates::TemplateSource::from_matches(sub_matches)?;
+            let vars = cli::placeholders::collect(sub_matches, program_name, interactive(sub_matches))?;
+            let preview = Preview::from_matches(sub_matches);
+            create_project(program_name, &target_dir, &source, &vars, false, preview)?;
+            if preview == Preview::Off {
+                run_hooks(sub_matches, &target_dir, true)?;
+            }
         }
+        Some(("serve", sub_matches)) => {
+            serve(
+                sub_matches.get_one::<String>("bind"),
+                sub_matches.get_one::<String>("programfiles"),
+                sub_matches.get_one::<String>("log_level").expect("has default"),
+                sub_matches.get_flag("project"),
+            )?;
+        }
+        Some(("user", sub_matches)) => cli::user::run(sub_matches)?,
+        Some(("config", sub_matches)) => cli::config::run(sub_matches)?,
+        Some(("templates", sub_matches)) => cli::templates::run(sub_matches)?,
+        Some(("upgrade", sub_matches)) => cli::upgrade::run(sub_matches)?,
+        Some(("generate", sub_matches)) => cli::generate::run(sub_matches)?,
+        Some(("migrate", sub_matches)) => cli::migrate::run(sub_matches)?,
+        Some(("auth", sub_matches)) => cli::auth::run(sub_matches)?,
+        Some(("backup", sub_matches)) => cli::backup::run(sub_matches)?,
+        Some(("seed", sub_matches)) => cli::seed::run(sub_matches)?,
         _ => unreachable!(),
     }
 
     Ok(())
 }
 
-fn create_project(project_name: &str, target_dir: &Path, force: bool) -> Result<()> {
+/// Run an sfx server with the given overrides, passed to the library as
+/// `SFX_*` environment variables
+fn serve(
+    bind: Option<&String>,
+    programfiles: Option<&String>,
+    log_level: &str,
+    project: bool,
+) -> Result<()> {
+    let mut overrides = vec![(sfx::logging::LOG_ENV, log_level.to_string())];
+    if let Some(bind) = bind {
+        overrides.push((sfx::op::BINDING_ENV, bind.clone()));
+    }
+    if let Some(dir) = programfiles {
+        let dir = fs::canonicalize(dir)
+            .with_context(|| format!("Programfiles directory '{}' not found", dir))?;
+        overrides.push((sfx::op::PROGRAMFILES_ENV, dir.to_string_lossy().into_owned()));
+    }
+
+    if project {
+        if !Path::new("Cargo.toml").exists() {
+            anyhow::bail!("No Cargo.toml in the current directory. Run this inside a project created by `sfx new`.");
+        }
+        let status = std::process::Command::new("cargo")
+            .arg("run")
+            .envs(overrides)
+            .status()
+            .context("Failed to run cargo")?;
+        if !status.success() {
+            anyhow::bail!("The project exited with {}", status);
+        }
+        return Ok(());
+    }
+
+    // `APP` and its configuration are loaded while the routes register,
+    // before `main` runs, so the overrides only take effect in a fresh process
+    if std::env::var_os(SERVE_EXEC_ENV).is_none() {
+        use std::os::unix::process::CommandExt;
+        let err = std::process::Command::new(std::env::current_exe()?)
+            .args(std::env::args_os().skip(1))
+            .envs(overrides)
+            .env(SERVE_EXEC_ENV, "1")
+            .exec();
+        return Err(err).context("Failed to restart sfx with the overrides");
+    }
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(sfx::serve(sfx::APP.clone()))?;
+    Ok(())
+}
+
+/// `--template`, `--template-path` and `--template-git`, shared by `init` and `new`
+fn template_args() -> [Arg; 3] {
+    [
+        Arg::new("template")
+            .long("template")
+            .short('t')
+            .default_value(cli::templates::DEFAULT)
+            .value_parser(cli::templates::names())
+            .help("Built-in project template, see `sfx templates list`"),
+        Arg::new("template_path")
+            .long("template-path")
+            .value_name("DIR")
+            .conflicts_with_all(["template", "template_git"])
+            .help("Use the template in a local directory"),
+        Arg::new("template_git")
+            .long("template-git")
+            .value_name("URL[#REF]")
+            .conflicts_with("template")
+            .help("Clone the template from a git repository, optionally at a branch or tag"),
+    ]
+}
+
+/// `--var` and `--interactive`, the placeholder values of `init` and `new`
+fn variable_args() -> [Arg; 2] {
+    [
+        Arg::new("var")
+            .long("var")
+            .value_name("KEY=VALUE")
+            .action(ArgAction::Append)
+            .value_parser(cli::placeholders::parse_var)
+            .help("Set a template variable (author, version, port, default_lang, license, or your own)"),
+        Arg::new("interactive")
+            .long("interactive")
+            .short('i')
+            .action(ArgAction::SetTrue)
+            .help("Ask which subsystems to enable and prompt for the variables not given with --var (what `new` does on a terminal when given no flags)"),
+    ]
+}
+
+/// Whether `new` asks its questions: with `--interactive`, or when nothing
+/// but the name and folder is given and a person is typing
+fn interactive(matches: &clap::ArgMatches) -> bool {
+    use std::io::IsTerminal;
+    if matches.get_flag("interactive") {
+        return true;
+    }
+    let flags_given = matches
+        .ids()
+        .filter(|id| !matches!(id.as_str(), "program_name" | "folder"))
+        .any(|id| matches.value_source(id.as_str()) == Some(clap::parser::ValueSource::CommandLine));
+    !flags_given && std::io::stdin().is_terminal()
+}
+
+/// `--dry-run` and `--diff`, which show what `init` and `new` would write
+fn preview_args() -> [Arg; 2] {
+    [
+        Arg::new("dry_run")
+            .long("dry-run")
+            .action(ArgAction::SetTrue)
+            .help("List the files that would be created or overwritten, without writing anything"),
+        Arg::new("diff")
+            .long("diff")
+            .action(ArgAction::SetTrue)
+            .help("Like --dry-run, and show a unified diff for every existing file that differs"),
+    ]
+}
+
+/// `--git`, `--no-git` and `--check`, the steps after `init` and `new`
+fn hook_args() -> [Arg; 3] {
+    [
+        Arg::new("git")
+            .long("git")
+            .action(ArgAction::SetTrue)
+            .overrides_with("no_git")
+            .help("Run `git init` and make a first commit (default for `new`)"),
+        Arg::new("no_git")
+            .long("no-git")
+            .action(ArgAction::SetTrue)
+            .overrides_with("git")
+            .help("Do not create a git repository"),
+        Arg::new("check")
+            .long("check")
+            .action(ArgAction::SetTrue)
+            .help("Run `cargo check` on the generated project"),
+    ]
+}
+
+/// Run the post-generation steps selected on the command line. `git_default`
+/// says whether a repository is created without `--git`/`--no-git`.
+fn run_hooks(matches: &clap::ArgMatches, target_dir: &Path, git_default: bool) -> Result<()> {
+    let git = if matches.get_flag("git") {
+        true
+    } else {
+        git_default && !matches.get_flag("no_git")
+    };
+    if git && let Err(err) = cli::hooks::git_init(target_dir) {
+        // The project is fine without it, so this does not fail the command
+        eprintln!("Warning: {:#}", err);
+    }
+    if matches.get_flag("check") {
+        cli::hooks::cargo_check(target_dir)?;
+    }
+    Ok(())
+}
+
+/// What `create_project` does instead of writing, if anything
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Preview {
+    Off,
+    DryRun,
+    Diff,
+}
+
+impl Preview {
+    fn from_matches(matches: &clap::ArgMatches) -> Self {
+        if matches.get_flag("diff") {
+            Preview::Diff
+        } else if matches.get_flag("dry_run") {
+            Preview::DryRun
+        } else {
+            Preview::Off
+        }
+    }
+}
+
+fn create_project(
+    project_name: &str,
+    target_dir: &Path,
+    source: &cli::templates::TemplateSource,
+    vars: &cli::placeholders::Vars,
+    force: bool,
+    preview: Preview,
+) -> Result<()> {
     // Validate project name
     if !is_valid_project_name(project_name) {
         anyhow::bail!(
@@ -72,20 +335,29 @@ fn create_project(project_name: &str, target_dir: &Path, force: bool) -> Result<
         );
     }
 
+    let planned = cli::scaffold::plan(&source.files()?, target_dir, vars)?;
+    if preview != Preview::Off {
+        return cli::scaffold::print_plan(&planned, target_dir, force, preview == Preview::Diff);
+    }
+
     // Create target directory if needed
     if !target_dir.exists() {
         fs::create_dir_all(target_dir)?;
     }
 
     // Copy template files with placeholder replacement
-    process_template_files(&TEMPLATE_DIR, target_dir, project_name, force)?;
+    cli::scaffold::write(&planned, force)?;
+    cli::scaffold::record(target_dir, source, vars, &planned)?;
 
     println!(
-        "Project '{}' created at {}",
+        "Project '{}' created at {} from the {}",
         project_name,
-        target_dir.display()
+        target_dir.display(),
+        source
     );
-    println!("The default admin user is 'Admin' with password 'Aa333333' in the Local server");
+    if vars.get("local_auth").is_some_and(|enabled| enabled == "true") {
+        println!("The default admin user is 'Admin' with password 'Aa333333' in the Local server");
+    }
     println!("\nTo run:");
     println!("  cd {}", target_dir.display());
     println!("  cargo run");
@@ -96,69 +368,5 @@ fn is_valid_project_name(name: &str) -> bool {
     !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
 }
 
-fn process_template_files(
-    dir: &Dir,
-    target_dir: &Path,
-    project_name: &str,
-    force: bool,
-) -> Result<()> {
-    for entry in dir.entries() {
-        match entry {
-            DirEntry::File(file) => {
-                let relative_path = file.path();
-                let initial_target_path = target_dir.join(relative_path);
-
-                // Handle .template files by removing the .template extension
-                let target_path = if let Some(file_name) = initial_target_path.file_name() {
-                    if let Some(file_name_str) = file_name.to_str() {
-                        if file_name_str.ends_with(".template") {
-                            let new_name = file_name_str.strip_suffix(".template").unwrap();
-                            let mut new_path = initial_target_path.clone();
-                            new_path.set_file_name(new_name);
-                            new_path
-                        } else {
-                            initial_target_path
-                        }
-                    } else {
-                        initial_target_path
-                    }
-                } else {
-                    initial_target_path
-                };
-
-                // Skip if file exists and not forcing
-                if target_path.exists() && !force {
-                    continue;
-                }
-
-                // Create parent directories
-                if let Some(parent) = target_path.parent() {
-                    fs::create_dir_all(parent)?;
-                }
-
-                // Handle text vs binary files
-                if let Ok(content) = std::str::from_utf8(file.contents()) {
-                    // Text file - replace placeholders
-                    let processed_content = replace_placeholders(content, project_name);
-                    fs::write(&target_path, processed_content)?;
-                } else {
-                    // Binary file - copy directly
-                    fs::write(&target_path, file.contents())?;
-                }
-            }
-            DirEntry::Dir(subdir) => {
-                // Recursively process subdirectories
-                process_template_files(subdir, target_dir, project_name, force)?;
-            }
-        }
-    }
-    Ok(())
-}
-
-fn replace_placeholders(content: &str, project_name: &str) -> String {
-    // Convert to valid Rust crate name (underscores)
-    let crate_name = project_name.replace('-', "_");
-    content.replace("{{crate_name}}", &crate_name)
-}
-
+mod cli;
 mod resource;
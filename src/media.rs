@@ -0,0 +1,612 @@
+//! media.rs
+//!
+//! A library of uploaded files. Every upload becomes an item with an id,
+//! its owner, MIME type, size and SHA-256 checksum; the bytes are kept once
+//! per checksum, so the same file uploaded twice takes the space of one.
+//! Limits come from `programfiles/op/media.json`:
+//!
+//! ```json
+//! {
+//!     "max_bytes": 26214400,
+//!     "types": ["image/*", "application/pdf", "text/plain"],
+//!     "link_ttl": 3600,
+//!     "secret": "file:secrets/media.key"
+//! }
+//! ```
+//!
+//! Items live in `programfiles/media/index.json`, their bytes under
+//! `files/<checksum>` of the configured [`crate::storage`]. Public items are served at
+//! `GET /files/<id>`; private ones only to their owner, to admins, or with a
+//! signed temporary URL (`/files/<id>?expires=...&sig=...`, see
+//! [`signed_url`]). `secret` keys the signatures; without one a random key
+//! is made at start, and signed URLs stop working on restart.
+//!
+//! Uploads go through the malware scanner of [`crate::scan`] when one is
+//! set. Flagged files are kept as quarantined items, under `quarantine/`
+//! instead of `files/`, and never served.
+//!
+//! Admins browse, replace and delete items at `/admin/media`.
+
+use hmac::{Hmac, Mac};
+use hotaru::prelude::*;
+use hotaru::http::*;
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use crate::op::APP;
+use crate::scan::{self, OnError, ScanResult, ScanStatus};
+use crate::storage::{self, StorageError};
+use crate::ctx::SfxCtx;
+use crate::methods;
+
+static MEDIA: Lazy<MediaSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/media.json");
+    let mut settings = match Value::from_jsonf(path.to_str().unwrap()) {
+        Ok(value) => MediaSettings::from_value(&value),
+        Err(_) => MediaSettings::default(),
+    };
+    settings.secret = crate::secrets::load(&settings.secret, "media.json secret");
+    if settings.secret.is_empty() {
+        settings.secret = hotaru_lib::random::random_alphanumeric_string(32);
+    }
+    settings
+});
+
+static ITEMS: Lazy<RwLock<Vec<MediaItem>>> = Lazy::new(|| {
+    let items = match Value::from_jsonf(index_path().to_string_lossy()) {
+        Ok(Value::List(items)) => items.iter().filter_map(MediaItem::from_json).collect(),
+        _ => Vec::new(),
+    };
+    RwLock::new(items)
+});
+
+/// Held while bytes are written or released, so a release never deletes
+/// bytes an upload of the same file is about to use
+static BLOBS: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Length of item ids
+const ID_LENGTH: usize = 16;
+
+/// Types shown in the browser rather than downloaded. Anything able to run
+/// script (HTML, SVG) is always downloaded.
+const INLINE_TYPES: &[&str] = &[
+    "image/png", "image/jpeg", "image/gif", "image/webp", "image/avif",
+    "application/pdf", "text/plain", "audio/mpeg", "audio/ogg", "video/mp4", "video/webm",
+];
+
+fn index_path() -> PathBuf {
+    crate::op::programfiles().join("media/index.json")
+}
+
+fn blob_key(checksum: &str, quarantined: bool) -> String {
+    format!("{}/{}", if quarantined { "quarantine" } else { "files" }, checksum)
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// The parsed content of `media.json`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaSettings {
+    /// Largest file accepted, in bytes
+    pub max_bytes: usize,
+    /// Accepted MIME types, `image/*` for a whole family
+    pub types: Vec<String>,
+    /// Seconds signed URLs made by the admin page stay valid
+    pub link_ttl: u64,
+    /// Key of the URL signatures
+    pub secret: String,
+}
+
+impl Default for MediaSettings {
+    fn default() -> Self {
+        Self {
+            max_bytes: 25 * 1024 * 1024,
+            types: vec!["image/*".into(), "application/pdf".into(), "text/plain".into()],
+            link_ttl: 3600,
+            secret: String::new(),
+        }
+    }
+}
+
+impl MediaSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let default = Self::default();
+        let number = |key: &str, fallback: u64| match value.get(key) {
+            Value::Numerical(_) => value.get(key).integer().max(1) as u64,
+            _ => fallback,
+        };
+        Self {
+            max_bytes: number("max_bytes", default.max_bytes as u64) as usize,
+            types: match value.get("types") {
+                Value::List(types) => types.iter().map(|kind| kind.string().to_ascii_lowercase()).collect(),
+                _ => default.types,
+            },
+            link_ttl: number("link_ttl", default.link_ttl),
+            secret: value.get("secret").string(),
+        }
+    }
+
+    /// Whether files of `mime` may be uploaded
+    pub fn accepts(&self, mime: &str) -> bool {
+        self.types.iter().any(|kind| match kind.strip_suffix("/*") {
+            Some(family) => mime.split('/').next() == Some(family),
+            None => kind == mime,
+        })
+    }
+
+    /// The signature of a URL of the item `id` valid until `expires`
+    pub fn sign(&self, id: &str, expires: u64) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes()).expect("HMAC takes keys of any length");
+        mac.update(format!("{}:{}", id, expires).as_bytes());
+        hex(&mac.finalize().into_bytes())
+    }
+
+    /// Whether `sig` signs the item `id` until `expires`, and it is not yet
+    /// `now`
+    pub fn verify(&self, id: &str, expires: u64, sig: &str, now: u64) -> bool {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes()).expect("HMAC takes keys of any length");
+        mac.update(format!("{}:{}", id, expires).as_bytes());
+        now < expires && unhex(sig).is_some_and(|sig| mac.verify_slice(&sig).is_ok())
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|at| u8::from_str_radix(text.get(at..at + 2)?, 16).ok()).collect()
+}
+
+/// A file of the library
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaItem {
+    pub id: String,
+    /// The file name it was uploaded with
+    pub name: String,
+    /// The user who uploaded it (`1@local`)
+    pub owner: String,
+    pub mime: String,
+    pub size: u64,
+    /// SHA-256 of the bytes, in hex
+    pub checksum: String,
+    pub created: u64,
+    pub private: bool,
+    /// Flagged by the scanner; kept for admins to look at, never served
+    pub quarantined: bool,
+    /// The last scan of the bytes, `None` when no scanner was set
+    pub scan: Option<ScanResult>,
+}
+
+impl MediaItem {
+    pub fn from_json(value: &Value) -> Option<Self> {
+        let checksum = value.get("checksum").string();
+        if checksum.len() != 64 || !checksum.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(Self {
+            id: value.get("id").string(),
+            name: value.get("name").string(),
+            owner: value.get("owner").string(),
+            mime: value.get("mime").string(),
+            size: value.get("size").integer().max(0) as u64,
+            checksum,
+            created: value.get("created").integer().max(0) as u64,
+            private: value.get("private").boolean(),
+            quarantined: value.get("quarantined").boolean(),
+            scan: ScanResult::from_json(value.get("scan")),
+        })
+    }
+
+    pub fn into_json(&self) -> Value {
+        let mut value = object!({
+            id: &self.id,
+            name: &self.name,
+            owner: &self.owner,
+            mime: &self.mime,
+            size: self.size,
+            checksum: &self.checksum,
+            created: self.created,
+            private: self.private,
+            quarantined: self.quarantined,
+        });
+        if let Some(scan) = &self.scan {
+            value.set("scan", scan.into_json());
+        }
+        value
+    }
+
+    /// The path it is served at; private items also need a signature
+    pub fn url(&self) -> String {
+        format!("/files/{}", self.id)
+    }
+}
+
+/// Why a file could not be stored, changed or served
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MediaError {
+    TooLarge,
+    TypeNotAllowed(String),
+    NotFound,
+    /// Flagged by the scanner, with what was found
+    Infected(String),
+    /// The scanner failed and `on_error` is `reject`
+    ScanFailed,
+    Io(String),
+}
+
+impl std::fmt::Display for MediaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MediaError::TooLarge => write!(f, "The file is too large"),
+            MediaError::TypeNotAllowed(mime) => write!(f, "Files of type {} are not accepted", mime),
+            MediaError::NotFound => write!(f, "File not found"),
+            MediaError::Infected(name) => write!(f, "The file was flagged as malware ({}) and quarantined", name),
+            MediaError::ScanFailed => write!(f, "The file could not be checked for malware, try again later"),
+            MediaError::Io(err) => write!(f, "Failed to store the file: {}", err),
+        }
+    }
+}
+
+impl From<StorageError> for MediaError {
+    fn from(err: StorageError) -> Self {
+        match err {
+            StorageError::NotFound => MediaError::NotFound,
+            err => MediaError::Io(err.to_string()),
+        }
+    }
+}
+
+/// The type of `bytes` uploaded as `declared`. Images are recognized by
+/// their content, so a page cannot pass for a picture.
+pub fn detect_mime(bytes: &[u8], declared: Option<&str>) -> String {
+    if let Ok(format) = image::guess_format(bytes) {
+        return format.to_mime_type().to_string();
+    }
+    let declared = declared
+        .and_then(|declared| declared.split(';').next())
+        .map(|declared| declared.trim().to_ascii_lowercase())
+        .unwrap_or_default();
+    let token = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || ".+-_".contains(c));
+    if !declared.split_once('/').is_some_and(|(kind, sub)| token(kind) && token(sub)) {
+        return "application/octet-stream".to_string();
+    }
+    match declared.as_str() {
+        // Only recognized images are images
+        "image/png" | "image/jpeg" | "image/gif" | "image/webp" => "application/octet-stream".to_string(),
+        _ => declared,
+    }
+}
+
+/// Whether files of `mime` are shown in the browser instead of downloaded
+pub fn is_inline(mime: &str) -> bool {
+    INLINE_TYPES.contains(&mime)
+}
+
+/// Checksum of `bytes`, SHA-256 in hex
+pub fn checksum(bytes: &[u8]) -> String {
+    hex(&Sha256::digest(bytes))
+}
+
+fn in_use(checksum: &str, quarantined: bool) -> bool {
+    ITEMS.read().unwrap().iter().any(|item| item.checksum == checksum && item.quarantined == quarantined)
+}
+
+/// Keep `bytes` under their checksum unless an item already has them.
+/// The caller holds `BLOBS`.
+async fn write_blob(bytes: &[u8], mime: &str, quarantined: bool) -> Result<String, MediaError> {
+    let checksum = checksum(bytes);
+    if !in_use(&checksum, quarantined) {
+        storage::store().put(&blob_key(&checksum, quarantined), bytes.to_vec(), mime).await?;
+    }
+    Ok(checksum)
+}
+
+/// Drop the bytes of `checksum` once no item uses them
+async fn release_blob(checksum: &str, quarantined: bool) {
+    let _blobs = BLOBS.lock().await;
+    if in_use(checksum, quarantined) {
+        return;
+    }
+    if let Err(err) = storage::store().delete(&blob_key(checksum, quarantined)).await {
+        tracing::warn!(checksum, %err, "Failed to remove an unused media file");
+    }
+}
+
+fn save() -> Result<(), MediaError> {
+    let path = index_path();
+    let items = Value::List(ITEMS.read().unwrap().iter().map(MediaItem::into_json).collect());
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|err| MediaError::Io(err.to_string()))?;
+    }
+    std::fs::write(&path, items.into_json()).map_err(|err| MediaError::Io(err.to_string()))
+}
+
+/// The file name without directories or characters unsafe in headers
+fn clean_name(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name
+        .chars()
+        .filter(|c| c.is_alphanumeric() || " .-_()".contains(*c))
+        .take(128)
+        .collect();
+    if name.trim().is_empty() { "file".to_string() } else { name.trim().to_string() }
+}
+
+/// Check `bytes` of `mime` against the limits
+fn check(bytes: &[u8], mime: &str) -> Result<(), MediaError> {
+    if bytes.len() > MEDIA.max_bytes {
+        return Err(MediaError::TooLarge);
+    }
+    if !MEDIA.accepts(mime) {
+        return Err(MediaError::TypeNotAllowed(mime.to_string()));
+    }
+    Ok(())
+}
+
+/// Scan `bytes`, refusing them when the scanner failed and `on_error` is
+/// `reject`
+async fn scan(bytes: &[u8]) -> Result<Option<ScanResult>, MediaError> {
+    let result = scan::scan(bytes).await;
+    match &result {
+        Some(result) if result.status == ScanStatus::Failed && scan::settings().on_error == OnError::Reject => {
+            Err(MediaError::ScanFailed)
+        }
+        _ => Ok(result),
+    }
+}
+
+/// Add an item of `bytes`, in quarantine when `scan` flagged them
+async fn add(
+    bytes: &[u8],
+    name: &str,
+    mime: String,
+    owner: &str,
+    private: bool,
+    scan: Option<ScanResult>,
+) -> Result<MediaItem, MediaError> {
+    let quarantined = scan.as_ref().is_some_and(|scan| scan.status == ScanStatus::Infected);
+    let _blobs = BLOBS.lock().await;
+    let checksum = write_blob(bytes, &mime, quarantined).await?;
+    let item = MediaItem {
+        id: hotaru_lib::random::random_alphanumeric_string(ID_LENGTH),
+        name: clean_name(name),
+        owner: owner.to_string(),
+        mime,
+        size: bytes.len() as u64,
+        checksum,
+        created: now(),
+        private,
+        quarantined,
+        scan,
+    };
+    ITEMS.write().unwrap().push(item.clone());
+    save()?;
+    if let Some(scan) = item.scan.as_ref().filter(|_| quarantined) {
+        tracing::warn!(id = %item.id, owner, found = %scan.detail, "Media quarantined");
+        scan::notify(item.into_json());
+        return Err(MediaError::Infected(scan.detail.clone()));
+    }
+    tracing::info!(id = %item.id, owner, mime = %item.mime, size = item.size, "Media stored");
+    Ok(item)
+}
+
+/// Add `bytes`, uploaded as `name` of type `declared`, to the library
+pub async fn store(bytes: &[u8], name: &str, declared: Option<&str>, owner: &str, private: bool) -> Result<MediaItem, MediaError> {
+    let mime = detect_mime(bytes, declared);
+    check(bytes, &mime)?;
+    let scan = scan(bytes).await?;
+    add(bytes, name, mime, owner, private, scan).await
+}
+
+/// Put new bytes behind the item `id`, keeping its id, owner and privacy.
+/// Flagged bytes leave the item alone and become a quarantined item of `by`.
+pub async fn replace(id: &str, bytes: &[u8], name: &str, declared: Option<&str>, by: &str) -> Result<MediaItem, MediaError> {
+    let mime = detect_mime(bytes, declared);
+    check(bytes, &mime)?;
+    let Some(current) = get(id) else {
+        return Err(MediaError::NotFound);
+    };
+    let scan = scan(bytes).await?;
+    if scan.as_ref().is_some_and(|scan| scan.status == ScanStatus::Infected) {
+        return add(bytes, name, mime, by, current.private, scan).await;
+    }
+    let blobs = BLOBS.lock().await;
+    let checksum = write_blob(bytes, &mime, false).await?;
+    let (item, old) = {
+        let mut items = ITEMS.write().unwrap();
+        let item = items.iter_mut().find(|item| item.id == id).ok_or(MediaError::NotFound)?;
+        let old = (std::mem::replace(&mut item.checksum, checksum), item.quarantined);
+        item.name = clean_name(name);
+        item.mime = mime;
+        item.size = bytes.len() as u64;
+        item.quarantined = false;
+        item.scan = scan;
+        (item.clone(), old)
+    };
+    save()?;
+    drop(blobs);
+    release_blob(&old.0, old.1).await;
+    tracing::info!(id, by, "Media replaced");
+    Ok(item)
+}
+
+/// Remove the item `id`, and its bytes unless another item has them
+pub async fn remove(id: &str, by: &str) -> Result<MediaItem, MediaError> {
+    let item = {
+        let mut items = ITEMS.write().unwrap();
+        let at = items.iter().position(|item| item.id == id).ok_or(MediaError::NotFound)?;
+        items.remove(at)
+    };
+    save()?;
+    release_blob(&item.checksum, item.quarantined).await;
+    tracing::info!(id, by, "Media removed");
+    Ok(item)
+}
+
+/// The item `id`
+pub fn get(id: &str) -> Option<MediaItem> {
+    ITEMS.read().unwrap().iter().find(|item| item.id == id).cloned()
+}
+
+/// Every item, newest first
+pub fn list() -> Vec<MediaItem> {
+    let mut items = ITEMS.read().unwrap().clone();
+    items.reverse();
+    items
+}
+
+/// The bytes of `item`
+pub async fn read(item: &MediaItem) -> Result<Vec<u8>, MediaError> {
+    Ok(storage::store().get(&blob_key(&item.checksum, item.quarantined)).await?)
+}
+
+/// A URL of the item `id` that works for anybody for `ttl` seconds
+pub fn signed_url(id: &str, ttl: u64) -> String {
+    let expires = now() + ttl;
+    format!("/files/{}?expires={}&sig={}", id, expires, MEDIA.sign(id, expires))
+}
+
+/// The loaded media settings
+pub fn settings() -> &'static MediaSettings {
+    &MEDIA
+}
+
+/// The signed-in user of `req`, `None` for guests
+fn signed_in(req: &HttpReqCtx) -> Option<String> {
+    req.signed_in().map(|user| user.get_user_id().to_string())
+}
+
+/// The answer to a failed store or replace
+pub fn error_response(err: MediaError) -> HttpResponse {
+    let status = match err {
+        MediaError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+        MediaError::TypeNotAllowed(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        MediaError::NotFound => StatusCode::NOT_FOUND,
+        MediaError::Infected(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        MediaError::ScanFailed => StatusCode::SERVICE_UNAVAILABLE,
+        MediaError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    json_response(object!({ success: false, message: err.to_string() })).status(status)
+}
+
+endpoint! {
+    APP.url("/op/media"),
+
+    /// Upload a file to the library
+    ///
+    /// # Request
+    /// `POST /op/media`, `multipart/form-data` with the file as `file` and
+    /// optionally `private=true`, from a signed-in user
+    ///
+    /// # Response
+    /// `{"success": true, "item": {"id": ..., "mime": ..., "checksum": ...}, "url": "/files/<id>"}`,
+    /// or `{"success": false, "message": "..."}` with `401`, `413`, `415`,
+    /// `422` when the scanner flagged the file or `503` when it failed
+    pub upload_media <HTTP> {
+        methods!(req, POST);
+        let Some(owner) = signed_in(req) else {
+            return json_response(object!({ success: false, message: "Sign in to upload files" }))
+                .status(StatusCode::UNAUTHORIZED);
+        };
+        let form = req.files_or_default().await;
+        let private = matches!(form.get_text_or_default("private").as_str(), "on" | "true" | "1");
+        let Some(file) = form.get_files("file").and_then(|files| files.first()).cloned() else {
+            return json_response(object!({ success: false, message: "No file" })).status(StatusCode::BAD_REQUEST);
+        };
+        let name = file.filename().unwrap_or_default();
+        match store(file.data(), &name, file.content_type().as_deref(), &owner, private).await {
+            Ok(item) => json_response(object!({ success: true, url: item.url(), item: item.into_json() })),
+            Err(err) => error_response(err),
+        }
+    }
+}
+
+endpoint! {
+    APP.url("/files/<id>"),
+
+    /// Serve a file of the library. Private files need a valid `expires`
+    /// and `sig`, or their owner or an admin signed in.
+    pub serve_media <HTTP> {
+        let id = req.param("id").unwrap_or_default();
+        let Some(item) = get(&id).filter(|item| !item.quarantined) else {
+            return text_response("Not found").status(StatusCode::NOT_FOUND);
+        };
+        if item.private {
+            let expires = req.query("expires").and_then(|expires| expires.parse::<u64>().ok()).unwrap_or_default();
+            let sig = req.query("sig").unwrap_or_default();
+            let allowed = MEDIA.verify(&item.id, expires, &sig, now())
+                || signed_in(req).is_some_and(|user| user == item.owner)
+                || crate::admin::check_is_admin(req).await;
+            if !allowed {
+                return text_response("Not found").status(StatusCode::NOT_FOUND);
+            }
+        }
+        let bytes = match read(&item).await {
+            Ok(bytes) => bytes,
+            Err(MediaError::NotFound) => return text_response("Not found").status(StatusCode::NOT_FOUND),
+            Err(err) => {
+                tracing::error!(id = %item.id, %err, "Cannot read a media file");
+                return text_response("Storage unavailable").status(StatusCode::SERVICE_UNAVAILABLE);
+            }
+        };
+        let disposition = if is_inline(&item.mime) { "inline" } else { "attachment" };
+        normal_response(StatusCode::OK, bytes)
+            .content_type(HttpContentType::from_str(&item.mime))
+            .add_header("Content-Disposition", format!("{}; filename=\"{}\"", disposition, item.name))
+            // Whatever the file is, it runs no script on this origin
+            .add_header("Content-Security-Policy", "sandbox")
+            .add_header("Cache-Control", if item.private { "private, no-store" } else { "public, max-age=300" })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn types_are_checked_and_urls_signed() {
+        let settings = MediaSettings { secret: "k".repeat(32), ..MediaSettings::default() };
+        assert!(settings.accepts("image/png") && settings.accepts("text/plain"));
+        assert!(!settings.accepts("text/html") && !settings.accepts("imagex/png"));
+
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        assert_eq!(detect_mime(png, Some("text/html")), "image/png");
+        assert_eq!(detect_mime(b"<script>", Some("image/png")), "application/octet-stream");
+        assert_eq!(detect_mime(b"notes", Some("text/plain; charset=utf-8")), "text/plain");
+        assert_eq!(detect_mime(b"x", Some("image/<b>")), "application/octet-stream");
+        assert!(is_inline("image/png") && !is_inline("image/svg+xml") && !is_inline("text/html"));
+
+        assert_eq!(checksum(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(clean_name("../../etc/pa\"ss wd.txt"), "pass wd.txt");
+
+        let scan = ScanResult { scanner: "clamav".into(), status: ScanStatus::Infected, detail: "Eicar".into(), time: 5 };
+        let item = MediaItem {
+            id: "abc".into(),
+            name: "eicar.txt".into(),
+            owner: "1@local".into(),
+            mime: "text/plain".into(),
+            size: 68,
+            checksum: checksum(b"x"),
+            created: 5,
+            private: false,
+            quarantined: true,
+            scan: Some(scan),
+        };
+        assert_eq!(MediaItem::from_json(&item.into_json()), Some(item.clone()));
+        assert_eq!(blob_key(&item.checksum, true), format!("quarantine/{}", item.checksum));
+
+        let sig = settings.sign("abc", 1000);
+        assert!(settings.verify("abc", 1000, &sig, 999));
+        assert!(!settings.verify("abc", 1000, &sig, 1000));
+        assert!(!settings.verify("abd", 1000, &sig, 999));
+        assert!(!settings.verify("abc", 1001, &sig, 999));
+        assert!(!settings.verify("abc", 1000, "zz", 999));
+    }
+}
@@ -0,0 +1,660 @@
+//! moderation.rs
+//!
+//! Reports of content and users, and the flags moderators put on users.
+//! Read from `programfiles/op/moderation.json`:
+//!
+//! ```json
+//! {
+//!     "moderators": ["3@local"],
+//!     "reasons": ["spam", "abuse", "illegal", "other"],
+//!     "hide_after": 3,
+//!     "max_open": 20,
+//!     "muted_paths": ["/op/comments", "/op/media", "/op/images", "/report"]
+//! }
+//! ```
+//!
+//! Signed-in users report anything with `POST /report`: a `kind` chosen by
+//! the app (`comment` and `user` are known here, `listing` could be one of
+//! yours), the `target` id and one of `reasons`. Reports wait at
+//! `/admin/moderation`, open to admins and to `moderators`, grouped by
+//! target. A comment with `hide_after` open reports goes back to the
+//! moderation queue until looked at. Nobody may have more than `max_open`
+//! reports open.
+//!
+//! Moderators may mute a user, for a while or until lifted, or shadowban
+//! them. The [`ModerationGuard`] middleware refuses every write of a muted
+//! user below `muted_paths`, and leaves the [`UserFlag`] of flagged users in
+//! `req.params`, where content such as comments looks for a shadowban: the
+//! user still sees what they post, nobody else does.
+//!
+//! Reports live in `programfiles/admin_info/reports.json`, flags in
+//! `programfiles/admin_info/user_flags.json`. Every report and moderation
+//! action, comments included, is appended to
+//! `programfiles/admin_info/moderation.log`, one JSON object per line.
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use crate::comments::{self, CommentStatus};
+use crate::op::APP;
+use crate::ctx::SfxCtx;
+use crate::methods;
+
+static MODERATION: Lazy<ModerationSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/moderation.json");
+    ModerationSettings::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None))
+});
+
+static REPORTS: Lazy<RwLock<Vec<Report>>> = Lazy::new(|| {
+    let reports = match Value::from_jsonf(reports_path().to_string_lossy()) {
+        Ok(Value::List(reports)) => reports.iter().filter_map(Report::from_json).collect(),
+        _ => Vec::new(),
+    };
+    RwLock::new(reports)
+});
+
+static FLAGS: Lazy<RwLock<Vec<UserFlag>>> = Lazy::new(|| {
+    let flags = match Value::from_jsonf(flags_path().to_string_lossy()) {
+        Ok(Value::List(flags)) => flags.iter().filter_map(UserFlag::from_json).collect(),
+        _ => Vec::new(),
+    };
+    RwLock::new(flags)
+});
+
+/// Length of report ids
+const ID_LENGTH: usize = 12;
+
+/// Longest `details` of a report, in characters
+const MAX_DETAILS: usize = 1000;
+
+fn reports_path() -> PathBuf {
+    crate::op::programfiles().join("admin_info/reports.json")
+}
+
+fn flags_path() -> PathBuf {
+    crate::op::programfiles().join("admin_info/user_flags.json")
+}
+
+fn log_path() -> PathBuf {
+    crate::op::programfiles().join("admin_info/moderation.log")
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// The parsed content of `moderation.json`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModerationSettings {
+    /// Users who moderate besides the admins (`3@local`)
+    pub moderators: Vec<String>,
+    /// The reasons a report may give
+    pub reasons: Vec<String>,
+    /// Open reports that send a comment back for review, 0 for never
+    pub hide_after: usize,
+    /// Reports one user may have open
+    pub max_open: usize,
+    /// Paths below which muted users may not write
+    pub muted_paths: Vec<String>,
+}
+
+impl Default for ModerationSettings {
+    fn default() -> Self {
+        Self {
+            moderators: Vec::new(),
+            reasons: ["spam", "abuse", "illegal", "other"].map(String::from).to_vec(),
+            hide_after: 3,
+            max_open: 20,
+            muted_paths: ["/op/comments", "/op/media", "/op/images", "/report"].map(String::from).to_vec(),
+        }
+    }
+}
+
+impl ModerationSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let default = Self::default();
+        let strings = |key: &str, fallback: Vec<String>| match value.get(key) {
+            Value::List(items) => items.iter().map(|item| item.string().trim().to_string()).filter(|item| !item.is_empty()).collect(),
+            _ => fallback,
+        };
+        let number = |key: &str, fallback: usize| match value.get(key) {
+            Value::Numerical(_) => value.get(key).integer().max(0) as usize,
+            _ => fallback,
+        };
+        Self {
+            moderators: strings("moderators", default.moderators),
+            reasons: strings("reasons", default.reasons),
+            hide_after: number("hide_after", default.hide_after),
+            max_open: number("max_open", default.max_open).max(1),
+            muted_paths: strings("muted_paths", default.muted_paths),
+        }
+    }
+
+    /// Whether a muted user may not write to `path`
+    pub fn guards(&self, path: &str) -> bool {
+        self.muted_paths.iter().any(|prefix| path.starts_with(prefix.as_str()))
+    }
+}
+
+/// Where a report stands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportStatus {
+    Open,
+    /// Acted upon
+    Resolved,
+    /// Looked at and found fine
+    Dismissed,
+}
+
+impl ReportStatus {
+    pub fn from_string(status: &str) -> Option<Self> {
+        match status {
+            "open" => Some(ReportStatus::Open),
+            "resolved" => Some(ReportStatus::Resolved),
+            "dismissed" => Some(ReportStatus::Dismissed),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReportStatus::Open => "open",
+            ReportStatus::Resolved => "resolved",
+            ReportStatus::Dismissed => "dismissed",
+        }
+    }
+}
+
+/// A report of something a user found wrong
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub id: String,
+    /// What is reported: `comment`, `user`, or a kind of the app
+    pub kind: String,
+    /// The id of the reported thing (a comment id, `3@local`)
+    pub target: String,
+    pub reason: String,
+    pub details: String,
+    /// The reporting user
+    pub by: String,
+    pub created: u64,
+    pub status: ReportStatus,
+    /// The moderator who closed it, and their note
+    pub handled_by: String,
+    pub note: String,
+}
+
+impl Report {
+    pub fn from_json(value: &Value) -> Option<Self> {
+        let id = value.get("id").string();
+        let kind = value.get("kind").string();
+        if id.is_empty() || !valid_kind(&kind) {
+            return None;
+        }
+        Some(Self {
+            id,
+            kind,
+            target: value.get("target").string(),
+            reason: value.get("reason").string(),
+            details: value.get("details").string(),
+            by: value.get("by").string(),
+            created: value.get("created").integer().max(0) as u64,
+            status: ReportStatus::from_string(&value.get("status").string())?,
+            handled_by: value.get("handled_by").string(),
+            note: value.get("note").string(),
+        })
+    }
+
+    pub fn into_json(&self) -> Value {
+        object!({
+            id: &self.id,
+            kind: &self.kind,
+            target: &self.target,
+            reason: &self.reason,
+            details: &self.details,
+            by: &self.by,
+            created: self.created,
+            status: self.status.as_str(),
+            handled_by: &self.handled_by,
+            note: &self.note,
+        })
+    }
+
+    fn is_about(&self, kind: &str, target: &str) -> bool {
+        self.kind == kind && self.target == target
+    }
+}
+
+/// What moderators decided about a user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserFlag {
+    /// The user (`3@local`)
+    pub user: String,
+    pub muted: bool,
+    /// When the mute ends, `None` until lifted
+    pub muted_until: Option<u64>,
+    pub shadowbanned: bool,
+    pub reason: String,
+    /// The moderator of the last change
+    pub by: String,
+    pub since: u64,
+}
+
+impl UserFlag {
+    pub fn from_json(value: &Value) -> Option<Self> {
+        let user = value.get("user").string();
+        if user.is_empty() {
+            return None;
+        }
+        Some(Self {
+            user,
+            muted: value.get("muted").boolean(),
+            muted_until: match value.get("muted_until") {
+                Value::Numerical(_) => Some(value.get("muted_until").integer().max(0) as u64),
+                _ => None,
+            },
+            shadowbanned: value.get("shadowbanned").boolean(),
+            reason: value.get("reason").string(),
+            by: value.get("by").string(),
+            since: value.get("since").integer().max(0) as u64,
+        })
+    }
+
+    pub fn into_json(&self) -> Value {
+        let mut value = object!({
+            user: &self.user,
+            muted: self.muted,
+            shadowbanned: self.shadowbanned,
+            reason: &self.reason,
+            by: &self.by,
+            since: self.since,
+        });
+        if let Some(until) = self.muted_until {
+            value.set("muted_until", until);
+        }
+        value
+    }
+
+    /// Whether the user is muted at `now`
+    pub fn is_muted(&self, now: u64) -> bool {
+        self.muted && self.muted_until.is_none_or(|until| now < until)
+    }
+}
+
+/// Why a report or moderation action failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModerationError {
+    SignInRequired,
+    InvalidKind,
+    InvalidTarget,
+    UnknownReason,
+    NotFound,
+    /// The user already has an open report of the same thing
+    Duplicate,
+    TooManyOpen,
+    Io(String),
+}
+
+impl std::fmt::Display for ModerationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModerationError::SignInRequired => write!(f, "Sign in to report"),
+            ModerationError::InvalidKind => write!(f, "Invalid kind"),
+            ModerationError::InvalidTarget => write!(f, "Invalid target"),
+            ModerationError::UnknownReason => write!(f, "Unknown reason"),
+            ModerationError::NotFound => write!(f, "Not found"),
+            ModerationError::Duplicate => write!(f, "You already reported this"),
+            ModerationError::TooManyOpen => write!(f, "Too many open reports, wait until they are looked at"),
+            ModerationError::Io(err) => write!(f, "Failed to save: {}", err),
+        }
+    }
+}
+
+/// Whether `kind` can be a report kind: lowercase letters, digits, `-_`
+pub fn valid_kind(kind: &str) -> bool {
+    !kind.is_empty() && kind.len() <= 32 && kind.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "-_".contains(c))
+}
+
+/// Whether `target` can be the id of a reported thing
+fn valid_target(target: &str) -> bool {
+    !target.is_empty() && target.len() <= 128 && target.chars().all(|c| c.is_ascii_graphic() && !"<>\"'&".contains(c))
+}
+
+fn save_reports() -> Result<(), ModerationError> {
+    write(&reports_path(), Value::List(REPORTS.read().unwrap().iter().map(Report::into_json).collect()))
+}
+
+fn save_flags() -> Result<(), ModerationError> {
+    write(&flags_path(), Value::List(FLAGS.read().unwrap().iter().map(UserFlag::into_json).collect()))
+}
+
+fn write(path: &std::path::Path, value: Value) -> Result<(), ModerationError> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|err| ModerationError::Io(err.to_string()))?;
+    }
+    std::fs::write(path, value.into_json()).map_err(|err| ModerationError::Io(err.to_string()))
+}
+
+/// Append a moderation action on `target` of `kind` to the audit trail
+pub fn audit(action: &str, kind: &str, target: &str, by: &str, note: &str) {
+    let entry = object!({
+        time: now(),
+        action: action,
+        kind: kind,
+        target: target,
+        by: by,
+        note: note,
+    });
+    let written = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path())
+        .and_then(|mut log| writeln!(log, "{}", entry.into_json()));
+    if let Err(err) = written {
+        tracing::error!(%err, "Failed to write the moderation audit trail");
+    }
+}
+
+/// The last `count` entries of the audit trail, newest first
+pub fn history(count: usize) -> Vec<Value> {
+    let log = std::fs::read_to_string(log_path()).unwrap_or_default();
+    log.lines().rev().filter_map(|line| Value::from_json(line).ok()).take(count).collect()
+}
+
+/// File a report of `target` of `kind` by the user `by`
+pub fn report(kind: &str, target: &str, reason: &str, details: &str, by: &str) -> Result<Report, ModerationError> {
+    if !valid_kind(kind) {
+        return Err(ModerationError::InvalidKind);
+    }
+    if !valid_target(target) {
+        return Err(ModerationError::InvalidTarget);
+    }
+    if !MODERATION.reasons.iter().any(|known| known == reason) {
+        return Err(ModerationError::UnknownReason);
+    }
+    if kind == "comment" && comments::get(target).is_none_or(|comment| comment.status == CommentStatus::Rejected) {
+        return Err(ModerationError::NotFound);
+    }
+    let details: String = details.chars().filter(|c| *c == '\n' || !c.is_control()).take(MAX_DETAILS).collect();
+    let (report, flags) = {
+        let mut reports = REPORTS.write().unwrap();
+        let open: Vec<&Report> = reports.iter().filter(|report| report.status == ReportStatus::Open).collect();
+        if open.iter().any(|report| report.by == by && report.is_about(kind, target)) {
+            return Err(ModerationError::Duplicate);
+        }
+        if open.iter().filter(|report| report.by == by).count() >= MODERATION.max_open {
+            return Err(ModerationError::TooManyOpen);
+        }
+        let report = Report {
+            id: hotaru_lib::random::random_alphanumeric_string(ID_LENGTH),
+            kind: kind.to_string(),
+            target: target.to_string(),
+            reason: reason.to_string(),
+            details: details.trim().to_string(),
+            by: by.to_string(),
+            created: now(),
+            status: ReportStatus::Open,
+            handled_by: String::new(),
+            note: String::new(),
+        };
+        let flags = open.iter().filter(|open| open.is_about(kind, target)).count() + 1;
+        reports.push(report.clone());
+        (report, flags)
+    };
+    save_reports()?;
+    audit("report", kind, target, by, reason);
+    tracing::info!(kind, target, by, reason, flags, "Report filed");
+    crate::events::publish(crate::events::ReportFiled {
+        id: report.id.clone(),
+        kind: report.kind.clone(),
+        target: report.target.clone(),
+        reason: report.reason.clone(),
+        by: report.by.clone(),
+    });
+    if kind == "comment" && MODERATION.hide_after > 0 && flags >= MODERATION.hide_after {
+        let note = format!("{} reports", flags);
+        if let Err(err) = comments::hold(target, &note, "reports") {
+            tracing::warn!(target, %err, "Failed to hold a reported comment");
+        }
+    }
+    Ok(report)
+}
+
+/// Close the report `id` as `status`, along with the other open reports of
+/// the same target
+pub fn close(id: &str, status: ReportStatus, note: &str, by: &str) -> Result<Vec<Report>, ModerationError> {
+    let closed: Vec<Report> = {
+        let mut reports = REPORTS.write().unwrap();
+        let report = reports.iter().find(|report| report.id == id).cloned().ok_or(ModerationError::NotFound)?;
+        reports
+            .iter_mut()
+            .filter(|other| other.status == ReportStatus::Open && other.is_about(&report.kind, &report.target))
+            .map(|other| {
+                other.status = status;
+                other.handled_by = by.to_string();
+                other.note = note.to_string();
+                other.clone()
+            })
+            .collect()
+    };
+    save_reports()?;
+    if let Some(report) = closed.first() {
+        audit(status.as_str(), &report.kind, &report.target, by, note);
+    }
+    Ok(closed)
+}
+
+/// The reports with `status`, or all of them, newest first
+pub fn reports(status: Option<ReportStatus>) -> Vec<Report> {
+    REPORTS
+        .read()
+        .unwrap()
+        .iter()
+        .rev()
+        .filter(|report| status.is_none_or(|status| report.status == status))
+        .cloned()
+        .collect()
+}
+
+/// Change the flag of `user` with `change`, dropping it once nothing is set
+fn update_flag(user: &str, reason: &str, by: &str, change: impl FnOnce(&mut UserFlag)) -> Result<UserFlag, ModerationError> {
+    if crate::user::UserID::from_str(user).is_none_or(|id| id.is_guest()) {
+        return Err(ModerationError::InvalidTarget);
+    }
+    let flag = {
+        let mut flags = FLAGS.write().unwrap();
+        let at = match flags.iter().position(|flag| flag.user == user) {
+            Some(at) => at,
+            None => {
+                flags.push(UserFlag {
+                    user: user.to_string(),
+                    muted: false,
+                    muted_until: None,
+                    shadowbanned: false,
+                    reason: String::new(),
+                    by: String::new(),
+                    since: 0,
+                });
+                flags.len() - 1
+            }
+        };
+        let flag = &mut flags[at];
+        change(flag);
+        flag.reason = reason.to_string();
+        flag.by = by.to_string();
+        flag.since = now();
+        let flag = flag.clone();
+        if !flag.muted && !flag.shadowbanned {
+            flags.remove(at);
+        }
+        flag
+    };
+    save_flags()?;
+    Ok(flag)
+}
+
+/// Mute `user` for `duration` seconds, or until lifted
+pub fn mute(user: &str, duration: Option<u64>, reason: &str, by: &str) -> Result<UserFlag, ModerationError> {
+    let flag = update_flag(user, reason, by, |flag| {
+        flag.muted = true;
+        flag.muted_until = duration.map(|duration| now() + duration);
+    })?;
+    audit("mute", "user", user, by, reason);
+    tracing::info!(user, by, reason, ?duration, "User muted");
+    Ok(flag)
+}
+
+pub fn unmute(user: &str, reason: &str, by: &str) -> Result<UserFlag, ModerationError> {
+    let flag = update_flag(user, reason, by, |flag| {
+        flag.muted = false;
+        flag.muted_until = None;
+    })?;
+    audit("unmute", "user", user, by, reason);
+    tracing::info!(user, by, reason, "User unmuted");
+    Ok(flag)
+}
+
+/// Shadowban `user`, or lift the shadowban
+pub fn shadowban(user: &str, on: bool, reason: &str, by: &str) -> Result<UserFlag, ModerationError> {
+    let flag = update_flag(user, reason, by, |flag| flag.shadowbanned = on)?;
+    audit(if on { "shadowban" } else { "unshadowban" }, "user", user, by, reason);
+    tracing::info!(user, by, reason, on, "User shadowban changed");
+    Ok(flag)
+}
+
+/// The flag of `user`, if moderators set one
+pub fn flag(user: &str) -> Option<UserFlag> {
+    FLAGS.read().unwrap().iter().find(|flag| flag.user == user).cloned()
+}
+
+/// Every flagged user, most recently changed first
+pub fn flags() -> Vec<UserFlag> {
+    let mut flags = FLAGS.read().unwrap().clone();
+    flags.sort_by_key(|flag| std::cmp::Reverse(flag.since));
+    flags
+}
+
+/// The loaded moderation settings
+pub fn settings() -> &'static ModerationSettings {
+    &MODERATION
+}
+
+/// The signed-in user of `req`, `None` for guests
+fn signed_in(req: &HttpReqCtx) -> Option<String> {
+    req.signed_in().map(|user| user.get_user_id().to_string())
+}
+
+/// Whether `req` comes from an admin or one of the `moderators`
+pub async fn is_moderator(req: &mut HttpReqCtx) -> bool {
+    if crate::admin::check_is_admin(req).await {
+        return true;
+    }
+    signed_in(req).is_some_and(|user| MODERATION.moderators.contains(&user))
+}
+
+/// The flag [`ModerationGuard`] found on the user of `req`
+pub fn user_flag(req: &HttpReqCtx) -> Option<&UserFlag> {
+    req.params.get::<UserFlag>()
+}
+
+fn error_response(err: ModerationError) -> HttpResponse {
+    let status = match err {
+        ModerationError::SignInRequired => StatusCode::UNAUTHORIZED,
+        ModerationError::NotFound => StatusCode::NOT_FOUND,
+        ModerationError::Duplicate => StatusCode::CONFLICT,
+        ModerationError::TooManyOpen => StatusCode::TOO_MANY_REQUESTS,
+        ModerationError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::BAD_REQUEST,
+    };
+    json_response(object!({ success: false, message: err.to_string() })).status(status)
+}
+
+middleware! {
+    /// Leaves the [`UserFlag`] of a flagged user in `req.params`, and
+    /// answers `403` to writes of muted users below `muted_paths`. Add it
+    /// after `UserFetch`.
+    pub ModerationGuard <HTTP> {
+        if let Some(user) = signed_in(&req)
+            && let Some(flag) = flag(&user)
+        {
+            if flag.is_muted(now()) && !matches!(req.method(), GET | HEAD | OPTIONS) && MODERATION.guards(&req.path()) {
+                tracing::info!(%user, path = %req.path(), "Write of a muted user refused");
+                req.response = json_response(object!({ success: false, message: "Your account is muted" }))
+                    .status(StatusCode::FORBIDDEN);
+                return Ok(req)
+            }
+            req.params.set::<UserFlag>(flag);
+        }
+        next(req).await
+    }
+}
+
+endpoint! {
+    APP.url("/report"),
+
+    /// Report a comment, a user or anything else of the app
+    ///
+    /// # Request
+    /// `POST /report`, URL-encoded form or JSON with `kind` (`comment`,
+    /// `user`, or a kind of the app), `target` (its id), `reason` (one of
+    /// `reasons` in moderation.json) and optionally `details`, from a
+    /// signed-in user
+    ///
+    /// # Response
+    /// `{"success": true, "report": {"id": ..., "kind": ..., "target": ..., "status": "open"}}`,
+    /// or `{"success": false, "message": "..."}` with `400`, `401`, `404` for
+    /// an unknown comment, `409` when already reported by the user or `429`
+    /// with `max_open` reports open
+    pub report_endpoint <HTTP> {
+        methods!(req, POST);
+        let Some(by) = signed_in(req) else {
+            return error_response(ModerationError::SignInRequired);
+        };
+        let is_json = req.header_str("content-type").is_some_and(|kind| kind.contains("application/json"));
+        let fields = if is_json {
+            req.json_or_default().await.clone()
+        } else {
+            let form = req.form_or_default().await;
+            let mut fields = Value::new_dict();
+            for key in ["kind", "target", "reason", "details"] {
+                fields.set(key, form.get_or_default(key).to_string());
+            }
+            fields
+        };
+        let field = |key: &str| fields.get(key).string().trim().to_string();
+        match report(&field("kind"), &field("target"), &field("reason"), &field("details"), &by) {
+            Ok(report) => json_response(object!({ success: true, report: report.into_json() })),
+            Err(err) => error_response(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_round_trip_and_mutes_run_out() {
+        let flag = UserFlag {
+            user: "3@local".into(),
+            muted: true,
+            muted_until: Some(200),
+            shadowbanned: false,
+            reason: "spam".into(),
+            by: "1@local".into(),
+            since: 100,
+        };
+        assert_eq!(UserFlag::from_json(&flag.into_json()), Some(flag.clone()));
+        assert!(flag.is_muted(199) && !flag.is_muted(200));
+        assert!(UserFlag { muted_until: None, ..flag.clone() }.is_muted(u64::MAX));
+        assert!(!UserFlag { muted: false, ..flag }.is_muted(0));
+
+        let settings = ModerationSettings::default();
+        assert!(settings.guards("/op/comments") && settings.guards("/report"));
+        assert!(!settings.guards("/op/consent"));
+        assert!(valid_kind("listing") && !valid_kind("Listing") && !valid_kind(""));
+    }
+}
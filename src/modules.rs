@@ -0,0 +1,339 @@
+//! modules.rs
+//!
+//! Switches for the optional subsystems of sfx. Their routes register on
+//! `APP` when the crate is linked, so a site that does without one turns it
+//! off here and its paths answer `404 Not Found`. Read from
+//! `programfiles/op/modules.json`:
+//!
+//! ```json
+//! {
+//!     "local_auth": true,
+//!     "admin": false,
+//!     "blog": true
+//! }
+//! ```
+//!
+//! A missing file or key leaves the module on. `sfx new` writes the file
+//! from the subsystems picked while scaffolding.
+//!
+//! Applications and other crates plug in subsystems of their own as a
+//! [`SfxModule`], switched off the same way by their name:
+//!
+//! ```rust,ignore
+//! struct Shop;
+//!
+//! impl SfxModule for Shop {
+//!     fn name(&self) -> &'static str { "shop" }
+//!     fn prefixes(&self) -> &'static [&'static str] { &["/shop"] }
+//!     fn middleware(&self) -> Vec<Layer> { vec![sfx::modules::layer::<CartCookie>()] }
+//!     fn jobs(&self) -> Vec<Job> { vec![Job::every("expire carts", Duration::from_secs(600), expire_carts)] }
+//!     fn nav(&self) -> Vec<NavItem> { vec![NavItem::navbar("/shop", "Shop")] }
+//! }
+//!
+//! APP.module(Shop);
+//! sfx::serve(APP.clone()).await?;
+//! ```
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+use hotaru::hotaru_core::MaybeSendBoxFuture;
+use std::future::Future;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static MODULES: Lazy<ModuleSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/modules.json");
+    ModuleSettings::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None))
+});
+
+/// A subsystem that can be switched off, with the path prefixes it owns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Module {
+    pub name: &'static str,
+    pub prefixes: &'static [&'static str],
+}
+
+/// The local account store: the `/auth/*` API MainAuth clients call and the
+/// `/users/*` account endpoints, described at `/.well-known/sfx-auth`
+pub const LOCAL_AUTH: Module = Module { name: "local_auth", prefixes: &["/auth", "/users", "/.well-known/sfx-auth"] };
+/// The admin panel and its JSON API
+pub const ADMIN: Module = Module { name: "admin", prefixes: &["/admin"] };
+/// The blog at `/blog` and its editor in the admin panel
+pub const BLOG: Module = Module { name: "blog", prefixes: &["/blog", "/admin/blog"] };
+/// The forms at `/forms/<slug>` and their answers in the admin panel
+pub const FORMS: Module = Module { name: "forms", prefixes: &["/forms", "/admin/forms"] };
+/// The editor of `crate::settings` in the admin panel; the store itself
+/// stays usable from code
+pub const SETTINGS_EDITOR: Module = Module { name: "settings_editor", prefixes: &["/admin/settings"] };
+/// The GraphQL API at `/graphql`, see `crate::graphql`
+pub const GRAPHQL: Module = Module { name: "graphql", prefixes: &["/graphql"] };
+
+pub const ALL: &[Module] = &[LOCAL_AUTH, ADMIN, BLOG, FORMS, SETTINGS_EDITOR, GRAPHQL];
+
+/// The parsed content of `modules.json`
+#[derive(Debug, Clone, Default)]
+pub struct ModuleSettings {
+    disabled: Vec<String>,
+}
+
+impl ModuleSettings {
+    /// Every key set to `false` is a disabled module, so that the modules
+    /// the application registers later are switched off too
+    pub fn from_value(value: &Value) -> Self {
+        let disabled = match value {
+            Value::Dict(modules) => modules
+                .iter()
+                .filter(|(_, value)| matches!(value, Value::Boolean(false)))
+                .map(|(name, _)| name.clone())
+                .collect(),
+            _ => Vec::new(),
+        };
+        Self { disabled }
+    }
+
+    pub fn enabled(&self, module: Module) -> bool {
+        !self.disabled.iter().any(|name| name == module.name)
+    }
+
+    /// The disabled module owning `path`, if any
+    pub fn blocks(&self, path: &str) -> Option<Module> {
+        let registered: Vec<Module> = REGISTERED.read().unwrap().iter().map(|entry| entry.module).collect();
+        ALL.iter().copied().chain(registered).find(|module| {
+            !self.enabled(*module)
+                && module
+                    .prefixes
+                    .iter()
+                    .any(|prefix| path == *prefix || path.starts_with(&format!("{}/", prefix)))
+        })
+    }
+}
+
+/// Whether `module` is switched on in `modules.json`
+pub fn enabled(module: Module) -> bool {
+    MODULES.enabled(module)
+}
+
+/// A middleware of a [`SfxModule`]
+pub type Layer = Arc<dyn AsyncMiddleware<HttpReqCtx>>;
+
+pub(crate) type Next = dyn Fn(HttpReqCtx) -> MaybeSendBoxFuture<'static, Result<HttpReqCtx, <HttpReqCtx as RequestContext>::Error>>
+    + Send
+    + Sync;
+
+/// The middleware `M`, as declared with `middleware!`, for [`SfxModule::middleware`]
+pub fn layer<M: AsyncMiddleware<HttpReqCtx>>() -> Layer {
+    Arc::new(M::return_self())
+}
+
+type Task = Arc<dyn Fn() -> MaybeSendBoxFuture<'static, ()> + Send + Sync>;
+
+/// Work a [`SfxModule`] runs in the background while the server is up
+#[derive(Clone)]
+pub struct Job {
+    pub name: &'static str,
+    pub every: Duration,
+    task: Task,
+}
+
+impl Job {
+    /// Run `task` every `every`, the first time one period after the start
+    pub fn every<F, Fut>(name: &'static str, every: Duration, task: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Self { name, every, task: Arc::new(move || Box::pin(task())) }
+    }
+
+    pub(crate) fn spawn(&self) {
+        let job = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(job.every).await;
+                tracing::debug!(job = job.name, "Running module job");
+                (job.task)().await;
+            }
+        });
+    }
+}
+
+impl std::fmt::Debug for Job {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Job").field("name", &self.name).field("every", &self.every).finish()
+    }
+}
+
+/// A subsystem plugged into sfx by the application or another crate.
+///
+/// Its routes are declared with `endpoint!` on `APP` as usual and register
+/// when the crate is linked; [`SfxModule::prefixes`] names the paths they
+/// live under, so `"<name>": false` in `modules.json` answers `404` there
+/// and skips the middleware, jobs and links of the module.
+pub trait SfxModule: Send + Sync + 'static {
+    /// The key of the module in `modules.json`
+    fn name(&self) -> &'static str;
+
+    /// The path prefixes of its routes
+    fn prefixes(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Middleware run for every request after the built-in chain, in order
+    fn middleware(&self) -> Vec<Layer> {
+        Vec::new()
+    }
+
+    /// Background jobs, started with the server
+    fn jobs(&self) -> Vec<Job> {
+        Vec::new()
+    }
+
+    /// Links of the navbar and footer, see `crate::nav`
+    fn nav(&self) -> Vec<crate::nav::NavItem> {
+        Vec::new()
+    }
+
+    /// Called once the module is registered and enabled, before its jobs start
+    fn init(&self) {}
+}
+
+struct Registered {
+    module: Module,
+    middleware: Vec<Layer>,
+    jobs: Vec<Job>,
+}
+
+static REGISTERED: Lazy<RwLock<Vec<Registered>>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+/// Whether `crate::serve` started the jobs of the modules registered so far
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Plug `module` into the server. Modules registered once the server runs
+/// start their jobs at once. A second module of the same name is ignored.
+pub fn register(module: impl SfxModule) {
+    let name = module.name();
+    if ALL.iter().any(|builtin| builtin.name == name) || REGISTERED.read().unwrap().iter().any(|entry| entry.module.name == name) {
+        tracing::warn!(module = name, "A module of this name is already registered");
+        return;
+    }
+    let entry = Registered {
+        module: Module { name, prefixes: module.prefixes() },
+        middleware: module.middleware(),
+        jobs: module.jobs(),
+    };
+    if enabled(entry.module) {
+        module.nav().into_iter().for_each(crate::nav::register);
+        module.init();
+        if STARTED.load(Ordering::SeqCst) {
+            entry.jobs.iter().for_each(Job::spawn);
+        }
+    }
+    tracing::info!(module = name, "Module registered");
+    REGISTERED.write().unwrap().push(entry);
+}
+
+/// The names of the registered modules, in order
+pub fn registered() -> Vec<&'static str> {
+    REGISTERED.read().unwrap().iter().map(|entry| entry.module.name).collect()
+}
+
+/// Start the jobs of the enabled modules registered so far; called by `crate::serve`
+pub(crate) fn start() {
+    if STARTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    for entry in REGISTERED.read().unwrap().iter().filter(|entry| enabled(entry.module)) {
+        entry.jobs.iter().for_each(Job::spawn);
+    }
+}
+
+/// Registration of modules on the server itself: `APP.module(Shop)`
+pub trait ModuleRegistry {
+    fn module(&self, module: impl SfxModule) -> &Self;
+}
+
+impl ModuleRegistry for Server<TcpTransport, TokioRuntime> {
+    fn module(&self, module: impl SfxModule) -> &Self {
+        register(module);
+        self
+    }
+}
+
+/// `next` behind the middleware `layers[index..]`
+pub(crate) fn chain(layers: Arc<[Layer]>, index: usize, next: Arc<Next>) -> Box<Next> {
+    Box::new(move |req| match layers.get(index) {
+        Some(layer) => layer.handle(req, chain(layers.clone(), index + 1, next.clone())),
+        None => next(req),
+    })
+}
+
+middleware! {
+    /// Middleware answering `404 Not Found` for the paths of modules
+    /// switched off in `modules.json`
+    pub ModuleGuard <HTTP> {
+        if MODULES.blocks(&req.path()).is_some() {
+            req.response = text_response("Not Found").status(StatusCode::NOT_FOUND);
+            return Ok(req)
+        }
+        next(req).await
+    }
+}
+
+middleware! {
+    /// Middleware running the middleware of the enabled registered modules
+    pub ModuleLayers <HTTP> {
+        let layers: Vec<Layer> = REGISTERED
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|entry| enabled(entry.module))
+            .flat_map(|entry| entry.middleware.iter().cloned())
+            .collect();
+        if layers.is_empty() {
+            return next(req).await;
+        }
+        chain(layers.into(), 0, Arc::from(next))(req).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabled_modules_block_their_prefixes_only() {
+        let settings = ModuleSettings::from_value(&object!({ local_auth: true, admin: false }));
+        assert!(settings.enabled(LOCAL_AUTH));
+        assert!(!settings.enabled(ADMIN));
+        assert_eq!(settings.blocks("/admin/panel"), Some(ADMIN));
+        assert_eq!(settings.blocks("/admin"), Some(ADMIN));
+        assert_eq!(settings.blocks("/administrator"), None);
+        assert_eq!(settings.blocks("/auth/login"), None);
+        assert_eq!(ModuleSettings::from_value(&object!({ blog: false })).blocks("/admin/blog/edit"), Some(BLOG));
+        assert_eq!(ModuleSettings::from_value(&object!({ forms: false })).blocks("/forms/signup"), Some(FORMS));
+        assert_eq!(ModuleSettings::from_value(&object!({ settings_editor: false })).blocks("/admin/settings/json"), Some(SETTINGS_EDITOR));
+        assert!(ModuleSettings::from_value(&Value::None).blocks("/admin/").is_none());
+    }
+
+    struct Shop;
+
+    impl SfxModule for Shop {
+        fn name(&self) -> &'static str {
+            "test_shop"
+        }
+
+        fn prefixes(&self) -> &'static [&'static str] {
+            &["/shop"]
+        }
+    }
+
+    #[test]
+    fn registered_modules_are_switched_off_by_name() {
+        register(Shop);
+        register(Shop);
+        assert_eq!(registered().iter().filter(|name| **name == "test_shop").count(), 1);
+        let settings = ModuleSettings::from_value(&object!({ test_shop: false }));
+        assert_eq!(settings.blocks("/shop/cart").map(|module| module.name), Some("test_shop"));
+        assert!(ModuleSettings::from_value(&object!({ test_shop: true })).blocks("/shop/cart").is_none());
+    }
+}
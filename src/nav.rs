@@ -0,0 +1,239 @@
+//! nav.rs
+//!
+//! Entries of the navbar and footer added from code, next to those of
+//! `navbar.json` and `footer.json`. Modules register theirs once and
+//! `op::pageprop` merges them into `nav` and `foot` for every page:
+//!
+//! ```rust,ignore
+//! use sfx::nav::{self, NavItem, Visibility};
+//!
+//! nav::register(NavItem::navbar("/shop", "Shop").display_in("ja", "ショップ").order(20));
+//! nav::register(NavItem::navbar("/shop/orders", "My orders").visible(Visibility::SignedIn));
+//! nav::register(NavItem::footer("Help", "/shop/returns", "Returns"));
+//! ```
+//!
+//! Entries of the JSON files keep their place unless they carry an
+//! `"order"`, which they otherwise take as 0; the rest sort by order, and
+//! entries of the same order keep the order they were registered in.
+
+use hotaru::prelude::*;
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+use crate::modules;
+use crate::op::escape_html;
+use crate::user::User;
+
+/// Entries registered so far, with the built-in ones of the enabled modules
+static ITEMS: Lazy<RwLock<Vec<NavItem>>> = Lazy::new(|| RwLock::new(builtin()));
+
+fn builtin() -> Vec<NavItem> {
+    let mut items = Vec::new();
+    if modules::enabled(modules::ADMIN) {
+        items.push(NavItem::navbar("/admin/", "Admin").display_in("ja", "管理").order(100).visible(Visibility::Admins));
+    }
+    items
+}
+
+/// Who sees an entry
+#[derive(Debug, Clone, Copy)]
+pub enum Visibility {
+    Everyone,
+    Guests,
+    SignedIn,
+    /// The admins of `admins.json`
+    Admins,
+    /// Those `fn` accepts
+    When(fn(&User) -> bool),
+}
+
+impl Visibility {
+    pub fn allows(&self, user: &User) -> bool {
+        let signed_in = !user.get_user_id().is_guest();
+        match self {
+            Visibility::Everyone => true,
+            Visibility::Guests => !signed_in,
+            Visibility::SignedIn => signed_in,
+            Visibility::Admins => signed_in && crate::op::get_admin().contains(&object!(user.get_user_id().to_string())),
+            Visibility::When(accepts) => accepts(user),
+        }
+    }
+}
+
+/// Where an entry goes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Place {
+    Navbar,
+    /// The footer column of this name, added when the footer has none
+    Footer(String),
+}
+
+/// A link in the navbar or footer
+#[derive(Debug, Clone)]
+pub struct NavItem {
+    pub place: Place,
+    pub url: String,
+    /// The text shown by language, `""` for the others
+    display: BTreeMap<String, String>,
+    pub order: i32,
+    pub visibility: Visibility,
+}
+
+impl NavItem {
+    fn new(place: Place, url: &str, display: &str) -> Self {
+        Self {
+            place,
+            url: url.to_string(),
+            display: BTreeMap::from([(String::new(), display.to_string())]),
+            order: 0,
+            visibility: Visibility::Everyone,
+        }
+    }
+
+    /// A link of the navbar
+    pub fn navbar(url: &str, display: &str) -> Self {
+        Self::new(Place::Navbar, url, display)
+    }
+
+    /// A link in the footer column `column`
+    pub fn footer(column: &str, url: &str, display: &str) -> Self {
+        Self::new(Place::Footer(column.to_string()), url, display)
+    }
+
+    /// The text shown to the pages in `lang`
+    pub fn display_in(mut self, lang: &str, display: &str) -> Self {
+        self.display.insert(lang.to_string(), display.to_string());
+        self
+    }
+
+    pub fn order(mut self, order: i32) -> Self {
+        self.order = order;
+        self
+    }
+
+    pub fn visible(mut self, visibility: Visibility) -> Self {
+        self.visibility = visibility;
+        self
+    }
+
+    /// The text shown to the pages in `lang`
+    pub fn display(&self, lang: &str) -> &str {
+        self.display.get(lang).or_else(|| self.display.get("")).map(String::as_str).unwrap_or_default()
+    }
+}
+
+/// Add `item` to every page
+pub fn register(item: NavItem) {
+    ITEMS.write().unwrap().push(item);
+}
+
+/// The registered entries, in order of registration
+pub fn items() -> Vec<NavItem> {
+    ITEMS.read().unwrap().clone()
+}
+
+fn order_of(value: &Value) -> i32 {
+    match value.get("order") {
+        Value::Numerical(order) => *order as i32,
+        _ => 0,
+    }
+}
+
+/// `listed` followed by `added`, each with its order, sorted stably
+fn merge(listed: &Value, added: Vec<(i32, Value)>) -> Value {
+    let mut all: Vec<(i32, Value)> = match listed {
+        Value::List(listed) => listed.iter().map(|item| (order_of(item), item.clone())).collect(),
+        _ => Vec::new(),
+    };
+    all.extend(added);
+    all.sort_by_key(|(order, _)| *order);
+    Value::List(all.into_iter().map(|(_, item)| item).collect())
+}
+
+fn shown<'a>(items: &'a [NavItem], user: &User) -> impl Iterator<Item = &'a NavItem> {
+    items.iter().filter(move |item| item.visibility.allows(user))
+}
+
+/// The `nav` of `pageprop`: `navbar` of `navbar.json` in `lang` with the
+/// entries `user` sees
+pub fn navbar(navbar: &Value, items: &[NavItem], user: &User, lang: &str) -> Value {
+    let added: Vec<(i32, Value)> = shown(items, user)
+        .filter(|item| item.place == Place::Navbar)
+        .map(|item| (item.order, object!({ display: escape_html(item.display(lang)), url: escape_html(&item.url), is_dropdown: false })))
+        .collect();
+    if added.is_empty() {
+        return navbar.clone();
+    }
+    let mut navbar = match navbar {
+        Value::Dict(_) => navbar.clone(),
+        _ => Value::new_dict(),
+    };
+    let itemlist = merge(navbar.get("itemlist"), added);
+    navbar.set("itemlist", itemlist);
+    navbar
+}
+
+/// The `foot` of `pageprop`: `footer` of `footer.json` in `lang` with the
+/// entries `user` sees, in the columns they name
+pub fn footer(footer: &Value, items: &[NavItem], user: &User, lang: &str) -> Value {
+    let mut by_column: Vec<(String, Vec<(i32, Value)>)> = Vec::new();
+    for item in shown(items, user) {
+        let Place::Footer(column) = &item.place else {
+            continue;
+        };
+        let link = (item.order, object!({ display: escape_html(item.display(lang)), url: escape_html(&item.url) }));
+        match by_column.iter_mut().find(|(name, _)| name == column) {
+            Some((_, links)) => links.push(link),
+            None => by_column.push((column.clone(), vec![link])),
+        }
+    }
+    if by_column.is_empty() {
+        return footer.clone();
+    }
+    let mut columns = match footer.get("items") {
+        Value::List(columns) => columns.clone(),
+        _ => Vec::new(),
+    };
+    for (column, links) in by_column {
+        match columns.iter_mut().find(|listed| listed.get("name").string() == column) {
+            Some(listed) => {
+                let itemlist = merge(listed.get("itemlist"), links);
+                listed.set("itemlist", itemlist);
+            }
+            None => columns.push(object!({ name: escape_html(&column), itemlist: merge(&Value::None, links) })),
+        }
+    }
+    let mut footer = match footer {
+        Value::Dict(_) => footer.clone(),
+        _ => Value::new_dict(),
+    };
+    footer.set("items", Value::List(columns));
+    footer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user::UserID;
+
+    #[test]
+    fn entries_merge_by_order_for_those_who_see_them() {
+        let listed = object!({ name: "Site", itemlist: [{ display: "Home", url: "/" }, { display: "Last", url: "/last", order: 50 }] });
+        let items = vec![
+            NavItem::navbar("/shop", "Shop").display_in("ja", "ショップ").order(10),
+            NavItem::navbar("/orders", "Orders").visible(Visibility::SignedIn),
+            NavItem::footer("Help", "/returns", "Returns"),
+        ];
+        let guest = User::guest("local");
+        let urls = |nav: &Value| nav.get("itemlist").list().iter().map(|item| item.get("url").string()).collect::<Vec<_>>();
+
+        assert_eq!(urls(&navbar(&listed, &items, &guest, "en")), vec!["/", "/shop", "/last"]);
+        let user = User::new(UserID::new(3, "local".into()), "u".into(), "e".into(), true, true);
+        assert_eq!(urls(&navbar(&listed, &items, &user, "en")), vec!["/", "/orders", "/shop", "/last"]);
+        assert_eq!(navbar(&listed, &items, &guest, "ja").get("itemlist").idx(1).get("display").string(), "ショップ");
+
+        let foot = footer(&Value::None, &items, &guest, "en");
+        assert_eq!(foot.get("items").idx(0).get("name").string(), "Help");
+        assert_eq!(foot.get("items").idx(0).get("itemlist").idx(0).get("url").string(), "/returns");
+    }
+}
@@ -0,0 +1,346 @@
+//! notifications.rs
+//!
+//! Notification mails about what happened on the site, like a reply to a
+//! comment. The application registers its categories once at startup and
+//! notifies a local account of one:
+//!
+//! ```rust,ignore
+//! use sfx::notifications;
+//!
+//! notifications::register("replies", "Replies to your comments");
+//! notifications::notify(uid, "replies", "New reply", "Bob answered your comment on Hello.").await;
+//! ```
+//!
+//! Each user chooses in their preferences (see `crate::preferences`, shown
+//! on their home page) whether the mails come at once or in one daily
+//! digest (`mail_delivery`), their UTC offset (`timezone`) and which
+//! categories they get at all (`notify_<category>`). Digests are sent at
+//! [`DIGEST_HOUR`] in the time of the user by the `mail digests` job, and
+//! their pending items are kept in `programfiles/mail/digest.json`.
+
+use hotaru::http::*;
+use hotaru::prelude::*;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Mutex, RwLock};
+
+use crate::ctx::SfxCtx;
+use crate::local_auth::LOCAL_AUTH;
+use crate::local_auth::analyze::get_auth_token;
+use crate::local_auth::fop::LANG_KEY;
+use crate::local_auth::scope::{self, require_scope};
+use crate::mail::{self, Mail};
+use crate::modules::Job;
+use crate::op::{self, APP};
+use crate::preferences::{self, Preference, UserPrefs};
+use crate::methods;
+
+/// The preference choosing `immediate` or `daily` mails
+pub const DELIVERY_KEY: &str = "mail_delivery";
+/// The preference holding the UTC offset of the user, like `+09:00`
+pub const TIMEZONE_KEY: &str = "timezone";
+/// Local hour digests are sent at
+pub const DIGEST_HOUR: u64 = 8;
+
+/// The UTC offsets in use; users move theirs for daylight saving time
+pub const TIMEZONES: &[&str] = &[
+    "-12:00", "-11:00", "-10:00", "-09:30", "-09:00", "-08:00", "-07:00", "-06:00", "-05:00", "-04:00", "-03:30",
+    "-03:00", "-02:00", "-01:00", "+00:00", "+01:00", "+02:00", "+03:00", "+03:30", "+04:00", "+04:30", "+05:00",
+    "+05:30", "+05:45", "+06:00", "+06:30", "+07:00", "+08:00", "+08:45", "+09:00", "+09:30", "+10:00", "+10:30",
+    "+11:00", "+12:00", "+12:45", "+13:00", "+14:00",
+];
+
+/// How often due digests are looked for
+const TICK: Duration = Duration::from_secs(60);
+/// Items a digest keeps, the oldest dropped first
+const MAX_ITEMS: usize = 200;
+
+static CATEGORIES: Lazy<RwLock<Vec<Category>>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+static DIGESTS: Lazy<Mutex<Digests>> = Lazy::new(|| Mutex::new(Digests::open(op::programfiles().join("mail/digest.json"))));
+
+fn now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// A kind of notification users turn on or off
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Category {
+    /// Lowercase letters, digits and `_`
+    pub name: String,
+    /// Shown next to its switch
+    pub label: String,
+}
+
+/// The preference switching the category `name`
+pub fn toggle_key(name: &str) -> String {
+    format!("notify_{}", name)
+}
+
+/// Add the category `name`, on by default, replacing one of the same name.
+/// The first one also adds the delivery and timezone preferences.
+pub fn register(name: &str, label: &str) {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_') {
+        tracing::warn!(category = name, "Notification categories are named with a-z, 0-9 and _");
+        return;
+    }
+    let mut categories = CATEGORIES.write().unwrap();
+    if categories.is_empty() {
+        preferences::register(Preference::new(DELIVERY_KEY, "immediate".to_string()).choices(["immediate", "daily"]));
+        preferences::register(Preference::new(TIMEZONE_KEY, "+00:00".to_string()).choices(TIMEZONES.iter().copied()));
+    }
+    preferences::register(Preference::new(&toggle_key(name), true));
+    categories.retain(|category| category.name != name);
+    categories.push(Category { name: name.to_string(), label: label.to_string() });
+}
+
+/// The registered categories, in order
+pub fn categories() -> Vec<Category> {
+    CATEGORIES.read().unwrap().clone()
+}
+
+/// Minutes east of UTC of an offset like `+05:45`, `0` when unreadable
+pub fn offset_minutes(timezone: &str) -> i64 {
+    let sign = match timezone.chars().next() {
+        Some('-') => -1,
+        Some('+') => 1,
+        _ => return 0,
+    };
+    match timezone[1..].split_once(':').map(|(h, m)| (h.parse::<i64>(), m.parse::<i64>())) {
+        Some((Ok(hours), Ok(minutes))) if hours <= 14 && minutes < 60 => sign * (hours * 60 + minutes),
+        _ => 0,
+    }
+}
+
+/// The next `hour` o'clock after `now` at `offset` minutes east of UTC
+pub fn next_digest(now: u64, offset: i64, hour: u64) -> u64 {
+    let local = now as i64 + offset * 60;
+    let mut at = local - local.rem_euclid(86_400) + hour as i64 * 3600;
+    if at <= local {
+        at += 86_400;
+    }
+    (at - offset * 60) as u64
+}
+
+/// A notification waiting for the digest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Item {
+    pub category: String,
+    pub subject: String,
+    pub text: String,
+    pub at: u64,
+}
+
+impl Item {
+    fn from_json(value: &Value) -> Self {
+        Self {
+            category: value.get("category").string(),
+            subject: value.get("subject").string(),
+            text: value.get("text").string(),
+            at: value.get("at").integer().max(0) as u64,
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        object!({ category: &self.category, subject: &self.subject, text: &self.text, at: self.at })
+    }
+}
+
+/// The digests waiting to be sent, by uid, written to their file at each change
+#[derive(Debug)]
+pub struct Digests {
+    path: PathBuf,
+    /// When each is due, and its items
+    pending: BTreeMap<u32, (u64, Vec<Item>)>,
+}
+
+impl Digests {
+    /// The digests kept in `path`, none when the file is missing
+    pub fn open(path: PathBuf) -> Self {
+        let mut pending = BTreeMap::new();
+        if let Ok(Value::Dict(digests)) = Value::from_jsonf(path.to_string_lossy()) {
+            for (uid, digest) in digests {
+                let items = match digest.get("items") {
+                    Value::List(items) => items.iter().map(Item::from_json).collect(),
+                    _ => Vec::new(),
+                };
+                if let Ok(uid) = uid.parse() {
+                    pending.insert(uid, (digest.get("due").integer().max(0) as u64, items));
+                }
+            }
+        }
+        Self { path, pending }
+    }
+
+    fn save(&self) {
+        let mut digests = Value::new_dict();
+        for (uid, (due, items)) in &self.pending {
+            digests.set(uid.to_string(), object!({ due: *due, items: Value::List(items.iter().map(Item::to_json).collect()) }));
+        }
+        let written = self
+            .path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&self.path, digests.into_json()));
+        if let Err(err) = written {
+            tracing::error!(path = %self.path.display(), %err, "Failed to write the mail digests");
+        }
+    }
+
+    /// Add `item` to the digest of `uid`, which is due at `due` when it is new
+    pub fn add(&mut self, uid: u32, item: Item, due: u64) {
+        let (_, items) = self.pending.entry(uid).or_insert_with(|| (due, Vec::new()));
+        items.push(item);
+        let over = items.len().saturating_sub(MAX_ITEMS);
+        items.drain(..over);
+        self.save();
+    }
+
+    /// The items of `uid` waiting for a digest
+    pub fn items(&self, uid: u32) -> &[Item] {
+        self.pending.get(&uid).map_or(&[], |(_, items)| items)
+    }
+
+    /// Remove and return the digests due at `now`
+    pub fn take_due(&mut self, now: u64) -> Vec<(u32, Vec<Item>)> {
+        let due: Vec<u32> = self.pending.iter().filter(|(_, (due, _))| *due <= now).map(|(uid, _)| *uid).collect();
+        if due.is_empty() {
+            return Vec::new();
+        }
+        let taken = due.into_iter().filter_map(|uid| self.pending.remove(&uid).map(|(_, items)| (uid, items))).collect();
+        self.save();
+        taken
+    }
+}
+
+/// Notify the local account `uid` of something of `category`, by mail now
+/// or in their next digest, unless they turned the category off
+pub async fn notify(uid: u32, category: &str, subject: &str, text: &str) {
+    let Some(user) = LOCAL_AUTH.admin_get_user(uid).await else { return };
+    if user.email.is_empty() {
+        return;
+    }
+    let prefs = UserPrefs::for_uid(uid).await;
+    if prefs.get::<bool>(&toggle_key(category)) == Some(false) {
+        return;
+    }
+    if prefs.get::<String>(DELIVERY_KEY).as_deref() == Some("daily") {
+        let timezone = prefs.get::<String>(TIMEZONE_KEY).unwrap_or_default();
+        let now = now();
+        let item = Item { category: category.to_string(), subject: subject.to_string(), text: text.to_string(), at: now };
+        DIGESTS.lock().unwrap().add(uid, item, next_digest(now, offset_minutes(&timezone), DIGEST_HOUR));
+    } else {
+        mail::send(Mail::new(user.email, subject, text));
+    }
+}
+
+/// The notifications of `uid` waiting for their next digest
+pub fn pending(uid: u32) -> Vec<Item> {
+    DIGESTS.lock().unwrap().items(uid).to_vec()
+}
+
+/// Mail the digests that are due
+pub async fn send_digests() {
+    let due = DIGESTS.lock().unwrap().take_due(now());
+    for (uid, items) in due {
+        let Some(user) = LOCAL_AUTH.admin_get_user(uid).await else { continue };
+        let vars = object!({
+            username: &user.username,
+            count: items.len(),
+            link: mail::settings().link("/user/home"),
+            items: Value::List(items.iter().map(|item| object!({ subject: &item.subject, text: &item.text })).collect()),
+        });
+        let lang = op::supported_or_default(&user.profile.get(LANG_KEY).string());
+        mail::templates::send(&user.email, "digest", &lang, &vars);
+    }
+}
+
+/// Start the job sending the digests. Called by `serve`.
+pub fn start() {
+    Job::every("mail digests", TICK, send_digests).spawn();
+}
+
+endpoint! {
+    APP.url("/users/me/notifications"),
+
+    /// How the user gets notification mails
+    ///
+    /// # Request
+    /// `GET`, with a bearer token with the `profile:read` scope or the
+    /// session of a signed-in local account. Changes go to
+    /// `PATCH /users/me/preferences`.
+    ///
+    /// # Response
+    /// `{"success": true, "delivery": "daily", "timezone": "+09:00", "timezones": [...], "digest_hour": 8,
+    /// "categories": [{"name": "replies", "label": "...", "key": "notify_replies", "enabled": true}]}`
+    pub user_notifications <HTTP> {
+        methods!(req, GET);
+        let uid = if get_auth_token(req).is_some() {
+            match require_scope(req, scope::PROFILE_READ).await {
+                Ok(uid) => uid,
+                Err(response) => return response,
+            }
+        } else {
+            match req.local_uid() {
+                Some(uid) => uid,
+                None => {
+                    return json_response(object!({ success: false, message: "Sign in with a local account" }))
+                        .status(StatusCode::UNAUTHORIZED);
+                }
+            }
+        };
+        let prefs = UserPrefs::for_uid(uid).await;
+        let categories: Vec<Value> = categories()
+            .iter()
+            .map(|category| {
+                let key = toggle_key(&category.name);
+                object!({ name: &category.name, label: &category.label, enabled: prefs.get::<bool>(&key).unwrap_or(true), key: key })
+            })
+            .collect();
+        json_response(object!({
+            success: true,
+            delivery: prefs.get::<String>(DELIVERY_KEY).unwrap_or_else(|| "immediate".to_string()),
+            timezone: prefs.get::<String>(TIMEZONE_KEY).unwrap_or_else(|| "+00:00".to_string()),
+            timezones: Value::List(TIMEZONES.iter().map(|timezone| Value::from(*timezone)).collect()),
+            digest_hour: DIGEST_HOUR,
+            pending: DIGESTS.lock().unwrap().items(uid).len(),
+            categories: Value::List(categories),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digests_wait_for_the_morning_of_the_user() {
+        assert_eq!(offset_minutes("+05:45"), 345);
+        assert_eq!(offset_minutes("-03:30"), -210);
+        assert_eq!(offset_minutes("Europe/Paris"), 0);
+        assert!(TIMEZONES.iter().all(|timezone| timezone == &"+00:00" || offset_minutes(timezone) != 0));
+
+        // 2026-10-16 00:00 UTC
+        let midnight = 1_792_108_800;
+        assert_eq!(next_digest(midnight, 0, 8), midnight + 8 * 3600);
+        assert_eq!(next_digest(midnight + 9 * 3600, 0, 8), midnight + 32 * 3600);
+        // 09:00 in Tokyo is past 08:00 there, so the next one is tomorrow 08:00 JST
+        assert_eq!(next_digest(midnight, 540, 8), midnight + 23 * 3600);
+        assert_eq!(next_digest(midnight, -300, 8), midnight + 13 * 3600);
+
+        let path = std::env::temp_dir().join(format!("sfx-digest-{}/digest.json", std::process::id()));
+        let item = |subject: &str| Item { category: "replies".to_string(), subject: subject.to_string(), text: "Hi".to_string(), at: 1 };
+        let mut digests = Digests::open(path.clone());
+        digests.add(3, item("First"), 100);
+        digests.add(3, item("Second"), 500);
+        digests.add(4, item("Other"), 200);
+        assert_eq!(digests.take_due(99), Vec::new());
+
+        let mut digests = Digests::open(path.clone());
+        assert_eq!(digests.items(3).len(), 2);
+        assert_eq!(digests.take_due(150), vec![(3, vec![item("First"), item("Second")])]);
+        assert_eq!(Digests::open(path.clone()).items(3).len(), 0);
+        assert_eq!(Digests::open(path.clone()).items(4).len(), 1);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
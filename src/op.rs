@@ -2,53 +2,271 @@ pub use hotaru::prelude::*;
 pub use hotaru::http::*; 
 pub use std::env;  
 
+use crate::ctx::SfxCtx;
 use crate::user;
 use crate::user::User;
 pub use crate::APP; 
 use std::path::PathBuf;
 use std::sync::RwLock;
 
-static NAVBAR: Lazy<Value> = Lazy::new(|| {
-    let mut path = env::current_dir().unwrap();
-    path.push("programfiles/op/navbar.json");
-    Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None)
-});
+static NAVBAR: Lazy<RwLock<Value>> = Lazy::new(|| RwLock::new(load_op_file("navbar.json")));
 
-static FOOTER: Lazy<Value> = Lazy::new(|| {
-    let mut path = env::current_dir().unwrap();
-    path.push("programfiles/op/footer.json");
-    Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None) 
-});
+static FOOTER: Lazy<RwLock<Value>> = Lazy::new(|| RwLock::new(load_op_file("footer.json")));
 
-static SUPPORT_LANG: Lazy<Value> = Lazy::new(|| {
-    let mut path = env::current_dir().unwrap();
-    path.push("programfiles/op/support_lang.json");
-    Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None)
-});
+static SUPPORT_LANG: Lazy<RwLock<Vec<Language>>> = Lazy::new(|| RwLock::new(Language::list(&load_op_file("support_lang.json"))));
+
+static BRANDING: Lazy<RwLock<Branding>> = Lazy::new(|| RwLock::new(Branding::from_value(&load_op_file("branding.json"))));
 
-static L10N: Lazy<Value> = Lazy::new(|| {
-    let mut path = env::current_dir().unwrap();
-    path.push("programfiles/op/l10n.json");
+/// The files of `programfiles/op` read again by [`reload_ui_files`]
+pub const UI_FILES: &[&str] = &["navbar.json", "footer.json", "support_lang.json", "l10n.json", "translation.json", "branding.json"];
+
+fn load_op_file(name: &str) -> Value {
+    let path = programfiles().join("op").join(name);
     Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None)
-}); 
+}
+
+/// Read the navbar, footer, supported languages and l10n strings again,
+/// so edits show without a restart; done by the dev-mode watcher
+pub fn reload_ui_files() {
+    *NAVBAR.write().unwrap() = load_op_file("navbar.json");
+    *FOOTER.write().unwrap() = load_op_file("footer.json");
+    *SUPPORT_LANG.write().unwrap() = Language::list(&load_op_file("support_lang.json"));
+    crate::l10n::reload();
+    *BRANDING.write().unwrap() = Branding::from_value(&load_op_file("branding.json"));
+}
+
+/// Languages written right to left, when `support_lang.json` gives no `dir`
+const RTL_LANGS: &[&str] = &["ar", "arc", "ckb", "dv", "fa", "he", "ps", "sd", "ug", "ur", "yi"];
+
+/// A language of `programfiles/op/support_lang.json`, given by its code
+/// or with its writing direction and a font hint:
+///
+/// ```json
+/// ["en", { "code": "ar", "dir": "rtl", "font": "'Noto Naskh Arabic', serif" }]
+/// ```
+///
+/// Pages get them as `pageprop.dir` (`rtl` or `ltr`) and `pageprop.font`.
+/// Without `dir`, Arabic, Hebrew, Persian, Urdu and the other languages of
+/// [`RTL_LANGS`] are right to left.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Language {
+    pub code: String,
+    pub rtl: bool,
+    /// A CSS `font-family` for the pages in the language, none when empty
+    pub font: String,
+}
+
+impl Language {
+    /// `code` with the direction its script is written in
+    pub fn new(code: impl Into<String>) -> Self {
+        let code = code.into();
+        let base = code.split(['-', '_']).next().unwrap_or_default().to_ascii_lowercase();
+        Self { rtl: RTL_LANGS.contains(&base.as_str()), code, font: String::new() }
+    }
+
+    /// An entry of `support_lang.json`; `None` without a code
+    pub fn from_value(value: &Value) -> Option<Self> {
+        let code = match value {
+            Value::Dict(_) => value.get("code").string(),
+            value => value.string(),
+        };
+        let code = code.trim();
+        if code.is_empty() {
+            return None;
+        }
+        let mut language = Self::new(code);
+        match value.get("dir").string().as_str() {
+            "rtl" => language.rtl = true,
+            "ltr" => language.rtl = false,
+            _ => {}
+        }
+        // The hint ends up in a stylesheet, so only font names are taken
+        language.font = value
+            .get("font")
+            .string()
+            .chars()
+            .filter(|c| c.is_alphanumeric() || matches!(c, ' ' | ',' | '-' | '_' | '.' | '\'' | '"'))
+            .collect::<String>()
+            .trim()
+            .to_string();
+        Some(language)
+    }
+
+    /// The languages of `support_lang.json`, the default first
+    pub fn list(value: &Value) -> Vec<Self> {
+        match value {
+            Value::List(entries) => entries.iter().filter_map(Self::from_value).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// `rtl` or `ltr`, for the `dir` attribute
+    pub fn dir(&self) -> &'static str {
+        if self.rtl { "rtl" } else { "ltr" }
+    }
+}
+
+/// The supported language `code`, or `code` with its usual direction
+pub fn language(code: &str) -> Language {
+    SUPPORT_LANG.read().unwrap().iter().find(|language| language.code == code).cloned().unwrap_or_else(|| Language::new(code))
+}
+
+/// The look and contacts of a deployment, from `programfiles/op/branding.json`:
+///
+/// ```json
+/// {
+///     "site_name": "Example",
+///     "logo": "/static/logo.svg",
+///     "color": "blue",
+///     "support_email": "help@example.com",
+///     "legal": "© 2025 Example Ltd."
+/// }
+/// ```
+///
+/// Pages read it as `pageprop.brand` (and `pageprop.color`), and every mail
+/// sent with `crate::mail` ends with [`Branding::mail_footer`]. Missing
+/// keys keep the defaults of sfx.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Branding {
+    pub site_name: String,
+    /// Path or URL of the logo shown in the navbar, none when empty
+    pub logo: String,
+    /// The theme color, a name of the `--fds-<color>-*` palette
+    pub color: String,
+    pub support_email: String,
+    /// Legal text of the page footer and of mails
+    pub legal: String,
+}
+
+impl Default for Branding {
+    fn default() -> Self {
+        Self {
+            site_name: "SFX".to_string(),
+            logo: String::new(),
+            color: "pink".to_string(),
+            support_email: String::new(),
+            legal: String::new(),
+        }
+    }
+}
+
+impl Branding {
+    pub fn from_value(value: &Value) -> Self {
+        let default = Self::default();
+        let text = |key: &str, default: String| match value.get(key) {
+            Value::Str(text) if !text.trim().is_empty() => text.trim().to_string(),
+            _ => default,
+        };
+        // The color ends up in CSS, so only palette names are taken
+        let color = text("color", default.color.clone());
+        let color = match !color.is_empty() && color.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-') {
+            true => color,
+            false => {
+                tracing::warn!(%color, "branding.json: `color` must be a palette name like `pink`");
+                default.color
+            }
+        };
+        Self {
+            site_name: text("site_name", default.site_name),
+            logo: text("logo", default.logo),
+            color,
+            support_email: text("support_email", default.support_email),
+            legal: text("legal", default.legal),
+        }
+    }
+
+    /// The lines put below the text of every mail
+    pub fn mail_footer(&self) -> String {
+        let mut footer = format!("-- \n{}", self.site_name);
+        if !self.support_email.is_empty() {
+            footer.push_str(&format!("\nQuestions? Write to {}", self.support_email));
+        }
+        if !self.legal.is_empty() {
+            footer.push_str(&format!("\n{}", self.legal));
+        }
+        footer
+    }
+}
+
+/// The loaded branding
+pub fn branding() -> Branding {
+    BRANDING.read().unwrap().clone()
+}
 
 static ADMINS : Lazy<RwLock<Value>> = Lazy::new(|| {
-    let mut path = env::current_dir().unwrap();
-    path.push("programfiles/admin_info/admins.json");
+    let path = programfiles().join("admin_info/admins.json");
     RwLock::new(Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None))
 }); 
 
 static TRUSTED_ORIGIN : Lazy<Value> = Lazy::new(|| {
-    let mut path = env::current_dir().unwrap();
-    path.push("programfiles/op/hosts.json");
+    let path = programfiles().join("op/hosts.json");
     Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None)
 }); 
 
-pub static BINDING: Lazy<String> = Lazy::new(|| {
-    let mut path = env::current_dir().unwrap();
-    path.push("programfiles/op/binding.txt");
-    std::fs::read_to_string(path)
-        .unwrap_or_else(|_| "localhost:3003".to_string())
+/// Environment variable overriding the `programfiles` directory
+pub const PROGRAMFILES_ENV: &str = "SFX_PROGRAMFILES";
+/// Environment variable overriding the content of `binding.txt`
+pub const BINDING_ENV: &str = "SFX_BINDING";
+/// Environment variable naming the run mode, see [`run_mode`]
+pub const RUN_MODE_ENV: &str = "SFX_ENV";
+
+/// The run mode of `$SFX_ENV`: `development`, `beta`, or `production`,
+/// which is also taken when it is unset or unknown
+pub fn run_mode() -> RunMode {
+    match env::var(RUN_MODE_ENV).unwrap_or_default().to_ascii_lowercase().as_str() {
+        "development" | "dev" => RunMode::Development,
+        "beta" => RunMode::Beta,
+        _ => RunMode::Production,
+    }
+}
+
+/// The directory holding the configuration and data files: `$SFX_PROGRAMFILES`
+/// if set, otherwise `programfiles` under the working directory
+pub fn programfiles() -> PathBuf {
+    match env::var_os(PROGRAMFILES_ENV) {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => env::current_dir().unwrap().join("programfiles"),
+    }
+}
+
+/// Where the server listens, as written in `binding.txt`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Binding {
+    /// A TCP address such as `localhost:3003`
+    Tcp(String),
+    /// A Unix domain socket path, written `unix:/run/sfx/sfx.sock`
+    Unix(PathBuf),
+}
+
+impl Binding {
+    pub fn parse(binding: &str) -> Self {
+        let binding = binding.trim();
+        match binding.strip_prefix("unix:") {
+            Some(path) => Binding::Unix(PathBuf::from(path)),
+            None => Binding::Tcp(binding.to_string()),
+        }
+    }
+}
+
+/// The parsed content of `binding.txt`, or of `$SFX_BINDING` when set
+pub static LISTEN: Lazy<Binding> = Lazy::new(|| {
+    if let Ok(binding) = env::var(BINDING_ENV)
+        && !binding.trim().is_empty()
+    {
+        return Binding::parse(&binding);
+    }
+    let path = programfiles().join("op/binding.txt");
+    Binding::parse(
+        &std::fs::read_to_string(path).unwrap_or_else(|_| "localhost:3003".to_string()),
+    )
+});
+
+/// The TCP address `APP` binds to. When `binding.txt` names a Unix socket
+/// this is the loopback `internal_binding` of `unix_socket.json`, which the
+/// socket forwards to and the server uses for calls to itself.
+pub static BINDING: Lazy<String> = Lazy::new(|| match &*LISTEN {
+    Binding::Tcp(address) => address.clone(),
+    Binding::Unix(_) => crate::unix_socket::settings().internal_binding.clone(),
 });
 
 static LOCALHOST: &str = "local";
@@ -79,7 +297,8 @@ const DEFAULT_ROBOTS: &str = "User-agent: *\nDisallow: /user/\nDisallow: /admin/
 /// * `keywords`    - Comma-separated `<meta name="keywords">` value
 ///
 /// # Returns
-/// A `Value` object containing the page properties
+/// A `Value` object containing the page properties, including the CSP
+/// `nonce` of the request for inline scripts
 pub fn pageprop_with_keywords(
     req: &mut HttpReqCtx,
     title: &str,
@@ -87,18 +306,48 @@ pub fn pageprop_with_keywords(
     keywords: &str,
 ) -> Value {
     let lang = lang(req);
-    let user_value: Value = req.params.get::<User>().unwrap().clone().into();
+    let user = req.user_or_guest();
+    let items = crate::nav::items();
+    let nav = crate::nav::navbar(NAVBAR.read().unwrap().get(&lang), &items, &user, &lang);
+    let foot = crate::nav::footer(FOOTER.read().unwrap().get(&lang), &items, &user, &lang);
+    let avatar = crate::user::avatar::of_user(&user).map(Value::from).unwrap_or(Value::None);
+    let mut user_value: Value = user.into();
+    user_value.set("avatar", avatar);
     let path = req.path();
+    let (consent, consented) = crate::consent::settings().pageprop(&crate::consent::answer(req), &lang);
+    let (flags, experiments) = crate::flags::pageprop(req);
+    let admin_read_only = crate::admin::read_only::enabled() && crate::admin::read_only::is_admin_path(&path);
+    let brand = branding();
+    let (canonical, alternates) = crate::locale_urls::pageprop(&path, &lang);
+    let language = language(&lang);
     object!({
         lang: &lang,
+        dir: language.dir(),
+        font: &language.font,
         title: title,
-        color: "pink",
+        color: &brand.color,
+        brand: object!({
+            name: &brand.site_name,
+            logo: &brand.logo,
+            support_email: &brand.support_email,
+            legal: &brand.legal,
+        }),
         description: description,
         keywords: keywords,
-        nav: NAVBAR.get(&lang).clone(),
-        foot: FOOTER.get(&lang).clone(),
+        nav: nav,
+        foot: foot,
         user: user_value,
         path: path,
+        canonical: canonical,
+        alternates: alternates,
+        nonce: crate::security_headers::nonce(req),
+        consent: consent,
+        consented: consented,
+        analytics: crate::analytics::tracks(req),
+        flags: flags,
+        experiments: experiments,
+        prefs: crate::preferences::pageprop(req),
+        admin_read_only: admin_read_only,
     })
 }
 
@@ -145,9 +394,20 @@ pub fn forbidden_response(req: &mut HttpReqCtx, message: Option<&str>) -> HttpRe
 
 /// Get the default language from the support languages list
 pub fn default_lang() -> String {
-    SUPPORT_LANG.idx(0).string()
+    SUPPORT_LANG.read().unwrap().first().map(|language| language.code.clone()).unwrap_or_default()
 } 
 
+/// The codes of `support_lang.json`, the default first
+pub fn supported_langs() -> Vec<String> {
+    SUPPORT_LANG.read().unwrap().iter().map(|language| language.code.clone()).collect()
+}
+
+/// `lang` when the site supports it, else the default language; for mails
+/// sent outside a request, in the language of the profile
+pub fn supported_or_default(lang: &str) -> String {
+    if supported_langs().iter().any(|supported| supported == lang) { lang.to_string() } else { default_lang() }
+}
+
 /// Check if the host is trusted 
 pub fn is_trusted(host: String) -> bool { 
     TRUSTED_ORIGIN
@@ -178,9 +438,7 @@ pub fn get_admin() -> Value {
 }
 
 fn admin_info_path() -> PathBuf {
-    let mut path = env::current_dir().unwrap();
-    path.push("programfiles/admin_info/admins.json");
-    path
+    programfiles().join("admin_info/admins.json")
 }
 
 pub fn read_admin_entries() -> Vec<String> {
@@ -268,10 +526,11 @@ pub use crate::admin::RedirectNonAdmin;
 /// Resolve the language for the current request.
 ///
 /// Resolution order:
-/// 1. `?lang=<code>` query parameter — used by crawlers and `<link
+/// 1. The `/<code>/...` prefix of the path, when `crate::locale_urls` is on.
+/// 2. `?lang=<code>` query parameter — used by crawlers and `<link
 ///    rel="alternate" hreflang>` so each language has its own crawlable URL.
-/// 2. `lang` cookie — set by the footer language switcher for human users.
-/// 3. `default_lang()` — site fallback.
+/// 3. `lang` cookie — set by the footer language switcher for human users.
+/// 4. `default_lang()` — site fallback.
 ///
 /// A value is accepted only if it appears in `SUPPORT_LANG`; an unrecognized
 /// value at any layer falls through to the next.
@@ -282,42 +541,49 @@ pub fn lang(req: &mut HttpReqCtx) -> String {
     lang_or_none(req).unwrap_or_else(default_lang)
 }
 
-/// Like `lang(req)` but returns `None` when neither the query string, a
-/// cookie, nor an `Accept-Language` header yielded a supported language.
+/// Like `lang(req)` but returns `None` when neither the path, the query
+/// string, the profile of the user, a cookie, nor an `Accept-Language`
+/// header yielded a supported language.
 /// Lets downstream apps insert their own fallback (e.g. a `/<code>` URL-
 /// prefix scheme) between SFX's negotiation layer and the site default —
 /// something `lang()` collapses into the same "default_lang()" answer.
 ///
 /// Resolution order:
-/// 1. `?lang=<code>` query parameter
-/// 2. `lang` cookie
-/// 3. `Accept-Language` header, via [`htmstd::PreferredLanguage::best_match`]
+/// 1. The language prefix of the path (see `crate::locale_urls`)
+/// 2. `?lang=<code>` query parameter
+/// 3. The language in the profile of the signed-in user, the same on every
+///    device (see `User::get_lang`)
+/// 4. `lang` cookie
+/// 5. `Accept-Language` header, via [`htmstd::PreferredLanguage::best_match`]
 ///    against the supported-language list (requires
 ///    [`htmstd::PreferredLanguageMiddleware`] in the protocol stack — which
 ///    SFX's default `APP` installs).
 pub fn lang_or_none(req: &mut HttpReqCtx) -> Option<String> {
+    if let Some(crate::locale_urls::PathLang(lang)) = req.params.get::<crate::locale_urls::PathLang>() {
+        return Some(lang.clone());
+    }
+    let supported = supported_langs();
     if let Some(q) = req.query("lang") {
-        if SUPPORT_LANG.contains(&q.clone().into()) {
+        if supported.contains(&q) {
             return Some(q);
         }
     }
+    if let Some(lang) = req.params.get::<User>().and_then(User::get_lang)
+        && supported.iter().any(|supported| supported == lang)
+    {
+        return Some(lang.to_string());
+    }
     if let Some(c) = req.get_cookie("lang") {
         let v = c.get_value().to_string();
-        if SUPPORT_LANG.contains(&v.clone().into()) {
+        if supported.contains(&v) {
             return Some(v);
         }
     }
-    if let Some(pref) = req.params.get::<htmstd::PreferredLanguage>() {
-        let supported: Vec<String> = SUPPORT_LANG
-            .list()
-            .iter()
-            .map(|v| v.string())
-            .collect();
-        if let Some(best) = pref.best_match_owned(supported) {
-            if SUPPORT_LANG.contains(&best.clone().into()) {
-                return Some(best);
-            }
-        }
+    if let Some(pref) = req.params.get::<htmstd::PreferredLanguage>()
+        && let Some(best) = pref.best_match_owned(supported.clone())
+        && supported.contains(&best)
+    {
+        return Some(best);
     }
     None
 }
@@ -354,6 +620,23 @@ pub fn from(req: &mut HttpReqCtx) -> String {
     }
 }
 
+/// `text` safe to place in HTML, for user input shown by templates (which
+/// do not escape)
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
 /// A type alias for a path object 
 /// Vector of tuples where each tuple contains a path segment name and its actual location url  
 pub type Path = Vec<(String, String)>; 
@@ -402,22 +685,53 @@ pub fn into_path_l(req: &mut HttpReqCtx, path: Vec<&str>) -> Value {
     into_path(req, slices)
 }
 
-/// Get a localized string from the localization dictionary 
+/// Get a localized string from the translation backend (see `crate::l10n`),
+/// in the default language when `lang` has none
 pub fn get_localized_string(key: &str, lang: &str) -> String {
-    let dict = L10N.get(key); 
-    match dict.try_get(lang) {
-        Ok(value) => value.string(), 
-        Err(hotaru::akari::ValueError::KeyNotFoundError) => {
-            dict.get(default_lang()).string()
-        }, 
-        Err(_) => dict.string(), 
-    } 
-} 
+    crate::l10n::localize(key, lang, &Value::None)
+}
+
+/// [`get_localized_string`] with the arguments of the dict `args` put in:
+/// `{name}` in `l10n.json`, `{ $name }` and plurals in Fluent files
+pub fn get_localized_string_with(key: &str, lang: &str, args: &Value) -> String {
+    crate::l10n::localize(key, lang, args)
+}
+
+/// The l10n strings whose key starts with `prefix`, in `lang`, as a dict
+/// from key to string
+pub fn localized_strings(prefix: &str, lang: &str) -> Value {
+    crate::l10n::strings(prefix, lang, &Value::None)
+}
+
+/// Keep `lang` in the profile of the signed-in user on their auth server,
+/// so their other devices and the frontends sharing the server follow, and
+/// in the cached user of the session. Guests only have the cookie.
+pub async fn save_lang(req: &mut HttpReqCtx, lang: &str) {
+    use crate::user::fetch::{cache_user_info, get_auth_token, get_host, request_with_auth_token, send_http_request};
+    let Some(user) = req.signed_in().cloned() else {
+        return;
+    };
+    if user.get_lang() == Some(lang) {
+        return;
+    }
+    let request = request_with_auth_token(json_request("/users/me/lang", object!({ lang: lang })), get_auth_token(req));
+    match send_http_request(get_host(req).get_address(), request, HttpSafety::default()).await {
+        Ok(response) => match response.body.parse_buffer(&HttpSafety::new()) {
+            HttpBody::Json(json) if json.get("success").boolean() => {
+                cache_user_info(req, user.with_lang(Some(json.get("lang").string())));
+            }
+            body => tracing::warn!(?body, "The auth server did not keep the language"),
+        },
+        Err(err) => tracing::warn!(?err, "The auth server did not keep the language"),
+    }
+}
 
 endpoint! {
     APP.url("/op/lang/<lang>"),
 
-    /// Change the user's language by setting a cookie and redirecting to the same page
+    /// Change the user's language by setting a cookie and redirecting to the same page,
+    /// under the prefix of the new language when `crate::locale_urls` is on.
+    /// A signed-in user keeps it in their profile too, see [`save_lang`]
     /// This may not work if running in http but not https
     ///
     /// # Request
@@ -428,7 +742,14 @@ endpoint! {
     /// A `HttpResponse` that redirects to the same page with the new language set in a cookie
     pub change_language <HTTP> {
         let lang = req.param("lang").unwrap_or_else(default_lang);
-        redirect_response(&from(req)).add_cookie(
+        let mut back = from(req);
+        if crate::locale_urls::settings().enabled && supported_langs().contains(&lang) {
+            back = crate::locale_urls::localized(&crate::locale_urls::unprefixed(&back, &supported_langs()), &lang);
+        }
+        if supported_langs().contains(&lang) {
+            save_lang(req, &lang).await;
+        }
+        redirect_response(&back).add_cookie(
             "lang",
             Cookie::new(lang)
                 .path("/")
@@ -477,7 +798,7 @@ endpoint! {
 
     /// Serve the site's `robots.txt`.
     ///
-    /// Reads `programfiles/op/robots.txt` from the current working directory
+    /// Reads `programfiles/op/robots.txt` (see [`programfiles`])
     /// if present, otherwise falls back to a built-in default that disallows
     /// `/user/` and `/admin/`. Consumers can override the default by shipping
     /// their own file at that path.
@@ -489,10 +810,30 @@ endpoint! {
     /// A `text/plain` `HttpResponse` with the robots directives.
     pub robots_txt <HTTP> {
         let _ = req;
-        let path = env::current_dir().unwrap_or_default()
-            .join("programfiles/op/robots.txt");
+        let path = programfiles().join("op/robots.txt");
         let body = std::fs::read_to_string(&path)
             .unwrap_or_else(|_| DEFAULT_ROBOTS.to_string());
         text_response(body)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn languages_know_their_direction() {
+        let langs = Language::list(&object!([
+            "en",
+            "ar",
+            "he-IL",
+            { code: "ar-Latn", dir: "ltr" },
+            { code: "syr", dir: "rtl", font: "'Noto Sans Syriac', serif; } body { color: red" },
+            { dir: "rtl" },
+        ]));
+        let dirs: Vec<(&str, &str)> = langs.iter().map(|language| (language.code.as_str(), language.dir())).collect();
+        assert_eq!(dirs, vec![("en", "ltr"), ("ar", "rtl"), ("he-IL", "rtl"), ("ar-Latn", "ltr"), ("syr", "rtl")]);
+        assert_eq!(langs[4].font, "'Noto Sans Syriac', serif  body  color red");
+        assert_eq!(langs[0].font, "");
+    }
+}
@@ -0,0 +1,359 @@
+//! preferences.rs
+//!
+//! Typed per-user preferences on top of the profile of local accounts. The
+//! application registers what its users may choose, with a type and a
+//! default, usually once at startup:
+//!
+//! ```rust,ignore
+//! use sfx::preferences::{self, Preference, UserPrefs};
+//!
+//! preferences::register(Preference::new("theme", "light".to_string()).choices(["light", "dark"]).in_pageprop());
+//! preferences::register(Preference::new("page_size", 20i64));
+//!
+//! let prefs = UserPrefs::of(req).await;
+//! let size: i64 = prefs.get("page_size").unwrap_or(20);
+//! ```
+//!
+//! The choices are kept in the `preferences` entry of the profile. Guests,
+//! and users signed in through a MainAuth server, get the defaults. The
+//! preferences marked `in_pageprop` are in `pageprop["prefs"]` of every page
+//! rendered after the `LoadPreferences` middleware, and users read and
+//! change theirs at `/users/me/preferences`.
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+use std::sync::RwLock;
+
+use crate::local_auth::LOCAL_AUTH;
+use crate::local_auth::analyze::get_auth_token;
+use crate::local_auth::scope::{self, require_scope};
+use crate::op::APP;
+use crate::settings::Setting;
+use crate::ctx::SfxCtx;
+use crate::methods;
+
+static REGISTRY: Lazy<RwLock<Vec<Preference>>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+/// Profile key holding the preferences
+pub const PROFILE_KEY: &str = "preferences";
+
+/// A preference users may set, as registered by the application
+#[derive(Debug, Clone)]
+pub struct Preference {
+    pub key: String,
+    pub default: Value,
+    /// Whether a value is of the registered type
+    accepts: fn(&Value) -> bool,
+    /// The only values allowed, any of the type when empty
+    pub choices: Vec<Value>,
+    /// Whether pages get it in `pageprop["prefs"]`
+    pub pageprop: bool,
+}
+
+fn accepts<T: Setting>(value: &Value) -> bool {
+    T::from_setting(value).is_some()
+}
+
+impl Preference {
+    /// A preference of the type of `default`: `String`, `bool`, `i64`,
+    /// `u64`, `f64` or `Vec<String>`
+    pub fn new<T: Setting>(key: &str, default: T) -> Self {
+        Self { key: key.to_string(), default: default.into_setting(), accepts: accepts::<T>, choices: Vec::new(), pageprop: false }
+    }
+
+    /// Allow only these values
+    pub fn choices<T: Into<Value>>(mut self, choices: impl IntoIterator<Item = T>) -> Self {
+        self.choices = choices.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Put the preference in `pageprop["prefs"]`
+    pub fn in_pageprop(mut self) -> Self {
+        self.pageprop = true;
+        self
+    }
+
+    /// What is wrong with `value` for this preference
+    pub fn problem(&self, value: &Value) -> Option<&'static str> {
+        if !(self.accepts)(value) {
+            Some("Wrong type")
+        } else if !self.choices.is_empty() && !self.choices.contains(value) {
+            Some("Not one of the choices")
+        } else {
+            None
+        }
+    }
+}
+
+/// Add `preference`, replacing one registered with the same key
+pub fn register(preference: Preference) {
+    let mut registry = REGISTRY.write().unwrap();
+    registry.retain(|other| other.key != preference.key);
+    registry.push(preference);
+}
+
+/// Every registered preference, in the order of registration
+pub fn registered() -> Vec<Preference> {
+    REGISTRY.read().unwrap().clone()
+}
+
+fn find(key: &str) -> Option<Preference> {
+    REGISTRY.read().unwrap().iter().find(|preference| preference.key == key).cloned()
+}
+
+#[derive(Debug)]
+pub enum PrefsError {
+    /// Nowhere to keep them: a guest or a user of another server
+    NotStored,
+    Unknown(String),
+    Invalid(String, &'static str),
+    Save(String),
+}
+
+impl std::fmt::Display for PrefsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PrefsError::NotStored => write!(f, "Preferences are kept for local accounts only"),
+            PrefsError::Unknown(key) => write!(f, "Unknown preference: {}", key),
+            PrefsError::Invalid(key, problem) => write!(f, "{}: {}", key, problem),
+            PrefsError::Save(err) => write!(f, "Failed to save preferences: {}", err),
+        }
+    }
+}
+
+/// The preferences of one user
+#[derive(Debug, Clone)]
+pub struct UserPrefs {
+    /// The local account they are kept in
+    uid: Option<u32>,
+    /// What the user chose, by key
+    chosen: Value,
+}
+
+impl Default for UserPrefs {
+    /// The defaults, kept nowhere
+    fn default() -> Self {
+        Self { uid: None, chosen: Value::None }
+    }
+}
+
+impl UserPrefs {
+    /// The preferences of local account `uid`
+    pub async fn for_uid(uid: u32) -> Self {
+        let chosen = match LOCAL_AUTH.admin_get_user(uid).await {
+            Some(user) => user.profile.get(PROFILE_KEY).clone(),
+            None => Value::None,
+        };
+        Self { uid: Some(uid), chosen }
+    }
+
+    /// The preferences of whoever sent `req`: those loaded by
+    /// `LoadPreferences`, else read now, else the defaults
+    pub async fn of(req: &HttpReqCtx) -> Self {
+        if let Some(prefs) = req.params.get::<UserPrefs>() {
+            return prefs.clone();
+        }
+        match req.local_uid() {
+            Some(uid) => Self::for_uid(uid).await,
+            None => Self::default(),
+        }
+    }
+
+    /// The preference `key`: the user's choice, or the registered default.
+    /// `None` for an unregistered key or another type than registered.
+    pub fn get<T: Setting>(&self, key: &str) -> Option<T> {
+        T::from_setting(&self.value(key)?)
+    }
+
+    /// The value of `key` as stored, or its default
+    fn value(&self, key: &str) -> Option<Value> {
+        let preference = find(key)?;
+        match self.chosen.get(key) {
+            value if preference.problem(value).is_none() => Some(value.clone()),
+            _ => Some(preference.default),
+        }
+    }
+
+    /// Choose `value` for `key`; kept once saved
+    pub fn set<T: Setting>(&mut self, key: &str, value: T) -> Result<(), PrefsError> {
+        self.set_value(key, value.into_setting())
+    }
+
+    fn set_value(&mut self, key: &str, value: Value) -> Result<(), PrefsError> {
+        let preference = find(key).ok_or_else(|| PrefsError::Unknown(key.to_string()))?;
+        if let Some(problem) = preference.problem(&value) {
+            return Err(PrefsError::Invalid(key.to_string(), problem));
+        }
+        if !matches!(self.chosen, Value::Dict(_)) {
+            self.chosen = Value::new_dict();
+        }
+        self.chosen.set(key, value);
+        Ok(())
+    }
+
+    /// Go back to the default of `key`; kept once saved
+    pub fn reset(&mut self, key: &str) {
+        if let Value::Dict(chosen) = &mut self.chosen {
+            chosen.remove(key);
+        }
+    }
+
+    /// Apply a change like `{"theme": "dark", "page_size": null}`, where
+    /// `null` goes back to the default. Nothing changes unless every entry is valid.
+    pub fn apply(&mut self, patch: &Value) -> Result<(), Vec<PrefsError>> {
+        let Value::Dict(entries) = patch else {
+            return Err(vec![PrefsError::Invalid("body".to_string(), "Must be an object of preferences by key")]);
+        };
+        let mut changed = self.clone();
+        let mut errors = Vec::new();
+        for (key, value) in entries {
+            match value {
+                Value::None if find(key).is_some() => changed.reset(key),
+                Value::None => errors.push(PrefsError::Unknown(key.clone())),
+                value => {
+                    if let Err(err) = changed.set_value(key, value.clone()) {
+                        errors.push(err);
+                    }
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        *self = changed;
+        Ok(())
+    }
+
+    /// Keep the choices in the profile
+    pub async fn save(&self) -> Result<(), PrefsError> {
+        let uid = self.uid.ok_or(PrefsError::NotStored)?;
+        LOCAL_AUTH.set_profile_entry(uid, PROFILE_KEY, self.chosen.clone()).await.map_err(|err| PrefsError::Save(err.to_string()))
+    }
+
+    /// Every registered preference with its value, or only those marked
+    /// `in_pageprop`
+    pub fn into_json(&self, pageprop_only: bool) -> Value {
+        let mut prefs = Value::new_dict();
+        for preference in registered().iter().filter(|preference| preference.pageprop || !pageprop_only) {
+            if let Some(value) = self.value(&preference.key) {
+                prefs.set(&preference.key, value);
+            }
+        }
+        prefs
+    }
+}
+
+/// `pageprop["prefs"]`: the preferences marked `in_pageprop`, loaded by
+/// `LoadPreferences` or their defaults
+pub fn pageprop(req: &HttpReqCtx) -> Value {
+    req.params.get::<UserPrefs>().cloned().unwrap_or_default().into_json(true)
+}
+
+middleware! {
+    /// Reads the preferences of a signed-in local account for the pages of
+    /// the request. Add it after `UserFetch`.
+    pub LoadPreferences <HTTP> {
+        if !REGISTRY.read().unwrap().is_empty()
+            && let Some(uid) = req.local_uid()
+        {
+            let prefs = UserPrefs::for_uid(uid).await;
+            req.params.set(prefs);
+        }
+        next(req).await
+    }
+}
+
+endpoint! {
+    APP.url("/users/me/preferences"),
+
+    /// Read or change the preferences of the user
+    ///
+    /// # Request
+    /// A bearer token with the `profile:read` scope (GET) or `profile:write`
+    /// (PATCH), or the session of a signed-in local account.
+    /// `PATCH` body: JSON like `{"theme": "dark", "page_size": null}`, where
+    /// `null` goes back to the default
+    ///
+    /// # Response
+    /// `{"success": true, "preferences": {"theme": "dark", "page_size": 20}}`
+    /// with every registered preference; failures are
+    /// `{"success": false, "message": "...", "errors": {"theme": "Not one of the choices"}}`
+    /// with `400`, `401`, `403` or `405`
+    pub user_preferences <HTTP> {
+        methods!(req, GET | PATCH);
+        let method = req.method();
+        let uid = if get_auth_token(req).is_some() {
+            let scope = if method == GET { scope::PROFILE_READ } else { scope::PROFILE_WRITE };
+            match require_scope(req, scope).await {
+                Ok(uid) => uid,
+                Err(response) => return response,
+            }
+        } else {
+            match req.local_uid() {
+                Some(uid) => uid,
+                None => {
+                    return json_response(object!({ success: false, message: "Sign in with a local account" }))
+                        .status(StatusCode::UNAUTHORIZED);
+                }
+            }
+        };
+        let mut prefs = UserPrefs::for_uid(uid).await;
+        if method == PATCH {
+            let patch = req.json_or_default().await.clone();
+            if let Err(errors) = prefs.apply(&patch) {
+                let mut problems = Value::new_dict();
+                for err in &errors {
+                    match err {
+                        PrefsError::Invalid(key, problem) => problems.set(key, *problem),
+                        PrefsError::Unknown(key) => problems.set(key, "Unknown preference"),
+                        _ => {}
+                    }
+                }
+                return json_response(object!({ success: false, message: "Invalid preferences", errors: problems }))
+                    .status(StatusCode::BAD_REQUEST);
+            }
+            if let Err(err) = prefs.save().await {
+                return json_response(object!({ success: false, message: err.to_string() }))
+                    .status(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+        json_response(object!({ success: true, preferences: prefs.into_json(false) }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestRequest};
+
+    #[test]
+    fn choices_fall_back_to_defaults_and_patches_are_all_or_nothing() {
+        register(Preference::new("test_theme", "light".to_string()).choices(["light", "dark"]).in_pageprop());
+        register(Preference::new("test_size", 20i64));
+        let mut prefs = UserPrefs { uid: None, chosen: Value::from_json(r#"{"test_theme": "neon", "test_size": 50}"#).unwrap() };
+        assert_eq!(prefs.get::<String>("test_theme").as_deref(), Some("light"));
+        assert_eq!(prefs.get::<i64>("test_size"), Some(50));
+        assert_eq!(prefs.get::<bool>("test_size"), None);
+
+        let bad = Value::from_json(r#"{"test_theme": "dark", "test_size": "big"}"#).unwrap();
+        assert!(matches!(prefs.apply(&bad).unwrap_err()[..], [PrefsError::Invalid(_, "Wrong type")]));
+        assert_eq!(prefs.get::<String>("test_theme").as_deref(), Some("light"));
+
+        prefs.apply(&Value::from_json(r#"{"test_theme": "dark", "test_size": null}"#).unwrap()).unwrap();
+        assert_eq!(prefs.get::<String>("test_theme").as_deref(), Some("dark"));
+        assert_eq!(prefs.get::<i64>("test_size"), Some(20));
+        assert_eq!(prefs.into_json(true).get("test_size"), &Value::None);
+        assert!(matches!(prefs.set("nope", true), Err(PrefsError::Unknown(_))));
+    }
+
+    #[tokio::test]
+    async fn the_endpoint_wants_a_local_account() {
+        let mut req = TestRequest::get("/users/me/preferences").build();
+        let res = testing::respond(user_preferences(&mut req).await, &mut req);
+        assert_eq!(testing::status(&res), 401);
+
+        let mut req = TestRequest::new(HttpMethod::DELETE, "/users/me/preferences").signed_in(testing::user(3, "u")).build();
+        let res = testing::respond(user_preferences(&mut req).await, &mut req);
+        assert_eq!(testing::status(&res), 405);
+    }
+}
@@ -0,0 +1,308 @@
+//! proxy.rs
+//!
+//! Reverse-proxy awareness. When sfx runs behind nginx or traefik the TCP
+//! peer is the proxy, and the client address, scheme and public host only
+//! survive in forwarded headers. Those headers are trivially forged, so they
+//! are only read when the peer is a trusted proxy.
+//!
+//! Configured in `programfiles/op/proxy.json`:
+//!
+//! ```json
+//! {
+//!     "trusted": ["127.0.0.1/32", "::1/128"],
+//!     "for_header": "x-forwarded-for",
+//!     "proto_header": "x-forwarded-proto",
+//!     "host_header": "x-forwarded-host"
+//! }
+//! ```
+//!
+//! Only the headers named there are read, as a proxy passes the others of the
+//! client on untouched. `"for_header": "forwarded"` reads the standard
+//! `Forwarded` header (RFC 7239) for the address, scheme and host instead of
+//! the `X-Forwarded-*` headers. Connections coming
+//! from the built-in TLS front (`crate::tls`) are resolved without headers,
+//! and the peer of the Unix socket (`crate::unix_socket`) is always trusted.
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::bindings::{self, Front};
+use crate::ip_filter::{Cidr, parse_cidr_list};
+
+/// The `for_header` reading the RFC 7239 header for all three values
+const FORWARDED: &str = "forwarded";
+
+static PROXY: Lazy<ProxySettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/proxy.json");
+    ProxySettings::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None))
+});
+
+/// The parsed content of `proxy.json`
+#[derive(Debug, Clone)]
+pub struct ProxySettings {
+    pub trusted: Vec<Cidr>,
+    pub for_header: String,
+    pub proto_header: String,
+    pub host_header: String,
+}
+
+impl Default for ProxySettings {
+    fn default() -> Self {
+        Self {
+            trusted: Vec::new(),
+            for_header: "x-forwarded-for".to_string(),
+            proto_header: "x-forwarded-proto".to_string(),
+            host_header: "x-forwarded-host".to_string(),
+        }
+    }
+}
+
+impl ProxySettings {
+    pub fn from_value(value: &Value) -> Self {
+        let default = Self::default();
+        let header = |key: &str, default: String| {
+            let name = value.get(key).string();
+            if name.is_empty() { default } else { name.to_ascii_lowercase() }
+        };
+        Self {
+            trusted: parse_cidr_list(value.get("trusted")),
+            for_header: header("for_header", default.for_header),
+            proto_header: header("proto_header", default.proto_header),
+            host_header: header("host_header", default.host_header),
+        }
+    }
+
+    /// The forwarded hops, scheme and host of a request with the `header`s
+    /// of a trusted proxy
+    pub fn read_headers<'a>(&self, header: impl Fn(&str) -> Option<&'a str>) -> (Vec<IpAddr>, Option<String>, Option<String>) {
+        if self.for_header == FORWARDED {
+            return header(FORWARDED).map(parse_forwarded).unwrap_or_default();
+        }
+        (
+            header(&self.for_header).map(|h| h.split(',').filter_map(parse_hop).collect()).unwrap_or_default(),
+            first_value(header(&self.proto_header)).map(|p| p.to_ascii_lowercase()),
+            first_value(header(&self.host_header)),
+        )
+    }
+
+    pub fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted.iter().any(|net| net.contains(ip))
+    }
+
+    /// Resolve the originating client from the peer and the forwarded hops
+    /// (in header order, client first).
+    ///
+    /// The hops are walked right to left, skipping trusted proxies; the first
+    /// untrusted hop is the client. An untrusted peer is returned as is.
+    pub fn resolve_client_ip(&self, peer: IpAddr, hops: &[IpAddr]) -> IpAddr {
+        if !self.is_trusted(&peer) {
+            return peer;
+        }
+        let mut client = peer;
+        for hop in hops.iter().rev() {
+            client = *hop;
+            if !self.is_trusted(hop) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+/// What the request looked like before it reached the proxy. Stored in
+/// `req.params` by [`ProxyHeaders`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForwardedInfo {
+    /// The originating client, `None` when the connection has no peer
+    pub client_ip: Option<IpAddr>,
+    /// `http` or `https` as seen by the client
+    pub scheme: String,
+    /// The public `Host` the client asked for
+    pub host: Option<String>,
+    /// Whether the values came from a trusted proxy's headers
+    pub via_proxy: bool,
+}
+
+/// The loaded proxy settings
+pub fn settings() -> &'static ProxySettings {
+    &PROXY
+}
+
+/// Parse one address of a forwarded header: `1.2.3.4`, `1.2.3.4:80`,
+/// `[2001:db8::1]:4711` or `"[2001:db8::1]"`
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim().trim_matches('"');
+    if let Ok(ip) = hop.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(addr) = hop.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    hop.strip_prefix('[')
+        .and_then(|rest| rest.split(']').next())
+        .and_then(|ip| ip.parse().ok())
+}
+
+/// Split an RFC 7239 `Forwarded` header into its `for` hops and the
+/// `proto`/`host` of the first (client-facing) element
+fn parse_forwarded(header: &str) -> (Vec<IpAddr>, Option<String>, Option<String>) {
+    let mut hops = Vec::new();
+    let (mut proto, mut host) = (None, None);
+    for (idx, element) in header.split(',').enumerate() {
+        for pair in element.split(';') {
+            let Some((key, value)) = pair.split_once('=') else { continue };
+            let value = value.trim().trim_matches('"');
+            match key.trim().to_ascii_lowercase().as_str() {
+                "for" => {
+                    if let Some(ip) = parse_hop(value) {
+                        hops.push(ip);
+                    }
+                }
+                "proto" if idx == 0 => proto = Some(value.to_ascii_lowercase()),
+                "host" if idx == 0 => host = Some(value.to_string()),
+                _ => {}
+            }
+        }
+    }
+    (hops, proto, host)
+}
+
+/// First entry of a comma separated `X-Forwarded-*` header
+fn first_value(header: Option<&str>) -> Option<String> {
+    header
+        .and_then(|h| h.split(',').next())
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// Work out the [`ForwardedInfo`] of a request from its peer and headers
+pub fn forwarded_info(req: &HttpReqCtx) -> ForwardedInfo {
+    let peer = req.client_ip_only();
+    let direct = ForwardedInfo {
+        client_ip: peer,
+        scheme: "http".to_string(),
+        host: req.header_str("host").map(|h| h.to_string()),
+        via_proxy: false,
+    };
+    // Connections forwarded by the built-in fronts
+    let front = bindings::front_of(req);
+    if let Some(Front::Tls(client, _)) = front {
+        return ForwardedInfo {
+            client_ip: Some(client.ip()),
+            scheme: "https".to_string(),
+            via_proxy: true,
+            ..direct
+        };
+    }
+    // Only local processes can reach the Unix socket, so its peer is as
+    // trusted as a listed proxy
+    let Some(peer) = peer.filter(|ip| front == Some(Front::Unix) || PROXY.is_trusted(ip)) else {
+        return direct;
+    };
+
+    let (hops, proto, host) = PROXY.read_headers(|name| req.header_str(name));
+    ForwardedInfo {
+        client_ip: Some(PROXY.resolve_client_ip(peer, &hops)),
+        scheme: proto
+            .filter(|p| p == "http" || p == "https")
+            .unwrap_or(direct.scheme),
+        host: host.or(direct.host),
+        via_proxy: true,
+    }
+}
+
+/// The [`ForwardedInfo`] of the request, computed on the fly if the
+/// [`ProxyHeaders`] middleware has not run
+fn info(req: &HttpReqCtx) -> ForwardedInfo {
+    req.params
+        .get::<ForwardedInfo>()
+        .cloned()
+        .unwrap_or_else(|| forwarded_info(req))
+}
+
+/// The address of the client that originated the request
+pub fn client_ip(req: &HttpReqCtx) -> Option<IpAddr> {
+    info(req).client_ip
+}
+
+/// `http` or `https`, as seen by the client
+pub fn scheme(req: &HttpReqCtx) -> String {
+    info(req).scheme
+}
+
+/// The public origin of the site, e.g. `https://example.com`, falling back
+/// to the server binding when the request carries no host
+pub fn public_origin(req: &HttpReqCtx) -> String {
+    let info = info(req);
+    let host = info.host.unwrap_or_else(|| crate::op::BINDING.clone());
+    format!("{}://{}", info.scheme, host)
+}
+
+middleware! {
+    /// Middleware resolving the client address, scheme and host from the
+    /// forwarded headers of trusted proxies. The result is stored as a
+    /// `ForwardedInfo` in `req.params` and the `Host` header is rewritten to
+    /// the public host. Add it first so later middlewares see the real client.
+    pub ProxyHeaders <HTTP> {
+        let info = forwarded_info(&req);
+        if info.via_proxy
+            && let Some(host) = &info.host
+        {
+            req.request.meta.set_host(Some(host.clone()));
+        }
+        req.params.set::<ForwardedInfo>(info);
+        next(req).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn forwarded_for_is_only_trusted_from_proxies() {
+        let settings = ProxySettings::from_value(&object!({
+            trusted: ["127.0.0.1", "10.0.0.0/8"]
+        }));
+        let hops = [ip("203.0.113.9"), ip("10.0.0.2")];
+        assert_eq!(settings.resolve_client_ip(ip("127.0.0.1"), &hops), ip("203.0.113.9"));
+        assert_eq!(settings.resolve_client_ip(ip("198.51.100.1"), &hops), ip("198.51.100.1"));
+        assert_eq!(
+            settings.resolve_client_ip(ip("127.0.0.1"), &[ip("1.1.1.1"), ip("203.0.113.9")]),
+            ip("203.0.113.9")
+        );
+        assert_eq!(settings.resolve_client_ip(ip("127.0.0.1"), &[]), ip("127.0.0.1"));
+    }
+
+    #[test]
+    fn parses_rfc7239_forwarded_header() {
+        let (hops, proto, host) = parse_forwarded(
+            r#"for=192.0.2.60;proto=HTTPS;host=example.com, for="[2001:db8::1]:4711""#,
+        );
+        assert_eq!(hops, vec![ip("192.0.2.60"), ip("2001:db8::1")]);
+        assert_eq!(proto.as_deref(), Some("https"));
+        assert_eq!(host.as_deref(), Some("example.com"));
+        assert_eq!(parse_hop("198.51.100.7:8080"), Some(ip("198.51.100.7")));
+        assert_eq!(parse_hop("unknown"), None);
+    }
+
+    #[test]
+    fn only_the_configured_headers_are_read() {
+        let headers = |name: &str| match name {
+            "forwarded" => Some("for=1.2.3.4;proto=https;host=evil.example"),
+            "x-forwarded-for" => Some("203.0.113.9"),
+            _ => None,
+        };
+        let settings = ProxySettings::default();
+        assert_eq!(settings.read_headers(headers), (vec![ip("203.0.113.9")], None, None));
+
+        let settings = ProxySettings::from_value(&object!({ for_header: "Forwarded" }));
+        let (hops, proto, host) = settings.read_headers(headers);
+        assert_eq!((hops, proto.as_deref(), host.as_deref()), (vec![ip("1.2.3.4")], Some("https"), Some("evil.example")));
+    }
+}
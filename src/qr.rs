@@ -0,0 +1,253 @@
+//! qr.rs
+//!
+//! QR codes, drawn server-side as SVG or PNG, for the short links of the
+//! admin panel, device login (`verification_uri_qr`) and 2FA provisioning
+//! (`otpauth://` URIs).
+//!
+//! `GET /op/qr?data=...` only encodes data on this site (starting with its
+//! public origin) or starting with a prefix of `programfiles/op/qr.json`, so
+//! the endpoint cannot be used to brand arbitrary codes with the site:
+//!
+//! ```json
+//! { "allowed_prefixes": ["otpauth://"], "max_size": 1024 }
+//! ```
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+use qrcodegen::{QrCode, QrCodeEcc};
+
+use crate::op::APP;
+use crate::proxy;
+
+static QR: Lazy<QrSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/qr.json");
+    match Value::from_jsonf(path.to_str().unwrap()) {
+        Ok(value) => QrSettings::from_value(&value),
+        Err(_) => QrSettings::default(),
+    }
+});
+
+/// Quiet zone around the code, in modules, as the standard asks
+pub const BORDER: i32 = 4;
+
+/// Pixels of an image when the request does not say
+const DEFAULT_SIZE: u32 = 256;
+
+/// Fewest pixels an image may have
+const MIN_SIZE: u32 = 64;
+
+/// Image formats of `/op/qr`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Svg,
+    Png,
+}
+
+impl Format {
+    pub fn parse(format: &str) -> Option<Self> {
+        match format.to_ascii_lowercase().as_str() {
+            "" | "svg" => Some(Format::Svg),
+            "png" => Some(Format::Png),
+            _ => None,
+        }
+    }
+}
+
+/// The parsed content of `qr.json`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QrSettings {
+    /// Beginnings of data encoded besides URLs of this site
+    pub allowed_prefixes: Vec<String>,
+    /// Most pixels an image may have
+    pub max_size: u32,
+}
+
+impl Default for QrSettings {
+    fn default() -> Self {
+        Self { allowed_prefixes: vec!["otpauth://".to_string()], max_size: 1024 }
+    }
+}
+
+impl QrSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let allowed_prefixes = match value.get("allowed_prefixes") {
+            Value::List(prefixes) => prefixes
+                .iter()
+                .map(|prefix| prefix.string())
+                .filter(|prefix| !prefix.is_empty())
+                .collect(),
+            _ => Vec::new(),
+        };
+        let max_size = match value.get("max_size") {
+            Value::Numerical(_) => value.get("max_size").integer().clamp(MIN_SIZE as i64, 4096) as u32,
+            _ => Self::default().max_size,
+        };
+        Self { allowed_prefixes, max_size }
+    }
+
+    /// Whether `data` may be encoded for a site at `origin`
+    pub fn allows(&self, data: &str, origin: &str) -> bool {
+        data.strip_prefix(origin).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            || self.allowed_prefixes.iter().any(|prefix| data.starts_with(prefix.as_str()))
+    }
+}
+
+/// The loaded QR settings
+pub fn settings() -> &'static QrSettings {
+    &QR
+}
+
+/// Encode `data` at medium error correction; `None` when it is too long for
+/// any QR version
+pub fn encode(data: &str) -> Option<QrCode> {
+    QrCode::encode_text(data, QrCodeEcc::Medium).ok()
+}
+
+/// `data` as an SVG image `pixels` wide and high
+pub fn svg(data: &str, pixels: u32) -> Option<String> {
+    let code = encode(data)?;
+    let size = code.size() + BORDER * 2;
+    let mut path = String::new();
+    for y in 0..code.size() {
+        for x in 0..code.size() {
+            if code.get_module(x, y) {
+                path.push_str(&format!("M{},{}h1v1h-1z", x + BORDER, y + BORDER));
+            }
+        }
+    }
+    Some(format!(
+        concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{px}" height="{px}" viewBox="0 0 {size} {size}" shape-rendering="crispEdges">"#,
+            r##"<rect width="100%" height="100%" fill="#fff"/><path d="{path}" fill="#000"/></svg>"##
+        ),
+        px = pixels,
+        size = size,
+        path = path
+    ))
+}
+
+/// `data` as a grayscale PNG of whole pixels per module, at most `pixels`
+/// wide and high unless the code does not fit in one pixel per module
+pub fn png(data: &str, pixels: u32) -> Option<Vec<u8>> {
+    let code = encode(data)?;
+    let modules = (code.size() + BORDER * 2) as u32;
+    let scale = (pixels / modules).max(1);
+    let width = modules * scale;
+    let mut image = Vec::with_capacity((width * width) as usize);
+    for row in 0..width {
+        let y = (row / scale) as i32 - BORDER;
+        for column in 0..width {
+            let x = (column / scale) as i32 - BORDER;
+            // `get_module` is false outside the code, which draws the border
+            image.push(if code.get_module(x, y) { 0 } else { 255 });
+        }
+    }
+    let mut out = Vec::new();
+    let mut encoder = png::Encoder::new(&mut out, width, width);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header().ok()?.write_image_data(&image).ok()?;
+    Some(out)
+}
+
+/// The path of `/op/qr` drawing `data`, for `<img src>`
+pub fn url(data: &str, format: Format, pixels: u32) -> String {
+    let format = match format {
+        Format::Svg => "svg",
+        Format::Png => "png",
+    };
+    format!(
+        "/op/qr?data={}&format={}&size={}",
+        hotaru_lib::url_encoding::encode_url_owned(data),
+        format,
+        pixels
+    )
+}
+
+fn bad_request(message: &str) -> HttpResponse {
+    json_response(object!({ success: false, message: message })).status(StatusCode::BAD_REQUEST)
+}
+
+endpoint! {
+    APP.url("/op/qr"),
+
+    /// Draw a QR code
+    ///
+    /// # Request
+    /// `GET /op/qr?data=<percent-encoded data>&format=svg|png&size=256`
+    /// `data` must be a URL of this site or start with one of
+    /// `allowed_prefixes`; `size` is in pixels, from 64 to `max_size`.
+    ///
+    /// # Response
+    /// The image, not cached since the data may be a secret (an `otpauth://`
+    /// URI), or `400` with `{"success": false, "message": "..."}`
+    pub qr_code <HTTP> {
+        let data = req.query("data").unwrap_or_default();
+        let Some(format) = Format::parse(&req.query("format").unwrap_or_default()) else {
+            return bad_request("Unknown format, use svg or png");
+        };
+        let size = match req.query("size") {
+            None => DEFAULT_SIZE,
+            Some(size) => match size.parse::<u32>() {
+                Ok(size) => size.clamp(MIN_SIZE, QR.max_size),
+                Err(_) => return bad_request("Invalid size"),
+            },
+        };
+        if data.is_empty() {
+            return bad_request("Nothing to encode");
+        }
+        if !QR.allows(&data, &proxy::public_origin(req)) {
+            return bad_request("This data may not be encoded");
+        }
+        let response = match format {
+            Format::Svg => svg(&data, size).map(|image| {
+                normal_response(StatusCode::OK, image).content_type(HttpContentType::from_str("image/svg+xml"))
+            }),
+            Format::Png => png(&data, size).map(|image| {
+                normal_response(StatusCode::OK, image).content_type(HttpContentType::from_str("image/png"))
+            }),
+        };
+        match response {
+            Some(response) => response.add_header("Cache-Control", "no-store"),
+            None => bad_request("Too long for a QR code"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_are_drawn_with_a_quiet_zone() {
+        let image = svg("https://example.com/l/abc", 128).unwrap();
+        // Version 2 at medium correction: 25 modules plus the border
+        assert!(image.contains(r#"viewBox="0 0 33 33""#));
+        assert!(image.contains(r#"width="128""#));
+        // The top-left finder pattern starts right inside the border
+        assert!(image.contains("M4,4h1v1h-1z"));
+        assert!(svg(&"x".repeat(3000), 128).is_none());
+
+        let image = png("https://example.com/l/abc", 128).unwrap();
+        assert_eq!(&image[1..4], b"PNG");
+        // 33 modules of 3 pixels: IHDR holds the width and height
+        assert_eq!(&image[16..24], &[0, 0, 0, 99, 0, 0, 0, 99]);
+    }
+
+    #[test]
+    fn only_site_urls_and_allowed_prefixes_are_encoded() {
+        let settings = QrSettings::default();
+        let origin = "https://example.com";
+        assert!(settings.allows("https://example.com/activate?code=BCDF-GHJK", origin));
+        assert!(settings.allows("https://example.com", origin));
+        assert!(settings.allows("otpauth://totp/sfx:alice?secret=ABC", origin));
+        assert!(!settings.allows("https://example.com.evil/", origin));
+        assert!(!settings.allows("https://evil.example/", origin));
+        assert_eq!(Format::parse("PNG"), Some(Format::Png));
+        assert_eq!(Format::parse("gif"), None);
+        assert_eq!(
+            url("https://example.com/l/a b", Format::Png, 512),
+            "/op/qr?data=https%3A%2F%2Fexample.com%2Fl%2Fa%20b&format=png&size=512"
+        );
+    }
+}
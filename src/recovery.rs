@@ -0,0 +1,160 @@
+//! recovery.rs
+//!
+//! A panic in a handler or middleware no longer drops the connection:
+//! [`PanicRecovery`], first in the chain of `APP`, runs the rest of the
+//! request on its own task and, when that task panics, logs the panic with
+//! the method, path, request id and backtrace, counts it in [`panics`] (also shown by
+//! `/health`) and answers `500`. Browsers get the `user/error.html` page in
+//! the site chrome, clients sending `Accept: application/json`
+//! `{"success": false, "message": "Internal Server Error"}`, and development
+//! mode the detailed page of [`crate::dev`].
+
+use hotaru::http::*;
+use hotaru::prelude::*;
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Once};
+
+use crate::op::pageprop;
+
+/// The template of the error page, under `templates/`
+const ERROR_TEMPLATE: &str = "user/error.html";
+
+tokio::task_local! {
+    /// Where the panic hook leaves the backtrace of a panicking request
+    static PANIC_TRACE: Arc<Mutex<Option<String>>>;
+}
+
+static PANIC_HOOK: Once = Once::new();
+
+static PANICS: AtomicU64 = AtomicU64::new(0);
+
+/// How many requests panicked since the server started
+pub fn panics() -> u64 {
+    PANICS.load(Ordering::Relaxed)
+}
+
+/// Keep the backtrace of panics raised under [`PanicRecovery`], still
+/// printing them the way the hook in place did
+fn install_panic_hook() {
+    PANIC_HOOK.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let caught = PANIC_TRACE.try_with(|slot| {
+                *slot.lock().unwrap() = Some(Backtrace::force_capture().to_string());
+            });
+            // Recovered panics are logged by the middleware instead
+            if caught.is_err() {
+                previous(info);
+            }
+        }));
+    });
+}
+
+/// The message a panic was raised with
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Box<dyn Any>".to_string()
+    }
+}
+
+/// The `500` answered for `request`, which panicked
+pub fn error_response(request: HttpRequest) -> HttpResponse {
+    // The request went down with its task, so the answer is made from a
+    // copy of its head: language and consent, but no user
+    let mut req = HttpReqCtx::new_client(String::new(), HttpSafety::default());
+    req.request = request;
+    if req.header_str("accept").is_some_and(|accept| accept.contains("application/json")) {
+        return json_response(object!({ success: false, message: "Internal Server Error" }))
+            .status(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    if !Path::new("templates").join(ERROR_TEMPLATE).is_file() {
+        return html_response(
+            "<!DOCTYPE html><html><head><title>500 Internal Server Error</title></head>\
+             <body><h1>500 Internal Server Error</h1><p>Something went wrong. Please try again later.</p></body></html>",
+        )
+        .status(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    akari_render!(
+        "user/error.html",
+        pageprop = pageprop(&mut req, "Error", ""),
+        status = 500,
+        message = "Something went wrong on our side. Please try again later."
+    )
+    .status(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+middleware! {
+    /// Answers `500` when the rest of the chain panics, see the module
+    /// documentation. Put it first so that it covers every middleware.
+    pub PanicRecovery <HTTP> {
+        install_panic_hook();
+        let request = HttpRequest::new(req.request.meta.clone(), HttpBody::Empty);
+        let trace = Arc::new(Mutex::new(None));
+        let err = match tokio::spawn(PANIC_TRACE.scope(trace.clone(), next(req))).await {
+            Ok(outcome) => return outcome,
+            Err(err) => err,
+        };
+
+        PANICS.fetch_add(1, Ordering::Relaxed);
+        let message = match err.try_into_panic() {
+            Ok(payload) => panic_message(payload.as_ref()),
+            Err(err) => err.to_string(),
+        };
+        let backtrace = trace.lock().unwrap().take().unwrap_or_default();
+        let method = request.meta.method().to_string();
+        let path = request.meta.path();
+        let request_id = request.meta.get_header(crate::access_log::REQUEST_ID_HEADER).unwrap_or_default();
+        tracing::error!(%method, %path, %request_id, %message, %backtrace, "Request handler panicked");
+
+        let mut req = HttpReqCtx::new_client(String::new(), HttpSafety::default());
+        req.response = if crate::dev::enabled() {
+            let failure = crate::dev::Failure { kind: "Panic", message, detail: backtrace };
+            crate::dev::error_page(StatusCode::INTERNAL_SERVER_ERROR, &method, &path, &crate::dev::headers(&request), &failure)
+        } else {
+            error_response(request)
+        };
+        Ok(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::{Layer, Next, chain, layer};
+    use crate::testing::{self, TestRequest};
+
+    #[tokio::test]
+    async fn panics_answer_500() {
+        let end: Arc<Next> = Arc::new(|req: HttpReqCtx| {
+            Box::pin(async move {
+                if req.path() == "/boom" {
+                    panic!("boom");
+                }
+                Ok(req)
+            })
+        });
+        let layers: Arc<[Layer]> = vec![layer::<PanicRecovery>()].into();
+        let before = panics();
+
+        let req = chain(layers.clone(), 0, end.clone())(TestRequest::get("/fine").build()).await.unwrap();
+        assert_eq!(testing::status(&req.response), 200);
+        assert_eq!(panics(), before);
+
+        let req = TestRequest::get("/boom").header("Accept", "application/json").build();
+        let req = chain(layers.clone(), 0, end.clone())(req).await.unwrap();
+        assert_eq!(testing::status(&req.response), 500);
+        assert_eq!(testing::json(&req.response).get("message").string(), "Internal Server Error");
+        assert!(panics() > before);
+
+        let req = chain(layers, 0, end)(TestRequest::get("/boom").build()).await.unwrap();
+        assert!(testing::text(&req.response).contains("500 Internal Server Error"));
+        assert_eq!(panic_message(&"boom"), "boom");
+    }
+}
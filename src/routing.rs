@@ -0,0 +1,155 @@
+//! routing.rs
+//!
+//! Answering a request by its method. hotaru routes a path to one endpoint
+//! whatever the method, so endpoints say which methods they take with
+//! [`methods!`](crate::methods) instead of branching on `req.method()`:
+//!
+//! ```rust,ignore
+//! use sfx::prelude::*;
+//!
+//! endpoint! {
+//!     APP.url("/shop/cart"),
+//!     pub cart <HTTP> {
+//!         methods!(req, {
+//!             GET => { json_response(cart(req)) }
+//!             POST => { add_to_cart(req).await }
+//!         })
+//!     }
+//! }
+//!
+//! endpoint! {
+//!     APP.url("/shop/checkout"),
+//!     pub checkout <HTTP> {
+//!         // Every other method is answered here
+//!         methods!(req, POST);
+//!         checkout(req).await
+//!     }
+//! }
+//! ```
+//!
+//! Other methods get `405 Method Not Allowed` with an `Allow` header and
+//! `{"success": false, "message": "Method not allowed"}`. APIs answering
+//! their errors under `error` name the key after the methods, as in
+//! `methods!(req, POST; error)`. A dispatch answers `HEAD` with its `GET`
+//! block.
+
+use hotaru::http::*;
+use hotaru::prelude::*;
+
+/// The method whose block answers a request of `method` among `listed`:
+/// `HEAD` is answered like `GET` unless it is listed itself
+pub fn dispatched(method: HttpMethod, listed: &[HttpMethod]) -> HttpMethod {
+    if method == HttpMethod::HEAD && !listed.contains(&HttpMethod::HEAD) && listed.contains(&HttpMethod::GET) {
+        return HttpMethod::GET;
+    }
+    method
+}
+
+/// `listed` with `HEAD` after `GET`, for the `Allow` header of a dispatch
+pub fn with_head(listed: &[HttpMethod]) -> Vec<HttpMethod> {
+    let mut methods = listed.to_vec();
+    if methods.contains(&HttpMethod::GET) && !methods.contains(&HttpMethod::HEAD) {
+        methods.push(HttpMethod::HEAD);
+    }
+    methods
+}
+
+/// The answer to a method the endpoint does not take; `Allow` lists `listed`
+pub fn method_not_allowed(listed: &[HttpMethod]) -> HttpResponse {
+    method_not_allowed_as("message", listed)
+}
+
+/// [`method_not_allowed`] with the text under `key`
+pub fn method_not_allowed_as(key: &str, listed: &[HttpMethod]) -> HttpResponse {
+    let allow: Vec<String> = listed.iter().map(HttpMethod::to_string).collect();
+    let mut body = object!({ success: false });
+    body.set(key, "Method not allowed");
+    json_response(body)
+        .status(StatusCode::METHOD_NOT_ALLOWED)
+        .add_header("Allow", allow.join(", "))
+}
+
+/// Answer a request by its method, or refuse the methods not listed.
+///
+/// `methods!(req, { GET => { .. } POST => { .. } })` runs the block of the
+/// method of the request, the `GET` block for `HEAD`. `methods!(req, POST)`
+/// or `methods!(req, GET | POST)` returns from the endpoint unless the
+/// request has exactly one of the methods, so code after it may still tell
+/// them apart. Other methods are answered by
+/// [`routing::method_not_allowed`](crate::routing::method_not_allowed), with
+/// the text under `key` when the methods are followed by `; key`.
+#[macro_export]
+macro_rules! methods {
+    ($req:expr, { $($method:ident => $body:block $(,)?)+ } ; $key:ident) => {{
+        let listed = [$($crate::prelude::HttpMethod::$method),+];
+        let method = $crate::routing::dispatched($req.method(), &listed);
+        $(
+            if method == $crate::prelude::HttpMethod::$method $body else
+        )+
+        { $crate::routing::method_not_allowed_as(stringify!($key), &$crate::routing::with_head(&listed)) }
+    }};
+    ($req:expr, { $($method:ident => $body:block $(,)?)+ }) => {
+        $crate::methods!($req, { $($method => $body)+ } ; message)
+    };
+    ($req:expr, $($method:ident)|+ ; $key:ident) => {
+        let listed = [$($crate::prelude::HttpMethod::$method),+];
+        if !listed.contains(&$req.method()) {
+            return $crate::routing::method_not_allowed_as(stringify!($key), &listed);
+        }
+    };
+    ($req:expr, $($method:ident)|+) => {
+        $crate::methods!($req, $($method)|+ ; message)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestRequest};
+
+    async fn cart(req: &mut HttpReqCtx) -> HttpResponse {
+        methods!(req, {
+            GET => { text_response("cart") }
+            POST => { text_response("added") }
+        })
+    }
+
+    async fn checkout(req: &mut HttpReqCtx) -> HttpResponse {
+        methods!(req, POST | PUT);
+        text_response("done")
+    }
+
+    async fn lookup(req: &mut HttpReqCtx) -> HttpResponse {
+        methods!(req, POST; error);
+        text_response("found")
+    }
+
+    #[tokio::test]
+    async fn requests_are_answered_by_their_method() {
+        let mut req = TestRequest::get("/cart").build();
+        assert_eq!(testing::text(&cart(&mut req).await), "cart");
+        let mut req = TestRequest::new(HttpMethod::HEAD, "/cart").build();
+        assert_eq!(testing::text(&cart(&mut req).await), "cart");
+        let mut req = TestRequest::post("/cart").build();
+        assert_eq!(testing::text(&cart(&mut req).await), "added");
+        let mut req = TestRequest::new(HttpMethod::DELETE, "/cart").build();
+        let res = cart(&mut req).await;
+        assert_eq!(testing::status(&res), 405);
+        assert_eq!(res.meta.get_header("Allow").as_deref(), Some("GET, POST, HEAD"));
+
+        let mut req = TestRequest::new(HttpMethod::HEAD, "/checkout").build();
+        assert_eq!(testing::status(&checkout(&mut req).await), 405);
+        let mut req = TestRequest::get("/checkout").build();
+        let res = checkout(&mut req).await;
+        assert_eq!(res.meta.get_header("Allow").as_deref(), Some("POST, PUT"));
+        assert_eq!(testing::status(&res), 405);
+        assert!(!testing::json(&res).get("success").boolean());
+        assert_eq!(testing::json(&res).get("message").string(), "Method not allowed");
+        let mut req = TestRequest::new(HttpMethod::PUT, "/checkout").build();
+        assert_eq!(testing::text(&checkout(&mut req).await), "done");
+
+        let mut req = TestRequest::get("/lookup").build();
+        let res = lookup(&mut req).await;
+        assert_eq!((testing::status(&res), testing::json(&res).get("error").string()), (405, "Method not allowed".to_string()));
+    }
+}
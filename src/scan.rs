@@ -0,0 +1,378 @@
+//! scan.rs
+//!
+//! Malware scanning of files uploaded to the media library. The scanner is
+//! set in `programfiles/op/scan.json`:
+//!
+//! ```json
+//! {
+//!     "scanner": "clamav",
+//!     "clamav": "unix:/run/clamav/clamd.ctl",
+//!     "command": "clamscan --no-summary {}",
+//!     "on_error": "reject",
+//!     "webhooks": ["https://alerts.example.com/sfx/malware"],
+//!     "secret": "env:SCAN_WEBHOOK_TOKEN"
+//! }
+//! ```
+//!
+//! - `clamav` streams the file to clamd (`INSTREAM`) at `unix:<socket>` or
+//!   `host:port`.
+//! - `command` runs a program (split on whitespace, no shell) on a private
+//!   temporary copy of the file, whose path replaces `{}` or is appended.
+//!   Exit code 0 means clean and 1 infected, as with `clamscan`; the
+//!   signature is read from a `...: <name> FOUND` line.
+//! - `none` (the default) scans nothing.
+//!
+//! `on_error` decides uploads the scanner could not check: `reject` (the
+//! default) refuses them, `accept` keeps them with the failure recorded.
+//! Flagged files are quarantined by [`crate::media`], and every webhook gets
+//! the item, `secret` being the bearer token. Apps can plug in another
+//! engine with [`set`].
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+static SCAN: Lazy<ScanSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/scan.json");
+    ScanSettings::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None))
+});
+
+static SCANNER: Lazy<RwLock<Option<Arc<dyn Scanner>>>> = Lazy::new(|| {
+    let scanner: Option<Arc<dyn Scanner>> = match SCAN.scanner.as_str() {
+        "clamav" => Some(Arc::new(ClamAv { address: SCAN.clamav.clone() })),
+        "command" => Some(Arc::new(CommandScanner { command: SCAN.command.clone() })),
+        _ => None,
+    };
+    RwLock::new(scanner)
+});
+
+/// Bytes sent to clamd per `INSTREAM` chunk
+const CHUNK: usize = 64 * 1024;
+
+/// What a [`Scanner`] makes of a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Clean,
+    /// Flagged, with the name of what was found
+    Infected(String),
+}
+
+/// What [`Scanner::scan`] returns; `Err` when the file could not be checked
+pub type ScanFuture<'a> = Pin<Box<dyn Future<Output = Result<Verdict, String>> + Send + 'a>>;
+
+/// A malware scanning engine
+pub trait Scanner: Send + Sync {
+    /// Name recorded with the results, `clamav`
+    fn name(&self) -> &'static str;
+
+    fn scan<'a>(&'a self, bytes: &'a [u8]) -> ScanFuture<'a>;
+}
+
+/// What to do with uploads the scanner failed on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnError {
+    Reject,
+    Accept,
+}
+
+/// The parsed content of `scan.json`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanSettings {
+    /// `none`, `clamav` or `command`
+    pub scanner: String,
+    /// Address of clamd, `unix:/path` or `host:port`
+    pub clamav: String,
+    pub command: String,
+    pub on_error: OnError,
+    pub webhooks: Vec<String>,
+    /// Reference to the bearer token of the webhooks, see `crate::secrets`
+    pub secret: String,
+}
+
+impl ScanSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let scanner = value.get("scanner").string();
+        let clamav = value.get("clamav").string();
+        Self {
+            scanner: if scanner.is_empty() { "none".to_string() } else { scanner },
+            clamav: if clamav.is_empty() { "127.0.0.1:3310".to_string() } else { clamav },
+            command: value.get("command").string(),
+            on_error: if value.get("on_error").string() == "accept" { OnError::Accept } else { OnError::Reject },
+            webhooks: match value.get("webhooks") {
+                Value::List(urls) => urls.iter().map(|url| url.string()).filter(|url| !url.is_empty()).collect(),
+                _ => Vec::new(),
+            },
+            secret: value.get("secret").string(),
+        }
+    }
+}
+
+/// How a scan ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanStatus {
+    Clean,
+    Infected,
+    /// The scanner could not check the file
+    Failed,
+}
+
+impl ScanStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScanStatus::Clean => "clean",
+            ScanStatus::Infected => "infected",
+            ScanStatus::Failed => "failed",
+        }
+    }
+}
+
+/// The scan of a file, as kept with it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanResult {
+    pub scanner: String,
+    pub status: ScanStatus,
+    /// What was found, or why the scan failed
+    pub detail: String,
+    pub time: u64,
+}
+
+impl ScanResult {
+    pub fn from_json(value: &Value) -> Option<Self> {
+        let status = match value.get("status").string().as_str() {
+            "clean" => ScanStatus::Clean,
+            "infected" => ScanStatus::Infected,
+            "failed" => ScanStatus::Failed,
+            _ => return None,
+        };
+        Some(Self {
+            scanner: value.get("scanner").string(),
+            status,
+            detail: value.get("detail").string(),
+            time: value.get("time").integer().max(0) as u64,
+        })
+    }
+
+    pub fn into_json(&self) -> Value {
+        object!({
+            scanner: &self.scanner,
+            status: self.status.as_str(),
+            detail: &self.detail,
+            time: self.time,
+        })
+    }
+}
+
+/// The meaning of a clamd reply such as `stream: Eicar-Signature FOUND`
+fn clamd_verdict(reply: &str) -> Result<Verdict, String> {
+    let reply = reply.trim_end_matches(['\0', '\n']).trim();
+    let result = reply.strip_prefix("stream:").unwrap_or(reply).trim();
+    if result == "OK" {
+        Ok(Verdict::Clean)
+    } else if let Some(name) = result.strip_suffix(" FOUND") {
+        Ok(Verdict::Infected(name.trim().to_string()))
+    } else {
+        Err(format!("clamd answered {:?}", reply))
+    }
+}
+
+/// clamd, spoken to over its socket
+pub struct ClamAv {
+    pub address: String,
+}
+
+impl ClamAv {
+    async fn instream(stream: &mut (impl AsyncRead + AsyncWrite + Unpin), bytes: &[u8]) -> std::io::Result<String> {
+        stream.write_all(b"zINSTREAM\0").await?;
+        for chunk in bytes.chunks(CHUNK) {
+            stream.write_all(&(chunk.len() as u32).to_be_bytes()).await?;
+            stream.write_all(chunk).await?;
+        }
+        stream.write_all(&0u32.to_be_bytes()).await?;
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).await?;
+        Ok(reply)
+    }
+}
+
+impl Scanner for ClamAv {
+    fn name(&self) -> &'static str {
+        "clamav"
+    }
+
+    fn scan<'a>(&'a self, bytes: &'a [u8]) -> ScanFuture<'a> {
+        Box::pin(async move {
+            let reply = match self.address.strip_prefix("unix:") {
+                #[cfg(unix)]
+                Some(path) => match tokio::net::UnixStream::connect(path).await {
+                    Ok(mut stream) => Self::instream(&mut stream, bytes).await,
+                    Err(err) => Err(err),
+                },
+                #[cfg(not(unix))]
+                Some(_) => Err(std::io::Error::other("unix sockets are not supported here")),
+                None => match tokio::net::TcpStream::connect(&self.address).await {
+                    Ok(mut stream) => Self::instream(&mut stream, bytes).await,
+                    Err(err) => Err(err),
+                },
+            };
+            clamd_verdict(&reply.map_err(|err| format!("clamd at {}: {}", self.address, err))?)
+        })
+    }
+}
+
+/// A program run on a temporary copy of the file
+pub struct CommandScanner {
+    pub command: String,
+}
+
+impl CommandScanner {
+    fn run(command: &str, bytes: &[u8]) -> Result<Verdict, String> {
+        let path = std::env::temp_dir().join(format!("sfx-scan-{}", hotaru_lib::random::random_alphanumeric_string(16)));
+        crate::secrets::write_private(&path, bytes).map_err(|err| format!("cannot write {}: {}", path.display(), err))?;
+        let file = path.to_string_lossy().into_owned();
+        let mut words: Vec<String> = command.split_whitespace().map(str::to_string).collect();
+        match words.iter_mut().find(|word| word.as_str() == "{}") {
+            Some(word) => *word = file,
+            None => words.push(file),
+        }
+        let output = std::process::Command::new(&words[0]).args(&words[1..]).output();
+        let _ = std::fs::remove_file(&path);
+        let output = output.map_err(|err| format!("cannot run {}: {}", words[0], err))?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        match output.status.code() {
+            Some(0) => Ok(Verdict::Clean),
+            Some(1) => {
+                let name = stdout
+                    .lines()
+                    .filter_map(|line| line.trim().strip_suffix(" FOUND"))
+                    .filter_map(|line| line.rsplit(": ").next())
+                    .next()
+                    .unwrap_or("flagged");
+                Ok(Verdict::Infected(name.to_string()))
+            }
+            _ => Err(format!("{} exited with {}", words[0], output.status)),
+        }
+    }
+}
+
+impl Scanner for CommandScanner {
+    fn name(&self) -> &'static str {
+        "command"
+    }
+
+    fn scan<'a>(&'a self, bytes: &'a [u8]) -> ScanFuture<'a> {
+        Box::pin(async move {
+            if self.command.split_whitespace().next().is_none() {
+                return Err("no scan command set".to_string());
+            }
+            let (command, bytes) = (self.command.clone(), bytes.to_vec());
+            tokio::task::spawn_blocking(move || Self::run(&command, &bytes)).await.map_err(|err| err.to_string())?
+        })
+    }
+}
+
+/// The loaded scan settings
+pub fn settings() -> &'static ScanSettings {
+    &SCAN
+}
+
+/// Scan with `scanner` instead of the one of `scan.json`
+pub fn set(scanner: impl Scanner + 'static) {
+    *SCANNER.write().unwrap() = Some(Arc::new(scanner));
+}
+
+/// Scan `bytes`. `None` when no scanner is set.
+pub async fn scan(bytes: &[u8]) -> Option<ScanResult> {
+    let scanner = SCANNER.read().unwrap().clone()?;
+    let (status, detail) = match scanner.scan(bytes).await {
+        Ok(Verdict::Clean) => (ScanStatus::Clean, String::new()),
+        Ok(Verdict::Infected(name)) => (ScanStatus::Infected, name),
+        Err(err) => {
+            tracing::error!(scanner = scanner.name(), %err, "Malware scan failed");
+            (ScanStatus::Failed, err)
+        }
+    };
+    // Shown on the admin page as is
+    let detail = detail.chars().filter(|c| !c.is_control() && !"\"'<>&`".contains(*c)).take(200).collect();
+    let time = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+    Some(ScanResult { scanner: scanner.name().to_string(), status, detail, time })
+}
+
+/// Post `item`, a quarantined file, to the webhooks of `scan.json`
+pub fn notify(item: Value) {
+    if SCAN.webhooks.is_empty() {
+        return;
+    }
+    let secret = crate::secrets::load(&SCAN.secret, "scan.json secret");
+    for url in &SCAN.webhooks {
+        let (origin, path) = crate::local_auth::rules::split_url(url);
+        let body = object!({ event: "media_quarantined", item: item.clone() });
+        let secret = secret.clone();
+        tokio::spawn(async move {
+            let mut meta = HttpMeta::new(HttpStartLine::request_post(&path), HashMap::new());
+            meta.set_content_type(HttpContentType::ApplicationJson());
+            let mut request = HttpRequest::new(meta, HttpBody::Json(body));
+            if !secret.is_empty() {
+                request = request.add_header("Authorization", format!("Bearer {}", secret));
+            }
+            if let Err(err) = crate::user::fetch::send_http_request(origin.clone(), request, HttpSafety::default()).await {
+                tracing::warn!(%origin, %path, ?err, "Malware webhook failed");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn scanners_report_clean_infected_and_failed_files() {
+        assert_eq!(clamd_verdict("stream: OK\0"), Ok(Verdict::Clean));
+        assert_eq!(clamd_verdict("stream: Eicar-Test-Signature FOUND\0"), Ok(Verdict::Infected("Eicar-Test-Signature".into())));
+        assert!(clamd_verdict("INSTREAM size limit exceeded. ERROR\0").is_err());
+
+        // A clamd that flags anything containing "EICAR"
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let clamav = ClamAv { address: listener.local_addr().unwrap().to_string() };
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut command = [0u8; 10];
+                stream.read_exact(&mut command).await.unwrap();
+                assert_eq!(&command, b"zINSTREAM\0");
+                let mut data = Vec::new();
+                loop {
+                    let length = stream.read_u32().await.unwrap() as usize;
+                    if length == 0 {
+                        break;
+                    }
+                    let mut chunk = vec![0; length];
+                    stream.read_exact(&mut chunk).await.unwrap();
+                    data.extend(chunk);
+                }
+                let flagged = data.windows(5).any(|window| window == b"EICAR");
+                let reply: &[u8] = if flagged { b"stream: Eicar-Test-Signature FOUND\0" } else { b"stream: OK\0" };
+                stream.write_all(reply).await.unwrap();
+            }
+        });
+        let mut large = vec![b'x'; CHUNK * 2 + 10];
+        assert_eq!(clamav.scan(&large).await, Ok(Verdict::Clean));
+        large.extend(b"EICAR");
+        assert_eq!(clamav.scan(&large).await, Ok(Verdict::Infected("Eicar-Test-Signature".into())));
+        assert!(ClamAv { address: "127.0.0.1:1".into() }.scan(b"x").await.is_err());
+
+        assert_eq!(CommandScanner { command: "true".into() }.scan(b"x").await, Ok(Verdict::Clean));
+        assert_eq!(CommandScanner { command: "false {}".into() }.scan(b"x").await, Ok(Verdict::Infected("flagged".into())));
+        assert!(CommandScanner { command: "sh -c".into() }.scan(b"x").await.is_err());
+        assert!(CommandScanner { command: String::new() }.scan(b"x").await.is_err());
+
+        let settings = ScanSettings::from_value(&Value::None);
+        assert_eq!((settings.scanner.as_str(), settings.on_error), ("none", OnError::Reject));
+        let result = ScanResult { scanner: "clamav".into(), status: ScanStatus::Infected, detail: "Eicar".into(), time: 1 };
+        assert_eq!(ScanResult::from_json(&result.into_json()), Some(result));
+    }
+}
@@ -0,0 +1,228 @@
+//! secrets.rs
+//!
+//! Secrets in `programfiles` (session keys, the logout notification secret,
+//! the CAPTCHA and form token secrets, the database URL) may be written as
+//! a reference instead of the secret itself:
+//!
+//! - `env:NAME` reads the environment variable `NAME`.
+//! - `file:secrets/session.key` reads a file, relative to `programfiles`
+//!   unless absolute. The file must not be readable by group or others.
+//! - `cmd:vault kv get -field=key secret/sfx` runs a command (split on
+//!   whitespace, no shell) in `programfiles` and takes what it prints. This
+//!   is the hook for KMS and vault CLIs.
+//!
+//! Surrounding whitespace is trimmed. Any other value is taken as the
+//! secret itself; `sfx config check` warns about those. Apps can add
+//! schemes with [`register`].
+
+use std::fmt;
+use std::path::Path;
+use std::sync::RwLock;
+
+use hotaru::prelude::Lazy;
+
+/// Directory of `programfiles` for secret files. Scaffolding makes its files
+/// private, `.gitignore` and backups leave it out.
+pub const DIR: &str = "secrets";
+
+/// Why a secret could not be loaded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SecretError {
+    /// The variable, file or command does not exist
+    Missing(String),
+    /// The file is readable by other users
+    Insecure(String),
+    /// The command failed or printed nothing
+    Failed(String),
+}
+
+impl fmt::Display for SecretError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretError::Missing(message) | SecretError::Insecure(message) | SecretError::Failed(message) => {
+                message.fmt(f)
+            }
+        }
+    }
+}
+
+/// A source of secrets, answering the references of one scheme
+pub trait SecretProvider: Send + Sync {
+    /// The prefix of the references it answers, `env` for `env:NAME`
+    fn scheme(&self) -> &'static str;
+
+    /// The secret `reference` (without the scheme) points to. Relative
+    /// paths are resolved from `programfiles`.
+    fn fetch(&self, reference: &str, programfiles: &Path) -> Result<String, SecretError>;
+}
+
+/// `env:NAME`
+pub struct EnvProvider;
+
+impl SecretProvider for EnvProvider {
+    fn scheme(&self) -> &'static str {
+        "env"
+    }
+
+    fn fetch(&self, reference: &str, _: &Path) -> Result<String, SecretError> {
+        std::env::var(reference).map_err(|_| SecretError::Missing(format!("environment variable {} is not set", reference)))
+    }
+}
+
+/// `file:path`
+pub struct FileProvider;
+
+impl SecretProvider for FileProvider {
+    fn scheme(&self) -> &'static str {
+        "file"
+    }
+
+    fn fetch(&self, reference: &str, programfiles: &Path) -> Result<String, SecretError> {
+        let path = programfiles.join(reference);
+        let metadata = std::fs::metadata(&path)
+            .map_err(|err| SecretError::Missing(format!("cannot read {}: {}", path.display(), err)))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = metadata.permissions().mode();
+            if mode & 0o077 != 0 {
+                return Err(SecretError::Insecure(format!(
+                    "{} is accessible to other users (mode {:o}), run `chmod 600` on it",
+                    path.display(),
+                    mode & 0o777
+                )));
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = metadata;
+        std::fs::read_to_string(&path)
+            .map_err(|err| SecretError::Missing(format!("cannot read {}: {}", path.display(), err)))
+    }
+}
+
+/// `cmd:program args...`
+pub struct CommandProvider;
+
+impl SecretProvider for CommandProvider {
+    fn scheme(&self) -> &'static str {
+        "cmd"
+    }
+
+    fn fetch(&self, reference: &str, programfiles: &Path) -> Result<String, SecretError> {
+        let mut words = reference.split_whitespace();
+        let program = words.next().ok_or_else(|| SecretError::Failed("no command given".to_string()))?;
+        let mut command = std::process::Command::new(program);
+        command.args(words);
+        if programfiles.is_dir() {
+            command.current_dir(programfiles);
+        }
+        let output = command
+            .output()
+            .map_err(|err| SecretError::Missing(format!("cannot run {}: {}", program, err)))?;
+        if !output.status.success() {
+            return Err(SecretError::Failed(format!("{} exited with {}", program, output.status)));
+        }
+        String::from_utf8(output.stdout).map_err(|_| SecretError::Failed(format!("{} printed invalid UTF-8", program)))
+    }
+}
+
+static PROVIDERS: Lazy<RwLock<Vec<Box<dyn SecretProvider>>>> =
+    Lazy::new(|| RwLock::new(vec![Box::new(EnvProvider), Box::new(FileProvider), Box::new(CommandProvider)]));
+
+/// Add a provider, e.g. one calling a KMS API directly. A provider with the
+/// scheme of an earlier one replaces it.
+pub fn register(provider: impl SecretProvider + 'static) {
+    let mut providers = PROVIDERS.write().unwrap();
+    providers.retain(|existing| existing.scheme() != provider.scheme());
+    providers.push(Box::new(provider));
+}
+
+/// Write a file only its owner can read, as the `file:` provider wants it
+pub fn write_private(path: &Path, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        // The mode only applies to new files
+        if path.exists() {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    options.open(path)?.write_all(contents.as_ref())
+}
+
+/// Whether `value` refers to a secret rather than holding it
+pub fn is_reference(value: &str) -> bool {
+    value
+        .split_once(':')
+        .is_some_and(|(scheme, _)| PROVIDERS.read().unwrap().iter().any(|provider| provider.scheme() == scheme))
+}
+
+/// The secret `value` stands for, references being resolved from the
+/// `programfiles` directory `programfiles`
+pub fn resolve_in(value: &str, programfiles: &Path) -> Result<String, SecretError> {
+    let providers = PROVIDERS.read().unwrap();
+    let provider = value
+        .split_once(':')
+        .and_then(|(scheme, reference)| Some((providers.iter().find(|p| p.scheme() == scheme)?, reference)));
+    match provider {
+        Some((provider, reference)) => {
+            let secret = provider.fetch(reference.trim(), programfiles)?.trim().to_string();
+            if secret.is_empty() {
+                return Err(SecretError::Failed(format!("{} is empty", value)));
+            }
+            Ok(secret)
+        }
+        None => Ok(value.to_string()),
+    }
+}
+
+/// [`resolve_in`] the configured `programfiles`
+pub fn resolve(value: &str) -> Result<String, SecretError> {
+    resolve_in(value, &crate::op::programfiles())
+}
+
+/// The secret `value` stands for, or an empty string after logging why it
+/// could not be loaded. `what` names the setting in the log.
+pub fn load(value: &str, what: &str) -> String {
+    resolve(value).unwrap_or_else(|err| {
+        tracing::error!(what, %err, "Cannot load secret");
+        String::new()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn references_are_resolved_by_their_provider() {
+        let dir = std::env::temp_dir().join(format!("sfx-secrets-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        assert_eq!(resolve_in("plain", &dir), Ok("plain".to_string()));
+        assert_eq!(resolve_in("postgres://sfx@db/sfx", &dir), Ok("postgres://sfx@db/sfx".to_string()));
+        assert!(!is_reference("postgres://sfx@db/sfx"));
+        assert!(is_reference("env:X"));
+
+        assert_eq!(resolve_in("env:PATH", &dir), Ok(std::env::var("PATH").unwrap().trim().to_string()));
+        assert!(matches!(resolve_in("env:SFX_SECRETS_TEST_UNSET", &dir), Err(SecretError::Missing(_))));
+
+        assert_eq!(resolve_in("cmd:echo  s3cret", &dir), Ok("s3cret".to_string()));
+        assert!(matches!(resolve_in("cmd:false", &dir), Err(SecretError::Failed(_))));
+
+        std::fs::write(dir.join("key"), "from-file\n").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(dir.join("key"), std::fs::Permissions::from_mode(0o644)).unwrap();
+            assert!(matches!(resolve_in("file:key", &dir), Err(SecretError::Insecure(_))));
+            std::fs::set_permissions(dir.join("key"), std::fs::Permissions::from_mode(0o600)).unwrap();
+        }
+        assert_eq!(resolve_in("file:key", &dir), Ok("from-file".to_string()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+//! security_headers.rs
+//!
+//! Response headers hardening every page, from
+//! `programfiles/op/security_headers.json`:
+//!
+//! ```json
+//! {
+//!     "content_security_policy": "default-src 'self'; script-src 'self' 'nonce-{nonce}'",
+//!     "report_only": false,
+//!     "headers": { "X-Content-Type-Options": "nosniff" }
+//! }
+//! ```
+//!
+//! Each request gets a fresh nonce, which replaces `{nonce}` in the policy
+//! and is handed to templates as `pageprop.nonce`, so inline scripts run
+//! under a policy without `'unsafe-inline'` when tagged
+//! `<script nonce="-[ pageprop["nonce"] ]-">`. With `report_only` the policy
+//! is sent as `Content-Security-Policy-Report-Only` to try it out first.
+//! Headers a handler already set are left alone. A missing file sends no
+//! header at all.
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+use hotaru_lib::random::random_alphanumeric_string;
+
+static SECURITY_HEADERS: Lazy<SecurityHeaderSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/security_headers.json");
+    SecurityHeaderSettings::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None))
+});
+
+/// Placeholder of the policy replaced by the nonce of the request
+pub const NONCE_PLACEHOLDER: &str = "{nonce}";
+
+/// The CSP nonce of a request
+#[derive(Debug, Clone, Default)]
+pub struct CspNonce(pub String);
+
+/// The parsed content of `security_headers.json`
+#[derive(Debug, Clone, Default)]
+pub struct SecurityHeaderSettings {
+    pub content_security_policy: String,
+    pub report_only: bool,
+    pub headers: Vec<(String, String)>,
+}
+
+impl SecurityHeaderSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let mut headers: Vec<(String, String)> = match value.get("headers") {
+            Value::Dict(map) => map.iter().map(|(name, value)| (name.clone(), value.string())).collect(),
+            _ => Vec::new(),
+        };
+        headers.sort();
+        Self {
+            content_security_policy: value.get("content_security_policy").string(),
+            report_only: value.get("report_only").boolean(),
+            headers,
+        }
+    }
+
+    /// The CSP header name and value for a request with `nonce`
+    pub fn policy(&self, nonce: &str) -> Option<(&'static str, String)> {
+        if self.content_security_policy.is_empty() {
+            return None;
+        }
+        let name = if self.report_only {
+            "Content-Security-Policy-Report-Only"
+        } else {
+            "Content-Security-Policy"
+        };
+        Some((name, self.content_security_policy.replace(NONCE_PLACEHOLDER, nonce)))
+    }
+}
+
+/// The loaded header settings
+pub fn settings() -> &'static SecurityHeaderSettings {
+    &SECURITY_HEADERS
+}
+
+/// The CSP nonce of `req`, empty outside `SecurityHeaders`
+pub fn nonce(req: &HttpReqCtx) -> String {
+    req.params.get::<CspNonce>().map(|nonce| nonce.0.clone()).unwrap_or_default()
+}
+
+middleware! {
+    /// Adds the headers of `security_headers.json` to every response, the
+    /// CSP carrying a nonce made for the request
+    pub SecurityHeaders <HTTP> {
+        let nonce = random_alphanumeric_string(24);
+        req.params.set(CspNonce(nonce.clone()));
+        let mut req = next(req).await?;
+
+        let mut headers = SECURITY_HEADERS.headers.clone();
+        if let Some((name, policy)) = SECURITY_HEADERS.policy(&nonce) {
+            headers.push((name.to_string(), policy));
+        }
+        for (name, value) in headers {
+            if req.response.meta.get_header(name.as_str()).is_none() {
+                req.response = req.response.add_header(name, value);
+            }
+        }
+        Ok(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nonce_is_put_into_the_policy() {
+        let settings = SecurityHeaderSettings::from_value(&Value::from_json(r#"{
+            "content_security_policy": "script-src 'self' 'nonce-{nonce}'",
+            "headers": { "X-Frame-Options": "DENY", "X-Content-Type-Options": "nosniff" }
+        }"#).unwrap());
+        assert_eq!(
+            settings.policy("abc"),
+            Some(("Content-Security-Policy", "script-src 'self' 'nonce-abc'".to_string()))
+        );
+        assert_eq!(settings.headers[0], ("X-Content-Type-Options".to_string(), "nosniff".to_string()));
+
+        let report_only = SecurityHeaderSettings { report_only: true, ..settings };
+        assert_eq!(report_only.policy("abc").unwrap().0, "Content-Security-Policy-Report-Only");
+        assert!(SecurityHeaderSettings::from_value(&Value::None).policy("abc").is_none());
+    }
+}
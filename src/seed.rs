@@ -0,0 +1,232 @@
+//! seed.rs
+//!
+//! Demo data for a local environment: local accounts `demo1`, `demo2`, …,
+//! the first of them admins, published blog posts and comments on them.
+//! Run by `sfx seed` with the server stopped, or by `POST /admin/dev/seed`
+//! on a server started with `SFX_ENV=development`.
+//!
+//! Seeding twice adds nothing twice: accounts and posts that exist are
+//! kept as they are, so the numbers can be raised on a later run.
+
+use hotaru::http::*;
+use hotaru::prelude::*;
+
+use crate::admin::check_is_admin;
+use crate::blog::{self, PostDraft, PostStatus};
+use crate::comments::{self, NewComment, Poster};
+use crate::local_auth::LOCAL_AUTH;
+use crate::local_auth::fop::AuthManager;
+use crate::op::{self, APP};
+use crate::methods;
+
+const TITLES: &[&str] = &[
+    "Welcome to the demo site",
+    "Release notes",
+    "A week of small fixes",
+    "How we deploy",
+    "Questions from the community",
+    "Plans for next quarter",
+];
+
+const TAGS: &[&str] = &["news", "release", "notes", "community"];
+
+const REMARKS: &[&str] = &[
+    "Thanks for writing this up!",
+    "Looking forward to the next one.",
+    "Could you say more about the second part?",
+    "This helped a lot, thank you.",
+];
+
+/// How much to seed
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeedPlan {
+    pub users: usize,
+    /// How many of the users are admins, from the first
+    pub admins: usize,
+    pub posts: usize,
+    /// Comments on each post
+    pub comments: usize,
+    /// The password of every demo account
+    pub password: String,
+}
+
+impl Default for SeedPlan {
+    fn default() -> Self {
+        Self { users: 10, admins: 1, posts: 5, comments: 3, password: "Demo1234".to_string() }
+    }
+}
+
+impl SeedPlan {
+    /// The plan of the fields of `form`, the defaults for those missing
+    pub fn from_form(form: &UrlEncodedForm) -> Self {
+        let default = Self::default();
+        let number = |key: &str, fallback: usize| form.get(key).and_then(|value| value.parse().ok()).unwrap_or(fallback);
+        Self {
+            users: number("users", default.users),
+            admins: number("admins", default.admins),
+            posts: number("posts", default.posts),
+            comments: number("comments", default.comments),
+            password: form.get("password").filter(|password| !password.is_empty()).cloned().unwrap_or(default.password),
+        }
+    }
+}
+
+/// What a seeding made
+#[derive(Debug, Clone, Default)]
+pub struct SeedReport {
+    /// The accounts made, with their uids
+    pub users: Vec<(u32, String)>,
+    /// Entries of `admins.json` added
+    pub admins: Vec<String>,
+    /// Slugs of the posts made
+    pub posts: Vec<String>,
+    pub comments: usize,
+    /// What could not be made, and why
+    pub errors: Vec<String>,
+}
+
+impl SeedReport {
+    pub fn to_json(&self) -> Value {
+        let users: Vec<Value> = self.users.iter().map(|(uid, username)| object!({ uid: *uid, username: username })).collect();
+        object!({
+            users: Value::List(users),
+            admins: self.admins.clone(),
+            posts: self.posts.clone(),
+            comments: self.comments,
+            errors: self.errors.clone(),
+        })
+    }
+}
+
+fn body(index: usize) -> String {
+    format!(
+        "This is demo post number {}, made by `sfx seed`.\n\n\
+         It has a list:\n\n- one item\n- another item\n\n\
+         And a [link to the blog](/blog).",
+        index
+    )
+}
+
+/// Seed `auth` and the content of `programfiles` following `plan`
+pub async fn seed(auth: &AuthManager, plan: &SeedPlan) -> SeedReport {
+    let mut report = SeedReport::default();
+
+    let mut authors: Vec<(u32, String)> = Vec::new();
+    for index in 1..=plan.users {
+        let username = format!("demo{}", index);
+        let uid = match auth.get_uid_by_username(&username).await {
+            Some(uid) => uid,
+            None => {
+                let email = format!("{}@example.com", username);
+                if let Err(err) = auth.register_user(&username, &email, &plan.password).await {
+                    report.errors.push(format!("User {}: {}", username, err.to_string()));
+                    continue;
+                }
+                match auth.get_uid_by_username(&username).await {
+                    Some(uid) => {
+                        report.users.push((uid, username.clone()));
+                        uid
+                    }
+                    None => continue,
+                }
+            }
+        };
+        if index <= plan.admins {
+            let entry = format!("{}@local", uid);
+            if !op::read_admin_entries().contains(&entry) {
+                match op::add_admin_entry(&entry) {
+                    Ok(()) => report.admins.push(entry),
+                    Err(err) => report.errors.push(format!("Admin {}: {}", username, err)),
+                }
+            }
+        }
+        authors.push((uid, username));
+    }
+
+    for index in 1..=plan.posts {
+        let slug = format!("demo-post-{}", index);
+        if blog::get(&slug).is_some() {
+            continue;
+        }
+        let (author, author_name) = match authors.get((index - 1) % authors.len().max(1)) {
+            Some((uid, username)) => (format!("{}@local", uid), username.clone()),
+            None => (String::new(), "Demo".to_string()),
+        };
+        let draft = PostDraft {
+            original: String::new(),
+            slug: slug.clone(),
+            title: TITLES[(index - 1) % TITLES.len()].to_string(),
+            tags: format!("demo, {}", TAGS[(index - 1) % TAGS.len()]),
+            summary: String::new(),
+            body: body(index),
+        };
+        if let Err(err) = blog::save(draft, &author, &author_name).and_then(|_| blog::set_status(&slug, PostStatus::Published)) {
+            report.errors.push(format!("Post {}: {}", slug, err));
+            continue;
+        }
+        report.posts.push(slug.clone());
+
+        for number in 0..plan.comments {
+            let Some((uid, username)) = authors.get((index + number) % authors.len().max(1)) else {
+                break;
+            };
+            let new = NewComment {
+                content: format!("blog/{}", slug),
+                parent: None,
+                body: REMARKS[(index + number) % REMARKS.len()].to_string(),
+                name: String::new(),
+            };
+            let poster = Poster::User { id: format!("{}@local", uid), name: username.clone(), shadowbanned: false };
+            match comments::post(new, &poster) {
+                Ok(_) => report.comments += 1,
+                Err(err) => report.errors.push(format!("Comment on {}: {}", slug, err)),
+            }
+        }
+    }
+    report
+}
+
+endpoint! {
+    APP.url("/admin/dev/seed"),
+
+    /// POST /admin/dev/seed - Fill the site with demo data
+    /// Only with `SFX_ENV=development` (or `beta`); `404` otherwise.
+    /// Form -> users, admins, posts, comments, password (all optional)
+    /// Response: {"success": true, "users": [{"uid": 2, "username": "demo1"}], "admins": ["2@local"],
+    ///            "posts": ["demo-post-1"], "comments": 3, "errors": []}
+    pub admin_dev_seed <HTTP> {
+        if op::run_mode() == RunMode::Production {
+            return text_response("404 Not Found").status(StatusCode::NOT_FOUND);
+        }
+        if !check_is_admin(req).await {
+            return json_response(object!({ success: false, message: "Unauthorized" })).status(StatusCode::UNAUTHORIZED);
+        }
+        methods!(req, POST);
+        let plan = SeedPlan::from_form(req.form_or_default().await);
+        let report = seed(&LOCAL_AUTH, &plan).await;
+        tracing::info!(users = report.users.len(), posts = report.posts.len(), comments = report.comments, "Seeded demo data");
+        let mut response = report.to_json();
+        response.set("success", true);
+        json_response(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[tokio::test]
+    async fn demo_accounts_are_made_once() {
+        let auth = testing::auth_manager(&[("demo2", "Aa333333")]).await;
+        let plan = SeedPlan { users: 3, admins: 0, posts: 0, ..SeedPlan::default() };
+        let report = seed(&auth, &plan).await;
+        assert_eq!(report.users.iter().map(|(_, username)| username.as_str()).collect::<Vec<_>>(), vec!["demo1", "demo3"]);
+        assert!(report.errors.is_empty());
+        assert!(seed(&auth, &plan).await.users.is_empty());
+        assert!(auth.check_password(2, "Demo1234").await);
+
+        let form = UrlEncodedForm { data: [("users".to_string(), "4".to_string()), ("posts".to_string(), "x".to_string())].into() };
+        assert_eq!(SeedPlan::from_form(&form), SeedPlan { users: 4, ..SeedPlan::default() });
+    }
+}
@@ -0,0 +1,223 @@
+//! session.rs
+//!
+//! The cookie session, sealed with the keys of `programfiles/op/session.json`
+//! so that keys can be rotated without logging everybody out:
+//!
+//! ```json
+//! {
+//!     "keys": [
+//!         { "id": "2026-10", "secret": "file:secrets/session-2026-10.key" },
+//!         { "id": "2026-04", "secret": "env:SFX_PREVIOUS_SESSION_KEY" }
+//!     ],
+//!     "retired": ["2025-10"]
+//! }
+//! ```
+//!
+//! The first key seals every cookie written. The others still open the
+//! cookies they sealed, which are then sealed again with the first key, so
+//! a key can be dropped once its sessions had time to come back. Sessions
+//! sealed with a key listed in `retired`, or with a key no longer listed at
+//! all, are discarded. `sfx config rotate-session-key` adds a new first key.
+//!
+//! Secrets are usually `file:` references into `programfiles/secrets`, see
+//! `crate::secrets`. Cookies are encrypted and authenticated (AES-256-GCM)
+//! with a key derived from the secret and the session id. Without any
+//! usable key a random one is generated at startup, so sessions do not
+//! survive a restart.
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+use hotaru_lib::ende::aes;
+use htmstd::CookieSessionSettings;
+use htmstd::session::CSessionRW;
+use htmstd::session::session_counter::generate_session_id;
+use std::collections::HashMap;
+
+/// Shorter secrets are ignored; the key derivation does no stretching
+pub const MIN_SECRET_LEN: usize = 32;
+
+/// Id of the key generated when `session.json` has none
+const EPHEMERAL: &str = "ephemeral";
+
+static SESSION: Lazy<SessionSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/session.json");
+    let mut settings = SessionSettings::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None));
+    for key in &mut settings.keys {
+        key.secret = crate::secrets::load(&key.secret, &format!("session.json key {}", key.id));
+    }
+    if settings.current().is_none() {
+        tracing::warn!("session.json has no usable key, sessions will not survive a restart");
+        settings.keys.insert(0, SessionKey {
+            id: EPHEMERAL.to_string(),
+            secret: hotaru_lib::random::random_string(64),
+        });
+    }
+    settings
+});
+
+/// One key of the ring
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionKey {
+    pub id: String,
+    pub secret: String,
+}
+
+impl SessionKey {
+    /// Whether the key can seal sessions: a long enough secret, and an id
+    /// that fits in front of the cookie
+    pub fn is_usable(&self) -> bool {
+        !self.id.is_empty() && !self.id.contains('.') && self.secret.len() >= MIN_SECRET_LEN
+    }
+}
+
+/// The parsed content of `session.json`
+#[derive(Debug, Clone, Default)]
+pub struct SessionSettings {
+    pub keys: Vec<SessionKey>,
+    pub retired: Vec<String>,
+}
+
+impl SessionSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let keys = match value.get("keys") {
+            Value::List(keys) => keys
+                .iter()
+                .map(|key| SessionKey { id: key.get("id").string(), secret: key.get("secret").string() })
+                .collect(),
+            _ => Vec::new(),
+        };
+        let retired = match value.get("retired") {
+            Value::List(ids) => ids.iter().map(|id| id.string()).collect(),
+            _ => Vec::new(),
+        };
+        Self { keys, retired }
+    }
+
+    /// The key opening cookies sealed under `id`
+    fn key(&self, id: &str) -> Option<&SessionKey> {
+        if self.retired.iter().any(|retired| retired == id) {
+            return None;
+        }
+        self.keys.iter().find(|key| key.id == id && key.is_usable())
+    }
+
+    /// The key sealing new cookies
+    pub fn current(&self) -> Option<&SessionKey> {
+        self.keys.iter().find(|key| key.is_usable() && !self.retired.contains(&key.id))
+    }
+
+    /// Seal `session` as the value of the `session_cont` cookie
+    pub fn seal(&self, session: &Value, session_id: u64) -> Option<String> {
+        let key = self.current()?;
+        let sealed = aes::encrypt(&session.into_json(), &format!("{}{}", key.secret, session_id)).ok()?;
+        Some(format!("{}.{}", key.id, sealed))
+    }
+
+    /// Open a `session_cont` cookie. The flag tells that it was sealed with
+    /// an older key and should be sealed again. `None` for cookies that are
+    /// forged, belong to another session id or were sealed with a retired
+    /// or unknown key.
+    pub fn open(&self, cookie: &str, session_id: u64) -> Option<(HashMap<String, Value>, bool)> {
+        let (id, sealed) = cookie.split_once('.')?;
+        let key = self.key(id)?;
+        let json = aes::decrypt(sealed, &format!("{}{}", key.secret, session_id)).ok()?;
+        match Value::from_json(&json) {
+            Ok(Value::Dict(map)) => Some((map, self.current().is_some_and(|current| current.id != id))),
+            _ => None,
+        }
+    }
+}
+
+/// The loaded session settings
+pub fn settings() -> &'static SessionSettings {
+    &SESSION
+}
+
+middleware! {
+    /// Cookie session sealed with the key ring of `session.json`. It takes
+    /// the place of htmstd's `CookieSession` and hands handlers the same
+    /// `CSessionRW`, honouring the `CookieSessionSettings` of the app.
+    pub KeyedSession <HTTP> {
+        let runtime = req.runtime();
+        let cookie_settings = runtime
+            .as_ref()
+            .and_then(|rt| rt.get_config::<CookieSessionSettings>())
+            .unwrap_or_default();
+        let run_mode = runtime.as_ref().map(|rt| rt.mode()).unwrap_or_default();
+
+        let parsed_id = req.get_cookie_or_default("session_id").get_value().parse::<u64>().ok();
+        let session_id = parsed_id.unwrap_or_else(generate_session_id);
+        let cookie = req.get_cookie("session_cont").map(|cookie| cookie.get_value().to_owned());
+        let (session, mut rewrite) = match cookie.as_deref().map(|cookie| SESSION.open(cookie, session_id)) {
+            Some(Some((session, resealed))) => (session, resealed),
+            // A cookie that no longer opens is replaced by an empty session
+            Some(None) => (HashMap::new(), true),
+            None => (HashMap::new(), false),
+        };
+        rewrite |= parsed_id.is_none();
+
+        req.params.set(CSessionRW::from_hash(session));
+        let mut req = next(req).await?;
+        let (session, modified) = req.params.take::<CSessionRW>().unwrap_or_default().into_tuple();
+
+        if modified || rewrite {
+            let sealed = SESSION.seal(&session, session_id).unwrap_or_default();
+            req.response = req
+                .response
+                .add_cookie(
+                    "session_id",
+                    cookie_settings.apply_to_cookie(Cookie::new(session_id.to_string()), run_mode.clone()),
+                )
+                .add_cookie(
+                    "session_cont",
+                    cookie_settings.apply_to_cookie(Cookie::new(sealed), run_mode),
+                );
+        }
+        Ok(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(id: &str) -> SessionKey {
+        SessionKey { id: id.to_string(), secret: format!("{}-{}", id, "s".repeat(MIN_SECRET_LEN)) }
+    }
+
+    #[test]
+    fn rotated_keys_open_old_sessions_until_retired() {
+        let old = SessionSettings { keys: vec![key("a")], retired: Vec::new() };
+        let session = object!({ auth_token: "t" });
+        let cookie = old.seal(&session, 7).unwrap();
+        assert!(cookie.starts_with("a."));
+        let (opened, reseal) = old.open(&cookie, 7).unwrap();
+        assert_eq!(Value::Dict(opened), session);
+        assert!(!reseal);
+        // Bound to its session id
+        assert!(old.open(&cookie, 8).is_none());
+
+        let rotated = SessionSettings { keys: vec![key("b"), key("a")], retired: Vec::new() };
+        assert!(rotated.open(&cookie, 7).is_some_and(|(_, reseal)| reseal));
+        assert!(rotated.seal(&session, 7).unwrap().starts_with("b."));
+
+        let retired = SessionSettings { keys: vec![key("b"), key("a")], retired: vec!["a".to_string()] };
+        assert!(retired.open(&cookie, 7).is_none());
+        let dropped = SessionSettings { keys: vec![key("b")], retired: Vec::new() };
+        assert!(dropped.open(&cookie, 7).is_none());
+    }
+
+    #[test]
+    fn unusable_keys_are_skipped() {
+        let settings = SessionSettings::from_value(&Value::from_json(r#"{
+            "keys": [
+                { "id": "short", "secret": "too short" },
+                { "id": "a.b", "secret": "0123456789abcdef0123456789abcdef" },
+                { "id": "ok", "secret": "0123456789abcdef0123456789abcdef" }
+            ]
+        }"#).unwrap());
+        assert_eq!(settings.keys.len(), 3);
+        assert_eq!(settings.current().map(|key| key.id.as_str()), Some("ok"));
+        assert!(SessionSettings::from_value(&Value::None).current().is_none());
+    }
+}
@@ -0,0 +1,354 @@
+//! settings.rs
+//!
+//! A store of runtime settings for the application built on sfx, kept apart
+//! from the `op/*.json` files sfx reads itself. Settings live in
+//! namespaces, one file `programfiles/settings/<namespace>.json` each:
+//!
+//! ```json
+//! {
+//!     "greeting": "Welcome back",
+//!     "max_items": 20,
+//!     "maintenance": false
+//! }
+//! ```
+//!
+//! Read and change them through a [`Namespace`]:
+//!
+//! ```rust,ignore
+//! let shop = sfx::settings::namespace("shop");
+//! let limit: i64 = shop.get_or("max_items", 20);
+//! shop.set("maintenance", true)?;
+//! sfx::settings::subscribe("shop", |change| tracing::info!(key = %change.key, "shop setting changed"));
+//! ```
+//!
+//! Changes are written at once and passed to the subscribers of their
+//! namespace, including those made at `/admin/settings`, then published as
+//! events to [`crate::events`]. Files edited by
+//! hand are read on restart.
+
+use hotaru::prelude::*;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+type Store = BTreeMap<String, BTreeMap<String, Value>>;
+type Callback = Arc<dyn Fn(&Change) + Send + Sync>;
+
+static STORE: Lazy<RwLock<Store>> = Lazy::new(|| RwLock::new(load()));
+
+/// Subscribers with the namespace they follow, empty for every namespace
+static SUBSCRIBERS: Lazy<RwLock<Vec<(String, Callback)>>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+/// Longest namespace
+const MAX_NAMESPACE: usize = 64;
+
+/// Longest key
+const MAX_KEY: usize = 128;
+
+fn settings_dir() -> PathBuf {
+    crate::op::programfiles().join("settings")
+}
+
+/// Whether `name` may be a namespace: lowercase letters, digits, `-` and `_`
+pub fn valid_namespace(name: &str) -> bool {
+    (1..=MAX_NAMESPACE).contains(&name.len())
+        && name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_')
+}
+
+/// Whether `key` may name a setting: not empty, no control characters
+pub fn valid_key(key: &str) -> bool {
+    (1..=MAX_KEY).contains(&key.len()) && !key.contains(char::is_control) && key.trim() == key
+}
+
+/// A type a setting can be read as and written from
+pub trait Setting: Sized {
+    fn from_setting(value: &Value) -> Option<Self>;
+    fn into_setting(self) -> Value;
+}
+
+impl Setting for String {
+    fn from_setting(value: &Value) -> Option<Self> {
+        match value {
+            Value::Str(text) => Some(text.clone()),
+            _ => None,
+        }
+    }
+
+    fn into_setting(self) -> Value {
+        Value::Str(self)
+    }
+}
+
+impl Setting for bool {
+    fn from_setting(value: &Value) -> Option<Self> {
+        match value {
+            Value::Boolean(flag) => Some(*flag),
+            _ => None,
+        }
+    }
+
+    fn into_setting(self) -> Value {
+        Value::Boolean(self)
+    }
+}
+
+impl Setting for i64 {
+    fn from_setting(value: &Value) -> Option<Self> {
+        match value {
+            Value::Numerical(number) if number.fract() == 0.0 => Some(*number as i64),
+            _ => None,
+        }
+    }
+
+    fn into_setting(self) -> Value {
+        Value::from(self)
+    }
+}
+
+impl Setting for u64 {
+    fn from_setting(value: &Value) -> Option<Self> {
+        match value {
+            Value::Numerical(number) if number.fract() == 0.0 && *number >= 0.0 => Some(*number as u64),
+            _ => None,
+        }
+    }
+
+    fn into_setting(self) -> Value {
+        Value::from(self)
+    }
+}
+
+impl Setting for f64 {
+    fn from_setting(value: &Value) -> Option<Self> {
+        match value {
+            Value::Numerical(number) => Some(*number),
+            _ => None,
+        }
+    }
+
+    fn into_setting(self) -> Value {
+        Value::Numerical(self)
+    }
+}
+
+impl Setting for Vec<String> {
+    fn from_setting(value: &Value) -> Option<Self> {
+        match value {
+            Value::List(items) => items.iter().map(String::from_setting).collect(),
+            _ => None,
+        }
+    }
+
+    fn into_setting(self) -> Value {
+        Value::List(self.into_iter().map(Value::Str).collect())
+    }
+}
+
+/// Any JSON value, as stored
+impl Setting for Value {
+    fn from_setting(value: &Value) -> Option<Self> {
+        Some(value.clone())
+    }
+
+    fn into_setting(self) -> Value {
+        self
+    }
+}
+
+/// A setting that was made, changed or removed
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub namespace: String,
+    pub key: String,
+    /// `None` for a new setting
+    pub old: Option<Value>,
+    /// `None` for a removed setting
+    pub new: Option<Value>,
+}
+
+#[derive(Debug)]
+pub enum SettingsError {
+    InvalidNamespace,
+    InvalidKey,
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SettingsError::InvalidNamespace => write!(f, "Namespaces are lowercase letters, digits, - and _"),
+            SettingsError::InvalidKey => write!(f, "Keys are 1 to {} characters without control characters or surrounding spaces", MAX_KEY),
+            SettingsError::Io(err) => write!(f, "Failed to save settings: {}", err),
+        }
+    }
+}
+
+impl From<std::io::Error> for SettingsError {
+    fn from(err: std::io::Error) -> Self {
+        SettingsError::Io(err)
+    }
+}
+
+/// The settings of `value`, the content of a namespace file
+pub fn from_value(value: &Value) -> Option<BTreeMap<String, Value>> {
+    match value {
+        Value::Dict(settings) => Some(settings.iter().map(|(key, value)| (key.clone(), value.clone())).collect()),
+        _ => None,
+    }
+}
+
+fn load() -> Store {
+    let mut store = Store::new();
+    for entry in std::fs::read_dir(settings_dir()).into_iter().flatten().flatten() {
+        let path = entry.path();
+        let Some(namespace) = path.file_name().and_then(|name| name.to_str()).and_then(|name| name.strip_suffix(".json")) else {
+            continue;
+        };
+        let settings = Value::from_jsonf(path.to_string_lossy()).ok().as_ref().and_then(from_value);
+        match settings {
+            Some(settings) if valid_namespace(namespace) => {
+                store.insert(namespace.to_string(), settings);
+            }
+            _ => tracing::warn!(namespace, "Ignoring settings file that is not a JSON object named by its namespace"),
+        }
+    }
+    store
+}
+
+/// Write `namespace` to its file, replacing it only once fully written
+fn save(namespace: &str, settings: &BTreeMap<String, Value>) -> Result<(), SettingsError> {
+    let dir = settings_dir();
+    std::fs::create_dir_all(&dir)?;
+    let mut value = Value::new_dict();
+    for (key, setting) in settings {
+        value.set(key, setting.clone());
+    }
+    let path = dir.join(format!("{}.json", namespace));
+    let temporary = dir.join(format!("{}.json.tmp", namespace));
+    std::fs::write(&temporary, value.into_json())?;
+    std::fs::rename(&temporary, &path)?;
+    Ok(())
+}
+
+/// Pass `change` to the subscribers of its namespace
+fn notify(change: &Change) {
+    let subscribers: Vec<Callback> = SUBSCRIBERS
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|(namespace, _)| namespace.is_empty() || *namespace == change.namespace)
+        .map(|(_, callback)| callback.clone())
+        .collect();
+    for callback in subscribers {
+        callback(change);
+    }
+}
+
+/// Call `callback` after every change in `namespace`, or in every namespace
+/// when it is empty. Callbacks run on the task making the change.
+pub fn subscribe(namespace: &str, callback: impl Fn(&Change) + Send + Sync + 'static) {
+    SUBSCRIBERS.write().unwrap().push((namespace.to_string(), Arc::new(callback)));
+}
+
+/// Every namespace holding settings, sorted
+pub fn namespaces() -> Vec<String> {
+    STORE.read().unwrap().keys().cloned().collect()
+}
+
+/// The settings of `name`
+pub fn namespace(name: &str) -> Namespace {
+    Namespace { name: name.to_string() }
+}
+
+/// The settings of one namespace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Namespace {
+    name: String,
+}
+
+impl Namespace {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The setting `key` as it is stored
+    pub fn raw(&self, key: &str) -> Option<Value> {
+        STORE.read().unwrap().get(&self.name).and_then(|settings| settings.get(key)).cloned()
+    }
+
+    /// The setting `key`, `None` when it is missing or of another type
+    pub fn get<T: Setting>(&self, key: &str) -> Option<T> {
+        self.raw(key).as_ref().and_then(T::from_setting)
+    }
+
+    /// The setting `key`, or `default` when it is missing or of another type
+    pub fn get_or<T: Setting>(&self, key: &str, default: T) -> T {
+        self.get(key).unwrap_or(default)
+    }
+
+    /// Every setting of the namespace, by key
+    pub fn all(&self) -> BTreeMap<String, Value> {
+        STORE.read().unwrap().get(&self.name).cloned().unwrap_or_default()
+    }
+
+    /// Store `value` as the setting `key`
+    pub fn set<T: Setting>(&self, key: &str, value: T) -> Result<(), SettingsError> {
+        self.change(key, Some(value.into_setting()))
+    }
+
+    /// Remove the setting `key`. Returns whether there was one.
+    pub fn remove(&self, key: &str) -> Result<bool, SettingsError> {
+        if self.raw(key).is_none() {
+            return Ok(false);
+        }
+        self.change(key, None).map(|_| true)
+    }
+
+    fn change(&self, key: &str, new: Option<Value>) -> Result<(), SettingsError> {
+        if !valid_namespace(&self.name) {
+            return Err(SettingsError::InvalidNamespace);
+        }
+        if !valid_key(key) {
+            return Err(SettingsError::InvalidKey);
+        }
+        let old = {
+            let mut store = STORE.write().unwrap();
+            let mut settings = store.get(&self.name).cloned().unwrap_or_default();
+            let old = match &new {
+                Some(value) => settings.insert(key.to_string(), value.clone()),
+                None => settings.remove(key),
+            };
+            if old == new {
+                return Ok(());
+            }
+            save(&self.name, &settings)?;
+            store.insert(self.name.clone(), settings);
+            old
+        };
+        let change = Change { namespace: self.name.clone(), key: key.to_string(), old, new };
+        notify(&change);
+        crate::events::publish(change);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_convert_only_to_their_own_type() {
+        assert_eq!(i64::from_setting(&Value::from(20)), Some(20));
+        assert_eq!(i64::from_setting(&Value::from(2.5)), None);
+        assert_eq!(u64::from_setting(&Value::from(-1)), None);
+        assert_eq!(f64::from_setting(&Value::from(2.5)), Some(2.5));
+        assert_eq!(bool::from_setting(&Value::from("true")), None);
+        assert_eq!(String::from_setting(&Value::from(1)), None);
+        let list = vec!["a".to_string(), "b".to_string()];
+        assert_eq!(Vec::<String>::from_setting(&list.clone().into_setting()), Some(list));
+        assert_eq!(Vec::<String>::from_setting(&Value::from_json(r#"["a", 1]"#).unwrap()), None);
+
+        assert!(valid_namespace("shop_2") && !valid_namespace("Shop") && !valid_namespace("../x"));
+        assert!(valid_key("Max items") && !valid_key("") && !valid_key(" padded") && !valid_key("a\nb"));
+    }
+}
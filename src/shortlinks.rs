@@ -0,0 +1,368 @@
+//! shortlinks.rs
+//!
+//! Short links made by admins at `/admin/links` and followed at
+//! `GET /l/<code>`. Links live in `programfiles/admin_info/links.json`:
+//!
+//! ```json
+//! [{ "code": "spring", "target": "/docs/events/spring", "by": "1@local",
+//!    "created": 1700000000, "expires": 1702592000, "clicks": 42,
+//!    "last_click": 1700500000 }]
+//! ```
+//!
+//! A target is either a path on this site (`/docs`) or an `http(s)` URL on
+//! a host allowed by `programfiles/op/shortlinks.json`, so the links cannot
+//! be used to send visitors anywhere:
+//!
+//! ```json
+//! { "allowed_hosts": ["example.com", "*.example.com"], "code_length": 6 }
+//! ```
+//!
+//! `*.` allows every subdomain. Links without `expires` never run out;
+//! expired ones answer `410` until removed. Clicks are counted in memory and
+//! written every minute and on shutdown.
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::op::APP;
+
+static SHORTLINKS: Lazy<ShortLinkSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/shortlinks.json");
+    ShortLinkSettings::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None))
+});
+
+static LINKS: Lazy<RwLock<Vec<Link>>> = Lazy::new(|| {
+    let links = match Value::from_jsonf(links_path().to_string_lossy()) {
+        Ok(Value::List(links)) => links.iter().filter_map(Link::from_json).collect(),
+        _ => Vec::new(),
+    };
+    RwLock::new(links)
+});
+
+/// Whether clicks were counted since the links were last written
+static DIRTY: AtomicBool = AtomicBool::new(false);
+
+/// How often counted clicks are written
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Longest code an admin may choose
+const MAX_CODE: usize = 64;
+
+fn links_path() -> PathBuf {
+    crate::op::programfiles().join("admin_info/links.json")
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// The parsed content of `shortlinks.json`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShortLinkSettings {
+    /// Hosts external targets may point to, `*.example.com` for subdomains
+    pub allowed_hosts: Vec<String>,
+    /// Length of the codes made for links given none
+    pub code_length: usize,
+}
+
+impl ShortLinkSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let allowed_hosts = match value.get("allowed_hosts") {
+            Value::List(hosts) => hosts
+                .iter()
+                .map(|host| host.string().trim().to_ascii_lowercase())
+                .filter(|host| !host.is_empty())
+                .collect(),
+            _ => Vec::new(),
+        };
+        let code_length = match value.get("code_length") {
+            Value::Numerical(_) => value.get("code_length").integer().clamp(4, MAX_CODE as i64) as usize,
+            _ => 6,
+        };
+        Self { allowed_hosts, code_length }
+    }
+
+    /// Whether external targets may point to `host`
+    pub fn allows_host(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        self.allowed_hosts.iter().any(|allowed| match allowed.strip_prefix("*.") {
+            Some(domain) => host == domain || host.strip_suffix(domain).is_some_and(|sub| sub.ends_with('.')),
+            None => host == *allowed,
+        })
+    }
+
+    /// Check that `target` is a path on this site or a URL on an allowed host
+    pub fn check_target(&self, target: &str) -> Result<(), LinkError> {
+        if target.is_empty()
+            || target.chars().any(|c| c.is_whitespace() || c.is_control() || "\"'<>\\`".contains(c))
+        {
+            return Err(LinkError::InvalidTarget);
+        }
+        if target.starts_with('/') {
+            // `//host` would leave the site
+            return if target.starts_with("//") { Err(LinkError::InvalidTarget) } else { Ok(()) };
+        }
+        let rest = target
+            .strip_prefix("https://")
+            .or_else(|| target.strip_prefix("http://"))
+            .ok_or(LinkError::InvalidTarget)?;
+        let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+        if authority.contains('@') {
+            return Err(LinkError::InvalidTarget);
+        }
+        let host = match authority.rsplit_once(':') {
+            Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+            _ => authority,
+        };
+        if host.is_empty() {
+            return Err(LinkError::InvalidTarget);
+        }
+        if !self.allows_host(host) {
+            return Err(LinkError::HostNotAllowed);
+        }
+        Ok(())
+    }
+}
+
+/// A short code and where it leads
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Link {
+    pub code: String,
+    pub target: String,
+    /// The admin entry who made the link
+    pub by: String,
+    pub created: u64,
+    /// Unix time the link stops working, `None` for a lasting link
+    pub expires: Option<u64>,
+    pub clicks: u64,
+    pub last_click: Option<u64>,
+}
+
+impl Link {
+    pub fn from_json(value: &Value) -> Option<Self> {
+        let time = |key: &str| value.try_get(key).ok().map(|v| v.integer()).filter(|&t| t > 0).map(|t| t as u64);
+        let code = value.get("code").string();
+        if !valid_code(&code) {
+            return None;
+        }
+        Some(Self {
+            code,
+            target: value.get("target").string(),
+            by: value.get("by").string(),
+            created: value.get("created").integer().max(0) as u64,
+            expires: time("expires"),
+            clicks: value.get("clicks").integer().max(0) as u64,
+            last_click: time("last_click"),
+        })
+    }
+
+    pub fn into_json(&self) -> Value {
+        let mut value = object!({
+            code: &self.code,
+            target: &self.target,
+            by: &self.by,
+            created: self.created,
+            clicks: self.clicks,
+        });
+        if let Some(expires) = self.expires {
+            value.set("expires", expires);
+        }
+        if let Some(last_click) = self.last_click {
+            value.set("last_click", last_click);
+        }
+        value
+    }
+
+    pub fn is_active(&self, now: u64) -> bool {
+        self.expires.is_none_or(|expires| expires > now)
+    }
+}
+
+/// Why a link could not be made, followed or removed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkError {
+    InvalidCode,
+    InvalidTarget,
+    HostNotAllowed,
+    CodeTaken,
+    NotFound,
+    Expired,
+    Io(String),
+}
+
+impl std::fmt::Display for LinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkError::InvalidCode => write!(f, "Codes are 1 to {} letters, digits, '-' or '_'", MAX_CODE),
+            LinkError::InvalidTarget => write!(f, "Not a path on this site or an http(s) URL"),
+            LinkError::HostNotAllowed => write!(f, "Links may not point to this host"),
+            LinkError::CodeTaken => write!(f, "The code is taken"),
+            LinkError::NotFound => write!(f, "Link not found"),
+            LinkError::Expired => write!(f, "The link has expired"),
+            LinkError::Io(err) => write!(f, "Failed to save the links: {}", err),
+        }
+    }
+}
+
+/// Whether `code` may name a link
+pub fn valid_code(code: &str) -> bool {
+    (1..=MAX_CODE).contains(&code.len())
+        && code.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// The loaded short link settings
+pub fn settings() -> &'static ShortLinkSettings {
+    &SHORTLINKS
+}
+
+/// Every link, newest first
+pub fn list() -> Vec<Link> {
+    let mut links = LINKS.read().unwrap().clone();
+    links.reverse();
+    links
+}
+
+/// Make a link from `code` (a random one when empty) to `target`, lasting
+/// `duration` seconds or for good
+pub fn add(code: &str, target: &str, by: &str, duration: Option<u64>) -> Result<Link, LinkError> {
+    let target = target.trim();
+    SHORTLINKS.check_target(target)?;
+    let link = {
+        let mut links = LINKS.write().unwrap();
+        let code = match code.trim() {
+            "" => loop {
+                let code = hotaru_lib::random::random_alphanumeric_string(SHORTLINKS.code_length);
+                if !links.iter().any(|link| link.code == code) {
+                    break code;
+                }
+            },
+            code if !valid_code(code) => return Err(LinkError::InvalidCode),
+            code if links.iter().any(|link| link.code == code) => return Err(LinkError::CodeTaken),
+            code => code.to_string(),
+        };
+        let now = now();
+        let link = Link {
+            code,
+            target: target.to_string(),
+            by: by.to_string(),
+            created: now,
+            expires: duration.map(|duration| now + duration),
+            clicks: 0,
+            last_click: None,
+        };
+        links.push(link.clone());
+        link
+    };
+    save()?;
+    tracing::info!(code = %link.code, target = %link.target, by, "Short link made");
+    Ok(link)
+}
+
+/// Remove the link `code`
+pub fn remove(code: &str, by: &str) -> Result<Link, LinkError> {
+    let link = {
+        let mut links = LINKS.write().unwrap();
+        let at = links.iter().position(|link| link.code == code).ok_or(LinkError::NotFound)?;
+        links.remove(at)
+    };
+    save()?;
+    tracing::info!(code, by, "Short link removed");
+    Ok(link)
+}
+
+/// The target of `code`, counting the click
+pub fn resolve(code: &str) -> Result<String, LinkError> {
+    let now = now();
+    let mut links = LINKS.write().unwrap();
+    let link = links.iter_mut().find(|link| link.code == code).ok_or(LinkError::NotFound)?;
+    if !link.is_active(now) {
+        return Err(LinkError::Expired);
+    }
+    link.clicks += 1;
+    link.last_click = Some(now);
+    DIRTY.store(true, Ordering::Relaxed);
+    Ok(link.target.clone())
+}
+
+fn save() -> Result<(), LinkError> {
+    let path = links_path();
+    let links = Value::List(LINKS.read().unwrap().iter().map(Link::into_json).collect());
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|err| LinkError::Io(err.to_string()))?;
+    }
+    DIRTY.store(false, Ordering::Relaxed);
+    std::fs::write(&path, links.into_json()).map_err(|err| LinkError::Io(err.to_string()))
+}
+
+/// Write the clicks counted since the last write
+pub fn flush() {
+    if DIRTY.load(Ordering::Relaxed)
+        && let Err(err) = save()
+    {
+        tracing::error!(%err, "Failed to write the short link clicks");
+    }
+}
+
+/// Write counted clicks every [`FLUSH_INTERVAL`]. Called by `serve`.
+pub fn start() {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(FLUSH_INTERVAL).await;
+            flush();
+        }
+    });
+}
+
+endpoint! {
+    APP.url("/l/<code>"),
+
+    /// Follow a short link: `302` to its target, `404` for unknown codes and
+    /// `410` once it expired
+    pub follow_link <HTTP> {
+        let code = req.param("code").unwrap_or_default();
+        match resolve(&code) {
+            Ok(target) => redirect_response(&target),
+            Err(LinkError::Expired) => text_response("Gone").status(StatusCode::GONE),
+            Err(_) => text_response("Not found").status(StatusCode::NOT_FOUND),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn targets_stay_on_site_or_allowed_hosts() {
+        let settings = ShortLinkSettings::from_value(&object!({ allowed_hosts: ["example.com", "*.docs.example"] }));
+        assert_eq!(settings.code_length, 6);
+        assert!(settings.check_target("/docs/start?x=1").is_ok());
+        assert!(settings.check_target("https://example.com/a").is_ok());
+        assert!(settings.check_target("http://EXAMPLE.com:8080").is_ok());
+        assert!(settings.check_target("https://api.docs.example/v1").is_ok());
+        assert!(settings.check_target("https://docs.example").is_ok());
+        assert_eq!(settings.check_target("https://evildocs.example"), Err(LinkError::HostNotAllowed));
+        assert_eq!(settings.check_target("https://example.com.evil/"), Err(LinkError::HostNotAllowed));
+        assert_eq!(settings.check_target("https://example.com@evil/"), Err(LinkError::InvalidTarget));
+        assert_eq!(settings.check_target("//evil"), Err(LinkError::InvalidTarget));
+        assert_eq!(settings.check_target("javascript:alert(1)"), Err(LinkError::InvalidTarget));
+        assert_eq!(settings.check_target("/a\"><script>"), Err(LinkError::InvalidTarget));
+
+        assert!(valid_code("spring-2026_a") && !valid_code("") && !valid_code("a/b"));
+        let link = Link {
+            code: "spring".into(),
+            target: "/docs".into(),
+            by: "1@local".into(),
+            created: 100,
+            expires: Some(200),
+            clicks: 3,
+            last_click: None,
+        };
+        assert_eq!(Link::from_json(&link.into_json()), Some(link.clone()));
+        assert!(link.is_active(199) && !link.is_active(200));
+    }
+}
@@ -0,0 +1,247 @@
+//! sms.rs
+//!
+//! Text messages to users, like the codes verifying a phone number or
+//! signing in with it. The sender is set in `programfiles/op/sms.json`:
+//!
+//! ```json
+//! {
+//!     "sender": "http",
+//!     "http": {
+//!         "url": "https://api.twilio.com/2010-04-01/Accounts/AC123/Messages.json",
+//!         "account": "AC123",
+//!         "token": "env:SMS_TOKEN",
+//!         "from": "+15550100"
+//!     },
+//!     "command": "/usr/local/bin/send-sms {to}"
+//! }
+//! ```
+//!
+//! - `http` posts the form `To`, `From`, `Body` to `url` with `account` and
+//!   `token` (see `crate::secrets`) as basic credentials, the way Twilio
+//!   and the services copying its API take messages.
+//! - `command` runs a program (split on whitespace, no shell) with the
+//!   number in place of `{to}`, the text on its standard input. Exit code
+//!   0 means sent.
+//! - `log` (the default) writes the message to the log, for development.
+//!
+//! Apps can plug in another gateway with [`set`].
+
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use hotaru::http::*;
+use hotaru::prelude::*;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+
+static SMS: Lazy<SmsSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/sms.json");
+    SmsSettings::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None))
+});
+
+static SENDER: Lazy<RwLock<Arc<dyn SmsSender>>> = Lazy::new(|| {
+    let sender: Arc<dyn SmsSender> = match SMS.sender.as_str() {
+        "http" => Arc::new(HttpSender {
+            url: SMS.url.clone(),
+            account: SMS.account.clone(),
+            token: crate::secrets::load(&SMS.token, "sms.json token"),
+            from: SMS.from.clone(),
+        }),
+        "command" => Arc::new(CommandSender { command: SMS.command.clone() }),
+        _ => Arc::new(LogSender),
+    };
+    RwLock::new(sender)
+});
+
+/// The parsed content of `sms.json`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SmsSettings {
+    /// `http`, `command` or `log`
+    pub sender: String,
+    pub url: String,
+    pub account: String,
+    /// Reference to the password of `account`, see `crate::secrets`
+    pub token: String,
+    pub from: String,
+    pub command: String,
+}
+
+impl SmsSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let http = value.get("http");
+        Self {
+            sender: match value.get("sender") {
+                Value::Str(sender) if !sender.is_empty() => sender.clone(),
+                _ => "log".to_string(),
+            },
+            url: http.get("url").string(),
+            account: http.get("account").string(),
+            token: http.get("token").string(),
+            from: http.get("from").string(),
+            command: value.get("command").string(),
+        }
+    }
+}
+
+/// The loaded SMS settings
+pub fn settings() -> &'static SmsSettings {
+    &SMS
+}
+
+/// What [`SmsSender::send`] returns; `Err` when the message was not taken
+pub type SmsFuture<'a> = Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'a>>;
+
+/// A gateway sending text messages
+pub trait SmsSender: Send + Sync {
+    /// Name written in the log, `http`
+    fn name(&self) -> &'static str;
+
+    /// Send `text` to the number `to`, in E.164 form (`+81901234567`)
+    fn send<'a>(&'a self, to: &'a str, text: &'a str) -> SmsFuture<'a>;
+}
+
+/// Writes messages to the log instead of sending them
+pub struct LogSender;
+
+impl SmsSender for LogSender {
+    fn name(&self) -> &'static str {
+        "log"
+    }
+
+    fn send<'a>(&'a self, to: &'a str, text: &'a str) -> SmsFuture<'a> {
+        Box::pin(async move {
+            tracing::info!(%to, %text, "SMS (logged, no sender set)");
+            Ok(())
+        })
+    }
+}
+
+/// Posts messages to a Twilio-style HTTP API
+pub struct HttpSender {
+    pub url: String,
+    pub account: String,
+    pub token: String,
+    pub from: String,
+}
+
+impl HttpSender {
+    /// The form posted for `text` to `to`
+    pub fn form(&self, to: &str, text: &str) -> String {
+        [("To", to), ("From", self.from.as_str()), ("Body", text)]
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, hotaru_lib::url_encoding::encode_url_owned(value)))
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+}
+
+impl SmsSender for HttpSender {
+    fn name(&self) -> &'static str {
+        "http"
+    }
+
+    fn send<'a>(&'a self, to: &'a str, text: &'a str) -> SmsFuture<'a> {
+        Box::pin(async move {
+            if !self.url.starts_with("http://") && !self.url.starts_with("https://") {
+                return Err("sms.json: `http.url` must be an http(s) URL".to_string());
+            }
+            let (origin, path) = crate::local_auth::rules::split_url(&self.url);
+            let meta = HttpMeta::new(HttpStartLine::request_post(&path), HashMap::new());
+            let credentials = BASE64.encode(format!("{}:{}", self.account, self.token));
+            let request = HttpRequest::new(meta, HttpBody::Binary(self.form(to, text).into_bytes()))
+                .content_type(HttpContentType::from_str("application/x-www-form-urlencoded"))
+                .add_header("Authorization", format!("Basic {}", credentials));
+            let response = crate::user::fetch::send_http_request(origin.clone(), request, HttpSafety::default())
+                .await
+                .map_err(|err| format!("{} is unreachable: {:?}", origin, err))?;
+            let status = response.meta.start_line.status_code().as_u16();
+            if (200..300).contains(&status) { Ok(()) } else { Err(format!("{} answered {}", origin, status)) }
+        })
+    }
+}
+
+/// Runs a program for each message
+pub struct CommandSender {
+    pub command: String,
+}
+
+impl CommandSender {
+    fn run(command: &str, to: &str, text: &str) -> Result<(), String> {
+        use std::io::Write;
+        let mut words: Vec<String> = command.split_whitespace().map(str::to_string).collect();
+        match words.iter_mut().find(|word| word.as_str() == "{to}") {
+            Some(word) => *word = to.to_string(),
+            None => words.push(to.to_string()),
+        }
+        let mut child = std::process::Command::new(&words[0])
+            .args(&words[1..])
+            .stdin(std::process::Stdio::piped())
+            .spawn()
+            .map_err(|err| format!("cannot run {}: {}", words[0], err))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // A program not reading the text may be gone already; its exit code tells
+            match stdin.write_all(text.as_bytes()) {
+                Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => {
+                    return Err(format!("cannot write to {}: {}", words[0], err));
+                }
+                _ => {}
+            }
+        }
+        let status = child.wait().map_err(|err| err.to_string())?;
+        if status.success() { Ok(()) } else { Err(format!("{} exited with {}", words[0], status)) }
+    }
+}
+
+impl SmsSender for CommandSender {
+    fn name(&self) -> &'static str {
+        "command"
+    }
+
+    fn send<'a>(&'a self, to: &'a str, text: &'a str) -> SmsFuture<'a> {
+        Box::pin(async move {
+            if self.command.split_whitespace().next().is_none() {
+                return Err("no SMS command set".to_string());
+            }
+            let (command, to, text) = (self.command.clone(), to.to_string(), text.to_string());
+            tokio::task::spawn_blocking(move || Self::run(&command, &to, &text)).await.map_err(|err| err.to_string())?
+        })
+    }
+}
+
+/// Send with `sender` instead of the one of `sms.json`
+pub fn set(sender: impl SmsSender + 'static) {
+    *SENDER.write().unwrap() = Arc::new(sender);
+}
+
+/// Send `text` to `to`; a failure is logged and returned
+pub async fn send(to: &str, text: &str) -> Result<(), String> {
+    let sender = SENDER.read().unwrap().clone();
+    let result = sender.send(to, text).await;
+    if let Err(err) = &result {
+        tracing::warn!(sender = sender.name(), %err, "SMS not sent");
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http_senders_post_a_twilio_form() {
+        let settings = SmsSettings::from_value(&object!({ sender: "http", http: { url: "https://sms.test/send", account: "AC1", from: "+15550100" } }));
+        assert_eq!((settings.sender.as_str(), settings.account.as_str()), ("http", "AC1"));
+        assert_eq!(SmsSettings::from_value(&Value::None).sender, "log");
+
+        let sender = HttpSender { url: settings.url, account: settings.account, token: "t".to_string(), from: settings.from };
+        assert_eq!(sender.form("+81901234567", "Code: 123 456"), "To=%2B81901234567&From=%2B15550100&Body=Code%3A%20123%20456");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn commands_get_the_text_on_stdin() {
+        // `cat +1555` fails, there being no such file
+        assert!(CommandSender { command: "cat".to_string() }.send("+1555", "hi").await.is_err());
+        assert!(CommandSender { command: "true {to}".to_string() }.send("+1555", "hi").await.is_ok());
+    }
+}
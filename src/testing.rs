@@ -0,0 +1,247 @@
+//! testing.rs
+//!
+//! Helpers for testing endpoints and middleware, in sfx and in the
+//! applications built on it. Requests are made up in memory, signed in or
+//! not, and handed to the handler without a socket:
+//!
+//! ```rust,ignore
+//! use sfx::testing::{self, TestRequest};
+//!
+//! #[tokio::test]
+//! async fn orders_need_an_account() {
+//!     let mut req = TestRequest::get("/shop/orders").build();
+//!     let res = testing::respond(orders(&mut req).await, &mut req);
+//!     assert_eq!(testing::status(&res), 302);
+//!
+//!     let mut req = TestRequest::get("/shop/orders").signed_in(testing::user(3, "alice")).build();
+//!     let res = testing::respond(orders(&mut req).await, &mut req);
+//!     assert_eq!(testing::json(&res).get("orders").list().len(), 0);
+//! }
+//! ```
+//!
+//! The request carries the session of `KeyedSession` both as the
+//! `CSessionRW` handlers read and as sealed cookies, so it can also be run
+//! through the real middleware with [`through`].
+//!
+//! Handlers called directly have no matched route, so `req.param` finds
+//! nothing there. Endpoints reading the global `LOCAL_AUTH` see the
+//! accounts of `programfiles`; code taking an `&AuthManager` can be handed
+//! one of [`auth_manager`]. Sessions of a MainAuth server can be tested
+//! against the [`mock_auth_server`].
+
+use hotaru::http::*;
+use hotaru::prelude::*;
+use htmstd::session::CSessionRW;
+use htmstd::session::session_counter::generate_session_id;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub mod mock_auth;
+
+pub use mock_auth::{MockAuthServer, MockReply, mock_auth_server, mock_auth_server_on};
+
+use crate::local_auth::fop::AuthManager;
+use crate::local_auth::scope::Scopes;
+use crate::modules::{Layer, Next};
+use crate::user::{Server, User, UserID};
+
+/// An active local user, `<username>@example.com`
+pub fn user(uid: u32, username: &str) -> User {
+    User::new(
+        UserID::new(uid as usize, Server::Local),
+        username.to_string(),
+        format!("{}@example.com", username),
+        true,
+        true,
+    )
+}
+
+/// An account store kept in memory with `users`, as `(username, password)`,
+/// registered in order from uid 1
+pub async fn auth_manager(users: &[(&str, &str)]) -> AuthManager {
+    let manager = AuthManager::in_memory();
+    for (username, password) in users {
+        let email = format!("{}@example.com", username);
+        if let Err(err) = manager.register_user(username, &email, password).await {
+            panic!("Failed to register {}: {}", username, err.to_string());
+        }
+    }
+    manager
+}
+
+/// A bearer token of `uid` in `manager`, without its password
+pub async fn token(manager: &AuthManager, uid: u32) -> String {
+    match manager.issue_token(uid, Scopes::All).await {
+        Ok(token) => token,
+        Err(err) => panic!("Failed to issue a token for {}: {}", uid, err.to_string()),
+    }
+}
+
+/// A request to build for a test
+pub struct TestRequest {
+    start_line: HttpStartLine,
+    headers: Vec<(String, String)>,
+    body: HttpBody,
+    user: Option<User>,
+    session: HashMap<String, Value>,
+}
+
+impl TestRequest {
+    pub fn new(method: HttpMethod, path: &str) -> Self {
+        Self {
+            start_line: HttpStartLine::new_request(HttpVersion::Http11, method, path.to_string()),
+            headers: Vec::new(),
+            body: HttpBody::Empty,
+            user: None,
+            session: HashMap::new(),
+        }
+    }
+
+    pub fn get(path: &str) -> Self {
+        Self::new(HttpMethod::GET, path)
+    }
+
+    pub fn post(path: &str) -> Self {
+        Self::new(HttpMethod::POST, path)
+    }
+
+    pub fn header(mut self, key: &str, value: &str) -> Self {
+        self.headers.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// An url-encoded form as the body
+    pub fn form(mut self, fields: &[(&str, &str)]) -> Self {
+        let fields: HashMap<String, String> = fields.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+        self.body = HttpBody::Form(fields.into());
+        self
+    }
+
+    /// A JSON body
+    pub fn json(mut self, body: Value) -> Self {
+        self.body = HttpBody::Json(body);
+        self
+    }
+
+    /// Sign the session in as `user`, the way `/user/login` leaves it
+    pub fn signed_in(mut self, user: User) -> Self {
+        let token = format!("test-token-{}", user.get_uid());
+        self.session.insert("auth_token".into(), token.into());
+        self.session.insert("host".into(), user.get_server().to_string().into());
+        self.session.insert("user_info_cache".into(), user.clone().into());
+        self.user = Some(user);
+        self
+    }
+
+    /// Sign the session in to `server` with `token`, with no user cached
+    /// yet, so that `UserFetch` asks the server who it is
+    pub fn remote(mut self, server: &Server, token: &str) -> Self {
+        self.session.insert("auth_token".into(), token.into());
+        self.session.insert("host".into(), server.to_string().into());
+        self
+    }
+
+    /// Send `token` as `Authorization: Bearer`, like API clients do
+    pub fn bearer(self, token: &str) -> Self {
+        self.header("Authorization", &format!("Bearer {}", token))
+    }
+
+    /// Put `value` in the session under `key`
+    pub fn session(mut self, key: &str, value: impl Into<Value>) -> Self {
+        self.session.insert(key.to_string(), value.into());
+        self
+    }
+
+    /// The request, as handlers see it after `KeyedSession` and `UserFetch`
+    pub fn build(self) -> HttpReqCtx {
+        let mut meta = HttpMeta::new(self.start_line, HashMap::new());
+        for (key, value) in self.headers {
+            meta.set_attribute(key, value);
+        }
+        let mut request = HttpRequest::new(meta, self.body);
+        let session_id = generate_session_id();
+        if let Some(sealed) = crate::session::settings().seal(&Value::Dict(self.session.clone()), session_id) {
+            request = request
+                .add_cookie("session_id", Cookie::new(session_id.to_string()))
+                .add_cookie("session_cont", Cookie::new(sealed));
+        }
+
+        let mut req = HttpReqCtx::new_client(String::new(), HttpSafety::default());
+        req.request = request;
+        req.params.set(CSessionRW::from_hash(self.session));
+        req.params.set(self.user.unwrap_or_else(|| User::guest(Server::Local)));
+        req
+    }
+}
+
+/// The response left by a handler called as `handler(&mut req).await`.
+/// `req` stays as the handler left it, session included.
+pub fn respond(outcome: impl EndpointOutcome<HttpReqCtx>, req: &mut HttpReqCtx) -> HttpResponse {
+    if let Err(err) = outcome.apply_to(req) {
+        panic!("The endpoint failed: {:?}", err);
+    }
+    std::mem::take(&mut req.response)
+}
+
+/// Run `layers` in order on `req`, as the server would before the endpoint
+pub async fn through(layers: &[Layer], req: HttpReqCtx) -> Result<HttpReqCtx, <HttpReqCtx as RequestContext>::Error> {
+    let end: Arc<Next> = Arc::new(|req| Box::pin(async move { Ok(req) }));
+    crate::modules::chain(layers.into(), 0, end)(req).await
+}
+
+pub fn status(response: &HttpResponse) -> u16 {
+    response.meta.start_line.status_code().as_u16()
+}
+
+/// The `Location` a redirect points to
+pub fn location(response: &HttpResponse) -> Option<String> {
+    response.meta.clone().get_location()
+}
+
+/// The JSON body, `Value::None` when the body is not JSON
+pub fn json(response: &HttpResponse) -> Value {
+    match &response.body {
+        HttpBody::Json(value) => value.clone(),
+        HttpBody::Text(text) => Value::from_json(text).unwrap_or(Value::None),
+        _ => Value::None,
+    }
+}
+
+/// The body as text
+pub fn text(response: &HttpResponse) -> String {
+    match &response.body {
+        HttpBody::Text(text) => text.clone(),
+        HttpBody::Json(value) => value.into_json(),
+        HttpBody::Binary(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ctx::SfxCtx;
+
+    #[tokio::test]
+    async fn requests_carry_the_session_and_user() {
+        let mut req = TestRequest::post("/x").form(&[("key", "value")]).signed_in(user(3, "alice")).build();
+        assert_eq!(req.local_uid(), Some(3));
+        assert_eq!(crate::user::fetch::get_auth_token(&req).as_deref(), Some("test-token-3"));
+        assert_eq!(req.form_or_default().await.get_or_default("key"), "value");
+
+        // UserFetch finds the same user in the fabricated session
+        let mut req = TestRequest::get("/x").signed_in(user(3, "alice")).build();
+        req.params.take::<User>();
+        let req = through(&[crate::modules::layer::<crate::user::middleware::UserFetch>()], req).await.unwrap();
+        assert_eq!(req.local_uid(), Some(3));
+    }
+
+    #[tokio::test]
+    async fn in_memory_accounts_issue_tokens() {
+        let manager = auth_manager(&[("alice", "Aa333333"), ("bob", "Bb444444")]).await;
+        assert_eq!(manager.get_uid_by_username("bob").await, Some(2));
+        let token = token(&manager, 1).await;
+        assert_eq!(manager.uid_of_token(&token).await, Some(1));
+        assert!(manager.flush().await.is_ok());
+    }
+}
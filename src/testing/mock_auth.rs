@@ -0,0 +1,400 @@
+//! mock_auth.rs
+//!
+//! A MainAuth server in the test process, answering `/auth/*`, `/users/me`,
+//! `/users/lookup`, `/health` and `/.well-known/sfx-auth` the way a real one
+//! does, from accounts made in the test.
+//! Any path can be scripted to fail, answer something else or answer late:
+//!
+//! ```rust,ignore
+//! let auth = testing::mock_auth_server().await;
+//! let uid = auth.add_user("alice", "Aa333333");
+//! let req = TestRequest::get("/").remote(&auth.server(), &auth.token_for(uid)).build();
+//!
+//! auth.reply("/users/me", MockReply::fail(503, "Down for maintenance"));
+//! let req = testing::through(&[modules::layer::<UserFetch>()], req).await?;
+//! assert!(req.signed_in().is_none());
+//! ```
+//!
+//! For local development `sfx auth mock` runs one with the accounts given
+//! on the command line. It speaks plain HTTP; [`MockAuthServer::server`]
+//! names it with its `http://` scheme, which `Server::get_address` keeps.
+
+use hotaru::prelude::*;
+use hotaru::http::UrlEncodedForm;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+use crate::user::Server;
+use crate::user::capabilities::{self, Capabilities};
+
+/// A scripted answer
+#[derive(Debug, Clone)]
+pub struct MockReply {
+    status: u16,
+    body: Value,
+    delay: Duration,
+    /// Close the connection without answering
+    hang_up: bool,
+}
+
+impl MockReply {
+    pub fn json(status: u16, body: Value) -> Self {
+        Self { status, body, delay: Duration::ZERO, hang_up: false }
+    }
+
+    /// `200` with `body`
+    pub fn ok(body: Value) -> Self {
+        Self::json(200, body)
+    }
+
+    /// `status` with `{"success": false, "error": error}`, as `/auth/*` fails
+    pub fn fail(status: u16, error: &str) -> Self {
+        Self::json(status, object!({ success: false, error: error }))
+    }
+
+    /// No answer at all, as from a server going down mid-request
+    pub fn hang_up() -> Self {
+        Self { hang_up: true, ..Self::json(0, Value::None) }
+    }
+
+    /// The same answer, sent `delay` late
+    pub fn after(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+struct Account {
+    username: String,
+    password: String,
+    is_admin: bool,
+}
+
+#[derive(Default)]
+struct State {
+    accounts: Vec<Account>,
+    tokens: HashMap<String, u32>,
+    /// Answers for the next requests to a path, used once each
+    once: HashMap<String, VecDeque<MockReply>>,
+    /// Answers for every request to a path
+    always: HashMap<String, MockReply>,
+    latency: Duration,
+    calls: Vec<String>,
+}
+
+/// A running mock MainAuth server, stopped when dropped
+pub struct MockAuthServer {
+    address: SocketAddr,
+    state: Arc<Mutex<State>>,
+    task: JoinHandle<()>,
+}
+
+/// Start a mock MainAuth server on a free port of `127.0.0.1`
+pub async fn mock_auth_server() -> MockAuthServer {
+    mock_auth_server_on("127.0.0.1:0").await.expect("Failed to start the mock auth server")
+}
+
+/// Start a mock MainAuth server listening on `address`, as `sfx auth mock` does
+pub async fn mock_auth_server_on(address: &str) -> std::io::Result<MockAuthServer> {
+    let listener = TcpListener::bind(address).await?;
+    let address = listener.local_addr()?;
+    let state = Arc::new(Mutex::new(State::default()));
+    let shared = state.clone();
+    let task = tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(serve(stream, shared.clone()));
+        }
+    });
+    Ok(MockAuthServer { address, state, task })
+}
+
+impl MockAuthServer {
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// The server as sessions and `User`s name it
+    pub fn server(&self) -> Server {
+        Server::MainAuth(format!("http://{}", self.address))
+    }
+
+    /// Make an account, returning its uid; uids count from 1
+    pub fn add_user(&self, username: &str, password: &str) -> u32 {
+        let mut state = self.state.lock().unwrap();
+        state.accounts.push(Account { username: username.to_string(), password: password.to_string(), is_admin: false });
+        state.accounts.len() as u32
+    }
+
+    /// Whether `/auth/admin` reports `uid` as an admin
+    pub fn set_admin(&self, uid: u32, is_admin: bool) {
+        if let Some(account) = self.state.lock().unwrap().accounts.get_mut(uid as usize - 1) {
+            account.is_admin = is_admin;
+        }
+    }
+
+    /// A valid token of `uid`, as a login would give
+    pub fn token_for(&self, uid: u32) -> String {
+        let token = hotaru_lib::random::random_alphanumeric_string(32);
+        self.state.lock().unwrap().tokens.insert(token.clone(), uid);
+        token
+    }
+
+    /// Answer the next request to `path` with `reply`. Replies queued for
+    /// the same path are used in order, then the server answers normally.
+    pub fn reply(&self, path: &str, reply: MockReply) {
+        self.state.lock().unwrap().once.entry(path.to_string()).or_default().push_back(reply);
+    }
+
+    /// Answer every request to `path` with `reply`
+    pub fn reply_always(&self, path: &str, reply: MockReply) {
+        self.state.lock().unwrap().always.insert(path.to_string(), reply);
+    }
+
+    /// Go back to answering every path normally
+    pub fn reset_replies(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.once.clear();
+        state.always.clear();
+    }
+
+    /// Delay every answer by `latency`
+    pub fn latency(&self, latency: Duration) {
+        self.state.lock().unwrap().latency = latency;
+    }
+
+    /// The requests received so far, like `GET /users/me`. Reads of the
+    /// capability document are left out.
+    pub fn calls(&self) -> Vec<String> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    /// Stop answering; connections are then refused
+    pub fn stop(&self) {
+        self.task.abort();
+    }
+}
+
+impl Drop for MockAuthServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// One request as the server reads it
+struct Request {
+    method: String,
+    path: String,
+    bearer: Option<String>,
+    body: Value,
+}
+
+async fn read_request(stream: &mut TcpStream) -> Option<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).await.ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.split('?').next()?.to_string();
+
+    let mut length = 0;
+    let mut bearer = None;
+    let mut form = false;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await.ok()? == 0 {
+            return None;
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => length = value.parse().unwrap_or(0),
+            "authorization" => bearer = value.strip_prefix("Bearer ").map(str::to_string),
+            "content-type" => form = value.starts_with("application/x-www-form-urlencoded"),
+            _ => {}
+        }
+    }
+    let mut raw = vec![0; length];
+    reader.read_exact(&mut raw).await.ok()?;
+    let body = if form {
+        let mut fields = Value::new_dict();
+        for (key, value) in UrlEncodedForm::parse(raw).get_all() {
+            fields.set(key, value.clone());
+        }
+        fields
+    } else {
+        Value::from_json(&String::from_utf8_lossy(&raw)).unwrap_or(Value::None)
+    };
+    Some(Request { method, path, bearer, body })
+}
+
+async fn serve(mut stream: TcpStream, state: Arc<Mutex<State>>) {
+    let Some(request) = read_request(&mut stream).await else {
+        return;
+    };
+    let (reply, latency) = {
+        let mut state = state.lock().unwrap();
+        if request.path != capabilities::WELL_KNOWN {
+            state.calls.push(format!("{} {}", request.method, request.path));
+        }
+        let scripted = state.once.get_mut(&request.path).and_then(VecDeque::pop_front);
+        let reply = scripted
+            .or_else(|| state.always.get(&request.path).cloned())
+            .unwrap_or_else(|| answer(&mut state, &request));
+        (reply, state.latency)
+    };
+    tokio::time::sleep(latency + reply.delay).await;
+    if reply.hang_up {
+        return;
+    }
+    let body = reply.body.into_json();
+    let response = format!(
+        "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        reply.status,
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+/// The answer of a real MainAuth server
+fn answer(state: &mut State, request: &Request) -> MockReply {
+    let uid = request.bearer.as_ref().and_then(|token| state.tokens.get(token).copied());
+    match request.path.as_str() {
+        "/health" => MockReply::ok(object!({ status: "ok" })),
+        capabilities::WELL_KNOWN => MockReply::ok(Capabilities::legacy("mock").into_json()),
+        "/auth/login" => {
+            let username = request.body.get("username").string();
+            let password = request.body.get("password").string();
+            let Some(index) = state.accounts.iter().position(|account| account.username == username) else {
+                return MockReply::fail(401, "User not found");
+            };
+            if state.accounts[index].password != password {
+                return MockReply::fail(401, "Password mismatch");
+            }
+            let token = hotaru_lib::random::random_alphanumeric_string(32);
+            state.tokens.insert(token.clone(), index as u32 + 1);
+            MockReply::ok(object!({ success: true, access_token: token, token_type: "Bearer" }))
+        }
+        "/auth/refresh" => match (uid, &request.bearer) {
+            (Some(uid), Some(old)) => {
+                state.tokens.remove(old);
+                let token = hotaru_lib::random::random_alphanumeric_string(32);
+                state.tokens.insert(token.clone(), uid);
+                MockReply::ok(object!({ success: true, access_token: token, token_type: "Bearer" }))
+            }
+            _ => MockReply::fail(401, "Token invalid"),
+        },
+        "/auth/logout" => {
+            if let Some(token) = &request.bearer {
+                state.tokens.remove(token);
+            }
+            MockReply::ok(object!({ success: true, message: "Logged out" }))
+        }
+        "/auth/admin" => match uid {
+            Some(uid) => MockReply::ok(object!({ success: true, uid: uid, is_admin: state.accounts[uid as usize - 1].is_admin })),
+            None => MockReply::fail(401, "Token invalid"),
+        },
+        "/users/me" => match uid {
+            Some(uid) => {
+                let account = &state.accounts[uid as usize - 1];
+                MockReply::ok(object!({ success: true, user: {
+                    uid: uid,
+                    username: &account.username,
+                    email: format!("{}@example.com", account.username),
+                    is_active: true,
+                    is_verified: true,
+                } }))
+            }
+            None => MockReply::fail(401, "Token invalid"),
+        },
+        "/users/lookup" => {
+            let Value::List(keys) = request.body.get("users") else {
+                return MockReply::fail(400, "No users requested");
+            };
+            let users: Vec<Value> = keys
+                .iter()
+                .filter_map(|key| {
+                    let index = match key {
+                        Value::Numerical(uid) => (*uid as usize).checked_sub(1),
+                        key => state.accounts.iter().position(|account| account.username == key.string()),
+                    }?;
+                    let account = state.accounts.get(index)?;
+                    Some(object!({ uid: index + 1, username: &account.username, is_active: true }))
+                })
+                .collect();
+            MockReply::ok(object!({ success: true, users: users }))
+        }
+        _ => MockReply::fail(404, "Not found"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ctx::SfxCtx;
+    use crate::modules;
+    use crate::testing::{TestRequest, through};
+    use crate::user::client::{AuthClient, UserKey};
+    use crate::user::fetch::{get_auth_token, refresh_user_token};
+    use crate::user::middleware::UserFetch;
+
+    #[tokio::test]
+    async fn sessions_of_the_mock_server_sign_in_and_refresh() {
+        let auth = mock_auth_server().await;
+        let uid = auth.add_user("alice", "Aa333333");
+        auth.set_admin(uid, true);
+        let token = auth.token_for(uid);
+
+        let req = TestRequest::get("/").remote(&auth.server(), &token).build();
+        let mut req = through(&[modules::layer::<UserFetch>()], req).await.unwrap();
+        assert_eq!(req.signed_in().map(|user| user.get_username().to_string()).as_deref(), Some("alice"));
+        assert!(AuthClient::new(auth.server(), token.clone()).is_admin().await);
+
+        assert!(refresh_user_token(&mut req).await.get("success").boolean());
+        assert_ne!(get_auth_token(&req), Some(token));
+        assert_eq!(auth.calls(), vec!["GET /users/me", "GET /auth/admin", "GET /auth/refresh"]);
+    }
+
+    #[tokio::test]
+    async fn lookups_are_one_call() {
+        let auth = mock_auth_server().await;
+        let alice = auth.add_user("alice", "Aa333333");
+        auth.add_user("bob", "Bb444444");
+        let client = AuthClient::new(auth.server(), auth.token_for(alice));
+
+        let keys = [UserKey::from(2), UserKey::from("alice"), UserKey::from("nobody")];
+        let users = client.lookup_many(&keys).await.unwrap();
+        let names: Vec<String> = users.iter().map(|user| user.get("username").string()).collect();
+        assert_eq!(names, vec!["bob", "alice"]);
+        assert_eq!(auth.calls(), vec!["POST /users/lookup"]);
+    }
+
+    #[tokio::test]
+    async fn scripted_failures_turn_sessions_into_guests() {
+        let auth = mock_auth_server().await;
+        let uid = auth.add_user("bob", "Bb444444");
+        auth.reply("/users/me", MockReply::fail(503, "Down for maintenance").after(Duration::from_millis(20)));
+
+        let req = TestRequest::get("/").remote(&auth.server(), &auth.token_for(uid)).build();
+        let req = through(&[modules::layer::<UserFetch>()], req).await.unwrap();
+        assert!(req.signed_in().is_none());
+
+        // The next request is answered normally again
+        let req = TestRequest::get("/").remote(&auth.server(), &auth.token_for(uid)).build();
+        let req = through(&[modules::layer::<UserFetch>()], req).await.unwrap();
+        assert_eq!(req.local_uid(), None);
+        assert!(req.signed_in().is_some());
+    }
+}
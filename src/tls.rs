@@ -0,0 +1,361 @@
+//! tls.rs
+//!
+//! Native HTTPS for the embedded server.
+//!
+//! `APP` is a plain-TCP hotaru server, so TLS is terminated by a small
+//! in-process front: it accepts TLS on its own binding and forwards the
+//! decrypted stream to the `APP` binding over loopback (see
+//! `crate::bindings::forward`). `crate::proxy` still reports the real client
+//! address and an `https` scheme for those connections.
+//!
+//! Configured in `programfiles/op/tls.json`:
+//!
+//! ```json
+//! {
+//!     "enabled": true,
+//!     "binding": "0.0.0.0:443",
+//!     "cert": "programfiles/tls/fullchain.pem",
+//!     "key": "programfiles/tls/privkey.pem",
+//!     "redirect_http": true,
+//!     "acme_webroot": "programfiles/tls/webroot",
+//!     "reload_interval": 3600,
+//!     "client_ca": "programfiles/tls/clients-ca.pem",
+//!     "client_cert_routes": ["/users", "/auth"],
+//!     "client_fingerprints": []
+//! }
+//! ```
+//!
+//! With `redirect_http` the plain binding (`binding.txt`) becomes the
+//! secondary one and answers every external request with a redirect to
+//! HTTPS, except ACME HTTP-01 challenges. Those are served from
+//! `acme_webroot`, so certificates can be provisioned and renewed by
+//! Let's Encrypt clients in webroot mode (`certbot certonly --webroot -w
+//! programfiles/tls/webroot -d example.com`). The certificate files are
+//! re-read every `reload_interval` seconds when they change.
+//!
+//! With `client_ca`, TLS clients may present a certificate signed by it
+//! (mutual TLS; frontends configure theirs in `outbound.json`). Browsers
+//! without one are still served, but the paths under `client_cert_routes`
+//! answer `403 Forbidden` unless the connection presented one, whose
+//! SHA-256 must also be among `client_fingerprints` when that is set. The
+//! server's own loopback calls and the Unix socket are let through.
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+use hotaru::hotaru_core::connection::{Accepter, ConnStream};
+use hotaru_tls::{TlsAccepter, TlsConfig, TlsStream};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpListener;
+
+use crate::bindings::{self, Front};
+use crate::op::{self, APP};
+use crate::proxy;
+
+static TLS: Lazy<TlsSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/tls.json");
+    TlsSettings::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None))
+});
+
+/// Path prefix of ACME HTTP-01 challenges
+const ACME_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// The parsed content of `tls.json`
+#[derive(Debug, Clone)]
+pub struct TlsSettings {
+    pub enabled: bool,
+    pub binding: String,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub redirect_http: bool,
+    pub acme_webroot: Option<PathBuf>,
+    pub reload_interval: Duration,
+    /// CA of the client certificates to ask TLS clients for
+    pub client_ca: Option<PathBuf>,
+    /// Path prefixes that need a client certificate
+    pub client_cert_routes: Vec<String>,
+    /// Accepted client certificates by lowercase hex SHA-256, any when empty
+    pub client_fingerprints: Vec<String>,
+}
+
+impl TlsSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let string_or = |key: &str, default: &str| {
+            let s = value.get(key).string();
+            if s.is_empty() { default.to_string() } else { s }
+        };
+        let webroot = value.get("acme_webroot").string();
+        let client_ca = value.get("client_ca").string();
+        let strings = |key: &str| match value.get(key) {
+            Value::List(items) => items.iter().map(|item| item.string()).filter(|item| !item.is_empty()).collect(),
+            _ => Vec::new(),
+        };
+        let reload = value.get("reload_interval").integer();
+        Self {
+            enabled: value.get("enabled").boolean(),
+            binding: string_or("binding", "0.0.0.0:443"),
+            cert: string_or("cert", "programfiles/tls/fullchain.pem").into(),
+            key: string_or("key", "programfiles/tls/privkey.pem").into(),
+            redirect_http: value.get("redirect_http").boolean(),
+            acme_webroot: (!webroot.is_empty()).then(|| webroot.into()),
+            reload_interval: Duration::from_secs(if reload > 0 { reload as u64 } else { 3600 }),
+            client_ca: (!client_ca.is_empty()).then(|| client_ca.into()),
+            client_cert_routes: strings("client_cert_routes")
+                .into_iter()
+                .map(|prefix| prefix.trim_end_matches('/').to_string())
+                .collect(),
+            client_fingerprints: strings("client_fingerprints")
+                .into_iter()
+                .map(|fingerprint| fingerprint.replace(':', "").to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    /// Whether `path` needs a client certificate
+    pub fn requires_client_cert(&self, path: &str) -> bool {
+        self.client_cert_routes.iter().any(|prefix| {
+            path == prefix || prefix.is_empty() || path.strip_prefix(prefix.as_str()).is_some_and(|rest| rest.starts_with('/'))
+        })
+    }
+
+    /// Whether a connection with the client certificate `fingerprint` may
+    /// use those paths
+    pub fn accepts_client_cert(&self, fingerprint: Option<&[u8; 32]>) -> bool {
+        fingerprint.is_some_and(|fingerprint| {
+            self.client_fingerprints.is_empty() || self.client_fingerprints.contains(&hex(fingerprint))
+        })
+    }
+
+    /// The `:port` suffix to use in redirects, empty for 443
+    fn port_suffix(&self) -> String {
+        match self.binding.rsplit_once(':').map(|(_, port)| port) {
+            Some("443") | None => String::new(),
+            Some(port) => format!(":{}", port),
+        }
+    }
+
+    fn load_config(&self) -> Result<TlsConfig, String> {
+        TlsConfig::builder()
+            .cert_chain_file(&self.cert)
+            .and_then(|b| b.private_key_file(&self.key))
+            .and_then(|b| match &self.client_ca {
+                Some(ca) => b.optional_client_auth(ca),
+                None => Ok(b),
+            })
+            .and_then(|b| b.alpn_protocols(&["http/1.1"]).build())
+            .map_err(|err| format!("{:?}", err))
+    }
+}
+
+/// The loaded TLS settings
+pub fn settings() -> &'static TlsSettings {
+    &TLS
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// SHA-256 of the certificate a TLS client presented; the handshake only
+/// completes when it is signed by `client_ca`
+fn client_fingerprint(tls: &TlsStream) -> Option<[u8; 32]> {
+    let TlsStream::Server(stream) = tls else { return None };
+    let leaf = stream.get_ref().1.peer_certificates()?.first()?;
+    Some(Sha256::digest(leaf).into())
+}
+
+/// The SHA-256 of the client certificate of a request, in hex
+pub fn client_cert_of(req: &HttpReqCtx) -> Option<String> {
+    match bindings::front_of(req) {
+        Some(Front::Tls(_, Some(fingerprint))) => Some(hex(&fingerprint)),
+        _ => None,
+    }
+}
+
+fn modified(settings: &TlsSettings) -> Option<SystemTime> {
+    let cert = std::fs::metadata(&settings.cert).and_then(|m| m.modified()).ok()?;
+    let key = std::fs::metadata(&settings.key).and_then(|m| m.modified()).ok()?;
+    Some(cert.max(key))
+}
+
+/// Start the TLS front if `tls.json` enables it. `upstream` is the plain
+/// `APP` binding the decrypted traffic is forwarded to.
+///
+/// Returns an error when the certificate cannot be loaded or the binding
+/// cannot be bound, so a misconfiguration fails at startup.
+pub async fn start(upstream: String) -> std::io::Result<()> {
+    if !TLS.enabled {
+        return Ok(());
+    }
+    let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg);
+    let accepter = TlsAccepter::new(TLS.load_config().map_err(invalid)?)
+        .map_err(|err| invalid(err.to_string()))?;
+    let accepter = std::sync::Arc::new(RwLock::new(std::sync::Arc::new(accepter)));
+    let listener = TcpListener::bind(&TLS.binding).await?;
+    tracing::info!(binding = %TLS.binding, %upstream, "TLS front listening");
+
+    // Pick up renewed certificates without a restart
+    let reloader = accepter.clone();
+    tokio::spawn(async move {
+        let mut last = modified(&TLS);
+        loop {
+            tokio::time::sleep(TLS.reload_interval).await;
+            let current = modified(&TLS);
+            if current == last {
+                continue;
+            }
+            match TLS.load_config().and_then(|c| TlsAccepter::new(c).map_err(|e| e.to_string())) {
+                Ok(new) => {
+                    *reloader.write().unwrap() = std::sync::Arc::new(new);
+                    last = current;
+                    tracing::info!("TLS certificate reloaded");
+                    crate::events::publish(crate::events::ConfigReloaded { source: "tls".to_string() });
+                }
+                Err(err) => tracing::error!(%err, "TLS certificate reload failed"),
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        loop {
+            let (tcp, peer) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(err) => {
+                    tracing::warn!(%err, "TLS front accept failed");
+                    continue;
+                }
+            };
+            let accepter = accepter.read().unwrap().clone();
+            let upstream = upstream.clone();
+            tokio::spawn(async move {
+                let tls = match accepter.upgrade(tcp).await {
+                    Ok(tls) => tls,
+                    Err(err) => {
+                        tracing::debug!(%peer, %err, "TLS handshake failed");
+                        return;
+                    }
+                };
+                let client = tls.peer_addr().unwrap_or(peer);
+                let cert = client_fingerprint(&tls);
+                bindings::forward(tls, &upstream, Front::Tls(client, cert)).await;
+            });
+        }
+    });
+    Ok(())
+}
+
+middleware! {
+    /// Middleware answering `403 Forbidden` on the `client_cert_routes` of
+    /// `tls.json` to requests without an accepted client certificate. The
+    /// server's own loopback calls and the Unix socket need none.
+    pub ClientCertGuard <HTTP> {
+        if TLS.requires_client_cert(&req.path()) {
+            let allowed = match bindings::front_of(&req) {
+                Some(Front::Tls(_, fingerprint)) => TLS.accepts_client_cert(fingerprint.as_ref()),
+                Some(Front::Unix) => true,
+                None => {
+                    req.client_ip_only().is_some_and(|ip| ip.is_loopback())
+                        && !proxy::forwarded_info(&req).via_proxy
+                }
+            };
+            if !allowed {
+                tracing::warn!(path = %req.path(), "Request without an accepted client certificate");
+                req.response = text_response("Forbidden").status(StatusCode::FORBIDDEN);
+                return Ok(req)
+            }
+        }
+        next(req).await
+    }
+}
+
+/// Whether an ACME challenge token is safe to use as a file name
+fn is_valid_token(token: &str) -> bool {
+    !token.is_empty()
+        && token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+middleware! {
+    /// Middleware redirecting plain-HTTP requests to HTTPS when `tls.json`
+    /// sets `redirect_http`. ACME challenges and loopback callers (the
+    /// server's own server-to-server calls) are let through.
+    pub HttpsRedirect <HTTP> {
+        let from_loopback = req
+            .client_ip_only()
+            .map(|ip| ip.is_loopback())
+            .unwrap_or(true);
+        let path = req.path();
+        if TLS.enabled
+            && TLS.redirect_http
+            && proxy::scheme(&req) == "http"
+            && !from_loopback
+            && !path.starts_with(ACME_CHALLENGE_PREFIX)
+        {
+            let host = req
+                .header_str("host")
+                .map(|h| h.to_string())
+                .unwrap_or_else(|| op::BINDING.clone());
+            let host = match host.rsplit_once(':') {
+                Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name.to_string(),
+                _ => host,
+            };
+            let target = format!("https://{}{}{}", host, TLS.port_suffix(), req.request.meta.url());
+            req.response = redirect_response(&target).status(StatusCode::PERMANENT_REDIRECT);
+            return Ok(req)
+        }
+        next(req).await
+    }
+}
+
+endpoint! {
+    APP.url("/.well-known/acme-challenge/<token>"),
+
+    /// Serves ACME HTTP-01 challenge files written by a webroot-mode client
+    ///
+    /// # Request
+    /// `GET /.well-known/acme-challenge/<token>`
+    ///
+    /// # Response
+    /// The key authorization stored in `<acme_webroot>/.well-known/acme-challenge/<token>`,
+    /// or 404 when the webroot is not configured or the token is unknown
+    pub acme_challenge <HTTP> {
+        let token = req.param("token").unwrap_or_default();
+        let Some(webroot) = TLS.acme_webroot.as_ref().filter(|_| is_valid_token(&token)) else {
+            return text_response("Not Found").status(StatusCode::NOT_FOUND);
+        };
+        match std::fs::read_to_string(webroot.join(".well-known/acme-challenge").join(&token)) {
+            Ok(content) => text_response(content),
+            Err(_) => text_response("Not Found").status(StatusCode::NOT_FOUND),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_cert_routes_need_a_pinned_certificate() {
+        let pin = format!("AB:CD:{}", "00:".repeat(29) + "01");
+        let settings = TlsSettings::from_value(
+            &Value::from_json(&format!(
+                r#"{{"client_ca": "ca.pem", "client_cert_routes": ["/auth", "/users/"], "client_fingerprints": ["{}"]}}"#,
+                pin
+            ))
+            .unwrap(),
+        );
+        assert!(settings.requires_client_cert("/auth/login") && settings.requires_client_cert("/users"));
+        assert!(!settings.requires_client_cert("/authors") && !settings.requires_client_cert("/blog"));
+        let mut pinned = [0u8; 32];
+        pinned[0] = 0xab;
+        pinned[1] = 0xcd;
+        pinned[31] = 0x01;
+        assert!(settings.accepts_client_cert(Some(&pinned)));
+        assert!(!settings.accepts_client_cert(Some(&[1; 32])) && !settings.accepts_client_cert(None));
+        let any = TlsSettings { client_fingerprints: Vec::new(), ..settings };
+        assert!(any.accepts_client_cert(Some(&[1; 32])) && !any.accepts_client_cert(None));
+    }
+}
@@ -0,0 +1,154 @@
+//! unix_socket.rs
+//!
+//! Listening on a Unix domain socket, for deployments where a local reverse
+//! proxy (nginx `proxy_pass http://unix:/run/sfx/sfx.sock;`) is the only
+//! client. Enabled by writing a socket path into `binding.txt`:
+//!
+//! ```text
+//! unix:/run/sfx/sfx.sock
+//! ```
+//!
+//! `APP` itself only speaks TCP, so it still binds a loopback address and
+//! the socket forwards each connection to it (see `crate::bindings::forward`).
+//! Both are read from `programfiles/op/unix_socket.json`:
+//!
+//! ```json
+//! {
+//!     "mode": "660",
+//!     "internal_binding": "127.0.0.1:3003"
+//! }
+//! ```
+//!
+//! `mode` is the octal permission of the socket file, so access can be
+//! limited to the proxy's group. A stale socket left by a crashed process is
+//! replaced at startup, and the file is removed again on shutdown.
+
+use hotaru::prelude::*;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio::net::UnixListener;
+
+use crate::bindings::{self, Front};
+use crate::op::{self, Binding};
+
+static UNIX_SOCKET: Lazy<UnixSocketSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/unix_socket.json");
+    UnixSocketSettings::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None))
+});
+
+/// The socket file created by [`start`], removed by [`cleanup`]
+static SOCKET_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// The parsed content of `unix_socket.json`
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnixSocketSettings {
+    /// Permission bits of the socket file, `None` to keep the umask default
+    pub mode: Option<u32>,
+    /// Loopback address `APP` binds to behind the socket
+    pub internal_binding: String,
+}
+
+impl UnixSocketSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let mode = match value.get("mode") {
+            Value::Numerical(mode) => u32::from_str_radix(&(*mode as u64).to_string(), 8).ok(),
+            mode => u32::from_str_radix(mode.string().trim_start_matches("0o"), 8).ok(),
+        };
+        let internal_binding = value.get("internal_binding").string();
+        Self {
+            mode: mode.filter(|mode| *mode <= 0o7777),
+            internal_binding: if internal_binding.is_empty() {
+                "127.0.0.1:3003".to_string()
+            } else {
+                internal_binding
+            },
+        }
+    }
+}
+
+/// The loaded Unix socket settings
+pub fn settings() -> &'static UnixSocketSettings {
+    &UNIX_SOCKET
+}
+
+/// Remove a socket file left behind by a previous run. Refuses to touch
+/// anything that is not a socket, or a socket another process still serves.
+fn remove_stale(path: &Path) -> std::io::Result<()> {
+    let Ok(meta) = std::fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    if !meta.file_type().is_socket() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AddrInUse,
+            format!("{} is in use by another process", path.display()),
+        ));
+    }
+    std::fs::remove_file(path)
+}
+
+/// Listen on the socket of `binding.txt`, if it names one, and forward its
+/// connections to the `APP` binding `upstream`
+pub async fn start(upstream: String) -> std::io::Result<()> {
+    let Binding::Unix(path) = &*op::LISTEN else {
+        return Ok(());
+    };
+    remove_stale(path)?;
+    let listener = UnixListener::bind(path)?;
+    *SOCKET_PATH.lock().unwrap() = Some(path.clone());
+    if let Some(mode) = UNIX_SOCKET.mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    tracing::info!(path = %path.display(), %upstream, "Listening on Unix socket");
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let upstream = upstream.clone();
+                    tokio::spawn(async move {
+                        bindings::forward(stream, &upstream, Front::Unix).await;
+                    });
+                }
+                Err(err) => tracing::warn!(%err, "Unix socket accept failed"),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Remove the socket file created by [`start`]
+pub fn cleanup() {
+    if let Some(path) = SOCKET_PATH.lock().unwrap().take()
+        && let Err(err) = std::fs::remove_file(&path)
+    {
+        tracing::warn!(path = %path.display(), %err, "Failed to remove Unix socket");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_binding_and_octal_mode() {
+        assert_eq!(
+            Binding::parse("unix:/run/sfx/sfx.sock\n"),
+            Binding::Unix(PathBuf::from("/run/sfx/sfx.sock"))
+        );
+        assert_eq!(Binding::parse("localhost:3003"), Binding::Tcp("localhost:3003".to_string()));
+
+        let settings = UnixSocketSettings::from_value(&object!({ mode: "660" }));
+        assert_eq!(settings.mode, Some(0o660));
+        assert_eq!(settings.internal_binding, "127.0.0.1:3003");
+        assert_eq!(UnixSocketSettings::from_value(&object!({ mode: 600 })).mode, Some(0o600));
+        assert_eq!(UnixSocketSettings::from_value(&object!({ mode: "abc" })).mode, None);
+        assert_eq!(UnixSocketSettings::from_value(&Value::None).mode, None);
+    }
+}
@@ -2,12 +2,21 @@ pub const CACHE_VALID_TIME: u64 = 60 * 60; // 1 hour
 
 pub const HALF_VALID_TIME: u64 = CACHE_VALID_TIME / 2; 
 
+pub mod avatar;
+pub mod breaker;
+pub mod cache;
+pub mod capabilities;
+pub mod client;
+pub mod confirm;
 pub mod endpoints; 
 pub mod fetch; 
+pub mod logout;
 pub mod user; 
 pub mod middleware; 
+pub mod security;
 pub mod server; 
 
+pub use client::AuthClient;
 pub use user::{User, UserID}; 
 pub use middleware::UserFetch; 
 pub use server::Server; 
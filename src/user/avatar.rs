@@ -0,0 +1,135 @@
+//! avatar.rs
+//!
+//! The picture shown for a user: the avatar they uploaded (the `avatar`
+//! entry of their profile), or else one from an avatar service keyed by the
+//! hash of their email, as set in `programfiles/op/avatar.json`:
+//!
+//! ```json
+//! { "provider": "gravatar", "default": "identicon", "size": 80 }
+//! ```
+//!
+//! `provider` is `gravatar`, `libravatar`, the base URL of another service
+//! speaking the same protocol, or `none` to never ask one, for deployments
+//! that do not want emails hashed into public URLs. `default` is what the
+//! service shows for emails it does not know (`identicon`, `retro`, `mp`,
+//! a URL...). Both services take the SHA-256 of the trimmed, lowercased
+//! email.
+//!
+//! Pages get the result in `pageprop.user.avatar`, `GET /users/<uid>` and
+//! `POST /users/lookup` in `avatar`; `null` when there is nothing to show.
+
+use hotaru::prelude::*;
+use sha2::{Digest, Sha256};
+
+use super::User;
+
+static AVATAR: Lazy<AvatarSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/avatar.json");
+    AvatarSettings::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None))
+});
+
+const GRAVATAR: &str = "https://www.gravatar.com/avatar/";
+const LIBRAVATAR: &str = "https://seccdn.libravatar.org/avatar/";
+
+/// The parsed content of `avatar.json`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AvatarSettings {
+    /// Base URL of the service, `None` when turned off
+    pub provider: Option<String>,
+    pub default: String,
+    /// Edge in pixels
+    pub size: u32,
+}
+
+impl Default for AvatarSettings {
+    fn default() -> Self {
+        Self { provider: Some(GRAVATAR.to_string()), default: "identicon".to_string(), size: 80 }
+    }
+}
+
+impl AvatarSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let default = Self::default();
+        let provider = match value.get("provider") {
+            Value::Str(name) => match name.as_str() {
+                "none" | "" => None,
+                "gravatar" => Some(GRAVATAR.to_string()),
+                "libravatar" => Some(LIBRAVATAR.to_string()),
+                base if base.ends_with('/') => Some(base.to_string()),
+                base => Some(format!("{}/", base)),
+            },
+            _ => default.provider,
+        };
+        Self {
+            provider,
+            default: match value.get("default") {
+                Value::Str(image) if !image.is_empty() => image.clone(),
+                _ => default.default,
+            },
+            size: match value.get("size") {
+                Value::Numerical(size) if *size >= 1.0 => (*size as u32).min(2048),
+                _ => default.size,
+            },
+        }
+    }
+
+    /// The avatar: `uploaded` when there is one, else the URL of the
+    /// service for `email`
+    pub fn resolve(&self, uploaded: Option<&str>, email: &str) -> Option<String> {
+        if let Some(uploaded) = uploaded.filter(|url| !url.is_empty()) {
+            return Some(uploaded.to_string());
+        }
+        let email = email.trim().to_lowercase();
+        let base = self.provider.as_ref().filter(|_| email.contains('@'))?;
+        let hash: String = Sha256::digest(email.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect();
+        Some(format!("{}{}?d={}&s={}", base, hash, query_escape(&self.default), self.size))
+    }
+}
+
+/// Percent-encode `value` for a query string
+fn query_escape(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+            byte => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// The loaded avatar settings
+pub fn settings() -> &'static AvatarSettings {
+    &AVATAR
+}
+
+/// The avatar of `uploaded` or `email`, see [`AvatarSettings::resolve`]
+pub fn resolve(uploaded: Option<&str>, email: &str) -> Option<String> {
+    settings().resolve(uploaded, email)
+}
+
+/// The avatar of `user`, `None` for guests
+pub fn of_user(user: &User) -> Option<String> {
+    if user.get_uid() == 0 {
+        return None;
+    }
+    resolve(user.get_avatar(), user.get_email())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uploads_win_over_hashed_emails() {
+        let settings = AvatarSettings::from_value(&object!({ provider: "libravatar", default: "https://x.test/a.png", size: 64 }));
+        assert_eq!(settings.resolve(Some("/media/me.png"), "a@b.c").as_deref(), Some("/media/me.png"));
+        let url = settings.resolve(None, " Alice@Example.com ").unwrap();
+        assert_eq!(url, settings.resolve(None, "alice@example.com").unwrap());
+        assert!(url.starts_with(LIBRAVATAR) && url.ends_with("?d=https%3A%2F%2Fx.test%2Fa.png&s=64"));
+        assert_eq!(url.len(), LIBRAVATAR.len() + 64 + "?d=https%3A%2F%2Fx.test%2Fa.png&s=64".len());
+
+        let off = AvatarSettings::from_value(&object!({ provider: "none" }));
+        assert_eq!(off.resolve(None, "alice@example.com"), None);
+        assert_eq!(AvatarSettings::from_value(&Value::None), AvatarSettings::default());
+    }
+}
@@ -0,0 +1,213 @@
+//! breaker.rs
+//!
+//! A circuit breaker for the auth servers `UserFetch` asks who a session
+//! belongs to, from `programfiles/op/auth_breaker.json`:
+//!
+//! ```json
+//! { "failures": 3, "open_secs": 30, "grace_secs": 900 }
+//! ```
+//!
+//! After `failures` calls in a row that get no answer (no connection, a
+//! `5xx`, a body that is not JSON), the circuit of the host opens: for
+//! `open_secs` no call is made and the host counts as unreachable at once,
+//! so requests stop waiting on a server that is down. Then a single call is
+//! let through; an answer closes the circuit, another failure keeps it open.
+//!
+//! While a host is unreachable, sessions keep the user they cached for
+//! `grace_secs` past the usual hour, flagged stale (`User::is_stale`),
+//! instead of being signed out. A token the server answers to and refuses
+//! still signs the session out.
+
+use hotaru::prelude::*;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static BREAKER_SETTINGS: Lazy<BreakerSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/auth_breaker.json");
+    BreakerSettings::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None))
+});
+
+static BREAKER: Lazy<Breaker> = Lazy::new(|| Breaker::new(settings().clone()));
+
+/// The parsed content of `auth_breaker.json`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BreakerSettings {
+    /// Failures in a row opening the circuit
+    pub failures: u32,
+    /// Seconds the circuit stays open before a trial call
+    pub open_secs: u64,
+    /// Seconds a cached user is served stale past its validity
+    pub grace_secs: u64,
+}
+
+impl Default for BreakerSettings {
+    fn default() -> Self {
+        Self { failures: 3, open_secs: 30, grace_secs: 900 }
+    }
+}
+
+impl BreakerSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let default = Self::default();
+        let number = |key: &str, fallback: u64| match value.get(key) {
+            Value::Numerical(number) if *number >= 0.0 => *number as u64,
+            _ => fallback,
+        };
+        Self {
+            failures: number("failures", default.failures as u64).max(1) as u32,
+            open_secs: number("open_secs", default.open_secs),
+            grace_secs: number("grace_secs", default.grace_secs),
+        }
+    }
+}
+
+/// The loaded breaker settings
+pub fn settings() -> &'static BreakerSettings {
+    &BREAKER_SETTINGS
+}
+
+#[derive(Debug, Default)]
+struct Circuit {
+    failures: u32,
+    /// When the circuit opened, or when its last trial call was let through
+    opened_at: Option<u64>,
+}
+
+/// The circuits of the hosts
+pub struct Breaker {
+    settings: BreakerSettings,
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+impl Breaker {
+    pub fn new(settings: BreakerSettings) -> Self {
+        Self { settings, circuits: Mutex::new(HashMap::new()) }
+    }
+
+    /// Whether a call to `host` may be made at `now`
+    pub fn allow(&self, host: &str, now: u64) -> bool {
+        let mut circuits = self.circuits.lock().unwrap();
+        let Some(circuit) = circuits.get_mut(host) else {
+            return true;
+        };
+        match circuit.opened_at {
+            None => true,
+            Some(opened_at) if now >= opened_at + self.settings.open_secs => {
+                // Let this one through; the others wait for its outcome
+                circuit.opened_at = Some(now);
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    /// `host` answered
+    pub fn success(&self, host: &str) {
+        self.circuits.lock().unwrap().remove(host);
+    }
+
+    /// `host` did not answer at `now`
+    pub fn failure(&self, host: &str, now: u64) {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits.entry(host.to_string()).or_default();
+        circuit.failures += 1;
+        if circuit.failures >= self.settings.failures {
+            if circuit.opened_at.is_none() {
+                tracing::warn!(%host, failures = circuit.failures, "Auth server unreachable, circuit opened");
+            }
+            circuit.opened_at = Some(now);
+        }
+    }
+
+    /// The hosts whose circuit is open
+    pub fn open_hosts(&self) -> Vec<String> {
+        let mut hosts: Vec<String> = self
+            .circuits
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, circuit)| circuit.opened_at.is_some())
+            .map(|(host, _)| host.clone())
+            .collect();
+        hosts.sort();
+        hosts
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Whether a call to `host` may be made now
+pub fn allow(host: &str) -> bool {
+    BREAKER.allow(host, now())
+}
+
+pub fn success(host: &str) {
+    BREAKER.success(host)
+}
+
+pub fn failure(host: &str) {
+    BREAKER.failure(host, now())
+}
+
+/// The auth servers whose circuit is open, for `/health`
+pub fn open_hosts() -> Vec<String> {
+    BREAKER.open_hosts()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ctx::SfxCtx;
+    use crate::modules;
+    use crate::testing::{MockReply, TestRequest, mock_auth_server, through};
+    use crate::user::middleware::UserFetch;
+    use crate::user::{CACHE_VALID_TIME, User, UserID};
+
+    #[test]
+    fn circuit_opens_and_lets_one_trial_through() {
+        let breaker = Breaker::new(BreakerSettings { failures: 2, open_secs: 30, grace_secs: 0 });
+        assert!(breaker.allow("https://auth", 100));
+        breaker.failure("https://auth", 100);
+        assert!(breaker.allow("https://auth", 101));
+        breaker.failure("https://auth", 101);
+        assert!(!breaker.allow("https://auth", 110));
+        assert_eq!(breaker.open_hosts(), vec!["https://auth"]);
+        assert!(breaker.allow("https://other", 110));
+
+        // One trial after open_secs, the next caller waits for it
+        assert!(breaker.allow("https://auth", 131));
+        assert!(!breaker.allow("https://auth", 132));
+        breaker.failure("https://auth", 132);
+        assert!(!breaker.allow("https://auth", 150));
+        assert!(breaker.allow("https://auth", 162));
+        breaker.success("https://auth");
+        assert!(breaker.allow("https://auth", 163) && breaker.open_hosts().is_empty());
+    }
+
+    #[tokio::test]
+    async fn expired_caches_are_served_stale_while_the_server_is_down() {
+        let auth = mock_auth_server().await;
+        let uid = auth.add_user("carol", "Cc555555");
+        auth.reply_always("/users/me", MockReply::fail(503, "Down"));
+        let expired = now() - CACHE_VALID_TIME - 60;
+        let cached: Value = User::new(UserID::new(uid as usize, auth.server()), "carol".into(), "c@example.com".into(), true, true)
+            .set_cached_time(Some(expired))
+            .into();
+
+        for _ in 0..settings().failures + 1 {
+            let req = TestRequest::get("/")
+                .remote(&auth.server(), &auth.token_for(uid))
+                .session("user_info_cache", cached.clone())
+                .build();
+            let req = through(&[modules::layer::<UserFetch>()], req).await.unwrap();
+            let user = req.signed_in().expect("the cached user is kept");
+            assert!(user.is_stale() && user.get_username() == "carol");
+        }
+        // The circuit opened after `failures` calls; the last request made none
+        assert_eq!(auth.calls().len(), settings().failures as usize);
+        assert!(open_hosts().contains(&auth.server().get_address()));
+    }
+}
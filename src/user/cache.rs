@@ -0,0 +1,195 @@
+//! cache.rs
+//!
+//! How long the user of a session is taken from the session cache rather
+//! than asked of the auth server again. The auth server says so on
+//! `/users/me`:
+//!
+//! ```text
+//! Cache-Control: private, max-age=1800, stale-while-revalidate=1800
+//! ```
+//!
+//! Within `max-age` the cached user is used as is. In the
+//! `stale-while-revalidate` seconds after it, the cached user is still used
+//! while [`UserFetch`](super::UserFetch) asks the server again in the
+//! background; the next request of the session takes the answer. Past both,
+//! the request waits for the server. `no-store` or `no-cache` asks on every
+//! request, and a server sending no `Cache-Control` gets
+//! [`CachePolicy::DEFAULT`].
+//!
+//! The local auth server never lets a user be cached past the time its
+//! token would stop holding (see `crate::local_auth::sessions`).
+
+use hotaru::prelude::*;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::fetch::{UserLookup, lookup_user_info};
+use super::{CACHE_VALID_TIME, HALF_VALID_TIME, Server};
+
+/// A background lookup: the time it started, and its answer once there
+type Lookup = (u64, Option<UserLookup>);
+
+/// The background lookups, by token
+static REVALIDATED: Lazy<Mutex<HashMap<String, Lookup>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// The `Cache-Control` of a `/users/me` answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CachePolicy {
+    /// Seconds the user is used as is
+    pub max_age: u64,
+    /// Seconds after `max_age` the user is still used while asked again
+    pub stale_while_revalidate: u64,
+}
+
+/// How a cached user may be used, by its age
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Freshness {
+    Fresh,
+    /// Use it, and ask the server again in the background
+    Revalidate,
+    /// Ask the server before using it
+    Expired,
+}
+
+impl CachePolicy {
+    /// Half an hour as is, half an hour more while asking again
+    pub const DEFAULT: Self = Self { max_age: HALF_VALID_TIME, stale_while_revalidate: CACHE_VALID_TIME - HALF_VALID_TIME };
+
+    /// The policy of a `Cache-Control` header; a directive left out is 0,
+    /// and no header at all is [`Self::DEFAULT`]. The header comes from
+    /// another server, so each directive is cut at [`CACHE_VALID_TIME`].
+    pub fn parse(header: Option<&str>) -> Self {
+        let Some(header) = header else { return Self::DEFAULT };
+        let mut policy = Self { max_age: 0, stale_while_revalidate: 0 };
+        for directive in header.split(',').map(str::trim) {
+            let (name, value) = directive.split_once('=').unwrap_or((directive, ""));
+            let seconds = || value.trim_matches('"').parse::<u64>().unwrap_or(0).min(CACHE_VALID_TIME);
+            match name.to_ascii_lowercase().as_str() {
+                "no-store" | "no-cache" => return Self { max_age: 0, stale_while_revalidate: 0 },
+                "max-age" => policy.max_age = seconds(),
+                "stale-while-revalidate" => policy.stale_while_revalidate = seconds(),
+                _ => {}
+            }
+        }
+        policy
+    }
+
+    /// [`Self::DEFAULT`], cut so that a user is not used past `held_for`
+    /// seconds from now
+    pub fn for_token(held_for: u64) -> Self {
+        let max_age = Self::DEFAULT.max_age.min(held_for);
+        Self { max_age, stale_while_revalidate: Self::DEFAULT.stale_while_revalidate.min(held_for - max_age) }
+    }
+
+    /// The `Cache-Control` header of the policy
+    pub fn header(&self) -> String {
+        format!("private, max-age={}, stale-while-revalidate={}", self.max_age, self.stale_while_revalidate)
+    }
+
+    /// Seconds the user may be used at all without the server
+    pub fn lifetime(&self) -> u64 {
+        self.max_age.saturating_add(self.stale_while_revalidate)
+    }
+
+    pub fn freshness(&self, age: u64) -> Freshness {
+        if age < self.max_age {
+            Freshness::Fresh
+        } else if age < self.lifetime() {
+            Freshness::Revalidate
+        } else {
+            Freshness::Expired
+        }
+    }
+}
+
+/// Ask `host` about `token` in the background, unless that is under way
+pub fn revalidate(host: Server, token: String) {
+    let now = now();
+    {
+        let mut revalidated = REVALIDATED.lock().unwrap();
+        // Answers no request of the session came back for
+        revalidated.retain(|_, (started, _)| now.saturating_sub(*started) < CACHE_VALID_TIME);
+        if revalidated.contains_key(&token) {
+            return;
+        }
+        revalidated.insert(token.clone(), (now, None));
+    }
+    tokio::spawn(async move {
+        let lookup = lookup_user_info(host, token.clone()).await;
+        if let Some(entry) = REVALIDATED.lock().unwrap().get_mut(&token) {
+            entry.1 = Some(lookup);
+        }
+    });
+}
+
+/// The answer of a finished background lookup of `token`
+pub fn take_revalidated(token: &str) -> Option<UserLookup> {
+    let mut revalidated = REVALIDATED.lock().unwrap();
+    if revalidated.get(token).is_some_and(|(_, lookup)| lookup.is_some()) {
+        return revalidated.remove(token).and_then(|(_, lookup)| lookup);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ctx::SfxCtx;
+    use crate::modules;
+    use crate::testing::{TestRequest, mock_auth_server, through};
+    use crate::user::{User, UserFetch, UserID};
+
+    #[test]
+    fn policies_come_from_cache_control() {
+        let policy = CachePolicy::parse(Some("private, max-age=60, stale-while-revalidate=240"));
+        assert_eq!(policy, CachePolicy { max_age: 60, stale_while_revalidate: 240 });
+        assert_eq!(policy.freshness(59), Freshness::Fresh);
+        assert_eq!(policy.freshness(60), Freshness::Revalidate);
+        assert_eq!(policy.freshness(300), Freshness::Expired);
+        assert_eq!(CachePolicy::parse(Some("max-age=60, no-cache")).lifetime(), 0);
+        assert_eq!(CachePolicy::parse(Some("private")).lifetime(), 0);
+        assert_eq!(CachePolicy::parse(None), CachePolicy::DEFAULT);
+        let huge = CachePolicy::parse(Some("max-age=18446744073709551615, stale-while-revalidate=1"));
+        assert_eq!((huge.max_age, huge.lifetime()), (CACHE_VALID_TIME, CACHE_VALID_TIME + 1));
+        assert_eq!(CachePolicy { max_age: u64::MAX, stale_while_revalidate: 1 }.lifetime(), u64::MAX);
+
+        // Never past the time the token stops holding
+        assert_eq!(CachePolicy::for_token(100_000), CachePolicy::DEFAULT);
+        assert_eq!(CachePolicy::for_token(2_000), CachePolicy { max_age: HALF_VALID_TIME, stale_while_revalidate: 2_000 - HALF_VALID_TIME });
+        assert_eq!(CachePolicy::for_token(600).lifetime(), 600);
+        assert_eq!(CachePolicy::parse(Some(&CachePolicy::for_token(600).header())), CachePolicy::for_token(600));
+    }
+
+    #[tokio::test]
+    async fn stale_users_are_served_while_asked_again() {
+        let auth = mock_auth_server().await;
+        let uid = auth.add_user("dora", "Dd666666");
+        let token = auth.token_for(uid);
+        let cached: Value = User::new(UserID::new(uid as usize, auth.server()), "dora (old)".into(), "d@example.com".into(), true, true)
+            .set_cached_time(Some(now() - 100))
+            .with_cache_policy(CachePolicy { max_age: 60, stale_while_revalidate: 600 })
+            .into();
+        let request = || TestRequest::get("/").remote(&auth.server(), &token).session("user_info_cache", cached.clone()).build();
+
+        let req = through(&[modules::layer::<UserFetch>()], request()).await.unwrap();
+        assert_eq!(req.signed_in().unwrap().get_username(), "dora (old)");
+        for _ in 0..50 {
+            if REVALIDATED.lock().unwrap().get(&token).is_some_and(|(_, lookup)| lookup.is_some()) {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert_eq!(auth.calls().len(), 1);
+
+        // The next request takes the answer
+        let req = through(&[modules::layer::<UserFetch>()], request()).await.unwrap();
+        let user = req.signed_in().unwrap();
+        assert_eq!((user.get_username(), user.cache_policy()), ("dora", CachePolicy::DEFAULT));
+        assert_eq!(auth.calls().len(), 1);
+    }
+}
@@ -0,0 +1,293 @@
+//! capabilities.rs
+//!
+//! What API an auth server speaks. Servers with the `local_auth` module
+//! describe theirs at `GET /.well-known/sfx-auth`:
+//!
+//! ```json
+//! {
+//!     "api_version": 1,
+//!     "min_api_version": 1,
+//!     "endpoints": { "users.me": "/users/me", "auth.refresh": "/auth/refresh", ... },
+//!     "features": ["users.lookup", "second_factor", "cache_control"]
+//! }
+//! ```
+//!
+//! Frontends read it once per host (for the `max-age` it is served with, an
+//! hour by default) and agree on the highest version both sides speak. The
+//! calls of `user::fetch` and `AuthClient` then take their paths from
+//! `endpoints`, so a server may move or leave out parts of its API; a call
+//! the server does not offer, or a server without a common version, fails
+//! with a [`CapabilityError`] naming the reason instead of a stray `404`.
+//!
+//! Servers from before the document (it answers `404`, or no JSON) are taken
+//! to speak version 1 with every endpoint at its usual path.
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use super::Server;
+use super::cache::CachePolicy;
+use super::fetch::send_http_request;
+use crate::modules;
+use crate::APP;
+
+/// Path of the document
+pub const WELL_KNOWN: &str = "/.well-known/sfx-auth";
+
+/// Newest API version this build speaks and serves
+pub const API_VERSION: u32 = 1;
+/// Oldest API version this build speaks
+pub const MIN_API_VERSION: u32 = 1;
+
+/// The endpoints of the API by name, at their version 1 paths
+pub const ENDPOINTS: &[(&str, &str)] = &[
+    ("users.me", "/users/me"),
+    ("users.lookup", "/users/lookup"),
+    ("auth.login", "/auth/login"),
+    ("auth.second_factor", "/auth/login/second_factor"),
+    ("auth.refresh", "/auth/refresh"),
+    ("auth.logout", "/auth/logout"),
+    ("auth.admin", "/auth/admin"),
+    ("admin.users", "/admin/users"),
+    ("health", "/health"),
+];
+
+/// Optional behaviour a server may advertise
+pub const FEATURES: &[&str] = &["users.lookup", "second_factor", "cache_control", "jsonapi"];
+
+/// Seconds a document is kept when the server gives no `max-age`
+const DEFAULT_TTL: u64 = 60 * 60;
+/// Seconds a server without a document is taken to have none
+const LEGACY_TTL: u64 = 10 * 60;
+
+/// A document and when it goes stale
+type Cached = (Instant, Arc<Capabilities>);
+
+/// Documents by host address
+static CACHE: Lazy<RwLock<HashMap<String, Cached>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Why a call cannot be made to a server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CapabilityError {
+    /// No API version is spoken by both sides
+    Version { host: String, min: u32, max: u32 },
+    /// The server does not offer the endpoint
+    Missing { host: String, endpoint: String },
+}
+
+impl std::fmt::Display for CapabilityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CapabilityError::Version { host, min, max } => write!(
+                f,
+                "Auth server {} speaks API versions {} to {}, this server {} to {}",
+                host, min, max, MIN_API_VERSION, API_VERSION
+            ),
+            CapabilityError::Missing { host, endpoint } => write!(f, "Auth server {} does not offer {}", host, endpoint),
+        }
+    }
+}
+
+/// The API of one auth server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    pub host: String,
+    pub api_version: u32,
+    pub min_api_version: u32,
+    /// Paths by endpoint name
+    pub endpoints: HashMap<String, String>,
+    pub features: Vec<String>,
+    /// Whether the server published a document, rather than being assumed
+    /// to speak version 1
+    pub discovered: bool,
+}
+
+impl Capabilities {
+    /// What a server without a document is taken to speak
+    pub fn legacy(host: &str) -> Self {
+        Self {
+            host: host.to_string(),
+            api_version: 1,
+            min_api_version: 1,
+            endpoints: ENDPOINTS.iter().map(|(name, path)| (name.to_string(), path.to_string())).collect(),
+            features: FEATURES.iter().map(|feature| feature.to_string()).collect(),
+            discovered: false,
+        }
+    }
+
+    /// What this server offers
+    pub fn local() -> Self {
+        let mut capabilities = Self { discovered: true, api_version: API_VERSION, min_api_version: MIN_API_VERSION, ..Self::legacy("local") };
+        if !modules::enabled(modules::ADMIN) {
+            capabilities.endpoints.remove("admin.users");
+            capabilities.features.retain(|feature| feature != "jsonapi");
+        }
+        capabilities
+    }
+
+    pub fn from_value(host: &str, value: &Value) -> Result<Self, String> {
+        let version = |key: &str| match value.get(key) {
+            Value::Numerical(number) if *number >= 1.0 && number.fract() == 0.0 => Ok(*number as u32),
+            _ => Err(format!("`{}` must be a positive integer", key)),
+        };
+        let (api_version, min_api_version) = (version("api_version")?, version("min_api_version")?);
+        if min_api_version > api_version {
+            return Err("`min_api_version` is above `api_version`".to_string());
+        }
+        let Value::Dict(endpoints) = value.get("endpoints") else {
+            return Err("`endpoints` must map names to paths".to_string());
+        };
+        let endpoints = endpoints
+            .iter()
+            .map(|(name, path)| (name.clone(), path.string()))
+            .filter(|(_, path)| path.starts_with('/'))
+            .collect();
+        let features = match value.get("features") {
+            Value::List(features) => features.iter().map(|feature| feature.string()).collect(),
+            _ => Vec::new(),
+        };
+        Ok(Self { host: host.to_string(), api_version, min_api_version, endpoints, features, discovered: true })
+    }
+
+    pub fn into_json(&self) -> Value {
+        let mut endpoints = Value::new(HashMap::<String, Value>::new());
+        for (name, path) in &self.endpoints {
+            endpoints.set(name, path.as_str());
+        }
+        object!({
+            api_version: self.api_version,
+            min_api_version: self.min_api_version,
+            endpoints: endpoints,
+            features: Value::new(self.features.iter().map(|feature| Value::from(feature.as_str())).collect::<Vec<Value>>()),
+        })
+    }
+
+    /// The highest API version both sides speak
+    pub fn version(&self) -> Result<u32, CapabilityError> {
+        let version = self.api_version.min(API_VERSION);
+        if version < self.min_api_version.max(MIN_API_VERSION) {
+            return Err(CapabilityError::Version { host: self.host.clone(), min: self.min_api_version, max: self.api_version });
+        }
+        Ok(version)
+    }
+
+    /// The path of the endpoint `name`, followed by `rest`
+    pub fn path(&self, name: &str, rest: &str) -> Result<String, CapabilityError> {
+        self.version()?;
+        match self.endpoints.get(name) {
+            Some(path) => Ok(format!("{}{}", path, rest)),
+            None => Err(CapabilityError::Missing { host: self.host.clone(), endpoint: name.to_string() }),
+        }
+    }
+
+    /// Whether the server advertises `feature`
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|offered| offered == feature)
+    }
+}
+
+/// The API of `server`, from the cache or asked for at [`WELL_KNOWN`]
+pub async fn of(server: &Server) -> Arc<Capabilities> {
+    if server.is_local() {
+        return Arc::new(Capabilities::local());
+    }
+    let address = server.get_address();
+    if let Some((until, capabilities)) = CACHE.read().unwrap().get(&address)
+        && Instant::now() < *until
+    {
+        return capabilities.clone();
+    }
+    let host = server.get_host();
+    let response = match send_http_request(address.clone(), get_request(WELL_KNOWN), HttpSafety::default()).await {
+        Ok(response) => response,
+        Err(err) => {
+            // Not kept: the call about to be made will find out for itself
+            tracing::debug!(%address, ?err, "Auth server unreachable for its API document");
+            return Arc::new(Capabilities::legacy(host));
+        }
+    };
+    let status = response.meta.start_line.status_code().as_u16();
+    let policy = CachePolicy::parse(response.meta.get_header("cache-control").as_deref());
+    let parsed = match response.body.parse_buffer(&HttpSafety::new()) {
+        HttpBody::Json(json) if status < 300 => Capabilities::from_value(host, &json).map_err(|err| {
+            tracing::warn!(%address, %err, "Auth server published an unusable API document, assuming version 1");
+        }),
+        _ => Err(()),
+    };
+    let (capabilities, ttl) = match parsed {
+        Ok(capabilities) => (capabilities, if policy.max_age > 0 { policy.max_age } else { DEFAULT_TTL }),
+        Err(()) => (Capabilities::legacy(host), LEGACY_TTL),
+    };
+    if let Err(err) = capabilities.version() {
+        tracing::error!(%err, "Auth server speaks no API version of ours");
+    }
+    let capabilities = Arc::new(capabilities);
+    CACHE.write().unwrap().insert(address, (Instant::now() + Duration::from_secs(ttl), capabilities.clone()));
+    capabilities
+}
+
+/// The path of the endpoint `name` of `server`, followed by `rest`
+pub async fn path(server: &Server, name: &str, rest: &str) -> Result<String, CapabilityError> {
+    of(server).await.path(name, rest)
+}
+
+endpoint! {
+    APP.url("/.well-known/sfx-auth"),
+
+    /// GET /.well-known/sfx-auth - The API versions, endpoints and features
+    /// of this auth server, for frontends to adapt their calls to
+    /// Response: {"api_version": 1, "min_api_version": 1, "endpoints": {"users.me": "/users/me", ...}, "features": [...]}
+    pub well_known_sfx_auth <HTTP> {
+        json_response(Capabilities::local().into_json()).add_header("Cache-Control", "public, max-age=3600")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{MockReply, mock_auth_server};
+    use crate::user::client::{AuthClient, ClientError, UserKey};
+
+    #[test]
+    fn calls_follow_the_advertised_api() {
+        let document = |json: &str| Capabilities::from_value("auth.example", &Value::from_json(json).unwrap());
+        let moved = document(r#"{"api_version": 3, "min_api_version": 1, "endpoints": {"users.me": "/api/me", "health": "status"}, "features": ["second_factor"]}"#).unwrap();
+        assert_eq!(moved.version(), Ok(1));
+        assert_eq!(moved.path("users.me", "?full=1"), Ok("/api/me?full=1".to_string()));
+        assert_eq!(
+            moved.path("health", ""),
+            Err(CapabilityError::Missing { host: "auth.example".to_string(), endpoint: "health".to_string() })
+        );
+        assert!(moved.supports("second_factor") && !moved.supports("users.lookup"));
+
+        let newer = document(r#"{"api_version": 3, "min_api_version": 2, "endpoints": {"users.me": "/users/me"}}"#).unwrap();
+        let err = newer.path("users.me", "").unwrap_err();
+        assert_eq!(err, CapabilityError::Version { host: "auth.example".to_string(), min: 2, max: 3 });
+        assert!(err.to_string().contains("speaks API versions 2 to 3"));
+        assert!(document(r#"{"api_version": 1, "min_api_version": 2, "endpoints": {}}"#).is_err());
+        assert!(document(r#"{"api_version": "1", "min_api_version": 1, "endpoints": {}}"#).is_err());
+
+        let legacy = Capabilities::legacy("old.example");
+        assert_eq!((legacy.version(), legacy.path("admin.users", "/7")), (Ok(1), Ok("/admin/users/7".to_string())));
+        let local = Capabilities::local();
+        assert_eq!(document(&local.into_json().into_json()).map(|parsed| parsed.endpoints), Ok(local.endpoints));
+    }
+
+    #[tokio::test]
+    async fn calls_a_host_does_not_offer_are_not_made() {
+        let auth = mock_auth_server().await;
+        let alice = auth.add_user("alice", "Aa333333");
+        let mut document = Capabilities::legacy("mock");
+        document.endpoints.remove("users.lookup");
+        auth.reply(WELL_KNOWN, MockReply::ok(document.into_json()));
+        let client = AuthClient::new(auth.server(), auth.token_for(alice));
+
+        let err = client.lookup_many(&[UserKey::from(1)]).await.unwrap_err();
+        assert!(matches!(err, ClientError::Unsupported(CapabilityError::Missing { .. })), "{:?}", err);
+        assert!(!client.is_admin().await);
+        assert_eq!(auth.calls(), vec!["GET /auth/admin"]);
+    }
+}
@@ -0,0 +1,240 @@
+//! client.rs
+//!
+//! `AuthClient`, a typed client for the admin API of a MainAuth server. It
+//! calls the server with the bearer token the signed-in user got from it at
+//! login, so it can only do what that user may do there: `/auth/admin` tells
+//! whether they are an admin, the `/admin/users` endpoints manage the users.
+//! Paths are those the server advertises, see [`super::capabilities`].
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+use std::collections::HashMap;
+
+use super::Server;
+use super::capabilities::{self, CapabilityError};
+use super::fetch::{get_auth_token, get_host, request_with_auth_token, send_http_request};
+use crate::admin::api::UserQuery;
+
+/// Changes to a user account; the fields left `None` are kept
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UserEdit {
+    pub username: Option<String>,
+    pub email: Option<String>,
+    pub is_active: Option<bool>,
+}
+
+impl UserEdit {
+    /// Read the edit from the form fields of `POST /admin/users/<uid>`
+    pub fn from_form(form: &UrlEncodedForm) -> Self {
+        Self {
+            username: form.get("username").cloned().filter(|value| !value.is_empty()),
+            email: form.get("email").cloned().filter(|value| !value.is_empty()),
+            is_active: form.get("is_active").map(|raw| matches!(raw.as_str(), "1" | "true" | "on" | "yes")),
+        }
+    }
+
+    fn into_form(self) -> Vec<(&'static str, String)> {
+        let mut form = Vec::new();
+        if let Some(username) = self.username {
+            form.push(("username", username));
+        }
+        if let Some(email) = self.email {
+            form.push(("email", email));
+        }
+        if let Some(is_active) = self.is_active {
+            form.push(("is_active", is_active.to_string()));
+        }
+        form
+    }
+}
+
+/// A user asked for by [`AuthClient::lookup_many`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UserKey {
+    Uid(u32),
+    Username(String),
+}
+
+impl From<u32> for UserKey {
+    fn from(uid: u32) -> Self {
+        UserKey::Uid(uid)
+    }
+}
+
+impl From<&str> for UserKey {
+    fn from(username: &str) -> Self {
+        UserKey::Username(username.to_string())
+    }
+}
+
+impl From<&UserKey> for Value {
+    fn from(key: &UserKey) -> Self {
+        match key {
+            UserKey::Uid(uid) => Value::from(*uid),
+            UserKey::Username(username) => Value::from(username.as_str()),
+        }
+    }
+}
+
+/// Why a call of an `AuthClient` failed
+#[derive(Debug, Clone)]
+pub enum ClientError {
+    /// The server could not be reached or did not answer JSON
+    Transport(String),
+    /// The server answered with `success: false`
+    Rejected { status: StatusCode, message: String },
+    /// The server does not offer the call, or speaks no API version of ours
+    Unsupported(CapabilityError),
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Transport(message) | ClientError::Rejected { message, .. } => message.fmt(f),
+            ClientError::Unsupported(err) => err.fmt(f),
+        }
+    }
+}
+
+/// A client of the admin API of one MainAuth server
+#[derive(Debug, Clone)]
+pub struct AuthClient {
+    server: Server,
+    token: String,
+}
+
+impl AuthClient {
+    pub fn new(server: Server, token: impl Into<String>) -> Self {
+        Self { server, token: token.into() }
+    }
+
+    /// The client for the MainAuth server the session is signed in to.
+    /// `None` for guests and for local accounts.
+    pub fn from_session(req: &HttpReqCtx) -> Option<Self> {
+        let server = get_host(req);
+        if server.is_local() {
+            return None;
+        }
+        get_auth_token(req).map(|token| Self::new(server, token))
+    }
+
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    /// Send `request` with the bearer token. Returns the JSON the server
+    /// answered when it reports success.
+    async fn send(&self, request: HttpRequest) -> Result<Value, ClientError> {
+        let request = request_with_auth_token(request, Some(self.token.clone()));
+        let response = send_http_request(self.server.get_address(), request, HttpSafety::default())
+            .await
+            .map_err(|err| ClientError::Transport(format!("{} is unreachable: {:?}", self.server, err)))?;
+        let status = response.meta.start_line.status_code();
+        match response.body.parse_buffer(&HttpSafety::new()) {
+            HttpBody::Json(json) if json.get("success").boolean() => Ok(json),
+            HttpBody::Json(json) => {
+                // The admin API reports failures under `message`, `/auth/*` under `error`
+                let message = match json.get("message").string() {
+                    message if message.is_empty() => json.get("error").string(),
+                    message => message,
+                };
+                Err(ClientError::Rejected { status, message })
+            }
+            _ => Err(ClientError::Transport(format!("{} answered without JSON", self.server))),
+        }
+    }
+
+    /// The path the server has the endpoint `name` at, followed by `rest`
+    async fn path(&self, name: &str, rest: &str) -> Result<String, ClientError> {
+        capabilities::path(&self.server, name, rest).await.map_err(ClientError::Unsupported)
+    }
+
+    async fn get(&self, endpoint: &str, rest: &str) -> Result<Value, ClientError> {
+        self.send(get_request(&self.path(endpoint, rest).await?)).await
+    }
+
+    async fn post(&self, endpoint: &str, rest: &str, fields: Vec<(&str, String)>) -> Result<Value, ClientError> {
+        let form: HashMap<String, String> = fields.into_iter().map(|(key, value)| (key.to_string(), value)).collect();
+        let meta = HttpMeta::new(HttpStartLine::request_post(&self.path(endpoint, rest).await?), HashMap::new());
+        self.send(HttpRequest::new(meta, HttpBody::Form(form.into()))).await
+    }
+
+    async fn post_json(&self, endpoint: &str, rest: &str, body: Value) -> Result<Value, ClientError> {
+        let mut meta = HttpMeta::new(HttpStartLine::request_post(&self.path(endpoint, rest).await?), HashMap::new());
+        meta.set_content_type(HttpContentType::ApplicationJson());
+        self.send(HttpRequest::new(meta, HttpBody::Json(body))).await
+    }
+
+    /// Whether the signed-in user is an admin of the server
+    pub async fn is_admin(&self) -> bool {
+        match self.get("auth.admin", "").await {
+            Ok(json) => json.get("is_admin").boolean(),
+            Err(err) => {
+                tracing::warn!(%err, "Remote admin check failed");
+                false
+            }
+        }
+    }
+
+    /// One page of users, in the shape of `GET /admin/users`
+    pub async fn list_users(&self, query: &UserQuery) -> Result<Value, ClientError> {
+        self.get("admin.users", &format!("?{}", query.to_query_string())).await
+    }
+
+    pub async fn get_user(&self, uid: u32) -> Result<Value, ClientError> {
+        self.get("admin.users", &format!("/{}", uid)).await
+    }
+
+    /// Create an account; `date_of_birth` is sent when not empty, for servers
+    /// with a minimum age
+    pub async fn create_user(
+        &self,
+        username: &str,
+        email: &str,
+        password: &str,
+        date_of_birth: &str,
+    ) -> Result<Value, ClientError> {
+        let mut fields = vec![("username", username.to_string()), ("email", email.to_string()), ("password", password.to_string())];
+        if !date_of_birth.is_empty() {
+            fields.push(("date_of_birth", date_of_birth.to_string()));
+        }
+        self.post("admin.users", "", fields).await
+    }
+
+    pub async fn edit_user(&self, uid: u32, edit: UserEdit) -> Result<Value, ClientError> {
+        self.post("admin.users", &format!("/{}", uid), edit.into_form()).await
+    }
+
+    /// Reset the password of `uid`, giving the server the `reason` it logs
+    pub async fn reset_password(&self, uid: u32, new_password: &str, reason: &str) -> Result<Value, ClientError> {
+        let fields = vec![("new_password", new_password.to_string()), ("reason", reason.to_string())];
+        self.post("admin.users", &format!("/{}/password", uid), fields).await
+    }
+
+    /// Delete `uid`, giving the server the `reason` it logs
+    pub async fn delete_user(&self, uid: u32, reason: &str) -> Result<Value, ClientError> {
+        self.post("admin.users", &format!("/{}/delete", uid), vec![("reason", reason.to_string())]).await
+    }
+
+    pub async fn sessions(&self, uid: u32) -> Result<Value, ClientError> {
+        self.get("admin.users", &format!("/{}/sessions", uid)).await
+    }
+
+    pub async fn revoke_sessions(&self, uid: u32) -> Result<Value, ClientError> {
+        self.post("admin.users", &format!("/{}/sessions/revoke", uid), Vec::new()).await
+    }
+
+    /// The public info of `keys`, `{uid, username, is_active}` each, in one
+    /// call to `POST /users/lookup`. Users the server does not know are left
+    /// out; more than `LOOKUP_LIMIT` keys are sent in several calls.
+    pub async fn lookup_many(&self, keys: &[UserKey]) -> Result<Vec<Value>, ClientError> {
+        let mut users = Vec::new();
+        for batch in keys.chunks(crate::local_auth::endpoints::LOOKUP_LIMIT) {
+            let body = object!({ users: Value::new(batch.iter().map(Value::from).collect::<Vec<Value>>()) });
+            if let Value::List(found) = self.post_json("users.lookup", "", body).await?.get("users") {
+                users.extend(found.iter().cloned());
+            }
+        }
+        Ok(users)
+    }
+}
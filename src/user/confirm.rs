@@ -0,0 +1,111 @@
+//! confirm.rs
+//!
+//! The page asking a signed-in user for their password again, for the
+//! actions the auth server keeps behind `require_recent_auth` (see
+//! `crate::local_auth::reauth`). A page answered `403` with `reauth: true`
+//! sends the user to `/user/confirm?next=<its path>`; once confirmed, they
+//! are taken back there to try again. The account may also confirm with a
+//! code of its second factor.
+//!
+//! The session notes when, under `"confirmed_at"`, for the checks of this
+//! frontend itself ([`confirmed_within`]).
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+
+use super::security::relay;
+use crate::ctx::SfxCtx;
+use crate::op::{self, APP};
+use crate::methods;
+
+/// Where to go once confirmed: `next` when it is a path of this site
+pub fn next_path(next: Option<String>) -> String {
+    match next {
+        Some(next) if next.starts_with('/') && !next.starts_with("//") && !next.contains('\\') => next,
+        _ => "/user/home".to_string(),
+    }
+}
+
+/// Whether the user of the session confirmed their password here within
+/// `seconds`, for the checks of this frontend like `crate::admin::sudo`
+pub fn confirmed_within(req: &HttpReqCtx, seconds: u64) -> bool {
+    let confirmed_at = req
+        .session()
+        .ok()
+        .and_then(|session| session.get("confirmed_at"))
+        .map(|at| at.integer().max(0) as u64)
+        .unwrap_or(0);
+    crate::local_auth::reauth::is_recent(confirmed_at, now(), seconds)
+}
+
+/// Whether the auth server answered a password or code it checked, as
+/// opposed to only sending a code (`{"second_factor": true}`)
+fn confirms(response: &HttpResponse) -> bool {
+    response.meta.start_line.status_code() == StatusCode::OK
+        && matches!(&response.body, HttpBody::Json(json) if json.get("authenticated_at").integer() > 0)
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+}
+
+endpoint! {
+    APP.url("/user/confirm"),
+
+    /// Confirm the password before a sensitive action
+    ///
+    /// # Request
+    /// `GET /user/confirm?next=<path>`, signed in
+    ///
+    /// `POST /user/confirm` with the JSON body of `POST /auth/reauth`
+    ///
+    /// # Response
+    /// (1) The page, or a redirect to the login page for guests
+    /// (2) The JSON answer of the auth server, with its status
+    pub confirm <HTTP> {
+        methods!(req, GET | HEAD | POST);
+        let next = next_path(req.query("next").map(|next| hotaru_lib::url_encoding::decode_url_owned(&next)));
+        if req.signed_in().is_none() {
+            if req.method() == POST {
+                return akari_json!({ success: false, error: "Not signed in" }).status(401);
+            }
+            return redirect_response(&format!("/user/login?next={}", next));
+        }
+        if req.method() == POST {
+            let response = relay(req, "/auth/reauth").await;
+            if confirms(&response)
+                && let Ok(session) = req.session_mut()
+            {
+                session.insert("confirmed_at".into(), now().into());
+            }
+            return response;
+        }
+        akari_render!(
+            "user/confirm.html",
+            pageprop = op::pageprop(req, "Confirm your password", "Confirm it is you before going on"),
+            path = op::into_path_l(req, vec!["home", "user", "confirm"]),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_paths_of_this_site_are_followed() {
+        assert_eq!(next_path(Some("/user/home/security".to_string())), "/user/home/security");
+        assert_eq!(next_path(Some("//evil.example".to_string())), "/user/home");
+        assert_eq!(next_path(Some("/\\evil.example".to_string())), "/user/home");
+        assert_eq!(next_path(Some("https://evil.example".to_string())), "/user/home");
+        assert_eq!(next_path(None), "/user/home");
+    }
+
+    #[test]
+    fn only_checked_passwords_and_codes_confirm() {
+        assert!(confirms(&akari_json!({ success: true, authenticated_at: 1700000000 })));
+        let challenge = akari_json!({ success: true, message: "Enter the code texted to your phone", second_factor: "sms", challenge: "abc" });
+        assert!(!confirms(&challenge));
+        assert!(!confirms(&akari_json!({ success: false, error: "Password mismatch" }).status(403)));
+    }
+}
@@ -1,13 +1,19 @@
 use hotaru::prelude::*;
 use hotaru::http::*;
 
-use htmstd::session::CSessionRW;
+use crate::ctx::SfxCtx;
 use std::collections::HashMap;
 
+use super::capabilities;
 use super::fetch::*;
 use super::user::*;
+use crate::captcha;
+use crate::extract::Extract;
+use crate::honeypot;
+use crate::proxy;
 use crate::op::{self, APP};
 use crate::user::Server;
+use crate::methods;
 
 endpoint! {
     APP.url("/user/login"),
@@ -23,6 +29,12 @@ endpoint! {
     /// host: The base server, use "local" to present local host 
     /// username: UserName 
     /// password: Password 
+    /// <captcha field>: The CAPTCHA response, when enabled for the `login` route 
+    /// website, form_token: The honeypot fields, when enabled for the `login` route 
+    /// challenge, code: The second step of an account with a second factor, 
+    /// sent instead of the fields above but `host` 
+    /// label: Optional name of the session, listed with its `User-Agent` among 
+    /// the sessions of the account 
     /// 
     /// # Response 
     /// (1) The HTML page for login 
@@ -30,28 +42,68 @@ endpoint! {
     /// {
     ///     success: false,
     ///     message: "Invalid response from server" // All other cases
+    ///     // or "Captcha required"/"Captcha verification failed"
+    ///     // or "Submission rejected"/"The form has expired, reload the page"
     /// } 
     /// (3) JSON 
     /// JSON response from the server 
-    /// While the auth token and the host will be added to the cookie 
+    /// While the auth token and the host will be added to the cookie; 
+    /// with `second_factor` and `challenge` the page asks for the code 
     pub login <HTTP> {
+        methods!(req, GET | HEAD | POST);
         logout(req).await; // Ensure user is logged out before login 
         if req.method() == POST {
             let form = req.form_or_default().await;
             let host = Server::from_string(&form.get_or_default("host"));
-            let username = form.get_or_default("username");
-            let password = form.get_or_default("password");
+            let username = form.get_or_default("username").clone();
+            let password = form.get_or_default("password").clone();
+            let challenge = form.get_or_default("challenge").clone();
+            let code = form.get_or_default("code").clone();
+            let label = form.get_or_default("label").clone();
+            // The challenge of the second step stands for the checks of the first
+            if challenge.is_empty() {
+                if let Err(err) = honeypot::verify_form(captcha::LOGIN, form) {
+                    return json_response(object!({
+                        success: false,
+                        message: err.to_string()
+                    }));
+                }
+                let captcha_response = captcha::response_from_form(form);
+                if let Err(err) = captcha::verify(req, captcha::LOGIN, &captcha_response).await {
+                    return json_response(object!({
+                        success: false,
+                        message: err.to_string()
+                    }));
+                }
+            }
             // println!("User login attempt: {} with password {}", username, password);
             // Send the request to the user login handler
-            let mut meta = HttpMeta::new(HttpStartLine::request_post("/auth/login"), HashMap::new());
+            let (endpoint, body) = if challenge.is_empty() {
+                ("auth.login", object!({ username: username, password: password, label: label }))
+            } else {
+                ("auth.second_factor", object!({ challenge: challenge, code: code }))
+            };
+            let path = match capabilities::path(&host, endpoint, "").await {
+                Ok(path) => path,
+                Err(err) => {
+                    tracing::error!(%err, "Cannot sign in");
+                    return json_response(object!({ success: false, message: err.to_string() }));
+                }
+            };
+            let mut meta = HttpMeta::new(HttpStartLine::request_post(&path), HashMap::new());
             meta.set_content_type(HttpContentType::ApplicationJson());
-            let request_content = HttpRequest::new(
-                meta,
-                HttpBody::Json(object!({
-                    username: username,
-                    password: password,
-                })),
-            );
+            // The auth server records where the login came from when it trusts this frontend (proxy.json)
+            if let Some(ip) = proxy::client_ip(req) {
+                meta.set_attribute("X-Forwarded-For", ip.to_string());
+            }
+            if let Some(user_agent) = req.header_str("user-agent") {
+                meta.set_attribute("User-Agent", user_agent.to_string());
+            }
+            // This server's `/auth/login` takes the checks above for its own
+            if host.is_local() && endpoint == "auth.login" {
+                meta.set_attribute(captcha::PASS_HEADER, captcha::pass());
+            }
+            let request_content = HttpRequest::new(meta, HttpBody::Json(body));
             println!("Server: {}, Address: {}", host, host.get_address());
             let response = send_http_request(&host.get_address(), request_content, HttpSafety::default())
                 .await
@@ -67,11 +119,14 @@ endpoint! {
                 message: "Invalid response from server" // All other cases
             }));
         }
+        let captcha = captcha::widget(req, captcha::LOGIN);
         akari_render!(
             "user/login.html",
             pageprop = op::pageprop(req, "User Login", "Login to your account"),
             path = op::into_path_l(req, vec!["home", "user", "login"]),
             hosts = op::get_host().clone(), // Get the list of host
+            captcha = captcha,
+            honeypot = honeypot::fields(captcha::LOGIN),
         )
     }
 }
@@ -110,9 +165,11 @@ endpoint! {
     /// This will refresh the user token and redirect to the specified URL 
     pub refresh_route <HTTP> {
         refresh_user_token(req).await;
-        let raw = req.query("redirect").unwrap_or_else(|| "/".to_string());
-        let decoded = hotaru_lib::url_encoding::decode_url_owned(&raw);
-        redirect_response(&decoded)
+        let redirect = match req.query_arg::<String>("redirect") {
+            Ok(redirect) => redirect.unwrap_or_else(|| "/".to_string()),
+            Err(res) => return res,
+        };
+        redirect_response(&redirect)
     }
 }
 
@@ -187,8 +244,8 @@ endpoint! {
     // } 
     pub get_self_cached_info <HTTP> {
         let user = req
-            .params
-            .get::<CSessionRW>()
+            .session()
+            .ok()
             .and_then(|session| session.get("user_info_cache"))
             .cloned()
             .unwrap_or(Value::None);
@@ -227,12 +284,12 @@ endpoint! {
     /// A `HttpResponse` that contains the user home page 
     /// If the user is a guest, it will redirect to the login page 
     pub home <HTTP> {
-        if req.params.get::<User>().unwrap().get_uid() == 0 {
+        if req.signed_in().is_none() {
             return redirect_response("/user/login");
         }
         let user = req
-            .params
-            .get::<CSessionRW>()
+            .session()
+            .ok()
             .and_then(|session| session.get("user_info_cache"))
             .map(|user| user.clone().into())
             .unwrap_or(User::guest(op::get_default_host()));
@@ -308,6 +365,48 @@ endpoint! {
     }
 }
 
+endpoint! {
+    APP.url("/user/home/display_name"),
+
+    /// The POST endpoint for changing the name shown to others.
+    ///
+    /// # Request
+    /// `POST /user/home/display_name`, UrlEncodedForm `{ display_name: String }`,
+    /// empty to show the username again
+    ///
+    /// # Response
+    /// `{ success: true, display_name: String }`, or `{ success: false, message: String }`
+    /// when the auth server refuses the name. The cached user of the session is
+    /// updated, so the new name shows on the next page.
+    pub change_display_name <HTTP> {
+        let Some(user) = req.signed_in().cloned() else {
+            return json_response(object!({ success: false, message: "Not signed in" })).status(StatusCode::UNAUTHORIZED);
+        };
+        let display_name = req.form_or_default().await.get_or_default("display_name");
+        let request = request_with_auth_token(
+            json_request("/users/me/display_name", object!({ display_name: display_name })),
+            get_auth_token(req),
+        );
+        let response = match send_http_request(get_host(req).get_address(), request, HttpSafety::default()).await {
+            Ok(response) => response,
+            Err(err) => {
+                tracing::warn!(?err, "Display name change failed");
+                return json_response(object!({ success: false, message: "Invalid response from server or no response" }))
+                    .status(StatusCode::BAD_GATEWAY);
+            }
+        };
+        match response.body.parse_buffer(&HttpSafety::new()) {
+            HttpBody::Json(json) if json.get("success").boolean() => {
+                let display_name = json.get("display_name").string();
+                cache_user_info(req, user.with_display_name(display_name.clone()));
+                json_response(object!({ success: true, display_name: display_name }))
+            }
+            HttpBody::Json(json) => json_response(object!({ success: false, message: json.get("error").string() })),
+            _ => json_response(object!({ success: false, message: "Invalid response from server or no response" })),
+        }
+    }
+}
+
 endpoint! {
     APP.url("/user/unauthorized"),
 
@@ -1,32 +1,43 @@
 //! fetch.rs
 //!
 //! Responsible for managing authentication tokens in the session, communicating with the
-//! remote auth/user service, and caching user info in the session store.
+//! remote auth/user service, and caching user info in the session store. The
+//! paths called are those the service advertises, see [`super::capabilities`].
 
 use hotaru::prelude::*;
 use hotaru::http::*;
-use hotaru::TcpOutbound;
 use hotaru::hotaru_http::protocol::HttpError;
-use htmstd::session::CSessionRW;
+use crate::ctx::SfxCtx;
 use super::user::*;
 use super::Server;
+use super::cache::CachePolicy;
+use super::capabilities;
 
 /// Thin wrapper around `hotaru_http::send_request` that handles the old
 /// 0.7-style `(host_url, request, safety)` shape: parses the scheme/host/port
-/// out of an `http://...` URL, builds a `TcpOutbound`, sets the `Host` header
-/// if absent, and runs one request/response. 
+/// out of an `http://...` or `https://...` URL, sets the `Host` header if
+/// absent, and runs one request/response, directly or through the proxy
+/// [`crate::outbound`] picks for the host. The time it takes is
+/// recorded against the host by [`crate::latency`].
 pub async fn send_http_request(
     host: impl Into<String>,
-    mut request: HttpRequest,
+    request: HttpRequest,
     safety: HttpSafety,
 ) -> Result<HttpResponse, HttpError> {
     let host_str = host.into();
+    let start = std::time::Instant::now();
+    let response = send_once(&host_str, request, safety).await;
+    crate::latency::record_upstream(&host_str, start.elapsed());
+    response
+}
+
+async fn send_once(host_str: &str, mut request: HttpRequest, safety: HttpSafety) -> Result<HttpResponse, HttpError> {
     let (is_https, without_scheme) = if let Some(rest) = host_str.strip_prefix("https://") {
         (true, rest.to_string())
     } else if let Some(rest) = host_str.strip_prefix("http://") {
         (false, rest.to_string())
     } else {
-        (false, host_str.clone())
+        (false, host_str.to_string())
     };
 
     let (host_part, port, explicit_port) = match without_scheme.rfind(':') {
@@ -57,17 +68,8 @@ pub async fn send_http_request(
         request.meta.set_host(Some(host_header));
     }
 
-    if is_https {
-        return Err(HttpError::Io(std::io::Error::new(
-            std::io::ErrorKind::Unsupported,
-            "HTTPS outbound is not enabled in this build (requires the hotaru `https` feature)",
-        )));
-    }
-
-    let outbound = TcpOutbound::build(format!("{}:{}", host_part, port))
-        .await
-        .map_err(HttpError::Io)?;
-    send_request(&outbound, request, safety).await
+    let route = crate::outbound::settings().route(is_https, &host_part, port);
+    crate::outbound::send(is_https, &host_part, port, &route, request, safety).await
 }
 
 /// Store the given authentication token in the HTTP-session under `"auth_token"`.
@@ -78,10 +80,12 @@ pub async fn send_http_request(
 /// * `token` – the raw JWT or bearer token string to persist
 pub fn set_auth_token(req: &mut HttpReqCtx, token: &str) {
     tracing::info!(%token, "Setting auth token in session");
-    req.params
-        .get_mut::<CSessionRW>()
-        .unwrap()
-        .insert("auth_token".into(), token.into());
+    match req.session_mut() {
+        Ok(session) => {
+            session.insert("auth_token".into(), token.into());
+        }
+        Err(err) => tracing::error!(%err, "Failed to keep the auth token"),
+    }
 }
 
 /// Retrieve the authentication token from the current HTTP-session, if present.
@@ -92,12 +96,37 @@ pub fn set_auth_token(req: &mut HttpReqCtx, token: &str) {
 ///
 /// * `req` – shared reference to the current request context
 pub fn get_auth_token(req: &HttpReqCtx) -> Option<String> {
-    req.params
-        .get::<CSessionRW>()
+    req.session()
+        .ok()
         .and_then(|session| session.get("auth_token"))
         .map(|token| token.string())
 } 
 
+/// Whether the session was left idle past `idle_minutes` of
+/// `crate::local_auth::sessions`; otherwise note it as seen now, under
+/// `"last_seen"`, at most once a minute.
+pub fn idled_out(req: &mut HttpReqCtx) -> bool {
+    let idle = crate::local_auth::sessions::settings().idle_minutes * 60;
+    if idle == 0 {
+        return false;
+    }
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+    let Ok(session) = req.session_mut() else {
+        return false;
+    };
+    let last_seen = match session.get("last_seen") {
+        Some(Value::Numerical(seen)) => *seen as u64,
+        _ => 0,
+    };
+    if last_seen != 0 && now >= last_seen + idle {
+        return true;
+    }
+    if now >= last_seen + 60 {
+        session.insert("last_seen".into(), now.into());
+    }
+    false
+}
+
 /// Store the given authentication token in the HTTP-session under `"auth_token"`.
 ///
 /// # Arguments
@@ -106,10 +135,12 @@ pub fn get_auth_token(req: &HttpReqCtx) -> Option<String> {
 /// * `host` – the host 
 pub fn set_host(req: &mut HttpReqCtx, host: &str) {
     tracing::info!(%host, "Setting host in session");
-    req.params
-        .get_mut::<CSessionRW>()
-        .unwrap()
-        .insert("host".into(), host.into());
+    match req.session_mut() {
+        Ok(session) => {
+            session.insert("host".into(), host.into());
+        }
+        Err(err) => tracing::error!(%err, "Failed to keep the host"),
+    }
 }
 
 /// Retrieve the authentication token from the current HTTP-session, if present. 
@@ -120,61 +151,84 @@ pub fn set_host(req: &mut HttpReqCtx, host: &str) {
 ///
 /// * `req` – shared reference to the current request context 
 pub fn get_host(req: &HttpReqCtx) -> Server { 
-    req.params
-        .get::<CSessionRW>()
-        .and_then(|session| session.get("host"))
-        .map(|token| token.string()) 
-        .map(|s| Server::from_string(&s))
-        .unwrap_or(Server::Local) 
+    req.host()
+}
+
+/// What the auth server said of a token
+#[derive(Debug, Clone)]
+pub enum UserLookup {
+    /// The user the token belongs to
+    Found(User),
+    /// The server answered and refused the token
+    Rejected,
+    /// No answer: unreachable, a `5xx`, a body that is not JSON, or its
+    /// circuit is open (see [`super::breaker`])
+    Unreachable,
 }
 
 /// Perform an authenticated GET on `/users/me` to fetch the remote user’s details,
 /// then deserialize into our local `User` type.
 ///
 /// Returns `Some(User)` on success, or `None` if the server returned a non-JSON body
-/// or an error.
+/// or an error. [`lookup_user_info`] tells the two apart.
 ///
 /// # Arguments
 ///
 /// * `host` - the host 
 /// * `auth` – the bearer token to include in the request
 pub async fn fetch_user_info(host: Server, auth: String) -> Option<User> {
-    println!("fetch_user_info: sending request to {}, token: {}", host.get_address(), auth);
-    let request = request_with_auth_token(get_request("/users/me"), Some(auth));
-    let response = send_http_request(
-        host.get_address(),
-        request,
-        HttpSafety::default(),
-    )
-    .await;
-
-    if response.is_err() {
-        println!("fetch_user_info: request failed: {:?}", response);
-        return None;
+    match lookup_user_info(host, auth).await {
+        UserLookup::Found(user) => Some(user),
+        UserLookup::Rejected | UserLookup::Unreachable => None,
     }
+}
 
-    let response = response.unwrap();
-    println!("fetch_user_info: response body type: {:?}", std::mem::discriminant(&response.body));
-
-    // Try to parse the body as JSON if it's a buffer
-    let body = response.body.parse_buffer(&HttpSafety::new());
-    println!("fetch_user_info: parsed body: {:?}", body);
-
-    if let HttpBody::Json(json) = body {
-        if json.get("success").boolean() {
-            // The JSON is assumed to be of the form { "success": true, "user": { ... } }
-            let mut user_value = json.get("user").clone();
-            user_value.set("server", host.clone());
-            println!("fetch_user_info: returning user: {:?}", user_value);
-            Some(user_value.into())
-        } else {
-            println!("fetch_user_info: success=false in response");
-            None
+/// Ask `host` at `/users/me` whom `auth` belongs to, through the circuit
+/// breaker of the host. A host that does not offer the call counts as
+/// unreachable.
+pub async fn lookup_user_info(host: Server, auth: String) -> UserLookup {
+    let address = host.get_address();
+    if !super::breaker::allow(&address) {
+        tracing::debug!(%address, "Circuit open, not asking the auth server");
+        return UserLookup::Unreachable;
+    }
+    let path = match capabilities::path(&host, "users.me", "").await {
+        Ok(path) => path,
+        Err(err) => {
+            tracing::error!(%err, "Cannot look up users");
+            return UserLookup::Unreachable;
         }
-    } else {
-        println!("fetch_user_info: unexpected response body: {:?}", body);
-        None
+    };
+    let request = request_with_auth_token(get_request(&path), Some(auth));
+    let response = match send_http_request(address.clone(), request, HttpSafety::default()).await {
+        Ok(response) => response,
+        Err(err) => {
+            tracing::warn!(%address, ?err, "Auth server unreachable");
+            super::breaker::failure(&address);
+            return UserLookup::Unreachable;
+        }
+    };
+    let status = response.meta.start_line.status_code().as_u16();
+    let cache_policy = CachePolicy::parse(response.meta.get_header("cache-control").as_deref());
+    let body = response.body.parse_buffer(&HttpSafety::new());
+    let HttpBody::Json(json) = body else {
+        tracing::warn!(%address, status, "Auth server answered /users/me without JSON");
+        super::breaker::failure(&address);
+        return UserLookup::Unreachable;
+    };
+    if status >= 500 {
+        tracing::warn!(%address, status, "Auth server failed on /users/me");
+        super::breaker::failure(&address);
+        return UserLookup::Unreachable;
+    }
+    super::breaker::success(&address);
+    if !json.get("success").boolean() {
+        return UserLookup::Rejected;
     }
+    // The JSON is assumed to be of the form { "success": true, "user": { ... } }
+    let mut user_value = json.get("user").clone();
+    user_value.set("server", host.clone());
+    UserLookup::Found(User::from(user_value).with_cache_policy(cache_policy))
 }
 
 /// Refresh the stored token by calling `/auth/refresh`.  If no token is in-session,
@@ -224,7 +278,8 @@ pub async fn refresh_user_token(req: &mut HttpReqCtx) -> Value {
 /// * `token` – the bearer token to refresh
 async fn get_new_token(host: Server, token: String) -> Result<String, Value> {
     tracing::info!(%token, "Requesting new token from auth server");
-    let request = get_request("/auth/refresh")
+    let path = capabilities::path(&host, "auth.refresh", "").await.map_err(unsupported)?;
+    let request = get_request(&path)
         .add_header("Authorization", format!("Bearer {}", token));
     let response = send_http_request(
         host.get_address(), 
@@ -256,18 +311,27 @@ async fn get_new_token(host: Server, token: String) -> Result<String, Value> {
 /// * `user` – the fully populated `User` object to store
 pub fn cache_user_info(req: &mut HttpReqCtx, user: User) {
     tracing::info!(user = ?user, "Caching user info in session");
-    req.params
-        .get_mut::<CSessionRW>()
-        .unwrap()
-        .insert("user_info_cache".into(), user.into());
+    match req.session_mut() {
+        Ok(session) => {
+            session.insert("user_info_cache".into(), user.into());
+        }
+        Err(err) => tracing::error!(%err, "Failed to cache the user info"),
+    }
 }
 
 /// Check the health endpoint (`/health`) of the auth server. Returns `true` if
 /// the JSON `{ "status": "ok" }` is returned, else `false`.
 pub async fn auth_server_health(host: Server) -> bool {
+    let path = match capabilities::path(&host, "health", "").await {
+        Ok(path) => path,
+        Err(err) => {
+            tracing::warn!(%err, "Cannot check the auth server");
+            return false;
+        }
+    };
     let response = send_http_request(
         host.get_address(),
-        get_request("/health"),
+        get_request(&path),
         HttpSafety::default(),
     )
     .await
@@ -288,10 +352,12 @@ pub async fn auth_server_health(host: Server) -> bool {
 /// * `req` – mutable reference to the current request context
 pub async fn logout(req: &mut HttpReqCtx) -> HttpResponse {
     tracing::info!("Clearing session and redirecting to login-refresh");
-    let params = req.params.get_mut::<CSessionRW>().unwrap();
-    params.remove("user_info_cache");
-    params.remove("auth_token");
-    params.remove("host"); 
+    if let Ok(params) = req.session_mut() {
+        params.remove("user_info_cache");
+        params.remove("auth_token");
+        params.remove("host");
+        params.remove("last_seen");
+    }
     redirect_response("/user/refresh?redirect=/user/login")
 }
 
@@ -323,7 +389,11 @@ pub fn redirect_refresh(req: &mut HttpReqCtx) {
 /// * `host` - the host 
 /// * `token` – the bearer token to revoke
 pub async fn disable_token(host: Server, token: String) -> Value {
-    let request = get_request("/auth/logout")
+    let path = match capabilities::path(&host, "auth.logout", "").await {
+        Ok(path) => path,
+        Err(err) => return unsupported(err),
+    };
+    let request = get_request(&path)
         .add_header("Authorization", format!("Bearer {}", token));
     let response = send_http_request(
         host.get_address(),
@@ -343,6 +413,15 @@ pub async fn disable_token(host: Server, token: String) -> Value {
     }
 }
 
+/// The error object of a call `host` does not offer
+fn unsupported(err: capabilities::CapabilityError) -> Value {
+    tracing::error!(%err, "Auth server call not offered");
+    object!({
+        success: false,
+        message: err.to_string()
+    })
+}
+
 /// Add an `Authorization: Bearer <token>` header if `token.is_some()`.
 ///
 /// # Arguments
@@ -358,10 +437,7 @@ pub fn request_with_auth_token(mut req: HttpRequest, token: Option<String>) -> H
 
 /// Convenience: pull the current `User` from `req.params` or fall back to `guest`.
 pub async fn get_user(req: &mut HttpReqCtx) -> User {
-    req.params
-        .get::<User>()
-        .map(|u| u.clone())
-        .unwrap_or_else(|| User::guest(get_host(req)))
+    req.user_or_guest()
 } 
 
 /// Convenience: pull the current `User` from `req.params` or fall back to `guest`. 
@@ -0,0 +1,195 @@
+//! logout.rs
+//!
+//! Single logout across the frontends sharing one auth server. Frontends
+//! cache the signed-in user in the session for up to an hour, so revoking
+//! tokens on the auth server alone leaves them showing the user as logged
+//! in. Configured in `programfiles/op/logout.json`:
+//!
+//! ```json
+//! {
+//!     "everywhere": true,
+//!     "notify": ["https://shop.example.com"],
+//!     "secret": "shared-secret",
+//!     "hosts": { "auth.example.com": "shared-secret" }
+//! }
+//! ```
+//!
+//! On the auth server, `everywhere` makes a logout end every session of the
+//! user instead of only the one logging out, and each frontend of `notify`
+//! is then sent `POST /user/logout_notify` with the `secret` as bearer
+//! token. On a frontend, `hosts` holds the secret each MainAuth host sends;
+//! a notification drops the cached sessions of the user made before it.
+//! Frontends using the `local` host are told in-process. Secrets may be
+//! references such as `env:SFX_LOGOUT_SECRET`, see `crate::secrets`.
+
+use hotaru::prelude::*;
+use hotaru::http::*;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use super::fetch::send_http_request;
+use super::{Server, User};
+use crate::APP;
+use crate::methods;
+
+static LOGOUT: Lazy<LogoutSettings> = Lazy::new(|| {
+    let path = crate::op::programfiles().join("op/logout.json");
+    let mut settings = LogoutSettings::from_value(&Value::from_jsonf(path.to_str().unwrap()).unwrap_or(Value::None));
+    settings.secret = crate::secrets::load(&settings.secret, "logout.json secret");
+    for (host, secret) in settings.hosts.iter_mut() {
+        *secret = crate::secrets::load(secret, &format!("logout.json secret of {}", host));
+    }
+    // A secret that failed to load must not match an empty bearer token
+    settings.hosts.retain(|_, secret| !secret.is_empty());
+    settings
+});
+
+/// When each user was last logged out everywhere, by host and uid
+static LOGGED_OUT: Lazy<RwLock<HashMap<(String, usize), u64>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// The parsed content of `logout.json`
+#[derive(Debug, Clone, Default)]
+pub struct LogoutSettings {
+    pub everywhere: bool,
+    pub notify: Vec<String>,
+    pub secret: String,
+    pub hosts: HashMap<String, String>,
+}
+
+impl LogoutSettings {
+    pub fn from_value(value: &Value) -> Self {
+        let notify = match value.get("notify") {
+            Value::List(list) => list.iter().map(|url| url.string()).filter(|url| !url.is_empty()).collect(),
+            _ => Vec::new(),
+        };
+        let hosts = match value.get("hosts") {
+            Value::Dict(hosts) => hosts
+                .iter()
+                .map(|(host, secret)| (host.clone(), secret.string()))
+                .filter(|(_, secret)| !secret.is_empty())
+                .collect(),
+            _ => HashMap::new(),
+        };
+        Self {
+            everywhere: value.get("everywhere").boolean(),
+            notify,
+            secret: value.get("secret").string(),
+            hosts,
+        }
+    }
+
+    /// The MainAuth host sending notifications signed with `secret`
+    pub fn host_of(&self, secret: &str) -> Option<&str> {
+        self.hosts
+            .iter()
+            .find(|(_, expected)| constant_time_eq(expected.as_bytes(), secret.as_bytes()))
+            .map(|(host, _)| host.as_str())
+    }
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// The loaded logout settings
+pub fn settings() -> &'static LogoutSettings {
+    &LOGOUT
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Remember that every session of `uid` on `server` ended now
+pub fn record(server: &Server, uid: usize) {
+    LOGGED_OUT.write().unwrap().insert((server.to_string(), uid), now());
+}
+
+/// Whether `user` was cached before they were logged out everywhere
+pub fn is_stale(user: &User) -> bool {
+    let key = (user.get_server().to_string(), user.get_uid());
+    LOGGED_OUT
+        .read()
+        .unwrap()
+        .get(&key)
+        .is_some_and(|&logged_out| user.cached_at() <= logged_out)
+}
+
+/// Every session of the local account `uid` ended: drop them from the
+/// caches of this server and tell the frontends of `notify`
+pub fn sessions_ended(uid: u32) {
+    record(&Server::Local, uid as usize);
+    for frontend in &LOGOUT.notify {
+        let frontend = frontend.clone();
+        tokio::spawn(async move {
+            let mut meta = HttpMeta::new(HttpStartLine::request_post("/user/logout_notify"), HashMap::new());
+            meta.set_content_type(HttpContentType::ApplicationJson());
+            let request = HttpRequest::new(meta, HttpBody::Json(object!({ uid: uid })))
+                .add_header("Authorization", format!("Bearer {}", LOGOUT.secret));
+            match send_http_request(frontend.clone(), request, HttpSafety::default()).await {
+                Ok(_) => tracing::info!(%frontend, uid, "Frontend notified of logout"),
+                Err(err) => tracing::warn!(%frontend, uid, ?err, "Logout notification failed"),
+            }
+        });
+    }
+}
+
+endpoint! {
+    APP.url("/user/logout_notify"),
+
+    /// POST /user/logout_notify - Sent by a MainAuth server when every session of a user ended
+    /// Request header: the bearer secret of the host in `logout.json`
+    /// Request body: Json -> {"uid": 12}
+    /// Response (1): {"success": false, "message": "Unauthorized"/"Invalid uid"}
+    /// Response (2): {"success": true}
+    pub logout_notify <HTTP> {
+        methods!(req, POST);
+        let secret = crate::local_auth::analyze::get_auth_token(req).unwrap_or_default();
+        let Some(host) = LOGOUT.host_of(&secret) else {
+            return json_response(object!({ success: false, message: "Unauthorized" }))
+                .status(StatusCode::UNAUTHORIZED);
+        };
+        let host = Server::from_string(host);
+        let uid = req.json_or_default().await.get("uid").integer();
+        if uid <= 0 {
+            return json_response(object!({ success: false, message: "Invalid uid" }))
+                .status(StatusCode::BAD_REQUEST);
+        }
+        tracing::info!(%host, uid, "Logged out everywhere by the auth server");
+        record(&host, uid as usize);
+        json_response(object!({ success: true }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notifications_are_matched_to_their_host() {
+        let settings = LogoutSettings::from_value(&Value::from_json(r#"{
+            "everywhere": true,
+            "notify": ["https://shop.example.com"],
+            "hosts": { "auth.example.com": "s3cret", "empty.example.com": "" }
+        }"#).unwrap());
+        assert!(settings.everywhere);
+        assert_eq!(settings.notify, vec!["https://shop.example.com".to_string()]);
+        assert_eq!(settings.host_of("s3cret"), Some("auth.example.com"));
+        assert_eq!(settings.host_of("s3cre"), None);
+        assert_eq!(settings.host_of(""), None);
+
+        let settings = LogoutSettings::from_value(&Value::None);
+        assert!(!settings.everywhere && settings.notify.is_empty());
+    }
+
+    #[test]
+    fn sessions_cached_before_a_logout_are_stale() {
+        let server = Server::from_string("stale.example.com");
+        let user = User::new(crate::user::UserID::new(7, server.clone()), "u".into(), "e".into(), true, true);
+        assert!(!is_stale(&user));
+        record(&server, 7);
+        assert!(is_stale(&user));
+        let later = user.clone().set_cached_time(Some(now() + 1));
+        assert!(!is_stale(&later));
+    }
+}
@@ -1,10 +1,11 @@
 use hotaru::prelude::*; 
 use hotaru::http::*; 
-use htmstd::session::CSessionRW;
+use crate::ctx::SfxCtx;
 
+use super::breaker;
+use super::cache::{self, Freshness};
 use super::fetch::*; 
 use super::user::*; 
-use super::{HALF_VALID_TIME, CACHE_VALID_TIME}; 
 
 middleware! {
     /// Middleware to fetch and cache user information based on auth token in session. 
@@ -17,74 +18,96 @@ middleware! {
             return next(req).await;
         } 
         let auth_token = auth_token.unwrap(); 
+        if idled_out(&mut req) {
+            logout(&mut req).await;
+            req.params.set::<User>(User::guest(host));
+            return next(req).await;
+        }
         // println!("Cached: {:?}", req
         //     .params
         //     .get_mut::<CSessionRW>()
         //     .unwrap()
         //     .get("user_info_cache")); 
-        let user = match req
-            .params
-            .get_mut::<CSessionRW>()
-            .unwrap()
-            .get("user_info_cache")
-        { 
-            Some(user) => user.clone().into(), 
-            None => match fetch_user_info(host.clone(), auth_token.clone()).await {
-                Some(user) => {
+        let cached = req.session().ok().and_then(|session| session.get("user_info_cache")).cloned();
+        let user: User = match cached { 
+            Some(user) => user.into(), 
+            None => match lookup_user_info(host.clone(), auth_token.clone()).await {
+                UserLookup::Found(user) => {
                     cache_user_info(&mut req, user.clone());
                     user
                 }
-                None => {
+                UserLookup::Rejected => {
                     logout(&mut req).await;
                     req.params.set::<User>(User::guest(host.clone()));
                     cache_user_info(&mut req, User::guest(host));
                     return next(req).await
                 }
+                UserLookup::Unreachable => {
+                    // Nothing cached to fall back on; keep the session for
+                    // when the server is back
+                    req.params.set::<User>(User::guest(host));
+                    return next(req).await
+                }
             },
         }; 
-        println!("User info: {:?}, Cached at: {}", user, user.cache_age()); 
-        match user.cache_age() {
-            0..HALF_VALID_TIME => {
+        if super::logout::is_stale(&user) {
+            // Logged out everywhere since this session cached the user
+            logout(&mut req).await;
+            req.params.set::<User>(User::guest(host));
+            return next(req).await;
+        }
+        // The answer of a lookup an earlier request started
+        match cache::take_revalidated(&auth_token) {
+            Some(UserLookup::Found(new_user)) => {
+                req.params.set::<User>(new_user.clone());
+                cache_user_info(&mut req, new_user);
+                return next(req).await;
+            }
+            Some(UserLookup::Rejected) => {
+                logout(&mut req).await;
+                req.params.set::<User>(User::guest(host));
+                return next(req).await;
+            }
+            Some(UserLookup::Unreachable) | None => {}
+        }
+        let age = user.cache_age();
+        let policy = user.cache_policy();
+        match policy.freshness(age) {
+            Freshness::Fresh => {
                 req.params.set::<User>(user);
                 return next(req).await;
             }
-            HALF_VALID_TIME..=CACHE_VALID_TIME => {
-                // Cache is half-valid: serve it, refresh in background.
+            Freshness::Revalidate => {
+                cache::revalidate(host, auth_token);
                 req.params.set::<User>(user);
-                match fetch_user_info(host.clone(), auth_token.clone()).await {
-                    Some(new_user) => {
-                        cache_user_info(&mut req, new_user);
-                        return next(req).await;
-                    }
-                    None => {
-                        // The stored token no longer validates (server restart,
-                        // manual revocation, TTL eviction, etc.). Redirecting to
-                        // /user/refresh would loop because /auth/refresh hits the
-                        // same failing token. Drop the session and continue as
-                        // guest so the handler can decide what to do.
-                        logout(&mut req).await;
-                        req.params.set::<User>(User::guest(host));
-                        return next(req).await;
-                    }
-                }
+                return next(req).await;
             }
-            _ => {
-                // Cache expired entirely.
-                match fetch_user_info(host.clone(), auth_token.clone()).await {
-                    Some(new_user) => {
-                        req.params.set::<User>(new_user.clone());
-                        cache_user_info(&mut req, new_user);
-                        return next(req).await;
-                    }
-                    None => {
-                        // Same as the half-valid case: token is dead; clear it
-                        // so the next request doesn't reload the loop.
-                        logout(&mut req).await;
-                        req.params.set::<User>(User::guest(host));
-                        return next(req).await;
-                    }
-                }
+            Freshness::Expired => {}
+        }
+        // Expired: ask the server before going on
+        match lookup_user_info(host.clone(), auth_token.clone()).await {
+            UserLookup::Found(new_user) => {
+                req.params.set::<User>(new_user.clone());
+                cache_user_info(&mut req, new_user);
+            }
+            UserLookup::Rejected => {
+                // The stored token no longer validates (server restart,
+                // manual revocation, TTL eviction, etc.). Redirecting to
+                // /user/refresh would loop because /auth/refresh hits the
+                // same failing token. Drop the session and continue as
+                // guest so the handler can decide what to do.
+                logout(&mut req).await;
+                req.params.set::<User>(User::guest(host));
+            }
+            UserLookup::Unreachable if age <= policy.lifetime().saturating_add(breaker::settings().grace_secs) => {
+                // Degraded: the cache is past its validity, but the server
+                // cannot say otherwise
+                req.params.set::<User>(user.mark_stale());
+            }
+            UserLookup::Unreachable => {
+                req.params.set::<User>(User::guest(host));
             }
         }
+        next(req).await
     }
 }
@@ -0,0 +1,124 @@
+//! security.rs
+//!
+//! The security page of the user center, `/user/home/security`: the second
+//! factors with the phone number and backup codes, the signed-in sessions
+//! and the last login attempts, with their buttons. It works the same for
+//! accounts of the local server and of a MainAuth server, the page calling
+//! `/user/home/security/<api>`, which passes the call on to the auth server
+//! of the session with its token.
+//!
+//! Only the APIs of [`API`] are passed on. An auth server without one
+//! answers an error, and the page leaves that part out.
+
+use hotaru::http::*;
+use hotaru::prelude::*;
+use std::collections::HashMap;
+
+use super::fetch::*;
+use crate::ctx::SfxCtx;
+use crate::op::{self, APP};
+use crate::methods;
+
+/// The names the page calls, with the path on the auth server
+pub const API: [(&str, &str); 6] = [
+    ("two_factor", "/users/me/two_factor"),
+    ("backup_codes", "/users/me/two_factor/backup_codes"),
+    ("phone", "/users/me/phone"),
+    ("phone_verify", "/users/me/phone/verify"),
+    ("sessions", "/users/me/sessions"),
+    ("activity", "/users/me/activity"),
+];
+
+/// The path on the auth server of the API `name`
+pub fn api_path(name: &str) -> Option<&'static str> {
+    API.iter().find(|(api, _)| *api == name).map(|(_, path)| *path)
+}
+
+endpoint! {
+    APP.url("/user/home/security"),
+
+    /// The security page of the user center
+    ///
+    /// # Request
+    /// `GET /user/home/security`, signed in
+    ///
+    /// # Response
+    /// The page, or a redirect to the login page for guests
+    pub security <HTTP> {
+        let Some(user) = req.signed_in().cloned() else {
+            return redirect_response("/user/login?next=/user/home/security");
+        };
+        akari_render!(
+            "user/security.html",
+            pageprop = op::pageprop(req, "Security", "Second factors, sessions and recent logins"),
+            path = op::into_path_l(req, vec!["home", "user", "home", "security"]),
+            user = user,
+            host = get_host(req).to_string(),
+        )
+    }
+}
+
+/// Pass `req` on to `path` of the auth server of the session, with its
+/// token and JSON body; the answer keeps its status. Used for the calls of
+/// the security page and of `super::confirm`.
+pub async fn relay(req: &mut HttpReqCtx, path: &str) -> HttpResponse {
+    methods!(req, GET | POST | DELETE; error);
+    let method = req.method();
+    let is_get = method == GET;
+    let mut meta = HttpMeta::new(HttpStartLine::new_request(HttpVersion::Http11, method, path.to_string()), HashMap::new());
+    let body = if is_get {
+        HttpBody::Empty
+    } else {
+        meta.set_content_type(HttpContentType::ApplicationJson());
+        HttpBody::Json(req.json_or_default().await.clone())
+    };
+    let request = request_with_auth_token(HttpRequest::new(meta, body), get_auth_token(req));
+    let response = match send_http_request(get_host(req).get_address(), request, HttpSafety::default()).await {
+        Ok(response) => response,
+        Err(err) => {
+            tracing::warn!(%path, ?err, "Relayed call to the auth server failed");
+            return akari_json!({ success: false, error: "Invalid response from server or no response" }).status(502);
+        }
+    };
+    let status = response.meta.start_line.status_code();
+    match response.body.parse_buffer(&HttpSafety::new()) {
+        HttpBody::Json(json) => json_response(json).status(status),
+        _ => akari_json!({ success: false, error: "Invalid response from server or no response" }).status(502),
+    }
+}
+
+endpoint! {
+    APP.url("/user/home/security/<api>"),
+
+    /// Pass a call of the security page on to the auth server of the session
+    ///
+    /// # Request
+    /// `GET`, `POST` or `DELETE /user/home/security/<api>`, `<api>` a name of
+    /// [`API`], with the JSON body of the API
+    ///
+    /// # Response
+    /// The JSON answer of the auth server, with its status; `404` for other
+    /// names, `401` for guests, `502` when the auth server is unreachable
+    pub security_api <HTTP> {
+        let Some(path) = req.param("api").and_then(|api| api_path(&api)) else {
+            return akari_json!({ success: false, error: "Not found" }).status(404);
+        };
+        if req.signed_in().is_none() {
+            return akari_json!({ success: false, error: "Not signed in" }).status(401);
+        }
+        relay(req, path).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_security_apis_are_passed_on() {
+        assert_eq!(api_path("phone_verify"), Some("/users/me/phone/verify"));
+        assert_eq!(api_path("password"), None);
+        assert_eq!(api_path("../admin"), None);
+        assert!(API.iter().all(|(_, path)| path.starts_with("/users/me/")));
+    }
+}
@@ -38,13 +38,31 @@ impl Server {
     } 
 
     /// Get the actual address of the server. 
+    /// 
+    /// For `Server::Local` this is the plain-HTTP binding used for server-to-server calls, 
+    /// see `get_public_address` for the address browsers use. 
+    /// Other servers are reached over HTTPS unless their host names a scheme. 
     pub fn get_address(&self) -> String { 
         if self.is_local() { 
             format!("http://{}", crate::op::APP.binding)
+        } else if self.get_host().contains("://") {
+            self.get_host().to_string()
         } else {
             format!("https://{}", self.get_host())
         } 
     }
+
+    /// Get the address clients reach the server on. 
+    /// 
+    /// For `Server::Local` the scheme and host come from the request, honoring the forwarded 
+    /// headers of trusted proxies (see `crate::proxy`). 
+    pub fn get_public_address(&self, req: &hotaru::http::HttpReqCtx) -> String { 
+        if self.is_local() { 
+            crate::proxy::public_origin(req)
+        } else {
+            self.get_address()
+        } 
+    }
 }
 
 impl std::fmt::Display for Server {
@@ -5,6 +5,7 @@
 
 use hotaru::{object, Value}; 
 use super::Server; 
+use super::cache::CachePolicy;
 
 /// Represents an authenticated user with metadata and a timestamp
 /// for when the data was cached locally.
@@ -13,12 +14,23 @@ pub struct User {
     /// Unique identifier and server origin
     pub id: UserID,
     username: String,
+    /// The name shown to others, empty for the username
+    display_name: String,
     email: String,
     is_active: bool,
     is_verified: bool,
+    /// URL of the avatar the user uploaded, see `user::avatar`
+    avatar: Option<String>,
+    /// The language the user chose, kept by the auth server
+    lang: Option<String>,
 
     /// Instant at which this struct was created or last updated
     cached_at: u64,
+    /// How long it may be cached, as the auth server said
+    cache_policy: CachePolicy,
+
+    /// Served from an expired cache while the auth server is unreachable
+    stale: bool,
 }
 
 impl User {
@@ -33,14 +45,19 @@ impl User {
         Self {
             id,
             username,
+            display_name: String::new(),
             email,
             is_active,
             is_verified,
+            avatar: None,
+            lang: None,
 
             cached_at: std::time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap_or_default()
                 .as_secs(),
+            cache_policy: CachePolicy::DEFAULT,
+            stale: false,
         }
     }
 
@@ -64,6 +81,18 @@ impl User {
         &self.username
     }
 
+    /// Return the name shown to others: the display name, or the username
+    /// when none was chosen. Logins and @mentions keep the username.
+    pub fn get_display_name(&self) -> &str {
+        if self.display_name.is_empty() { &self.username } else { &self.display_name }
+    }
+
+    /// Set the display name; empty falls back to the username.
+    pub fn with_display_name(mut self, display_name: impl Into<String>) -> Self {
+        self.display_name = display_name.into();
+        self
+    }
+
     /// Return the email address.
     pub fn get_email(&self) -> &str {
         &self.email
@@ -79,6 +108,58 @@ impl User {
         self.is_verified
     }
 
+    /// Return the URL of the uploaded avatar, if any. Pages show
+    /// `user::avatar::of_user`, which falls back to an avatar service.
+    pub fn get_avatar(&self) -> Option<&str> {
+        self.avatar.as_deref()
+    }
+
+    /// Set the URL of the uploaded avatar.
+    pub fn with_avatar(mut self, avatar: Option<String>) -> Self {
+        self.avatar = avatar.filter(|url| !url.is_empty());
+        self
+    }
+
+    /// Return the language the user chose on any device, if any. `op::lang`
+    /// prefers it to the cookie and the browser.
+    pub fn get_lang(&self) -> Option<&str> {
+        self.lang.as_deref()
+    }
+
+    /// Set the language the user chose.
+    pub fn with_lang(mut self, lang: Option<String>) -> Self {
+        self.lang = lang.filter(|lang| !lang.is_empty());
+        self
+    }
+
+    /// Return the unix time the data was cached at.
+    pub fn cached_at(&self) -> u64 {
+        self.cached_at
+    }
+
+    /// Return how long the user may be cached.
+    pub fn cache_policy(&self) -> CachePolicy {
+        self.cache_policy
+    }
+
+    /// Set how long the user may be cached.
+    pub fn with_cache_policy(mut self, cache_policy: CachePolicy) -> Self {
+        self.cache_policy = cache_policy;
+        self
+    }
+
+    /// `true` if the user comes from an expired cache, kept because the
+    /// auth server could not be asked (see `user::breaker`).
+    pub fn is_stale(&self) -> bool {
+        self.stale
+    }
+
+    /// Flag the user as served from an expired cache.
+    pub fn mark_stale(mut self) -> Self {
+        self.stale = true;
+        self
+    }
+
     /// Compute time elapsed since `cached_at`.
     pub fn cache_age(&self) -> u64 {
         std::time::SystemTime::now()
@@ -115,7 +196,8 @@ impl User {
 
 /// Construct a `User` from a `hotaru::Value` JSON object. Expects
 /// fields `uid`, `username`, `email`, `is_active`, `is_verified` and
-/// optionally `cached_time` (seconds old).
+/// optionally `display_name`, `avatar`, `lang`, `cached_time` (seconds old)
+/// and `max_age` / `stale_while_revalidate` (see `user::cache`).
 impl From<Value> for User {
     fn from(value: Value) -> Self {
         let base = User::new(
@@ -130,24 +212,56 @@ impl From<Value> for User {
             .try_get("cached_time")
             .ok()
             .map(|v| v.integer() as u64);
-        base.set_cached_time(with_time)
+        let avatar = value.try_get("avatar").ok().map(|v| v.string());
+        let lang = value.try_get("lang").ok().map(|v| v.string());
+        let mut user = base
+            .set_cached_time(with_time)
+            .with_avatar(avatar)
+            .with_lang(lang)
+            .with_display_name(value.get("display_name").string());
+        let seconds = |key: &str| value.try_get(key).ok().map(|v| v.integer() as u64);
+        if let (Some(max_age), Some(stale_while_revalidate)) = (seconds("max_age"), seconds("stale_while_revalidate")) {
+            user.cache_policy = CachePolicy { max_age, stale_while_revalidate };
+        }
+        user.stale = value.get("stale").boolean();
+        user
     }
 }
 
 /// Convert a `User` into a `hotaru::Value` map for JSON responses
 /// or session storage. Fields:
-/// - `uid`, `server`, `username`, `email`, `is_active`, `is_verified`, `cached_time`
+/// - `uid`, `server`, `username`, `display_name` (the username when none was
+///   chosen), `email`, `is_active`, `is_verified`, `cached_time`,
+///   `max_age`, `stale_while_revalidate`, `avatar` when one was uploaded,
+///   `lang` when one was chosen, and `stale: true` for a stale user
 impl Into<Value> for User {
     fn into(self) -> Value {
-        object!({
+        let stale = self.stale;
+        let display_name = self.get_display_name().to_string();
+        let avatar = self.avatar;
+        let lang = self.lang;
+        let mut value = object!({
             uid: self.id.uid,
             server: self.id.server.to_string(),
             username: self.username,
+            display_name: display_name,
             email: self.email,
             is_active: self.is_active,
             is_verified: self.is_verified,
             cached_time: self.cached_at,
-        })
+            max_age: self.cache_policy.max_age,
+            stale_while_revalidate: self.cache_policy.stale_while_revalidate,
+        });
+        if let Some(avatar) = avatar {
+            value.set("avatar", avatar);
+        }
+        if let Some(lang) = lang {
+            value.set("lang", lang);
+        }
+        if stale {
+            value.set("stale", true);
+        }
+        value
     }
 }
 
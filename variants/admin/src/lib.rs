@@ -0,0 +1,14 @@
+use sfx::prelude::*;
+pub use sfx::APP;
+pub use sfx::op;
+
+endpoint! {
+    APP.url("/"),
+
+    /// The site is only the admin panel: send visitors straight to it.
+    /// Non-admins are redirected from there to the login page.
+    pub home_route <HTTP> {
+        let _ = req;
+        redirect_response("/admin/")
+    }
+}
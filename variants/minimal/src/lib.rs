@@ -0,0 +1,38 @@
+use sfx::prelude::*;
+pub use sfx::APP;
+pub use sfx::op;
+
+endpoint! {
+    APP.url("/"),
+
+    /// Service status
+    ///
+    /// # Request
+    /// `GET /`
+    ///
+    /// # Response
+    /// `{ "name": "{{crate_name}}", "status": "ok" }`
+    pub status <HTTP> {
+        let _ = req;
+        let mut status = Value::Dict(Default::default());
+        status.set("name", "{{crate_name}}");
+        status.set("status", "ok");
+        json_response(status)
+    }
+}
+
+endpoint! {
+    APP.url("/api/whoami"),
+
+    /// The user behind the session cookie or bearer token
+    ///
+    /// # Request
+    /// `GET /api/whoami`
+    ///
+    /// # Response
+    /// The user as JSON, the guest user when not logged in
+    pub whoami <HTTP> {
+        let user: Value = op::get_user(req).await.into();
+        json_response(user)
+    }
+}